@@ -25,7 +25,7 @@ use compute_api::responses::{ComputeMetrics, ComputeStatus};
 use compute_api::spec::{ComputeMode, ComputeSpec};
 use utils::measured_stream::MeasuredReader;
 
-use remote_storage::{DownloadError, GenericRemoteStorage, RemotePath};
+use remote_storage::{GenericRemoteStorage, RemotePath, RemoteStorageError};
 
 use crate::pg_helpers::*;
 use crate::spec::*;
@@ -316,21 +316,25 @@ impl ComputeNode {
         let copyreader = client.copy_out(basebackup_cmd.as_str())?;
         let mut measured_reader = MeasuredReader::new(copyreader);
 
-        // Check the magic number to see if it's a gzip or not. Even though
+        // Check the magic number to see if it's a gzip, zstd or neither. Even though
         // we might explicitly ask for gzip, an old pageserver with no implementation
         // of gzip compression might send us uncompressed data. After some time
         // passes we can assume all pageservers know how to compress and we can
-        // delete this check.
+        // delete this check. The pageserver sends zstd only if asked for it with
+        // `--zstd`, but we don't rely on that either.
         //
-        // If the data is not gzip, it will be tar. It will not be mistakenly
-        // recognized as gzip because tar starts with an ascii encoding of a filename,
-        // and 0x1f and 0x8b are unlikely first characters for any filename. Moreover,
-        // we send the "global" directory first from the pageserver, so it definitely
-        // won't be recognized as gzip.
+        // If the data is not compressed, it will be tar. It will not be mistakenly
+        // recognized as compressed because tar starts with an ascii encoding of a
+        // filename, and neither 0x1f 0x8b nor 0x28 0xb5 are likely first characters
+        // for any filename. Moreover, we send the "global" directory first from the
+        // pageserver, so it definitely won't be recognized as compressed.
         let mut bufreader = std::io::BufReader::new(&mut measured_reader);
-        let gzip = {
-            let peek = bufreader.fill_buf().unwrap();
-            peek[0] == 0x1f && peek[1] == 0x8b
+        let (is_gzip, is_zstd) = {
+            let peek = bufreader.fill_buf()?;
+            (
+                peek.starts_with(&[0x1f, 0x8b]),
+                peek.starts_with(&[0x28, 0xb5, 0x2f, 0xfd]),
+            )
         };
 
         // Read the archive directly from the `CopyOutReader`
@@ -338,10 +342,15 @@ impl ComputeNode {
         // Set `ignore_zeros` so that unpack() reads all the Copy data and
         // doesn't stop at the end-of-archive marker. Otherwise, if the server
         // sends an Error after finishing the tarball, we will not notice it.
-        if gzip {
+        if is_gzip {
             let mut ar = tar::Archive::new(flate2::read::GzDecoder::new(&mut bufreader));
             ar.set_ignore_zeros(true);
             ar.unpack(&self.pgdata)?;
+        } else if is_zstd {
+            let mut ar =
+                tar::Archive::new(zstd::stream::read::Decoder::with_buffer(&mut bufreader)?);
+            ar.set_ignore_zeros(true);
+            ar.unpack(&self.pgdata)?;
         } else {
             let mut ar = tar::Archive::new(&mut bufreader);
             ar.set_ignore_zeros(true);
@@ -968,13 +977,13 @@ LIMIT 100",
         &self,
         ext_name: &str,
         is_library: bool,
-    ) -> Result<u64, DownloadError> {
-        let remote_storage = self
-            .ext_remote_storage
-            .as_ref()
-            .ok_or(DownloadError::BadInput(anyhow::anyhow!(
-                "Remote extensions storage is not configured",
-            )))?;
+    ) -> Result<u64, RemoteStorageError> {
+        let remote_storage =
+            self.ext_remote_storage
+                .as_ref()
+                .ok_or(RemoteStorageError::Permanent(anyhow::anyhow!(
+                    "Remote extensions storage is not configured",
+                )))?;
 
         let mut real_ext_name = ext_name;
         if is_library {
@@ -989,7 +998,7 @@ LIMIT 100",
                 .get()
                 .expect("must have already downloaded the library_index")
                 .get(&lib_raw_name)
-                .ok_or(DownloadError::BadInput(anyhow::anyhow!(
+                .ok_or(RemoteStorageError::Permanent(anyhow::anyhow!(
                     "library {} is not found",
                     lib_raw_name
                 )))?;
@@ -1000,7 +1009,7 @@ LIMIT 100",
             .get()
             .expect("error accessing ext_remote_paths")
             .get(real_ext_name)
-            .ok_or(DownloadError::BadInput(anyhow::anyhow!(
+            .ok_or(RemoteStorageError::Permanent(anyhow::anyhow!(
                 "real_ext_name {} is not found",
                 real_ext_name
             )))?;
@@ -1063,7 +1072,7 @@ LIMIT 100",
             &self.pgbin,
         )
         .await
-        .map_err(DownloadError::Other);
+        .map_err(RemoteStorageError::from);
 
         self.ext_download_progress
             .write()
@@ -269,12 +269,39 @@ pub fn init_remote_storage(remote_ext_config: &str) -> anyhow::Result<GenericRem
         bucket_region: remote_ext_json.region,
         prefix_in_bucket: remote_ext_json.prefix,
         endpoint: remote_ext_json.endpoint,
+        force_path_style: None,
         concurrency_limit: NonZeroUsize::new(100).expect("100 != 0"),
         max_keys_per_list_response: None,
+        multipart_part_size: NonZeroUsize::new(
+            remote_storage::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE,
+        )
+        .expect("part size != 0"),
+        multipart_upload_concurrency: NonZeroUsize::new(
+            remote_storage::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_CONCURRENCY,
+        )
+        .expect("concurrency != 0"),
+        multipart_resume_dir: None,
+        multipart_upload_max_age: None,
+        storage_class: None,
+        content_type: None,
+        cache_control: None,
+        max_object_size: None,
     };
     let config = RemoteStorageConfig {
         max_concurrent_syncs: NonZeroUsize::new(100).expect("100 != 0"),
+        max_concurrent_sync_per_tenant: None,
+        max_concurrent_sync_startup: None,
         max_sync_errors: NonZeroU32::new(100).expect("100 != 0"),
+        max_retries: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_RETRIES,
+        base_backoff_ms: remote_storage::DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS,
+        compression: remote_storage::Compression::None,
+        max_bytes_per_sec: None,
+        dry_run: false,
+        encryption_key_file: None,
+        operation_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+        list_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+        upload_start_jitter: remote_storage::DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+        prefix: None,
         storage: RemoteStorageKind::AwsS3(config),
     };
     GenericRemoteStorage::from_config(&config)
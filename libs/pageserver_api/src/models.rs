@@ -157,6 +157,94 @@ pub enum ActivatingFrom {
     Attaching,
 }
 
+/// Where the remote storage operations of a timeline are at.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(tag = "state")]
+pub enum RemoteSyncStatus {
+    /// The upload queue is not initialized yet, e.g. while the timeline is loading.
+    Uninitialized,
+    /// Operations are scheduled, but none of them have started yet.
+    Queued,
+    /// Layers are being downloaded. The bytes are summed over all the layer downloads in flight.
+    Downloading { bytes_done: u64, bytes_total: u64 },
+    /// Uploads or deletions are in flight.
+    Uploading,
+    /// Nothing to do, the remote storage is up to date.
+    Ready,
+    /// The last operation of a kind (upload, deletion or download) failed for good. It is
+    /// retried, the status changes once one of the same kind succeeds.
+    Failed { error: String },
+}
+
+/// How far a replica of the timeline, in one of the remote storages after the first one of the
+/// `remote_storage` list, is behind the primary remote storage.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RemoteReplicaSyncStatus {
+    /// Position of the replica in the `remote_storage` list, 1 for the first replica.
+    pub replica: usize,
+    /// Operations done in the primary remote storage, not yet in the replica.
+    pub queued_operations: usize,
+    /// Error of the last operation in the replica, until one succeeds.
+    pub error: Option<String>,
+}
+
+/// A remote storage operation of a timeline that has failed `max_sync_errors` times in a row.
+/// It is not attempted anymore until it is retried: an upload holds back the operations after
+/// it, a timeline that failed to load from the remote storage stays out of the tenant.
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RemoteSyncFailedTask {
+    pub operation: String,
+    pub error: String,
+}
+
+/// Differences between the local files of a timeline and its copy in the remote storage.
+/// Nothing is changed to produce it. The uploads that are still queued show up as missing.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct RemoteConsistencyReport {
+    /// Local layer files that are not in the remote index part, or whose object is not
+    /// in the remote storage.
+    pub missing_uploads: Vec<String>,
+    /// Layers in the remote index part whose object is not in the remote storage.
+    /// Includes the layers kept for the remote GC retention.
+    pub missing_remote_objects: Vec<String>,
+    /// Remote objects of the timeline that neither the local files nor the remote index part
+    /// know about.
+    pub remote_only: Vec<String>,
+    /// Layers whose local size differs from the size in the remote index part.
+    pub size_mismatches: Vec<LayerSizeMismatch>,
+    /// Layers of the same size, whose local contents don't match the checksum in the remote
+    /// index part, or the checksums of the chunks of the remote object.
+    #[serde(default)]
+    pub checksum_mismatches: Vec<String>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub local_disk_consistent_lsn: Lsn,
+    /// `None` if the timeline has no remote index part.
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub remote_disk_consistent_lsn: Option<Lsn>,
+    /// The remote storage has layers of the timeline, but no index part to attach it from.
+    #[serde(default)]
+    pub missing_index_part: bool,
+}
+
+impl RemoteConsistencyReport {
+    /// Whether the remote storage has everything the local timeline has.
+    pub fn is_consistent(&self) -> bool {
+        self.missing_uploads.is_empty()
+            && self.missing_remote_objects.is_empty()
+            && self.size_mismatches.is_empty()
+            && self.checksum_mismatches.is_empty()
+            && self.remote_disk_consistent_lsn == Some(self.local_disk_consistent_lsn)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct LayerSizeMismatch {
+    pub layer: String,
+    pub local_size: u64,
+    pub remote_size: u64,
+}
+
 /// A state of a timeline in pageserver's memory.
 #[derive(Debug, Clone, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
 pub enum TimelineState {
@@ -318,6 +406,21 @@ impl std::ops::Deref for TenantAttachConfig {
     }
 }
 
+/// What a tenant detach did with the remote storage operations of the tenant's timelines.
+#[serde_as]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct TenantDetachSummary {
+    /// Uploads and deletions that were queued or in flight when the detach started, and
+    /// completed before it went on.
+    pub flushed_operations: usize,
+    /// Uploads and deletions that were dropped: all of them, unless the detach flushes, and
+    /// the ones left when the flush failed or timed out otherwise.
+    pub cancelled_operations: usize,
+    /// The timelines whose flush failed or timed out.
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub unflushed_timelines: Vec<TimelineId>,
+}
+
 /// See [`TenantState::attachment_status`] and the OpenAPI docs for context.
 #[derive(Serialize, Deserialize, Clone)]
 #[serde(tag = "slug", content = "data", rename_all = "snake_case")]
@@ -340,6 +443,29 @@ pub struct TenantInfo {
     pub attachment_status: TenantAttachmentStatus,
 }
 
+/// A timeline in the remote storage, as its index part describes it, listed whether the tenant
+/// is attached or not.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct RemoteTimelineInfo {
+    #[serde_as(as = "DisplayFromStr")]
+    pub timeline_id: TimelineId,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub ancestor_timeline_id: Option<TimelineId>,
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub ancestor_lsn: Option<Lsn>,
+    #[serde_as(as = "DisplayFromStr")]
+    pub disk_consistent_lsn: Lsn,
+    #[serde_as(as = "DisplayFromStr")]
+    pub latest_gc_cutoff_lsn: Lsn,
+    pub pg_version: u32,
+    /// The `disk_consistent_lsn`s the timeline can be restored at, from the index parts kept
+    /// in the remote storage with `remote_index_generations`, oldest first.
+    #[serde(default)]
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub index_generations: Vec<Lsn>,
+}
+
 /// This represents the output of the "timeline_detail" and "timeline_list" API calls.
 #[serde_as]
 #[derive(Debug, Serialize, Deserialize, Clone)]
@@ -381,6 +507,11 @@ pub struct TimelineInfo {
     pub pg_version: u32,
 
     pub state: TimelineState,
+    /// None if the pageserver has no remote storage configured.
+    pub remote_sync_status: Option<RemoteSyncStatus>,
+    /// One per remote storage replica, empty without them.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub remote_replica_sync_status: Vec<RemoteReplicaSyncStatus>,
 }
 
 #[derive(Debug, Clone, Serialize)]
@@ -550,6 +681,17 @@ pub struct TimelineGcRequest {
     pub gc_horizon: Option<u64>,
 }
 
+/// Result of the round trip of a sentinel object through the remote storage. The times are
+/// only there for the steps that succeeded.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RemoteStorageHealth {
+    pub healthy: bool,
+    pub upload_micros: Option<u64>,
+    pub download_micros: Option<u64>,
+    pub delete_micros: Option<u64>,
+    pub error: Option<String>,
+}
+
 // Wrapped in libpq CopyData
 #[derive(PartialEq, Eq, Debug)]
 pub enum PagestreamFeMessage {
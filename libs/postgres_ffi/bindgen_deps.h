@@ -5,6 +5,7 @@
  * header here, and whitelist the struct in the build.rs file.
  */
 #include "c.h"
+#include "catalog/catversion.h"
 #include "catalog/pg_control.h"
 #include "access/xlog_internal.h"
 
@@ -12,4 +13,5 @@
 #include "storage/bufpage.h"
 #include "storage/off.h"
 #include "access/multixact.h"
+#include "access/slru.h"
 #include "utils/snapshot.h"
\ No newline at end of file
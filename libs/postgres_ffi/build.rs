@@ -1,7 +1,7 @@
 extern crate bindgen;
 
 use std::env;
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::process::Command;
 
 use anyhow::{anyhow, Context};
@@ -48,8 +48,12 @@ fn main() -> anyhow::Result<()> {
     println!("cargo:rerun-if-changed=bindgen_deps.h");
 
     // Finding the location of C headers for the Postgres server:
+    // - if PG_CONFIG_{PG_MAJORVERSION} (e.g. PG_CONFIG_V15) is set, ask that pg_config binary for it,
+    //   this allows to build against a Postgres installed elsewhere, e.g. a system one
     // - if POSTGRES_INSTALL_DIR is set look into it, otherwise look into `<project_root>/pg_install`
     // - if there's a `bin/pg_config` file use it for getting include server, otherwise use `<project_root>/pg_install/{PG_MAJORVERSION}/include/postgresql/server`
+    // - if pg_config fails, fall back to the latter path too
+    println!("cargo:rerun-if-env-changed=POSTGRES_INSTALL_DIR");
     let pg_install_dir = if let Some(postgres_install_dir) = env::var_os("POSTGRES_INSTALL_DIR") {
         postgres_install_dir.into()
     } else {
@@ -63,30 +67,30 @@ fn main() -> anyhow::Result<()> {
             pg_install_dir_versioned = cwd.join("..").join("..").join(pg_install_dir_versioned);
         }
 
-        let pg_config_bin = pg_install_dir_versioned.join("bin").join("pg_config");
-        let inc_server_path: String = if pg_config_bin.exists() {
-            let output = Command::new(pg_config_bin)
-                .arg("--includedir-server")
-                .output()
-                .context("failed to execute `pg_config --includedir-server`")?;
+        // One variable per version, as a pg_config binary only knows about its own version.
+        let pg_config_env = format!("PG_CONFIG_{}", pg_version.to_uppercase());
+        println!("cargo:rerun-if-env-changed={pg_config_env}");
+        let pg_config_bin = match env::var_os(&pg_config_env) {
+            Some(pg_config_bin) => Some(PathBuf::from(pg_config_bin)),
+            None => Some(pg_install_dir_versioned.join("bin").join("pg_config"))
+                .filter(|pg_config_bin| pg_config_bin.exists()),
+        };
 
-            if !output.status.success() {
-                panic!("`pg_config --includedir-server` failed")
+        let inc_server_path = match pg_config_bin.map(|bin| pg_config_includedir_server(&bin)) {
+            Some(Ok(inc_server_path)) => inc_server_path,
+            other => {
+                if let Some(Err(e)) = other {
+                    println!("cargo:warning=falling back to the default {pg_version} include path: {e:#}");
+                }
+                let server_path = pg_install_dir_versioned
+                    .join("include")
+                    .join("postgresql")
+                    .join("server")
+                    .into_os_string();
+                server_path
+                    .into_string()
+                    .map_err(|s| anyhow!("Bad postgres server path {s:?}"))?
             }
-
-            String::from_utf8(output.stdout)
-                .context("pg_config output is not UTF-8")?
-                .trim_end()
-                .into()
-        } else {
-            let server_path = pg_install_dir_versioned
-                .join("include")
-                .join("postgresql")
-                .join("server")
-                .into_os_string();
-            server_path
-                .into_string()
-                .map_err(|s| anyhow!("Bad postgres server path {s:?}"))?
         };
 
         // The bindgen::Builder is the main entry point
@@ -123,6 +127,13 @@ fn main() -> anyhow::Result<()> {
             .allowlist_var("XLOG_PAGE_MAGIC")
             .allowlist_var("PG_CONTROL_FILE_SIZE")
             .allowlist_var("PG_CONTROLFILEDATA_OFFSETOF_CRC")
+            .allowlist_var("PG_CONTROL_VERSION")
+            .allowlist_var("CATALOG_VERSION_NO")
+            .allowlist_var("BLCKSZ")
+            .allowlist_var("RELSEG_SIZE")
+            .allowlist_var("XLOG_BLCKSZ")
+            .allowlist_var("DEFAULT_XLOG_SEG_SIZE")
+            .allowlist_var("SLRU_PAGES_PER_SEGMENT")
             .allowlist_type("PageHeaderData")
             .allowlist_type("DBState")
             .allowlist_type("XidCSN")
@@ -151,3 +162,23 @@ fn main() -> anyhow::Result<()> {
 
     Ok(())
 }
+
+/// Location of the server C headers, as reported by `pg_config --includedir-server`.
+fn pg_config_includedir_server(pg_config_bin: &Path) -> anyhow::Result<String> {
+    let output = Command::new(pg_config_bin)
+        .arg("--includedir-server")
+        .output()
+        .with_context(|| format!("failed to execute `{pg_config_bin:?} --includedir-server`"))?;
+
+    if !output.status.success() {
+        anyhow::bail!(
+            "`{pg_config_bin:?} --includedir-server` failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )
+    }
+
+    Ok(String::from_utf8(output.stdout)
+        .context("pg_config output is not UTF-8")?
+        .trim_end()
+        .into())
+}
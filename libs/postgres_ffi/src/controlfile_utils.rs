@@ -23,7 +23,10 @@
 //! information. You can use PostgreSQL's pg_controldata utility to view its
 //! contents.
 //!
-use super::bindings::{ControlFileData, PG_CONTROL_FILE_SIZE};
+use super::bindings::{
+    ControlFileData, CATALOG_VERSION_NO, PG_CONTROL_FILE_SIZE, PG_CONTROL_VERSION,
+};
+use super::PG_MAJORVERSION;
 
 use anyhow::{bail, Result};
 use bytes::{Bytes, BytesMut};
@@ -69,6 +72,22 @@ impl ControlFileData {
             );
         }
 
+        // The layout of the struct differs between the versions. A control file of another
+        // version decodes into garbage, which would then be encoded into a control file that
+        // Postgres can't make sense of.
+        if controlfile.pg_control_version != PG_CONTROL_VERSION
+            || controlfile.catalog_version_no != CATALOG_VERSION_NO
+        {
+            bail!(
+                "control file has pg_control version {} and catalog version {}, expected {} and {} for PostgreSQL {}",
+                controlfile.pg_control_version,
+                controlfile.catalog_version_no,
+                PG_CONTROL_VERSION,
+                CATALOG_VERSION_NO,
+                &PG_MAJORVERSION[1..],
+            );
+        }
+
         Ok(controlfile)
     }
 
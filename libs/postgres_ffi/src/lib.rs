@@ -42,6 +42,19 @@ macro_rules! postgres_ffi {
             // Re-export some symbols from bindings
             pub use bindings::DBState_DB_SHUTDOWNED;
             pub use bindings::{CheckPoint, ControlFileData, XLogRecord};
+
+            // The version independent constants are shared by all the versions, which
+            // requires them to be built with the same sizes.
+            const _: () = {
+                assert!(bindings::BLCKSZ as u64 == crate::BLCKSZ as u64);
+                assert!(bindings::RELSEG_SIZE as u64 == crate::RELSEG_SIZE as u64);
+                assert!(bindings::XLOG_BLCKSZ as u64 == crate::XLOG_BLCKSZ as u64);
+                assert!(bindings::DEFAULT_XLOG_SEG_SIZE as u64 == crate::WAL_SEGMENT_SIZE as u64);
+                assert!(
+                    bindings::SLRU_PAGES_PER_SEGMENT as u64
+                        == crate::pg_constants::SLRU_PAGES_PER_SEGMENT as u64
+                );
+            };
         }
     };
 }
@@ -74,11 +87,29 @@ pub use v14::xlog_utils::{XLOG_SIZE_OF_XLOG_RECORD, XLOG_SIZE_OF_XLOG_SHORT_PHD}
 pub use v14::bindings::{CheckPoint, ControlFileData};
 
 // from pg_config.h. These can be changed with configure options --with-blocksize=BLOCKSIZE and
-// --with-segsize=SEGSIZE, but assume the defaults for now.
-pub const BLCKSZ: u16 = 8192;
-pub const RELSEG_SIZE: u32 = 1024 * 1024 * 1024 / (BLCKSZ as u32);
-pub const XLOG_BLCKSZ: usize = 8192;
-pub const WAL_SEGMENT_SIZE: usize = 16 * 1024 * 1024;
+// --with-segsize=SEGSIZE, they are taken from the headers Postgres was built with. Each version
+// module checks that its headers agree.
+pub const BLCKSZ: u16 = v14::bindings::BLCKSZ as u16;
+pub const RELSEG_SIZE: u32 = v14::bindings::RELSEG_SIZE;
+pub const XLOG_BLCKSZ: usize = v14::bindings::XLOG_BLCKSZ as usize;
+pub const WAL_SEGMENT_SIZE: usize = v14::bindings::DEFAULT_XLOG_SEG_SIZE as usize;
+
+/// The WAL segment size of a cluster, `xlog_seg_size` of its control file. Unlike
+/// [`WAL_SEGMENT_SIZE`], it's chosen at `initdb --wal-segsize`: a power of two between 1MB
+/// and 1GB.
+pub fn wal_segment_size(xlog_seg_size: u32) -> anyhow::Result<usize> {
+    const MIN_WAL_SEGMENT_SIZE: u32 = 1024 * 1024;
+    const MAX_WAL_SEGMENT_SIZE: u32 = 1024 * 1024 * 1024;
+    anyhow::ensure!(
+        xlog_seg_size.is_power_of_two()
+            && (MIN_WAL_SEGMENT_SIZE..=MAX_WAL_SEGMENT_SIZE).contains(&xlog_seg_size),
+        "invalid WAL segment size {xlog_seg_size} in the control file"
+    );
+    Ok(xlog_seg_size as usize)
+}
+
+// utils cannot depend on this crate, so it keeps its own copy for Lsn arithmetic.
+const _: () = assert!(utils::lsn::XLOG_BLCKSZ as usize == XLOG_BLCKSZ);
 
 pub const MAX_SEND_SIZE: usize = XLOG_BLCKSZ * 16;
 
@@ -104,37 +135,97 @@ pub fn generate_wal_segment(
     segno: u64,
     system_id: u64,
     pg_version: u32,
+    pg_tli: TimeLineID,
     lsn: Lsn,
 ) -> Result<Bytes, SerializeError> {
     assert_eq!(segno, lsn.segment_number(WAL_SEGMENT_SIZE));
 
     match pg_version {
-        14 => v14::xlog_utils::generate_wal_segment(segno, system_id, lsn),
-        15 => v15::xlog_utils::generate_wal_segment(segno, system_id, lsn),
+        14 => v14::xlog_utils::generate_wal_segment(segno, system_id, pg_tli, lsn),
+        15 => v15::xlog_utils::generate_wal_segment(segno, system_id, pg_tli, lsn),
+        _ => Err(SerializeError::BadInput),
+    }
+}
+
+pub fn generate_wal_segment_headers(
+    segno: u64,
+    system_id: u64,
+    pg_version: u32,
+    pg_tli: TimeLineID,
+    lsn: Lsn,
+    wal_seg_size: usize,
+) -> Result<Vec<(usize, Bytes)>, SerializeError> {
+    assert_eq!(segno, lsn.segment_number(wal_seg_size));
+
+    match pg_version {
+        14 => v14::xlog_utils::generate_wal_segment_headers(
+            segno,
+            system_id,
+            pg_tli,
+            lsn,
+            wal_seg_size,
+        ),
+        15 => v15::xlog_utils::generate_wal_segment_headers(
+            segno,
+            system_id,
+            pg_tli,
+            lsn,
+            wal_seg_size,
+        ),
         _ => Err(SerializeError::BadInput),
     }
 }
 
+// The control file layout is the same in all the supported versions, which is what allows
+// to use the structs of one version for all of them, see `decode_pg_control`.
+const _: () = assert!(
+    std::mem::size_of::<v14::bindings::ControlFileData>()
+        == std::mem::size_of::<v15::bindings::ControlFileData>()
+);
+
+/// Decodes a control file of the given Postgres version.
+///
+/// The version specific decoding checks the CRC, and that the control file is of that
+/// version. The layout is the same in all the supported versions, so the result is
+/// returned as the common [`ControlFileData`].
+pub fn decode_pg_control(
+    pg_control_bytes: &[u8],
+    pg_version: u32,
+) -> anyhow::Result<ControlFileData> {
+    use utils::bin_ser::LeSer;
+
+    match pg_version {
+        14 => v14::bindings::ControlFileData::decode(pg_control_bytes),
+        15 => {
+            v15::bindings::ControlFileData::decode(pg_control_bytes)?;
+            Ok(ControlFileData::des_prefix(pg_control_bytes)?)
+        }
+        _ => anyhow::bail!("Unknown version {}", pg_version),
+    }
+}
+
 pub fn generate_pg_control(
     pg_control_bytes: &[u8],
     checkpoint_bytes: &[u8],
     lsn: Lsn,
     pg_version: u32,
+    pg_tli: TimeLineID,
 ) -> anyhow::Result<(Bytes, u64)> {
     match pg_version {
-        14 => v14::xlog_utils::generate_pg_control(pg_control_bytes, checkpoint_bytes, lsn),
-        15 => v15::xlog_utils::generate_pg_control(pg_control_bytes, checkpoint_bytes, lsn),
+        14 => v14::xlog_utils::generate_pg_control(pg_control_bytes, checkpoint_bytes, lsn, pg_tli),
+        15 => v15::xlog_utils::generate_pg_control(pg_control_bytes, checkpoint_bytes, lsn, pg_tli),
         _ => anyhow::bail!("Unknown version {}", pg_version),
     }
 }
 
-// PG timeline is always 1, changing it doesn't have any useful meaning in Neon.
+// PG timeline of the WAL that the safekeepers store and stream, and of freshly
+// initialized databases. Changing it doesn't have any useful meaning in Neon.
 //
 // NOTE: this is not to be confused with Neon timelines; different concept!
 //
-// It's a shaky assumption, that it's always 1. We might import a
-// PostgreSQL data directory that has gone through timeline bumps,
-// for example. FIXME later.
+// The pageserver follows the PG timeline recorded in the checkpoints instead,
+// which differs after an imported data directory has gone through timeline
+// bumps, see the basebackup.
 pub const PG_TLI: u32 = 1;
 
 //  See TransactionIdIsNormal in transam.h
@@ -157,6 +248,45 @@ pub const fn transaction_id_precedes(id1: TransactionId, id2: TransactionId) ->
     diff < 0
 }
 
+/// The first Postgres version that names the two-phase state files by the full, 64-bit
+/// transaction ids, with the epoch in the first 8 hex digits. The older ones use the 32-bit
+/// xids alone, which collide across the epochs.
+pub const TWOPHASE_FULL_XID_PG_VERSION: u32 = 17;
+
+/// The full transaction id of `xid`, which is not older than the epoch before the one of
+/// `next_full_xid`, e.g. the `nextXid` of the checkpoint. See AdjustToFullTransactionId in
+/// twophase.c.
+pub const fn adjust_to_full_transaction_id(xid: TransactionId, next_full_xid: u64) -> u64 {
+    let next_xid = next_full_xid as u32;
+    let mut epoch = (next_full_xid >> 32) as u32;
+    if xid > next_xid {
+        // Wrapped around, from the previous epoch.
+        epoch = epoch.saturating_sub(1);
+    }
+    ((epoch as u64) << 32) | xid as u64
+}
+
+/// Name of the two-phase state file of `xid` in `pg_twophase`, the one Postgres of `pg_version`
+/// looks for at startup, see TwoPhaseFilePath in twophase.c. `next_full_xid` gives the epoch of
+/// `xid` for the versions that need it.
+pub fn twophase_file_name(pg_version: u32, xid: TransactionId, next_full_xid: u64) -> String {
+    if pg_version >= TWOPHASE_FULL_XID_PG_VERSION {
+        format!("{:016X}", adjust_to_full_transaction_id(xid, next_full_xid))
+    } else {
+        format!("{xid:08X}")
+    }
+}
+
+/// The transaction id of a two-phase state file named by [`twophase_file_name`], by any version.
+pub fn parse_twophase_file_name(file_name: &str) -> anyhow::Result<TransactionId> {
+    let full_xid = match file_name.len() {
+        8 | 16 => u64::from_str_radix(file_name, 16),
+        _ => anyhow::bail!("invalid two-phase state file name {file_name:?}"),
+    }
+    .map_err(|e| anyhow::anyhow!("invalid two-phase state file name {file_name:?}: {e}"))?;
+    Ok(full_xid as TransactionId)
+}
+
 // Check if page is not yet initialized (port of Postgres PageIsInit() macro)
 pub fn page_is_new(pg: &[u8]) -> bool {
     pg[14] == 0 && pg[15] == 0 // pg_upper == 0
@@ -198,7 +328,7 @@ pub fn fsm_logical_to_physical(addr: BlockNumber) -> BlockNumber {
 
 pub mod waldecoder {
 
-    use crate::{v14, v15};
+    use crate::{v14, v15, XLogRecord, XLOG_SIZE_OF_XLOG_RECORD};
     use bytes::{Buf, Bytes, BytesMut};
     use std::num::NonZeroU32;
     use thiserror::Error;
@@ -266,5 +396,231 @@ pub mod waldecoder {
                 }),
             }
         }
+
+        /// Iterates over the complete records of the input fed so far, see [`WalRecords`].
+        pub fn records(&mut self) -> WalRecords<'_> {
+            WalRecords {
+                decoder: self,
+                failed: false,
+            }
+        }
+    }
+
+    /// Iterator over the WAL records decoded by a [`WalStreamDecoder`].
+    ///
+    /// Yields the LSN of the next record (like [`WalStreamDecoder::poll_decode`]), the
+    /// header of the record and the whole record, header included, with page headers
+    /// and continuations already stripped. The record CRC is checked.
+    ///
+    /// The iterator ends when the decoder needs more input, or after the first error.
+    /// Feeding more bytes and asking for the records again carries on from there.
+    pub struct WalRecords<'a> {
+        decoder: &'a mut WalStreamDecoder,
+        failed: bool,
+    }
+
+    impl Iterator for WalRecords<'_> {
+        type Item = Result<(Lsn, XLogRecord, Bytes), WalDecodeError>;
+
+        fn next(&mut self) -> Option<Self::Item> {
+            if self.failed {
+                return None;
+            }
+            let (lsn, recordbuf) = match self.decoder.poll_decode() {
+                Ok(Some(decoded)) => decoded,
+                Ok(None) => return None,
+                Err(e) => {
+                    self.failed = true;
+                    return Some(Err(e));
+                }
+            };
+            // The record header is the same in all the supported versions.
+            match XLogRecord::from_slice(&recordbuf[..XLOG_SIZE_OF_XLOG_RECORD]) {
+                Ok(xlogrec) => Some(Ok((lsn, xlogrec, recordbuf))),
+                Err(e) => {
+                    self.failed = true;
+                    Some(Err(WalDecodeError {
+                        msg: format!("xlog record deserialization failed {}", e),
+                        lsn,
+                    }))
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use crate::pg_constants::{XLP_FIRST_IS_CONTRECORD, XLP_LONG_HEADER};
+        use crate::v14::bindings::{XLogLongPageHeaderData, XLogPageHeaderData, XLOG_PAGE_MAGIC};
+        use crate::{encode_logical_message, WAL_SEGMENT_SIZE, XLOG_BLCKSZ};
+
+        /// Lays out the records on WAL pages from `start` on, the way Postgres writes them.
+        /// Returns the WAL and the LSN after each record.
+        fn write_wal(start: Lsn, records: &[Vec<u8>]) -> (Vec<u8>, Vec<Lsn>) {
+            let mut wal = Vec::new();
+            let mut end_lsns = Vec::new();
+            let mut lsn = start;
+            for record in records {
+                let mut rest = &record[..];
+                while !rest.is_empty() {
+                    if lsn.block_offset() == 0 {
+                        let contrecord = rest.len() < record.len();
+                        let std = XLogPageHeaderData {
+                            xlp_magic: XLOG_PAGE_MAGIC as u16,
+                            xlp_info: if contrecord {
+                                XLP_FIRST_IS_CONTRECORD
+                            } else {
+                                0
+                            },
+                            xlp_tli: crate::PG_TLI,
+                            xlp_pageaddr: lsn.0,
+                            xlp_rem_len: if contrecord { rest.len() as u32 } else { 0 },
+                            ..Default::default()
+                        };
+                        let header = if lsn.segment_offset(WAL_SEGMENT_SIZE) == 0 {
+                            XLogLongPageHeaderData {
+                                std: XLogPageHeaderData {
+                                    xlp_info: std.xlp_info | XLP_LONG_HEADER,
+                                    ..std
+                                },
+                                xlp_sysid: 42,
+                                xlp_seg_size: WAL_SEGMENT_SIZE as u32,
+                                xlp_xlog_blcksz: XLOG_BLCKSZ as u32,
+                            }
+                            .encode()
+                            .unwrap()
+                        } else {
+                            std.encode().unwrap()
+                        };
+                        wal.extend_from_slice(&header);
+                        lsn += header.len() as u64;
+                    }
+                    let n = std::cmp::min(rest.len(), lsn.remaining_in_block() as usize);
+                    wal.extend_from_slice(&rest[..n]);
+                    lsn += n as u64;
+                    rest = &rest[n..];
+                }
+                let aligned = lsn.align();
+                wal.resize(wal.len() + (aligned.0 - lsn.0) as usize, 0);
+                lsn = aligned;
+                end_lsns.push(lsn);
+            }
+            (wal, end_lsns)
+        }
+
+        fn test_records() -> Vec<Vec<u8>> {
+            // Enough records of different lengths to cross a few page boundaries at
+            // different offsets.
+            (0..300)
+                .map(|i| {
+                    let mut record = encode_logical_message("prefix", &"x".repeat(i * 7 % 200));
+                    // Without the alignment padding, which `write_wal` adds.
+                    let xl_tot_len = u32::from_le_bytes(record[0..4].try_into().unwrap());
+                    record.truncate(xl_tot_len as usize);
+                    record
+                })
+                .collect()
+        }
+
+        #[test]
+        fn records_across_pages() {
+            let start = Lsn(WAL_SEGMENT_SIZE as u64);
+            let records = test_records();
+            let (wal, end_lsns) = write_wal(start, &records);
+            assert!(wal.len() > 3 * XLOG_BLCKSZ);
+
+            let mut decoder = WalStreamDecoder::new(start, 14);
+            let mut decoded = Vec::new();
+            for chunk in wal.chunks(1000) {
+                decoder.feed_bytes(chunk);
+                for record in decoder.records() {
+                    decoded.push(record.unwrap());
+                }
+            }
+
+            assert_eq!(decoded.len(), records.len());
+            for ((lsn, xlogrec, recordbuf), (record, end_lsn)) in
+                decoded.iter().zip(records.iter().zip(end_lsns))
+            {
+                assert_eq!(*lsn, end_lsn);
+                assert_eq!(xlogrec.xl_tot_len as usize, record.len());
+                assert_eq!(&recordbuf[..], &record[..]);
+            }
+        }
+
+        #[test]
+        fn crc_mismatch() {
+            let start = Lsn(WAL_SEGMENT_SIZE as u64);
+            let mut records = test_records();
+            let last = records[9].len() - 1;
+            records[9][last] ^= 0xFF;
+            let (wal, _) = write_wal(start, &records);
+
+            let mut decoder = WalStreamDecoder::new(start, 14);
+            decoder.feed_bytes(&wal);
+            let mut iter = decoder.records();
+            for _ in 0..9 {
+                assert!(iter.next().unwrap().is_ok());
+            }
+            let err = iter.next().unwrap().unwrap_err();
+            assert!(err.msg.contains("crc mismatch"), "{err}");
+            assert!(iter.next().is_none());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn twophase_file_names() -> anyhow::Result<()> {
+        // Epoch 5, a prepared transaction from the same epoch and one from before the wraparound.
+        let next_full_xid = (5 << 32) | 1000;
+        assert_eq!(twophase_file_name(15, 900, next_full_xid), "00000384");
+        assert_eq!(
+            twophase_file_name(TWOPHASE_FULL_XID_PG_VERSION, 900, next_full_xid),
+            "0000000500000384"
+        );
+        assert_eq!(
+            twophase_file_name(TWOPHASE_FULL_XID_PG_VERSION, 0xFFFF_FFF0, next_full_xid),
+            "00000004FFFFFFF0"
+        );
+
+        for pg_version in [14, 15, TWOPHASE_FULL_XID_PG_VERSION] {
+            for xid in [3, 900, 0xFFFF_FFF0] {
+                let file_name = twophase_file_name(pg_version, xid, next_full_xid);
+                assert_eq!(parse_twophase_file_name(&file_name)?, xid);
+            }
+        }
+        assert!(parse_twophase_file_name("384").is_err());
+        assert!(parse_twophase_file_name("0000038G").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn control_files_decode_with_their_version() -> anyhow::Result<()> {
+        let v14_control_file = v14::bindings::ControlFileData {
+            pg_control_version: v14::bindings::PG_CONTROL_VERSION,
+            catalog_version_no: v14::bindings::CATALOG_VERSION_NO,
+            checkPoint: 0x1000,
+            ..Default::default()
+        }
+        .encode();
+        let v15_control_file = v15::bindings::ControlFileData {
+            pg_control_version: v15::bindings::PG_CONTROL_VERSION,
+            catalog_version_no: v15::bindings::CATALOG_VERSION_NO,
+            checkPoint: 0x2000,
+            ..Default::default()
+        }
+        .encode();
+
+        assert_eq!(decode_pg_control(&v14_control_file, 14)?.checkPoint, 0x1000);
+        assert_eq!(decode_pg_control(&v15_control_file, 15)?.checkPoint, 0x2000);
+        // The catalog versions differ, a control file is never taken for another version's.
+        assert!(decode_pg_control(&v14_control_file, 15).is_err());
+        assert!(decode_pg_control(&v15_control_file, 14).is_err());
+        Ok(())
     }
 }
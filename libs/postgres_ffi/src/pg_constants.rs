@@ -98,8 +98,13 @@ pub const XLOG_XACT_ABORT: u8 = 0x20;
 pub const XLOG_XACT_COMMIT_PREPARED: u8 = 0x30;
 pub const XLOG_XACT_ABORT_PREPARED: u8 = 0x40;
 
+// From twophase.c
+pub const TWOPHASE_MAGIC: u32 = 0x57F94534;
+/// Size of the `magic` and `total_len` fields at the start of `TwoPhaseFileHeader`.
+pub const TWOPHASE_FILE_HEADER_PREFIX_SIZE: usize = 8;
+
 // From srlu.h
-pub const SLRU_PAGES_PER_SEGMENT: u32 = 32;
+pub const SLRU_PAGES_PER_SEGMENT: u32 = crate::v14::bindings::SLRU_PAGES_PER_SEGMENT;
 pub const SLRU_SEG_SIZE: usize = BLCKSZ as usize * SLRU_PAGES_PER_SEGMENT as usize;
 
 /* mask for filtering opcodes out of xl_info */
@@ -12,6 +12,10 @@ pub const FSM_FORKNUM: u8 = 1;
 pub const VISIBILITYMAP_FORKNUM: u8 = 2;
 pub const INIT_FORKNUM: u8 = 3;
 
+/// Highest segment number of a relation fork: a relation has at most `MaxBlockNumber`
+/// (0xFFFFFFFE) blocks, split into segments of `RELSEG_SIZE` blocks.
+pub const MAX_SEGNO: u32 = (u32::MAX - 1) / crate::RELSEG_SIZE;
+
 #[derive(Debug, Clone, thiserror::Error, PartialEq, Eq)]
 pub enum FilePathError {
     #[error("invalid relation fork name")]
@@ -146,8 +150,9 @@ mod tests {
         assert_eq!(parse_relfilename("0"), Ok((0, 0, 0)));
 
         // PostgreSQL has a limit of 2^32-2 blocks in a table. With 8k block size and
-        // 1 GB segments, the max segment number is 32767. But we accept larger values
-        // currently.
+        // 1 GB segments, the max segment number is 32767. The file names with larger values
+        // are parsed, it's up to the caller to check the segment number against MAX_SEGNO.
         assert_eq!(parse_relfilename("1.123456"), Ok((1, 0, 123456)));
+        assert_eq!(MAX_SEGNO, 32767);
     }
 }
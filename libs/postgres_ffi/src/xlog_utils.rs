@@ -112,18 +112,30 @@ pub fn generate_pg_control(
     pg_control_bytes: &[u8],
     checkpoint_bytes: &[u8],
     lsn: Lsn,
+    pg_tli: TimeLineID,
 ) -> anyhow::Result<(Bytes, u64)> {
     let mut pg_control = ControlFileData::decode(pg_control_bytes)?;
     let mut checkpoint = CheckPoint::decode(checkpoint_bytes)?;
 
     // Generate new pg_control needed for bootstrap
-    checkpoint.redo = normalize_lsn(lsn, WAL_SEGMENT_SIZE).0;
+    let wal_seg_size = crate::wal_segment_size(pg_control.xlog_seg_size)?;
+    checkpoint.redo = normalize_lsn(lsn, wal_seg_size).0;
 
     //reset some fields we don't want to preserve
     //TODO Check this.
     //We may need to determine the value from twophase data.
     checkpoint.oldestActiveXid = 0;
 
+    // nextXid, nextOid, nextMulti and nextMultiOffset are kept as they are: the WAL ingestion
+    // keeps them in the checkpoint of the repository up to `lsn`, and Postgres continues to
+    // assign the XIDs and OIDs from them. The ones of `pg_control_bytes` are those of the
+    // initdb or of the import, and would make the compute reuse the XIDs and OIDs since then.
+
+    // The compute starts on the timeline of the WAL segment generated along with the control
+    // file, no timeline switch happens at the bootstrap checkpoint.
+    checkpoint.ThisTimeLineID = pg_tli;
+    checkpoint.PrevTimeLineID = pg_tli;
+
     //save new values in pg_control
     pg_control.checkPoint = 0;
     pg_control.checkPointCopy = checkpoint;
@@ -195,9 +207,9 @@ pub fn find_end_of_wal(
                     decoder.feed_bytes(&buf[0..bytes_read]);
 
                     // advance result past all completely read records
-                    loop {
-                        match decoder.poll_decode() {
-                            Ok(Some(record)) => result = record.0,
+                    for record in decoder.records() {
+                        match record {
+                            Ok((lsn, _, _)) => result = lsn,
                             Err(e) => {
                                 debug!(
                                     "find_end_of_wal reached end at {:?}, decode error: {:?}",
@@ -205,7 +217,6 @@ pub fn find_end_of_wal(
                                 );
                                 return Ok(result);
                             }
-                            Ok(None) => break, // need more data
                         }
                     }
                 }
@@ -331,18 +342,74 @@ impl CheckPoint {
         }
         false
     }
+
+    /// Advances the counters of the next XID, multixact and multixact offset, and, for a
+    /// shutdown checkpoint, of the next OID, to those of a checkpoint record found in the WAL,
+    /// the way Postgres does when it replays one. The counters never go backwards.
+    ///
+    /// An online checkpoint's nextOid can be older than the `XLOG_NEXTOID` records written
+    /// while the checkpoint was running, so it is not used, like in Postgres.
+    ///
+    /// Returns 'true' if any counter was updated.
+    pub fn advance_counters(&mut self, xlog_checkpoint: &CheckPoint, shutdown: bool) -> bool {
+        let mut updated = false;
+        if xlog_checkpoint.nextXid.value > self.nextXid.value {
+            self.nextXid = xlog_checkpoint.nextXid;
+            updated = true;
+        }
+        if shutdown && (xlog_checkpoint.nextOid.wrapping_sub(self.nextOid) as i32) > 0 {
+            self.nextOid = xlog_checkpoint.nextOid;
+            updated = true;
+        }
+        if (xlog_checkpoint.nextMulti.wrapping_sub(self.nextMulti) as i32) > 0 {
+            self.nextMulti = xlog_checkpoint.nextMulti;
+            updated = true;
+        }
+        if (xlog_checkpoint
+            .nextMultiOffset
+            .wrapping_sub(self.nextMultiOffset) as i32)
+            > 0
+        {
+            self.nextMultiOffset = xlog_checkpoint.nextMultiOffset;
+            updated = true;
+        }
+        updated
+    }
 }
 
 /// Generate new, empty WAL segment, with correct block headers at the first
 /// page of the segment and the page that contains the given LSN.
 /// We need this segment to start compute node.
-pub fn generate_wal_segment(segno: u64, system_id: u64, lsn: Lsn) -> Result<Bytes, SerializeError> {
-    let mut seg_buf = BytesMut::with_capacity(WAL_SEGMENT_SIZE);
+pub fn generate_wal_segment(
+    segno: u64,
+    system_id: u64,
+    pg_tli: TimeLineID,
+    lsn: Lsn,
+) -> Result<Bytes, SerializeError> {
+    let mut seg_buf = BytesMut::zeroed(WAL_SEGMENT_SIZE);
+    let headers = generate_wal_segment_headers(segno, system_id, pg_tli, lsn, WAL_SEGMENT_SIZE)?;
+    for (offset, header) in headers {
+        seg_buf[offset..offset + header.len()].copy_from_slice(&header);
+    }
+    Ok(seg_buf.freeze())
+}
 
-    let pageaddr = XLogSegNoOffsetToRecPtr(segno, 0, WAL_SEGMENT_SIZE);
+/// The non-zero parts of the segment from [`generate_wal_segment`], with their offsets
+/// in the segment, in ascending order. The rest of the segment is zeroes.
+///
+/// `wal_seg_size` is the segment size of the cluster, which can differ from the compiled
+/// in [`WAL_SEGMENT_SIZE`].
+pub fn generate_wal_segment_headers(
+    segno: u64,
+    system_id: u64,
+    pg_tli: TimeLineID,
+    lsn: Lsn,
+    wal_seg_size: usize,
+) -> Result<Vec<(usize, Bytes)>, SerializeError> {
+    let pageaddr = XLogSegNoOffsetToRecPtr(segno, 0, wal_seg_size);
 
     let page_off = lsn.block_offset();
-    let seg_off = lsn.segment_offset(WAL_SEGMENT_SIZE);
+    let seg_off = lsn.segment_offset(wal_seg_size);
 
     let first_page_only = seg_off < XLOG_BLCKSZ;
     let (shdr_rem_len, infoflags) = if first_page_only {
@@ -356,25 +423,21 @@ pub fn generate_wal_segment(segno: u64, system_id: u64, lsn: Lsn) -> Result<Byte
             XLogPageHeaderData {
                 xlp_magic: XLOG_PAGE_MAGIC as u16,
                 xlp_info: pg_constants::XLP_LONG_HEADER | infoflags,
-                xlp_tli: PG_TLI,
+                xlp_tli: pg_tli,
                 xlp_pageaddr: pageaddr,
                 xlp_rem_len: shdr_rem_len as u32,
                 ..Default::default() // Put 0 in padding fields.
             }
         },
         xlp_sysid: system_id,
-        xlp_seg_size: WAL_SEGMENT_SIZE as u32,
+        xlp_seg_size: wal_seg_size as u32,
         xlp_xlog_blcksz: XLOG_BLCKSZ as u32,
     };
 
-    let hdr_bytes = hdr.encode()?;
-    seg_buf.extend_from_slice(&hdr_bytes);
-
-    //zero out the rest of the file
-    seg_buf.resize(WAL_SEGMENT_SIZE, 0);
+    let mut headers = vec![(0, hdr.encode()?)];
 
     if !first_page_only {
-        let block_offset = lsn.page_offset_in_segment(WAL_SEGMENT_SIZE) as usize;
+        let block_offset = lsn.page_offset_in_segment(wal_seg_size) as usize;
         let header = XLogPageHeaderData {
             xlp_magic: XLOG_PAGE_MAGIC as u16,
             xlp_info: if page_off >= pg_constants::SIZE_OF_PAGE_HEADER as u64 {
@@ -382,7 +445,7 @@ pub fn generate_wal_segment(segno: u64, system_id: u64, lsn: Lsn) -> Result<Byte
             } else {
                 0
             },
-            xlp_tli: PG_TLI,
+            xlp_tli: pg_tli,
             xlp_pageaddr: lsn.page_lsn().0,
             xlp_rem_len: if page_off >= pg_constants::SIZE_OF_PAGE_HEADER as u64 {
                 page_off as u32
@@ -393,13 +456,13 @@ pub fn generate_wal_segment(segno: u64, system_id: u64, lsn: Lsn) -> Result<Byte
         };
         let hdr_bytes = header.encode()?;
 
-        debug_assert!(seg_buf.len() > block_offset + hdr_bytes.len());
+        debug_assert!(wal_seg_size > block_offset + hdr_bytes.len());
         debug_assert_ne!(block_offset, 0);
 
-        seg_buf[block_offset..block_offset + hdr_bytes.len()].copy_from_slice(&hdr_bytes[..]);
+        headers.push((block_offset, hdr_bytes));
     }
 
-    Ok(seg_buf.freeze())
+    Ok(headers)
 }
 
 #[repr(C)]
@@ -0,0 +1,59 @@
+//! Uploads of many small layer files into one directory of the local file system storage, with
+//! one directory fsync per upload, and with the fsyncs of the concurrent uploads batched.
+//!
+//! The fsyncs only cost something on a real disk: run it with the storage on one, e.g.
+//! `TMPDIR=/mnt/disk cargo bench -p remote_storage --bench bench_local_fs`.
+
+use std::time::Duration;
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+use remote_storage::{GenericRemoteStorage, LocalFs, RemotePath};
+
+const LAYER_FILES: usize = 64;
+const LAYER_FILE_SIZE: usize = 64 * 1024;
+
+fn upload_layer_files(c: &mut Criterion) {
+    let runtime = tokio::runtime::Builder::new_current_thread()
+        .enable_all()
+        .build()
+        .unwrap();
+    let contents = vec![0x42; LAYER_FILE_SIZE];
+    let paths = (0..LAYER_FILES)
+        .map(|i| RemotePath::from_string(&format!("tenants/t/timelines/t/layer_{i}")).unwrap())
+        .collect::<Vec<_>>();
+
+    let mut group = c.benchmark_group("upload_layer_files");
+    for fsync_batch_window in [None, Some(Duration::from_millis(1))] {
+        let storage_root = tempfile::tempdir().unwrap();
+        let mut local_fs = LocalFs::new(storage_root.path().to_owned()).unwrap();
+        if let Some(window) = fsync_batch_window {
+            local_fs = local_fs.with_fsync_batch_window(window);
+        }
+        let storage = GenericRemoteStorage::LocalFs(local_fs);
+
+        let id = match fsync_batch_window {
+            Some(window) => format!("batched_{}us", window.as_micros()),
+            None => "unbatched".to_owned(),
+        };
+        group.bench_function(BenchmarkId::from_parameter(id), |b| {
+            b.iter(|| {
+                runtime
+                    .block_on(futures_util::future::try_join_all(paths.iter().map(
+                        |path| {
+                            storage.upload(
+                                std::io::Cursor::new(contents.clone()),
+                                contents.len(),
+                                path,
+                                None,
+                            )
+                        },
+                    )))
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, upload_layer_files);
+criterion_main!(benches);
@@ -0,0 +1,560 @@
+//! Azure Blob Storage wrapper around the `azure_storage_blobs` library.
+//!
+//! Respects `prefix_in_container` property from [`AzureConfig`], using the same
+//! key layout as [`crate::S3Bucket`] does for `prefix_in_bucket`, so the relative
+//! [`RemotePath`]s of the files are identical for both storages.
+
+use std::borrow::Cow;
+use std::collections::HashMap;
+use std::env;
+use std::num::NonZeroU32;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::Duration;
+
+use anyhow::Context;
+use azure_core::error::ErrorKind;
+use azure_core::request_options::{MaxResults, Metadata, Range};
+use azure_identity::DefaultAzureCredential;
+use azure_storage::StorageCredentials;
+use azure_storage_blobs::blob::operations::GetBlobBuilder;
+use azure_storage_blobs::blob::{BlobBlockType, BlockList, CopyStatus};
+use azure_storage_blobs::prelude::{BlobClient, ClientBuilder, ContainerClient};
+use futures_util::stream::{self, Stream, StreamExt, TryStreamExt};
+use http_types::StatusCode;
+use tokio::io::{self, AsyncReadExt};
+use tokio::sync::Semaphore;
+use tokio_util::io::StreamReader;
+use tracing::debug;
+
+use crate::{
+    AzureConfig, Download, ObjectMeta, RatelimitedAsyncRead, RemotePath, RemoteStorage,
+    RemoteStorageError, StorageMetadata, UploadStream, REMOTE_STORAGE_PREFIX_SEPARATOR,
+};
+
+/// The uploads are split into blocks of that size, staged one by one and committed at the
+/// end, so only one block of the data is held in memory at a time.
+const UPLOAD_BLOCK_SIZE: usize = 8 * 1024 * 1024;
+/// Azure rejects block blobs with more blocks than that.
+const MAX_BLOCKS_PER_BLOB: u64 = 50_000;
+/// How often the status of a copy is checked while Azure still copies the blob.
+const COPY_POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Azure Blob Storage container.
+pub struct AzureBlob {
+    client: ContainerClient,
+    prefix_in_container: Option<String>,
+    max_keys_per_list_response: Option<NonZeroU32>,
+    // Azure throttles the storage account after a certain number of requests per second,
+    // same as S3 does with the bucket, so the same kind of limiter is used.
+    concurrency_limiter: Arc<Semaphore>,
+}
+
+impl AzureBlob {
+    /// Creates the Azure storage, errors if incorrect Azure configuration provided.
+    pub fn new(azure_config: &AzureConfig) -> anyhow::Result<Self> {
+        debug!(
+            "Creating azure remote storage for azure container {}",
+            azure_config.container_name
+        );
+
+        let account = match &azure_config.storage_account {
+            Some(account) => account.clone(),
+            None => env::var("AZURE_STORAGE_ACCOUNT")
+                .context("'storage_account' is not set and AZURE_STORAGE_ACCOUNT is missing")?,
+        };
+
+        // Prefer the access key, if given, and fall back to the token based credentials
+        // (managed identity, Azure CLI, etc.) otherwise.
+        let credentials = match env::var("AZURE_STORAGE_ACCESS_KEY") {
+            Ok(access_key) => StorageCredentials::access_key(account.clone(), access_key),
+            Err(_) => {
+                StorageCredentials::token_credential(Arc::new(DefaultAzureCredential::default()))
+            }
+        };
+
+        let client = ClientBuilder::new(account, credentials)
+            .container_client(azure_config.container_name.clone());
+
+        let max_keys_per_list_response = azure_config
+            .max_keys_per_list_response
+            .map(|limit| {
+                u32::try_from(limit)
+                    .ok()
+                    .and_then(NonZeroU32::new)
+                    .context("'max_keys_per_list_response' should be a positive integer")
+            })
+            .transpose()?;
+
+        let prefix_in_container = azure_config.prefix_in_container.as_deref().map(|prefix| {
+            prefix
+                .trim_matches(REMOTE_STORAGE_PREFIX_SEPARATOR)
+                .to_string()
+        });
+
+        Ok(Self {
+            client,
+            prefix_in_container,
+            max_keys_per_list_response,
+            concurrency_limiter: Arc::new(Semaphore::new(azure_config.concurrency_limit.get())),
+        })
+    }
+
+    pub fn relative_path_to_name(&self, path: &RemotePath) -> String {
+        assert_eq!(std::path::MAIN_SEPARATOR, REMOTE_STORAGE_PREFIX_SEPARATOR);
+        let path_string = path
+            .get_path()
+            .to_string_lossy()
+            .trim_end_matches(REMOTE_STORAGE_PREFIX_SEPARATOR)
+            .to_string();
+        match &self.prefix_in_container {
+            Some(prefix) => prefix.clone() + "/" + &path_string,
+            None => path_string,
+        }
+    }
+
+    fn name_to_relative_path(&self, key: &str) -> RemotePath {
+        let stripped = match &self.prefix_in_container {
+            Some(prefix) if !prefix.is_empty() => key
+                .strip_prefix(prefix.as_str())
+                .and_then(|key| key.strip_prefix(REMOTE_STORAGE_PREFIX_SEPARATOR)),
+            _ => Some(key),
+        };
+        let relative_path = match stripped {
+            Some(stripped) => stripped,
+            // we rely on Azure to return properly prefixed paths
+            // for requests with a certain prefix
+            None => panic!(
+                "Key {} does not start with container prefix {:?}",
+                key, self.prefix_in_container
+            ),
+        };
+        RemotePath(
+            relative_path
+                .split(REMOTE_STORAGE_PREFIX_SEPARATOR)
+                .collect(),
+        )
+    }
+
+    async fn permit(&self) -> tokio::sync::SemaphorePermit<'_> {
+        self.concurrency_limiter
+            .acquire()
+            .await
+            .expect("semaphore is never closed")
+    }
+
+    async fn owned_permit(&self) -> tokio::sync::OwnedSemaphorePermit {
+        Arc::clone(&self.concurrency_limiter)
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed")
+    }
+
+    async fn download_for_builder(
+        &self,
+        blob_client: &BlobClient,
+        builder: GetBlobBuilder,
+    ) -> Result<Download, RemoteStorageError> {
+        let permit = self.owned_permit().await;
+
+        // The blob metadata is not returned in the get blob responses,
+        // so it has to be queried separately.
+        let metadata = blob_client
+            .get_metadata()
+            .await
+            .map_err(to_storage_error)?
+            .metadata;
+
+        // The SDK gets the blob in chunks, a request per chunk. The first one is awaited here,
+        // so a missing blob fails the download call and not the first read of the stream.
+        let mut chunks = builder.into_stream();
+        let first_chunk = match chunks.next().await {
+            Some(chunk) => Some(chunk.map_err(to_storage_error)?),
+            None => None,
+        };
+        let body = stream::iter(first_chunk.map(Ok))
+            .chain(chunks)
+            .map_ok(|chunk| chunk.data.map_err(to_io_error))
+            .map_err(to_io_error)
+            .try_flatten();
+
+        Ok(Download {
+            download_stream: Box::pin(RatelimitedAsyncRead::new(
+                permit,
+                StreamReader::new(SyncStream::new(body)),
+            )),
+            metadata: Some(from_azure_metadata(metadata)),
+        })
+    }
+}
+
+/// The SDK streams are `Send` but not `Sync`, which [`Download`] streams have to be.
+/// Polling needs `&mut` access anyway, so the mutex is never locked, only used for its
+/// `Sync` implementation.
+struct SyncStream<S>(Mutex<Pin<Box<S>>>);
+
+impl<S> SyncStream<S> {
+    fn new(inner: S) -> Self {
+        Self(Mutex::new(Box::pin(inner)))
+    }
+}
+
+impl<S: Stream> Stream for SyncStream<S> {
+    type Item = S::Item;
+
+    fn poll_next(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<Option<Self::Item>> {
+        self.get_mut()
+            .0
+            .get_mut()
+            .expect("the mutex is never locked")
+            .as_mut()
+            .poll_next(cx)
+    }
+}
+
+fn to_io_error(error: azure_core::Error) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::Other, error)
+}
+
+/// Azure only accepts metadata names that are C# identifiers. The other names are rejected
+/// here: Azure would reject them only with the block list, after all blocks are uploaded.
+fn to_azure_metadata(metadata: StorageMetadata) -> Result<Metadata, RemoteStorageError> {
+    let mut res = Metadata::new();
+    for (k, v) in metadata.0.into_iter() {
+        if !is_valid_metadata_name(&k) {
+            return Err(RemoteStorageError::Permanent(anyhow::anyhow!(
+                "Invalid Azure metadata name '{k}', expected a C# identifier"
+            )));
+        }
+        res.insert(k, v);
+    }
+    Ok(res)
+}
+
+fn is_valid_metadata_name(name: &str) -> bool {
+    let mut chars = name.chars();
+    chars
+        .next()
+        .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+        && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+}
+
+fn from_azure_metadata(mut metadata: Metadata) -> StorageMetadata {
+    let metadata = std::mem::take(metadata.as_mut())
+        .into_iter()
+        .map(|(name, value)| (name, String::from_utf8_lossy(&value).into_owned()))
+        .collect::<HashMap<_, _>>();
+    StorageMetadata(metadata)
+}
+
+fn http_status(error: &azure_core::Error) -> Option<StatusCode> {
+    error.as_http_error().map(|e| e.status())
+}
+
+fn to_storage_error(error: azure_core::Error) -> RemoteStorageError {
+    if matches!(error.kind(), ErrorKind::Credential) {
+        return RemoteStorageError::Unauthorized(anyhow::Error::new(error));
+    }
+    match http_status(&error) {
+        Some(status) => {
+            RemoteStorageError::from_http_status(u16::from(status), anyhow::Error::new(error))
+        }
+        None => RemoteStorageError::Transient(anyhow::Error::new(error)),
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for AzureBlob {
+    /// See the doc for `RemoteStorage::list_prefixes`
+    async fn list_prefixes(
+        &self,
+        prefix: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        // get the passed prefix or if it is not set use prefix_in_container value
+        let list_prefix = prefix
+            .map(|p| self.relative_path_to_name(p))
+            .or_else(|| self.prefix_in_container.clone())
+            .map(|mut p| {
+                // required to end with a separator
+                // otherwise request will return only the entry of a prefix
+                if !p.ends_with(REMOTE_STORAGE_PREFIX_SEPARATOR) {
+                    p.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
+                }
+                p
+            });
+
+        let mut builder = self
+            .client
+            .list_blobs()
+            .delimiter(REMOTE_STORAGE_PREFIX_SEPARATOR.to_string());
+        if let Some(prefix) = list_prefix {
+            builder = builder.prefix(Cow::from(prefix));
+        }
+        if let Some(limit) = self.max_keys_per_list_response {
+            builder = builder.max_results(MaxResults::new(limit));
+        }
+
+        // every page is a separate request, the pagination markers are handled by the SDK
+        let mut response = builder.into_stream();
+        let mut document_keys = Vec::new();
+        loop {
+            let _guard = self.permit().await;
+            let Some(page) = response.next().await else {
+                break;
+            };
+            let page = page.map_err(to_storage_error)?;
+            document_keys.extend(
+                page.blobs
+                    .prefixes()
+                    .map(|prefix| self.name_to_relative_path(&prefix.name)),
+            );
+        }
+
+        Ok(document_keys)
+    }
+
+    /// See the doc for `RemoteStorage::list_files`
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        let folder_name = folder.map(|p| self.relative_path_to_name(p)).or_else(|| {
+            self.prefix_in_container
+                .as_ref()
+                .filter(|prefix| !prefix.is_empty())
+                .map(|prefix| format!("{prefix}{REMOTE_STORAGE_PREFIX_SEPARATOR}"))
+        });
+
+        let mut builder = self.client.list_blobs();
+        if let Some(folder_name) = folder_name {
+            builder = builder.prefix(Cow::from(folder_name));
+        }
+        if let Some(limit) = self.max_keys_per_list_response {
+            builder = builder.max_results(MaxResults::new(limit));
+        }
+
+        let mut response = builder.into_stream();
+        let mut all_files = Vec::new();
+        loop {
+            let _guard = self.permit().await;
+            let Some(page) = response.next().await else {
+                break;
+            };
+            let page = page.map_err(to_storage_error)?;
+            all_files.extend(
+                page.blobs
+                    .blobs()
+                    .map(|blob| self.name_to_relative_path(&blob.name)),
+            );
+        }
+
+        Ok(all_files)
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        Some(MAX_BLOCKS_PER_BLOB * UPLOAD_BLOCK_SIZE as u64)
+    }
+
+    async fn upload(
+        &self,
+        mut from: UploadStream,
+        data_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        let _guard = self.permit().await;
+        let blob_client = self.client.blob_client(self.relative_path_to_name(to));
+        let metadata = metadata.map(to_azure_metadata).transpose()?;
+
+        // Every block has to be seekable for the SDK retries, so a block is buffered before
+        // it is staged. The staged blocks become the blob only after the block list is
+        // committed, so a failed upload leaves the previous version of the blob intact.
+        let mut blocks = Vec::new();
+        let mut uploaded = 0;
+        loop {
+            let remaining = data_size_bytes.saturating_sub(uploaded);
+            let mut block = Vec::with_capacity(UPLOAD_BLOCK_SIZE.min(remaining));
+            (&mut from)
+                .take(UPLOAD_BLOCK_SIZE as u64)
+                .read_to_end(&mut block)
+                .await
+                .context("Failed to read the upload data")?;
+            // An empty block list commits an empty blob.
+            if block.is_empty() {
+                break;
+            }
+            uploaded += block.len();
+            if uploaded > data_size_bytes {
+                break;
+            }
+
+            // All block ids of a blob have to be of the same length.
+            let block_id = format!("{:08}", blocks.len());
+            blob_client
+                .put_block(block_id.clone(), block)
+                .await
+                .map_err(to_storage_error)?;
+            blocks.push(BlobBlockType::new_uncommitted(block_id));
+        }
+        if uploaded != data_size_bytes {
+            return Err(RemoteStorageError::Permanent(anyhow::anyhow!(
+                "Upload data size {uploaded} does not match the expected size {data_size_bytes}",
+            )));
+        }
+
+        let mut builder = blob_client.put_block_list(BlockList { blocks });
+        if let Some(metadata) = metadata {
+            builder = builder.metadata(metadata);
+        }
+        builder.await.map_err(to_storage_error)?;
+
+        Ok(())
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
+        let blob_client = self.client.blob_client(self.relative_path_to_name(from));
+        let builder = blob_client.get();
+        self.download_for_builder(&blob_client, builder).await
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, RemoteStorageError> {
+        // Gets translated into the same `Range: bytes=start-end` header as S3 uses,
+        // with the end made inclusive by the SDK, so an empty range cannot be requested:
+        // the blob properties are fetched instead, to fail on a missing blob still.
+        if let Some(end_exclusive) = end_exclusive {
+            if end_exclusive < start_inclusive {
+                return Err(RemoteStorageError::Permanent(anyhow::anyhow!(
+                    "Invalid range, start ({start_inclusive}) is greater than end_exclusive ({end_exclusive})"
+                )));
+            }
+            if end_exclusive == start_inclusive {
+                return Ok(Download {
+                    download_stream: Box::pin(io::empty()),
+                    metadata: self.stat(from).await?.metadata,
+                });
+            }
+        }
+        // The end past the blob size is fine, the blob is read up to its end then.
+        let range = Range::new(start_inclusive, end_exclusive.unwrap_or(u64::MAX));
+
+        let blob_client = self.client.blob_client(self.relative_path_to_name(from));
+        let builder = blob_client.get().range(range);
+        self.download_for_builder(&blob_client, builder).await
+    }
+
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        let _guard = self.permit().await;
+        let blob_client = self.client.blob_client(self.relative_path_to_name(path));
+
+        // Unlike the get blob responses, the properties come with the blob metadata.
+        let blob = blob_client
+            .get_properties()
+            .await
+            .map_err(to_storage_error)?
+            .blob;
+        Ok(ObjectMeta {
+            size: blob.properties.content_length,
+            last_modified: Some(blob.properties.last_modified.into()),
+            metadata: blob.metadata.map(StorageMetadata),
+        })
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+        let _guard = self.permit().await;
+        let blob_client = self.client.blob_client(self.relative_path_to_name(path));
+
+        match blob_client.delete().await {
+            Ok(_) => Ok(()),
+            // S3 does not error on deleting a missing object, keep the same semantics
+            Err(e) if http_status(&e) == Some(StatusCode::NotFound) => Ok(()),
+            Err(e) => Err(to_storage_error(e)),
+        }
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
+        // Azure has batch deletes, but the SDK does not support them yet
+        for path in paths {
+            self.delete(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        let _guard = self.permit().await;
+        let source_url = self
+            .client
+            .blob_client(self.relative_path_to_name(from))
+            .url()
+            .map_err(to_storage_error)?;
+        let blob_client = self.client.blob_client(self.relative_path_to_name(to));
+
+        // Copies within the storage account are usually done by the time Azure responds,
+        // otherwise the destination properties are polled until the copy ends.
+        let mut copy_status = blob_client
+            .copy(source_url)
+            .await
+            .map_err(to_storage_error)?
+            .copy_status;
+        loop {
+            match copy_status {
+                CopyStatus::Success => return Ok(()),
+                CopyStatus::Pending => {}
+                status => {
+                    return Err(anyhow::anyhow!(
+                        "Copy of {from} to {to} did not succeed: {status:?}"
+                    )
+                    .into())
+                }
+            }
+            tokio::time::sleep(COPY_POLL_INTERVAL).await;
+            copy_status = blob_client
+                .get_properties()
+                .await
+                .map_err(to_storage_error)?
+                .blob
+                .properties
+                .copy_status
+                .with_context(|| format!("No copy status for {to} after its copy"))?;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ZstdDictionary;
+
+    #[test]
+    fn metadata_roundtrips() {
+        let metadata = StorageMetadata::from([
+            ("checksum_crc32c", "0badc0de"),
+            ("compression", "zstd"),
+            ("_1", "value"),
+        ]);
+        let azure_metadata = to_azure_metadata(metadata.clone()).unwrap();
+        assert_eq!(from_azure_metadata(azure_metadata), metadata);
+
+        // The metadata of the dictionary-compressed objects.
+        let dictionary = ZstdDictionary::new("dictionary".to_owned(), Vec::new());
+        let metadata = dictionary.record_in_metadata(None).unwrap();
+        let azure_metadata = to_azure_metadata(metadata.clone()).unwrap();
+        assert_eq!(from_azure_metadata(azure_metadata), metadata);
+
+        for name in ["checksum-crc32c", "1st", ""] {
+            let metadata = StorageMetadata::from([(name, "value")]);
+            assert!(
+                matches!(
+                    to_azure_metadata(metadata),
+                    Err(RemoteStorageError::Permanent(_))
+                ),
+                "{name}"
+            );
+        }
+    }
+}
@@ -0,0 +1,379 @@
+//! Compression of the objects uploaded to the remote storage.
+//!
+//! The codec is selected with the `compression` option of [`crate::RemoteStorageConfig`]
+//! and applies to new uploads only: every compressed object records its codec in the
+//! [`StorageMetadata`], so the objects uploaded before the option was changed are still
+//! decompressed correctly. Objects without such record are not compressed.
+//!
+//! Small objects compress poorly on their own, a [`ZstdDictionary`] trained on similar ones
+//! helps. The dictionary is an object of its own, the objects compressed with it record its
+//! name, and it has to be downloaded before they can be decompressed.
+
+use std::{fmt, pin::Pin, str::FromStr, sync::Arc, sync::Mutex};
+
+use async_compression::tokio::bufread::{GzipDecoder, GzipEncoder, ZstdDecoder, ZstdEncoder};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, BufReader};
+
+use crate::StorageMetadata;
+
+const COMPRESSION_METADATA_KEY: &str = "compression";
+/// Azure only accepts metadata names that are C# identifiers, hence the underscore.
+const ZSTD_DICTIONARY_METADATA_KEY: &str = "zstd_dictionary";
+/// The key the objects uploaded by the older versions have their dictionary under.
+const LEGACY_ZSTD_DICTIONARY_METADATA_KEY: &str = "zstd-dictionary";
+
+/// Codec to compress the uploaded objects with.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Compression {
+    /// Upload the data as is, e.g. for already compressed files.
+    #[default]
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl Compression {
+    /// Returns the codec the object was uploaded with.
+    pub fn from_metadata(metadata: Option<&StorageMetadata>) -> anyhow::Result<Self> {
+        match metadata.and_then(|metadata| metadata.get(COMPRESSION_METADATA_KEY)) {
+            Some(codec) => codec.parse(),
+            None => Ok(Compression::None),
+        }
+    }
+
+    /// Adds the codec to the metadata of the object being uploaded.
+    pub fn record_in_metadata(&self, metadata: Option<StorageMetadata>) -> Option<StorageMetadata> {
+        if *self == Compression::None {
+            return metadata;
+        }
+        let mut metadata = metadata.unwrap_or_default();
+        metadata
+            .0
+            .insert(COMPRESSION_METADATA_KEY.to_owned(), self.to_string());
+        Some(metadata)
+    }
+
+    /// Media type of the objects compressed with this codec, `None` if they are stored as is.
+    pub(crate) fn media_type(&self) -> Option<&'static str> {
+        match self {
+            Compression::None => None,
+            Compression::Gzip => Some("application/gzip"),
+            Compression::Zstd => Some("application/zstd"),
+        }
+    }
+
+    /// Compresses `data` into `out` as it is read, and returns the compressed size. The
+    /// storages need to know the size of an upload before it starts, so a large object is
+    /// compressed into a temporary file first, not into memory.
+    pub async fn compress(
+        &self,
+        data: impl AsyncRead + Unpin,
+        out: &mut (impl AsyncWrite + Unpin),
+    ) -> io::Result<u64> {
+        let mut reader = BufReader::new(data);
+        let compressed_size = match self {
+            Compression::None => io::copy_buf(&mut reader, out).await?,
+            Compression::Gzip => io::copy(&mut GzipEncoder::new(reader), out).await?,
+            Compression::Zstd => io::copy(&mut ZstdEncoder::new(reader), out).await?,
+        };
+        out.flush().await?;
+        Ok(compressed_size)
+    }
+
+    /// Wraps the download stream of an object compressed with this codec into a decoder.
+    pub fn decompress(
+        &self,
+        stream: Pin<Box<dyn AsyncRead + Unpin + Send + Sync>>,
+    ) -> Pin<Box<dyn AsyncRead + Unpin + Send + Sync>> {
+        match self {
+            Compression::None => stream,
+            Compression::Gzip => {
+                Box::pin(SyncReader::new(GzipDecoder::new(BufReader::new(stream))))
+            }
+            Compression::Zstd => {
+                Box::pin(SyncReader::new(ZstdDecoder::new(BufReader::new(stream))))
+            }
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Compression::None),
+            "gzip" => Ok(Compression::Gzip),
+            "zstd" => Ok(Compression::Zstd),
+            _ => anyhow::bail!("unknown compression '{s}', expected one of: none, gzip, zstd"),
+        }
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Compression::None => "none",
+            Compression::Gzip => "gzip",
+            Compression::Zstd => "zstd",
+        })
+    }
+}
+
+/// A zstd dictionary, for the small objects that have a lot in common with each other but
+/// little within themselves. The objects compressed with it are single zstd frames, they can
+/// only be decompressed in memory, as a whole.
+#[derive(Clone)]
+pub struct ZstdDictionary {
+    /// Name of the dictionary object, recorded in the metadata of the objects compressed with it.
+    name: String,
+    data: Arc<[u8]>,
+}
+
+impl fmt::Debug for ZstdDictionary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ZstdDictionary")
+            .field("name", &self.name)
+            .field("size", &self.data.len())
+            .finish()
+    }
+}
+
+impl ZstdDictionary {
+    /// The dictionary downloaded from the object `name`.
+    pub fn new(name: String, data: Vec<u8>) -> Self {
+        ZstdDictionary {
+            name,
+            data: data.into(),
+        }
+    }
+
+    /// Trains a dictionary of at most `max_size` bytes on `samples`. Fails if the samples are
+    /// too few or too small to train on.
+    pub fn train<S: AsRef<[u8]>>(name: String, samples: &[S], max_size: usize) -> io::Result<Self> {
+        let data = zstd::dict::from_samples(samples, max_size)?;
+        Ok(Self::new(name, data))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The contents of the dictionary object.
+    pub fn data(&self) -> &[u8] {
+        &self.data
+    }
+
+    /// Returns the name of the dictionary the object was compressed with, if any.
+    pub fn name_from_metadata(metadata: Option<&StorageMetadata>) -> Option<&str> {
+        let metadata = metadata?;
+        metadata
+            .get(ZSTD_DICTIONARY_METADATA_KEY)
+            .or_else(|| metadata.get(LEGACY_ZSTD_DICTIONARY_METADATA_KEY))
+    }
+
+    /// Adds the dictionary, and the zstd codec, to the metadata of the object being uploaded.
+    pub fn record_in_metadata(&self, metadata: Option<StorageMetadata>) -> Option<StorageMetadata> {
+        let mut metadata = Compression::Zstd
+            .record_in_metadata(metadata)
+            .expect("zstd is recorded in the metadata");
+        metadata
+            .0
+            .insert(ZSTD_DICTIONARY_METADATA_KEY.to_owned(), self.name.clone());
+        Some(metadata)
+    }
+
+    /// Compresses the whole `data` in memory with the dictionary, off the async executor.
+    pub async fn compress(&self, data: Vec<u8>) -> io::Result<Vec<u8>> {
+        let dictionary = Arc::clone(&self.data);
+        spawn_blocking(move || {
+            zstd::bulk::Compressor::with_dictionary(zstd::DEFAULT_COMPRESSION_LEVEL, &dictionary)?
+                .compress(&data)
+        })
+        .await
+    }
+
+    /// Reads the whole download stream of an object compressed with the dictionary, and
+    /// decompresses it.
+    pub async fn decompress(
+        &self,
+        mut stream: Pin<Box<dyn AsyncRead + Unpin + Send + Sync>>,
+    ) -> io::Result<Vec<u8>> {
+        let mut compressed = Vec::new();
+        stream.read_to_end(&mut compressed).await?;
+        let dictionary = Arc::clone(&self.data);
+        spawn_blocking(move || {
+            let mut decompressed = Vec::new();
+            std::io::Read::read_to_end(
+                &mut zstd::stream::read::Decoder::with_dictionary(
+                    std::io::Cursor::new(compressed),
+                    &dictionary,
+                )?,
+                &mut decompressed,
+            )?;
+            Ok(decompressed)
+        })
+        .await
+    }
+}
+
+/// Runs the CPU-bound `f` on a blocking thread.
+async fn spawn_blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> io::Result<T> {
+    tokio::task::spawn_blocking(f)
+        .await
+        .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?
+}
+
+/// The decoder contexts are `Send`, but not `Sync`, which the download streams have to be.
+/// The reader is only ever polled through `&mut`, so the mutex is never actually locked.
+struct SyncReader<R>(Mutex<R>);
+
+impl<R> SyncReader<R> {
+    fn new(inner: R) -> Self {
+        SyncReader(Mutex::new(inner))
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for SyncReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let inner = self
+            .get_mut()
+            .0
+            .get_mut()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        Pin::new(inner).poll_read(cx, buf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+    use std::path::Path;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{LocalFs, RemotePath, RemoteStorage};
+
+    #[tokio::test]
+    async fn compressed_upload_roundtrip() -> anyhow::Result<()> {
+        let storage_root = tempdir()?;
+        let storage = LocalFs::new(storage_root.path().to_owned())?;
+
+        let original: Vec<u8> = (0..100_000u32)
+            .flat_map(|i| (i % 251).to_le_bytes())
+            .collect();
+
+        for compression in [Compression::None, Compression::Gzip, Compression::Zstd] {
+            let path = RemotePath::new(Path::new(&format!("timeline/layer_{compression}")))?;
+
+            let mut compressed = Vec::new();
+            let compressed_size = compression
+                .compress(Cursor::new(original.clone()), &mut compressed)
+                .await?;
+            assert_eq!(compressed_size, compressed.len() as u64);
+            if compression != Compression::None {
+                assert!(
+                    compressed.len() < original.len(),
+                    "{compression} should compress"
+                );
+            }
+            storage
+                .upload(
+                    Box::new(Cursor::new(compressed)),
+                    compressed_size as usize,
+                    &path,
+                    compression.record_in_metadata(None),
+                )
+                .await?;
+
+            let download = storage.download(&path).await?;
+            let recorded = Compression::from_metadata(download.metadata.as_ref())?;
+            assert_eq!(recorded, compression);
+
+            let mut restored = Vec::new();
+            recorded
+                .decompress(download.download_stream)
+                .read_to_end(&mut restored)
+                .await?;
+            assert!(
+                restored == original,
+                "{compression} should restore the original data"
+            );
+        }
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn dictionary_compressed_upload_roundtrip() -> anyhow::Result<()> {
+        let storage_root = tempdir()?;
+        let storage = LocalFs::new(storage_root.path().to_owned())?;
+
+        // Small files, similar to each other and not to themselves.
+        let file = |i: u32| -> Vec<u8> {
+            let mut file =
+                format!("header of a small file, version 15, flags 0x{:x}\n", i % 7).into_bytes();
+            file.extend((0..64u32).flat_map(|j| (j.wrapping_mul(2654435761) ^ i).to_le_bytes()));
+            file.extend_from_slice(b"and a footer that all of these small files share\n");
+            file
+        };
+        let samples = (0..1000).map(file).collect::<Vec<_>>();
+        let dictionary = ZstdDictionary::train("dictionary".to_owned(), &samples, 4096)?;
+
+        let original = file(5000);
+        let compressed = dictionary.compress(original.clone()).await?;
+        assert!(
+            (compressed.len() as u64)
+                < Compression::Zstd
+                    .compress(Cursor::new(original.clone()), &mut Vec::new())
+                    .await?,
+            "the dictionary should help"
+        );
+
+        let path = RemotePath::new(Path::new("timeline/small_layer"))?;
+        let compressed_size = compressed.len();
+        storage
+            .upload(
+                Box::new(Cursor::new(compressed)),
+                compressed_size,
+                &path,
+                dictionary.record_in_metadata(None),
+            )
+            .await?;
+
+        let download = storage.download(&path).await?;
+        assert_eq!(
+            ZstdDictionary::name_from_metadata(download.metadata.as_ref()),
+            Some("dictionary")
+        );
+        assert_eq!(
+            Compression::from_metadata(download.metadata.as_ref())?,
+            Compression::Zstd
+        );
+        let restored = dictionary.decompress(download.download_stream).await?;
+        assert!(
+            restored == original,
+            "the dictionary should restore the original data"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn dictionary_name_from_legacy_metadata() {
+        let metadata = StorageMetadata::from([
+            (COMPRESSION_METADATA_KEY, "zstd"),
+            (LEGACY_ZSTD_DICTIONARY_METADATA_KEY, "dictionary"),
+        ]);
+        assert_eq!(
+            ZstdDictionary::name_from_metadata(Some(&metadata)),
+            Some("dictionary")
+        );
+    }
+}
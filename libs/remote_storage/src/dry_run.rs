@@ -0,0 +1,249 @@
+//! This module provides a wrapper around a real RemoteStorage implementation that
+//! logs the uploads and deletions instead of performing them, so that the plan of
+//! a sync can be checked before any changes are made to the remote storage.
+//!
+//! Listings and downloads still go to the real storage, they do not change it and
+//! make the plan match what a real sync would do.
+//!
+//! The skipped operations add up into a [`DryRunPlan`], logged with each of them.
+use std::{fmt, sync::Mutex};
+
+use tracing::info;
+
+use crate::{
+    Download, ListingStream, ObjectMeta, RemotePath, RemoteStorage, RemoteStorageError,
+    StorageMetadata, UploadStream,
+};
+
+/// The operations a [`DryRunWrapper`] skipped so far, and the sizes of their objects.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct DryRunPlan {
+    pub uploads: u64,
+    pub upload_bytes: u64,
+    pub deletions: u64,
+    pub deletion_bytes: u64,
+    pub copies: u64,
+    pub copy_bytes: u64,
+}
+
+impl fmt::Display for DryRunPlan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} uploads ({} bytes), {} deletions ({} bytes), {} copies ({} bytes)",
+            self.uploads,
+            self.upload_bytes,
+            self.deletions,
+            self.deletion_bytes,
+            self.copies,
+            self.copy_bytes
+        )
+    }
+}
+
+pub struct DryRunWrapper {
+    inner: crate::GenericRemoteStorage,
+    plan: Mutex<DryRunPlan>,
+}
+
+impl DryRunWrapper {
+    pub fn new(inner: crate::GenericRemoteStorage) -> Self {
+        DryRunWrapper {
+            inner,
+            plan: Mutex::default(),
+        }
+    }
+
+    /// What a real sync would have done so far.
+    pub fn plan(&self) -> DryRunPlan {
+        *self.plan.lock().unwrap()
+    }
+
+    /// Adds a skipped operation to the plan, returning the plan so far.
+    fn record(&self, add: impl FnOnce(&mut DryRunPlan)) -> DryRunPlan {
+        let mut plan = self.plan.lock().unwrap();
+        add(&mut plan);
+        *plan
+    }
+
+    /// The size of an object on the real storage, `None` if there is no such object.
+    async fn object_size(&self, path: &RemotePath) -> Result<Option<u64>, RemoteStorageError> {
+        match self.inner.stat(path).await {
+            Ok(meta) => Ok(Some(meta.size)),
+            Err(RemoteStorageError::NotFound) => Ok(None),
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn record_deletion(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+        let Some(size) = self.object_size(path).await? else {
+            info!("dry run: skipping deletion of {path}, it does not exist");
+            return Ok(());
+        };
+        let plan = self.record(|plan| {
+            plan.deletions += 1;
+            plan.deletion_bytes += size;
+        });
+        info!("dry run: skipping deletion of {size} bytes at {path}, plan so far: {plan}");
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for DryRunWrapper {
+    async fn list_prefixes(
+        &self,
+        prefix: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        self.inner.list_prefixes(prefix).await
+    }
+
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        self.inner.list_files(folder).await
+    }
+
+    fn list_files_stream<'a>(&'a self, folder: Option<&'a RemotePath>) -> ListingStream<'a> {
+        self.inner.list_files_stream(folder)
+    }
+
+    async fn upload(
+        &self,
+        _data: UploadStream,
+        data_size_bytes: usize,
+        to: &RemotePath,
+        _metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        let plan = self.record(|plan| {
+            plan.uploads += 1;
+            plan.upload_bytes += data_size_bytes as u64;
+        });
+        info!("dry run: skipping upload of {data_size_bytes} bytes to {to}, plan so far: {plan}");
+        Ok(())
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
+        info!("dry run: downloading {from}");
+        self.inner.download(from).await
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, RemoteStorageError> {
+        info!("dry run: downloading {from} from byte {start_inclusive} to {end_exclusive:?}");
+        self.inner
+            .download_byte_range(from, start_inclusive, end_exclusive)
+            .await
+    }
+
+    fn efficient_byte_ranges(&self) -> bool {
+        self.inner.efficient_byte_ranges()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        self.inner.stat(path).await
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+        self.record_deletion(path).await
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
+        for path in paths {
+            self.record_deletion(path).await?;
+        }
+        Ok(())
+    }
+
+    /// Lists the objects that would be deleted.
+    async fn delete_prefix(&self, prefix: &RemotePath) -> Result<usize, RemoteStorageError> {
+        let paths = match self.inner.list_files(Some(prefix)).await {
+            Ok(paths) => paths,
+            Err(RemoteStorageError::NotFound) => return Ok(0),
+            Err(e) => return Err(e),
+        };
+        let mut deleted = 0;
+        for path in paths
+            .iter()
+            .filter(|path| path.get_path().starts_with(prefix.get_path()))
+        {
+            self.record_deletion(path).await?;
+            deleted += 1;
+        }
+        Ok(deleted)
+    }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        let size = self
+            .object_size(from)
+            .await?
+            .ok_or(RemoteStorageError::NotFound)?;
+        let plan = self.record(|plan| {
+            plan.copies += 1;
+            plan.copy_bytes += size;
+        });
+        info!("dry run: skipping copy of {size} bytes from {from} to {to}, plan so far: {plan}");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{GenericRemoteStorage, LocalFs};
+
+    #[tokio::test]
+    async fn dry_run_does_not_change_the_storage() -> anyhow::Result<()> {
+        let storage_root = tempdir()?;
+        let real = GenericRemoteStorage::LocalFs(LocalFs::new(storage_root.path().to_owned())?);
+        let existing = RemotePath::from_string("existing")?;
+        real.upload(Cursor::new(b"data".to_vec()), 4, &existing, None)
+            .await?;
+
+        let dry_run = DryRunWrapper::new(real.clone());
+        let new = RemotePath::from_string("new")?;
+        dry_run
+            .upload(Box::new(Cursor::new(b"data".to_vec())), 4, &new, None)
+            .await?;
+        dry_run.delete(&existing).await?;
+        dry_run.copy(&existing, &new).await?;
+        assert!(matches!(
+            dry_run.copy(&new, &existing).await,
+            Err(RemoteStorageError::NotFound)
+        ));
+        dry_run.delete(&new).await?;
+
+        assert_eq!(
+            dry_run.plan(),
+            DryRunPlan {
+                uploads: 1,
+                upload_bytes: 4,
+                deletions: 1,
+                deletion_bytes: 4,
+                copies: 1,
+                copy_bytes: 4,
+            }
+        );
+
+        assert_eq!(dry_run.list_files(None).await?, vec![existing.clone()]);
+        assert!(dry_run.download(&existing).await.is_ok());
+        assert!(matches!(
+            real.download(&new).await,
+            Err(RemoteStorageError::NotFound)
+        ));
+        Ok(())
+    }
+}
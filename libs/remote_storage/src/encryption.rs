@@ -0,0 +1,788 @@
+//! This module provides a wrapper around a real RemoteStorage implementation that
+//! encrypts the uploaded objects on the client side, with AES-256-GCM and a key
+//! that never leaves the client, and decrypts them on download.
+//!
+//! The encrypted objects are marked in their [`StorageMetadata`], so the objects uploaded
+//! before the encryption was enabled are still downloaded as they are. The downloads return
+//! the metadata without the mark, as their contents are decrypted: uploading them to another
+//! storage as they are doesn't mark the plain contents as encrypted.
+//!
+//! An encrypted object starts with a header: the format magic and version, and a random nonce
+//! prefix of the object. The data follows in chunks of [`CHUNK_LEN`] bytes, the last one may
+//! be shorter, each sealed separately with its own tag, as in the STREAM construction: the nonce
+//! of a chunk is the prefix followed by the chunk number, and the header with the flag of the
+//! last chunk is its associated data. So the objects are encrypted and decrypted as they
+//! stream, and the chunks can't be reordered, dropped or moved to another object, nor can the
+//! object be truncated at a chunk boundary. A byte range of the data maps to a range of the
+//! chunks, downloaded along with the header.
+use std::path::Path;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{ready, Poll};
+
+use anyhow::{anyhow, Context};
+use ring::aead::{Aad, LessSafeKey, Nonce, UnboundKey, AES_256_GCM, NONCE_LEN};
+use ring::rand::{SecureRandom, SystemRandom};
+use tokio::io::{self, AsyncReadExt};
+
+use crate::{
+    Download, ListingStream, ObjectMeta, RemotePath, RemoteStorage, RemoteStorageError,
+    StorageMetadata, UploadStream,
+};
+
+const ENCRYPTION_METADATA_KEY: &str = "encryption";
+const ENCRYPTION_ALGORITHM: &str = "aes-256-gcm";
+
+const FORMAT_MAGIC: &[u8; 4] = b"ZENC";
+const FORMAT_VERSION: u8 = 1;
+/// The nonce of a chunk is this prefix, random for every object, and the 4 byte chunk number.
+const NONCE_PREFIX_LEN: usize = NONCE_LEN - 4;
+const HEADER_LEN: usize = FORMAT_MAGIC.len() + 1 + NONCE_PREFIX_LEN;
+
+/// Size of the data in every chunk but the last one.
+const CHUNK_LEN: usize = 64 * 1024;
+/// Size of the AES-256-GCM tag after every chunk.
+const TAG_LEN: usize = 16;
+const ENCRYPTED_CHUNK_LEN: usize = CHUNK_LEN + TAG_LEN;
+
+/// Whether the object with `metadata` was uploaded through an [`EncryptedWrapper`].
+pub(crate) fn is_encrypted(metadata: Option<&StorageMetadata>) -> bool {
+    metadata.is_some_and(|metadata| metadata.get(ENCRYPTION_METADATA_KEY).is_some())
+}
+
+/// Removes the mark of the encrypted objects from the metadata of their decrypted contents.
+fn remove_encryption_mark(metadata: &mut Option<StorageMetadata>) {
+    if let Some(m) = metadata {
+        m.0.remove(ENCRYPTION_METADATA_KEY);
+        if m.0.is_empty() {
+            *metadata = None;
+        }
+    }
+}
+
+/// Size of the encrypted object with `data_len` bytes of data. Empty data still has a chunk,
+/// with the tag only.
+fn encrypted_len(data_len: u64) -> u64 {
+    let chunks = data_len.div_ceil(CHUNK_LEN as u64).max(1);
+    HEADER_LEN as u64 + data_len + chunks * TAG_LEN as u64
+}
+
+/// Size of the data in the encrypted object of `encrypted_len` bytes. For the sizes no
+/// encrypted object has, it's the most data that fits into that many bytes.
+fn data_len(encrypted_len: u64) -> u64 {
+    let chunks_len = encrypted_len.saturating_sub(HEADER_LEN as u64);
+    let full_chunks = chunks_len / ENCRYPTED_CHUNK_LEN as u64;
+    let last_chunk_len = chunks_len % ENCRYPTED_CHUNK_LEN as u64;
+    full_chunks * CHUNK_LEN as u64 + last_chunk_len.saturating_sub(TAG_LEN as u64)
+}
+
+#[derive(Clone, Copy)]
+struct Header([u8; HEADER_LEN]);
+
+impl Header {
+    fn generate(rng: &SystemRandom) -> anyhow::Result<Self> {
+        let mut header = [0; HEADER_LEN];
+        header[..FORMAT_MAGIC.len()].copy_from_slice(FORMAT_MAGIC);
+        header[FORMAT_MAGIC.len()] = FORMAT_VERSION;
+        rng.fill(&mut header[FORMAT_MAGIC.len() + 1..])
+            .map_err(|_| anyhow!("Failed to generate the encryption nonce"))?;
+        Ok(Header(header))
+    }
+
+    fn parse(header: [u8; HEADER_LEN]) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            &header[..FORMAT_MAGIC.len()] == FORMAT_MAGIC,
+            "Not an encrypted object, no format magic in front"
+        );
+        let version = header[FORMAT_MAGIC.len()];
+        anyhow::ensure!(
+            version == FORMAT_VERSION,
+            "Unknown encryption format version {version}"
+        );
+        Ok(Header(header))
+    }
+
+    fn nonce(&self, chunk: u64) -> io::Result<Nonce> {
+        let chunk = u32::try_from(chunk).map_err(|_| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("Encrypted objects have at most {} chunks", u32::MAX),
+            )
+        })?;
+        let mut nonce = [0; NONCE_LEN];
+        nonce[..NONCE_PREFIX_LEN].copy_from_slice(&self.0[FORMAT_MAGIC.len() + 1..]);
+        nonce[NONCE_PREFIX_LEN..].copy_from_slice(&chunk.to_be_bytes());
+        Ok(Nonce::assume_unique_for_key(nonce))
+    }
+
+    /// The associated data of a chunk: the header, so that the format and the nonce prefix are
+    /// authenticated too, and whether it's the last chunk.
+    fn aad(&self, last_chunk: bool) -> Aad<[u8; HEADER_LEN + 1]> {
+        let mut aad = [0; HEADER_LEN + 1];
+        aad[..HEADER_LEN].copy_from_slice(&self.0);
+        aad[HEADER_LEN] = u8::from(last_chunk);
+        Aad::from(aad)
+    }
+}
+
+pub struct EncryptedWrapper {
+    inner: crate::GenericRemoteStorage,
+    key: Arc<LessSafeKey>,
+    rng: SystemRandom,
+}
+
+impl EncryptedWrapper {
+    pub fn new(inner: crate::GenericRemoteStorage, key: &[u8]) -> anyhow::Result<Self> {
+        let key = UnboundKey::new(&AES_256_GCM, key).map_err(|_| {
+            anyhow!(
+                "Invalid encryption key: expected {} bytes, got {}",
+                AES_256_GCM.key_len(),
+                key.len()
+            )
+        })?;
+        debug_assert_eq!(AES_256_GCM.tag_len(), TAG_LEN);
+        Ok(EncryptedWrapper {
+            inner,
+            key: Arc::new(LessSafeKey::new(key)),
+            rng: SystemRandom::new(),
+        })
+    }
+
+    /// Reads the raw 32 bytes of the key from the given file.
+    pub fn from_key_file(inner: crate::GenericRemoteStorage, path: &Path) -> anyhow::Result<Self> {
+        let key = std::fs::read(path)
+            .with_context(|| format!("Failed to read the encryption key file {path:?}"))?;
+        Self::new(inner, &key).with_context(|| format!("Failed to load the key from {path:?}"))
+    }
+
+    /// Starts decrypting `stream`, which has the chunks from `first_chunk` on, preceded by the
+    /// header unless it's given. The first chunk is decrypted right away, so that a wrong key or
+    /// a corrupted object fail the download, not the reads of it later.
+    async fn start_decryption(
+        &self,
+        from: &RemotePath,
+        mut stream: Pin<Box<dyn io::AsyncRead + Unpin + Send + Sync>>,
+        header: Option<Header>,
+        first_chunk: u64,
+        last_chunk: Option<u64>,
+        skip: usize,
+    ) -> Result<DecryptingReader, RemoteStorageError> {
+        let header = match header {
+            Some(header) => header,
+            None => read_header(from, &mut stream).await?,
+        };
+        let mut reader = DecryptingReader {
+            inner: stream,
+            key: Arc::clone(&self.key),
+            header,
+            chunk: first_chunk,
+            last_chunk,
+            skip,
+            chunk_buf: Vec::new(),
+            filled: 0,
+            decrypted: Vec::new(),
+            decrypted_pos: 0,
+            done: false,
+        };
+        std::future::poll_fn(|cx| reader.poll_open_chunk(cx))
+            .await
+            .map_err(|e| read_error(from, e))?;
+        Ok(reader)
+    }
+}
+
+/// Whether the object with `metadata` is encrypted with the algorithm of [`EncryptedWrapper`].
+fn check_encryption(
+    from: &RemotePath,
+    metadata: Option<&StorageMetadata>,
+) -> Result<bool, RemoteStorageError> {
+    match metadata.and_then(|metadata| metadata.get(ENCRYPTION_METADATA_KEY)) {
+        None => Ok(false),
+        Some(ENCRYPTION_ALGORITHM) => Ok(true),
+        Some(unknown) => Err(RemoteStorageError::Permanent(anyhow!(
+            "Object {from} is encrypted with unknown algorithm '{unknown}'"
+        ))),
+    }
+}
+
+async fn read_header(
+    from: &RemotePath,
+    stream: &mut (dyn io::AsyncRead + Unpin + Send + Sync),
+) -> Result<Header, RemoteStorageError> {
+    let mut header = [0; HEADER_LEN];
+    stream
+        .read_exact(&mut header)
+        .await
+        .map_err(|e| read_error(from, e))?;
+    Header::parse(header)
+        .with_context(|| format!("Failed to decrypt {from}"))
+        .map_err(RemoteStorageError::Permanent)
+}
+
+/// Corrupted and truncated objects, and the ones encrypted with another key, fail for good,
+/// the rest of the read errors may go away on a retry.
+fn read_error(from: &RemotePath, e: io::Error) -> RemoteStorageError {
+    match e.kind() {
+        io::ErrorKind::InvalidData | io::ErrorKind::UnexpectedEof => {
+            RemoteStorageError::Permanent(anyhow!(e).context(format!("Failed to decrypt {from}")))
+        }
+        _ => RemoteStorageError::Transient(
+            anyhow!(e).context(format!("Failed to download the encrypted object {from}")),
+        ),
+    }
+}
+
+/// Copies the bytes of `pending` from `pos` on into `buf`, as many as fit.
+fn read_pending(pending: &[u8], pos: &mut usize, buf: &mut io::ReadBuf<'_>) {
+    let n = buf.remaining().min(pending.len() - *pos);
+    buf.put_slice(&pending[*pos..*pos + n]);
+    *pos += n;
+}
+
+/// Reads `inner` into `buf` until `buf` is full, from `filled` bytes on. Returns whether the
+/// stream ended before that.
+fn poll_fill(
+    inner: &mut (dyn io::AsyncRead + Unpin + Send + Sync),
+    cx: &mut std::task::Context<'_>,
+    buf: &mut [u8],
+    filled: &mut usize,
+) -> Poll<io::Result<bool>> {
+    while *filled < buf.len() {
+        let mut read_buf = io::ReadBuf::new(&mut buf[*filled..]);
+        ready!(Pin::new(&mut *inner).poll_read(cx, &mut read_buf))?;
+        match read_buf.filled().len() {
+            0 => return Poll::Ready(Ok(true)),
+            n => *filled += n,
+        }
+    }
+    Poll::Ready(Ok(false))
+}
+
+/// The encrypted object of the data `inner` streams: the header and the chunks, each sealed
+/// once its data is read.
+struct EncryptingReader {
+    inner: UploadStream,
+    key: Arc<LessSafeKey>,
+    header: Header,
+    /// Number of the next chunk.
+    chunk: u64,
+    /// How many bytes of data `inner` has left.
+    remaining: usize,
+    /// The data of the next chunk, read so far.
+    chunk_buf: Vec<u8>,
+    filled: usize,
+    /// The header, then every sealed chunk, until it's read.
+    encrypted: Vec<u8>,
+    encrypted_pos: usize,
+    done: bool,
+}
+
+impl EncryptingReader {
+    fn new(inner: UploadStream, key: Arc<LessSafeKey>, header: Header, len: usize) -> Self {
+        EncryptingReader {
+            inner,
+            key,
+            header,
+            chunk: 0,
+            remaining: len,
+            chunk_buf: Vec::new(),
+            filled: 0,
+            encrypted: header.0.to_vec(),
+            encrypted_pos: 0,
+            done: false,
+        }
+    }
+
+    fn poll_seal_chunk(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        let len = self.remaining.min(CHUNK_LEN);
+        let last_chunk = len == self.remaining;
+        // The last chunk reads a byte more, to catch the streams that are too long.
+        let read_len = if last_chunk { len + 1 } else { len };
+        self.chunk_buf.resize(read_len, 0);
+        ready!(poll_fill(
+            &mut self.inner,
+            cx,
+            &mut self.chunk_buf,
+            &mut self.filled
+        ))?;
+        if self.filled != len {
+            let error = if self.filled < len {
+                "Upload stream is shorter than its size"
+            } else {
+                "Upload stream is longer than its size"
+            };
+            return Poll::Ready(Err(io::Error::new(io::ErrorKind::InvalidInput, error)));
+        }
+
+        self.chunk_buf.truncate(len);
+        self.key
+            .seal_in_place_append_tag(
+                self.header.nonce(self.chunk)?,
+                self.header.aad(last_chunk),
+                &mut self.chunk_buf,
+            )
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "Failed to encrypt the data"))?;
+        std::mem::swap(&mut self.encrypted, &mut self.chunk_buf);
+        self.encrypted_pos = 0;
+        self.filled = 0;
+        self.remaining -= len;
+        self.chunk += 1;
+        self.done = last_chunk;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl io::AsyncRead for EncryptingReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.encrypted_pos < this.encrypted.len() {
+                read_pending(&this.encrypted, &mut this.encrypted_pos, buf);
+                return Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_seal_chunk(cx))?;
+        }
+    }
+}
+
+/// The data of the encrypted chunks `inner` streams, each opened once it's read whole.
+struct DecryptingReader {
+    inner: Pin<Box<dyn io::AsyncRead + Unpin + Send + Sync>>,
+    key: Arc<LessSafeKey>,
+    header: Header,
+    /// Number of the next chunk.
+    chunk: u64,
+    /// Number of the last chunk of the object. If not known, the chunk `inner` ends with is
+    /// the last one.
+    last_chunk: Option<u64>,
+    /// How many bytes of the data of the first chunk to skip.
+    skip: usize,
+    /// The next chunk, read so far.
+    chunk_buf: Vec<u8>,
+    filled: usize,
+    /// The data of the last opened chunk, until it's read.
+    decrypted: Vec<u8>,
+    decrypted_pos: usize,
+    done: bool,
+}
+
+impl DecryptingReader {
+    fn poll_open_chunk(&mut self, cx: &mut std::task::Context<'_>) -> Poll<io::Result<()>> {
+        // Without the number of the last chunk, a byte past the chunk tells if there's more.
+        let read_len = match self.last_chunk {
+            Some(_) => ENCRYPTED_CHUNK_LEN,
+            None => ENCRYPTED_CHUNK_LEN + 1,
+        };
+        self.chunk_buf.resize(read_len, 0);
+        let ended = ready!(poll_fill(
+            &mut self.inner,
+            cx,
+            &mut self.chunk_buf,
+            &mut self.filled
+        ))?;
+        let last_chunk = match self.last_chunk {
+            Some(last_chunk) => self.chunk == last_chunk,
+            None => ended,
+        };
+        let len = self.filled.min(ENCRYPTED_CHUNK_LEN);
+        if len < TAG_LEN || (!last_chunk && len < ENCRYPTED_CHUNK_LEN) {
+            return Poll::Ready(Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "Encrypted object is truncated",
+            )));
+        }
+        let next_byte = (self.filled > len).then(|| self.chunk_buf[len]);
+
+        let data_len = self
+            .key
+            .open_in_place(
+                self.header.nonce(self.chunk)?,
+                self.header.aad(last_chunk),
+                &mut self.chunk_buf[..len],
+            )
+            .map_err(|_| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "Failed to decrypt the object, wrong key or corrupted data",
+                )
+            })?
+            .len();
+        self.chunk_buf.truncate(data_len);
+        std::mem::swap(&mut self.decrypted, &mut self.chunk_buf);
+        self.decrypted_pos = std::mem::take(&mut self.skip).min(data_len);
+        self.chunk_buf.clear();
+        self.chunk_buf.extend(next_byte);
+        self.filled = self.chunk_buf.len();
+        self.chunk += 1;
+        self.done = last_chunk;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl io::AsyncRead for DecryptingReader {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.decrypted_pos < this.decrypted.len() {
+                read_pending(&this.decrypted, &mut this.decrypted_pos, buf);
+                return Poll::Ready(Ok(()));
+            }
+            if this.done {
+                return Poll::Ready(Ok(()));
+            }
+            ready!(this.poll_open_chunk(cx))?;
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for EncryptedWrapper {
+    async fn list_prefixes(
+        &self,
+        prefix: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        self.inner.list_prefixes(prefix).await
+    }
+
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        self.inner.list_files(folder).await
+    }
+
+    fn list_files_stream<'a>(&'a self, folder: Option<&'a RemotePath>) -> ListingStream<'a> {
+        self.inner.list_files_stream(folder)
+    }
+
+    async fn upload(
+        &self,
+        data: UploadStream,
+        data_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        let header =
+            Header::generate(&self.rng).with_context(|| format!("Failed to encrypt {to}"))?;
+        let encrypted_size = usize::try_from(encrypted_len(data_size_bytes as u64))
+            .with_context(|| format!("Encrypted {to} is too large"))
+            .map_err(RemoteStorageError::Permanent)?;
+        let mut metadata = metadata.unwrap_or_default();
+        metadata.0.insert(
+            ENCRYPTION_METADATA_KEY.to_owned(),
+            ENCRYPTION_ALGORITHM.to_owned(),
+        );
+        self.inner
+            .upload(
+                EncryptingReader::new(data, Arc::clone(&self.key), header, data_size_bytes),
+                encrypted_size,
+                to,
+                Some(metadata),
+            )
+            .await
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
+        let mut download = self.inner.download(from).await?;
+        if check_encryption(from, download.metadata.as_ref())? {
+            let reader = self
+                .start_decryption(from, download.download_stream, None, 0, None, 0)
+                .await?;
+            download.download_stream = Box::pin(reader);
+            remove_encryption_mark(&mut download.metadata);
+        }
+        Ok(download)
+    }
+
+    /// Downloads the header and the chunks the range is in only. The size of the object, to
+    /// map the range to the chunks, comes from [`RemoteStorage::stat`].
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, RemoteStorageError> {
+        let object = self.inner.stat(from).await?;
+        if !check_encryption(from, object.metadata.as_ref())? {
+            return self
+                .inner
+                .download_byte_range(from, start_inclusive, end_exclusive)
+                .await;
+        }
+        if object.size < encrypted_len(0) {
+            return Err(RemoteStorageError::Permanent(anyhow!(
+                "Encrypted object {from} is too short: {} bytes",
+                object.size
+            )));
+        }
+        let mut metadata = object.metadata;
+        remove_encryption_mark(&mut metadata);
+
+        let len = data_len(object.size);
+        let end_exclusive = end_exclusive.map_or(len, |end| end.min(len));
+        if start_inclusive > end_exclusive {
+            return Err(RemoteStorageError::Permanent(anyhow!(
+                "Invalid range {start_inclusive}..{end_exclusive} for {from} of {len} bytes"
+            )));
+        }
+        if start_inclusive == end_exclusive {
+            return Ok(Download {
+                download_stream: Box::pin(io::empty()),
+                metadata,
+            });
+        }
+
+        let first_chunk = start_inclusive / CHUNK_LEN as u64;
+        let last_chunk = (end_exclusive - 1) / CHUNK_LEN as u64;
+        let chunks_start = HEADER_LEN as u64 + first_chunk * ENCRYPTED_CHUNK_LEN as u64;
+        let chunks_end = HEADER_LEN as u64 + (last_chunk + 1) * ENCRYPTED_CHUNK_LEN as u64;
+        let chunks_end = (chunks_end < object.size).then_some(chunks_end);
+        let (header, chunks) = if first_chunk == 0 {
+            (
+                None,
+                self.inner.download_byte_range(from, 0, chunks_end).await?,
+            )
+        } else {
+            let mut header = self
+                .inner
+                .download_byte_range(from, 0, Some(HEADER_LEN as u64))
+                .await?;
+            let header = read_header(from, &mut header.download_stream).await?;
+            let chunks = self
+                .inner
+                .download_byte_range(from, chunks_start, chunks_end)
+                .await?;
+            (Some(header), chunks)
+        };
+        let object_last_chunk = len.div_ceil(CHUNK_LEN as u64).max(1) - 1;
+        let reader = self
+            .start_decryption(
+                from,
+                chunks.download_stream,
+                header,
+                first_chunk,
+                Some(object_last_chunk),
+                (start_inclusive - first_chunk * CHUNK_LEN as u64) as usize,
+            )
+            .await?;
+        Ok(Download {
+            download_stream: Box::pin(reader.take(end_exclusive - start_inclusive)),
+            metadata,
+        })
+    }
+
+    fn efficient_byte_ranges(&self) -> bool {
+        self.inner.efficient_byte_ranges()
+    }
+
+    /// The encrypted objects are larger than the data by the header and the tags.
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size().map(data_len)
+    }
+
+    /// The size of an encrypted object is the size of its decrypted contents, which the
+    /// downloads return.
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        let mut object_meta = self.inner.stat(path).await?;
+        if is_encrypted(object_meta.metadata.as_ref()) {
+            object_meta.size = data_len(object_meta.size);
+            remove_encryption_mark(&mut object_meta.metadata);
+        }
+        Ok(object_meta)
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+        self.inner.delete(path).await
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
+        self.inner.delete_objects(paths).await
+    }
+
+    async fn delete_prefix(&self, prefix: &RemotePath) -> Result<usize, RemoteStorageError> {
+        self.inner.delete_prefix(prefix).await
+    }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        // The nonce prefix is in the header, the copy can be decrypted as is.
+        self.inner.copy(from, to).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use tempfile::tempdir;
+
+    use super::*;
+    use crate::{GenericRemoteStorage, LocalFs};
+
+    async fn read_all(download: Download) -> anyhow::Result<Vec<u8>> {
+        let mut stream = download.download_stream;
+        let mut contents = Vec::new();
+        stream.read_to_end(&mut contents).await?;
+        Ok(contents)
+    }
+
+    #[tokio::test]
+    async fn encrypted_upload_roundtrip() -> anyhow::Result<()> {
+        let storage_root = tempdir()?;
+        let real = GenericRemoteStorage::LocalFs(LocalFs::new(storage_root.path().to_owned())?);
+        let encrypted = EncryptedWrapper::new(real.clone(), &[7; 32])?;
+
+        let original: Vec<u8> = (0..100_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let path = RemotePath::from_string("timeline/layer")?;
+        encrypted
+            .upload(
+                Box::new(Cursor::new(original.clone())),
+                original.len(),
+                &path,
+                Some(StorageMetadata::from([("key", "value")])),
+            )
+            .await?;
+
+        // The storage only has the ciphertext.
+        let stored = real.download(&path).await?;
+        assert_eq!(
+            stored.metadata.as_ref().and_then(|m| m.get("key")),
+            Some("value")
+        );
+        let stored = read_all(stored).await?;
+        assert_eq!(stored.len() as u64, encrypted_len(original.len() as u64));
+        assert!(!stored.windows(16).any(|w| w == &original[..16]));
+
+        let download = encrypted.download(&path).await?;
+        assert_eq!(
+            download.metadata.as_ref().and_then(|m| m.get("key")),
+            Some("value")
+        );
+        assert!(!is_encrypted(download.metadata.as_ref()));
+        assert!(read_all(download).await? == original);
+
+        let range = encrypted.download_byte_range(&path, 100, Some(200)).await?;
+        assert!(read_all(range).await? == original[100..200]);
+        assert_eq!(encrypted.stat(&path).await?.size, original.len() as u64);
+        let tail = encrypted.download_byte_range(&path, 390_000, None).await?;
+        assert!(read_all(tail).await? == original[390_000..]);
+        let across_chunks = encrypted
+            .download_byte_range(
+                &path,
+                CHUNK_LEN as u64 - 10,
+                Some(3 * CHUNK_LEN as u64 + 10),
+            )
+            .await?;
+        assert!(read_all(across_chunks).await? == original[CHUNK_LEN - 10..3 * CHUNK_LEN + 10]);
+
+        // The same contents are encrypted with a different nonce every time.
+        let other_path = RemotePath::from_string("timeline/other_layer")?;
+        encrypted
+            .upload(
+                Box::new(Cursor::new(original.clone())),
+                original.len(),
+                &other_path,
+                None,
+            )
+            .await?;
+        assert!(read_all(real.download(&other_path).await?).await? != stored);
+
+        // A wrong key does not decrypt the object.
+        let wrong_key = EncryptedWrapper::new(real.clone(), &[8; 32])?;
+        assert!(matches!(
+            wrong_key.download(&path).await,
+            Err(RemoteStorageError::Permanent(_))
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn tampered_objects_are_rejected() -> anyhow::Result<()> {
+        let storage_root = tempdir()?;
+        let real = GenericRemoteStorage::LocalFs(LocalFs::new(storage_root.path().to_owned())?);
+        let encrypted = EncryptedWrapper::new(real.clone(), &[7; 32])?;
+        let original = vec![42u8; 3 * CHUNK_LEN + 100];
+        let path = RemotePath::from_string("timeline/layer")?;
+        encrypted
+            .upload(
+                Box::new(Cursor::new(original.clone())),
+                original.len(),
+                &path,
+                None,
+            )
+            .await?;
+        let stored = read_all(real.download(&path).await?).await?;
+        let metadata = real.stat(&path).await?.metadata;
+
+        let tampered_path = RemotePath::from_string("timeline/tampered_layer")?;
+        let changes: [fn(&mut Vec<u8>); 4] = [
+            // Another format version.
+            |object| object[FORMAT_MAGIC.len()] += 1,
+            // Another nonce prefix.
+            |object| object[HEADER_LEN - 1] ^= 1,
+            // The first two chunks swapped.
+            |object| {
+                let (first, rest) = object[HEADER_LEN..].split_at_mut(ENCRYPTED_CHUNK_LEN);
+                first.swap_with_slice(&mut rest[..ENCRYPTED_CHUNK_LEN]);
+            },
+            // Truncated at a chunk boundary.
+            |object| object.truncate(HEADER_LEN + 2 * ENCRYPTED_CHUNK_LEN),
+        ];
+        for change in changes {
+            let mut tampered = stored.clone();
+            change(&mut tampered);
+            let tampered_len = tampered.len();
+            real.upload(
+                Cursor::new(tampered),
+                tampered_len,
+                &tampered_path,
+                metadata.clone(),
+            )
+            .await?;
+            let decrypted = match encrypted.download(&tampered_path).await {
+                Ok(download) => read_all(download).await,
+                Err(e) => Err(e.into()),
+            };
+            assert!(decrypted.is_err());
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn unencrypted_objects_are_downloaded_as_is() -> anyhow::Result<()> {
+        let storage_root = tempdir()?;
+        let real = GenericRemoteStorage::LocalFs(LocalFs::new(storage_root.path().to_owned())?);
+        let path = RemotePath::from_string("timeline/layer")?;
+        real.upload(Cursor::new(b"plain data".to_vec()), 10, &path, None)
+            .await?;
+
+        let encrypted = EncryptedWrapper::new(real, &[7; 32])?;
+        assert_eq!(
+            read_all(encrypted.download(&path).await?).await?,
+            b"plain data"
+        );
+        let range = encrypted.download_byte_range(&path, 6, None).await?;
+        assert_eq!(read_all(range).await?, b"data");
+
+        Ok(())
+    }
+
+    #[test]
+    fn key_must_be_32_bytes() {
+        let storage_root = tempdir().unwrap();
+        let real =
+            GenericRemoteStorage::LocalFs(LocalFs::new(storage_root.path().to_owned()).unwrap());
+        assert!(EncryptedWrapper::new(real, &[7; 16]).is_err());
+    }
+}
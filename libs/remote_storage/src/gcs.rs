@@ -0,0 +1,653 @@
+//! Google Cloud Storage wrapper, talking to the GCS HTTP APIs directly.
+//!
+//! Objects are read and written via the XML API, which takes the object metadata
+//! as `x-goog-meta-*` headers, and listed via the JSON API, which has proper pagination tokens.
+//! Respects `prefix_in_bucket` property from [`GcsConfig`] the same way [`crate::S3Bucket`] does,
+//! so the object names are the same for both storages.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use anyhow::Context;
+use futures_util::TryStreamExt;
+use jsonwebtoken::{Algorithm, EncodingKey, Header};
+use reqwest::header::{HeaderMap, CONTENT_LENGTH, RANGE};
+use reqwest::{Body, Client, Method, RequestBuilder, StatusCode, Url};
+use serde::{Deserialize, Serialize};
+use tokio::io;
+use tokio::sync::{Mutex, Semaphore};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::debug;
+
+use crate::{
+    size_and_modification_time, Download, GcsConfig, ObjectMeta, RatelimitedAsyncRead, RemotePath,
+    RemoteStorage, RemoteStorageError, StorageMetadata, UploadStream,
+    REMOTE_STORAGE_PREFIX_SEPARATOR,
+};
+
+const DEFAULT_GCS_ENDPOINT: &str = "https://storage.googleapis.com";
+
+const GCS_SCOPE: &str = "https://www.googleapis.com/auth/devstorage.read_write";
+const GCS_METADATA_HEADER_PREFIX: &str = "x-goog-meta-";
+/// Access tokens live for an hour, refresh them a bit earlier than that to
+/// avoid using an expiring one for a long download.
+const TOKEN_LIFETIME: Duration = Duration::from_secs(3600);
+const TOKEN_REFRESH_MARGIN: Duration = Duration::from_secs(300);
+/// GCS rejects larger objects.
+const MAX_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024 * 1024;
+
+/// Google Cloud Storage bucket.
+pub struct Gcs {
+    client: Client,
+    endpoint: Url,
+    bucket_name: String,
+    prefix_in_bucket: Option<String>,
+    max_keys_per_list_response: Option<i32>,
+    service_account: ServiceAccountKey,
+    access_token: Mutex<Option<AccessToken>>,
+    concurrency_limiter: Arc<Semaphore>,
+}
+
+/// The fields of the service account JSON key file, needed to obtain access tokens.
+#[derive(Deserialize)]
+struct ServiceAccountKey {
+    client_email: String,
+    private_key: String,
+    token_uri: String,
+}
+
+#[derive(Serialize)]
+struct TokenClaims<'a> {
+    iss: &'a str,
+    scope: &'a str,
+    aud: &'a str,
+    iat: u64,
+    exp: u64,
+}
+
+#[derive(Deserialize)]
+struct TokenResponse {
+    access_token: String,
+    expires_in: u64,
+}
+
+struct AccessToken {
+    token: String,
+    refresh_at: SystemTime,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct ListObjectsResponse {
+    #[serde(default)]
+    items: Vec<ObjectResource>,
+    #[serde(default)]
+    prefixes: Vec<String>,
+    next_page_token: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct ObjectResource {
+    name: String,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct RewriteResponse {
+    done: bool,
+    rewrite_token: Option<String>,
+}
+
+impl Gcs {
+    /// Creates the GCS storage, errors if incorrect GCS configuration provided.
+    pub fn new(gcs_config: &GcsConfig) -> anyhow::Result<Self> {
+        debug!(
+            "Creating gcs remote storage for GCS bucket {}",
+            gcs_config.bucket_name
+        );
+
+        let key_path = match &gcs_config.service_account_key_path {
+            Some(path) => path.clone(),
+            None => PathBuf::from(std::env::var("GOOGLE_APPLICATION_CREDENTIALS").context(
+                "'service_account_key_path' is not set and GOOGLE_APPLICATION_CREDENTIALS is missing",
+            )?),
+        };
+        let key_contents = std::fs::read(&key_path).with_context(|| {
+            format!(
+                "Failed to read service account key file {}",
+                key_path.display()
+            )
+        })?;
+        let service_account: ServiceAccountKey = serde_json::from_slice(&key_contents)
+            .with_context(|| {
+                format!(
+                    "Failed to parse service account key file {}",
+                    key_path.display()
+                )
+            })?;
+
+        let endpoint = Url::parse(
+            gcs_config
+                .endpoint
+                .as_deref()
+                .unwrap_or(DEFAULT_GCS_ENDPOINT),
+        )
+        .context("Failed to parse GCS endpoint")?;
+
+        let prefix_in_bucket = gcs_config.prefix_in_bucket.as_deref().map(|prefix| {
+            prefix
+                .trim_matches(REMOTE_STORAGE_PREFIX_SEPARATOR)
+                .to_string()
+        });
+
+        Ok(Self {
+            client: Client::new(),
+            endpoint,
+            bucket_name: gcs_config.bucket_name.clone(),
+            prefix_in_bucket,
+            max_keys_per_list_response: gcs_config.max_keys_per_list_response,
+            service_account,
+            access_token: Mutex::new(None),
+            concurrency_limiter: Arc::new(Semaphore::new(gcs_config.concurrency_limit.get())),
+        })
+    }
+
+    fn gcs_object_to_relative_path(&self, key: &str) -> RemotePath {
+        let stripped = match &self.prefix_in_bucket {
+            Some(prefix) if !prefix.is_empty() => key
+                .strip_prefix(prefix.as_str())
+                .and_then(|key| key.strip_prefix(REMOTE_STORAGE_PREFIX_SEPARATOR)),
+            _ => Some(key),
+        };
+        let relative_path = match stripped {
+            Some(stripped) => stripped,
+            // we rely on GCS to return properly prefixed paths
+            // for requests with a certain prefix
+            None => panic!(
+                "Key {} does not start with bucket prefix {:?}",
+                key, self.prefix_in_bucket
+            ),
+        };
+        RemotePath(
+            relative_path
+                .split(REMOTE_STORAGE_PREFIX_SEPARATOR)
+                .collect(),
+        )
+    }
+
+    pub fn relative_path_to_gcs_object(&self, path: &RemotePath) -> String {
+        assert_eq!(std::path::MAIN_SEPARATOR, REMOTE_STORAGE_PREFIX_SEPARATOR);
+        let path_string = path
+            .get_path()
+            .to_string_lossy()
+            .trim_end_matches(REMOTE_STORAGE_PREFIX_SEPARATOR)
+            .to_string();
+        match &self.prefix_in_bucket {
+            Some(prefix) => prefix.clone() + "/" + &path_string,
+            None => path_string,
+        }
+    }
+
+    /// XML API url of the object: `{endpoint}/{bucket}/{object name}`
+    fn object_url(&self, path: &RemotePath) -> Url {
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut()
+            .expect("endpoint url is a base url")
+            .pop_if_empty()
+            .push(&self.bucket_name)
+            .extend(
+                self.relative_path_to_gcs_object(path)
+                    .split(REMOTE_STORAGE_PREFIX_SEPARATOR),
+            );
+        url
+    }
+
+    /// JSON API url to list the bucket objects: `{endpoint}/storage/v1/b/{bucket}/o`
+    fn list_url(&self) -> Url {
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut()
+            .expect("endpoint url is a base url")
+            .pop_if_empty()
+            .extend(["storage", "v1", "b", self.bucket_name.as_str(), "o"]);
+        url
+    }
+
+    /// JSON API url to rewrite one object into another:
+    /// `{endpoint}/storage/v1/b/{bucket}/o/{object name}/rewriteTo/b/{bucket}/o/{object name}`,
+    /// with the `/` separators of the object names encoded.
+    fn rewrite_url(&self, from: &RemotePath, to: &RemotePath) -> Url {
+        let mut url = self.endpoint.clone();
+        url.path_segments_mut()
+            .expect("endpoint url is a base url")
+            .pop_if_empty()
+            .extend(["storage", "v1", "b", self.bucket_name.as_str(), "o"])
+            .push(&self.relative_path_to_gcs_object(from))
+            .extend(["rewriteTo", "b", self.bucket_name.as_str(), "o"])
+            .push(&self.relative_path_to_gcs_object(to));
+        url
+    }
+
+    async fn access_token(&self) -> anyhow::Result<String> {
+        let mut access_token = self.access_token.lock().await;
+        if let Some(token) = access_token.as_ref() {
+            if SystemTime::now() < token.refresh_at {
+                return Ok(token.token.clone());
+            }
+        }
+
+        let now = SystemTime::now();
+        let iat = now.duration_since(UNIX_EPOCH)?.as_secs();
+        let claims = TokenClaims {
+            iss: &self.service_account.client_email,
+            scope: GCS_SCOPE,
+            aud: &self.service_account.token_uri,
+            iat,
+            exp: iat + TOKEN_LIFETIME.as_secs(),
+        };
+        let key = EncodingKey::from_rsa_pem(self.service_account.private_key.as_bytes())
+            .context("Failed to parse service account private key")?;
+        let assertion = jsonwebtoken::encode(&Header::new(Algorithm::RS256), &claims, &key)
+            .context("Failed to sign access token request")?;
+
+        let response = self
+            .client
+            .post(&self.service_account.token_uri)
+            .form(&[
+                ("grant_type", "urn:ietf:params:oauth:grant-type:jwt-bearer"),
+                ("assertion", &assertion),
+            ])
+            .send()
+            .await
+            .context("Failed to request GCS access token")?;
+        if response.status().is_client_error() {
+            // The service account is rejected, asking again won't help.
+            return Err(RemoteStorageError::Unauthorized(anyhow::anyhow!(
+                "GCS access token request failed: {}",
+                response.status()
+            ))
+            .into());
+        }
+        let response = response
+            .error_for_status()
+            .context("GCS access token request failed")?
+            .json::<TokenResponse>()
+            .await
+            .context("Failed to parse GCS access token response")?;
+
+        let token = response.access_token;
+        *access_token = Some(AccessToken {
+            token: token.clone(),
+            refresh_at: now
+                + Duration::from_secs(response.expires_in).saturating_sub(TOKEN_REFRESH_MARGIN),
+        });
+        Ok(token)
+    }
+
+    async fn request(&self, method: Method, url: Url) -> anyhow::Result<RequestBuilder> {
+        let token = self.access_token().await?;
+        Ok(self.client.request(method, url).bearer_auth(token))
+    }
+
+    async fn permit(&self) -> tokio::sync::SemaphorePermit<'_> {
+        self.concurrency_limiter
+            .acquire()
+            .await
+            .expect("semaphore is never closed")
+    }
+
+    async fn owned_permit(&self) -> tokio::sync::OwnedSemaphorePermit {
+        Arc::clone(&self.concurrency_limiter)
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed")
+    }
+
+    /// Goes over all pages of the JSON API object listing for the given prefix.
+    async fn list_objects(
+        &self,
+        prefix: Option<String>,
+        delimiter: Option<char>,
+    ) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        let mut object_names = Vec::new();
+        let mut common_prefixes = Vec::new();
+
+        let mut page_token: Option<String> = None;
+        loop {
+            let _guard = self.permit().await;
+
+            let mut url = self.list_url();
+            {
+                let mut query = url.query_pairs_mut();
+                query.append_pair("fields", "items(name),prefixes,nextPageToken");
+                if let Some(prefix) = &prefix {
+                    query.append_pair("prefix", prefix);
+                }
+                if let Some(delimiter) = delimiter {
+                    query.append_pair("delimiter", &delimiter.to_string());
+                }
+                if let Some(max_keys) = self.max_keys_per_list_response {
+                    query.append_pair("maxResults", &max_keys.to_string());
+                }
+                if let Some(page_token) = &page_token {
+                    query.append_pair("pageToken", page_token);
+                }
+            }
+
+            let response = self
+                .request(Method::GET, url)
+                .await?
+                .send()
+                .await
+                .context("Failed to list GCS objects")?;
+            if !response.status().is_success() {
+                return Err(status_error(response.status(), "GCS list request").into());
+            }
+            let response = response
+                .json::<ListObjectsResponse>()
+                .await
+                .context("Failed to parse GCS list response")?;
+
+            object_names.extend(response.items.into_iter().map(|item| item.name));
+            common_prefixes.extend(response.prefixes);
+
+            page_token = match response.next_page_token {
+                Some(token) => Some(token),
+                None => break,
+            };
+        }
+
+        Ok((object_names, common_prefixes))
+    }
+
+    async fn download_object(
+        &self,
+        from: &RemotePath,
+        range: Option<String>,
+    ) -> Result<Download, RemoteStorageError> {
+        let permit = self.owned_permit().await;
+
+        let mut request = self
+            .request(Method::GET, self.object_url(from))
+            .await
+            .map_err(RemoteStorageError::from)?;
+        if let Some(range) = range {
+            request = request.header(RANGE, range);
+        }
+
+        let response = request
+            .send()
+            .await
+            .context("Failed to download GCS object")
+            .map_err(RemoteStorageError::from)?;
+
+        if !response.status().is_success() {
+            return Err(status_error(
+                response.status(),
+                &format!("GCS download request for {from}"),
+            ));
+        }
+
+        let metadata = metadata_from_headers(response.headers());
+        let download_stream = response
+            .bytes_stream()
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e));
+
+        Ok(Download {
+            download_stream: Box::pin(RatelimitedAsyncRead::new(
+                permit,
+                StreamReader::new(download_stream),
+            )),
+            metadata,
+        })
+    }
+}
+
+fn status_error(status: StatusCode, request_description: &str) -> RemoteStorageError {
+    RemoteStorageError::from_http_status(
+        status.as_u16(),
+        anyhow::anyhow!("{request_description} failed: {status}"),
+    )
+}
+
+fn metadata_from_headers(headers: &HeaderMap) -> Option<StorageMetadata> {
+    let metadata = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let key = name.as_str().strip_prefix(GCS_METADATA_HEADER_PREFIX)?;
+            Some((key.to_string(), value.to_str().ok()?.to_string()))
+        })
+        .collect::<HashMap<_, _>>();
+    if metadata.is_empty() {
+        None
+    } else {
+        Some(StorageMetadata(metadata))
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for Gcs {
+    /// See the doc for `RemoteStorage::list_prefixes`
+    async fn list_prefixes(
+        &self,
+        prefix: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        // get the passed prefix or if it is not set use prefix_in_bucket value
+        let list_prefix = prefix
+            .map(|p| self.relative_path_to_gcs_object(p))
+            .or_else(|| self.prefix_in_bucket.clone())
+            .map(|mut p| {
+                // required to end with a separator
+                // otherwise request will return only the entry of a prefix
+                if !p.ends_with(REMOTE_STORAGE_PREFIX_SEPARATOR) {
+                    p.push(REMOTE_STORAGE_PREFIX_SEPARATOR);
+                }
+                p
+            });
+
+        let (_, common_prefixes) = self
+            .list_objects(list_prefix, Some(REMOTE_STORAGE_PREFIX_SEPARATOR))
+            .await
+            .map_err(RemoteStorageError::from)?;
+
+        Ok(common_prefixes
+            .iter()
+            .map(|prefix| self.gcs_object_to_relative_path(prefix))
+            .collect())
+    }
+
+    /// See the doc for `RemoteStorage::list_files`
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        let folder_name = folder
+            .map(|p| self.relative_path_to_gcs_object(p))
+            .or_else(|| {
+                self.prefix_in_bucket
+                    .as_ref()
+                    .filter(|prefix| !prefix.is_empty())
+                    .map(|prefix| format!("{prefix}{REMOTE_STORAGE_PREFIX_SEPARATOR}"))
+            });
+
+        let (object_names, _) = self.list_objects(folder_name, None).await?;
+
+        Ok(object_names
+            .iter()
+            .map(|name| self.gcs_object_to_relative_path(name))
+            .collect())
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        Some(MAX_OBJECT_SIZE)
+    }
+
+    async fn upload(
+        &self,
+        from: UploadStream,
+        from_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        let _guard = self.permit().await;
+
+        let mut request = self
+            .request(Method::PUT, self.object_url(to))
+            .await?
+            .header(CONTENT_LENGTH, from_size_bytes)
+            .body(Body::wrap_stream(ReaderStream::new(from)));
+        for (key, value) in metadata.map(|m| m.0).unwrap_or_default() {
+            request = request.header(format!("{GCS_METADATA_HEADER_PREFIX}{key}"), value);
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("Failed to upload GCS object for path {to}"))?;
+        if !response.status().is_success() {
+            return Err(status_error(
+                response.status(),
+                &format!("GCS upload request for {to}"),
+            ));
+        }
+
+        Ok(())
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
+        self.download_object(from, None).await
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, RemoteStorageError> {
+        // GCS accepts the same inclusive byte ranges as S3 does, which cannot express an empty
+        // range: the object metadata is fetched instead, to fail on a missing object still.
+        if let Some(end_exclusive) = end_exclusive {
+            if end_exclusive < start_inclusive {
+                return Err(RemoteStorageError::Permanent(anyhow::anyhow!(
+                    "Invalid range, start ({start_inclusive}) is greater than end_exclusive ({end_exclusive})"
+                )));
+            }
+            if end_exclusive == start_inclusive {
+                return Ok(Download {
+                    download_stream: Box::pin(io::empty()),
+                    metadata: self.stat(from).await?.metadata,
+                });
+            }
+        }
+        let end_inclusive = end_exclusive.map(|end| end - 1);
+        let range = match end_inclusive {
+            Some(end_inclusive) => format!("bytes={start_inclusive}-{end_inclusive}"),
+            None => format!("bytes={start_inclusive}-"),
+        };
+
+        self.download_object(from, Some(range)).await
+    }
+
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        let _guard = self.permit().await;
+
+        let response = self
+            .request(Method::HEAD, self.object_url(path))
+            .await
+            .map_err(RemoteStorageError::from)?
+            .send()
+            .await
+            .with_context(|| format!("Failed to get the GCS object metadata for path {path}"))
+            .map_err(RemoteStorageError::from)?;
+        if !response.status().is_success() {
+            return Err(status_error(
+                response.status(),
+                &format!("GCS head request for {path}"),
+            ));
+        }
+
+        let (size, last_modified) = size_and_modification_time(response.headers())
+            .with_context(|| format!("Invalid GCS head response for {path}"))
+            .map_err(RemoteStorageError::from)?;
+        Ok(ObjectMeta {
+            size,
+            last_modified,
+            metadata: metadata_from_headers(response.headers()),
+        })
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+        let _guard = self.permit().await;
+
+        let response = self
+            .request(Method::DELETE, self.object_url(path))
+            .await?
+            .send()
+            .await
+            .with_context(|| format!("Failed to delete GCS object for path {path}"))?;
+
+        match response.status() {
+            // S3 does not error on deleting a missing object, keep the same semantics
+            StatusCode::NOT_FOUND => Ok(()),
+            status if status.is_success() => Ok(()),
+            status => Err(status_error(
+                status,
+                &format!("GCS delete request for {path}"),
+            )),
+        }
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
+        // GCS batch requests are a multipart/mixed API of its own, not worth it for now
+        for path in paths {
+            self.delete(path).await?;
+        }
+        Ok(())
+    }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        let _guard = self.permit().await;
+
+        // A rewrite copies the object with its metadata, but large objects may take several
+        // requests: each one continues from the token the previous one returned.
+        let mut rewrite_token: Option<String> = None;
+        loop {
+            let mut url = self.rewrite_url(from, to);
+            {
+                let mut query = url.query_pairs_mut();
+                query.append_pair("fields", "done,rewriteToken");
+                if let Some(rewrite_token) = &rewrite_token {
+                    query.append_pair("rewriteToken", rewrite_token);
+                }
+            }
+
+            let response = self
+                .request(Method::POST, url)
+                .await?
+                .header(CONTENT_LENGTH, 0)
+                .send()
+                .await
+                .with_context(|| format!("Failed to copy GCS object {from} to {to}"))?;
+            if !response.status().is_success() {
+                return Err(status_error(
+                    response.status(),
+                    &format!("GCS rewrite request for {from} to {to}"),
+                ));
+            }
+            let response = response
+                .json::<RewriteResponse>()
+                .await
+                .context("Failed to parse GCS rewrite response")?;
+
+            if response.done {
+                return Ok(());
+            }
+            rewrite_token = Some(
+                response
+                    .rewrite_token
+                    .context("GCS rewrite response is not done and has no rewrite token")?,
+            );
+        }
+    }
+}
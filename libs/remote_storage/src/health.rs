@@ -0,0 +1,147 @@
+//! A round trip of a small object through the remote storage, to tell whether the storage is
+//! reachable and writable with the configured credentials, e.g. right after a deployment,
+//! instead of finding it out at the first upload of a layer.
+
+use std::time::{Duration, Instant, SystemTime};
+
+use anyhow::anyhow;
+use tokio::io::AsyncReadExt;
+
+use crate::{Download, GenericRemoteStorage, RemotePath, RemoteStorageError};
+
+/// The directory the sentinel objects are uploaded to, outside of the tenant data.
+pub const HEALTH_CHECK_PREFIX: &str = "health_check";
+
+/// What [`check_storage_health`] found.
+///
+/// The steps run in order, and stop at the first failure, except for the deletion, which is
+/// attempted after any successful upload, so that no sentinel is left behind.
+#[derive(Debug)]
+pub struct StorageHealth {
+    pub upload_latency: Option<Duration>,
+    pub download_latency: Option<Duration>,
+    pub delete_latency: Option<Duration>,
+    /// The first failure.
+    pub error: Option<RemoteStorageError>,
+}
+
+impl StorageHealth {
+    pub fn is_healthy(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+/// Uploads a small sentinel object, downloads it back, compares the contents and deletes it.
+/// Each step that takes longer than `timeout` fails with [`RemoteStorageError::Timeout`].
+///
+/// Every check uses a new path, so the concurrent checks of the pageservers that share the
+/// storage don't see each other's objects.
+pub async fn check_storage_health(
+    storage: &GenericRemoteStorage,
+    timeout: Duration,
+) -> StorageHealth {
+    let now = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default();
+    let name = format!("{}-{}", std::process::id(), now.as_nanos());
+    let path = RemotePath::from_string(&format!("{HEALTH_CHECK_PREFIX}/{name}"))
+        .expect("sentinel path is relative");
+    let contents = format!("remote storage health check {name}").into_bytes();
+
+    let mut health = StorageHealth {
+        upload_latency: None,
+        download_latency: None,
+        delete_latency: None,
+        error: None,
+    };
+
+    let started_at = Instant::now();
+    let upload = storage.upload(
+        std::io::Cursor::new(contents.clone()),
+        contents.len(),
+        &path,
+        None,
+    );
+    if let Err(e) = with_timeout(timeout, upload).await {
+        health.error = Some(e);
+        return health;
+    }
+    health.upload_latency = Some(started_at.elapsed());
+
+    let started_at = Instant::now();
+    let download = async {
+        let Download {
+            mut download_stream,
+            ..
+        } = storage.download(&path).await?;
+        let mut downloaded = Vec::new();
+        download_stream
+            .read_to_end(&mut downloaded)
+            .await
+            .map_err(|e| RemoteStorageError::Transient(anyhow!(e).context("read the sentinel")))?;
+        if downloaded != contents {
+            return Err(RemoteStorageError::Permanent(anyhow!(
+                "downloaded {} bytes of sentinel {path} differ from the {} uploaded",
+                downloaded.len(),
+                contents.len()
+            )));
+        }
+        Ok(())
+    };
+    match with_timeout(timeout, download).await {
+        Ok(()) => health.download_latency = Some(started_at.elapsed()),
+        Err(e) => health.error = Some(e),
+    }
+
+    let started_at = Instant::now();
+    match with_timeout(timeout, storage.delete(&path)).await {
+        Ok(()) => health.delete_latency = Some(started_at.elapsed()),
+        Err(e) => {
+            health.error.get_or_insert(e);
+        }
+    }
+    health
+}
+
+async fn with_timeout<T>(
+    timeout: Duration,
+    op: impl std::future::Future<Output = Result<T, RemoteStorageError>>,
+) -> Result<T, RemoteStorageError> {
+    tokio::time::timeout(timeout, op)
+        .await
+        .unwrap_or(Err(RemoteStorageError::Timeout))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{DryRunWrapper, LocalFs};
+
+    #[tokio::test]
+    async fn round_trip() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let storage = GenericRemoteStorage::LocalFs(LocalFs::new(root.path().to_owned())?);
+
+        let health = check_storage_health(&storage, Duration::from_secs(10)).await;
+        assert!(health.is_healthy(), "{health:?}");
+        assert!(health.delete_latency.is_some());
+        // Nothing is left behind.
+        assert!(storage
+            .list_files(None)
+            .await?
+            .iter()
+            .all(|path| path.with_base(root.path()).is_dir()));
+
+        // Uploads of a dry run are never stored, so there is nothing to download.
+        let dry_run =
+            GenericRemoteStorage::DryRun(std::sync::Arc::new(DryRunWrapper::new(storage.clone())));
+        let health = check_storage_health(&dry_run, Duration::from_secs(10)).await;
+        assert!(health.upload_latency.is_some());
+        assert!(health.download_latency.is_none());
+        assert!(
+            matches!(health.error, Some(RemoteStorageError::NotFound)),
+            "{health:?}"
+        );
+        Ok(())
+    }
+}
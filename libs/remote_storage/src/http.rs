@@ -0,0 +1,427 @@
+//! Read-only storage on a plain HTTP(S) server, e.g. a CDN in front of a copy of a bucket,
+//! for the nodes that only restore from it and have no credentials to the real storage.
+//!
+//! Static servers don't list directories, so the objects are listed from an index file with
+//! one object path per line, relative to the base URL: `find . -type f` in the root of the copy
+//! produces one. Empty lines and lines starting with `#` are skipped.
+//!
+//! The object metadata is taken from the `x-amz-meta-*` response headers, which S3 returns for
+//! the public objects too, so the compressed and encrypted objects are still recognized when
+//! the server is in front of an S3 bucket.
+//!
+//! Uploads, deletions and copies fail with a permanent error.
+
+use std::collections::{BTreeSet, HashMap};
+
+use anyhow::Context;
+use futures_util::TryStreamExt;
+use reqwest::header::{HeaderMap, RANGE};
+use reqwest::{Client, StatusCode, Url};
+use tokio::io::{self, AsyncReadExt};
+use tokio::sync::Semaphore;
+use tokio_util::io::StreamReader;
+use tracing::debug;
+
+use crate::{
+    size_and_modification_time, Download, HttpConfig, ObjectMeta, RemotePath, RemoteStorage,
+    RemoteStorageError, StorageMetadata, UploadStream,
+};
+
+const DEFAULT_INDEX_FILE_NAME: &str = "index.txt";
+const METADATA_HEADER_PREFIX: &str = "x-amz-meta-";
+
+pub struct HttpReadOnly {
+    client: Client,
+    base_url: Url,
+    index_url: Url,
+    concurrency_limiter: Semaphore,
+}
+
+impl HttpReadOnly {
+    pub fn new(http_config: &HttpConfig) -> anyhow::Result<Self> {
+        debug!(
+            "Creating read-only http remote storage for {}",
+            http_config.base_url
+        );
+
+        let mut base_url = Url::parse(&http_config.base_url).context("Failed to parse base_url")?;
+        anyhow::ensure!(
+            matches!(base_url.scheme(), "http" | "https"),
+            "base_url {base_url} is not an http(s) url"
+        );
+        // Joined with relative paths below, which replace the last segment without the slash.
+        if !base_url.path().ends_with('/') {
+            base_url.set_path(&format!("{}/", base_url.path()));
+        }
+        let index_url = match &http_config.index_url {
+            Some(index_url) => Url::parse(index_url).context("Failed to parse index_url")?,
+            None => base_url
+                .join(DEFAULT_INDEX_FILE_NAME)
+                .context("Failed to build the index url")?,
+        };
+
+        Ok(Self {
+            client: Client::new(),
+            base_url,
+            index_url,
+            concurrency_limiter: Semaphore::new(http_config.concurrency_limit.get()),
+        })
+    }
+
+    fn object_url(&self, path: &RemotePath) -> Url {
+        let mut url = self.base_url.clone();
+        url.path_segments_mut()
+            .expect("base url is an http(s) url")
+            .pop_if_empty()
+            .extend(
+                path.get_path()
+                    .iter()
+                    .map(|segment| segment.to_string_lossy()),
+            );
+        url
+    }
+
+    async fn permit(&self) -> tokio::sync::SemaphorePermit<'_> {
+        self.concurrency_limiter
+            .acquire()
+            .await
+            .expect("semaphore is never closed")
+    }
+
+    async fn list_index(&self) -> anyhow::Result<Vec<RemotePath>> {
+        let _guard = self.permit().await;
+        let response = self
+            .client
+            .get(self.index_url.clone())
+            .send()
+            .await
+            .with_context(|| format!("Failed to download the index {}", self.index_url))?;
+        if !response.status().is_success() {
+            return Err(status_error(
+                response.status(),
+                &format!("Index request {}", self.index_url),
+            )
+            .into());
+        }
+        let index = response
+            .text()
+            .await
+            .with_context(|| format!("Failed to read the index {}", self.index_url))?;
+        parse_index(&index)
+    }
+
+    async fn download_object(
+        &self,
+        from: &RemotePath,
+        range: Option<(u64, Option<u64>)>,
+    ) -> Result<Download, RemoteStorageError> {
+        let _guard = self.permit().await;
+
+        let mut request = self.client.get(self.object_url(from));
+        if let Some((start_inclusive, end_exclusive)) = range {
+            let range = match end_exclusive {
+                Some(end_exclusive) => {
+                    format!(
+                        "bytes={start_inclusive}-{}",
+                        end_exclusive.saturating_sub(1)
+                    )
+                }
+                None => format!("bytes={start_inclusive}-"),
+            };
+            request = request.header(RANGE, range);
+        }
+
+        let response = request
+            .send()
+            .await
+            .with_context(|| format!("Failed to download {from}"))
+            .map_err(RemoteStorageError::from)?;
+
+        let status = response.status();
+        if !status.is_success() {
+            return Err(status_error(
+                status,
+                &format!("Download request for {from}"),
+            ));
+        }
+
+        let metadata = metadata_from_headers(response.headers());
+        let mut download_stream = StreamReader::new(
+            response
+                .bytes_stream()
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e)),
+        );
+
+        // Servers that don't support ranges return the whole object, cut the range out of it.
+        if let (Some((start_inclusive, end_exclusive)), StatusCode::OK) = (range, status) {
+            debug!("{} ignored the range request for {from}", self.base_url);
+            io::copy(
+                &mut (&mut download_stream).take(start_inclusive),
+                &mut io::sink(),
+            )
+            .await
+            .with_context(|| format!("Failed to skip to byte {start_inclusive} of {from}"))
+            .map_err(RemoteStorageError::from)?;
+            let limit = end_exclusive.map_or(u64::MAX, |end| end.saturating_sub(start_inclusive));
+            return Ok(Download {
+                download_stream: Box::pin(download_stream.take(limit)),
+                metadata,
+            });
+        }
+
+        Ok(Download {
+            download_stream: Box::pin(download_stream),
+            metadata,
+        })
+    }
+}
+
+/// Object paths listed in the index file.
+fn parse_index(index: &str) -> anyhow::Result<Vec<RemotePath>> {
+    index
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| {
+            let line = line.trim_start_matches("./").trim_start_matches('/');
+            RemotePath::from_string(line).with_context(|| format!("Invalid index entry {line:?}"))
+        })
+        .collect()
+}
+
+/// The entries right under the prefix: the objects there, and the "directories" of the
+/// objects deeper down, same as `LocalFs` lists them.
+fn prefixes_under(objects: &[RemotePath], prefix: Option<&RemotePath>) -> Vec<RemotePath> {
+    let mut prefixes = BTreeSet::new();
+    for object in objects {
+        let relative = match prefix {
+            Some(prefix) => match object.get_path().strip_prefix(prefix.get_path()) {
+                Ok(relative) => relative,
+                Err(_) => continue,
+            },
+            None => object.get_path().as_path(),
+        };
+        if let Some(first) = relative.iter().next() {
+            let first = std::path::Path::new(first);
+            prefixes.insert(match prefix {
+                Some(prefix) => prefix.join(first),
+                None => RemotePath(first.to_owned()),
+            });
+        }
+    }
+    prefixes.into_iter().collect()
+}
+
+fn status_error(status: StatusCode, request_description: &str) -> RemoteStorageError {
+    RemoteStorageError::from_http_status(
+        status.as_u16(),
+        anyhow::anyhow!("{request_description} failed: {status}"),
+    )
+}
+
+fn read_only_error(operation: &str, path: &RemotePath) -> RemoteStorageError {
+    RemoteStorageError::Permanent(anyhow::anyhow!(
+        "Cannot {operation} {path}: the http remote storage is a read-only backend"
+    ))
+}
+
+fn metadata_from_headers(headers: &HeaderMap) -> Option<StorageMetadata> {
+    let metadata = headers
+        .iter()
+        .filter_map(|(name, value)| {
+            let key = name.as_str().strip_prefix(METADATA_HEADER_PREFIX)?;
+            Some((key.to_string(), value.to_str().ok()?.to_string()))
+        })
+        .collect::<HashMap<_, _>>();
+    if metadata.is_empty() {
+        None
+    } else {
+        Some(StorageMetadata(metadata))
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for HttpReadOnly {
+    async fn list_prefixes(
+        &self,
+        prefix: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        let objects = self.list_index().await.map_err(RemoteStorageError::from)?;
+        Ok(prefixes_under(&objects, prefix))
+    }
+
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        let mut objects = self.list_index().await?;
+        if let Some(folder) = folder {
+            objects.retain(|object| object.get_path().starts_with(folder.get_path()));
+        }
+        Ok(objects)
+    }
+
+    async fn upload(
+        &self,
+        _from: UploadStream,
+        _data_size_bytes: usize,
+        to: &RemotePath,
+        _metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        Err(read_only_error("upload", to))
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
+        self.download_object(from, None).await
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, RemoteStorageError> {
+        self.download_object(from, Some((start_inclusive, end_exclusive)))
+            .await
+    }
+
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        let _guard = self.permit().await;
+
+        let response = self
+            .client
+            .head(self.object_url(path))
+            .send()
+            .await
+            .with_context(|| format!("Failed to get the metadata of {path}"))
+            .map_err(RemoteStorageError::from)?;
+        if !response.status().is_success() {
+            return Err(status_error(
+                response.status(),
+                &format!("Head request for {path}"),
+            ));
+        }
+
+        let (size, last_modified) = size_and_modification_time(response.headers())
+            .with_context(|| format!("Invalid head response for {path}"))
+            .map_err(RemoteStorageError::from)?;
+        Ok(ObjectMeta {
+            size,
+            last_modified,
+            metadata: metadata_from_headers(response.headers()),
+        })
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+        Err(read_only_error("delete", path))
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
+        match paths.first() {
+            Some(path) => Err(read_only_error("delete", path)),
+            None => Ok(()),
+        }
+    }
+
+    async fn copy(&self, _from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        Err(read_only_error("copy to", to))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::num::NonZeroUsize;
+
+    use super::*;
+
+    fn paths(paths: &[&str]) -> Vec<RemotePath> {
+        paths
+            .iter()
+            .map(|path| RemotePath::from_string(path).unwrap())
+            .collect()
+    }
+
+    fn storage(base_url: &str) -> HttpReadOnly {
+        HttpReadOnly::new(&HttpConfig {
+            base_url: base_url.to_owned(),
+            index_url: None,
+            concurrency_limit: NonZeroUsize::new(1).unwrap(),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn index_is_parsed() -> anyhow::Result<()> {
+        let index = "# nightly copy\n./tenants/a/timelines/b/index_part.json\n\n/tenants/a/timelines/b/layer\n";
+        assert_eq!(
+            parse_index(index)?,
+            paths(&[
+                "tenants/a/timelines/b/index_part.json",
+                "tenants/a/timelines/b/layer"
+            ])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn prefixes_are_listed_from_the_index() {
+        let objects = paths(&[
+            "tenants/a/timelines/b/index_part.json",
+            "tenants/a/timelines/b/layer",
+            "tenants/a/timelines/c/index_part.json",
+            "tenants/d/timelines/e/index_part.json",
+            "tenants_backup/a",
+        ]);
+        assert_eq!(
+            prefixes_under(&objects, None),
+            paths(&["tenants", "tenants_backup"])
+        );
+        assert_eq!(
+            prefixes_under(&objects, Some(&RemotePath::from_string("tenants").unwrap())),
+            paths(&["tenants/a", "tenants/d"])
+        );
+        assert_eq!(
+            prefixes_under(
+                &objects,
+                Some(&RemotePath::from_string("tenants/a/timelines/b").unwrap())
+            ),
+            paths(&[
+                "tenants/a/timelines/b/index_part.json",
+                "tenants/a/timelines/b/layer"
+            ])
+        );
+    }
+
+    #[test]
+    fn urls() {
+        let storage = storage("https://cdn.example.com/nightly");
+        assert_eq!(
+            storage.index_url.as_str(),
+            "https://cdn.example.com/nightly/index.txt"
+        );
+        assert_eq!(
+            storage
+                .object_url(&RemotePath::from_string("tenants/a b/layer").unwrap())
+                .as_str(),
+            "https://cdn.example.com/nightly/tenants/a%20b/layer"
+        );
+        assert!(HttpReadOnly::new(&HttpConfig {
+            base_url: "ftp://cdn.example.com".to_owned(),
+            index_url: None,
+            concurrency_limit: NonZeroUsize::new(1).unwrap(),
+        })
+        .is_err());
+    }
+
+    #[tokio::test]
+    async fn writes_fail_permanently() {
+        let storage = storage("https://cdn.example.com/");
+        let path = RemotePath::from_string("layer").unwrap();
+        let upload = storage
+            .upload(Box::new(std::io::Cursor::new(Vec::new())), 0, &path, None)
+            .await;
+        assert!(upload.unwrap_err().is_permanent());
+        assert!(storage.delete(&path).await.unwrap_err().is_permanent());
+        assert!(storage.copy(&path, &path).await.unwrap_err().is_permanent());
+    }
+}
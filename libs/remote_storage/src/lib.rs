@@ -4,27 +4,64 @@
 //! [`RemoteStorage`] trait a CRUD-like generic abstraction to use for adapting external storages with a few implementations:
 //!   * [`local_fs`] allows to use local file system as an external storage
 //!   * [`s3_bucket`] uses AWS S3 bucket as an external storage
+//!   * [`azure_blob`] uses Azure Blob Storage container as an external storage
+//!   * [`gcs`] uses Google Cloud Storage bucket as an external storage
+//!   * [`sftp`] uses a directory on an SFTP server as an external storage
 //!
+//! The uploaded objects may be compressed, see [`compression`], and encrypted, see [`encryption`].
+//! The bandwidth of the uploads and downloads may be limited, see [`throttle`].
+//! The uploads and deletions may be only logged instead, see [`dry_run`].
+//! The stuck transfers can be told apart from the long ones, see [`progress`].
+//!
+//! Other storages can be plugged in by implementing [`RemoteStorage`] outside of this crate,
+//! and wrapping the implementation into [`GenericRemoteStorage::Custom`].
+//!
+mod azure_blob;
+mod compression;
+mod dry_run;
+mod encryption;
+mod gcs;
+mod health;
+mod http;
 mod local_fs;
+mod progress;
 mod s3_bucket;
+mod sftp;
 mod simulate_failures;
+mod throttle;
 
 use std::{
     collections::HashMap,
     fmt::Debug,
-    num::{NonZeroU32, NonZeroUsize},
+    num::{NonZeroU32, NonZeroU64, NonZeroUsize},
     path::{Path, PathBuf},
     pin::Pin,
     sync::Arc,
+    time::{Duration, SystemTime},
 };
 
 use anyhow::{bail, Context};
 
+use futures_util::stream::{self, Stream, TryStreamExt};
 use tokio::io;
 use toml_edit::Item;
-use tracing::info;
+use tracing::{info, warn};
 
-pub use self::{local_fs::LocalFs, s3_bucket::S3Bucket, simulate_failures::UnreliableWrapper};
+pub use self::{
+    azure_blob::AzureBlob,
+    compression::{Compression, ZstdDictionary},
+    dry_run::{DryRunPlan, DryRunWrapper},
+    encryption::EncryptedWrapper,
+    gcs::Gcs,
+    health::{check_storage_health, StorageHealth, HEALTH_CHECK_PREFIX},
+    http::HttpReadOnly,
+    local_fs::LocalFs,
+    progress::timeout_without_progress,
+    s3_bucket::S3Bucket,
+    sftp::Sftp,
+    simulate_failures::UnreliableWrapper,
+    throttle::ThrottledWrapper,
+};
 
 /// How many different timelines can be processed simultaneously when synchronizing layers with the remote storage.
 /// During regular work, pageserver produces one layer file per timeline checkpoint, with bursts of concurrency
@@ -32,12 +69,45 @@ pub use self::{local_fs::LocalFs, s3_bucket::S3Bucket, simulate_failures::Unreli
 /// Both cases may trigger timeline download, that might download a lot of layers. This concurrency is limited by the clients internally, if needed.
 pub const DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_SYNCS: usize = 50;
 pub const DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS: u32 = 10;
+/// How many times a single remote storage request is retried before its error is returned,
+/// the backoff between the attempts starts from [`DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS`]
+/// and doubles with every attempt.
+pub const DEFAULT_REMOTE_STORAGE_MAX_RETRIES: u32 = 10;
+pub const DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS: u64 = 100;
+/// Long enough for a slow request to get its response, the transfers of large files are only
+/// cancelled once they stall, see [`timeout_without_progress`].
+pub const DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT: Duration = Duration::from_secs(120);
+/// Listing a tenant with many timelines, or a timeline with many layers, takes many requests.
+pub const DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT: Duration = Duration::from_secs(600);
+/// The uploads start as soon as they are scheduled.
+pub const DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER: Duration = Duration::ZERO;
 /// Currently, sync happens with AWS S3, that has two limits on requests per second:
 /// ~200 RPS for IAM services
 /// <https://docs.aws.amazon.com/AmazonRDS/latest/AuroraUserGuide/UsingWithRDS.IAMDBAuth.html>
 /// ~3500 PUT/COPY/POST/DELETE or 5500 GET/HEAD S3 requests
 /// <https://aws.amazon.com/premiumsupport/knowledge-center/s3-request-limit-avoid-throttling/>
 pub const DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT: usize = 100;
+/// Objects larger than that are uploaded to S3 in parts of this size, the rest are uploaded with a single request.
+/// S3 requires every part but the last one to be at least 5 MiB.
+/// <https://docs.aws.amazon.com/AmazonS3/latest/userguide/qfacts.html>
+pub const DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE: usize = 8 * 1024 * 1024;
+/// How many parts of a single multipart upload are sent to S3 at once.
+/// Every part request also takes a permit from the `concurrency_limit` ones.
+pub const DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_CONCURRENCY: usize = 4;
+/// Azure Blob Storage allows ~20000 requests per second per storage account, yet we share the account
+/// between many users, so stay at the same conservative limit as for S3.
+/// <https://learn.microsoft.com/en-us/azure/storage/common/scalability-targets-standard-account>
+pub const DEFAULT_REMOTE_STORAGE_AZURE_CONCURRENCY_LIMIT: usize = 100;
+/// GCS scales its request rate per bucket gradually, starting from ~1000 writes and ~5000 reads per second.
+/// <https://cloud.google.com/storage/docs/request-rate>
+pub const DEFAULT_REMOTE_STORAGE_GCS_CONCURRENCY_LIMIT: usize = 100;
+/// Every concurrent SFTP request needs its own SSH channel, and SSH servers limit the channels
+/// of a single connection, e.g. OpenSSH's `MaxSessions` is 10 by default.
+pub const DEFAULT_REMOTE_STORAGE_SFTP_CONCURRENCY_LIMIT: usize = 10;
+pub const DEFAULT_SFTP_PORT: u16 = 22;
+/// CDNs and static servers scale with the load, the limit only keeps a single restore from
+/// opening too many connections at once.
+pub const DEFAULT_REMOTE_STORAGE_HTTP_CONCURRENCY_LIMIT: usize = 100;
 /// No limits on the client side, which currenltly means 1000 for AWS S3.
 /// <https://docs.aws.amazon.com/AmazonS3/latest/API/API_ListObjectsV2.html#API_ListObjectsV2_RequestSyntax>
 pub const DEFAULT_MAX_KEYS_PER_LIST_RESPONSE: Option<i32> = None;
@@ -90,9 +160,37 @@ impl RemotePath {
     }
 }
 
+/// The contents to upload, see [`RemoteStorage::upload`].
+pub type UploadStream = Box<dyn io::AsyncRead + Unpin + Send + Sync + 'static>;
+
+/// The paths of a listing, see [`RemoteStorage::list_files_stream`].
+pub type ListingStream<'a> =
+    Pin<Box<dyn Stream<Item = Result<RemotePath, RemoteStorageError>> + Send + 'a>>;
+
+/// The contents of an object, see [`GenericRemoteStorage::open_read`].
+pub type ReadStream = Pin<Box<dyn io::AsyncRead + Unpin + Send + Sync>>;
+
 /// Storage (potentially remote) API to manage its state.
 /// This storage tries to be unaware of any layered repository context,
 /// providing basic CRUD operations for storage files.
+///
+/// The trait is object safe: a storage implemented outside of this crate is passed around
+/// as [`GenericRemoteStorage::Custom`]. Such implementations have to uphold what the users
+/// of the trait, e.g. the pageserver, rely on:
+///
+/// * The user has exclusive write access to the paths it is given. Nothing but the requests
+///   of the user creates, overwrites or deletes objects there: no lifecycle rules, no other
+///   clients writing to the same prefix. The user does not issue concurrent writes to the
+///   same path, so the implementation does not need to order them.
+/// * An upload is atomic. Once [`Self::upload`] returns `Ok`, the downloads and listings
+///   see the whole new object; a failed upload leaves the previous object, or no object,
+///   in place, never a partially written one. So does an upload future dropped before it
+///   completes: the user cancels the uploads of the deleted timelines that way.
+/// * The metadata passed to the upload is returned by the downloads of that object unchanged.
+/// * A download or a [`Self::stat`] of a missing object returns [`RemoteStorageError::NotFound`],
+///   a deletion of one succeeds.
+/// * Errors are returned as the [`RemoteStorageError`] kind they are: the user retries only
+///   those that are not [`RemoteStorageError::is_permanent`].
 #[async_trait::async_trait]
 pub trait RemoteStorage: Send + Sync + 'static {
     /// Lists all top level subdirectories for a given prefix
@@ -102,7 +200,7 @@ pub trait RemoteStorage: Send + Sync + 'static {
     async fn list_prefixes(
         &self,
         prefix: Option<&RemotePath>,
-    ) -> Result<Vec<RemotePath>, DownloadError>;
+    ) -> Result<Vec<RemotePath>, RemoteStorageError>;
 
     /// Lists all files in directory "recursively"
     /// (not really recursively, because AWS has a flat namespace)
@@ -115,22 +213,47 @@ pub trait RemoteStorage: Send + Sync + 'static {
     /// whereas,
     /// list_prefixes("foo/bar/") = ["cat", "dog"]
     /// See `test_real_s3.rs` for more details.
-    async fn list_files(&self, folder: Option<&RemotePath>) -> anyhow::Result<Vec<RemotePath>>;
+    ///
+    /// Both listings only go through the objects under the given prefix, on the storage side
+    /// (e.g. with the `prefix` of S3 `ListObjectsV2`, or by walking only that subdirectory),
+    /// so listing a single tenant is cheap even in a bucket shared by many pageservers.
+    ///
+    /// The implementations may return the paths in any order, e.g. in the directory order of
+    /// the file system: [`GenericRemoteStorage`] sorts both listings, see [`sort_listing`].
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError>;
+
+    /// Same as [`Self::list_files`], but the paths are returned as they are listed, so a
+    /// listing of millions of objects is never in memory at once. The next batch is only
+    /// requested from the storage when the previous one is consumed. Unlike the other
+    /// listings, the stream is never sorted: it comes in the order of the storage.
+    ///
+    /// By default, everything is listed with [`Self::list_files`] first: storages that list
+    /// in pages or directory by directory should override this.
+    fn list_files_stream<'a>(&'a self, folder: Option<&'a RemotePath>) -> ListingStream<'a> {
+        Box::pin(
+            stream::once(self.list_files(folder))
+                .map_ok(|paths| stream::iter(paths.into_iter().map(Ok)))
+                .try_flatten(),
+        )
+    }
 
     /// Streams the local file contents into remote into the remote storage entry.
     async fn upload(
         &self,
-        from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from: UploadStream,
         // S3 PUT request requires the content length to be specified,
         // otherwise it starts to fail with the concurrent connection count increasing.
         data_size_bytes: usize,
         to: &RemotePath,
         metadata: Option<StorageMetadata>,
-    ) -> anyhow::Result<()>;
+    ) -> Result<(), RemoteStorageError>;
 
     /// Streams the remote storage entry contents into the buffered writer given, returns the filled writer.
     /// Returns the metadata, if any was stored with the file previously.
-    async fn download(&self, from: &RemotePath) -> Result<Download, DownloadError>;
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError>;
 
     /// Streams a given byte range of the remote storage entry contents into the buffered writer given, returns the filled writer.
     /// Returns the metadata, if any was stored with the file previously.
@@ -139,11 +262,81 @@ pub trait RemoteStorage: Send + Sync + 'static {
         from: &RemotePath,
         start_inclusive: u64,
         end_exclusive: Option<u64>,
-    ) -> Result<Download, DownloadError>;
+    ) -> Result<Download, RemoteStorageError>;
+
+    /// Whether [`Self::download_byte_range`] transfers only the requested range of the object.
+    /// Large objects are downloaded in several ranges at once only from the storages that do.
+    fn efficient_byte_ranges(&self) -> bool {
+        true
+    }
+
+    /// The size of the largest object [`Self::upload`] can store, `None` if there is no limit.
+    /// The larger files have to be split into several objects by the user.
+    fn max_object_size(&self) -> Option<u64> {
+        None
+    }
+
+    /// Returns the size, the last modification time and the stored metadata of the object,
+    /// without its contents, or [`RemoteStorageError::NotFound`] if there is no such object.
+    ///
+    /// By default, the object is downloaded and counted: storages that can tell it without
+    /// sending the contents, e.g. with a `HEAD` request, should override this.
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        let Download {
+            mut download_stream,
+            metadata,
+        } = self.download(path).await?;
+        let size = io::copy(&mut download_stream, &mut io::sink())
+            .await
+            .with_context(|| format!("Failed to read {path} for its size"))
+            .map_err(RemoteStorageError::Transient)?;
+        Ok(ObjectMeta {
+            size,
+            last_modified: None,
+            metadata,
+        })
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError>;
 
-    async fn delete(&self, path: &RemotePath) -> anyhow::Result<()>;
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError>;
 
-    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> anyhow::Result<()>;
+    /// Deletes all objects under `prefix`, taken as a directory: the objects of `a/bc` are not
+    /// under `a/b`. Returns the number of the deleted objects, zero if there were none.
+    ///
+    /// By default, the objects are listed and deleted with [`Self::delete_objects`]: storages
+    /// that can delete a whole directory at once, or in batches as the listing goes, should
+    /// override this.
+    async fn delete_prefix(&self, prefix: &RemotePath) -> Result<usize, RemoteStorageError> {
+        let paths: Vec<RemotePath> = match self.list_files(Some(prefix)).await {
+            Ok(paths) => paths,
+            Err(RemoteStorageError::NotFound) => return Ok(0),
+            Err(e) => return Err(e),
+        }
+        .into_iter()
+        .filter(|path| path.get_path().starts_with(prefix.get_path()))
+        .collect();
+        self.delete_objects(&paths).await?;
+        Ok(paths.len())
+    }
+
+    /// Copies the object with its metadata to another path, overwriting the object there, if any.
+    ///
+    /// By default, the download of the object is streamed into its upload, with the size from
+    /// [`Self::stat`]: storages that can copy the objects on their side should override this.
+    /// An object that is overwritten meanwhile fails the upload with a size mismatch.
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        let size = self.stat(from).await?.size;
+        let size = usize::try_from(size)
+            .with_context(|| format!("Object {from} of {size} bytes is too large to copy"))
+            .map_err(RemoteStorageError::Permanent)?;
+        let Download {
+            download_stream,
+            metadata,
+        } = self.download(from).await?;
+        self.upload(Box::new(download_stream), size, to, metadata)
+            .await
+    }
 }
 
 pub struct Download {
@@ -160,29 +353,170 @@ impl Debug for Download {
     }
 }
 
+pin_project_lite::pin_project! {
+    /// An `AsyncRead` adapter which carries a permit for the lifetime of the value.
+    ///
+    /// The storages hand it out as [`Download::download_stream`], so that the concurrency
+    /// limit covers the body transfer, not only the request which started it.
+    pub(crate) struct RatelimitedAsyncRead<S> {
+        permit: tokio::sync::OwnedSemaphorePermit,
+        #[pin]
+        inner: S,
+    }
+}
+
+impl<S: io::AsyncRead> RatelimitedAsyncRead<S> {
+    pub(crate) fn new(permit: tokio::sync::OwnedSemaphorePermit, inner: S) -> Self {
+        RatelimitedAsyncRead { permit, inner }
+    }
+}
+
+impl<S: io::AsyncRead> io::AsyncRead for RatelimitedAsyncRead<S> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut io::ReadBuf<'_>,
+    ) -> std::task::Poll<std::io::Result<()>> {
+        let this = self.project();
+        this.inner.poll_read(cx, buf)
+    }
+}
+
+/// What [`RemoteStorage::stat`] knows about an object.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ObjectMeta {
+    /// Size of the object as its downloads return it, i.e. still compressed if it was
+    /// uploaded compressed, see [`Compression`].
+    pub size: u64,
+    /// `None` if the storage does not tell.
+    pub last_modified: Option<SystemTime>,
+    /// Same as returned by the downloads of the object.
+    pub metadata: Option<StorageMetadata>,
+}
+
+/// Why a remote storage request failed, for the callers to decide whether and how soon
+/// to retry it.
+///
+/// Every error carries the chain of what went wrong, but `NotFound` and `Timeout`, which
+/// tell it all. Errors raised as `anyhow::Error` are converted with [`From`]: the ones with a
+/// [`RemoteStorageError`] inside keep its kind, the rest are [`RemoteStorageError::Transient`].
 #[derive(Debug)]
-pub enum DownloadError {
-    /// Validation or other error happened due to user input.
-    BadInput(anyhow::Error),
-    /// The file was not found in the remote storage.
+pub enum RemoteStorageError {
+    /// The object does not exist.
     NotFound,
-    /// The file was found in the remote storage, but the download failed.
-    Other(anyhow::Error),
+    /// The storage asked to slow down, e.g. with a 429 or an S3 `SlowDown` response.
+    Throttled(anyhow::Error),
+    /// The credentials are missing or rejected, or don't allow the request.
+    Unauthorized(anyhow::Error),
+    /// The request did not finish in time.
+    Timeout,
+    /// Network errors, server side errors and anything else that may go away on a retry.
+    Transient(anyhow::Error),
+    /// Invalid requests, unsupported operations and corrupted objects: the same request
+    /// fails the same way again.
+    Permanent(anyhow::Error),
 }
 
-impl std::fmt::Display for DownloadError {
+impl std::fmt::Display for RemoteStorageError {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         match self {
-            DownloadError::BadInput(e) => {
-                write!(f, "Failed to download a remote file due to user input: {e}")
+            Self::NotFound => write!(f, "No file found for the remote object id given"),
+            Self::Throttled(e) => write!(f, "Remote storage request was throttled: {e:#}"),
+            Self::Unauthorized(e) => write!(f, "Remote storage request was not authorized: {e:#}"),
+            Self::Timeout => write!(f, "Remote storage request timed out"),
+            Self::Transient(e) => write!(f, "Remote storage request failed: {e:#}"),
+            Self::Permanent(e) => write!(f, "Remote storage request failed permanently: {e:#}"),
+        }
+    }
+}
+
+impl std::error::Error for RemoteStorageError {}
+
+impl From<anyhow::Error> for RemoteStorageError {
+    fn from(e: anyhow::Error) -> Self {
+        match e.downcast_ref::<RemoteStorageError>() {
+            Some(Self::NotFound) => Self::NotFound,
+            Some(Self::Timeout) => Self::Timeout,
+            Some(Self::Throttled(_)) => Self::Throttled(e),
+            Some(Self::Unauthorized(_)) => Self::Unauthorized(e),
+            Some(Self::Permanent(_)) => Self::Permanent(e),
+            Some(Self::Transient(_)) | None => Self::Transient(e),
+        }
+    }
+}
+
+impl RemoteStorageError {
+    /// Returns true if the error won't go away when the same request is retried.
+    pub fn is_permanent(&self) -> bool {
+        match self {
+            Self::NotFound | Self::Unauthorized(_) | Self::Permanent(_) => true,
+            Self::Throttled(_) | Self::Timeout | Self::Transient(_) => false,
+        }
+    }
+
+    /// Classifies the error of a request answered with the given HTTP status.
+    fn from_http_status(status: u16, e: anyhow::Error) -> Self {
+        match status {
+            404 => Self::NotFound,
+            401 | 403 => Self::Unauthorized(e),
+            408 => Self::Timeout,
+            429 | 503 => Self::Throttled(e),
+            400 | 405 | 411 | 412 | 413 | 416 => Self::Permanent(e),
+            _ => Self::Transient(e),
+        }
+    }
+
+    /// Classifies the error of a filesystem operation.
+    fn from_io(e: std::io::Error, context: String) -> Self {
+        use std::io::ErrorKind;
+        match e.kind() {
+            ErrorKind::NotFound => Self::NotFound,
+            ErrorKind::TimedOut => Self::Timeout,
+            ErrorKind::PermissionDenied => {
+                Self::Unauthorized(anyhow::Error::new(e).context(context))
+            }
+            ErrorKind::InvalidInput | ErrorKind::InvalidData | ErrorKind::Unsupported => {
+                Self::Permanent(anyhow::Error::new(e).context(context))
             }
-            DownloadError::NotFound => write!(f, "No file found for the remote object id given"),
-            DownloadError::Other(e) => write!(f, "Failed to download a remote file: {e:?}"),
+            _ => Self::Transient(anyhow::Error::new(e).context(context)),
         }
     }
 }
 
-impl std::error::Error for DownloadError {}
+/// Checks whether retrying the failed remote storage operation makes no sense.
+pub fn is_permanent_error(e: &anyhow::Error) -> bool {
+    e.downcast_ref::<RemoteStorageError>()
+        .is_some_and(RemoteStorageError::is_permanent)
+}
+
+/// Sorts the listed paths in the order S3 lists its keys in, byte by byte, so that a listing
+/// comes in the same order from every storage and on every run. The component-wise order of
+/// the paths would differ, e.g. put `a/b` before `a-b`.
+pub fn sort_listing(paths: &mut [RemotePath]) {
+    paths.sort_unstable_by(|a, b| a.0.as_os_str().cmp(b.0.as_os_str()));
+}
+
+/// The size and the modification time of the object from the `Content-Length` and
+/// `Last-Modified` headers of a `HEAD` response.
+///
+/// The length is taken from the header: the body of a `HEAD` response is always empty.
+fn size_and_modification_time(
+    headers: &reqwest::header::HeaderMap,
+) -> anyhow::Result<(u64, Option<SystemTime>)> {
+    let size = headers
+        .get(reqwest::header::CONTENT_LENGTH)
+        .context("Response has no Content-Length header")?
+        .to_str()
+        .ok()
+        .and_then(|length| length.parse().ok())
+        .context("Response has an invalid Content-Length header")?;
+    let last_modified = headers
+        .get(reqwest::header::LAST_MODIFIED)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| chrono::DateTime::parse_from_rfc2822(value).ok())
+        .map(SystemTime::from);
+    Ok((size, last_modified))
+}
 
 /// Every storage, currently supported.
 /// Serves as a simple way to pass around the [`RemoteStorage`] without dealing with generics.
@@ -190,18 +524,57 @@ impl std::error::Error for DownloadError {}
 pub enum GenericRemoteStorage {
     LocalFs(LocalFs),
     AwsS3(Arc<S3Bucket>),
+    AzureBlob(Arc<AzureBlob>),
+    Gcs(Arc<Gcs>),
+    Sftp(Arc<Sftp>),
+    HttpReadOnly(Arc<HttpReadOnly>),
     Unreliable(Arc<UnreliableWrapper>),
+    Throttled(Arc<ThrottledWrapper>),
+    DryRun(Arc<DryRunWrapper>),
+    Encrypted(Arc<EncryptedWrapper>),
+    /// A storage implemented outside of this crate, see [`RemoteStorage`] for the contract.
+    Custom(Arc<dyn RemoteStorage>),
 }
 
 impl GenericRemoteStorage {
     // A function for listing all the files in a "directory"
     // Example:
     // list_files("foo/bar") = ["foo/bar/a.txt", "foo/bar/b.txt"]
-    pub async fn list_files(&self, folder: Option<&RemotePath>) -> anyhow::Result<Vec<RemotePath>> {
-        match self {
+    pub async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        let mut files = match self {
             Self::LocalFs(s) => s.list_files(folder).await,
             Self::AwsS3(s) => s.list_files(folder).await,
+            Self::AzureBlob(s) => s.list_files(folder).await,
+            Self::Gcs(s) => s.list_files(folder).await,
+            Self::Sftp(s) => s.list_files(folder).await,
+            Self::HttpReadOnly(s) => s.list_files(folder).await,
             Self::Unreliable(s) => s.list_files(folder).await,
+            Self::Throttled(s) => s.list_files(folder).await,
+            Self::DryRun(s) => s.list_files(folder).await,
+            Self::Encrypted(s) => s.list_files(folder).await,
+            Self::Custom(s) => s.list_files(folder).await,
+        }?;
+        sort_listing(&mut files);
+        Ok(files)
+    }
+
+    /// See [`RemoteStorage::list_files_stream`].
+    pub fn list_files_stream<'a>(&'a self, folder: Option<&'a RemotePath>) -> ListingStream<'a> {
+        match self {
+            Self::LocalFs(s) => s.list_files_stream(folder),
+            Self::AwsS3(s) => s.list_files_stream(folder),
+            Self::AzureBlob(s) => s.list_files_stream(folder),
+            Self::Gcs(s) => s.list_files_stream(folder),
+            Self::Sftp(s) => s.list_files_stream(folder),
+            Self::HttpReadOnly(s) => s.list_files_stream(folder),
+            Self::Unreliable(s) => s.list_files_stream(folder),
+            Self::Throttled(s) => s.list_files_stream(folder),
+            Self::DryRun(s) => s.list_files_stream(folder),
+            Self::Encrypted(s) => s.list_files_stream(folder),
+            Self::Custom(s) => s.list_files_stream(folder),
         }
     }
 
@@ -211,12 +584,22 @@ impl GenericRemoteStorage {
     pub async fn list_prefixes(
         &self,
         prefix: Option<&RemotePath>,
-    ) -> Result<Vec<RemotePath>, DownloadError> {
-        match self {
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        let mut prefixes = match self {
             Self::LocalFs(s) => s.list_prefixes(prefix).await,
             Self::AwsS3(s) => s.list_prefixes(prefix).await,
+            Self::AzureBlob(s) => s.list_prefixes(prefix).await,
+            Self::Gcs(s) => s.list_prefixes(prefix).await,
+            Self::Sftp(s) => s.list_prefixes(prefix).await,
+            Self::HttpReadOnly(s) => s.list_prefixes(prefix).await,
             Self::Unreliable(s) => s.list_prefixes(prefix).await,
-        }
+            Self::Throttled(s) => s.list_prefixes(prefix).await,
+            Self::DryRun(s) => s.list_prefixes(prefix).await,
+            Self::Encrypted(s) => s.list_prefixes(prefix).await,
+            Self::Custom(s) => s.list_prefixes(prefix).await,
+        }?;
+        sort_listing(&mut prefixes);
+        Ok(prefixes)
     }
 
     pub async fn upload(
@@ -225,20 +608,47 @@ impl GenericRemoteStorage {
         data_size_bytes: usize,
         to: &RemotePath,
         metadata: Option<StorageMetadata>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RemoteStorageError> {
+        // Rejected upfront, instead of with whatever error the storage returns, if any, after
+        // the data is sent.
+        if let Some(max_object_size) = self.max_object_size() {
+            if data_size_bytes as u64 > max_object_size {
+                return Err(RemoteStorageError::Permanent(anyhow::anyhow!(
+                    "Cannot upload {data_size_bytes} bytes to {to}: the remote storage takes objects of at most {max_object_size} bytes"
+                )));
+            }
+        }
+        let from: UploadStream = Box::new(progress::track_progress(from));
         match self {
             Self::LocalFs(s) => s.upload(from, data_size_bytes, to, metadata).await,
             Self::AwsS3(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::AzureBlob(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::Gcs(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::Sftp(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::HttpReadOnly(s) => s.upload(from, data_size_bytes, to, metadata).await,
             Self::Unreliable(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::Throttled(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::DryRun(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::Encrypted(s) => s.upload(from, data_size_bytes, to, metadata).await,
+            Self::Custom(s) => s.upload(from, data_size_bytes, to, metadata).await,
         }
     }
 
-    pub async fn download(&self, from: &RemotePath) -> Result<Download, DownloadError> {
-        match self {
+    pub async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
+        let download = match self {
             Self::LocalFs(s) => s.download(from).await,
             Self::AwsS3(s) => s.download(from).await,
+            Self::AzureBlob(s) => s.download(from).await,
+            Self::Gcs(s) => s.download(from).await,
+            Self::Sftp(s) => s.download(from).await,
+            Self::HttpReadOnly(s) => s.download(from).await,
             Self::Unreliable(s) => s.download(from).await,
-        }
+            Self::Throttled(s) => s.download(from).await,
+            Self::DryRun(s) => s.download(from).await,
+            Self::Encrypted(s) => s.download(from).await,
+            Self::Custom(s) => s.download(from).await,
+        }?;
+        Ok(progress::track_download_progress(download))
     }
 
     pub async fn download_byte_range(
@@ -246,8 +656,8 @@ impl GenericRemoteStorage {
         from: &RemotePath,
         start_inclusive: u64,
         end_exclusive: Option<u64>,
-    ) -> Result<Download, DownloadError> {
-        match self {
+    ) -> Result<Download, RemoteStorageError> {
+        let download = match self {
             Self::LocalFs(s) => {
                 s.download_byte_range(from, start_inclusive, end_exclusive)
                     .await
@@ -256,45 +666,290 @@ impl GenericRemoteStorage {
                 s.download_byte_range(from, start_inclusive, end_exclusive)
                     .await
             }
+            Self::AzureBlob(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
+            Self::Gcs(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
+            Self::Sftp(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
+            Self::HttpReadOnly(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
             Self::Unreliable(s) => {
                 s.download_byte_range(from, start_inclusive, end_exclusive)
                     .await
             }
+            Self::Throttled(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
+            Self::DryRun(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
+            Self::Encrypted(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
+            Self::Custom(s) => {
+                s.download_byte_range(from, start_inclusive, end_exclusive)
+                    .await
+            }
+        }?;
+        Ok(progress::track_download_progress(download))
+    }
+
+    pub fn efficient_byte_ranges(&self) -> bool {
+        match self {
+            Self::LocalFs(s) => s.efficient_byte_ranges(),
+            Self::AwsS3(s) => s.efficient_byte_ranges(),
+            Self::AzureBlob(s) => s.efficient_byte_ranges(),
+            Self::Gcs(s) => s.efficient_byte_ranges(),
+            Self::Sftp(s) => s.efficient_byte_ranges(),
+            Self::HttpReadOnly(s) => s.efficient_byte_ranges(),
+            Self::Unreliable(s) => s.efficient_byte_ranges(),
+            Self::Throttled(s) => s.efficient_byte_ranges(),
+            Self::DryRun(s) => s.efficient_byte_ranges(),
+            Self::Encrypted(s) => s.efficient_byte_ranges(),
+            Self::Custom(s) => s.efficient_byte_ranges(),
+        }
+    }
+
+    pub fn max_object_size(&self) -> Option<u64> {
+        match self {
+            Self::LocalFs(s) => s.max_object_size(),
+            Self::AwsS3(s) => s.max_object_size(),
+            Self::AzureBlob(s) => s.max_object_size(),
+            Self::Gcs(s) => s.max_object_size(),
+            Self::Sftp(s) => s.max_object_size(),
+            Self::HttpReadOnly(s) => s.max_object_size(),
+            Self::Unreliable(s) => s.max_object_size(),
+            Self::Throttled(s) => s.max_object_size(),
+            Self::DryRun(s) => s.max_object_size(),
+            Self::Encrypted(s) => s.max_object_size(),
+            Self::Custom(s) => s.max_object_size(),
         }
     }
 
-    pub async fn delete(&self, path: &RemotePath) -> anyhow::Result<()> {
+    pub async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        match self {
+            Self::LocalFs(s) => s.stat(path).await,
+            Self::AwsS3(s) => s.stat(path).await,
+            Self::AzureBlob(s) => s.stat(path).await,
+            Self::Gcs(s) => s.stat(path).await,
+            Self::Sftp(s) => s.stat(path).await,
+            Self::HttpReadOnly(s) => s.stat(path).await,
+            Self::Unreliable(s) => s.stat(path).await,
+            Self::Throttled(s) => s.stat(path).await,
+            Self::DryRun(s) => s.stat(path).await,
+            Self::Encrypted(s) => s.stat(path).await,
+            Self::Custom(s) => s.stat(path).await,
+        }
+    }
+
+    pub async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
         match self {
             Self::LocalFs(s) => s.delete(path).await,
             Self::AwsS3(s) => s.delete(path).await,
+            Self::AzureBlob(s) => s.delete(path).await,
+            Self::Gcs(s) => s.delete(path).await,
+            Self::Sftp(s) => s.delete(path).await,
+            Self::HttpReadOnly(s) => s.delete(path).await,
             Self::Unreliable(s) => s.delete(path).await,
+            Self::Throttled(s) => s.delete(path).await,
+            Self::DryRun(s) => s.delete(path).await,
+            Self::Encrypted(s) => s.delete(path).await,
+            Self::Custom(s) => s.delete(path).await,
         }
     }
 
-    pub async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> anyhow::Result<()> {
+    pub async fn delete_objects<'a>(
+        &self,
+        paths: &'a [RemotePath],
+    ) -> Result<(), RemoteStorageError> {
         match self {
             Self::LocalFs(s) => s.delete_objects(paths).await,
             Self::AwsS3(s) => s.delete_objects(paths).await,
+            Self::AzureBlob(s) => s.delete_objects(paths).await,
+            Self::Gcs(s) => s.delete_objects(paths).await,
+            Self::Sftp(s) => s.delete_objects(paths).await,
+            Self::HttpReadOnly(s) => s.delete_objects(paths).await,
             Self::Unreliable(s) => s.delete_objects(paths).await,
+            Self::Throttled(s) => s.delete_objects(paths).await,
+            Self::DryRun(s) => s.delete_objects(paths).await,
+            Self::Encrypted(s) => s.delete_objects(paths).await,
+            Self::Custom(s) => s.delete_objects(paths).await,
+        }
+    }
+
+    pub async fn delete_prefix(&self, prefix: &RemotePath) -> Result<usize, RemoteStorageError> {
+        match self {
+            Self::LocalFs(s) => s.delete_prefix(prefix).await,
+            Self::AwsS3(s) => s.delete_prefix(prefix).await,
+            Self::AzureBlob(s) => s.delete_prefix(prefix).await,
+            Self::Gcs(s) => s.delete_prefix(prefix).await,
+            Self::Sftp(s) => s.delete_prefix(prefix).await,
+            Self::HttpReadOnly(s) => s.delete_prefix(prefix).await,
+            Self::Unreliable(s) => s.delete_prefix(prefix).await,
+            Self::Throttled(s) => s.delete_prefix(prefix).await,
+            Self::DryRun(s) => s.delete_prefix(prefix).await,
+            Self::Encrypted(s) => s.delete_prefix(prefix).await,
+            Self::Custom(s) => s.delete_prefix(prefix).await,
+        }
+    }
+
+    pub async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        match self {
+            Self::LocalFs(s) => s.copy(from, to).await,
+            Self::AwsS3(s) => s.copy(from, to).await,
+            Self::AzureBlob(s) => s.copy(from, to).await,
+            Self::Gcs(s) => s.copy(from, to).await,
+            Self::Sftp(s) => s.copy(from, to).await,
+            Self::HttpReadOnly(s) => s.copy(from, to).await,
+            Self::Unreliable(s) => s.copy(from, to).await,
+            Self::Throttled(s) => s.copy(from, to).await,
+            Self::DryRun(s) => s.copy(from, to).await,
+            Self::Encrypted(s) => s.copy(from, to).await,
+            Self::Custom(s) => s.copy(from, to).await,
         }
     }
 }
 
 impl GenericRemoteStorage {
     pub fn from_config(storage_config: &RemoteStorageConfig) -> anyhow::Result<Self> {
-        Ok(match &storage_config.storage {
-            RemoteStorageKind::LocalFs(root) => {
-                info!("Using fs root '{}' as a remote storage", root.display());
-                Self::LocalFs(LocalFs::new(root.clone())?)
+        let storage_kind = match &storage_config.prefix {
+            Some(prefix) => {
+                info!("Using prefix '{prefix}' for all paths of the remote storage");
+                storage_config.storage.with_prefix(prefix)
+            }
+            None => storage_config.storage.clone(),
+        };
+        let storage = match &storage_kind {
+            RemoteStorageKind::LocalFs(local_fs_config) => {
+                let root = &local_fs_config.local_path;
+                if local_fs_config.read_only {
+                    info!(
+                        "Using fs root '{}' as a read-only remote storage",
+                        root.display()
+                    );
+                    Self::LocalFs(LocalFs::new_read_only(root.clone())?)
+                } else {
+                    info!("Using fs root '{}' as a remote storage", root.display());
+                    let mut local_fs = LocalFs::new(root.clone())?;
+                    if let Some(window) = local_fs_config.fsync_batch_window {
+                        info!("Batching the directory fsyncs of the uploads within {window:?}");
+                        local_fs = local_fs.with_fsync_batch_window(window);
+                    }
+                    if let Some(min_size) = local_fs_config.direct_io_min_size {
+                        info!("Writing the uploads of at least {min_size} bytes with O_DIRECT");
+                        local_fs = local_fs.with_direct_io_min_size(min_size);
+                    }
+                    Self::LocalFs(local_fs)
+                }
             }
             RemoteStorageKind::AwsS3(s3_config) => {
                 info!("Using s3 bucket '{}' in region '{}' as a remote storage, prefix in bucket: '{:?}', bucket endpoint: '{:?}'",
                       s3_config.bucket_name, s3_config.bucket_region, s3_config.prefix_in_bucket, s3_config.endpoint);
                 Self::AwsS3(Arc::new(S3Bucket::new(s3_config)?))
             }
+            RemoteStorageKind::AzureBlob(azure_config) => {
+                info!("Using azure container '{}' in region '{}' as a remote storage, prefix in container: '{:?}'",
+                      azure_config.container_name, azure_config.container_region, azure_config.prefix_in_container);
+                Self::AzureBlob(Arc::new(AzureBlob::new(azure_config)?))
+            }
+            RemoteStorageKind::Gcs(gcs_config) => {
+                info!(
+                    "Using gcs bucket '{}' as a remote storage, prefix in bucket: '{:?}', endpoint: '{:?}'",
+                    gcs_config.bucket_name, gcs_config.prefix_in_bucket, gcs_config.endpoint
+                );
+                Self::Gcs(Arc::new(Gcs::new(gcs_config)?))
+            }
+            RemoteStorageKind::Sftp(sftp_config) => {
+                info!(
+                    "Using directory '{}' on sftp server '{}:{}' as a remote storage",
+                    sftp_config.root_path.display(),
+                    sftp_config.host,
+                    sftp_config.port
+                );
+                Self::Sftp(Arc::new(Sftp::new(sftp_config)?))
+            }
+            RemoteStorageKind::HttpReadOnly(http_config) => {
+                info!(
+                    "Using '{}' as a read-only remote storage, listed by index '{:?}'",
+                    http_config.base_url, http_config.index_url
+                );
+                Self::HttpReadOnly(Arc::new(HttpReadOnly::new(http_config)?))
+            }
+        };
+
+        Self::with_common_settings(storage_config, storage)
+    }
+
+    /// Applies the settings that are the same for every storage kind.
+    fn with_common_settings(
+        storage_config: &RemoteStorageConfig,
+        storage: Self,
+    ) -> anyhow::Result<Self> {
+        let storage = match &storage_config.encryption_key_file {
+            Some(key_file) => {
+                info!(
+                    "Encrypting the uploaded files with the key from '{}'",
+                    key_file.display()
+                );
+                Self::Encrypted(Arc::new(EncryptedWrapper::from_key_file(
+                    storage, key_file,
+                )?))
+            }
+            None => storage,
+        };
+        let storage = match storage_config.max_bytes_per_sec {
+            Some(max_bytes_per_sec) => {
+                info!(
+                    "Limiting the remote storage bandwidth to {max_bytes_per_sec} bytes per second"
+                );
+                Self::Throttled(Arc::new(ThrottledWrapper::new(storage, max_bytes_per_sec)))
+            }
+            None => storage,
+        };
+        Ok(if storage_config.dry_run {
+            warn!("Remote storage dry run: uploads and deletions are only logged, not performed");
+            Self::DryRun(Arc::new(DryRunWrapper::new(storage)))
+        } else {
+            storage
         })
     }
 
+    /// Uses a storage implemented outside of this crate, with the common settings of
+    /// `storage_config` applied on top of it. The `storage` and `prefix` parts of the config
+    /// are ignored: the custom storage lays out its paths itself.
+    pub fn custom(
+        storage_config: &RemoteStorageConfig,
+        storage: Arc<dyn RemoteStorage>,
+    ) -> anyhow::Result<Self> {
+        Self::with_common_settings(storage_config, Self::Custom(storage))
+    }
+
+    /// Whether the uploads and deletions are only logged, see [`dry_run`]. Nothing the
+    /// storage reports as uploaded is actually there.
+    pub fn is_dry_run(&self) -> bool {
+        matches!(self, Self::DryRun(_))
+    }
+
+    /// What the skipped uploads and deletions of a dry run would have done so far.
+    pub fn dry_run_plan(&self) -> Option<DryRunPlan> {
+        match self {
+            Self::DryRun(s) => Some(s.plan()),
+            _ => None,
+        }
+    }
+
     pub fn unreliable_wrapper(s: Self, fail_first: u64) -> Self {
         Self::Unreliable(Arc::new(UnreliableWrapper::new(s, fail_first)))
     }
@@ -323,26 +978,114 @@ impl GenericRemoteStorage {
         &self,
         byte_range: Option<(u64, Option<u64>)>,
         from: &RemotePath,
-    ) -> Result<Download, DownloadError> {
+    ) -> Result<Download, RemoteStorageError> {
         match byte_range {
             Some((start, end)) => self.download_byte_range(from, start, end).await,
             None => self.download(from).await,
         }
     }
+
+    /// Opens the object for reading, to relay its contents, e.g. to a network client, without
+    /// a scratch file. Unlike [`Self::download`], the stream has the contents as they were
+    /// before the upload compressed them with the object's [`Compression`].
+    ///
+    /// An object compressed with a [`ZstdDictionary`] is rejected: it can only be decompressed
+    /// with the dictionary, by the user that compressed it.
+    pub async fn open_read(&self, from: &RemotePath) -> Result<ReadStream, RemoteStorageError> {
+        let download = self.download(from).await?;
+        if let Some(name) = ZstdDictionary::name_from_metadata(download.metadata.as_ref()) {
+            return Err(RemoteStorageError::Permanent(anyhow::anyhow!(
+                "Cannot read {from}: it is compressed with the zstd dictionary {name}"
+            )));
+        }
+        let compression = Compression::from_metadata(download.metadata.as_ref())?;
+        Ok(compression.decompress(download.download_stream))
+    }
 }
 
 /// Extra set of key-value pairs that contain arbitrary metadata about the storage entry.
 /// Immutable, cannot be changed once the file is created.
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
 pub struct StorageMetadata(HashMap<String, String>);
 
+const IMMUTABLE_METADATA_KEY: &str = "immutable";
+
+impl StorageMetadata {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).map(String::as_str)
+    }
+
+    /// Marks the object being uploaded as one that is never overwritten with other contents,
+    /// e.g. a layer archive, so that the storages read through an HTTP cache let it be cached
+    /// for good.
+    pub fn mark_immutable(metadata: Option<StorageMetadata>) -> Option<StorageMetadata> {
+        let mut metadata = metadata.unwrap_or_default();
+        metadata
+            .0
+            .insert(IMMUTABLE_METADATA_KEY.to_owned(), "true".to_owned());
+        Some(metadata)
+    }
+
+    /// Whether the object was uploaded with the metadata of [`Self::mark_immutable`].
+    pub fn is_immutable(metadata: Option<&StorageMetadata>) -> bool {
+        metadata.is_some_and(|metadata| metadata.get(IMMUTABLE_METADATA_KEY).is_some())
+    }
+}
+
+impl<const N: usize> From<[(&str, &str); N]> for StorageMetadata {
+    fn from(arr: [(&str, &str); N]) -> Self {
+        let map: HashMap<String, String> = arr
+            .iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        Self(map)
+    }
+}
+
 /// External backup storage configuration, enough for creating a client for that storage.
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct RemoteStorageConfig {
     /// Max allowed number of concurrent sync operations between the API user and the remote storage.
     pub max_concurrent_syncs: NonZeroUsize,
+    /// Max allowed number of concurrent sync operations of a single tenant, on top of
+    /// [`Self::max_concurrent_syncs`]. `None` means a tenant can use all of those.
+    pub max_concurrent_sync_per_tenant: Option<NonZeroUsize>,
+    /// Max allowed number of concurrent sync operations until the initial load of the tenants
+    /// is done, in place of [`Self::max_concurrent_syncs`]. `None` means the same limit at startup.
+    pub max_concurrent_sync_startup: Option<NonZeroUsize>,
     /// Max allowed errors before the sync task is considered failed and evicted.
+    /// Only the tasks that failed after all of their [`Self::max_retries`] are counted.
     pub max_sync_errors: NonZeroU32,
+    /// How many times a failed remote storage request is retried, before it is considered failed.
+    /// Requests that failed with [`RemoteStorageError::is_permanent`] errors are not retried.
+    pub max_retries: u32,
+    /// The initial backoff between retries, doubled with every attempt and randomized ("jittered").
+    pub base_backoff_ms: u64,
+    /// Codec to compress the uploaded files with.
+    pub compression: Compression,
+    /// Limit of the total upload and download bandwidth, shared by all concurrent syncs.
+    /// `None` means unlimited.
+    pub max_bytes_per_sec: Option<NonZeroU64>,
+    /// Only log the uploads and deletions, without making them. Listings and downloads are
+    /// still made, so that the logged plan is the same as the real one would be.
+    pub dry_run: bool,
+    /// File with the 32 byte key to encrypt the uploaded files with, on the client side.
+    /// `None` uploads the files unencrypted.
+    pub encryption_key_file: Option<PathBuf>,
+    /// Time for a single attempt of an upload, a download or a deletion to go without progress,
+    /// i.e. no bytes uploaded or downloaded, before it's cancelled and retried. A long transfer
+    /// that keeps moving is not cancelled, see [`timeout_without_progress`].
+    pub operation_timeout: Duration,
+    /// Same as [`Self::operation_timeout`], for the listings, which can take many requests.
+    pub list_timeout: Duration,
+    /// Max random delay of the start of a layer upload, to spread the uploads of the many
+    /// timelines that flush their layers at the same time. Someone waiting for the uploads
+    /// to complete cuts the delay short.
+    pub upload_start_jitter: Duration,
+    /// Path prepended to every path of the storage, whatever its built-in kind, so that several
+    /// pageservers can share one bucket, each under its own prefix. The listings don't go
+    /// outside of it. Set without the leading and trailing `/`.
+    pub prefix: Option<String>,
     /// The storage connection configuration.
     pub storage: RemoteStorageKind,
 }
@@ -352,10 +1095,85 @@ pub struct RemoteStorageConfig {
 pub enum RemoteStorageKind {
     /// Storage based on local file system.
     /// Specify a root folder to place all stored files into.
-    LocalFs(PathBuf),
+    LocalFs(LocalFsConfig),
     /// AWS S3 based storage, storing all files in the S3 bucket
     /// specified by the config
     AwsS3(S3Config),
+    /// Azure Blob Storage based storage, storing all files in the container
+    /// specified by the config
+    AzureBlob(AzureConfig),
+    /// Google Cloud Storage based storage, storing all files in the bucket
+    /// specified by the config
+    Gcs(GcsConfig),
+    /// SFTP based storage, storing all files in a directory on the server
+    /// specified by the config
+    Sftp(SftpConfig),
+    /// Read-only storage on a plain HTTP(S) server, e.g. a CDN, listing its files
+    /// in an index file, see [`HttpConfig`]
+    HttpReadOnly(HttpConfig),
+}
+
+impl RemoteStorageKind {
+    /// The same storage, with all of its paths under `prefix`: on top of the prefix the kind
+    /// might already have, e.g. `prefix_in_bucket` of S3.
+    fn with_prefix(&self, prefix: &str) -> Self {
+        let join_prefix = |outer: Option<&str>| {
+            let outer = outer
+                .unwrap_or_default()
+                .trim_matches(REMOTE_STORAGE_PREFIX_SEPARATOR);
+            Some(if outer.is_empty() {
+                prefix.to_string()
+            } else {
+                format!("{outer}{REMOTE_STORAGE_PREFIX_SEPARATOR}{prefix}")
+            })
+        };
+
+        match self {
+            Self::LocalFs(local_fs_config) => Self::LocalFs(LocalFsConfig {
+                local_path: local_fs_config.local_path.join(prefix),
+                ..local_fs_config.clone()
+            }),
+            Self::AwsS3(s3_config) => Self::AwsS3(S3Config {
+                prefix_in_bucket: join_prefix(s3_config.prefix_in_bucket.as_deref()),
+                ..s3_config.clone()
+            }),
+            Self::AzureBlob(azure_config) => Self::AzureBlob(AzureConfig {
+                prefix_in_container: join_prefix(azure_config.prefix_in_container.as_deref()),
+                ..azure_config.clone()
+            }),
+            Self::Gcs(gcs_config) => Self::Gcs(GcsConfig {
+                prefix_in_bucket: join_prefix(gcs_config.prefix_in_bucket.as_deref()),
+                ..gcs_config.clone()
+            }),
+            Self::Sftp(sftp_config) => Self::Sftp(SftpConfig {
+                root_path: sftp_config.root_path.join(prefix),
+                ..sftp_config.clone()
+            }),
+            Self::HttpReadOnly(http_config) => Self::HttpReadOnly(HttpConfig {
+                base_url: format!(
+                    "{}{REMOTE_STORAGE_PREFIX_SEPARATOR}{prefix}{REMOTE_STORAGE_PREFIX_SEPARATOR}",
+                    http_config
+                        .base_url
+                        .trim_end_matches(REMOTE_STORAGE_PREFIX_SEPARATOR)
+                ),
+                ..http_config.clone()
+            }),
+        }
+    }
+}
+
+/// A directory of the local file system to keep the files in.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LocalFsConfig {
+    pub local_path: PathBuf,
+    /// Only list and download the files of an existing directory, e.g. a read-only mount of
+    /// a snapshot: the directory is not created, and uploads and deletions fail.
+    pub read_only: bool,
+    /// Share one directory fsync between the uploads renamed into the directory within this
+    /// window, instead of one fsync each. The uploads are still durable once they return.
+    pub fsync_batch_window: Option<Duration>,
+    /// Write the uploads of at least this many bytes with `O_DIRECT`, bypassing the page cache.
+    pub direct_io_min_size: Option<u64>,
 }
 
 /// AWS S3 bucket coordinates and access credentials to manage the bucket contents (read and write).
@@ -374,10 +1192,40 @@ pub struct S3Config {
     ///
     /// Example: `http://127.0.0.1:5000`
     pub endpoint: Option<String>,
+    /// Address the bucket in the path of the URL (`http://endpoint/bucket/key`), instead of its
+    /// host name (`http://bucket.endpoint/key`). MinIO and many other S3 flavors need that.
+    /// Defaults to path style with a custom `endpoint`, and to virtual-hosted style otherwise.
+    pub force_path_style: Option<bool>,
     /// AWS S3 has various limits on its API calls, we need not to exceed those.
     /// See [`DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT`] for more details.
     pub concurrency_limit: NonZeroUsize,
     pub max_keys_per_list_response: Option<i32>,
+    /// Size of a single part of the multipart upload, in bytes.
+    /// Objects that fit into one part are uploaded with a single request.
+    pub multipart_part_size: NonZeroUsize,
+    /// Max number of parts of a single object uploaded concurrently.
+    pub multipart_upload_concurrency: NonZeroUsize,
+    /// Local directory to keep the progress of the multipart uploads in, so that an upload
+    /// interrupted by an error or a restart continues with the parts already uploaded.
+    /// Interrupted uploads are aborted and start over if not set.
+    pub multipart_resume_dir: Option<PathBuf>,
+    /// Age of the unfinished multipart uploads under the prefix to abort at the first
+    /// multipart upload of the process. They are left to the bucket's lifecycle rules if not set.
+    pub multipart_upload_max_age: Option<Duration>,
+    /// S3 storage class of the uploaded objects, e.g. `STANDARD_IA`.
+    /// The bucket's default class is used if not set. The archive classes, `GLACIER` and
+    /// `DEEP_ARCHIVE`, are rejected: their objects cannot be downloaded without a restore.
+    pub storage_class: Option<String>,
+    /// `Content-Type` of the objects uploaded uncompressed, `application/octet-stream` if not
+    /// set. The compressed objects get the type of their codec, e.g. `application/zstd`.
+    pub content_type: Option<String>,
+    /// `Cache-Control` of the objects that may be overwritten, e.g. the index parts, not sent if
+    /// not set. The immutable objects, like the layer archives, are always cacheable for a year.
+    pub cache_control: Option<String>,
+    /// Size of the largest object to upload, for the S3 flavors that take smaller objects than
+    /// AWS S3 does. The larger files are split into several objects by the user. Defaults to the
+    /// largest object AWS S3 takes with the `multipart_part_size`.
+    pub max_object_size: Option<u64>,
 }
 
 impl Debug for S3Config {
@@ -386,20 +1234,155 @@ impl Debug for S3Config {
             .field("bucket_name", &self.bucket_name)
             .field("bucket_region", &self.bucket_region)
             .field("prefix_in_bucket", &self.prefix_in_bucket)
+            .field("endpoint", &self.endpoint)
+            .field("force_path_style", &self.force_path_style)
             .field("concurrency_limit", &self.concurrency_limit)
             .field(
                 "max_keys_per_list_response",
                 &self.max_keys_per_list_response,
             )
+            .field("multipart_part_size", &self.multipart_part_size)
+            .field(
+                "multipart_upload_concurrency",
+                &self.multipart_upload_concurrency,
+            )
+            .field("multipart_resume_dir", &self.multipart_resume_dir)
+            .field("multipart_upload_max_age", &self.multipart_upload_max_age)
+            .field("storage_class", &self.storage_class)
+            .field("content_type", &self.content_type)
+            .field("cache_control", &self.cache_control)
+            .field("max_object_size", &self.max_object_size)
             .finish()
     }
 }
 
+/// Azure Blob Storage container coordinates to manage the container contents (read and write).
+/// The access key is not part of the config and is read from the `AZURE_STORAGE_ACCESS_KEY`
+/// environment variable, token based credentials are used if it's not set.
+#[derive(Clone, PartialEq, Eq)]
+pub struct AzureConfig {
+    /// Name of the container to connect to.
+    pub container_name: String,
+    /// The region where the container is located at.
+    pub container_region: String,
+    /// Storage account the container belongs to.
+    /// Taken from the `AZURE_STORAGE_ACCOUNT` environment variable, if not set.
+    pub storage_account: Option<String>,
+    /// A "subfolder" in the container, to use the same container separately by multiple remote storage users at once.
+    pub prefix_in_container: Option<String>,
+    /// Azure has various limits on its API calls, we need not to exceed those.
+    /// See [`DEFAULT_REMOTE_STORAGE_AZURE_CONCURRENCY_LIMIT`] for more details.
+    pub concurrency_limit: NonZeroUsize,
+    pub max_keys_per_list_response: Option<i32>,
+}
+
+impl Debug for AzureConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("AzureConfig")
+            .field("container_name", &self.container_name)
+            .field("container_region", &self.container_region)
+            .field("storage_account", &self.storage_account)
+            .field("prefix_in_container", &self.prefix_in_container)
+            .field("concurrency_limit", &self.concurrency_limit)
+            .field(
+                "max_keys_per_list_response",
+                &self.max_keys_per_list_response,
+            )
+            .finish()
+    }
+}
+
+/// Google Cloud Storage bucket coordinates and credentials to manage the bucket contents (read and write).
+#[derive(Clone, PartialEq, Eq)]
+pub struct GcsConfig {
+    /// Name of the bucket to connect to.
+    pub bucket_name: String,
+    /// A "subfolder" in the bucket, to use the same bucket separately by multiple remote storage users at once.
+    pub prefix_in_bucket: Option<String>,
+    /// Path to the service account JSON key file, used to authenticate the requests.
+    /// Taken from the `GOOGLE_APPLICATION_CREDENTIALS` environment variable, if not set.
+    pub service_account_key_path: Option<PathBuf>,
+    /// A base URL to send GCS requests to, `https://storage.googleapis.com` by default.
+    pub endpoint: Option<String>,
+    /// GCS has various limits on its API calls, we need not to exceed those.
+    /// See [`DEFAULT_REMOTE_STORAGE_GCS_CONCURRENCY_LIMIT`] for more details.
+    pub concurrency_limit: NonZeroUsize,
+    pub max_keys_per_list_response: Option<i32>,
+}
+
+impl Debug for GcsConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("GcsConfig")
+            .field("bucket_name", &self.bucket_name)
+            .field("prefix_in_bucket", &self.prefix_in_bucket)
+            .field("service_account_key_path", &self.service_account_key_path)
+            .field("endpoint", &self.endpoint)
+            .field("concurrency_limit", &self.concurrency_limit)
+            .field(
+                "max_keys_per_list_response",
+                &self.max_keys_per_list_response,
+            )
+            .finish()
+    }
+}
+
+/// SFTP server coordinates and credentials to manage the files in a directory there (read and write).
+#[derive(Clone, PartialEq, Eq)]
+pub struct SftpConfig {
+    /// Host name or IP address of the server.
+    pub host: String,
+    pub port: u16,
+    pub username: String,
+    /// Path to the private key to authenticate with, in any format OpenSSH reads.
+    pub key_path: PathBuf,
+    /// The `known_hosts` file to check the server's host key against,
+    /// `~/.ssh/known_hosts` if not set. Unknown servers are never connected to.
+    pub known_hosts_path: Option<PathBuf>,
+    /// Directory on the server to store all files in.
+    /// Relative paths start from the login directory of the user.
+    pub root_path: PathBuf,
+    /// Max number of SFTP sessions open at once, each running one request at a time.
+    /// See [`DEFAULT_REMOTE_STORAGE_SFTP_CONCURRENCY_LIMIT`] for more details.
+    pub concurrency_limit: NonZeroUsize,
+}
+
+impl Debug for SftpConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("SftpConfig")
+            .field("host", &self.host)
+            .field("port", &self.port)
+            .field("username", &self.username)
+            .field("key_path", &self.key_path)
+            .field("known_hosts_path", &self.known_hosts_path)
+            .field("root_path", &self.root_path)
+            .field("concurrency_limit", &self.concurrency_limit)
+            .finish()
+    }
+}
+
+/// A copy of the remote storage contents served over plain HTTP(S), e.g. by a CDN, to download
+/// from without any credentials. Uploads and deletions fail.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HttpConfig {
+    /// URL the paths of the objects are relative to.
+    pub base_url: String,
+    /// URL of the file listing the paths of the objects, one per line.
+    /// `index.txt` under the `base_url` if not set.
+    pub index_url: Option<String>,
+    /// See [`DEFAULT_REMOTE_STORAGE_HTTP_CONCURRENCY_LIMIT`] for more details.
+    pub concurrency_limit: NonZeroUsize,
+}
+
 impl RemoteStorageConfig {
     pub fn from_toml(toml: &toml_edit::Item) -> anyhow::Result<Option<RemoteStorageConfig>> {
         let local_path = toml.get("local_path");
         let bucket_name = toml.get("bucket_name");
         let bucket_region = toml.get("bucket_region");
+        let container_name = toml.get("container_name");
+        let container_region = toml.get("container_region");
+        let gcs_bucket = toml.get("gcs_bucket");
+        let sftp_host = toml.get("sftp_host");
+        let http_base_url = toml.get("http_base_url");
 
         let max_concurrent_syncs = NonZeroUsize::new(
             parse_optional_integer("max_concurrent_syncs", toml)?
@@ -407,15 +1390,95 @@ impl RemoteStorageConfig {
         )
         .context("Failed to parse 'max_concurrent_syncs' as a positive integer")?;
 
+        // 0 is the same as not setting the limit at all
+        let max_concurrent_sync_per_tenant =
+            parse_optional_integer::<usize, _>("max_concurrent_sync_per_tenant", toml)?
+                .and_then(NonZeroUsize::new);
+
+        // 0 is the same as not setting it, i.e. using max_concurrent_syncs from the start
+        let max_concurrent_sync_startup =
+            parse_optional_integer::<usize, _>("max_concurrent_sync_startup", toml)?
+                .and_then(NonZeroUsize::new);
+
         let max_sync_errors = NonZeroU32::new(
             parse_optional_integer("max_sync_errors", toml)?
                 .unwrap_or(DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS),
         )
         .context("Failed to parse 'max_sync_errors' as a positive integer")?;
 
+        let max_retries = parse_optional_integer("max_retries", toml)?
+            .unwrap_or(DEFAULT_REMOTE_STORAGE_MAX_RETRIES);
+
+        let base_backoff_ms = parse_optional_integer("base_backoff_ms", toml)?
+            .unwrap_or(DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS);
+
+        let compression = toml
+            .get("compression")
+            .map(|compression| parse_toml_string("compression", compression)?.parse())
+            .transpose()
+            .context("Failed to parse 'compression'")?
+            .unwrap_or_default();
+
+        // 0 is the same as not setting the limit at all
+        let max_bytes_per_sec =
+            parse_optional_integer::<u64, _>("max_bytes_per_sec", toml)?.and_then(NonZeroU64::new);
+
+        let dry_run = toml
+            .get("dry_run")
+            .map(|dry_run| {
+                dry_run
+                    .as_bool()
+                    .context("configure option dry_run is not a bool")
+            })
+            .transpose()?
+            .unwrap_or(false);
+
+        let encryption_key_file = toml
+            .get("encryption_key_file")
+            .map(|key_file| parse_toml_string("encryption_key_file", key_file).map(PathBuf::from))
+            .transpose()?;
+
+        let operation_timeout = parse_optional_duration("operation_timeout", toml)?
+            .unwrap_or(DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT);
+        let list_timeout = parse_optional_duration("list_timeout", toml)?
+            .unwrap_or(DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT);
+        let upload_start_jitter = parse_optional_duration("upload_start_jitter", toml)?
+            .unwrap_or(DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER);
+        let read_only = toml
+            .get("read_only")
+            .map(|read_only| {
+                read_only
+                    .as_bool()
+                    .context("configure option read_only is not a bool")
+            })
+            .transpose()?
+            .unwrap_or(false);
+        if read_only && local_path.is_none() {
+            bail!("'read_only' option is only supported with 'local_path'");
+        }
+        let fsync_batch_window = parse_optional_duration("fsync_batch_window", toml)?;
+        let direct_io_min_size = parse_optional_integer::<u64, _>("direct_io_min_size", toml)?;
+        if (fsync_batch_window.is_some() || direct_io_min_size.is_some()) && local_path.is_none() {
+            bail!("'fsync_batch_window' and 'direct_io_min_size' options are only supported with 'local_path'");
+        }
+        let prefix = toml
+            .get("prefix")
+            .map(|prefix| parse_prefix(&parse_toml_string("prefix", prefix)?))
+            .transpose()?;
+
+        let default_concurrency_limit = if container_name.is_some() {
+            DEFAULT_REMOTE_STORAGE_AZURE_CONCURRENCY_LIMIT
+        } else if gcs_bucket.is_some() {
+            DEFAULT_REMOTE_STORAGE_GCS_CONCURRENCY_LIMIT
+        } else if sftp_host.is_some() {
+            DEFAULT_REMOTE_STORAGE_SFTP_CONCURRENCY_LIMIT
+        } else if http_base_url.is_some() {
+            DEFAULT_REMOTE_STORAGE_HTTP_CONCURRENCY_LIMIT
+        } else {
+            DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT
+        };
         let concurrency_limit = NonZeroUsize::new(
-            parse_optional_integer("concurrency_limit", toml)?
-                .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_CONCURRENCY_LIMIT),
+            parse_optional_integer("concurrency_limit", toml)?.unwrap_or(default_concurrency_limit),
         )
         .context("Failed to parse 'concurrency_limit' as a positive integer")?;
 
@@ -424,22 +1487,120 @@ impl RemoteStorageConfig {
                 .context("Failed to parse 'max_keys_per_list_response' as a positive integer")?
                 .or(DEFAULT_MAX_KEYS_PER_LIST_RESPONSE);
 
-        let storage = match (local_path, bucket_name, bucket_region) {
-            // no 'local_path' nor 'bucket_name' options are provided, consider this remote storage disabled
-            (None, None, None) => return Ok(None),
-            (_, Some(_), None) => {
+        let storage = match (
+            local_path,
+            bucket_name,
+            bucket_region,
+            container_name,
+            container_region,
+            gcs_bucket,
+            sftp_host,
+            http_base_url,
+        ) {
+            // no 'local_path' nor 'bucket_name' nor 'container_name' nor 'gcs_bucket' nor 'sftp_host' nor 'http_base_url' options are provided, consider this remote storage disabled
+            (None, None, None, None, None, None, None, None) => return Ok(None),
+            (_, Some(_), None, ..) => {
                 bail!("'bucket_region' option is mandatory if 'bucket_name' is given ")
             }
-            (_, None, Some(_)) => {
+            (_, None, Some(_), ..) => {
                 bail!("'bucket_name' option is mandatory if 'bucket_region' is given ")
             }
-            (None, Some(bucket_name), Some(bucket_region)) => RemoteStorageKind::AwsS3(S3Config {
-                bucket_name: parse_toml_string("bucket_name", bucket_name)?,
-                bucket_region: parse_toml_string("bucket_region", bucket_region)?,
+            (_, _, _, Some(_), None, ..) => {
+                bail!("'container_region' option is mandatory if 'container_name' is given ")
+            }
+            (_, _, _, None, Some(_), ..) => {
+                bail!("'container_name' option is mandatory if 'container_region' is given ")
+            }
+            (None, Some(bucket_name), Some(bucket_region), None, None, None, None, None) => {
+                RemoteStorageKind::AwsS3(S3Config {
+                    bucket_name: parse_toml_string("bucket_name", bucket_name)?,
+                    bucket_region: parse_toml_string("bucket_region", bucket_region)?,
+                    prefix_in_bucket: toml
+                        .get("prefix_in_bucket")
+                        .map(|prefix_in_bucket| {
+                            parse_toml_string("prefix_in_bucket", prefix_in_bucket)
+                        })
+                        .transpose()?,
+                    endpoint: toml
+                        .get("endpoint")
+                        .map(|endpoint| parse_toml_string("endpoint", endpoint))
+                        .transpose()?,
+                    force_path_style: toml
+                        .get("force_path_style")
+                        .map(|force_path_style| {
+                            force_path_style
+                                .as_bool()
+                                .context("configure option force_path_style is not a bool")
+                        })
+                        .transpose()?,
+                    concurrency_limit,
+                    max_keys_per_list_response,
+                    multipart_part_size: NonZeroUsize::new(
+                        parse_optional_integer("multipart_part_size", toml)?
+                            .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE),
+                    )
+                    .context("Failed to parse 'multipart_part_size' as a positive integer")?,
+                    multipart_upload_concurrency: NonZeroUsize::new(
+                        parse_optional_integer("multipart_upload_concurrency", toml)?
+                            .unwrap_or(DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_CONCURRENCY),
+                    )
+                    .context(
+                        "Failed to parse 'multipart_upload_concurrency' as a positive integer",
+                    )?,
+                    multipart_resume_dir: toml
+                        .get("multipart_resume_dir")
+                        .map(|dir| parse_toml_string("multipart_resume_dir", dir).map(PathBuf::from))
+                        .transpose()?,
+                    multipart_upload_max_age: parse_optional_duration(
+                        "multipart_upload_max_age",
+                        toml,
+                    )?,
+                    storage_class: toml
+                        .get("storage_class")
+                        .map(|storage_class| parse_toml_string("storage_class", storage_class))
+                        .transpose()?,
+                    content_type: toml
+                        .get("content_type")
+                        .map(|content_type| parse_toml_string("content_type", content_type))
+                        .transpose()?,
+                    cache_control: toml
+                        .get("cache_control")
+                        .map(|cache_control| parse_toml_string("cache_control", cache_control))
+                        .transpose()?,
+                    max_object_size: parse_optional_integer("max_object_size", toml)?,
+                })
+            }
+            (None, None, None, Some(container_name), Some(container_region), None, None, None) => {
+                RemoteStorageKind::AzureBlob(AzureConfig {
+                    container_name: parse_toml_string("container_name", container_name)?,
+                    container_region: parse_toml_string("container_region", container_region)?,
+                    storage_account: toml
+                        .get("storage_account")
+                        .map(|storage_account| {
+                            parse_toml_string("storage_account", storage_account)
+                        })
+                        .transpose()?,
+                    prefix_in_container: toml
+                        .get("prefix_in_container")
+                        .map(|prefix_in_container| {
+                            parse_toml_string("prefix_in_container", prefix_in_container)
+                        })
+                        .transpose()?,
+                    concurrency_limit,
+                    max_keys_per_list_response,
+                })
+            }
+            (None, None, None, None, None, Some(gcs_bucket), None, None) => RemoteStorageKind::Gcs(GcsConfig {
+                bucket_name: parse_toml_string("gcs_bucket", gcs_bucket)?,
                 prefix_in_bucket: toml
                     .get("prefix_in_bucket")
                     .map(|prefix_in_bucket| parse_toml_string("prefix_in_bucket", prefix_in_bucket))
                     .transpose()?,
+                service_account_key_path: toml
+                    .get("service_account_key_path")
+                    .map(|path| parse_toml_string("service_account_key_path", path))
+                    .transpose()?
+                    .map(PathBuf::from),
                 endpoint: toml
                     .get("endpoint")
                     .map(|endpoint| parse_toml_string("endpoint", endpoint))
@@ -447,18 +1608,94 @@ impl RemoteStorageConfig {
                 concurrency_limit,
                 max_keys_per_list_response,
             }),
-            (Some(local_path), None, None) => RemoteStorageKind::LocalFs(PathBuf::from(
-                parse_toml_string("local_path", local_path)?,
-            )),
-            (Some(_), Some(_), _) => bail!("local_path and bucket_name are mutually exclusive"),
+            (None, None, None, None, None, None, Some(sftp_host), None) => {
+                RemoteStorageKind::Sftp(SftpConfig {
+                    host: parse_toml_string("sftp_host", sftp_host)?,
+                    port: parse_optional_integer("sftp_port", toml)?.unwrap_or(DEFAULT_SFTP_PORT),
+                    username: parse_toml_string(
+                        "sftp_username",
+                        toml.get("sftp_username")
+                            .context("'sftp_username' option is mandatory if 'sftp_host' is given")?,
+                    )?,
+                    key_path: PathBuf::from(parse_toml_string(
+                        "sftp_key_path",
+                        toml.get("sftp_key_path")
+                            .context("'sftp_key_path' option is mandatory if 'sftp_host' is given")?,
+                    )?),
+                    known_hosts_path: toml
+                        .get("sftp_known_hosts_path")
+                        .map(|path| parse_toml_string("sftp_known_hosts_path", path))
+                        .transpose()?
+                        .map(PathBuf::from),
+                    root_path: PathBuf::from(parse_toml_string(
+                        "sftp_root_path",
+                        toml.get("sftp_root_path")
+                            .context("'sftp_root_path' option is mandatory if 'sftp_host' is given")?,
+                    )?),
+                    concurrency_limit,
+                })
+            }
+            (None, None, None, None, None, None, None, Some(http_base_url)) => {
+                RemoteStorageKind::HttpReadOnly(HttpConfig {
+                    base_url: parse_toml_string("http_base_url", http_base_url)?,
+                    index_url: toml
+                        .get("http_index_url")
+                        .map(|index_url| parse_toml_string("http_index_url", index_url))
+                        .transpose()?,
+                    concurrency_limit,
+                })
+            }
+            (Some(local_path), None, None, None, None, None, None, None) => {
+                RemoteStorageKind::LocalFs(LocalFsConfig {
+                    local_path: PathBuf::from(parse_toml_string("local_path", local_path)?),
+                    read_only,
+                    fsync_batch_window,
+                    direct_io_min_size,
+                })
+            }
+            _ => bail!(
+                "local_path, bucket_name, container_name, gcs_bucket, sftp_host and http_base_url are mutually exclusive"
+            ),
         };
 
         Ok(Some(RemoteStorageConfig {
             max_concurrent_syncs,
+            max_concurrent_sync_per_tenant,
+            max_concurrent_sync_startup,
             max_sync_errors,
+            max_retries,
+            base_backoff_ms,
+            compression,
+            max_bytes_per_sec,
+            dry_run,
+            encryption_key_file,
+            operation_timeout,
+            list_timeout,
+            upload_start_jitter,
+            prefix,
             storage,
         }))
     }
+
+    /// Parses either a single storage, same as [`Self::from_toml`], or a list of them, as an
+    /// array of tables (`[[remote_storage]]`) or of inline tables. The unconfigured ones, i.e.
+    /// the empty tables, are skipped.
+    pub fn list_from_toml(toml: &toml_edit::Item) -> anyhow::Result<Vec<RemoteStorageConfig>> {
+        let items = match toml {
+            Item::ArrayOfTables(tables) => tables.iter().cloned().map(Item::Table).collect(),
+            Item::Value(toml_edit::Value::Array(array)) => {
+                array.iter().cloned().map(Item::Value).collect()
+            }
+            item => vec![item.clone()],
+        };
+        let mut configs = Vec::with_capacity(items.len());
+        for (i, item) in items.iter().enumerate() {
+            let config = Self::from_toml(item)
+                .with_context(|| format!("Failed to parse the remote storage number {}", i + 1))?;
+            configs.extend(config);
+        }
+        Ok(configs)
+    }
 }
 
 // Helper functions to parse a toml Item
@@ -479,6 +1716,28 @@ where
         .with_context(|| format!("configure option {name} is too large"))
 }
 
+fn parse_optional_duration(name: &str, item: &toml_edit::Item) -> anyhow::Result<Option<Duration>> {
+    item.get(name)
+        .map(|duration| {
+            let duration = parse_toml_string(name, duration)?;
+            humantime::parse_duration(&duration)
+                .with_context(|| format!("configure option {name} is not a valid duration"))
+        })
+        .transpose()
+}
+
+fn parse_prefix(prefix: &str) -> anyhow::Result<String> {
+    let prefix = prefix.trim_matches(REMOTE_STORAGE_PREFIX_SEPARATOR);
+    anyhow::ensure!(!prefix.is_empty(), "configure option prefix is empty");
+    anyhow::ensure!(
+        prefix
+            .split(REMOTE_STORAGE_PREFIX_SEPARATOR)
+            .all(|segment| !matches!(segment, "" | "." | "..")),
+        "configure option prefix '{prefix}' is not a plain relative path"
+    );
+    Ok(prefix.to_string())
+}
+
 fn parse_toml_string(name: &str, item: &Item) -> anyhow::Result<String> {
     let s = item
         .as_str()
@@ -511,4 +1770,518 @@ mod tests {
         let err = RemotePath::new(Path::new("/")).expect_err("Should fail on absolute paths");
         assert_eq!(err.to_string(), "Path \"/\" is not relative");
     }
+
+    /// The contents of an object in the [`InMemoryStorage`], and its metadata.
+    type InMemoryObject = (Vec<u8>, Option<StorageMetadata>);
+
+    /// Keeps the objects in memory, to check that storages from outside of the crate plug in.
+    #[derive(Default)]
+    struct InMemoryStorage {
+        objects: std::sync::Mutex<HashMap<RemotePath, InMemoryObject>>,
+        max_object_size: Option<u64>,
+    }
+
+    impl InMemoryStorage {
+        fn get(&self, path: &RemotePath) -> Result<InMemoryObject, RemoteStorageError> {
+            self.objects
+                .lock()
+                .unwrap()
+                .get(path)
+                .cloned()
+                .ok_or(RemoteStorageError::NotFound)
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl RemoteStorage for InMemoryStorage {
+        async fn list_prefixes(
+            &self,
+            prefix: Option<&RemotePath>,
+        ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+            let prefix = prefix.map_or(Path::new(""), |prefix| prefix.get_path());
+            let prefixes = self
+                .objects
+                .lock()
+                .unwrap()
+                .keys()
+                .filter_map(|path| {
+                    let mut components = path.get_path().strip_prefix(prefix).ok()?.components();
+                    let child = components.next()?;
+                    // A child with no objects under it is an object, not a prefix.
+                    components.next()?;
+                    Some(RemotePath::new(&prefix.join(child)).expect("the keys are relative"))
+                })
+                .collect::<std::collections::HashSet<_>>();
+            Ok(prefixes.into_iter().collect())
+        }
+
+        async fn list_files(
+            &self,
+            folder: Option<&RemotePath>,
+        ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+            let folder = folder.map_or(Path::new(""), |folder| folder.get_path());
+            Ok(self
+                .objects
+                .lock()
+                .unwrap()
+                .keys()
+                .filter(|path| path.get_path().starts_with(folder))
+                .cloned()
+                .collect())
+        }
+
+        fn max_object_size(&self) -> Option<u64> {
+            self.max_object_size
+        }
+
+        async fn upload(
+            &self,
+            mut from: UploadStream,
+            _data_size_bytes: usize,
+            to: &RemotePath,
+            metadata: Option<StorageMetadata>,
+        ) -> Result<(), RemoteStorageError> {
+            let mut contents = Vec::new();
+            tokio::io::AsyncReadExt::read_to_end(&mut from, &mut contents)
+                .await
+                .map_err(anyhow::Error::from)?;
+            self.objects
+                .lock()
+                .unwrap()
+                .insert(to.clone(), (contents, metadata));
+            Ok(())
+        }
+
+        async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
+            let (contents, metadata) = self.get(from)?;
+            Ok(Download {
+                download_stream: Box::pin(std::io::Cursor::new(contents)),
+                metadata,
+            })
+        }
+
+        async fn download_byte_range(
+            &self,
+            from: &RemotePath,
+            start_inclusive: u64,
+            end_exclusive: Option<u64>,
+        ) -> Result<Download, RemoteStorageError> {
+            let (contents, metadata) = self.get(from)?;
+            let len = contents.len() as u64;
+            let end = end_exclusive.map_or(len, |end| end.min(len));
+            let start = start_inclusive.min(end);
+            Ok(Download {
+                download_stream: Box::pin(std::io::Cursor::new(
+                    contents[start as usize..end as usize].to_vec(),
+                )),
+                metadata,
+            })
+        }
+
+        async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+            self.objects.lock().unwrap().remove(path);
+            Ok(())
+        }
+
+        async fn delete_objects<'a>(
+            &self,
+            paths: &'a [RemotePath],
+        ) -> Result<(), RemoteStorageError> {
+            for path in paths {
+                self.delete(path).await?;
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn error_kind_survives_anyhow() {
+        let not_found =
+            anyhow::Error::new(RemoteStorageError::NotFound).context("Failed to download");
+        assert!(is_permanent_error(&not_found));
+        assert!(matches!(
+            RemoteStorageError::from(not_found),
+            RemoteStorageError::NotFound
+        ));
+
+        let throttled = anyhow::Error::new(RemoteStorageError::from_http_status(
+            429,
+            anyhow::anyhow!("slow down"),
+        ));
+        assert!(!is_permanent_error(&throttled));
+        assert!(matches!(
+            RemoteStorageError::from(throttled),
+            RemoteStorageError::Throttled(_)
+        ));
+
+        let unknown = anyhow::anyhow!("connection reset");
+        assert!(!is_permanent_error(&unknown));
+        assert!(matches!(
+            RemoteStorageError::from(unknown),
+            RemoteStorageError::Transient(_)
+        ));
+    }
+
+    #[test]
+    fn head_response_headers() -> anyhow::Result<()> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert(reqwest::header::CONTENT_LENGTH, "1234".parse()?);
+        headers.insert(
+            reqwest::header::LAST_MODIFIED,
+            "Wed, 21 Oct 2015 07:28:00 GMT".parse()?,
+        );
+        let (size, last_modified) = size_and_modification_time(&headers)?;
+        assert_eq!(size, 1234);
+        assert_eq!(
+            last_modified,
+            Some(std::time::UNIX_EPOCH + Duration::from_secs(1_445_412_480))
+        );
+
+        headers.remove(reqwest::header::LAST_MODIFIED);
+        assert_eq!(size_and_modification_time(&headers)?, (1234, None));
+        headers.remove(reqwest::header::CONTENT_LENGTH);
+        assert!(size_and_modification_time(&headers).is_err());
+        Ok(())
+    }
+
+    fn parse_config(toml: &str) -> anyhow::Result<RemoteStorageConfig> {
+        let document = format!("remote_storage = {toml}").parse::<toml_edit::Document>()?;
+        RemoteStorageConfig::from_toml(&document["remote_storage"])?
+            .context("no remote storage configured")
+    }
+
+    #[test]
+    fn list_config() -> anyhow::Result<()> {
+        let document = "[[remote_storage]]
+local_path = 'nas'
+
+[[remote_storage]]
+bucket_name = 'bucket'
+bucket_region = 'region'
+"
+        .parse::<toml_edit::Document>()?;
+        let configs = RemoteStorageConfig::list_from_toml(&document["remote_storage"])?;
+        assert_eq!(configs.len(), 2);
+        assert!(matches!(configs[0].storage, RemoteStorageKind::LocalFs(_)));
+        assert!(matches!(configs[1].storage, RemoteStorageKind::AwsS3(_)));
+
+        let document = "remote_storage = [{ local_path = 'nas' }, { local_path = 'other' }]"
+            .parse::<toml_edit::Document>()?;
+        assert_eq!(
+            RemoteStorageConfig::list_from_toml(&document["remote_storage"])?.len(),
+            2
+        );
+
+        let document = "remote_storage = { local_path = 'nas' }".parse::<toml_edit::Document>()?;
+        assert_eq!(
+            RemoteStorageConfig::list_from_toml(&document["remote_storage"])?.len(),
+            1
+        );
+
+        let document =
+            "remote_storage = [{ local_path = 'nas' }, { local_path = 'a', bucket_name = 'b' }]"
+                .parse::<toml_edit::Document>()?;
+        let error = RemoteStorageConfig::list_from_toml(&document["remote_storage"]).unwrap_err();
+        assert!(
+            format!("{error:#}").contains("remote storage number 2"),
+            "{error:#}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn prefix_config() -> anyhow::Result<()> {
+        let config = parse_config(
+            "{ bucket_name = 'bucket', bucket_region = 'region', prefix_in_bucket = '/shared/', prefix = '/pageserver-1/' }",
+        )?;
+        assert_eq!(config.prefix.as_deref(), Some("pageserver-1"));
+        let RemoteStorageKind::AwsS3(s3_config) = config.storage.with_prefix("pageserver-1") else {
+            panic!("not an s3 config");
+        };
+        assert_eq!(
+            s3_config.prefix_in_bucket.as_deref(),
+            Some("shared/pageserver-1")
+        );
+
+        for invalid in ["/", "a/../b", "a//b"] {
+            assert!(
+                parse_config(&format!(
+                    "{{ local_path = 'unused', prefix = '{invalid}' }}"
+                ))
+                .is_err(),
+                "prefix '{invalid}' should be rejected"
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn read_only_config() -> anyhow::Result<()> {
+        let config = parse_config("{ local_path = '/snapshot', read_only = true }")?;
+        assert_eq!(
+            config.storage,
+            RemoteStorageKind::LocalFs(LocalFsConfig {
+                local_path: PathBuf::from("/snapshot"),
+                read_only: true,
+                fsync_batch_window: None,
+                direct_io_min_size: None,
+            })
+        );
+        assert!(parse_config(
+            "{ bucket_name = 'bucket', bucket_region = 'region', read_only = true }"
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn local_fs_write_options_config() -> anyhow::Result<()> {
+        let config = parse_config(
+            "{ local_path = '/data', fsync_batch_window = '5ms', direct_io_min_size = 1048576 }",
+        )?;
+        assert_eq!(
+            config.storage,
+            RemoteStorageKind::LocalFs(LocalFsConfig {
+                local_path: PathBuf::from("/data"),
+                read_only: false,
+                fsync_batch_window: Some(Duration::from_millis(5)),
+                direct_io_min_size: Some(1048576),
+            })
+        );
+        assert!(parse_config(
+            "{ bucket_name = 'bucket', bucket_region = 'region', fsync_batch_window = '5ms' }"
+        )
+        .is_err());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prefixes_share_a_storage() -> anyhow::Result<()> {
+        let root = tempfile::tempdir()?;
+        let storage = |prefix: &str| -> anyhow::Result<GenericRemoteStorage> {
+            let config = parse_config(&format!(
+                "{{ local_path = '{}', prefix = '{prefix}' }}",
+                root.path().display()
+            ))?;
+            GenericRemoteStorage::from_config(&config)
+        };
+        let storage_a = storage("pageserver-a")?;
+        let storage_b = storage("pageserver-b")?;
+        // Starts with the prefix of `storage_a`, but is not under it.
+        let storage_ab = storage("pageserver-ab")?;
+
+        let path = RemotePath::from_string("tenant/timeline/layer")?;
+        storage_a
+            .upload(std::io::Cursor::new(b"contents".to_vec()), 8, &path, None)
+            .await?;
+        storage_ab
+            .upload(std::io::Cursor::new(b"contents".to_vec()), 8, &path, None)
+            .await?;
+
+        // LocalFs lists the directories on the way to the files too.
+        let mut listed = storage_a.list_files(None).await?;
+        listed.sort();
+        assert_eq!(
+            listed,
+            vec![
+                RemotePath::from_string("tenant")?,
+                RemotePath::from_string("tenant/timeline")?,
+                path.clone()
+            ]
+        );
+        assert!(storage_b.list_files(None).await?.is_empty());
+        assert!(matches!(
+            storage_b.download(&path).await,
+            Err(RemoteStorageError::NotFound)
+        ));
+        assert!(root
+            .path()
+            .join("pageserver-a/tenant/timeline/layer")
+            .is_file());
+
+        storage_b.delete(&path).await?;
+        storage_a.download(&path).await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn custom_storage_roundtrip() -> anyhow::Result<()> {
+        let config = RemoteStorageConfig {
+            max_concurrent_syncs: NonZeroUsize::new(1).unwrap(),
+            max_concurrent_sync_per_tenant: None,
+            max_concurrent_sync_startup: None,
+            max_sync_errors: NonZeroU32::new(1).unwrap(),
+            max_retries: 0,
+            base_backoff_ms: 0,
+            compression: Compression::None,
+            max_bytes_per_sec: None,
+            dry_run: false,
+            encryption_key_file: None,
+            operation_timeout: DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+            list_timeout: DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+            upload_start_jitter: DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+            prefix: None,
+            storage: RemoteStorageKind::LocalFs(LocalFsConfig {
+                local_path: PathBuf::from("unused"),
+                read_only: false,
+                fsync_batch_window: None,
+                direct_io_min_size: None,
+            }),
+        };
+        let storage = GenericRemoteStorage::custom(&config, Arc::new(InMemoryStorage::default()))?;
+        let path = RemotePath::from_string("tenant/timeline/layer")?;
+        let metadata = StorageMetadata::from([("key", "value")]);
+
+        storage
+            .upload(
+                std::io::Cursor::new(b"contents".to_vec()),
+                8,
+                &path,
+                Some(metadata.clone()),
+            )
+            .await?;
+        assert_eq!(storage.list_files(None).await?, vec![path.clone()]);
+
+        let mut download = storage.download(&path).await?;
+        let mut contents = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut download.download_stream, &mut contents).await?;
+        assert_eq!(contents, b"contents");
+        assert_eq!(download.metadata, Some(metadata));
+
+        let stat = storage.stat(&path).await?;
+        assert_eq!(stat.size, 8);
+        assert_eq!(stat.metadata, download.metadata);
+
+        let copy_path = RemotePath::from_string("tenant/branch/layer")?;
+        storage.copy(&path, &copy_path).await?;
+        let copied = storage.download(&copy_path).await?;
+        assert_eq!(copied.metadata, download.metadata);
+
+        let tenant_path = RemotePath::from_string("tenant")?;
+        assert_eq!(
+            storage.list_prefixes(None).await?,
+            vec![tenant_path.clone()]
+        );
+        let mut timelines = storage.list_prefixes(Some(&tenant_path)).await?;
+        timelines.sort();
+        assert_eq!(
+            timelines,
+            vec![
+                RemotePath::from_string("tenant/branch")?,
+                RemotePath::from_string("tenant/timeline")?,
+            ]
+        );
+
+        let mut range = storage.download_byte_range(&path, 2, Some(6)).await?;
+        let mut contents = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut range.download_stream, &mut contents).await?;
+        assert_eq!(contents, b"nten");
+
+        storage.delete(&path).await?;
+        assert!(matches!(
+            storage.download(&path).await,
+            Err(RemoteStorageError::NotFound)
+        ));
+        assert!(matches!(
+            storage.stat(&path).await,
+            Err(RemoteStorageError::NotFound)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn open_read_decompresses() -> anyhow::Result<()> {
+        let storage = GenericRemoteStorage::Custom(Arc::new(InMemoryStorage::default()));
+        let path = RemotePath::from_string("tenant/timeline/layer")?;
+        let contents = b"contents ".repeat(100);
+
+        let mut compressed = Vec::new();
+        Compression::Zstd
+            .compress(contents.as_slice(), &mut compressed)
+            .await?;
+        storage
+            .upload(
+                std::io::Cursor::new(compressed.clone()),
+                compressed.len(),
+                &path,
+                Compression::Zstd.record_in_metadata(None),
+            )
+            .await?;
+        let mut read = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut storage.open_read(&path).await?, &mut read)
+            .await?;
+        assert_eq!(read, contents);
+
+        let dictionary = ZstdDictionary::new("dictionary".to_owned(), b"contents".to_vec());
+        let compressed = dictionary.compress(contents.clone()).await?;
+        storage
+            .upload(
+                std::io::Cursor::new(compressed.clone()),
+                compressed.len(),
+                &path,
+                dictionary.record_in_metadata(None),
+            )
+            .await?;
+        let error = storage.open_read(&path).await.err().unwrap();
+        assert!(error.is_permanent());
+
+        assert!(matches!(
+            storage
+                .open_read(&RemotePath::from_string("tenant/missing")?)
+                .await,
+            Err(RemoteStorageError::NotFound)
+        ));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listings_are_sorted() -> anyhow::Result<()> {
+        let storage = GenericRemoteStorage::Custom(Arc::new(InMemoryStorage::default()));
+        let names = [
+            "tenant/b",
+            "tenant/a/layer",
+            "tenant/a-1",
+            "tenant/a",
+            "tenant/0",
+        ];
+        for name in names {
+            let path = RemotePath::from_string(name)?;
+            storage
+                .upload(std::io::Cursor::new(Vec::new()), 0, &path, None)
+                .await?;
+        }
+
+        let listed = storage.list_files(None).await?;
+        assert_eq!(
+            listed.iter().map(ToString::to_string).collect::<Vec<_>>(),
+            [
+                "tenant/0",
+                "tenant/a",
+                "tenant/a-1",
+                "tenant/a/layer",
+                "tenant/b"
+            ]
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn uploads_over_max_object_size_are_rejected() -> anyhow::Result<()> {
+        let storage = GenericRemoteStorage::Custom(Arc::new(InMemoryStorage {
+            max_object_size: Some(8),
+            ..InMemoryStorage::default()
+        }));
+        let path = RemotePath::from_string("tenant/timeline/layer")?;
+
+        storage
+            .upload(std::io::Cursor::new(b"contents".to_vec()), 8, &path, None)
+            .await?;
+        let error = storage
+            .upload(std::io::Cursor::new(b"contents!".to_vec()), 9, &path, None)
+            .await
+            .unwrap_err();
+        assert!(error.is_permanent());
+        assert!(error.to_string().contains("at most 8 bytes"), "{error}");
+        Ok(())
+    }
 }
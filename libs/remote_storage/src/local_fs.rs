@@ -6,29 +6,59 @@
 
 use std::{
     borrow::Cow,
+    collections::HashSet,
     future::Future,
     io::ErrorKind,
     path::{Path, PathBuf},
     pin::Pin,
+    sync::Arc,
+    time::Duration,
 };
 
 use anyhow::{bail, ensure, Context};
+use futures_util::stream::{self, TryStreamExt};
 use tokio::{
     fs,
     io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
 };
 use tracing::*;
-use utils::{crashsafe::path_with_suffix_extension, fs_ext::is_directory_empty};
+use utils::{
+    crashsafe::{self, path_with_suffix_extension},
+    fs_ext::is_directory_empty,
+};
+
+use crate::{Download, ListingStream, ObjectMeta, RemotePath, RemoteStorageError};
+
+use super::{RemoteStorage, StorageMetadata, UploadStream};
 
-use crate::{Download, DownloadError, RemotePath};
+mod dir_fsync;
+mod direct_io;
 
-use super::{RemoteStorage, StorageMetadata};
+use self::dir_fsync::DirFsyncBatcher;
 
 const LOCAL_FS_TEMP_FILE_SUFFIX: &str = "___temp";
 
+/// How deep `list_files` goes into the directories of the storage. Remote paths are only a few
+/// levels deep, anything deeper is not ours and is not worth walking forever.
+const MAX_LIST_FILES_DEPTH: usize = 32;
+
+/// Where [`LocalFs::list_files_stream`] is in its walk over the directories.
+struct ListingState {
+    /// The directory being read, with its depth.
+    current: Option<(PathBuf, usize, fs::ReadDir)>,
+    /// The directories to read next, with their depths.
+    directory_queue: Vec<(PathBuf, usize)>,
+}
+
 #[derive(Debug, Clone)]
 pub struct LocalFs {
     storage_root: PathBuf,
+    /// Uploads, deletions and copies fail, and nothing under the root is ever written.
+    read_only: bool,
+    /// Shares the directory fsyncs between the uploads that finish close to each other.
+    dir_fsync_batcher: Option<Arc<DirFsyncBatcher>>,
+    /// The uploads of at least this many bytes are written with `O_DIRECT`.
+    direct_io_min_size: Option<u64>,
 }
 
 impl LocalFs {
@@ -46,7 +76,62 @@ impl LocalFs {
             })?;
         }
 
-        Ok(Self { storage_root })
+        Ok(Self {
+            storage_root,
+            read_only: false,
+            dir_fsync_batcher: None,
+            direct_io_min_size: None,
+        })
+    }
+
+    /// Opens an existing storage root without writing anything to it, e.g. on a read-only
+    /// mount of a snapshot: only the listings and downloads work.
+    pub fn new_read_only(mut storage_root: PathBuf) -> anyhow::Result<Self> {
+        anyhow::ensure!(
+            storage_root.is_dir(),
+            "Read-only storage root {storage_root:?} is not an existing directory"
+        );
+        if !storage_root.is_absolute() {
+            storage_root = storage_root.canonicalize().with_context(|| {
+                format!("Failed to represent path {storage_root:?} as an absolute path")
+            })?;
+        }
+
+        Ok(Self {
+            storage_root,
+            read_only: true,
+            dir_fsync_batcher: None,
+            direct_io_min_size: None,
+        })
+    }
+
+    /// An upload fsyncs the directory it renamed the file into before it returns. With the
+    /// batching, the uploads that rename their files within `window` of each other wait for
+    /// one fsync of the directory, at the cost of up to `window` of latency each.
+    pub fn with_fsync_batch_window(mut self, window: Duration) -> Self {
+        self.dir_fsync_batcher = Some(Arc::new(DirFsyncBatcher::new(window)));
+        self
+    }
+
+    /// Writes the uploads of at least `min_size` bytes with `O_DIRECT`, bypassing the page
+    /// cache. The file systems that don't support it get the buffered writes.
+    pub fn with_direct_io_min_size(mut self, min_size: u64) -> Self {
+        self.direct_io_min_size = Some(min_size);
+        self
+    }
+
+    fn ensure_writable(
+        &self,
+        operation: &str,
+        path: &RemotePath,
+    ) -> Result<(), RemoteStorageError> {
+        if self.read_only {
+            return Err(RemoteStorageError::Permanent(anyhow::anyhow!(
+                "Cannot {operation} {path}: the local storage at {:?} is read-only",
+                self.storage_root
+            )));
+        }
+        Ok(())
     }
 
     // mirrors S3Bucket::s3_object_to_relative_path
@@ -88,6 +173,7 @@ impl LocalFs {
         Ok(get_all_files(&self.storage_root, true)
             .await?
             .into_iter()
+            .filter(|path| !is_temp_file(path))
             .map(|path| {
                 path.strip_prefix(&self.storage_root)
                     .context("Failed to strip storage root prefix")
@@ -98,6 +184,119 @@ impl LocalFs {
             })
             .collect())
     }
+
+    /// Writes the file under a temporary name, then fsyncs and renames it into place: after a
+    /// crash, the path has either its previous contents or the new ones, never a part of them.
+    ///
+    /// NOTE: Because temp file suffix always the same this operation is racy.
+    /// Two concurrent operations can lead to the following sequence:
+    /// T1: write(temp)
+    /// T2: write(temp) -> overwrites the content
+    /// T1: rename(temp, dst) -> succeeds
+    /// T2: rename(temp, dst) -> fails, temp no longet exists
+    /// This can be solved by supplying unique temp suffix every time, but this situation
+    /// is not normal in the first place, the error can help (and helped at least once)
+    /// to discover bugs in upper level synchronization.
+    async fn write_atomically(
+        &self,
+        target_file_path: &Path,
+        data: UploadStream,
+        expected_size_bytes: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let temp_file = self
+            .write_temp_file(target_file_path, data, expected_size_bytes)
+            .await?;
+        self.rename_into_place(temp_file, target_file_path).await
+    }
+
+    /// The first half of [`Self::write_atomically`]: writes and fsyncs the temp file of
+    /// `target_file_path`, which is removed again if it's dropped before it's renamed.
+    async fn write_temp_file(
+        &self,
+        target_file_path: &Path,
+        data: UploadStream,
+        expected_size_bytes: Option<u64>,
+    ) -> anyhow::Result<TempFile> {
+        let temp_file_path =
+            path_with_suffix_extension(target_file_path, LOCAL_FS_TEMP_FILE_SUFFIX);
+        // A failed or cancelled write doesn't leave its part of the file behind.
+        let temp_file = TempFile(Some(temp_file_path.clone()));
+
+        let mut buffer_to_read = data.take(expected_size_bytes.unwrap_or(u64::MAX));
+        let direct_io = match (self.direct_io_min_size, expected_size_bytes) {
+            (Some(min_size), Some(size)) => size >= min_size,
+            _ => false,
+        };
+        let written_directly = if direct_io {
+            direct_io::write_synced(&temp_file_path, &mut buffer_to_read)
+                .await
+                .with_context(|| {
+                    format!("Failed to write temp file '{}'", temp_file_path.display())
+                })?
+        } else {
+            None
+        };
+        let bytes_read = match written_directly {
+            Some(bytes_read) => bytes_read,
+            None => {
+                if direct_io {
+                    debug!(
+                        "O_DIRECT is not supported for '{}', writing it buffered",
+                        temp_file_path.display()
+                    );
+                }
+                write_synced(&temp_file_path, &mut buffer_to_read).await?
+            }
+        };
+
+        if let Some(from_size_bytes) = expected_size_bytes {
+            if bytes_read < from_size_bytes {
+                bail!("Provided stream was shorter than expected: {bytes_read} vs {from_size_bytes} bytes");
+            }
+            // Check if there is any extra data after the given size.
+            let mut from = buffer_to_read.into_inner();
+            let extra_read = from.read(&mut [1]).await?;
+            ensure!(
+                extra_read == 0,
+                "Provided stream was larger than expected: expected {from_size_bytes} bytes",
+            );
+        }
+        Ok(temp_file)
+    }
+
+    /// The second half of [`Self::write_atomically`]: renames the temp file into place and
+    /// fsyncs the directory.
+    async fn rename_into_place(
+        &self,
+        mut temp_file: TempFile,
+        target_file_path: &Path,
+    ) -> anyhow::Result<()> {
+        let temp_file_path = temp_file.0.as_ref().expect("set until renamed");
+        fs::rename(temp_file_path, target_file_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to rename temp file into '{}'",
+                    target_file_path.display()
+                )
+            })?;
+        temp_file.0 = None;
+
+        // Make the rename itself durable.
+        let parent = target_file_path
+            .parent()
+            .expect("target directory was created above");
+        let fsync_result = match &self.dir_fsync_batcher {
+            Some(batcher) => batcher.fsync(parent).await,
+            None => {
+                let parent = parent.to_owned();
+                tokio::task::spawn_blocking(move || crashsafe::fsync(&parent))
+                    .await
+                    .context("Failed to join the fsync task")?
+            }
+        };
+        fsync_result.context("Failed to fsync the target directory")
+    }
 }
 
 #[async_trait::async_trait]
@@ -105,7 +304,7 @@ impl RemoteStorage for LocalFs {
     async fn list_prefixes(
         &self,
         prefix: Option<&RemotePath>,
-    ) -> Result<Vec<RemotePath>, DownloadError> {
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
         let path = match prefix {
             Some(prefix) => Cow::Owned(prefix.with_base(&self.storage_root)),
             None => Cow::Borrowed(&self.storage_root),
@@ -113,7 +312,7 @@ impl RemoteStorage for LocalFs {
 
         let prefixes_to_filter = get_all_files(path.as_ref(), false)
             .await
-            .map_err(DownloadError::Other)?;
+            .map_err(RemoteStorageError::from)?;
 
         let mut prefixes = Vec::with_capacity(prefixes_to_filter.len());
 
@@ -122,7 +321,7 @@ impl RemoteStorage for LocalFs {
             if prefix.is_dir()
                 && is_directory_empty(&prefix)
                     .await
-                    .map_err(DownloadError::Other)?
+                    .map_err(RemoteStorageError::from)?
             {
                 continue;
             }
@@ -143,148 +342,187 @@ impl RemoteStorage for LocalFs {
 
     // recursively lists all files in a directory,
     // mirroring the `list_files` for `s3_bucket`
-    async fn list_files(&self, folder: Option<&RemotePath>) -> anyhow::Result<Vec<RemotePath>> {
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        self.list_files_stream(folder).try_collect().await
+    }
+
+    /// Reads the directories an entry at a time.
+    fn list_files_stream<'a>(&'a self, folder: Option<&'a RemotePath>) -> ListingStream<'a> {
         let full_path = match folder {
             Some(folder) => folder.with_base(&self.storage_root),
             None => self.storage_root.clone(),
         };
-        let mut files = vec![];
-        let mut directory_queue = vec![full_path.clone()];
-
-        while let Some(cur_folder) = directory_queue.pop() {
-            let mut entries = fs::read_dir(cur_folder.clone()).await?;
-            while let Some(entry) = entries.next_entry().await? {
-                let file_name: PathBuf = entry.file_name().into();
-                let full_file_name = cur_folder.clone().join(&file_name);
-                let file_remote_path = self.local_file_to_relative_path(full_file_name.clone());
-                files.push(file_remote_path.clone());
-                if full_file_name.is_dir() {
-                    directory_queue.push(full_file_name);
+        let state = ListingState {
+            current: None,
+            directory_queue: vec![(full_path.clone(), 0)],
+        };
+
+        Box::pin(stream::try_unfold(state, move |mut state| {
+            let full_path = full_path.clone();
+            async move {
+                loop {
+                    let (cur_folder, depth, mut entries) = match state.current.take() {
+                        Some(current) => current,
+                        None => match state.directory_queue.pop() {
+                            Some((cur_folder, depth)) => {
+                                let entries = fs::read_dir(&cur_folder).await.map_err(|e| {
+                                    RemoteStorageError::from_io(
+                                        e,
+                                        format!("Failed to list {cur_folder:?}"),
+                                    )
+                                })?;
+                                (cur_folder, depth, entries)
+                            }
+                            None => return Ok(None),
+                        },
+                    };
+                    let list_error = |e| {
+                        RemoteStorageError::from_io(e, format!("Failed to list {cur_folder:?}"))
+                    };
+                    let Some(entry) = entries.next_entry().await.map_err(list_error)? else {
+                        continue;
+                    };
+                    let full_file_name = cur_folder.join(entry.file_name());
+                    let file_type = entry.file_type().await.map_err(list_error);
+                    state.current = Some((cur_folder, depth, entries));
+
+                    if is_temp_file(&full_file_name) {
+                        continue;
+                    }
+                    // Don't follow the symlinks, like `get_all_files`: a symlink to one of
+                    // the parent directories would keep us walking in circles.
+                    let file_type = file_type?;
+                    if file_type.is_symlink() {
+                        debug!("{full_file_name:?} is a symlink, skipping");
+                        continue;
+                    }
+                    if file_type.is_dir() {
+                        if depth >= MAX_LIST_FILES_DEPTH {
+                            return Err(RemoteStorageError::Permanent(anyhow::anyhow!(
+                                "Directory {full_file_name:?} is nested more than {MAX_LIST_FILES_DEPTH} levels deep in {full_path:?}"
+                            )));
+                        }
+                        state
+                            .directory_queue
+                            .push((full_file_name.clone(), depth + 1));
+                    }
+                    let file_remote_path = self.local_file_to_relative_path(full_file_name);
+                    return Ok(Some((file_remote_path, state)));
                 }
             }
-        }
-        Ok(files)
+        }))
     }
 
     async fn upload(
         &self,
-        data: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        data: UploadStream,
         data_size_bytes: usize,
         to: &RemotePath,
         metadata: Option<StorageMetadata>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RemoteStorageError> {
+        self.ensure_writable("upload", to)?;
         let target_file_path = to.with_base(&self.storage_root);
         create_target_directory(&target_file_path).await?;
-        // We need this dance with sort of durable rename (without fsyncs)
-        // to prevent partial uploads. This was really hit when pageserver shutdown
-        // cancelled the upload and partial file was left on the fs
-        // NOTE: Because temp file suffix always the same this operation is racy.
-        // Two concurrent operations can lead to the following sequence:
-        // T1: write(temp)
-        // T2: write(temp) -> overwrites the content
-        // T1: rename(temp, dst) -> succeeds
-        // T2: rename(temp, dst) -> fails, temp no longet exists
-        // This can be solved by supplying unique temp suffix every time, but this situation
-        // is not normal in the first place, the error can help (and helped at least once)
-        // to discover bugs in upper level synchronization.
-        let temp_file_path =
-            path_with_suffix_extension(&target_file_path, LOCAL_FS_TEMP_FILE_SUFFIX);
-        let mut destination = io::BufWriter::new(
-            fs::OpenOptions::new()
-                .write(true)
-                .create(true)
-                .open(&temp_file_path)
-                .await
-                .with_context(|| {
-                    format!(
-                        "Failed to open target fs destination at '{}'",
-                        target_file_path.display()
-                    )
-                })?,
-        );
-
-        let from_size_bytes = data_size_bytes as u64;
-        let mut buffer_to_read = data.take(from_size_bytes);
 
-        let bytes_read = io::copy(&mut buffer_to_read, &mut destination)
+        // The data is written into its temp file first: a failed or cancelled upload changes
+        // nothing, not even the metadata of the object it would overwrite.
+        let temp_file = self
+            .write_temp_file(&target_file_path, data, Some(data_size_bytes as u64))
             .await
             .with_context(|| {
                 format!(
-                    "Failed to upload file (write temp) to the local storage at '{}'",
-                    temp_file_path.display()
+                    "Failed to upload file to the local storage at '{}'",
+                    target_file_path.display()
                 )
             })?;
 
-        if bytes_read < from_size_bytes {
-            bail!("Provided stream was shorter than expected: {bytes_read} vs {from_size_bytes} bytes");
+        // Then the metadata: a crash before the file is renamed into place leaves the new
+        // metadata next to the old file, which is caught by the checksums, instead of the new
+        // file with no metadata, that would be read as uncompressed.
+        let storage_metadata_path = storage_metadata_path(&target_file_path);
+        // A new object that is never renamed into place leaves no metadata.
+        let is_new_object = !target_file_path.exists();
+        match metadata {
+            Some(storage_metadata) => {
+                let metadata_json = serde_json::to_string(&storage_metadata.0)
+                    .context("Failed to serialize storage metadata as json")?;
+                self.write_atomically(
+                    &storage_metadata_path,
+                    Box::new(std::io::Cursor::new(metadata_json.into_bytes())),
+                    None,
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to write metadata to the local storage at '{}'",
+                        storage_metadata_path.display()
+                    )
+                })?;
+            }
+            // Same as in S3, overwriting the object drops its previous metadata.
+            None => match fs::remove_file(&storage_metadata_path).await {
+                Ok(()) => {}
+                Err(e) if e.kind() == ErrorKind::NotFound => {}
+                Err(e) => {
+                    return Err(RemoteStorageError::from_io(
+                        e,
+                        format!(
+                            "Failed to remove the previous metadata at '{}'",
+                            storage_metadata_path.display()
+                        ),
+                    ))
+                }
+            },
         }
-        // Check if there is any extra data after the given size.
-        let mut from = buffer_to_read.into_inner();
-        let extra_read = from.read(&mut [1]).await?;
-        ensure!(
-            extra_read == 0,
-            "Provided stream was larger than expected: expected {from_size_bytes} bytes",
-        );
 
-        destination.flush().await.with_context(|| {
-            format!(
-                "Failed to upload (flush temp) file to the local storage at '{}'",
-                temp_file_path.display()
-            )
-        })?;
-
-        fs::rename(temp_file_path, &target_file_path)
+        let metadata_guard = scopeguard::guard(&storage_metadata_path, |path| {
+            if is_new_object {
+                let _ = std::fs::remove_file(path);
+            }
+        });
+        self.rename_into_place(temp_file, &target_file_path)
             .await
             .with_context(|| {
                 format!(
-                    "Failed to upload (rename) file to the local storage at '{}'",
+                    "Failed to upload file to the local storage at '{}'",
                     target_file_path.display()
                 )
             })?;
-
-        if let Some(storage_metadata) = metadata {
-            let storage_metadata_path = storage_metadata_path(&target_file_path);
-            fs::write(
-                &storage_metadata_path,
-                serde_json::to_string(&storage_metadata.0)
-                    .context("Failed to serialize storage metadata as json")?,
-            )
-            .await
-            .with_context(|| {
-                format!(
-                    "Failed to write metadata to the local storage at '{}'",
-                    storage_metadata_path.display()
-                )
-            })?;
-        }
-
+        scopeguard::ScopeGuard::into_inner(metadata_guard);
         Ok(())
     }
 
-    async fn download(&self, from: &RemotePath) -> Result<Download, DownloadError> {
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
         let target_path = from.with_base(&self.storage_root);
-        if file_exists(&target_path).map_err(DownloadError::BadInput)? {
+        if file_exists(&target_path).map_err(RemoteStorageError::Permanent)? {
             let source = io::BufReader::new(
                 fs::OpenOptions::new()
                     .read(true)
                     .open(&target_path)
                     .await
-                    .with_context(|| {
-                        format!("Failed to open source file {target_path:?} to use in the download")
-                    })
-                    .map_err(DownloadError::Other)?,
+                    .map_err(|e| {
+                        RemoteStorageError::from_io(
+                            e,
+                            format!(
+                                "Failed to open source file {target_path:?} to use in the download"
+                            ),
+                        )
+                    })?,
             );
 
             let metadata = self
                 .read_storage_metadata(&target_path)
                 .await
-                .map_err(DownloadError::Other)?;
+                .map_err(RemoteStorageError::from)?;
             Ok(Download {
                 metadata,
                 download_stream: Box::pin(source),
             })
         } else {
-            Err(DownloadError::NotFound)
+            Err(RemoteStorageError::NotFound)
         }
     }
 
@@ -293,36 +531,40 @@ impl RemoteStorage for LocalFs {
         from: &RemotePath,
         start_inclusive: u64,
         end_exclusive: Option<u64>,
-    ) -> Result<Download, DownloadError> {
+    ) -> Result<Download, RemoteStorageError> {
         if let Some(end_exclusive) = end_exclusive {
             if end_exclusive <= start_inclusive {
-                return Err(DownloadError::Other(anyhow::anyhow!("Invalid range, start ({start_inclusive}) is not less than end_exclusive ({end_exclusive:?})")));
+                return Err(RemoteStorageError::Permanent(anyhow::anyhow!("Invalid range, start ({start_inclusive}) is not less than end_exclusive ({end_exclusive:?})")));
             };
             if start_inclusive == end_exclusive.saturating_sub(1) {
-                return Err(DownloadError::Other(anyhow::anyhow!("Invalid range, start ({start_inclusive}) and end_exclusive ({end_exclusive:?}) difference is zero bytes")));
+                return Err(RemoteStorageError::Permanent(anyhow::anyhow!("Invalid range, start ({start_inclusive}) and end_exclusive ({end_exclusive:?}) difference is zero bytes")));
             }
         }
         let target_path = from.with_base(&self.storage_root);
-        if file_exists(&target_path).map_err(DownloadError::BadInput)? {
+        if file_exists(&target_path).map_err(RemoteStorageError::Permanent)? {
             let mut source = io::BufReader::new(
                 fs::OpenOptions::new()
                     .read(true)
                     .open(&target_path)
                     .await
-                    .with_context(|| {
-                        format!("Failed to open source file {target_path:?} to use in the download")
-                    })
-                    .map_err(DownloadError::Other)?,
+                    .map_err(|e| {
+                        RemoteStorageError::from_io(
+                            e,
+                            format!(
+                                "Failed to open source file {target_path:?} to use in the download"
+                            ),
+                        )
+                    })?,
             );
             source
                 .seek(io::SeekFrom::Start(start_inclusive))
                 .await
                 .context("Failed to seek to the range start in a local storage file")
-                .map_err(DownloadError::Other)?;
+                .map_err(RemoteStorageError::from)?;
             let metadata = self
                 .read_storage_metadata(&target_path)
                 .await
-                .map_err(DownloadError::Other)?;
+                .map_err(RemoteStorageError::from)?;
 
             Ok(match end_exclusive {
                 Some(end_exclusive) => Download {
@@ -335,11 +577,34 @@ impl RemoteStorage for LocalFs {
                 },
             })
         } else {
-            Err(DownloadError::NotFound)
+            Err(RemoteStorageError::NotFound)
+        }
+    }
+
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        let target_path = path.with_base(&self.storage_root);
+        if !file_exists(&target_path).map_err(RemoteStorageError::Permanent)? {
+            return Err(RemoteStorageError::NotFound);
         }
+        let file_metadata = fs::metadata(&target_path).await.map_err(|e| {
+            RemoteStorageError::from_io(
+                e,
+                format!("Failed to get the metadata of file {target_path:?}"),
+            )
+        })?;
+        let metadata = self
+            .read_storage_metadata(&target_path)
+            .await
+            .map_err(RemoteStorageError::from)?;
+        Ok(ObjectMeta {
+            size: file_metadata.len(),
+            last_modified: file_metadata.modified().ok(),
+            metadata,
+        })
     }
 
-    async fn delete(&self, path: &RemotePath) -> anyhow::Result<()> {
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+        self.ensure_writable("delete", path)?;
         let file_path = path.with_base(&self.storage_root);
         match fs::remove_file(&file_path).await {
             Ok(()) => Ok(()),
@@ -347,16 +612,193 @@ impl RemoteStorage for LocalFs {
             // See https://docs.aws.amazon.com/AmazonS3/latest/API/API_DeleteObject.html
             // > If there isn't a null version, Amazon S3 does not remove any objects but will still respond that the command was successful.
             Err(e) if e.kind() == ErrorKind::NotFound => Ok(()),
-            Err(e) => Err(anyhow::anyhow!(e)),
+            Err(e) => Err(RemoteStorageError::from_io(
+                e,
+                format!("Failed to delete file {file_path:?}"),
+            )),
         }
     }
 
-    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> anyhow::Result<()> {
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
         for path in paths {
             self.delete(path).await?
         }
         Ok(())
     }
+
+    /// Counts the files under the directory, then removes it with everything in it.
+    async fn delete_prefix(&self, prefix: &RemotePath) -> Result<usize, RemoteStorageError> {
+        self.ensure_writable("delete", prefix)?;
+        let dir_path = prefix.with_base(&self.storage_root);
+        if !dir_path.is_dir() {
+            return Ok(0);
+        }
+        // The listing has the subdirectories and the metadata files too, neither are objects.
+        let files: HashSet<PathBuf> = self
+            .list_files(Some(prefix))
+            .await?
+            .iter()
+            .map(|path| path.with_base(&self.storage_root))
+            .filter(|file_path| !file_path.is_dir())
+            .collect();
+        let deleted = files
+            .iter()
+            .filter(|file_path| {
+                let object_path = file_path.with_extension("");
+                storage_metadata_path(&object_path) != **file_path || !files.contains(&object_path)
+            })
+            .count();
+        match fs::remove_dir_all(&dir_path).await {
+            Ok(()) => Ok(deleted),
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(deleted),
+            Err(e) => Err(RemoteStorageError::from_io(
+                e,
+                format!("Failed to delete directory {dir_path:?}"),
+            )),
+        }
+    }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        self.ensure_writable("copy to", to)?;
+        let source_file_path = from.with_base(&self.storage_root);
+        if !file_exists(&source_file_path).map_err(RemoteStorageError::Permanent)? {
+            return Err(RemoteStorageError::NotFound);
+        }
+        let target_file_path = to.with_base(&self.storage_root);
+        create_target_directory(&target_file_path).await?;
+
+        // Uploads replace the files instead of writing into them, so a hard link is as good as
+        // a copy. Same as in the upload, the link is created under a temporary name, and renamed
+        // into place after the metadata is written.
+        let temp_file_path =
+            path_with_suffix_extension(&target_file_path, LOCAL_FS_TEMP_FILE_SUFFIX);
+        // Left behind by an interrupted copy, the link would fail otherwise.
+        let _ = fs::remove_file(&temp_file_path).await;
+        let temp_file = TempFile(Some(temp_file_path.clone()));
+        if let Err(e) = fs::hard_link(&source_file_path, &temp_file_path).await {
+            debug!(
+                "Failed to hard link '{}', copying it instead: {e}",
+                source_file_path.display()
+            );
+            fs::copy(&source_file_path, &temp_file_path)
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to copy file '{}' in the local storage",
+                        source_file_path.display()
+                    )
+                })?;
+        }
+
+        // The metadata goes before the rename, as in the upload.
+        let target_metadata_path = storage_metadata_path(&target_file_path);
+        let is_new_object = !target_file_path.exists();
+        match fs::read(storage_metadata_path(&source_file_path)).await {
+            Ok(metadata) => self
+                .write_atomically(
+                    &target_metadata_path,
+                    Box::new(std::io::Cursor::new(metadata)),
+                    None,
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to copy metadata to the local storage at '{}'",
+                        target_metadata_path.display()
+                    )
+                })?,
+            // Overwriting the target drops its previous metadata.
+            Err(e) if e.kind() == ErrorKind::NotFound => {
+                match fs::remove_file(&target_metadata_path).await {
+                    Ok(()) => {}
+                    Err(e) if e.kind() == ErrorKind::NotFound => {}
+                    Err(e) => {
+                        return Err(RemoteStorageError::from_io(
+                            e,
+                            format!(
+                                "Failed to remove the previous metadata at '{}'",
+                                target_metadata_path.display()
+                            ),
+                        ))
+                    }
+                }
+            }
+            Err(e) => {
+                return Err(RemoteStorageError::from_io(
+                    e,
+                    format!(
+                        "Failed to read the metadata of '{}'",
+                        source_file_path.display()
+                    ),
+                ))
+            }
+        }
+
+        let metadata_guard = scopeguard::guard(&target_metadata_path, |path| {
+            if is_new_object {
+                let _ = std::fs::remove_file(path);
+            }
+        });
+        self.rename_into_place(temp_file, &target_file_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "Failed to copy (rename) file to the local storage at '{}'",
+                    target_file_path.display()
+                )
+            })?;
+        scopeguard::ScopeGuard::into_inner(metadata_guard);
+        Ok(())
+    }
+}
+
+/// Writes all of `data` into the file at `path` through the page cache, truncating the file
+/// first, and fsyncs it. Returns the number of bytes written.
+async fn write_synced(path: &Path, data: &mut (impl io::AsyncRead + Unpin)) -> anyhow::Result<u64> {
+    // Truncate whatever is left of the temp file after a crash.
+    let mut destination = io::BufWriter::new(
+        fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .open(path)
+            .await
+            .with_context(|| format!("Failed to open temp file '{}'", path.display()))?,
+    );
+    let bytes_read = io::copy(data, &mut destination)
+        .await
+        .with_context(|| format!("Failed to write temp file '{}'", path.display()))?;
+    destination
+        .flush()
+        .await
+        .with_context(|| format!("Failed to flush temp file '{}'", path.display()))?;
+    destination
+        .get_ref()
+        .sync_all()
+        .await
+        .with_context(|| format!("Failed to fsync temp file '{}'", path.display()))?;
+    Ok(bytes_read)
+}
+
+/// A temp file written by [`LocalFs::write_temp_file`], removed when dropped unless it has been
+/// renamed into place.
+struct TempFile(Option<PathBuf>);
+
+impl Drop for TempFile {
+    fn drop(&mut self) {
+        if let Some(path) = self.0.take() {
+            let _ = std::fs::remove_file(path);
+        }
+    }
+}
+
+/// Temp files are the uploads in progress, or the leftovers of the interrupted ones.
+fn is_temp_file(path: &Path) -> bool {
+    path.extension()
+        .and_then(|extension| extension.to_str())
+        .map_or(false, |extension| {
+            extension.ends_with(LOCAL_FS_TEMP_FILE_SUFFIX)
+        })
 }
 
 fn storage_metadata_path(original_path: &Path) -> PathBuf {
@@ -432,6 +874,7 @@ fn file_exists(file_path: &Path) -> anyhow::Result<bool> {
 mod fs_tests {
     use super::*;
 
+    use futures_util::StreamExt;
     use std::{collections::HashMap, io::Write};
     use tempfile::tempdir;
 
@@ -513,6 +956,33 @@ mod fs_tests {
         LocalFs::new(tempdir()?.path().to_owned())
     }
 
+    #[tokio::test]
+    async fn upload_with_write_options() -> anyhow::Result<()> {
+        let storage = create_storage()?
+            .with_fsync_batch_window(Duration::from_millis(10))
+            .with_direct_io_min_size(1);
+
+        let names = (0..16).map(|i| format!("upload_{i}")).collect::<Vec<_>>();
+        let uploads = futures_util::future::try_join_all(
+            names
+                .iter()
+                .map(|name| upload_dummy_file(&storage, name, None)),
+        )
+        .await?;
+        for (name, upload_target) in names.iter().zip(&uploads) {
+            let contents =
+                read_and_assert_remote_file_contents(&storage, upload_target, None).await?;
+            assert_eq!(dummy_contents(name), contents);
+        }
+
+        let metadata = StorageMetadata(HashMap::from([("one".to_string(), "1".to_string())]));
+        let upload_target =
+            upload_dummy_file(&storage, "upload_with_metadata", Some(metadata.clone())).await?;
+        let download = storage.download(&upload_target).await?;
+        assert_eq!(download.metadata, Some(metadata));
+        Ok(())
+    }
+
     #[tokio::test]
     async fn download_file() -> anyhow::Result<()> {
         let storage = create_storage()?;
@@ -528,7 +998,7 @@ mod fs_tests {
 
         let non_existing_path = "somewhere/else";
         match storage.download(&RemotePath::new(Path::new(non_existing_path))?).await {
-            Err(DownloadError::NotFound) => {} // Should get NotFound for non existing keys
+            Err(RemoteStorageError::NotFound) => {} // Should get NotFound for non existing keys
             other => panic!("Should get a NotFound error when downloading non-existing storage files, but got: {other:?}"),
         }
         Ok(())
@@ -711,6 +1181,353 @@ mod fs_tests {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn stat_file() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let metadata = StorageMetadata(HashMap::from([("one".to_string(), "1".to_string())]));
+        let upload_target = upload_dummy_file(&storage, "upload_1", Some(metadata.clone())).await?;
+
+        let stat = storage.stat(&upload_target).await?;
+        assert_eq!(stat.size, dummy_contents("upload_1").len() as u64);
+        assert!(stat.last_modified.is_some());
+        assert_eq!(stat.metadata, Some(metadata));
+
+        let no_metadata = upload_dummy_file(&storage, "upload_2", None).await?;
+        assert_eq!(storage.stat(&no_metadata).await?.metadata, None);
+
+        assert!(matches!(
+            storage.stat(&RemotePath::new(Path::new("missing"))?).await,
+            Err(RemoteStorageError::NotFound)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copy_file() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let metadata = StorageMetadata(HashMap::from([("one".to_string(), "1".to_string())]));
+        let source = upload_dummy_file(&storage, "upload_1", Some(metadata.clone())).await?;
+        let target = RemotePath::new(Path::new("timelines/branch/upload_1"))?;
+
+        storage.copy(&source, &target).await?;
+        let copied_contents =
+            read_and_assert_remote_file_contents(&storage, &target, Some(&metadata)).await?;
+        assert_eq!(dummy_contents("upload_1"), copied_contents);
+
+        // Overwriting the source leaves the copy intact.
+        let new_contents = "new contents";
+        storage
+            .upload(
+                Box::new(std::io::Cursor::new(new_contents.as_bytes().to_vec())),
+                new_contents.len(),
+                &source,
+                None,
+            )
+            .await?;
+        let copied_contents =
+            read_and_assert_remote_file_contents(&storage, &target, Some(&metadata)).await?;
+        assert_eq!(dummy_contents("upload_1"), copied_contents);
+
+        // Copying an object without metadata over the copy drops the metadata there.
+        let no_metadata = upload_dummy_file(&storage, "upload_2", None).await?;
+        storage.copy(&no_metadata, &target).await?;
+        let copied_contents = read_and_assert_remote_file_contents(&storage, &target, None).await?;
+        assert_eq!(dummy_contents("upload_2"), copied_contents);
+
+        assert!(matches!(
+            storage
+                .copy(&RemotePath::new(Path::new("missing"))?, &target)
+                .await,
+            Err(RemoteStorageError::NotFound)
+        ));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interrupted_upload_is_not_visible() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let target = RemotePath::new(Path::new("timelines/some_timeline/layer"))?;
+        let target_path = target.with_base(&storage.storage_root);
+        let temp_path = path_with_suffix_extension(&target_path, LOCAL_FS_TEMP_FILE_SUFFIX);
+
+        // The stream ends too early.
+        let err = storage
+            .upload(
+                Box::new(std::io::Cursor::new(vec![1; 10])),
+                20,
+                &target,
+                None,
+            )
+            .await
+            .expect_err("Upload of a short stream should fail");
+        assert!(err.to_string().contains("Failed to upload file"));
+        assert!(storage.list().await?.is_empty());
+        assert_no_file_listed(&storage).await?;
+
+        // The upload is stopped in the middle, e.g. by a shutdown.
+        let data = (&b"some data"[..]).chain(PendingForever);
+        let upload = storage.upload(Box::new(data), 20, &target, None);
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(500), upload)
+                .await
+                .is_err()
+        );
+        assert!(
+            !temp_path.exists(),
+            "Cancelled upload should remove its temp file"
+        );
+        assert!(storage.list().await?.is_empty());
+        assert_no_file_listed(&storage).await?;
+
+        // A complete upload over the leftovers of the interrupted one.
+        upload_dummy_file(&storage, "layer", None).await?;
+        assert_eq!(list_files_sorted(&storage).await?.len(), 1);
+        assert!(!temp_path.exists());
+
+        // A failed overwrite keeps both the previous contents and their metadata.
+        let metadata = StorageMetadata(HashMap::from([("one".to_string(), "1".to_string())]));
+        let target = upload_dummy_file(&storage, "with_metadata", Some(metadata.clone())).await?;
+        storage
+            .upload(
+                Box::new(std::io::Cursor::new(vec![1; 10])),
+                20,
+                &target,
+                Some(StorageMetadata(HashMap::from([(
+                    "two".to_string(),
+                    "2".to_string(),
+                )]))),
+            )
+            .await
+            .expect_err("Upload of a short stream should fail");
+        let contents =
+            read_and_assert_remote_file_contents(&storage, &target, Some(&metadata)).await?;
+        assert_eq!(dummy_contents("with_metadata"), contents);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn listing_with_prefix_stays_in_the_subdirectory() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        for path in [
+            "tenants/a/timelines/1/layer",
+            "tenants/a/timelines/2/layer",
+            "tenants/b/timelines/3/layer",
+        ] {
+            let path = RemotePath::new(Path::new(path))?;
+            storage
+                .upload(Box::new(std::io::Cursor::new(vec![1])), 1, &path, None)
+                .await?;
+        }
+        // Anything outside of the listed prefix must not even be looked at.
+        std::os::unix::fs::symlink("/nonexistent", storage.storage_root.join("broken"))?;
+
+        let tenant_a = RemotePath::new(Path::new("tenants/a"))?;
+        let mut files = storage.list_files(Some(&tenant_a)).await?;
+        files.sort();
+        assert!(files.iter().all(|file| file.0.starts_with(&tenant_a.0)));
+        assert!(files.contains(&RemotePath::new(Path::new("tenants/a/timelines/2/layer"))?));
+        assert_eq!(files.len(), 5, "{files:?}");
+
+        let timelines = RemotePath::new(Path::new("tenants/a/timelines"))?;
+        let mut prefixes = storage.list_prefixes(Some(&timelines)).await?;
+        prefixes.sort();
+        assert_eq!(
+            prefixes,
+            vec![
+                RemotePath::new(Path::new("tenants/a/timelines/1"))?,
+                RemotePath::new(Path::new("tenants/a/timelines/2"))?,
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn delete_prefix_removes_the_directory() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let metadata = StorageMetadata::from([("key", "value")]);
+        for (path, metadata) in [
+            ("tenants/a/timelines/1/layer", Some(metadata.clone())),
+            ("tenants/a/timelines/2/layer", None),
+            ("tenants/ab/timelines/3/layer", None),
+        ] {
+            let path = RemotePath::new(Path::new(path))?;
+            storage
+                .upload(Box::new(std::io::Cursor::new(vec![1])), 1, &path, metadata)
+                .await?;
+        }
+
+        let tenant_a = RemotePath::new(Path::new("tenants/a"))?;
+        assert_eq!(storage.delete_prefix(&tenant_a).await?, 2);
+        assert!(!tenant_a.with_base(&storage.storage_root).exists());
+        assert_eq!(
+            storage.list_files(None).await?.len(),
+            // tenants, tenants/ab, tenants/ab/timelines, tenants/ab/timelines/3 and the layer
+            5
+        );
+
+        assert_eq!(storage.delete_prefix(&tenant_a).await?, 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn read_only_storage() -> anyhow::Result<()> {
+        let missing_root = tempdir()?.path().join("missing");
+        assert!(LocalFs::new_read_only(missing_root.clone()).is_err());
+        assert!(!missing_root.exists(), "read-only root must not be created");
+
+        let storage = create_storage()?;
+        let upload_target = upload_dummy_file(&storage, "upload_1", None).await?;
+        let read_only = LocalFs::new_read_only(storage.storage_root.clone())?;
+
+        assert_eq!(
+            list_files_sorted(&read_only).await?,
+            vec![upload_target.clone()]
+        );
+        let contents =
+            read_and_assert_remote_file_contents(&read_only, &upload_target, None).await?;
+        assert_eq!(contents, dummy_contents("upload_1"));
+        let mut first_bytes = String::new();
+        read_only
+            .download_byte_range(&upload_target, 0, Some(8))
+            .await?
+            .download_stream
+            .read_to_string(&mut first_bytes)
+            .await?;
+        assert_eq!(first_bytes, dummy_contents("upload_1")[..8]);
+
+        let other = RemotePath::new(Path::new("timelines/some_timeline/other"))?;
+        let failures = [
+            read_only
+                .upload(Box::new(std::io::Cursor::new(vec![1])), 1, &other, None)
+                .await,
+            read_only.copy(&upload_target, &other).await,
+            read_only.delete(&upload_target).await,
+            read_only
+                .delete_objects(std::slice::from_ref(&upload_target))
+                .await,
+            read_only
+                .delete_prefix(&RemotePath::new(Path::new("timelines"))?)
+                .await
+                .map(|_| ()),
+        ];
+        for failure in failures {
+            assert!(
+                matches!(failure, Err(RemoteStorageError::Permanent(_))),
+                "{failure:?}"
+            );
+        }
+        assert_eq!(list_files_sorted(&storage).await?, vec![upload_target]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_files_skips_symlinks() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let uploaded = upload_dummy_file(&storage, "layer", None).await?;
+        let timeline_dir = uploaded
+            .with_base(&storage.storage_root)
+            .parent()
+            .unwrap()
+            .to_owned();
+        // A loop back to the root, and a link to a file.
+        std::os::unix::fs::symlink(&storage.storage_root, timeline_dir.join("loop"))?;
+        std::os::unix::fs::symlink(
+            uploaded.with_base(&storage.storage_root),
+            timeline_dir.join("layer_link"),
+        )?;
+
+        let mut listed = storage.list_files(None).await?;
+        listed.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            listed,
+            vec![
+                RemotePath::new(Path::new("timelines"))?,
+                RemotePath::new(Path::new("timelines/some_timeline"))?,
+                uploaded,
+            ]
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_files_depth_is_limited() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let mut deep_dir = storage.storage_root.clone();
+        for _ in 0..=MAX_LIST_FILES_DEPTH + 1 {
+            deep_dir.push("d");
+        }
+        std::fs::create_dir_all(&deep_dir)?;
+
+        let err = storage
+            .list_files(None)
+            .await
+            .expect_err("listing too deep directories should fail");
+        assert!(err.to_string().contains("levels deep"), "{err:#}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_files_stream_yields_before_failing() -> anyhow::Result<()> {
+        let storage = create_storage()?;
+        let uploaded = upload_dummy_file(&storage, "layer", None).await?;
+        let mut deep_dir = storage.storage_root.join("z");
+        for _ in 0..=MAX_LIST_FILES_DEPTH {
+            deep_dir.push("d");
+        }
+        std::fs::create_dir_all(&deep_dir)?;
+
+        let listed = storage.list_files_stream(None).collect::<Vec<_>>().await;
+        let (ok, err): (Vec<_>, Vec<_>) = listed.into_iter().partition(Result::is_ok);
+        // At least the directories on the way to the deep one come first.
+        assert!(ok.len() > MAX_LIST_FILES_DEPTH, "{ok:?}");
+        assert_eq!(err.len(), 1, "the stream ends at the first error");
+        assert!(matches!(err[0], Err(RemoteStorageError::Permanent(_))));
+
+        // Short of the limit, the stream and the whole listing are the same.
+        std::fs::remove_dir_all(storage.storage_root.join("z"))?;
+        let mut streamed = storage
+            .list_files_stream(None)
+            .try_collect::<Vec<_>>()
+            .await?;
+        streamed.sort();
+        let mut listed = storage.list_files(None).await?;
+        listed.sort();
+        assert_eq!(streamed, listed);
+        assert!(streamed.contains(&uploaded));
+
+        Ok(())
+    }
+
+    /// `list_files` lists the directories too, only the files should not be there.
+    async fn assert_no_file_listed(storage: &LocalFs) -> anyhow::Result<()> {
+        for listed in storage.list_files(None).await? {
+            assert!(
+                listed.with_base(&storage.storage_root).is_dir(),
+                "Unexpected file listed: {listed:?}"
+            );
+        }
+        Ok(())
+    }
+
+    /// A stream that never produces any more data, nor ends.
+    struct PendingForever;
+
+    impl io::AsyncRead for PendingForever {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _: &mut std::task::Context<'_>,
+            _: &mut io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
     async fn upload_dummy_file(
         storage: &LocalFs,
         name: &str,
@@ -0,0 +1,103 @@
+//! Group commit of the directory fsyncs that make the renames of the uploaded files durable.
+//!
+//! Every upload renames its file into place and has to fsync the directory before it returns,
+//! so the upload is durable by the time the caller registers it, e.g. in an index part. When
+//! many files are uploaded into one directory at once, a single fsync started after all the
+//! renames covers them all: the uploads that arrive within the batch window wait for the same
+//! fsync, instead of each issuing its own.
+
+use std::collections::HashMap;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use futures_util::future::{BoxFuture, FutureExt, Shared};
+use utils::crashsafe;
+
+type DirFsync = Shared<BoxFuture<'static, Result<(), Arc<io::Error>>>>;
+
+pub(super) struct DirFsyncBatcher {
+    window: Duration,
+    /// The fsync of each directory that has not started yet, and can still be joined.
+    pending: Arc<Mutex<HashMap<PathBuf, DirFsync>>>,
+}
+
+impl std::fmt::Debug for DirFsyncBatcher {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("DirFsyncBatcher")
+            .field("window", &self.window)
+            .finish_non_exhaustive()
+    }
+}
+
+impl DirFsyncBatcher {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            pending: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// Returns once `dir` is fsynced, after everything renamed into it before the call.
+    pub async fn fsync(&self, dir: &Path) -> io::Result<()> {
+        let batch = {
+            let mut pending = self.pending.lock().unwrap();
+            pending
+                .entry(dir.to_owned())
+                .or_insert_with(|| self.start_batch(dir.to_owned()))
+                .clone()
+        };
+        batch
+            .await
+            .map_err(|e| io::Error::new(e.kind(), e.to_string()))
+    }
+
+    fn start_batch(&self, dir: PathBuf) -> DirFsync {
+        let window = self.window;
+        let pending = Arc::clone(&self.pending);
+        async move {
+            tokio::time::sleep(window).await;
+            // The renames done after this point are not covered by this fsync, their uploads
+            // start the next batch.
+            pending.lock().unwrap().remove(&dir);
+            tokio::task::spawn_blocking(move || crashsafe::fsync(&dir))
+                .await
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .and_then(|fsync_result| fsync_result)
+                .map_err(Arc::new)
+        }
+        .boxed()
+        .shared()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn concurrent_fsyncs_share_a_batch() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let batcher = DirFsyncBatcher::new(Duration::from_millis(50));
+
+        let mut fsyncs = std::pin::pin!(futures_util::future::try_join_all(
+            (0..8).map(|_| batcher.fsync(dir.path()))
+        ));
+        // All of them wait for the one fsync that is waiting for the window to pass.
+        assert!(futures_util::poll!(fsyncs.as_mut()).is_pending());
+        assert_eq!(batcher.pending.lock().unwrap().len(), 1);
+        fsyncs.await?;
+        assert!(batcher.pending.lock().unwrap().is_empty());
+
+        let missing = dir.path().join("missing");
+        assert_eq!(
+            batcher.fsync(&missing).await.unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        // A failed batch is not reused.
+        std::fs::create_dir(&missing)?;
+        batcher.fsync(&missing).await?;
+        Ok(())
+    }
+}
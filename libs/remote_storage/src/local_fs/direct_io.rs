@@ -0,0 +1,135 @@
+//! Writes of the large files with `O_DIRECT`, so that the files written once and read back
+//! rarely, e.g. the layers uploaded by a busy ingest node, don't evict the rest of the page
+//! cache.
+//!
+//! `O_DIRECT` writes whole aligned blocks from aligned memory only: the data is staged in an
+//! aligned buffer, the last block is padded with zeros and the file is truncated to the real
+//! size afterwards.
+
+use std::fs::File;
+use std::io::{self, Write};
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+use tokio::io::{AsyncRead, AsyncReadExt};
+
+/// The block size `O_DIRECT` needs the offsets, lengths and memory to be aligned to, a
+/// multiple of the logical block size of the common file systems.
+const ALIGNMENT: usize = 4096;
+
+/// 1 MiB written per syscall.
+const BUFFER_BLOCKS: usize = 256;
+
+#[repr(C, align(4096))]
+struct Block([u8; ALIGNMENT]);
+
+struct AlignedBuffer {
+    blocks: Vec<Block>,
+}
+
+impl AlignedBuffer {
+    fn new() -> Self {
+        Self {
+            blocks: (0..BUFFER_BLOCKS).map(|_| Block([0; ALIGNMENT])).collect(),
+        }
+    }
+
+    fn bytes(&self) -> &[u8] {
+        // SAFETY: the blocks are contiguous and have no padding, their size is their alignment.
+        unsafe {
+            std::slice::from_raw_parts(
+                self.blocks.as_ptr().cast::<u8>(),
+                self.blocks.len() * ALIGNMENT,
+            )
+        }
+    }
+
+    fn bytes_mut(&mut self) -> &mut [u8] {
+        // SAFETY: same as in `bytes`, and the borrow is exclusive.
+        unsafe {
+            std::slice::from_raw_parts_mut(
+                self.blocks.as_mut_ptr().cast::<u8>(),
+                self.blocks.len() * ALIGNMENT,
+            )
+        }
+    }
+}
+
+fn open_direct(path: &Path) -> io::Result<File> {
+    let mut options = std::fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(target_os = "linux")]
+    options.custom_flags(libc::O_DIRECT);
+    options.open(path)
+}
+
+/// Writes all of `data` into the file at `path`, truncating it first, and fsyncs it.
+/// Returns the number of bytes written, or `None` without reading anything if the file
+/// system doesn't support `O_DIRECT`, e.g. tmpfs.
+pub(super) async fn write_synced(
+    path: &Path,
+    data: &mut (impl AsyncRead + Unpin),
+) -> io::Result<Option<u64>> {
+    let file_path = path.to_owned();
+    let mut file = match tokio::task::spawn_blocking(move || open_direct(&file_path)).await? {
+        Ok(file) => file,
+        Err(e) if e.raw_os_error() == Some(libc::EINVAL) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+
+    let mut buffer = AlignedBuffer::new();
+    let mut written = 0;
+    loop {
+        let bytes = buffer.bytes_mut();
+        let mut filled = 0;
+        while filled < bytes.len() {
+            match data.read(&mut bytes[filled..]).await? {
+                0 => break,
+                n => filled += n,
+            }
+        }
+        let end_of_data = filled < bytes.len();
+        let aligned = filled.next_multiple_of(ALIGNMENT);
+        bytes[filled..aligned].fill(0);
+
+        (file, buffer) = tokio::task::spawn_blocking(move || {
+            file.write_all(&buffer.bytes()[..aligned])
+                .map(|()| (file, buffer))
+        })
+        .await??;
+        written += filled as u64;
+        if end_of_data {
+            break;
+        }
+    }
+
+    tokio::task::spawn_blocking(move || {
+        // Cut off the padding of the last block.
+        file.set_len(written)?;
+        file.sync_all()
+    })
+    .await??;
+    Ok(Some(written))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn unaligned_sizes_are_written_exactly() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let path = dir.path().join("file");
+        for size in [0, 1, ALIGNMENT, BUFFER_BLOCKS * ALIGNMENT + 5] {
+            let contents = (0..size).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+            match write_synced(&path, &mut contents.as_slice()).await? {
+                Some(written) => assert_eq!(written, size as u64),
+                // Nothing to check on this file system.
+                None => return Ok(()),
+            }
+            assert_eq!(std::fs::read(&path)?, contents);
+        }
+        Ok(())
+    }
+}
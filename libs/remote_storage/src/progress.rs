@@ -0,0 +1,155 @@
+//! Timeouts of the remote operations that don't cut the long transfers short.
+//!
+//! A layer of a few gigabytes takes long to upload or download even when nothing is wrong, so
+//! a flat timeout either cancels such transfers or takes ages to notice a hung connection.
+//! [`timeout_without_progress`] cancels an operation only once no bytes moved for the whole
+//! timeout: the upload and download streams of [`crate::GenericRemoteStorage`] that are created
+//! inside of the operation report every read to it. An operation with no data streams, e.g. a
+//! deletion, gets the timeout for all of its requests.
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::task::{ready, Poll};
+use std::time::Duration;
+
+use tokio::io::{self, AsyncRead};
+use tokio::time::Instant;
+
+use crate::Download;
+
+tokio::task_local! {
+    /// The progress of the [`timeout_without_progress`] operation that's being polled.
+    static PROGRESS: Arc<Progress>;
+}
+
+struct Progress {
+    started_at: Instant,
+    /// Milliseconds from `started_at` to the last read of a data stream.
+    last_read_millis: AtomicU64,
+}
+
+impl Progress {
+    fn last_read_at(&self) -> Instant {
+        self.started_at + Duration::from_millis(self.last_read_millis.load(Ordering::Relaxed))
+    }
+
+    fn record_read(&self) {
+        self.last_read_millis.store(
+            self.started_at.elapsed().as_millis() as u64,
+            Ordering::Relaxed,
+        );
+    }
+}
+
+/// Runs `op`, failing once `timeout` passes without any of its data streams moving a byte.
+pub async fn timeout_without_progress<F: Future>(
+    timeout: Duration,
+    op: F,
+) -> anyhow::Result<F::Output> {
+    let progress = Arc::new(Progress {
+        started_at: Instant::now(),
+        last_read_millis: AtomicU64::new(0),
+    });
+    let op = PROGRESS.scope(Arc::clone(&progress), op);
+    tokio::pin!(op);
+    loop {
+        match tokio::time::timeout_at(progress.last_read_at() + timeout, &mut op).await {
+            Ok(output) => return Ok(output),
+            Err(_) if progress.last_read_at().elapsed() >= timeout => {
+                anyhow::bail!("remote operation made no progress for {timeout:?}")
+            }
+            Err(_) => {}
+        }
+    }
+}
+
+/// Reports the reads of `inner` to the [`timeout_without_progress`] operation it's created in,
+/// if any, wherever it's read later.
+pub(crate) fn track_progress<R: AsyncRead>(inner: R) -> ProgressReader<R> {
+    ProgressReader {
+        progress: PROGRESS.try_with(Arc::clone).ok(),
+        inner,
+    }
+}
+
+pub(crate) fn track_download_progress(download: Download) -> Download {
+    Download {
+        download_stream: Box::pin(track_progress(download.download_stream)),
+        metadata: download.metadata,
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// An `AsyncRead` adapter, reporting every read to the operation's [`Progress`].
+    pub(crate) struct ProgressReader<R> {
+        progress: Option<Arc<Progress>>,
+        #[pin]
+        inner: R,
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        let before = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+        if buf.filled().len() > before {
+            if let Some(progress) = this.progress {
+                progress.record_read();
+            }
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::AsyncReadExt;
+
+    use super::*;
+
+    /// Gives out a byte every `interval`.
+    fn slow_reader(len: usize, interval: Duration) -> impl AsyncRead + Unpin {
+        Box::pin(tokio_util::io::StreamReader::new(
+            futures_util::stream::unfold(len, move |left| async move {
+                if left == 0 {
+                    return None;
+                }
+                tokio::time::sleep(interval).await;
+                Some((Ok::<_, std::io::Error>(&[0u8][..]), left - 1))
+            }),
+        ))
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn long_transfer_with_progress_is_not_cancelled() {
+        let read = timeout_without_progress(Duration::from_secs(10), async {
+            let mut reader = track_progress(slow_reader(10, Duration::from_secs(5)));
+            let mut read = Vec::new();
+            reader.read_to_end(&mut read).await.unwrap();
+            read.len()
+        })
+        .await
+        .expect("the transfer makes progress every 5 seconds");
+        assert_eq!(read, 10);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn stalled_transfer_is_cancelled() {
+        let started_at = Instant::now();
+        timeout_without_progress(Duration::from_secs(10), async {
+            let mut reader = track_progress(slow_reader(10, Duration::from_secs(5)));
+            let mut read = [0; 3];
+            reader.read_exact(&mut read).await.unwrap();
+            std::future::pending::<()>().await
+        })
+        .await
+        .expect_err("the transfer stalls after 3 bytes");
+        assert_eq!(started_at.elapsed(), Duration::from_secs(25));
+    }
+}
@@ -4,7 +4,11 @@
 //! allowing multiple api users to independently work with the same S3 bucket, if
 //! their bucket prefixes are both specified and different.
 
+use std::collections::HashMap;
+use std::future::Future;
+use std::num::NonZeroUsize;
 use std::sync::Arc;
+use std::time::{Duration, SystemTime};
 
 use anyhow::Context;
 use aws_config::{
@@ -16,31 +20,50 @@ use aws_credential_types::cache::CredentialsCache;
 use aws_sdk_s3::{
     config::{Config, Region},
     error::SdkError,
-    operation::get_object::GetObjectError,
+    operation::{get_object::GetObjectError, head_object::HeadObjectError},
     primitives::ByteStream,
-    types::{Delete, ObjectIdentifier},
+    types::{CompletedMultipartUpload, CompletedPart, Delete, ObjectIdentifier, StorageClass},
     Client,
 };
 use aws_smithy_http::body::SdkBody;
+use futures_util::stream::{self, FuturesUnordered, StreamExt, TryStreamExt};
 use hyper::Body;
 use scopeguard::ScopeGuard;
 use tokio::{
-    io::{self, AsyncRead},
-    sync::Semaphore,
+    io::{self, AsyncRead, AsyncReadExt},
+    sync::{OnceCell, Semaphore},
 };
 use tokio_util::io::ReaderStream;
-use tracing::debug;
+use tracing::{debug, info, warn};
 
 use super::StorageMetadata;
 use crate::{
-    Download, DownloadError, RemotePath, RemoteStorage, S3Config, REMOTE_STORAGE_PREFIX_SEPARATOR,
+    encryption, Compression, Download, ListingStream, ObjectMeta, RatelimitedAsyncRead, RemotePath,
+    RemoteStorage, RemoteStorageError, S3Config, UploadStream, REMOTE_STORAGE_PREFIX_SEPARATOR,
 };
 
 const MAX_DELETE_OBJECTS_REQUEST_SIZE: usize = 1000;
 
+/// S3 rejects multipart uploads with parts smaller than that, except for the last part.
+const MIN_MULTIPART_PART_SIZE: usize = 5 * 1024 * 1024;
+/// S3 rejects multipart uploads with more parts than that.
+const MAX_MULTIPART_PARTS: usize = 10_000;
+/// S3 rejects larger parts, and larger objects uploaded with a single request.
+const MAX_MULTIPART_PART_SIZE: usize = 5 * 1024 * 1024 * 1024;
+/// S3 rejects larger objects, however they are uploaded.
+const MAX_OBJECT_SIZE: u64 = 5 * 1024 * 1024 * 1024 * 1024;
+
+/// `Content-Type` of the objects stored as is, unless configured otherwise.
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+/// `Cache-Control` of the objects that are never overwritten: cached for a year, without
+/// revalidation.
+const IMMUTABLE_CACHE_CONTROL: &str = "public, max-age=31536000, immutable";
+
 pub(super) mod metrics;
+mod multipart_state;
 
 use self::metrics::{AttemptOutcome, RequestKind};
+use self::multipart_state::{part_digest, MultipartStateDir, MultipartUploadState, UploadedPart};
 
 /// AWS S3 storage.
 pub struct S3Bucket {
@@ -52,6 +75,17 @@ pub struct S3Bucket {
     // Same goes to IAM, which is queried before every S3 request, if enabled. IAM has even lower RPS threshold.
     // The helps to ensure we don't exceed the thresholds.
     concurrency_limiter: Arc<Semaphore>,
+    multipart_part_size: NonZeroUsize,
+    multipart_upload_concurrency: NonZeroUsize,
+    /// Where the progress of the multipart uploads is kept to resume them, if anywhere.
+    multipart_state: Option<MultipartStateDir>,
+    multipart_upload_max_age: Option<Duration>,
+    /// Set once the multipart uploads older than `multipart_upload_max_age` are aborted.
+    stale_multipart_uploads_aborted: OnceCell<()>,
+    storage_class: Option<StorageClass>,
+    content_type: Option<String>,
+    cache_control: Option<String>,
+    max_object_size: u64,
 }
 
 #[derive(Default)]
@@ -95,12 +129,58 @@ impl S3Bucket {
             .credentials_provider(credentials_provider);
 
         if let Some(custom_endpoint) = aws_config.endpoint.clone() {
-            config_builder = config_builder
-                .endpoint_url(custom_endpoint)
-                .force_path_style(true);
+            config_builder = config_builder.endpoint_url(custom_endpoint);
         }
+        let force_path_style = aws_config
+            .force_path_style
+            .unwrap_or(aws_config.endpoint.is_some());
+        config_builder = config_builder.force_path_style(force_path_style);
         let client = Client::from_conf(config_builder.build());
 
+        anyhow::ensure!(
+            aws_config.multipart_part_size.get() >= MIN_MULTIPART_PART_SIZE,
+            "'multipart_part_size' should be at least {MIN_MULTIPART_PART_SIZE} bytes, got {}",
+            aws_config.multipart_part_size
+        );
+        anyhow::ensure!(
+            aws_config.multipart_part_size.get() <= MAX_MULTIPART_PART_SIZE,
+            "'multipart_part_size' should be at most {MAX_MULTIPART_PART_SIZE} bytes, got {}",
+            aws_config.multipart_part_size
+        );
+        // The objects larger than one part are uploaded in parts, of which there can't be more
+        // than `MAX_MULTIPART_PARTS`.
+        let multipart_max_object_size =
+            aws_config.multipart_part_size.get() as u64 * MAX_MULTIPART_PARTS as u64;
+        let max_object_size = aws_config
+            .max_object_size
+            .unwrap_or(u64::MAX)
+            .min(multipart_max_object_size)
+            .min(MAX_OBJECT_SIZE);
+
+        let storage_class = aws_config
+            .storage_class
+            .as_deref()
+            .map(|storage_class| {
+                anyhow::ensure!(
+                    StorageClass::values().contains(&storage_class),
+                    "Unknown S3 storage class '{storage_class}', expected one of {:?}",
+                    StorageClass::values()
+                );
+                let storage_class = StorageClass::from(storage_class);
+                // All objects get the class, index_part.json and the copies too, and the pageserver
+                // reads them back without restoring them from the archive first.
+                anyhow::ensure!(
+                    !matches!(
+                        storage_class,
+                        StorageClass::Glacier | StorageClass::DeepArchive
+                    ),
+                    "S3 storage class '{}' is an archive class, its objects cannot be downloaded",
+                    storage_class.as_str()
+                );
+                Ok(storage_class)
+            })
+            .transpose()?;
+
         let prefix_in_bucket = aws_config.prefix_in_bucket.as_deref().map(|prefix| {
             let mut prefix = prefix;
             while prefix.starts_with(REMOTE_STORAGE_PREFIX_SEPARATOR) {
@@ -119,20 +199,60 @@ impl S3Bucket {
             max_keys_per_list_response: aws_config.max_keys_per_list_response,
             prefix_in_bucket,
             concurrency_limiter: Arc::new(Semaphore::new(aws_config.concurrency_limit.get())),
+            multipart_part_size: aws_config.multipart_part_size,
+            multipart_upload_concurrency: aws_config.multipart_upload_concurrency,
+            multipart_state: aws_config
+                .multipart_resume_dir
+                .clone()
+                .map(MultipartStateDir::new),
+            multipart_upload_max_age: aws_config.multipart_upload_max_age,
+            stale_multipart_uploads_aborted: OnceCell::new(),
+            storage_class,
+            content_type: aws_config.content_type.clone(),
+            cache_control: aws_config.cache_control.clone(),
+            max_object_size,
         })
     }
 
+    /// `Content-Type` and `Cache-Control` of an object uploaded with `metadata`, for the CDNs
+    /// and other HTTP clients that read the bucket directly.
+    fn object_headers(&self, metadata: Option<&StorageMetadata>) -> (String, Option<String>) {
+        // An encrypted object is opaque, whatever its contents are compressed with.
+        let content_type = if encryption::is_encrypted(metadata) {
+            DEFAULT_CONTENT_TYPE
+        } else {
+            Compression::from_metadata(metadata)
+                .ok()
+                .and_then(|compression| compression.media_type())
+                .or(self.content_type.as_deref())
+                .unwrap_or(DEFAULT_CONTENT_TYPE)
+        };
+        let cache_control = if StorageMetadata::is_immutable(metadata) {
+            Some(IMMUTABLE_CACHE_CONTROL.to_owned())
+        } else {
+            self.cache_control.clone()
+        };
+        (content_type.to_owned(), cache_control)
+    }
+
     fn s3_object_to_relative_path(&self, key: &str) -> RemotePath {
-        let relative_path =
-            match key.strip_prefix(self.prefix_in_bucket.as_deref().unwrap_or_default()) {
-                Some(stripped) => stripped,
-                // we rely on AWS to return properly prefixed paths
-                // for requests with a certain prefix
-                None => panic!(
-                    "Key {} does not start with bucket prefix {:?}",
-                    key, self.prefix_in_bucket
-                ),
-            };
+        // The separator after the prefix belongs to it: with the prefix `a`, the key `a/b/c` is
+        // `b/c`, and `ab/c` is not under the prefix at all.
+        let stripped = match &self.prefix_in_bucket {
+            Some(prefix) if !prefix.is_empty() => key
+                .strip_prefix(prefix.as_str())
+                .and_then(|key| key.strip_prefix(REMOTE_STORAGE_PREFIX_SEPARATOR)),
+            _ => Some(key),
+        };
+        let relative_path = match stripped {
+            Some(stripped) => stripped,
+            // we rely on AWS to return properly prefixed paths
+            // for requests with a certain prefix
+            None => panic!(
+                "Key {} does not start with bucket prefix {:?}",
+                key, self.prefix_in_bucket
+            ),
+        };
         RemotePath(
             relative_path
                 .split(REMOTE_STORAGE_PREFIX_SEPARATOR)
@@ -185,7 +305,56 @@ impl S3Bucket {
         permit
     }
 
-    async fn download_object(&self, request: GetObjectRequest) -> Result<Download, DownloadError> {
+    /// Lists one response page of the files under `folder_name`, returns them with the
+    /// token of the next page, if there is one.
+    async fn list_files_page(
+        &self,
+        folder_name: Option<String>,
+        continuation_token: Option<String>,
+    ) -> Result<(Vec<RemotePath>, Option<String>), RemoteStorageError> {
+        let kind = RequestKind::List;
+        let _guard = self.permit(kind).await;
+        metrics::inc_list_objects();
+        let started_at = start_measuring_requests(kind);
+
+        let response = self
+            .client
+            .list_objects_v2()
+            .bucket(self.bucket_name.clone())
+            .set_prefix(folder_name)
+            .set_continuation_token(continuation_token)
+            .set_max_keys(self.max_keys_per_list_response)
+            .send()
+            .await
+            .map_err(|e| {
+                metrics::inc_list_objects_fail();
+                sdk_error_to_storage_error(e)
+            })
+            .context("Failed to list files in S3 bucket");
+
+        let started_at = ScopeGuard::into_inner(started_at);
+        metrics::BUCKET_METRICS
+            .req_seconds
+            .observe_elapsed(kind, &response, started_at);
+
+        let response = response?;
+
+        let files = response
+            .contents()
+            .unwrap_or_default()
+            .iter()
+            .map(|object| {
+                let object_path = object.key().expect("response does not contain a key");
+                self.s3_object_to_relative_path(object_path)
+            })
+            .collect();
+        Ok((files, response.next_continuation_token))
+    }
+
+    async fn download_object(
+        &self,
+        request: GetObjectRequest,
+    ) -> Result<Download, RemoteStorageError> {
         let kind = RequestKind::Get;
         let permit = self.owned_permit(kind).await;
 
@@ -225,38 +394,536 @@ impl S3Bucket {
                 })
             }
             Err(SdkError::ServiceError(e)) if matches!(e.err(), GetObjectError::NoSuchKey(_)) => {
-                Err(DownloadError::NotFound)
+                Err(RemoteStorageError::NotFound)
+            }
+            // Objects in the archive storage classes have to be restored before they can be read,
+            // retrying won't help until someone restores them.
+            Err(SdkError::ServiceError(e))
+                if matches!(e.err(), GetObjectError::InvalidObjectState(_)) =>
+            {
+                let storage_class = match e.err() {
+                    GetObjectError::InvalidObjectState(state) => state.storage_class().cloned(),
+                    _ => None,
+                };
+                Err(RemoteStorageError::Permanent(
+                    anyhow::Error::new(e.into_err())
+                        .context(format!(
+                            "s3 object is archived in storage class {storage_class:?} and has to be restored before download"
+                        )),
+                ))
             }
-            Err(e) => Err(DownloadError::Other(
-                anyhow::Error::new(e).context("download s3 object"),
-            )),
+            Err(e) => Err(sdk_error_to_storage_error(e)),
         }
     }
-}
 
-pin_project_lite::pin_project! {
-    /// An `AsyncRead` adapter which carries a permit for the lifetime of the value.
-    struct RatelimitedAsyncRead<S> {
-        permit: tokio::sync::OwnedSemaphorePermit,
-        #[pin]
-        inner: S,
+    /// Sends a single request that is not a plain object upload, download or listing, e.g.
+    /// one of a multipart upload or a copy, with the same limiting and metrics as the regular
+    /// requests of the `kind`.
+    async fn send_request<T, E>(
+        &self,
+        kind: RequestKind,
+        request: impl Future<Output = Result<T, SdkError<E, aws_smithy_http::operation::Response>>>,
+    ) -> Result<T, RemoteStorageError>
+    where
+        E: std::error::Error + Send + Sync + 'static,
+    {
+        let (inc, inc_fail): (fn(), fn()) = match kind {
+            RequestKind::Get => (metrics::inc_get_object, metrics::inc_get_object_fail),
+            RequestKind::Put => (metrics::inc_put_object, metrics::inc_put_object_fail),
+            RequestKind::Delete => (metrics::inc_delete_object, metrics::inc_delete_object_fail),
+            RequestKind::List => (metrics::inc_list_objects, metrics::inc_list_objects_fail),
+        };
+        let _guard = self.permit(kind).await;
+
+        inc();
+        let started_at = start_measuring_requests(kind);
+
+        let res = request.await.map_err(|e| {
+            inc_fail();
+            sdk_error_to_storage_error(e)
+        });
+
+        let started_at = ScopeGuard::into_inner(started_at);
+        metrics::BUCKET_METRICS
+            .req_seconds
+            .observe_elapsed(kind, &res, started_at);
+
+        res
     }
-}
 
-impl<S: AsyncRead> RatelimitedAsyncRead<S> {
-    fn new(permit: tokio::sync::OwnedSemaphorePermit, inner: S) -> Self {
-        RatelimitedAsyncRead { permit, inner }
+    /// Uploads the object in parts of `multipart_part_size` bytes, sending up to
+    /// `multipart_upload_concurrency` parts at once.
+    ///
+    /// S3 keeps (and bills for) the parts of an unfinished multipart upload, until it's
+    /// either completed or aborted. Without a `multipart_resume_dir`, any failure aborts the
+    /// whole upload, and so does dropping the upload before it's finished, e.g. when the
+    /// caller times it out. With it, the parts are kept for the next upload of the same
+    /// object to continue from, see [`Self::upload_multipart_resumable`].
+    async fn upload_multipart(
+        &self,
+        mut from: impl io::AsyncRead + Unpin,
+        from_size_bytes: usize,
+        key: String,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<()> {
+        if let Some(max_age) = self.multipart_upload_max_age {
+            self.stale_multipart_uploads_aborted
+                .get_or_init(|| self.abort_stale_multipart_uploads(max_age))
+                .await;
+        }
+        let metadata = metadata.map(|m| m.0);
+        if let Some(state_dir) = &self.multipart_state {
+            return self
+                .upload_multipart_resumable(state_dir, from, from_size_bytes, key, metadata)
+                .await;
+        }
+
+        let upload_id = self.create_multipart_upload(&key, metadata.clone()).await?;
+
+        // Dropped with the upload future, so the abort request has to run on its own.
+        let abort_on_drop = scopeguard::guard((), {
+            let client = self.client.clone();
+            let bucket_name = self.bucket_name.clone();
+            let key = key.clone();
+            let upload_id = upload_id.clone();
+            move |()| {
+                let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+                    warn!("Cannot abort the cancelled multipart upload {upload_id} of {key} outside of a runtime");
+                    return;
+                };
+                runtime.spawn(async move {
+                    let abort_res = client
+                        .abort_multipart_upload()
+                        .bucket(bucket_name)
+                        .key(key.clone())
+                        .upload_id(upload_id.clone())
+                        .send()
+                        .await;
+                    if let Err(e) = abort_res {
+                        warn!(
+                            "Failed to abort cancelled multipart upload {upload_id} of {key}: {:#}",
+                            sdk_error_to_storage_error(e)
+                        );
+                    }
+                });
+            }
+        });
+
+        let res: anyhow::Result<()> = async {
+            let mut state = MultipartUploadState::new(
+                key.clone(),
+                from_size_bytes,
+                self.multipart_part_size.get(),
+                metadata,
+                upload_id.clone(),
+            );
+            let parts = self.upload_parts(&mut from, &mut state, None).await?;
+            self.complete_multipart_upload(&key, &upload_id, parts)
+                .await
+        }
+        .await;
+        ScopeGuard::into_inner(abort_on_drop);
+
+        if res.is_err() {
+            if let Err(e) = self.abort_multipart_upload(&key, &upload_id).await {
+                warn!("Failed to abort multipart upload {upload_id} of {key}: {e:#}");
+            }
+        }
+
+        res
     }
-}
 
-impl<S: AsyncRead> AsyncRead for RatelimitedAsyncRead<S> {
-    fn poll_read(
-        self: std::pin::Pin<&mut Self>,
-        cx: &mut std::task::Context<'_>,
-        buf: &mut io::ReadBuf<'_>,
-    ) -> std::task::Poll<std::io::Result<()>> {
-        let this = self.project();
-        this.inner.poll_read(cx, buf)
+    /// Continues the unfinished upload of the same object, if there is one, sending only the
+    /// parts S3 doesn't have yet.
+    ///
+    /// The upload id and the acknowledged parts are stored in the `state_dir` as the upload
+    /// goes, and removed once it's completed. A failed or dropped upload is not aborted: its
+    /// parts are left to the next attempt, or to [`Self::abort_stale_multipart_uploads`].
+    async fn upload_multipart_resumable(
+        &self,
+        state_dir: &MultipartStateDir,
+        mut from: impl io::AsyncRead + Unpin,
+        from_size_bytes: usize,
+        key: String,
+        metadata: Option<HashMap<String, String>>,
+    ) -> anyhow::Result<()> {
+        let mut state = self
+            .resume_or_create_upload(state_dir, &key, from_size_bytes, metadata)
+            .await?;
+        let parts = self
+            .upload_parts(&mut from, &mut state, Some(state_dir))
+            .await?;
+        self.complete_multipart_upload(&key, &state.upload_id, parts)
+            .await?;
+        if let Err(e) = state_dir.remove(&key).await {
+            warn!("{e:#}");
+        }
+        Ok(())
+    }
+
+    async fn resume_or_create_upload(
+        &self,
+        state_dir: &MultipartStateDir,
+        key: &str,
+        from_size_bytes: usize,
+        metadata: Option<HashMap<String, String>>,
+    ) -> anyhow::Result<MultipartUploadState> {
+        let part_size = self.multipart_part_size.get();
+        match state_dir.load(key).await {
+            Ok(Some(mut state))
+                if state.matches(key, from_size_bytes, part_size, metadata.as_ref()) =>
+            {
+                match self.list_uploaded_parts(key, &state.upload_id).await {
+                    Ok(uploaded) => {
+                        // Only the parts S3 still has, as they were acknowledged.
+                        state.parts.retain(|part_number, part| {
+                            uploaded.get(part_number) == Some(&part.e_tag)
+                        });
+                        info!(
+                            "Resuming multipart upload {} of {key} with {} parts already uploaded",
+                            state.upload_id,
+                            state.parts.len()
+                        );
+                        return Ok(state);
+                    }
+                    // Completed or aborted meanwhile.
+                    Err(RemoteStorageError::NotFound) => {}
+                    Err(e) => {
+                        return Err(anyhow::Error::new(e).context(format!(
+                            "Failed to list the parts of multipart upload {} of {key}",
+                            state.upload_id
+                        )))
+                    }
+                }
+            }
+            Ok(Some(state)) => {
+                // Of a different version of the object, which is not going to be resumed.
+                if let Err(e) = self
+                    .abort_multipart_upload(&state.key, &state.upload_id)
+                    .await
+                {
+                    warn!(
+                        "Failed to abort outdated multipart upload {} of {key}: {e:#}",
+                        state.upload_id
+                    );
+                }
+            }
+            Ok(None) => {}
+            Err(e) => warn!("Starting the multipart upload of {key} over: {e:#}"),
+        }
+
+        let upload_id = self.create_multipart_upload(key, metadata.clone()).await?;
+        let state = MultipartUploadState::new(
+            key.to_owned(),
+            from_size_bytes,
+            part_size,
+            metadata,
+            upload_id,
+        );
+        // Not fatal: the upload just won't be resumed, if interrupted.
+        if let Err(e) = state_dir.store(&state).await {
+            warn!("{e:#}");
+        }
+        Ok(state)
+    }
+
+    async fn create_multipart_upload(
+        &self,
+        key: &str,
+        metadata: Option<HashMap<String, String>>,
+    ) -> anyhow::Result<String> {
+        let (content_type, cache_control) =
+            self.object_headers(metadata.clone().map(StorageMetadata).as_ref());
+        let upload = self
+            .send_request(
+                RequestKind::Put,
+                self.client
+                    .create_multipart_upload()
+                    .bucket(self.bucket_name.clone())
+                    .key(key)
+                    .set_metadata(metadata)
+                    .set_storage_class(self.storage_class.clone())
+                    .content_type(content_type)
+                    .set_cache_control(cache_control)
+                    .send(),
+            )
+            .await
+            .context("Failed to start a multipart upload")?;
+        Ok(upload
+            .upload_id()
+            .context("S3 returned no id for the multipart upload")?
+            .to_owned())
+    }
+
+    async fn complete_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+        parts: Vec<CompletedPart>,
+    ) -> anyhow::Result<()> {
+        self.send_request(
+            RequestKind::Put,
+            self.client
+                .complete_multipart_upload()
+                .bucket(self.bucket_name.clone())
+                .key(key)
+                .upload_id(upload_id)
+                .multipart_upload(
+                    CompletedMultipartUpload::builder()
+                        .set_parts(Some(parts))
+                        .build(),
+                )
+                .send(),
+        )
+        .await
+        .context("Failed to complete the multipart upload")?;
+        Ok(())
+    }
+
+    async fn abort_multipart_upload(
+        &self,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<(), RemoteStorageError> {
+        self.send_request(
+            RequestKind::Put,
+            self.client
+                .abort_multipart_upload()
+                .bucket(self.bucket_name.clone())
+                .key(key)
+                .upload_id(upload_id)
+                .send(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// The ETags of the parts of the upload S3 has, by their numbers.
+    async fn list_uploaded_parts(
+        &self,
+        key: &str,
+        upload_id: &str,
+    ) -> Result<HashMap<i32, Option<String>>, RemoteStorageError> {
+        let mut uploaded = HashMap::new();
+        let mut part_number_marker = None;
+        loop {
+            let response = self
+                .send_request(
+                    RequestKind::List,
+                    self.client
+                        .list_parts()
+                        .bucket(self.bucket_name.clone())
+                        .key(key)
+                        .upload_id(upload_id)
+                        .set_part_number_marker(part_number_marker)
+                        .send(),
+                )
+                .await?;
+            for part in response.parts().unwrap_or_default() {
+                uploaded.insert(part.part_number(), part.e_tag().map(str::to_owned));
+            }
+            part_number_marker = match response.next_part_number_marker() {
+                Some(marker) if response.is_truncated() => Some(marker.to_owned()),
+                _ => break,
+            };
+        }
+        Ok(uploaded)
+    }
+
+    /// Aborts the multipart uploads under the prefix of the bucket that were started more than
+    /// `max_age` ago, e.g. by a pageserver that crashed without a `multipart_resume_dir`, or
+    /// the ones no one came back to resume.
+    ///
+    /// Runs before the first multipart upload of the process, which is when the uploads of
+    /// its previous run are abandoned. Failures are only logged.
+    async fn abort_stale_multipart_uploads(&self, max_age: Duration) {
+        let Some(started_before) = SystemTime::now().checked_sub(max_age) else {
+            return;
+        };
+        let prefix = self
+            .prefix_in_bucket
+            .as_ref()
+            .filter(|prefix| !prefix.is_empty())
+            .map(|prefix| format!("{prefix}{REMOTE_STORAGE_PREFIX_SEPARATOR}"));
+
+        let mut aborted = 0;
+        let mut key_marker = None;
+        let mut upload_id_marker = None;
+        loop {
+            let response = self
+                .send_request(
+                    RequestKind::List,
+                    self.client
+                        .list_multipart_uploads()
+                        .bucket(self.bucket_name.clone())
+                        .set_prefix(prefix.clone())
+                        .set_key_marker(key_marker)
+                        .set_upload_id_marker(upload_id_marker)
+                        .send(),
+                )
+                .await;
+            let response = match response {
+                Ok(response) => response,
+                Err(e) => {
+                    warn!("Failed to list the multipart uploads to abort the stale ones: {e:#}");
+                    break;
+                }
+            };
+
+            for upload in response.uploads().unwrap_or_default() {
+                let (Some(key), Some(upload_id)) = (upload.key(), upload.upload_id()) else {
+                    continue;
+                };
+                let initiated = upload
+                    .initiated()
+                    .and_then(|initiated| SystemTime::try_from(*initiated).ok());
+                if !initiated.is_some_and(|initiated| initiated < started_before) {
+                    continue;
+                }
+                match self.abort_multipart_upload(key, upload_id).await {
+                    Ok(()) => aborted += 1,
+                    Err(e) => {
+                        warn!("Failed to abort stale multipart upload {upload_id} of {key}: {e:#}")
+                    }
+                }
+            }
+
+            if !response.is_truncated() {
+                break;
+            }
+            key_marker = response.next_key_marker().map(str::to_owned);
+            upload_id_marker = response.next_upload_id_marker().map(str::to_owned);
+        }
+
+        if aborted > 0 {
+            info!(
+                "Aborted {aborted} multipart uploads started more than {} ago",
+                humantime::format_duration(max_age)
+            );
+        }
+    }
+
+    /// Uploads the parts of the `state`'s upload that it doesn't have yet, adding them to it,
+    /// and stores it in the `state_dir`, if any, after each one.
+    async fn upload_parts(
+        &self,
+        from: &mut (impl io::AsyncRead + Unpin),
+        state: &mut MultipartUploadState,
+        state_dir: Option<&MultipartStateDir>,
+    ) -> anyhow::Result<Vec<CompletedPart>> {
+        let from_size_bytes = state.size;
+        let part_size = state.part_size;
+        let part_count = (from_size_bytes + part_size - 1) / part_size;
+        anyhow::ensure!(
+            part_count <= MAX_MULTIPART_PARTS,
+            "Upload of {from_size_bytes} bytes needs {part_count} parts of {part_size} bytes, more than {MAX_MULTIPART_PARTS} allowed"
+        );
+
+        // The parts in flight borrow these, while the state gets their results.
+        let key = state.key.clone();
+        let upload_id = state.upload_id.clone();
+
+        let mut completed_parts = Vec::with_capacity(part_count);
+        let mut in_flight = FuturesUnordered::new();
+        let mut read_bytes = 0;
+        for part_number in 1..=part_count as i32 {
+            let mut part = Vec::with_capacity(part_size.min(from_size_bytes - read_bytes));
+            (&mut *from)
+                .take(part_size as u64)
+                .read_to_end(&mut part)
+                .await
+                .context("Failed to read the upload data")?;
+            anyhow::ensure!(
+                !part.is_empty(),
+                "Upload data ended after {read_bytes} bytes, expected {from_size_bytes}"
+            );
+            read_bytes += part.len();
+
+            // Hashing a part takes a fraction of the time to send it, and tells whether
+            // the part uploaded before an interruption has the same data.
+            let digest = part_digest(&part);
+            if let Some(uploaded) = state.parts.get(&part_number) {
+                if uploaded.digest == digest {
+                    completed_parts.push(uploaded.completed_part(part_number));
+                    continue;
+                }
+            }
+
+            // The parts in flight only make progress while being polled, which happens here,
+            // once the concurrency limit is reached, and after the last part has been read.
+            if in_flight.len() >= self.multipart_upload_concurrency.get() {
+                let (part_number, uploaded) =
+                    in_flight.next().await.expect("in_flight is not empty")?;
+                completed_parts
+                    .push(Self::part_uploaded(state, state_dir, part_number, uploaded).await);
+            }
+            in_flight.push(self.upload_part(&key, &upload_id, part_number, part, digest));
+        }
+        anyhow::ensure!(
+            read_bytes == from_size_bytes,
+            "Upload data size {read_bytes} does not match the expected size {from_size_bytes}"
+        );
+
+        while let Some(uploaded) = in_flight.next().await {
+            let (part_number, uploaded) = uploaded?;
+            completed_parts
+                .push(Self::part_uploaded(state, state_dir, part_number, uploaded).await);
+        }
+        // S3 requires the parts to be listed in ascending order
+        completed_parts.sort_by_key(|part| part.part_number());
+
+        Ok(completed_parts)
+    }
+
+    async fn part_uploaded(
+        state: &mut MultipartUploadState,
+        state_dir: Option<&MultipartStateDir>,
+        part_number: i32,
+        uploaded: UploadedPart,
+    ) -> CompletedPart {
+        let completed = uploaded.completed_part(part_number);
+        state.parts.insert(part_number, uploaded);
+        if let Some(state_dir) = state_dir {
+            // Not fatal: the part is just sent again, if the upload is interrupted.
+            if let Err(e) = state_dir.store(state).await {
+                warn!("{e:#}");
+            }
+        }
+        completed
+    }
+
+    async fn upload_part(
+        &self,
+        key: &str,
+        upload_id: &str,
+        part_number: i32,
+        part: Vec<u8>,
+        digest: String,
+    ) -> anyhow::Result<(i32, UploadedPart)> {
+        let part_size = part.len();
+        let uploaded = self
+            .send_request(
+                RequestKind::Put,
+                self.client
+                    .upload_part()
+                    .bucket(self.bucket_name.clone())
+                    .key(key)
+                    .upload_id(upload_id)
+                    .part_number(part_number)
+                    .content_length(part_size.try_into()?)
+                    .body(ByteStream::from(part))
+                    .send(),
+            )
+            .await
+            .with_context(|| format!("Failed to upload part {part_number} of {key}"))?;
+
+        Ok((
+            part_number,
+            UploadedPart {
+                e_tag: uploaded.e_tag().map(str::to_owned),
+                digest,
+            },
+        ))
     }
 }
 
@@ -315,7 +982,7 @@ impl RemoteStorage for S3Bucket {
     async fn list_prefixes(
         &self,
         prefix: Option<&RemotePath>,
-    ) -> Result<Vec<RemotePath>, DownloadError> {
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
         let kind = RequestKind::List;
 
         // get the passed prefix or if it is not set use prefix_in_bucket value
@@ -352,10 +1019,10 @@ impl RemoteStorage for S3Bucket {
                 .await
                 .map_err(|e| {
                     metrics::inc_list_objects_fail();
-                    e
+                    sdk_error_to_storage_error(e)
                 })
                 .context("Failed to list S3 prefixes")
-                .map_err(DownloadError::Other);
+                .map_err(RemoteStorageError::from);
 
             let started_at = ScopeGuard::into_inner(started_at);
 
@@ -383,63 +1050,70 @@ impl RemoteStorage for S3Bucket {
     }
 
     /// See the doc for `RemoteStorage::list_files`
-    async fn list_files(&self, folder: Option<&RemotePath>) -> anyhow::Result<Vec<RemotePath>> {
-        let kind = RequestKind::List;
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        self.list_files_stream(folder).try_collect().await
+    }
 
+    /// Lists a response page at a time, the next one is requested when the previous one
+    /// is consumed.
+    fn list_files_stream<'a>(&'a self, folder: Option<&'a RemotePath>) -> ListingStream<'a> {
         let folder_name = folder
             .map(|p| self.relative_path_to_s3_object(p))
-            .or_else(|| self.prefix_in_bucket.clone());
-
-        // AWS may need to break the response into several parts
-        let mut continuation_token = None;
-        let mut all_files = vec![];
-        loop {
-            let _guard = self.permit(kind).await;
-            metrics::inc_list_objects();
-            let started_at = start_measuring_requests(kind);
-
-            let response = self
-                .client
-                .list_objects_v2()
-                .bucket(self.bucket_name.clone())
-                .set_prefix(folder_name.clone())
-                .set_continuation_token(continuation_token)
-                .set_max_keys(self.max_keys_per_list_response)
-                .send()
-                .await
-                .map_err(|e| {
-                    metrics::inc_list_objects_fail();
-                    e
-                })
-                .context("Failed to list files in S3 bucket");
-
-            let started_at = ScopeGuard::into_inner(started_at);
-            metrics::BUCKET_METRICS
-                .req_seconds
-                .observe_elapsed(kind, &response, started_at);
-
-            let response = response?;
+            .or_else(|| {
+                // With the separator, the prefix `a` does not list the keys of the prefix `ab`.
+                self.prefix_in_bucket
+                    .as_ref()
+                    .filter(|prefix| !prefix.is_empty())
+                    .map(|prefix| format!("{prefix}{REMOTE_STORAGE_PREFIX_SEPARATOR}"))
+            });
 
-            for object in response.contents().unwrap_or_default() {
-                let object_path = object.key().expect("response does not contain a key");
-                let remote_path = self.s3_object_to_relative_path(object_path);
-                all_files.push(remote_path);
-            }
-            match response.next_continuation_token {
-                Some(new_token) => continuation_token = Some(new_token),
-                None => break,
+        // AWS may need to break the response into several parts: `Some(None)` is the first
+        // one, `None` is past the last one.
+        let pages = stream::try_unfold(Some(None), move |continuation_token| {
+            let folder_name = folder_name.clone();
+            async move {
+                let Some(continuation_token) = continuation_token else {
+                    return Ok::<_, RemoteStorageError>(None);
+                };
+                let (files, next_continuation_token) = self
+                    .list_files_page(folder_name, continuation_token)
+                    .await?;
+                Ok(Some((files, next_continuation_token.map(Some))))
             }
-        }
-        Ok(all_files)
+        });
+        Box::pin(
+            pages
+                .map_ok(|files| stream::iter(files.into_iter().map(Ok)))
+                .try_flatten(),
+        )
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        Some(self.max_object_size)
     }
 
     async fn upload(
         &self,
-        from: impl io::AsyncRead + Unpin + Send + Sync + 'static,
+        from: UploadStream,
         from_size_bytes: usize,
         to: &RemotePath,
         metadata: Option<StorageMetadata>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RemoteStorageError> {
+        if from_size_bytes > self.multipart_part_size.get() {
+            return self
+                .upload_multipart(
+                    from,
+                    from_size_bytes,
+                    self.relative_path_to_s3_object(to),
+                    metadata,
+                )
+                .await
+                .map_err(RemoteStorageError::from);
+        }
+
         let kind = RequestKind::Put;
         let _guard = self.permit(kind).await;
 
@@ -448,6 +1122,7 @@ impl RemoteStorage for S3Bucket {
 
         let body = Body::wrap_stream(ReaderStream::new(from));
         let bytes_stream = ByteStream::new(SdkBody::from(body));
+        let (content_type, cache_control) = self.object_headers(metadata.as_ref());
 
         let res = self
             .client
@@ -455,13 +1130,20 @@ impl RemoteStorage for S3Bucket {
             .bucket(self.bucket_name.clone())
             .key(self.relative_path_to_s3_object(to))
             .set_metadata(metadata.map(|m| m.0))
-            .content_length(from_size_bytes.try_into()?)
+            .set_storage_class(self.storage_class.clone())
+            .content_type(content_type)
+            .set_cache_control(cache_control)
+            .content_length(
+                from_size_bytes
+                    .try_into()
+                    .context("Upload size does not fit the content length")?,
+            )
             .body(bytes_stream)
             .send()
             .await
             .map_err(|e| {
                 metrics::inc_put_object_fail();
-                e
+                sdk_error_to_storage_error(e)
             });
 
         let started_at = ScopeGuard::into_inner(started_at);
@@ -474,7 +1156,7 @@ impl RemoteStorage for S3Bucket {
         Ok(())
     }
 
-    async fn download(&self, from: &RemotePath) -> Result<Download, DownloadError> {
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
         // if prefix is not none then download file `prefix/from`
         // if prefix is none then download file `from`
         self.download_object(GetObjectRequest {
@@ -490,7 +1172,7 @@ impl RemoteStorage for S3Bucket {
         from: &RemotePath,
         start_inclusive: u64,
         end_exclusive: Option<u64>,
-    ) -> Result<Download, DownloadError> {
+    ) -> Result<Download, RemoteStorageError> {
         // S3 accepts ranges as https://www.w3.org/Protocols/rfc2616/rfc2616-sec14.html#sec14.35
         // and needs both ends to be exclusive
         let end_inclusive = end_exclusive.map(|end| end.saturating_sub(1));
@@ -506,7 +1188,47 @@ impl RemoteStorage for S3Bucket {
         })
         .await
     }
-    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> anyhow::Result<()> {
+    /// A `HeadObject` request, limited and measured as a `GetObject` one: S3 counts both
+    /// towards the same request rate limit.
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        let kind = RequestKind::Get;
+        let _guard = self.permit(kind).await;
+
+        metrics::inc_get_object();
+        let started_at = start_measuring_requests(kind);
+
+        let head_object = self
+            .client
+            .head_object()
+            .bucket(self.bucket_name.clone())
+            .key(self.relative_path_to_s3_object(path))
+            .send()
+            .await;
+
+        let started_at = ScopeGuard::into_inner(started_at);
+        metrics::BUCKET_METRICS
+            .req_seconds
+            .observe_elapsed(kind, &head_object, started_at);
+
+        match head_object {
+            Ok(output) => Ok(ObjectMeta {
+                size: u64::try_from(output.content_length()).unwrap_or_default(),
+                last_modified: output
+                    .last_modified()
+                    .and_then(|last_modified| std::time::SystemTime::try_from(*last_modified).ok()),
+                metadata: output.metadata().cloned().map(StorageMetadata),
+            }),
+            Err(SdkError::ServiceError(e)) if matches!(e.err(), HeadObjectError::NotFound(_)) => {
+                Err(RemoteStorageError::NotFound)
+            }
+            Err(e) => {
+                metrics::inc_get_object_fail();
+                Err(sdk_error_to_storage_error(e))
+            }
+        }
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
         let kind = RequestKind::Delete;
         let _guard = self.permit(kind).await;
 
@@ -539,22 +1261,45 @@ impl RemoteStorage for S3Bucket {
                 Ok(resp) => {
                     if let Some(errors) = resp.errors {
                         metrics::inc_delete_objects_fail(errors.len() as u64);
-                        return Err(anyhow::format_err!(
+                        return Err(RemoteStorageError::Transient(anyhow::format_err!(
                             "Failed to delete {} objects",
                             errors.len()
-                        ));
+                        )));
                     }
                 }
                 Err(e) => {
                     metrics::inc_delete_objects_fail(chunk.len() as u64);
-                    return Err(e.into());
+                    return Err(sdk_error_to_storage_error(e));
                 }
             }
         }
         Ok(())
     }
 
-    async fn delete(&self, path: &RemotePath) -> anyhow::Result<()> {
+    /// Deletes a listing page at a time: a page has at most 1000 keys, as many as a single
+    /// `DeleteObjects` request takes.
+    async fn delete_prefix(&self, prefix: &RemotePath) -> Result<usize, RemoteStorageError> {
+        let folder_name = format!(
+            "{}{REMOTE_STORAGE_PREFIX_SEPARATOR}",
+            self.relative_path_to_s3_object(prefix)
+        );
+        let mut deleted = 0;
+        let mut continuation_token = None;
+        loop {
+            let (files, next_continuation_token) = self
+                .list_files_page(Some(folder_name.clone()), continuation_token)
+                .await?;
+            self.delete_objects(&files).await?;
+            deleted += files.len();
+            continuation_token = match next_continuation_token {
+                Some(token) => Some(token),
+                None => break,
+            };
+        }
+        Ok(deleted)
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
         let kind = RequestKind::Delete;
         let _guard = self.permit(kind).await;
 
@@ -570,7 +1315,7 @@ impl RemoteStorage for S3Bucket {
             .await
             .map_err(|e| {
                 metrics::inc_delete_object_fail();
-                e
+                sdk_error_to_storage_error(e)
             });
 
         let started_at = ScopeGuard::into_inner(started_at);
@@ -582,6 +1327,62 @@ impl RemoteStorage for S3Bucket {
 
         Ok(())
     }
+
+    /// Copies the object on the S3 side with `CopyObject`, which is limited to 5 GiB objects.
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        let copy_source = format!(
+            "{}/{}",
+            self.bucket_name,
+            encode_copy_source_key(&self.relative_path_to_s3_object(from))
+        );
+        self.send_request(
+            RequestKind::Put,
+            self.client
+                .copy_object()
+                .bucket(self.bucket_name.clone())
+                .key(self.relative_path_to_s3_object(to))
+                .copy_source(copy_source)
+                .set_storage_class(self.storage_class.clone())
+                .send(),
+        )
+        .await
+        .with_context(|| format!("Failed to copy {from} to {to}"))?;
+        Ok(())
+    }
+}
+
+/// Classifies the SDK error by the status of the S3 response, if there was one: S3 answers
+/// with a 503 `SlowDown` when the request rate of a prefix is too high.
+fn sdk_error_to_storage_error<E>(
+    e: SdkError<E, aws_smithy_http::operation::Response>,
+) -> RemoteStorageError
+where
+    E: std::error::Error + Send + Sync + 'static,
+{
+    if matches!(e, SdkError::TimeoutError(_)) {
+        return RemoteStorageError::Timeout;
+    }
+    let status = e
+        .raw_response()
+        .map(|response| response.http().status().as_u16());
+    match status {
+        Some(status) => RemoteStorageError::from_http_status(status, anyhow::Error::new(e)),
+        None => RemoteStorageError::Transient(anyhow::Error::new(e)),
+    }
+}
+
+/// Percent-encodes the key for the `x-amz-copy-source` header, keeping the `/` separators.
+fn encode_copy_source_key(key: &str) -> String {
+    let mut encoded = String::with_capacity(key.len());
+    for byte in key.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' | b'/' => {
+                encoded.push(byte as char)
+            }
+            _ => encoded.push_str(&format!("%{byte:02X}")),
+        }
+    }
+    encoded
 }
 
 /// On drop (cancellation) count towards [`metrics::BucketMetrics::cancelled_waits`].
@@ -611,7 +1412,14 @@ mod tests {
     use std::num::NonZeroUsize;
     use std::path::Path;
 
-    use crate::{RemotePath, S3Bucket, S3Config};
+    use aws_sdk_s3::types::StorageClass;
+
+    use crate::{Compression, RemotePath, RemoteStorage, S3Bucket, S3Config, StorageMetadata};
+
+    use super::{
+        encode_copy_source_key, DEFAULT_CONTENT_TYPE, IMMUTABLE_CACHE_CONTROL, MAX_MULTIPART_PARTS,
+        MAX_MULTIPART_PART_SIZE, MAX_OBJECT_SIZE, MIN_MULTIPART_PART_SIZE,
+    };
 
     #[test]
     fn relative_path() {
@@ -653,8 +1461,17 @@ mod tests {
                 bucket_region: "region".to_owned(),
                 prefix_in_bucket: prefix.map(str::to_string),
                 endpoint: None,
+                force_path_style: None,
                 concurrency_limit: NonZeroUsize::new(100).unwrap(),
                 max_keys_per_list_response: Some(5),
+                multipart_part_size: NonZeroUsize::new(MIN_MULTIPART_PART_SIZE).unwrap(),
+                multipart_upload_concurrency: NonZeroUsize::new(4).unwrap(),
+                multipart_resume_dir: None,
+                multipart_upload_max_age: None,
+                storage_class: None,
+                content_type: None,
+                cache_control: None,
+                max_object_size: None,
             };
             let storage = S3Bucket::new(&config).expect("remote storage init");
             for (test_path_idx, test_path) in all_paths.iter().enumerate() {
@@ -664,4 +1481,191 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    fn relative_path_round_trip() {
+        for prefix in [None, Some("test"), Some("test/prefix/")] {
+            let config = S3Config {
+                bucket_name: "bucket".to_owned(),
+                bucket_region: "region".to_owned(),
+                prefix_in_bucket: prefix.map(str::to_string),
+                endpoint: None,
+                force_path_style: None,
+                concurrency_limit: NonZeroUsize::new(100).unwrap(),
+                max_keys_per_list_response: None,
+                multipart_part_size: NonZeroUsize::new(MIN_MULTIPART_PART_SIZE).unwrap(),
+                multipart_upload_concurrency: NonZeroUsize::new(4).unwrap(),
+                multipart_resume_dir: None,
+                multipart_upload_max_age: None,
+                storage_class: None,
+                content_type: None,
+                cache_control: None,
+                max_object_size: None,
+            };
+            let storage = S3Bucket::new(&config).expect("remote storage init");
+            let path = RemotePath::new(Path::new("tenant/timeline/layer")).unwrap();
+            let key = storage.relative_path_to_s3_object(&path);
+            assert_eq!(storage.s3_object_to_relative_path(&key), path);
+        }
+    }
+
+    #[test]
+    #[should_panic(expected = "does not start with bucket prefix")]
+    fn key_of_a_longer_prefix() {
+        let config = S3Config {
+            bucket_name: "bucket".to_owned(),
+            bucket_region: "region".to_owned(),
+            prefix_in_bucket: Some("test".to_owned()),
+            endpoint: None,
+            force_path_style: None,
+            concurrency_limit: NonZeroUsize::new(100).unwrap(),
+            max_keys_per_list_response: None,
+            multipart_part_size: NonZeroUsize::new(MIN_MULTIPART_PART_SIZE).unwrap(),
+            multipart_upload_concurrency: NonZeroUsize::new(4).unwrap(),
+            multipart_resume_dir: None,
+            multipart_upload_max_age: None,
+            storage_class: None,
+            content_type: None,
+            cache_control: None,
+            max_object_size: None,
+        };
+        let storage = S3Bucket::new(&config).expect("remote storage init");
+        storage.s3_object_to_relative_path("testing/layer");
+    }
+
+    #[test]
+    fn storage_class() {
+        let config = |storage_class: &str| S3Config {
+            bucket_name: "bucket".to_owned(),
+            bucket_region: "region".to_owned(),
+            prefix_in_bucket: None,
+            endpoint: None,
+            force_path_style: None,
+            concurrency_limit: NonZeroUsize::new(100).unwrap(),
+            max_keys_per_list_response: None,
+            multipart_part_size: NonZeroUsize::new(MIN_MULTIPART_PART_SIZE).unwrap(),
+            multipart_upload_concurrency: NonZeroUsize::new(4).unwrap(),
+            multipart_resume_dir: None,
+            multipart_upload_max_age: None,
+            storage_class: Some(storage_class.to_owned()),
+            content_type: None,
+            cache_control: None,
+            max_object_size: None,
+        };
+
+        let storage = S3Bucket::new(&config("GLACIER_IR")).expect("remote storage init");
+        assert_eq!(storage.storage_class, Some(StorageClass::GlacierIr));
+        assert!(S3Bucket::new(&config("glacier")).is_err());
+        assert!(S3Bucket::new(&config("GLACIER")).is_err());
+        assert!(S3Bucket::new(&config("DEEP_ARCHIVE")).is_err());
+    }
+
+    #[test]
+    fn object_headers() {
+        let config = |content_type: Option<&str>| S3Config {
+            bucket_name: "bucket".to_owned(),
+            bucket_region: "region".to_owned(),
+            prefix_in_bucket: None,
+            endpoint: None,
+            force_path_style: None,
+            concurrency_limit: NonZeroUsize::new(100).unwrap(),
+            max_keys_per_list_response: None,
+            multipart_part_size: NonZeroUsize::new(MIN_MULTIPART_PART_SIZE).unwrap(),
+            multipart_upload_concurrency: NonZeroUsize::new(4).unwrap(),
+            multipart_resume_dir: None,
+            multipart_upload_max_age: None,
+            storage_class: None,
+            content_type: content_type.map(str::to_owned),
+            cache_control: Some("no-cache".to_owned()),
+            max_object_size: None,
+        };
+        let headers = |content_type: &str, cache_control: &str| {
+            (content_type.to_owned(), Some(cache_control.to_owned()))
+        };
+
+        let storage = S3Bucket::new(&config(None)).expect("remote storage init");
+        assert_eq!(
+            storage.object_headers(None),
+            headers(DEFAULT_CONTENT_TYPE, "no-cache")
+        );
+        let compressed = Compression::Zstd.record_in_metadata(None);
+        assert_eq!(
+            storage.object_headers(compressed.as_ref()),
+            headers("application/zstd", "no-cache")
+        );
+        let layer = StorageMetadata::mark_immutable(compressed.clone());
+        assert_eq!(
+            storage.object_headers(layer.as_ref()),
+            headers("application/zstd", IMMUTABLE_CACHE_CONTROL)
+        );
+        let mut encrypted = compressed.unwrap();
+        encrypted
+            .0
+            .insert("encryption".to_owned(), "aes-256-gcm".to_owned());
+        assert_eq!(
+            storage.object_headers(Some(&encrypted)),
+            headers(DEFAULT_CONTENT_TYPE, "no-cache")
+        );
+
+        let storage =
+            S3Bucket::new(&config(Some("application/x-layer"))).expect("remote storage init");
+        assert_eq!(
+            storage.object_headers(None),
+            headers("application/x-layer", "no-cache")
+        );
+        assert_eq!(
+            storage.object_headers(Compression::Gzip.record_in_metadata(None).as_ref()),
+            headers("application/gzip", "no-cache")
+        );
+    }
+
+    #[test]
+    fn max_object_size() {
+        let config = |multipart_part_size: usize, max_object_size: Option<u64>| S3Config {
+            bucket_name: "bucket".to_owned(),
+            bucket_region: "region".to_owned(),
+            prefix_in_bucket: None,
+            endpoint: None,
+            force_path_style: None,
+            concurrency_limit: NonZeroUsize::new(100).unwrap(),
+            max_keys_per_list_response: None,
+            multipart_part_size: NonZeroUsize::new(multipart_part_size).unwrap(),
+            multipart_upload_concurrency: NonZeroUsize::new(4).unwrap(),
+            multipart_resume_dir: None,
+            multipart_upload_max_age: None,
+            storage_class: None,
+            content_type: None,
+            cache_control: None,
+            max_object_size,
+        };
+        let max_object_size = |config| {
+            S3Bucket::new(&config)
+                .expect("remote storage init")
+                .max_object_size()
+        };
+
+        // Limited by the number of parts.
+        assert_eq!(
+            max_object_size(config(MIN_MULTIPART_PART_SIZE, None)),
+            Some(MIN_MULTIPART_PART_SIZE as u64 * MAX_MULTIPART_PARTS as u64)
+        );
+        assert_eq!(
+            max_object_size(config(MAX_MULTIPART_PART_SIZE, None)),
+            Some(MAX_OBJECT_SIZE)
+        );
+        assert_eq!(
+            max_object_size(config(MIN_MULTIPART_PART_SIZE, Some(1 << 30))),
+            Some(1 << 30)
+        );
+        assert!(S3Bucket::new(&config(MAX_MULTIPART_PART_SIZE + 1, None)).is_err());
+    }
+
+    #[test]
+    fn copy_source_encoding() {
+        assert_eq!(
+            encode_copy_source_key("prefix/tenant/timeline/layer-name_1.old"),
+            "prefix/tenant/timeline/layer-name_1.old"
+        );
+        assert_eq!(encode_copy_source_key("a b+c"), "a%20b%2Bc");
+    }
 }
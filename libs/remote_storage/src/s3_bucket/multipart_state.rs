@@ -0,0 +1,192 @@
+//! The progress of the multipart uploads, kept in a local directory, so that an upload
+//! interrupted by an error or a restart continues with the parts S3 already has, instead of
+//! starting over.
+//!
+//! An upload is resumed only by an upload of the same key, size, part size and metadata.
+//! The parts are remembered with the digest of their contents, so a part whose data differs
+//! from the one uploaded before is sent again.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io;
+use std::path::PathBuf;
+
+use anyhow::Context;
+use aws_sdk_s3::types::CompletedPart;
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct MultipartUploadState {
+    pub key: String,
+    pub size: usize,
+    pub part_size: usize,
+    pub metadata: Option<HashMap<String, String>>,
+    pub upload_id: String,
+    /// The parts S3 has acknowledged, by their numbers.
+    pub parts: BTreeMap<i32, UploadedPart>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct UploadedPart {
+    pub e_tag: Option<String>,
+    /// Hex encoded SHA-256 of the part contents.
+    pub digest: String,
+}
+
+impl MultipartUploadState {
+    pub fn new(
+        key: String,
+        size: usize,
+        part_size: usize,
+        metadata: Option<HashMap<String, String>>,
+        upload_id: String,
+    ) -> Self {
+        Self {
+            key,
+            size,
+            part_size,
+            metadata,
+            upload_id,
+            parts: BTreeMap::new(),
+        }
+    }
+
+    /// Whether the upload can be continued by an upload of `key` with these parameters.
+    pub fn matches(
+        &self,
+        key: &str,
+        size: usize,
+        part_size: usize,
+        metadata: Option<&HashMap<String, String>>,
+    ) -> bool {
+        self.key == key
+            && self.size == size
+            && self.part_size == part_size
+            && self.metadata.as_ref() == metadata
+    }
+}
+
+impl UploadedPart {
+    pub fn completed_part(&self, part_number: i32) -> CompletedPart {
+        CompletedPart::builder()
+            .part_number(part_number)
+            .set_e_tag(self.e_tag.clone())
+            .build()
+    }
+}
+
+pub(super) fn part_digest(part: &[u8]) -> String {
+    hex(digest::digest(&digest::SHA256, part).as_ref())
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes
+        .iter()
+        .fold(String::with_capacity(bytes.len() * 2), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+/// The directory with the state file of every unfinished multipart upload.
+pub(super) struct MultipartStateDir {
+    dir: PathBuf,
+}
+
+impl MultipartStateDir {
+    pub fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The keys can be longer than a file name is allowed to be, so the files are named by
+    /// their digests.
+    fn state_path(&self, key: &str) -> PathBuf {
+        self.dir
+            .join(format!("{}.json", part_digest(key.as_bytes())))
+    }
+
+    pub async fn load(&self, key: &str) -> anyhow::Result<Option<MultipartUploadState>> {
+        let path = self.state_path(key);
+        let contents = match tokio::fs::read(&path).await {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => {
+                return Err(anyhow::Error::new(e).context(format!(
+                    "Failed to read the upload state {}",
+                    path.display()
+                )))
+            }
+        };
+        let state: MultipartUploadState = serde_json::from_slice(&contents)
+            .with_context(|| format!("Failed to parse the upload state {}", path.display()))?;
+        Ok(Some(state).filter(|state| state.key == key))
+    }
+
+    /// Replaces the state file atomically, a crash leaves either the old or the new state.
+    pub async fn store(&self, state: &MultipartUploadState) -> anyhow::Result<()> {
+        tokio::fs::create_dir_all(&self.dir)
+            .await
+            .with_context(|| format!("Failed to create directory {}", self.dir.display()))?;
+        let path = self.state_path(&state.key);
+        let temp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&temp_path, serde_json::to_vec(state)?)
+            .await
+            .with_context(|| format!("Failed to write the upload state {}", temp_path.display()))?;
+        tokio::fs::rename(&temp_path, &path)
+            .await
+            .with_context(|| format!("Failed to rename the upload state to {}", path.display()))
+    }
+
+    pub async fn remove(&self, key: &str) -> anyhow::Result<()> {
+        let path = self.state_path(key);
+        match tokio::fs::remove_file(&path).await {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(anyhow::Error::new(e).context(
+                format!("Failed to remove the upload state {}", path.display()),
+            )),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn state_round_trip() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        // Created on the first store.
+        let dir = temp_dir.path().join("multipart_uploads");
+        let state_dir = MultipartStateDir::new(dir.clone());
+        let key = "prefix/tenants/tenant/timelines/timeline/layer".repeat(10);
+        assert_eq!(state_dir.load(&key).await?, None);
+
+        let mut state =
+            MultipartUploadState::new(key.clone(), 20 << 20, 8 << 20, None, "upload-id".to_owned());
+        state_dir.store(&state).await?;
+        state.parts.insert(
+            2,
+            UploadedPart {
+                e_tag: Some("\"etag\"".to_owned()),
+                digest: part_digest(b"part"),
+            },
+        );
+        state_dir.store(&state).await?;
+        assert_eq!(state_dir.load(&key).await?, Some(state.clone()));
+        assert_eq!(state_dir.load("another/key").await?, None);
+
+        assert!(state.matches(&key, 20 << 20, 8 << 20, None));
+        assert!(!state.matches(&key, 21 << 20, 8 << 20, None));
+        assert!(!state.matches(&key, 20 << 20, 5 << 20, None));
+        let metadata = HashMap::from([("key".to_owned(), "value".to_owned())]);
+        assert!(!state.matches(&key, 20 << 20, 8 << 20, Some(&metadata)));
+
+        state_dir.remove(&key).await?;
+        assert_eq!(state_dir.load(&key).await?, None);
+        // Already removed.
+        state_dir.remove(&key).await?;
+        assert_eq!(std::fs::read_dir(&dir)?.count(), 0);
+        Ok(())
+    }
+}
@@ -0,0 +1,733 @@
+//! A directory on an SFTP server acting as a remote storage, for the setups that have no
+//! S3-compatible endpoint to use.
+//!
+//! All requests go over a single SSH connection, each in its own SFTP session (an SSH channel).
+//! Opening a session takes a few round trips, so the sessions are kept open and reused,
+//! up to `concurrency_limit` of them.
+//!
+//! SFTP has no object metadata, so it's stored in a file next to the object, the same way
+//! [`crate::LocalFs`] does. Uploads are written to temporary files and renamed into place,
+//! the metadata first. The plain SFTP rename cannot replace a file, so the renames use the
+//! `posix-rename@openssh.com` extension when the server supports it, and fall back to removing
+//! the previous version first otherwise.
+
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context as TaskContext, Poll};
+use std::time::{Duration, UNIX_EPOCH};
+
+use anyhow::{bail, ensure, Context};
+use russh::client;
+use russh_keys::key;
+use russh_sftp::client::{error::Error as SftpError, fs::File, RawSftpSession, SftpSession};
+use russh_sftp::protocol::{Packet, StatusCode};
+use serde::Serialize;
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, ReadBuf};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tracing::{debug, warn};
+
+use crate::{
+    Download, ObjectMeta, RemotePath, RemoteStorage, RemoteStorageError, SftpConfig,
+    StorageMetadata, UploadStream, REMOTE_STORAGE_PREFIX_SEPARATOR,
+};
+
+const SFTP_TEMP_FILE_SUFFIX: &str = "___temp";
+const SFTP_METADATA_FILE_SUFFIX: &str = "metadata";
+const POSIX_RENAME_EXTENSION: &str = "posix-rename@openssh.com";
+
+/// A directory on an SFTP server.
+pub struct Sftp {
+    host: String,
+    port: u16,
+    username: String,
+    key: Arc<key::KeyPair>,
+    known_hosts_path: Option<PathBuf>,
+    root_path: String,
+    connection: tokio::sync::Mutex<Option<client::Handle<ServerKeyCheck>>>,
+    sessions: Arc<SessionPool>,
+}
+
+impl Sftp {
+    /// Creates the SFTP storage, errors if the key cannot be loaded.
+    /// Connects to the server lazily, on the first request.
+    pub fn new(sftp_config: &SftpConfig) -> anyhow::Result<Self> {
+        debug!(
+            "Creating sftp remote storage for {}@{}:{}",
+            sftp_config.username, sftp_config.host, sftp_config.port
+        );
+        let key = russh_keys::load_secret_key(&sftp_config.key_path, None).with_context(|| {
+            format!(
+                "Failed to load the sftp private key from {}",
+                sftp_config.key_path.display()
+            )
+        })?;
+        let root_path = sftp_config
+            .root_path
+            .to_str()
+            .with_context(|| {
+                format!(
+                    "Sftp root path {} is not valid UTF-8",
+                    sftp_config.root_path.display()
+                )
+            })?
+            .trim_end_matches(REMOTE_STORAGE_PREFIX_SEPARATOR)
+            .to_string();
+
+        Ok(Self {
+            host: sftp_config.host.clone(),
+            port: sftp_config.port,
+            username: sftp_config.username.clone(),
+            key: Arc::new(key),
+            known_hosts_path: sftp_config.known_hosts_path.clone(),
+            root_path,
+            connection: tokio::sync::Mutex::new(None),
+            sessions: Arc::new(SessionPool {
+                idle: Mutex::new(Vec::new()),
+                concurrency_limiter: Arc::new(Semaphore::new(sftp_config.concurrency_limit.get())),
+            }),
+        })
+    }
+
+    fn relative_path_to_sftp_path(&self, path: &RemotePath) -> String {
+        let path = path.get_path().to_string_lossy();
+        let path = path.trim_end_matches(REMOTE_STORAGE_PREFIX_SEPARATOR);
+        if path.is_empty() {
+            self.root_path.clone()
+        } else if self.root_path.is_empty() {
+            path.to_string()
+        } else {
+            format!("{}/{path}", self.root_path)
+        }
+    }
+
+    fn sftp_path_to_relative_path(&self, sftp_path: &str) -> anyhow::Result<RemotePath> {
+        let relative_path = if self.root_path.is_empty() {
+            sftp_path
+        } else {
+            sftp_path
+                .strip_prefix(&self.root_path)
+                .with_context(|| {
+                    format!(
+                        "Path {sftp_path} is not in the root directory {}",
+                        self.root_path
+                    )
+                })?
+                .trim_start_matches(REMOTE_STORAGE_PREFIX_SEPARATOR)
+        };
+        RemotePath::new(Path::new(relative_path))
+    }
+
+    async fn connect(&self) -> anyhow::Result<client::Handle<ServerKeyCheck>> {
+        let server_key_check = ServerKeyCheck {
+            host: self.host.clone(),
+            port: self.port,
+            known_hosts_path: self.known_hosts_path.clone(),
+        };
+        let mut connection = client::connect(
+            Arc::new(client::Config::default()),
+            (self.host.as_str(), self.port),
+            server_key_check,
+        )
+        .await
+        .with_context(|| {
+            format!(
+                "Failed to connect to sftp server {}:{}",
+                self.host, self.port
+            )
+        })?;
+
+        let authenticated = connection
+            .authenticate_publickey(self.username.clone(), Arc::clone(&self.key))
+            .await
+            .context("Failed to authenticate on the sftp server")?;
+        ensure!(
+            authenticated,
+            "Sftp server {}:{} rejected the key of user {}",
+            self.host,
+            self.port,
+            self.username
+        );
+        Ok(connection)
+    }
+
+    async fn open_session(&self) -> anyhow::Result<Session> {
+        let mut connection = self.connection.lock().await;
+        if connection.as_ref().map_or(true, |c| c.is_closed()) {
+            *connection = Some(self.connect().await?);
+        }
+        let connection = connection.as_ref().expect("connected above");
+
+        let mut channels = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let channel = connection
+                .channel_open_session()
+                .await
+                .context("Failed to open an ssh channel")?;
+            channel
+                .request_subsystem(true, "sftp")
+                .await
+                .context("Failed to request the sftp subsystem")?;
+            channels.push(channel);
+        }
+        let sftp = SftpSession::new(channels.remove(0).into_stream())
+            .await
+            .context("Failed to start an sftp session")?;
+
+        // SftpSession has no method for the extension, so the renames go over a raw session of
+        // their own. It's closed on drop if the server has no such extension.
+        let raw = RawSftpSession::new(channels.remove(0).into_stream());
+        let version = raw
+            .init()
+            .await
+            .context("Failed to start an sftp session for the renames")?;
+        let posix_rename = match version.extensions.get(POSIX_RENAME_EXTENSION) {
+            Some(extension_version) if extension_version == "1" => Some(raw),
+            _ => {
+                debug!(
+                    "sftp server has no {POSIX_RENAME_EXTENSION} extension, renames are not atomic"
+                );
+                None
+            }
+        };
+        Ok(Session { sftp, posix_rename })
+    }
+
+    /// Takes an idle session or opens a new one, waiting if `concurrency_limit` sessions are busy.
+    async fn session(&self) -> anyhow::Result<PooledSession> {
+        let permit = Arc::clone(&self.sessions.concurrency_limiter)
+            .acquire_owned()
+            .await
+            .expect("semaphore is never closed");
+        let idle = self.sessions.idle.lock().unwrap().pop();
+        let session = match idle {
+            Some(session) => session,
+            None => self.open_session().await?,
+        };
+        Ok(PooledSession {
+            session: Some(session),
+            pool: Arc::clone(&self.sessions),
+            _permit: permit,
+        })
+    }
+
+    async fn read_storage_metadata(
+        &self,
+        session: &SftpSession,
+        file_path: &str,
+    ) -> anyhow::Result<Option<StorageMetadata>> {
+        let metadata_path = storage_metadata_path(file_path);
+        if !session.try_exists(metadata_path.clone()).await? {
+            return Ok(None);
+        }
+        let mut metadata_string = String::new();
+        session
+            .open(metadata_path.clone())
+            .await?
+            .read_to_string(&mut metadata_string)
+            .await
+            .with_context(|| format!("Failed to read metadata from {metadata_path}"))?;
+        serde_json::from_str(&metadata_string)
+            .with_context(|| format!("Failed to deserialize metadata from {metadata_path}"))
+            .map(|metadata| Some(StorageMetadata(metadata)))
+    }
+
+    async fn download_from(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, RemoteStorageError> {
+        let session = self.session().await.map_err(RemoteStorageError::from)?;
+        let file_path = self.relative_path_to_sftp_path(from);
+        let res = async {
+            if !session.try_exists(file_path.clone()).await? {
+                return Ok(None);
+            }
+            let mut file = session.open(file_path.clone()).await?;
+            if start_inclusive > 0 {
+                file.seek(SeekFrom::Start(start_inclusive)).await?;
+            }
+            let metadata = self.read_storage_metadata(&session, &file_path).await?;
+            anyhow::Ok(Some((file, metadata)))
+        }
+        .await
+        .with_context(|| format!("Failed to download {file_path}"));
+
+        let (file, metadata) = match res {
+            Ok(Some(found)) => found,
+            Ok(None) => return Err(RemoteStorageError::NotFound),
+            Err(e) => {
+                session.discard();
+                return Err(RemoteStorageError::Transient(e));
+            }
+        };
+        // The session goes back to the pool once the download is read or dropped.
+        let download = SessionBoundRead {
+            inner: file,
+            _session: session,
+        };
+        Ok(Download {
+            metadata,
+            download_stream: match end_exclusive {
+                Some(end_exclusive) => Box::pin(download.take(end_exclusive - start_inclusive)),
+                None => Box::pin(download),
+            },
+        })
+    }
+
+    /// Creates the missing parent directories of the file, as SFTP does not create them on write.
+    async fn create_parent_directories(
+        &self,
+        session: &SftpSession,
+        file_path: &str,
+    ) -> anyhow::Result<()> {
+        let mut missing = Vec::new();
+        let mut directory = Path::new(file_path).parent();
+        while let Some(path) = directory.and_then(Path::to_str) {
+            if path.is_empty() || session.try_exists(path.to_string()).await? {
+                break;
+            }
+            missing.push(path.to_string());
+            directory = Path::new(path).parent();
+        }
+        for path in missing.into_iter().rev() {
+            if let Err(e) = session.create_dir(path.clone()).await {
+                // Another upload might have created it in the meantime.
+                if !session.try_exists(path.clone()).await? {
+                    return Err(e).with_context(|| format!("Failed to create directory {path}"));
+                }
+            }
+        }
+        Ok(())
+    }
+
+    async fn upload_with_session(
+        &self,
+        session: &Session,
+        data: UploadStream,
+        data_size_bytes: usize,
+        file_path: &str,
+        metadata: Option<StorageMetadata>,
+    ) -> anyhow::Result<()> {
+        self.create_parent_directories(session, file_path).await?;
+
+        let temp_file_path = format!("{file_path}.{SFTP_TEMP_FILE_SUFFIX}");
+        let mut destination = session
+            .create(temp_file_path.clone())
+            .await
+            .with_context(|| format!("Failed to create {temp_file_path}"))?;
+
+        let from_size_bytes = data_size_bytes as u64;
+        let mut buffer_to_read = data.take(from_size_bytes);
+        let bytes_read = io::copy(&mut buffer_to_read, &mut destination)
+            .await
+            .with_context(|| format!("Failed to write {temp_file_path}"))?;
+        if bytes_read < from_size_bytes {
+            bail!("Provided stream was shorter than expected: {bytes_read} vs {from_size_bytes} bytes");
+        }
+        let mut from = buffer_to_read.into_inner();
+        let extra_read = from.read(&mut [1]).await?;
+        ensure!(
+            extra_read == 0,
+            "Provided stream was larger than expected: expected {from_size_bytes} bytes",
+        );
+        destination
+            .shutdown()
+            .await
+            .with_context(|| format!("Failed to close {temp_file_path}"))?;
+
+        // The metadata goes in place first: if the upload fails after that, it's retried and
+        // overwrites the metadata again, while the object never shows up without its metadata.
+        let metadata_path = storage_metadata_path(file_path);
+        match metadata {
+            Some(storage_metadata) => {
+                let temp_metadata_path = format!("{metadata_path}.{SFTP_TEMP_FILE_SUFFIX}");
+                let mut metadata_file = session
+                    .create(temp_metadata_path.clone())
+                    .await
+                    .with_context(|| format!("Failed to create {temp_metadata_path}"))?;
+                metadata_file
+                    .write_all(
+                        serde_json::to_string(&storage_metadata.0)
+                            .context("Failed to serialize storage metadata as json")?
+                            .as_bytes(),
+                    )
+                    .await
+                    .with_context(|| format!("Failed to write metadata to {temp_metadata_path}"))?;
+                metadata_file
+                    .shutdown()
+                    .await
+                    .with_context(|| format!("Failed to close {temp_metadata_path}"))?;
+                session
+                    .rename_over(&temp_metadata_path, &metadata_path)
+                    .await?;
+            }
+            None => {
+                // Metadata of the previous version does not belong to the new one.
+                if session.try_exists(metadata_path.clone()).await? {
+                    session
+                        .remove_file(metadata_path.clone())
+                        .await
+                        .with_context(|| format!("Failed to remove {metadata_path}"))?;
+                }
+            }
+        }
+
+        session.rename_over(&temp_file_path, file_path).await
+    }
+
+    async fn delete_with_session(
+        &self,
+        session: &SftpSession,
+        file_path: &str,
+    ) -> anyhow::Result<()> {
+        // Deleting a missing file succeeds, as in S3.
+        for path in [file_path.to_string(), storage_metadata_path(file_path)] {
+            if session.try_exists(path.clone()).await? {
+                session
+                    .remove_file(path.clone())
+                    .await
+                    .with_context(|| format!("Failed to delete {path}"))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lists the directory, returning the full paths of the files and the subdirectories in it.
+    async fn read_dir(
+        &self,
+        session: &SftpSession,
+        directory: &str,
+    ) -> anyhow::Result<(Vec<String>, Vec<String>)> {
+        let mut files = Vec::new();
+        let mut directories = Vec::new();
+        if !session.try_exists(directory.to_string()).await? {
+            return Ok((files, directories));
+        }
+        let entries = session
+            .read_dir(directory.to_string())
+            .await
+            .with_context(|| format!("Failed to list directory {directory}"))?;
+        for entry in entries {
+            let name = entry.file_name();
+            if name == "." || name == ".." {
+                continue;
+            }
+            let path = if directory.is_empty() {
+                name
+            } else {
+                format!("{directory}/{name}")
+            };
+            if entry.file_type().is_dir() {
+                directories.push(path);
+            } else if !is_internal_file(&path) {
+                files.push(path);
+            }
+        }
+        Ok((files, directories))
+    }
+}
+
+/// Checks the server's host key against the `known_hosts` file.
+struct ServerKeyCheck {
+    host: String,
+    port: u16,
+    known_hosts_path: Option<PathBuf>,
+}
+
+#[async_trait::async_trait]
+impl client::Handler for ServerKeyCheck {
+    type Error = anyhow::Error;
+
+    async fn check_server_key(
+        self,
+        server_public_key: &key::PublicKey,
+    ) -> Result<(Self, bool), Self::Error> {
+        let known = match &self.known_hosts_path {
+            Some(path) => {
+                russh_keys::check_known_hosts_path(&self.host, self.port, server_public_key, path)
+            }
+            None => russh_keys::check_known_hosts(&self.host, self.port, server_public_key),
+        }
+        .context("Failed to check the sftp server key against the known hosts")?;
+        if !known {
+            warn!(
+                "Sftp server {}:{} is not in the known hosts, refusing to connect",
+                self.host, self.port
+            );
+        }
+        Ok((self, known))
+    }
+}
+
+/// An SFTP session, with a raw one next to it for the renames that replace their target.
+struct Session {
+    sftp: SftpSession,
+    /// `None` if the server does not support the `posix-rename@openssh.com` extension.
+    posix_rename: Option<RawSftpSession>,
+}
+
+/// The request data of the `posix-rename@openssh.com` extension.
+#[derive(Serialize)]
+struct PosixRename {
+    oldpath: String,
+    newpath: String,
+}
+
+impl Session {
+    /// Renames `from` into `to`, replacing the file at `to` if there's one.
+    async fn rename_over(&self, from: &str, to: &str) -> anyhow::Result<()> {
+        let Some(raw) = &self.posix_rename else {
+            // Plain SFTP rename fails if the target exists. Without the extension, the previous
+            // version disappears shortly before the new one is in place.
+            if self.sftp.try_exists(to.to_string()).await? {
+                self.sftp
+                    .remove_file(to.to_string())
+                    .await
+                    .with_context(|| format!("Failed to remove the previous version of {to}"))?;
+            }
+            return self
+                .sftp
+                .rename(from.to_string(), to.to_string())
+                .await
+                .with_context(|| format!("Failed to rename {from} into {to}"));
+        };
+
+        let request = russh_sftp::ser::to_bytes(&PosixRename {
+            oldpath: from.to_string(),
+            newpath: to.to_string(),
+        })
+        .context("Failed to serialize the rename request")?;
+        let result = match raw.extended(POSIX_RENAME_EXTENSION, request.to_vec()).await {
+            Ok(Packet::Status(status)) if status.status_code == StatusCode::Ok => Ok(()),
+            Ok(Packet::Status(status)) => Err(SftpError::Status(status)),
+            Ok(_) => Err(SftpError::UnexpectedPacket),
+            Err(e) => Err(e),
+        };
+        result.with_context(|| format!("Failed to rename {from} into {to}"))
+    }
+}
+
+impl std::ops::Deref for Session {
+    type Target = SftpSession;
+
+    fn deref(&self) -> &SftpSession {
+        &self.sftp
+    }
+}
+
+struct SessionPool {
+    idle: Mutex<Vec<Session>>,
+    concurrency_limiter: Arc<Semaphore>,
+}
+
+/// A session taken from the pool, returned there on drop unless discarded.
+struct PooledSession {
+    session: Option<Session>,
+    pool: Arc<SessionPool>,
+    _permit: OwnedSemaphorePermit,
+}
+
+impl PooledSession {
+    /// Closes the session instead of reusing it, after an error that might have broken it.
+    fn discard(mut self) {
+        self.session = None;
+    }
+}
+
+impl std::ops::Deref for PooledSession {
+    type Target = Session;
+
+    fn deref(&self) -> &Session {
+        self.session.as_ref().expect("only taken on drop")
+    }
+}
+
+impl Drop for PooledSession {
+    fn drop(&mut self) {
+        if let Some(session) = self.session.take() {
+            self.pool.idle.lock().unwrap().push(session);
+        }
+    }
+}
+
+/// Keeps the session of the downloaded file out of the pool while the file is being read.
+struct SessionBoundRead {
+    inner: File,
+    _session: PooledSession,
+}
+
+impl AsyncRead for SessionBoundRead {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.inner).poll_read(cx, buf)
+    }
+}
+
+fn storage_metadata_path(file_path: &str) -> String {
+    format!("{file_path}.{SFTP_METADATA_FILE_SUFFIX}")
+}
+
+/// The metadata and unfinished upload files are not the objects of the storage.
+fn is_internal_file(path: &str) -> bool {
+    path.ends_with(&format!(".{SFTP_METADATA_FILE_SUFFIX}"))
+        || path.ends_with(&format!(".{SFTP_TEMP_FILE_SUFFIX}"))
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for Sftp {
+    async fn list_prefixes(
+        &self,
+        prefix: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        let directory = match prefix {
+            Some(prefix) => self.relative_path_to_sftp_path(prefix),
+            None => self.root_path.clone(),
+        };
+        let session = self.session().await.map_err(RemoteStorageError::from)?;
+        let (_, directories) = match self.read_dir(&session, &directory).await {
+            Ok(listing) => listing,
+            Err(e) => {
+                session.discard();
+                return Err(RemoteStorageError::Transient(e));
+            }
+        };
+        directories
+            .iter()
+            .map(|path| self.sftp_path_to_relative_path(path))
+            .collect::<anyhow::Result<_>>()
+            .map_err(RemoteStorageError::from)
+    }
+
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        let folder = match folder {
+            Some(folder) => self.relative_path_to_sftp_path(folder),
+            None => self.root_path.clone(),
+        };
+        let session = self.session().await?;
+        let mut files = Vec::new();
+        let mut directory_queue = vec![folder];
+        while let Some(directory) = directory_queue.pop() {
+            let (directory_files, subdirectories) = match self.read_dir(&session, &directory).await
+            {
+                Ok(listing) => listing,
+                Err(e) => {
+                    session.discard();
+                    return Err(e.into());
+                }
+            };
+            for path in directory_files {
+                files.push(self.sftp_path_to_relative_path(&path)?);
+            }
+            directory_queue.extend(subdirectories);
+        }
+        Ok(files)
+    }
+
+    async fn upload(
+        &self,
+        data: UploadStream,
+        data_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        let session = self.session().await?;
+        let file_path = self.relative_path_to_sftp_path(to);
+        let res = self
+            .upload_with_session(&session, data, data_size_bytes, &file_path, metadata)
+            .await;
+        if res.is_err() {
+            session.discard();
+        }
+        res.map_err(RemoteStorageError::from)
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
+        self.download_from(from, 0, None).await
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, RemoteStorageError> {
+        if let Some(end_exclusive) = end_exclusive {
+            if end_exclusive <= start_inclusive {
+                return Err(RemoteStorageError::Permanent(anyhow::anyhow!(
+                    "Invalid range, start ({start_inclusive}) is not less than end_exclusive ({end_exclusive})"
+                )));
+            }
+        }
+        self.download_from(from, start_inclusive, end_exclusive)
+            .await
+    }
+
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        let session = self.session().await.map_err(RemoteStorageError::from)?;
+        let file_path = self.relative_path_to_sftp_path(path);
+        let res = async {
+            if !session.try_exists(file_path.clone()).await? {
+                return Ok(None);
+            }
+            let attributes = session.metadata(file_path.clone()).await?;
+            let metadata = self.read_storage_metadata(&session, &file_path).await?;
+            anyhow::Ok(Some(ObjectMeta {
+                size: attributes.size.context("Server returned no file size")?,
+                last_modified: attributes
+                    .mtime
+                    .map(|mtime| UNIX_EPOCH + Duration::from_secs(mtime.into())),
+                metadata,
+            }))
+        }
+        .await
+        .with_context(|| format!("Failed to get the attributes of {file_path}"));
+
+        match res {
+            Ok(Some(object_meta)) => Ok(object_meta),
+            Ok(None) => Err(RemoteStorageError::NotFound),
+            Err(e) => {
+                session.discard();
+                Err(RemoteStorageError::Transient(e))
+            }
+        }
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+        let session = self.session().await?;
+        let res = self
+            .delete_with_session(&session, &self.relative_path_to_sftp_path(path))
+            .await;
+        if res.is_err() {
+            session.discard();
+        }
+        res.map_err(RemoteStorageError::from)
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
+        for path in paths {
+            self.delete(path).await?
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn internal_files_are_not_listed() {
+        assert!(is_internal_file("root/tenant/timeline/layer.metadata"));
+        assert!(is_internal_file("root/tenant/timeline/layer.___temp"));
+        assert!(!is_internal_file("root/tenant/timeline/index_part.json"));
+    }
+}
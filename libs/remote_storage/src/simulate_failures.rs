@@ -5,7 +5,10 @@ use std::collections::hash_map::Entry;
 use std::collections::HashMap;
 use std::sync::Mutex;
 
-use crate::{Download, DownloadError, RemotePath, RemoteStorage, StorageMetadata};
+use crate::{
+    Download, ObjectMeta, RemotePath, RemoteStorage, RemoteStorageError, StorageMetadata,
+    UploadStream,
+};
 
 pub struct UnreliableWrapper {
     inner: crate::GenericRemoteStorage,
@@ -23,8 +26,11 @@ enum RemoteOp {
     ListPrefixes(Option<RemotePath>),
     Upload(RemotePath),
     Download(RemotePath),
+    Stat(RemotePath),
     Delete(RemotePath),
     DeleteObjects(Vec<RemotePath>),
+    DeletePrefix(RemotePath),
+    Copy(RemotePath, RemotePath),
 }
 
 impl UnreliableWrapper {
@@ -43,7 +49,7 @@ impl UnreliableWrapper {
     /// On the first attempts of this operation, return an error. After 'attempts_to_fail'
     /// attempts, let the operation go ahead, and clear the counter.
     ///
-    fn attempt(&self, op: RemoteOp) -> Result<u64, DownloadError> {
+    fn attempt(&self, op: RemoteOp) -> Result<u64, RemoteStorageError> {
         let mut attempts = self.attempts.lock().unwrap();
 
         match attempts.entry(op) {
@@ -61,13 +67,13 @@ impl UnreliableWrapper {
                 } else {
                     let error =
                         anyhow::anyhow!("simulated failure of remote operation {:?}", e.key());
-                    Err(DownloadError::Other(error))
+                    Err(RemoteStorageError::Transient(error))
                 }
             }
             Entry::Vacant(e) => {
                 let error = anyhow::anyhow!("simulated failure of remote operation {:?}", e.key());
                 e.insert(1);
-                Err(DownloadError::Other(error))
+                Err(RemoteStorageError::Transient(error))
             }
         }
     }
@@ -78,30 +84,33 @@ impl RemoteStorage for UnreliableWrapper {
     async fn list_prefixes(
         &self,
         prefix: Option<&RemotePath>,
-    ) -> Result<Vec<RemotePath>, DownloadError> {
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
         self.attempt(RemoteOp::ListPrefixes(prefix.cloned()))?;
         self.inner.list_prefixes(prefix).await
     }
 
-    async fn list_files(&self, folder: Option<&RemotePath>) -> anyhow::Result<Vec<RemotePath>> {
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
         self.attempt(RemoteOp::ListPrefixes(folder.cloned()))?;
         self.inner.list_files(folder).await
     }
 
     async fn upload(
         &self,
-        data: impl tokio::io::AsyncRead + Unpin + Send + Sync + 'static,
+        data: UploadStream,
         // S3 PUT request requires the content length to be specified,
         // otherwise it starts to fail with the concurrent connection count increasing.
         data_size_bytes: usize,
         to: &RemotePath,
         metadata: Option<StorageMetadata>,
-    ) -> anyhow::Result<()> {
+    ) -> Result<(), RemoteStorageError> {
         self.attempt(RemoteOp::Upload(to.clone()))?;
         self.inner.upload(data, data_size_bytes, to, metadata).await
     }
 
-    async fn download(&self, from: &RemotePath) -> Result<Download, DownloadError> {
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
         self.attempt(RemoteOp::Download(from.clone()))?;
         self.inner.download(from).await
     }
@@ -111,7 +120,7 @@ impl RemoteStorage for UnreliableWrapper {
         from: &RemotePath,
         start_inclusive: u64,
         end_exclusive: Option<u64>,
-    ) -> Result<Download, DownloadError> {
+    ) -> Result<Download, RemoteStorageError> {
         // Note: We treat any download_byte_range as an "attempt" of the same
         // operation. We don't pay attention to the ranges. That's good enough
         // for now.
@@ -121,12 +130,25 @@ impl RemoteStorage for UnreliableWrapper {
             .await
     }
 
-    async fn delete(&self, path: &RemotePath) -> anyhow::Result<()> {
+    fn efficient_byte_ranges(&self) -> bool {
+        self.inner.efficient_byte_ranges()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        self.attempt(RemoteOp::Stat(path.clone()))?;
+        self.inner.stat(path).await
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
         self.attempt(RemoteOp::Delete(path.clone()))?;
         self.inner.delete(path).await
     }
 
-    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> anyhow::Result<()> {
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
         self.attempt(RemoteOp::DeleteObjects(paths.to_vec()))?;
         let mut error_counter = 0;
         for path in paths {
@@ -135,11 +157,21 @@ impl RemoteStorage for UnreliableWrapper {
             }
         }
         if error_counter > 0 {
-            return Err(anyhow::anyhow!(
+            return Err(RemoteStorageError::Transient(anyhow::anyhow!(
                 "failed to delete {} objects",
                 error_counter
-            ));
+            )));
         }
         Ok(())
     }
+
+    async fn delete_prefix(&self, prefix: &RemotePath) -> Result<usize, RemoteStorageError> {
+        self.attempt(RemoteOp::DeletePrefix(prefix.clone()))?;
+        self.inner.delete_prefix(prefix).await
+    }
+
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        self.attempt(RemoteOp::Copy(from.clone(), to.clone()))?;
+        self.inner.copy(from, to).await
+    }
 }
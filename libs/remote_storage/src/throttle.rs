@@ -0,0 +1,244 @@
+//! This module provides a wrapper around a real RemoteStorage implementation that
+//! limits the total bandwidth of all its uploads and downloads, so that the bulk
+//! transfers (e.g. downloading many timelines on startup) don't starve the rest of
+//! the network traffic.
+//!
+//! The limit is a token bucket shared by all the data streams of the wrapper: every
+//! read from an upload or a download stream takes its size out of the bucket, and the
+//! stream is paused once the bucket is in debt, until it's refilled again.
+use std::future::Future;
+use std::num::NonZeroU64;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{ready, Poll};
+use std::time::Duration;
+
+use tokio::io::{self, AsyncRead};
+use tokio::time::Instant;
+
+use crate::{
+    Download, ObjectMeta, RemotePath, RemoteStorage, RemoteStorageError, StorageMetadata,
+    UploadStream,
+};
+
+pub struct ThrottledWrapper {
+    inner: crate::GenericRemoteStorage,
+    bucket: Arc<TokenBucket>,
+}
+
+impl ThrottledWrapper {
+    pub fn new(inner: crate::GenericRemoteStorage, max_bytes_per_sec: NonZeroU64) -> Self {
+        ThrottledWrapper {
+            inner,
+            bucket: Arc::new(TokenBucket::new(max_bytes_per_sec)),
+        }
+    }
+
+    fn throttle<R: AsyncRead>(&self, inner: R) -> ThrottledReader<R> {
+        ThrottledReader {
+            bucket: Arc::clone(&self.bucket),
+            delay: None,
+            inner,
+        }
+    }
+
+    fn throttle_download(&self, download: Download) -> Download {
+        Download {
+            download_stream: Box::pin(self.throttle(download.download_stream)),
+            metadata: download.metadata,
+        }
+    }
+}
+
+struct TokenBucket {
+    bytes_per_sec: f64,
+    state: Mutex<TokenBucketState>,
+}
+
+struct TokenBucketState {
+    /// Bytes that can be transferred without waiting, negative when in debt.
+    available: f64,
+    refilled_at: Instant,
+}
+
+impl TokenBucket {
+    fn new(bytes_per_sec: NonZeroU64) -> Self {
+        let bytes_per_sec = bytes_per_sec.get() as f64;
+        TokenBucket {
+            bytes_per_sec,
+            state: Mutex::new(TokenBucketState {
+                available: bytes_per_sec,
+                refilled_at: Instant::now(),
+            }),
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, returning how long to wait before transferring more.
+    /// The bucket holds at most a second worth of the bandwidth, to limit the bursts after idle periods.
+    fn take(&self, bytes: usize) -> Duration {
+        let mut state = self.state.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(state.refilled_at).as_secs_f64() * self.bytes_per_sec;
+        state.available = (state.available + refill).min(self.bytes_per_sec);
+        state.refilled_at = now;
+
+        state.available -= bytes as f64;
+        if state.available >= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(-state.available / self.bytes_per_sec)
+        }
+    }
+}
+
+pin_project_lite::pin_project! {
+    /// An `AsyncRead` adapter, pausing the reads when the shared [`TokenBucket`] runs out of bytes.
+    struct ThrottledReader<R> {
+        bucket: Arc<TokenBucket>,
+        delay: Option<Pin<Box<tokio::time::Sleep>>>,
+        #[pin]
+        inner: R,
+    }
+}
+
+impl<R: AsyncRead> AsyncRead for ThrottledReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+        buf: &mut io::ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.project();
+        if let Some(delay) = this.delay.as_mut() {
+            ready!(delay.as_mut().poll(cx));
+            *this.delay = None;
+        }
+
+        let before = buf.filled().len();
+        ready!(this.inner.poll_read(cx, buf))?;
+
+        let wait = this.bucket.take(buf.filled().len() - before);
+        if !wait.is_zero() {
+            *this.delay = Some(Box::pin(tokio::time::sleep(wait)));
+        }
+        Poll::Ready(Ok(()))
+    }
+}
+
+#[async_trait::async_trait]
+impl RemoteStorage for ThrottledWrapper {
+    async fn list_prefixes(
+        &self,
+        prefix: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        self.inner.list_prefixes(prefix).await
+    }
+
+    async fn list_files(
+        &self,
+        folder: Option<&RemotePath>,
+    ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+        self.inner.list_files(folder).await
+    }
+
+    async fn upload(
+        &self,
+        data: UploadStream,
+        data_size_bytes: usize,
+        to: &RemotePath,
+        metadata: Option<StorageMetadata>,
+    ) -> Result<(), RemoteStorageError> {
+        self.inner
+            .upload(self.throttle(data), data_size_bytes, to, metadata)
+            .await
+    }
+
+    async fn download(&self, from: &RemotePath) -> Result<Download, RemoteStorageError> {
+        let download = self.inner.download(from).await?;
+        Ok(self.throttle_download(download))
+    }
+
+    async fn download_byte_range(
+        &self,
+        from: &RemotePath,
+        start_inclusive: u64,
+        end_exclusive: Option<u64>,
+    ) -> Result<Download, RemoteStorageError> {
+        let download = self
+            .inner
+            .download_byte_range(from, start_inclusive, end_exclusive)
+            .await?;
+        Ok(self.throttle_download(download))
+    }
+
+    fn efficient_byte_ranges(&self) -> bool {
+        self.inner.efficient_byte_ranges()
+    }
+
+    fn max_object_size(&self) -> Option<u64> {
+        self.inner.max_object_size()
+    }
+
+    /// Not throttled, no contents are transferred.
+    async fn stat(&self, path: &RemotePath) -> Result<ObjectMeta, RemoteStorageError> {
+        self.inner.stat(path).await
+    }
+
+    async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+        self.inner.delete(path).await
+    }
+
+    async fn delete_objects<'a>(&self, paths: &'a [RemotePath]) -> Result<(), RemoteStorageError> {
+        self.inner.delete_objects(paths).await
+    }
+
+    async fn delete_prefix(&self, prefix: &RemotePath) -> Result<usize, RemoteStorageError> {
+        self.inner.delete_prefix(prefix).await
+    }
+
+    /// Not throttled: S3 and the local fs copy the data without sending it through the pageserver.
+    async fn copy(&self, from: &RemotePath, to: &RemotePath) -> Result<(), RemoteStorageError> {
+        self.inner.copy(from, to).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_bucket_allows_a_second_worth_of_burst() {
+        let bucket = TokenBucket::new(NonZeroU64::new(1000).unwrap());
+        assert_eq!(bucket.take(1000), Duration::ZERO);
+
+        let wait = bucket.take(500);
+        assert!(
+            wait > Duration::from_millis(400) && wait <= Duration::from_millis(500),
+            "unexpected wait {wait:?} for 500 bytes over the limit"
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn throttled_reader_respects_the_limit() {
+        let bucket = Arc::new(TokenBucket::new(NonZeroU64::new(1024).unwrap()));
+        let data = vec![0u8; 4 * 1024];
+        let mut reader = ThrottledReader {
+            bucket,
+            delay: None,
+            inner: data.as_slice(),
+        };
+
+        let started_at = Instant::now();
+        let mut read = Vec::new();
+        tokio::io::AsyncReadExt::read_to_end(&mut reader, &mut read)
+            .await
+            .unwrap();
+
+        assert_eq!(read, data);
+        // the first second worth of data is in the bucket already, the rest goes at the limit
+        let elapsed = started_at.elapsed();
+        assert!(
+            elapsed >= Duration::from_secs(3) && elapsed < Duration::from_millis(3100),
+            "4 KiB took {elapsed:?} at 1 KiB/s"
+        );
+    }
+}
@@ -9,7 +9,8 @@ use std::time::UNIX_EPOCH;
 use anyhow::Context;
 use once_cell::sync::OnceCell;
 use remote_storage::{
-    GenericRemoteStorage, RemotePath, RemoteStorageConfig, RemoteStorageKind, S3Config,
+    GenericRemoteStorage, RemotePath, RemoteStorageConfig, RemoteStorageError, RemoteStorageKind,
+    S3Config, StorageMetadata,
 };
 use test_context::{test_context, AsyncTestContext};
 use tokio::task::JoinSet;
@@ -205,6 +206,71 @@ async fn s3_delete_objects_works(ctx: &mut MaybeEnabledS3) -> anyhow::Result<()>
     Ok(())
 }
 
+#[test_context(MaybeEnabledS3)]
+#[tokio::test]
+async fn s3_delete_prefix_works(ctx: &mut MaybeEnabledS3) -> anyhow::Result<()> {
+    let ctx = match ctx {
+        MaybeEnabledS3::Enabled(ctx) => ctx,
+        MaybeEnabledS3::Disabled => return Ok(()),
+    };
+
+    let path = |name: &str| {
+        RemotePath::new(&PathBuf::from(format!("{}/{name}", ctx.base_prefix)))
+            .with_context(|| "RemotePath conversion")
+    };
+    let data = "remote blob data".as_bytes();
+    for name in ["tenant/a/1", "tenant/a/2", "tenant/ab/3"] {
+        ctx.client
+            .upload(std::io::Cursor::new(data), data.len(), &path(name)?, None)
+            .await?;
+    }
+
+    assert_eq!(ctx.client.delete_prefix(&path("tenant/a")?).await?, 2);
+    assert_eq!(
+        ctx.client.list_files(Some(&path("tenant")?)).await?,
+        vec![path("tenant/ab/3")?]
+    );
+    assert_eq!(ctx.client.delete_prefix(&path("tenant")?).await?, 1);
+
+    Ok(())
+}
+
+#[test_context(MaybeEnabledS3)]
+#[tokio::test]
+async fn s3_stat_works(ctx: &mut MaybeEnabledS3) -> anyhow::Result<()> {
+    let ctx = match ctx {
+        MaybeEnabledS3::Enabled(ctx) => ctx,
+        MaybeEnabledS3::Disabled => return Ok(()),
+    };
+
+    let path = RemotePath::new(&PathBuf::from(format!("{}/stat", ctx.base_prefix)))
+        .with_context(|| "RemotePath conversion")?;
+    assert!(matches!(
+        ctx.client.stat(&path).await,
+        Err(RemoteStorageError::NotFound)
+    ));
+
+    let data = "remote blob data".as_bytes();
+    let metadata = StorageMetadata::from([("key", "value")]);
+    ctx.client
+        .upload(
+            std::io::Cursor::new(data),
+            data.len(),
+            &path,
+            Some(metadata.clone()),
+        )
+        .await?;
+
+    let stat = ctx.client.stat(&path).await?;
+    assert_eq!(stat.size, data.len() as u64);
+    assert!(stat.last_modified.is_some());
+    assert_eq!(stat.metadata, Some(metadata));
+
+    ctx.client.delete(&path).await?;
+
+    Ok(())
+}
+
 fn ensure_logging_ready() {
     LOGGING_DONE.get_or_init(|| {
         utils::logging::init(
@@ -388,14 +454,41 @@ fn create_s3_client(
         .as_nanos();
     let remote_storage_config = RemoteStorageConfig {
         max_concurrent_syncs: NonZeroUsize::new(100).unwrap(),
+        max_concurrent_sync_per_tenant: None,
+        max_concurrent_sync_startup: None,
         max_sync_errors: NonZeroU32::new(5).unwrap(),
+        max_retries: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_RETRIES,
+        base_backoff_ms: remote_storage::DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS,
+        compression: remote_storage::Compression::None,
+        max_bytes_per_sec: None,
+        dry_run: false,
+        encryption_key_file: None,
+        operation_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+        list_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+        upload_start_jitter: remote_storage::DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+        prefix: None,
         storage: RemoteStorageKind::AwsS3(S3Config {
             bucket_name: remote_storage_s3_bucket,
             bucket_region: remote_storage_s3_region,
             prefix_in_bucket: Some(format!("pagination_should_work_test_{random_prefix_part}/")),
             endpoint: None,
+            force_path_style: None,
             concurrency_limit: NonZeroUsize::new(100).unwrap(),
             max_keys_per_list_response,
+            multipart_part_size: NonZeroUsize::new(
+                remote_storage::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_PART_SIZE,
+            )
+            .unwrap(),
+            multipart_upload_concurrency: NonZeroUsize::new(
+                remote_storage::DEFAULT_REMOTE_STORAGE_S3_MULTIPART_UPLOAD_CONCURRENCY,
+            )
+            .unwrap(),
+            multipart_resume_dir: None,
+            multipart_upload_max_age: None,
+            storage_class: None,
+            content_type: None,
+            cache_control: None,
+            max_object_size: None,
         }),
     };
     Ok(Arc::new(
@@ -0,0 +1,232 @@
+use std::env;
+use std::num::{NonZeroU32, NonZeroUsize};
+use std::path::PathBuf;
+use std::sync::Arc;
+use std::time::UNIX_EPOCH;
+
+use anyhow::Context;
+use once_cell::sync::OnceCell;
+use remote_storage::{
+    GenericRemoteStorage, RemotePath, RemoteStorageConfig, RemoteStorageError, RemoteStorageKind,
+    SftpConfig, StorageMetadata,
+};
+use test_context::{test_context, AsyncTestContext};
+use tokio::io::AsyncReadExt;
+use tracing::info;
+
+static LOGGING_DONE: OnceCell<()> = OnceCell::new();
+
+const ENABLE_REAL_SFTP_REMOTE_STORAGE_ENV_VAR_NAME: &str = "ENABLE_REAL_SFTP_REMOTE_STORAGE";
+
+/// Uploads a blob to a real SFTP server, downloads it back whole and by a byte range, then
+/// overwrites it with a new version without metadata.
+/// Requires [`ENABLE_REAL_SFTP_REMOTE_STORAGE_ENV_VAR_NAME`] and the server env vars specified,
+/// see [`create_sftp_client`] for details. Test will skip real code and pass if env vars not set.
+#[test_context(MaybeEnabledSftp)]
+#[tokio::test]
+async fn sftp_upload_download_works(ctx: &mut MaybeEnabledSftp) -> anyhow::Result<()> {
+    let client = match ctx {
+        MaybeEnabledSftp::Enabled(client) => client,
+        MaybeEnabledSftp::Disabled => return Ok(()),
+    };
+
+    let path = RemotePath::new(&PathBuf::from("upload_download/blob"))
+        .with_context(|| "RemotePath conversion")?;
+    assert!(matches!(
+        client.download(&path).await,
+        Err(RemoteStorageError::NotFound)
+    ));
+
+    let data = "remote blob data".as_bytes();
+    let metadata = StorageMetadata::from([("key", "value")]);
+    client
+        .upload(
+            std::io::Cursor::new(data),
+            data.len(),
+            &path,
+            Some(metadata.clone()),
+        )
+        .await?;
+
+    let mut download = client.download(&path).await?;
+    let mut downloaded = Vec::new();
+    download
+        .download_stream
+        .read_to_end(&mut downloaded)
+        .await?;
+    assert_eq!(downloaded, data);
+    assert_eq!(download.metadata, Some(metadata));
+
+    let mut download = client.download_byte_range(&path, 7, Some(11)).await?;
+    let mut downloaded = Vec::new();
+    download
+        .download_stream
+        .read_to_end(&mut downloaded)
+        .await?;
+    assert_eq!(downloaded, &data[7..11]);
+
+    // The new version replaces the previous one, along with its metadata.
+    let new_data = "new remote blob data".as_bytes();
+    client
+        .upload(std::io::Cursor::new(new_data), new_data.len(), &path, None)
+        .await?;
+    let mut download = client.download(&path).await?;
+    let mut downloaded = Vec::new();
+    download
+        .download_stream
+        .read_to_end(&mut downloaded)
+        .await?;
+    assert_eq!(downloaded, new_data);
+    assert_eq!(download.metadata, None);
+
+    // Only the blob is listed, not its temporary or metadata files.
+    assert_eq!(client.list_files(None).await?, vec![path.clone()]);
+
+    client.delete(&path).await?;
+    assert!(matches!(
+        client.download(&path).await,
+        Err(RemoteStorageError::NotFound)
+    ));
+
+    Ok(())
+}
+
+/// A failed upload leaves the previous version of the blob in place.
+#[test_context(MaybeEnabledSftp)]
+#[tokio::test]
+async fn sftp_failed_upload_keeps_previous_version(
+    ctx: &mut MaybeEnabledSftp,
+) -> anyhow::Result<()> {
+    let client = match ctx {
+        MaybeEnabledSftp::Enabled(client) => client,
+        MaybeEnabledSftp::Disabled => return Ok(()),
+    };
+
+    let path = RemotePath::new(&PathBuf::from("failed_upload/blob"))
+        .with_context(|| "RemotePath conversion")?;
+    let data = "remote blob data".as_bytes();
+    let metadata = StorageMetadata::from([("key", "value")]);
+    client
+        .upload(
+            std::io::Cursor::new(data),
+            data.len(),
+            &path,
+            Some(metadata.clone()),
+        )
+        .await?;
+
+    let short_data = "short".as_bytes();
+    client
+        .upload(
+            std::io::Cursor::new(short_data),
+            short_data.len() + 1,
+            &path,
+            None,
+        )
+        .await
+        .expect_err("upload of a stream shorter than its size should fail");
+
+    let mut download = client.download(&path).await?;
+    let mut downloaded = Vec::new();
+    download
+        .download_stream
+        .read_to_end(&mut downloaded)
+        .await?;
+    assert_eq!(downloaded, data);
+    assert_eq!(download.metadata, Some(metadata));
+
+    client.delete(&path).await?;
+
+    Ok(())
+}
+
+fn ensure_logging_ready() {
+    LOGGING_DONE.get_or_init(|| {
+        utils::logging::init(
+            utils::logging::LogFormat::Test,
+            utils::logging::TracingErrorLayerEnablement::Disabled,
+        )
+        .expect("logging init failed");
+    });
+}
+
+enum MaybeEnabledSftp {
+    Enabled(Arc<GenericRemoteStorage>),
+    Disabled,
+}
+
+#[async_trait::async_trait]
+impl AsyncTestContext for MaybeEnabledSftp {
+    async fn setup() -> Self {
+        ensure_logging_ready();
+
+        if env::var(ENABLE_REAL_SFTP_REMOTE_STORAGE_ENV_VAR_NAME).is_err() {
+            info!(
+                "`{}` env variable is not set, skipping the test",
+                ENABLE_REAL_SFTP_REMOTE_STORAGE_ENV_VAR_NAME
+            );
+            return Self::Disabled;
+        }
+
+        Self::Enabled(
+            create_sftp_client()
+                .context("SFTP client creation")
+                .expect("SFTP client creation failed"),
+        )
+    }
+}
+
+fn create_sftp_client() -> anyhow::Result<Arc<GenericRemoteStorage>> {
+    let host = env::var("REMOTE_STORAGE_SFTP_HOST").context(
+        "`REMOTE_STORAGE_SFTP_HOST` env var is not set, but real SFTP tests are enabled",
+    )?;
+    let port = match env::var("REMOTE_STORAGE_SFTP_PORT") {
+        Ok(port) => port
+            .parse()
+            .context("`REMOTE_STORAGE_SFTP_PORT` env var is not a port number")?,
+        Err(_) => 22,
+    };
+    let username = env::var("REMOTE_STORAGE_SFTP_USERNAME").context(
+        "`REMOTE_STORAGE_SFTP_USERNAME` env var is not set, but real SFTP tests are enabled",
+    )?;
+    let key_path = env::var("REMOTE_STORAGE_SFTP_KEY_PATH").context(
+        "`REMOTE_STORAGE_SFTP_KEY_PATH` env var is not set, but real SFTP tests are enabled",
+    )?;
+    let random_prefix_part = std::time::SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .context("random sftp test directory calculation")?
+        .as_nanos();
+    let remote_storage_config = RemoteStorageConfig {
+        max_concurrent_syncs: NonZeroUsize::new(100).unwrap(),
+        max_concurrent_sync_per_tenant: None,
+        max_concurrent_sync_startup: None,
+        max_sync_errors: NonZeroU32::new(5).unwrap(),
+        max_retries: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_RETRIES,
+        base_backoff_ms: remote_storage::DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS,
+        compression: remote_storage::Compression::None,
+        max_bytes_per_sec: None,
+        dry_run: false,
+        encryption_key_file: None,
+        operation_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+        list_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+        upload_start_jitter: remote_storage::DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+        prefix: None,
+        storage: RemoteStorageKind::Sftp(SftpConfig {
+            host,
+            port,
+            username,
+            key_path: PathBuf::from(key_path),
+            known_hosts_path: env::var("REMOTE_STORAGE_SFTP_KNOWN_HOSTS_PATH")
+                .ok()
+                .map(PathBuf::from),
+            root_path: PathBuf::from(format!("sftp_test_{random_prefix_part}")),
+            concurrency_limit: NonZeroUsize::new(
+                remote_storage::DEFAULT_REMOTE_STORAGE_SFTP_CONCURRENCY_LIMIT,
+            )
+            .unwrap(),
+        }),
+    };
+    Ok(Arc::new(
+        GenericRemoteStorage::from_config(&remote_storage_config).context("remote storage init")?,
+    ))
+}
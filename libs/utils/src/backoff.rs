@@ -1,6 +1,8 @@
 use std::fmt::{Debug, Display};
+use std::time::Duration;
 
 use futures::Future;
+use rand::Rng;
 
 pub const DEFAULT_BASE_BACKOFF_SECONDS: f64 = 0.1;
 pub const DEFAULT_MAX_BACKOFF_SECONDS: f64 = 3.0;
@@ -24,6 +26,22 @@ pub fn exponential_backoff_duration_seconds(n: u32, base_increment: f64, max_sec
     }
 }
 
+/// Exponential backoff with "full jitter": a random duration between zero and `base * 2^n`,
+/// capped at `max`.
+///
+/// Unlike [`exponential_backoff_duration_seconds`], spreads the retries of many clients that
+/// failed at the same time (e.g. all throttled by the same remote storage) instead of having
+/// them all retry at once again.
+pub fn jittered_backoff_duration(n: u32, base: Duration, max: Duration) -> Duration {
+    let upper_bound = base
+        .checked_mul(2u32.saturating_pow(n))
+        .map_or(max, |backoff| backoff.min(max));
+    if upper_bound.is_zero() {
+        return Duration::ZERO;
+    }
+    rand::thread_rng().gen_range(Duration::ZERO..=upper_bound)
+}
+
 /// retries passed operation until one of the following conditions are met:
 /// Encountered error is considered as permanent (non-retryable)
 /// Retries have been exhausted.
@@ -31,7 +49,7 @@ pub fn exponential_backoff_duration_seconds(n: u32, base_increment: f64, max_sec
 /// When attempts cross `warn_threshold` function starts to emit log warnings.
 /// `description` argument is added to log messages. Its value should identify the `op` is doing
 pub async fn retry<T, O, F, E>(
-    mut op: O,
+    op: O,
     is_permanent: impl Fn(&E) -> bool,
     warn_threshold: u32,
     max_retries: u32,
@@ -43,6 +61,62 @@ where
     E: Display + Debug,
     O: FnMut() -> F,
     F: Future<Output = Result<T, E>>,
+{
+    retry_impl(
+        op,
+        is_permanent,
+        warn_threshold,
+        max_retries,
+        description,
+        |attempts| {
+            Duration::from_secs_f64(exponential_backoff_duration_seconds(
+                attempts,
+                DEFAULT_BASE_BACKOFF_SECONDS,
+                DEFAULT_MAX_BACKOFF_SECONDS,
+            ))
+        },
+    )
+    .await
+}
+
+/// Same as [`retry`], but sleeps for a [`jittered_backoff_duration`] between the attempts.
+pub async fn retry_with_jitter<T, O, F, E>(
+    op: O,
+    is_permanent: impl Fn(&E) -> bool,
+    warn_threshold: u32,
+    max_retries: u32,
+    base_backoff: Duration,
+    max_backoff: Duration,
+    description: &str,
+) -> Result<T, E>
+where
+    E: Display + Debug,
+    O: FnMut() -> F,
+    F: Future<Output = Result<T, E>>,
+{
+    retry_impl(
+        op,
+        is_permanent,
+        warn_threshold,
+        max_retries,
+        description,
+        |attempts| jittered_backoff_duration(attempts, base_backoff, max_backoff),
+    )
+    .await
+}
+
+async fn retry_impl<T, O, F, E>(
+    mut op: O,
+    is_permanent: impl Fn(&E) -> bool,
+    warn_threshold: u32,
+    max_retries: u32,
+    description: &str,
+    backoff: impl Fn(u32) -> Duration,
+) -> Result<T, E>
+where
+    E: Display + Debug,
+    O: FnMut() -> F,
+    F: Future<Output = Result<T, E>>,
 {
     let mut attempts = 0;
     loop {
@@ -76,12 +150,14 @@ where
             }
         }
         // sleep and retry
-        exponential_backoff(
-            attempts,
-            DEFAULT_BASE_BACKOFF_SECONDS,
-            DEFAULT_MAX_BACKOFF_SECONDS,
-        )
-        .await;
+        let backoff_duration = backoff(attempts);
+        if !backoff_duration.is_zero() {
+            tracing::info!(
+                "Backoff: waiting {} seconds before processing with the task",
+                backoff_duration.as_secs_f64()
+            );
+            tokio::time::sleep(backoff_duration).await;
+        }
         attempts += 1;
     }
 }
@@ -120,6 +196,50 @@ mod tests {
         );
     }
 
+    #[test]
+    fn jittered_backoff_stays_within_bounds() {
+        let base = Duration::from_millis(100);
+        let max = Duration::from_secs(3);
+
+        for n in 0..100 {
+            let upper_bound = if n < 5 { base * 2u32.pow(n) } else { max };
+            for _ in 0..10 {
+                let backoff = jittered_backoff_duration(n, base, max);
+                assert!(
+                    backoff <= upper_bound,
+                    "{n}th backoff value {backoff:?} is larger than {upper_bound:?}"
+                );
+            }
+        }
+
+        assert_eq!(
+            jittered_backoff_duration(3, Duration::ZERO, max),
+            Duration::ZERO
+        );
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn retry_with_jitter_stops_after_max_retries() {
+        let count = Mutex::new(0);
+        let err_result = retry_with_jitter(
+            || async {
+                *count.lock().await += 1;
+                Result::<(), io::Error>::Err(io::Error::from(io::ErrorKind::Other))
+            },
+            |_e| false,
+            1,
+            3,
+            Duration::from_millis(100),
+            Duration::from_secs(1),
+            "work",
+        )
+        .await;
+
+        assert!(err_result.is_err());
+
+        assert_eq!(*count.lock().await, 4);
+    }
+
     #[tokio::test(start_paused = true)]
     async fn retry_always_error() {
         let count = Mutex::new(0);
@@ -8,6 +8,7 @@ mod draw_timeline_dir;
 mod layer_map_analyzer;
 mod layers;
 
+use anyhow::Context;
 use clap::{Parser, Subcommand};
 use layers::LayerCmd;
 use pageserver::{
@@ -17,7 +18,6 @@ use pageserver::{
     tenant::{dump_layerfile_from_path, metadata::TimelineMetadata},
     virtual_file,
 };
-use postgres_ffi::ControlFileData;
 use std::path::{Path, PathBuf};
 use utils::{lsn::Lsn, project_git_version};
 
@@ -103,8 +103,18 @@ async fn main() -> anyhow::Result<()> {
 }
 
 fn read_pg_control_file(control_file_path: &Path) -> anyhow::Result<()> {
-    let control_file = ControlFileData::decode(&std::fs::read(control_file_path)?)?;
-    println!("{control_file:?}");
+    let bytes = std::fs::read(control_file_path)?;
+    // The file doesn't say which version it is of, other than through the version fields.
+    let (control_file, pg_version) = [14, 15]
+        .into_iter()
+        .find_map(|pg_version| {
+            let control_file = postgres_ffi::decode_pg_control(&bytes, pg_version).ok()?;
+            Some((control_file, pg_version))
+        })
+        .with_context(|| {
+            format!("not a control file of a supported PostgreSQL version: {control_file_path:?}")
+        })?;
+    println!("PostgreSQL {pg_version}: {control_file:?}");
     let control_file_initdb = Lsn(control_file.checkPoint);
     println!(
         "pg_initdb_lsn: {}, aligned: {}",
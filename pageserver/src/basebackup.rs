@@ -10,47 +10,220 @@
 //! This module is responsible for creation of such tarball
 //! from data stored in object storage.
 //!
+//! The tarball can be compressed on the fly, see [`BasebackupCompression`]. The compute
+//! asks for it with a flag of the `basebackup` command and recognizes the format that
+//! it receives by its magic number, so it can also restore an uncompressed tarball of
+//! a pageserver that doesn't know the flag.
+//!
+//! An incremental basebackup is the difference of a full one from an older full one, for
+//! a data directory that is already at the older LSN. Only the changed blocks of the
+//! relation files are sent, in a `<segment file>.delta` entry per changed segment, along
+//! with [`INCREMENTAL_MANIFEST_PATH`], an [`IncrementalManifest`] in JSON. To apply it,
+//! the receiver deletes the relation segment files that are not in the manifest, resizes
+//! the rest to their size in it, and writes the pages of each delta entry at the block
+//! numbers the manifest lists for them. All the other files are sent in full as usual and
+//! replace the older ones.
+//!
+//! With `basebackup_spill_threshold` set, the whole tarball is produced before any of it is
+//! sent, in memory while it's small and in a temporary file in the timeline directory once
+//! it's larger than the threshold, see [`SpillBuffer`]. That bounds the memory of many
+//! concurrent basebackups, and the timeline isn't held by a slow client.
+//!
+//! A [`DatabaseFilter`] leaves some of the user databases out, e.g. to restore only a few
+//! databases of a large cluster. The shared catalogs in `global/` and the system databases
+//! are always included, so the catalogs stay consistent: the databases left out are still
+//! listed in `pg_database`, and connecting to them fails for the lack of their directory.
+//!
 use anyhow::{anyhow, bail, ensure, Context};
+use async_compression::tokio::write::{GzipEncoder, ZstdEncoder};
 use bytes::{BufMut, BytesMut};
 use fail::fail_point;
+use futures::stream::{self, StreamExt};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
 use std::fmt::Write as FmtWrite;
-use std::time::SystemTime;
+use std::future::Future;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::task::{ready, Context as TaskContext, Poll};
 use tokio::io;
-use tokio::io::AsyncWrite;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
 use tracing::*;
 
 use tokio_tar::{Builder, EntryType, Header};
 
+use crate::config::PageServerConf;
 use crate::context::RequestContext;
-use crate::pgdatadir_mapping::Version;
+use crate::pgdatadir_mapping::{BlockNumber, RelChanges, Version};
 use crate::tenant::Timeline;
+use crate::TEMP_FILE_SUFFIX;
 use pageserver_api::reltag::{RelTag, SlruKind};
 
-use postgres_ffi::pg_constants::{DEFAULTTABLESPACE_OID, GLOBALTABLESPACE_OID};
+use postgres_ffi::pg_constants::{
+    DEFAULTTABLESPACE_OID, FIRST_NORMAL_OBJECT_ID, GLOBALTABLESPACE_OID,
+};
 use postgres_ffi::pg_constants::{PGDATA_SPECIAL_FILES, PGDATA_SUBDIRS, PG_HBA};
+use postgres_ffi::pg_constants::{TWOPHASE_FILE_HEADER_PREFIX_SIZE, TWOPHASE_MAGIC};
 use postgres_ffi::relfile_utils::{INIT_FORKNUM, MAIN_FORKNUM};
-use postgres_ffi::TransactionId;
 use postgres_ffi::XLogFileName;
 use postgres_ffi::PG_TLI;
-use postgres_ffi::{BLCKSZ, RELSEG_SIZE, WAL_SEGMENT_SIZE};
+use postgres_ffi::{twophase_file_name, CheckPoint, TimeLineID, TransactionId};
+use postgres_ffi::{BLCKSZ, RELSEG_SIZE};
+use utils::id::TimelineId;
 use utils::lsn::Lsn;
 
+/// Modification time of all the files in the tarball, the Postgres epoch (2000-01-01).
+/// Postgres does not look at it, and with a fixed one, two basebackups of the same LSN
+/// are byte for byte equal, as long as the entries are added in the same order too.
+const BASEBACKUP_MTIME: u64 = 946_684_800;
+
+/// Number of pages of a file that are reconstructed at once. The pages are collected in
+/// order, and the file is appended to the tarball once all of its pages are there.
+const PAGE_FETCH_CONCURRENCY: usize = 16;
+
+/// Compression of the tarball, applied as it's streamed, so the whole tarball is never
+/// in memory. The compressed formats are the usual `.tar.gz` and `.tar.zst` ones.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum BasebackupCompression {
+    /// A plain tarball.
+    #[default]
+    None,
+    /// Gzip, magic number `1f 8b`.
+    Gzip,
+    /// Zstandard, magic number `28 b5 2f fd`. Smaller and faster to decompress than gzip.
+    Zstd,
+}
+
+/// What a basebackup sent, e.g. for logging or the backup catalog of the control plane.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BasebackupStats {
+    pub timeline_id: TimelineId,
+    /// The LSN the basebackup is taken at: the end of the timeline if no LSN was requested.
+    pub lsn: Lsn,
+    /// The LSN of the record before `lsn` given to the compute, 0 if it's not known.
+    pub prev_lsn: Lsn,
+    /// Regular files in the tarball, directories are not counted.
+    pub files_written: usize,
+    /// Total size of those files, without the tar headers and before compression.
+    pub bytes_written: u64,
+    pub slru_segments: usize,
+    pub twophase_files: usize,
+    /// Files left out of a best effort basebackup because they failed to be fetched.
+    pub skipped_files: usize,
+    /// User databases left out by the [`DatabaseFilter`].
+    pub skipped_databases: usize,
+}
+
+impl BasebackupStats {
+    fn new(timeline_id: TimelineId, lsn: Lsn, prev_lsn: Lsn) -> Self {
+        Self {
+            timeline_id,
+            lsn,
+            prev_lsn,
+            files_written: 0,
+            bytes_written: 0,
+            slru_segments: 0,
+            twophase_files: 0,
+            skipped_files: 0,
+            skipped_databases: 0,
+        }
+    }
+}
+
+/// The user databases to include in a basebackup, by the OIDs of their `base/<dbnode>/`
+/// directories.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum DatabaseFilter {
+    #[default]
+    All,
+    /// Only these user databases.
+    Include(HashSet<u32>),
+    /// All the user databases but these.
+    Exclude(HashSet<u32>),
+}
+
+impl DatabaseFilter {
+    /// Whether the files of the database go into the basebackup. The databases created by
+    /// initdb (template1, template0 and postgres), with OIDs below `FirstNormalObjectId`, are
+    /// always included: the compute doesn't start without them.
+    pub fn includes(&self, dbnode: u32) -> bool {
+        if dbnode < FIRST_NORMAL_OBJECT_ID {
+            return true;
+        }
+        match self {
+            DatabaseFilter::All => true,
+            DatabaseFilter::Include(dbnodes) => dbnodes.contains(&dbnode),
+            DatabaseFilter::Exclude(dbnodes) => !dbnodes.contains(&dbnode),
+        }
+    }
+}
+
+/// The error of a basebackup cancelled through the token given to [`send_basebackup_tarball`].
+#[derive(Debug, thiserror::Error)]
+#[error("basebackup was cancelled")]
+pub struct BasebackupCancelled;
+
+/// Name of the manifest of an incremental basebackup in the tarball.
+pub const INCREMENTAL_MANIFEST_PATH: &str = "incremental_backup.json";
+
+/// The list of the relation files of an incremental basebackup.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IncrementalManifest {
+    /// The LSN the data directory the basebackup applies to must be at.
+    pub since_lsn: Lsn,
+    pub lsn: Lsn,
+    /// All the relation segment files at `lsn`, the unchanged ones too.
+    pub files: Vec<IncrementalFile>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct IncrementalFile {
+    /// Path of the segment file in the data directory, e.g. `base/5/1259.1`.
+    pub path: String,
+    /// Size of the file in blocks.
+    pub nblocks: u32,
+    /// Block numbers within the file of the pages in the `<path>.delta` entry, in the order
+    /// they are there. The entry is only sent if some are changed.
+    pub changed_blocks: Vec<u32>,
+}
+
 /// Create basebackup with non-rel data in it.
 /// Only include relational data if 'full_backup' is true.
-///
+/// If `since_lsn` is given, the basebackup is incremental, see the module docs; it
+/// must be a full one then.
+/// The tarball is compressed with `compression`, and spilled to a temporary file before
+/// it's sent if it's larger than the `basebackup_spill_threshold` of `conf`.
+/// With `best_effort`, the SLRU segments, relmap and twophase files that fail to be fetched
+/// are left out of the tarball with a warning, instead of failing the basebackup.
+/// The user databases that `databases` doesn't include are left out, see the module docs. In
+/// an incremental basebackup, they're missing from the manifest too, so the receiver deletes
+/// their relation files.
+/// Once `cancel` is cancelled, e.g. because the client went away, the basebackup fails with
+/// [`BasebackupCancelled`] before the next file is added to the tarball, or before the next page
+/// of a large file is fetched.
 /// Currently we use empty 'req_lsn' in two cases:
 ///  * During the basebackup right after timeline creation
 ///  * When working without safekeepers. In this situation it is important to match the lsn
 ///    we are taking basebackup on with the lsn that is used in pageserver's walreceiver
 ///    to start the replication.
+#[allow(clippy::too_many_arguments)]
 pub async fn send_basebackup_tarball<'a, W>(
+    conf: &'static PageServerConf,
     write: &'a mut W,
     timeline: &'a Timeline,
     req_lsn: Option<Lsn>,
     prev_lsn: Option<Lsn>,
     full_backup: bool,
+    since_lsn: Option<Lsn>,
+    compression: BasebackupCompression,
+    best_effort: bool,
+    databases: &'a DatabaseFilter,
     ctx: &'a RequestContext,
-) -> anyhow::Result<()>
+    cancel: &'a CancellationToken,
+) -> anyhow::Result<BasebackupStats>
 where
     W: AsyncWrite + Send + Sync + Unpin,
 {
@@ -98,24 +271,383 @@ where
     };
 
     info!(
-        "taking basebackup lsn={}, prev_lsn={} (full_backup={})",
-        backup_lsn, prev_lsn, full_backup
+        "taking basebackup lsn={}, prev_lsn={} (full_backup={}, compression={:?})",
+        backup_lsn, prev_lsn, full_backup, compression
     );
 
+    // Every file of the tarball is read from the timeline at `backup_lsn`, which the WAL may
+    // have left far behind by the time the last one is. Holding the GC cutoff keeps GC from
+    // removing the page versions of `backup_lsn` meanwhile: it waits for the basebackup to
+    // finish before it moves the cutoff, so all the files are of that one LSN.
+    let latest_gc_cutoff_lsn = timeline.get_latest_gc_cutoff_lsn();
+    timeline
+        .check_lsn_is_in_scope(backup_lsn, &latest_gc_cutoff_lsn)
+        .context("invalid basebackup lsn")?;
+
+    let incremental = match since_lsn {
+        Some(since_lsn) => {
+            ensure!(full_backup, "an incremental basebackup must be a full one");
+            // The changes are only known from the layers of this timeline that GC hasn't
+            // removed yet.
+            let earliest = std::cmp::max(timeline.get_ancestor_lsn(), timeline.initdb_lsn)
+                .max(*latest_gc_cutoff_lsn);
+            ensure!(
+                since_lsn >= earliest && since_lsn <= backup_lsn,
+                "incremental basebackup can only be taken since an LSN between {earliest} and {backup_lsn}, not {since_lsn}"
+            );
+            let rel_changes = timeline
+                .get_rel_changes(since_lsn, backup_lsn, ctx)
+                .await
+                .context("failed to collect the changed relation blocks")?;
+            info!(
+                "taking incremental basebackup since {since_lsn}, {} relations changed",
+                rel_changes.len()
+            );
+            Some(Incremental {
+                rel_changes,
+                manifest: IncrementalManifest {
+                    since_lsn,
+                    lsn: backup_lsn,
+                    files: Vec::new(),
+                },
+            })
+        }
+        None => None,
+    };
+
+    if conf.basebackup_spill_threshold == 0 {
+        let stats = write_tarball(
+            write,
+            timeline,
+            backup_lsn,
+            prev_lsn,
+            full_backup,
+            incremental,
+            compression,
+            best_effort,
+            databases,
+            ctx,
+            cancel,
+        )
+        .await;
+        drop(latest_gc_cutoff_lsn);
+        return stats;
+    }
+
+    let temp_dir = conf.timeline_path(&timeline.tenant_id, &timeline.timeline_id);
+    let mut spill = SpillBuffer::new(&temp_dir, conf.basebackup_spill_threshold);
+    let stats = write_tarball(
+        &mut spill,
+        timeline,
+        backup_lsn,
+        prev_lsn,
+        full_backup,
+        incremental,
+        compression,
+        best_effort,
+        databases,
+        ctx,
+        cancel,
+    )
+    .await?;
+    // Nothing is read from the timeline anymore.
+    drop(latest_gc_cutoff_lsn);
+    if spill.spilled {
+        info!("basebackup spilled to {}", spill.path.display());
+    }
+    tokio::select! {
+        sent = spill.send(write) => sent?,
+        _ = cancel.cancelled() => return Err(BasebackupCancelled.into()),
+    }
+    Ok(stats)
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn write_tarball<W>(
+    write: &mut W,
+    timeline: &Timeline,
+    backup_lsn: Lsn,
+    prev_lsn: Lsn,
+    full_backup: bool,
+    incremental: Option<Incremental>,
+    compression: BasebackupCompression,
+    best_effort: bool,
+    databases: &DatabaseFilter,
+    ctx: &RequestContext,
+    cancel: &CancellationToken,
+) -> anyhow::Result<BasebackupStats>
+where
+    W: AsyncWrite + Send + Sync + Unpin,
+{
+    // NOTE using fast compression because it's on the critical path
+    //      for compute startup. For an empty database, we get
+    //      <100KB with this method. The Level::Best compression method
+    //      gives us <20KB, but maybe we should add basebackup caching
+    //      on compute shutdown first.
+    let level = async_compression::Level::Fastest;
+    match compression {
+        BasebackupCompression::None => {
+            send_tarball(
+                write,
+                timeline,
+                backup_lsn,
+                prev_lsn,
+                full_backup,
+                incremental,
+                best_effort,
+                databases,
+                ctx,
+                cancel,
+            )
+            .await
+        }
+        BasebackupCompression::Gzip => {
+            let mut encoder = GzipEncoder::with_quality(write, level);
+            let stats = send_tarball(
+                &mut encoder,
+                timeline,
+                backup_lsn,
+                prev_lsn,
+                full_backup,
+                incremental,
+                best_effort,
+                databases,
+                ctx,
+                cancel,
+            )
+            .await?;
+            // shutdown the encoder to ensure the gzip footer is written
+            encoder.shutdown().await?;
+            Ok(stats)
+        }
+        BasebackupCompression::Zstd => {
+            let mut encoder = ZstdEncoder::with_quality(write, level);
+            let stats = send_tarball(
+                &mut encoder,
+                timeline,
+                backup_lsn,
+                prev_lsn,
+                full_backup,
+                incremental,
+                best_effort,
+                databases,
+                ctx,
+                cancel,
+            )
+            .await?;
+            // the zstd frame is only complete once the encoder is shut down
+            encoder.shutdown().await?;
+            Ok(stats)
+        }
+    }
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn send_tarball<W>(
+    write: &mut W,
+    timeline: &Timeline,
+    lsn: Lsn,
+    prev_record_lsn: Lsn,
+    full_backup: bool,
+    incremental: Option<Incremental>,
+    best_effort: bool,
+    databases: &DatabaseFilter,
+    ctx: &RequestContext,
+    cancel: &CancellationToken,
+) -> anyhow::Result<BasebackupStats>
+where
+    W: AsyncWrite + Send + Sync + Unpin,
+{
     let basebackup = Basebackup {
         ar: Builder::new_non_terminated(write),
         timeline,
-        lsn: backup_lsn,
-        prev_record_lsn: prev_lsn,
+        lsn,
+        prev_record_lsn,
         full_backup,
+        incremental,
+        best_effort,
+        databases,
+        stats: BasebackupStats::new(timeline.timeline_id, lsn, prev_record_lsn),
         ctx,
+        cancel,
     };
     basebackup
         .send_tarball()
-        .instrument(info_span!("send_tarball", backup_lsn=%backup_lsn))
+        .instrument(info_span!("send_tarball", backup_lsn=%lsn))
         .await
 }
 
+/// A tarball that is kept in memory up to `max_in_memory` bytes. The first write that goes
+/// past that moves the buffered bytes into a temporary file, and appends everything after
+/// them there.
+///
+/// The file is only created once the tarball spills, in a blocking task, and removed after
+/// it's sent. A buffer dropped before that, also in the middle of the send, removes the file
+/// in the background, so that the executor is not blocked either; a file left behind by a
+/// crash is cleaned up as any other temporary file.
+struct SpillBuffer {
+    max_in_memory: u64,
+    memory: Vec<u8>,
+    /// How much of `memory` is written into the file, once spilled.
+    memory_written: usize,
+    spilled: bool,
+    creating: Option<JoinHandle<io::Result<std::fs::File>>>,
+    file: Option<tokio::fs::File>,
+    path: PathBuf,
+}
+
+impl SpillBuffer {
+    fn new(temp_dir: &Path, max_in_memory: u64) -> Self {
+        let rand_string: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+        Self {
+            max_in_memory,
+            memory: Vec::new(),
+            memory_written: 0,
+            spilled: false,
+            creating: None,
+            file: None,
+            path: temp_dir.join(format!("basebackup.{rand_string}.{TEMP_FILE_SUFFIX}")),
+        }
+    }
+
+    /// Starts creating the file on the first call, ready once the file is there.
+    fn poll_create_file(&mut self, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        if self.file.is_none() {
+            let path = &self.path;
+            let creating = self.creating.get_or_insert_with(|| {
+                let path = path.clone();
+                tokio::task::spawn_blocking(move || {
+                    std::fs::OpenOptions::new()
+                        .read(true)
+                        .write(true)
+                        .create_new(true)
+                        .open(path)
+                })
+            });
+            let created = ready!(Pin::new(creating).poll(cx))
+                .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+                .and_then(|created| created);
+            self.creating = None;
+            let file = created.map_err(|e| {
+                io::Error::new(
+                    e.kind(),
+                    format!("create basebackup spill file {}: {e}", self.path.display()),
+                )
+            })?;
+            self.file = Some(tokio::fs::File::from_std(file));
+        }
+        Poll::Ready(Ok(()))
+    }
+
+    /// Writes the whole tarball to `write`, from memory or from the start of the file.
+    ///
+    /// The file is kept in the buffer until it's sent, so that the drop of a failed or
+    /// cancelled send removes it.
+    async fn send<W>(mut self, write: &mut W) -> anyhow::Result<()>
+    where
+        W: AsyncWrite + Unpin,
+    {
+        let Some(file) = self.file.as_mut() else {
+            write.write_all(&self.memory).await?;
+            return Ok(());
+        };
+        file.flush().await?;
+        file.rewind().await?;
+        io::copy(file, write)
+            .await
+            .context("send the spilled basebackup")?;
+        self.file = None;
+        remove_spill_file(&self.path).await;
+        Ok(())
+    }
+}
+
+async fn remove_spill_file(path: &Path) {
+    if let Err(e) = tokio::fs::remove_file(path).await {
+        warn!(
+            "failed to remove basebackup spill file {}: {e}",
+            path.display()
+        );
+    }
+}
+
+impl AsyncWrite for SpillBuffer {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut TaskContext<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let this = self.get_mut();
+        if !this.spilled {
+            if (this.memory.len() + buf.len()) as u64 <= this.max_in_memory {
+                this.memory.extend_from_slice(buf);
+                return Poll::Ready(Ok(buf.len()));
+            }
+            this.spilled = true;
+        }
+        ready!(this.poll_create_file(cx))?;
+        let file = this.file.as_mut().expect("file is created above");
+        while this.memory_written < this.memory.len() {
+            let n =
+                ready!(Pin::new(&mut *file).poll_write(cx, &this.memory[this.memory_written..]))?;
+            if n == 0 {
+                return Poll::Ready(Err(io::ErrorKind::WriteZero.into()));
+            }
+            this.memory_written += n;
+        }
+        if !this.memory.is_empty() {
+            this.memory = Vec::new();
+            this.memory_written = 0;
+        }
+        Pin::new(file).poll_write(cx, buf)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        match &mut self.get_mut().file {
+            Some(file) => Pin::new(file).poll_flush(cx),
+            None => Poll::Ready(Ok(())),
+        }
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut TaskContext<'_>) -> Poll<io::Result<()>> {
+        self.poll_flush(cx)
+    }
+}
+
+impl Drop for SpillBuffer {
+    fn drop(&mut self) {
+        if self.file.is_none() && self.creating.is_none() {
+            return;
+        }
+        drop(self.file.take());
+        let creating = self.creating.take();
+        let path = std::mem::take(&mut self.path);
+        let Ok(runtime) = tokio::runtime::Handle::try_current() else {
+            // Dropped off the runtime, there is no executor to block.
+            if let Err(e) = std::fs::remove_file(&path) {
+                if e.kind() != io::ErrorKind::NotFound {
+                    warn!(
+                        "failed to remove basebackup spill file {}: {e}",
+                        path.display()
+                    );
+                }
+            }
+            return;
+        };
+        runtime.spawn(async move {
+            // A file that is still being created is removed once it's there.
+            if let Some(creating) = creating {
+                let _ = creating.await;
+            }
+            remove_spill_file(&path).await;
+        });
+    }
+}
+
 /// This is short-living object only for the time of tarball creation,
 /// created mostly to avoid passing a lot of parameters between various functions
 /// used for constructing tarball.
@@ -128,21 +660,34 @@ where
     lsn: Lsn,
     prev_record_lsn: Lsn,
     full_backup: bool,
+    incremental: Option<Incremental>,
+    /// Leave out the non-relational files that fail to be fetched, instead of failing.
+    best_effort: bool,
+    databases: &'a DatabaseFilter,
+    /// Counted as the files are appended.
+    stats: BasebackupStats,
     ctx: &'a RequestContext,
+    cancel: &'a CancellationToken,
+}
+
+/// State of an incremental basebackup.
+struct Incremental {
+    rel_changes: HashMap<RelTag, RelChanges>,
+    /// Filled in as the relations are added.
+    manifest: IncrementalManifest,
 }
 
 impl<'a, W> Basebackup<'a, W>
 where
     W: AsyncWrite + Send + Sync + Unpin,
 {
-    async fn send_tarball(mut self) -> anyhow::Result<()> {
+    async fn send_tarball(mut self) -> anyhow::Result<BasebackupStats> {
         // TODO include checksum
 
         // Create pgdata subdirs structure
         for dir in PGDATA_SUBDIRS.iter() {
             let header = new_tar_header_dir(dir)?;
-            self.ar
-                .append(&header, &mut io::empty())
+            self.append(&header, &mut io::empty())
                 .await
                 .context("could not add directory to basebackup tarball")?;
         }
@@ -152,85 +697,153 @@ where
             if *filepath == "pg_hba.conf" {
                 let data = PG_HBA.as_bytes();
                 let header = new_tar_header(filepath, data.len() as u64)?;
-                self.ar
-                    .append(&header, data)
+                self.append(&header, data)
                     .await
                     .context("could not add config file to basebackup tarball")?;
             } else {
                 let header = new_tar_header(filepath, 0)?;
-                self.ar
-                    .append(&header, &mut io::empty())
+                self.append(&header, &mut io::empty())
                     .await
                     .context("could not add config file to basebackup tarball")?;
             }
         }
 
         // Gather non-relational files from object storage pages.
-        for kind in [
-            SlruKind::Clog,
-            SlruKind::MultiXactOffsets,
-            SlruKind::MultiXactMembers,
-            SlruKind::Csn,
-        ] {
-            for segno in self
-                .timeline
-                .list_slru_segments(kind, Version::Lsn(self.lsn), self.ctx)
-                .await?
-            {
-                self.add_slru_segment(kind, segno).await?;
-            }
-        }
-
-        // Create tablespace directories
-        for ((spcnode, dbnode), has_relmap_file) in
-            self.timeline.list_dbdirs(self.lsn, self.ctx).await?
-        {
-            self.add_dbdir(spcnode, dbnode, has_relmap_file).await?;
-
-            // If full backup is requested, include all relation files.
-            // Otherwise only include init forks of unlogged relations.
-            let rels = self
-                .timeline
-                .list_rels(spcnode, dbnode, Version::Lsn(self.lsn), self.ctx)
-                .await?;
-            for &rel in rels.iter() {
-                // Send init fork as main fork to provide well formed empty
-                // contents of UNLOGGED relations. Postgres copies it in
-                // `reinit.c` during recovery.
-                if rel.forknum == INIT_FORKNUM {
-                    // I doubt we need _init fork itself, but having it at least
-                    // serves as a marker relation is unlogged.
-                    self.add_rel(rel, rel).await?;
-                    self.add_rel(rel, rel.with_forknum(MAIN_FORKNUM)).await?;
-                    continue;
-                }
+        self.add_slru_segments().await?;
 
-                if self.full_backup {
-                    if rel.forknum == MAIN_FORKNUM && rels.contains(&rel.with_forknum(INIT_FORKNUM))
-                    {
-                        // skip this, will include it when we reach the init fork
-                        continue;
-                    }
-                    self.add_rel(rel, rel).await?;
-                }
-            }
-        }
-        for xid in self
+        self.add_databases().await?;
+        let mut xids = self
             .timeline
             .list_twophase_files(self.lsn, self.ctx)
             .await?
-        {
-            self.add_twophase_file(xid).await?;
+            .into_iter()
+            .collect::<Vec<_>>();
+        xids.sort_unstable();
+        if !xids.is_empty() {
+            // The repository keeps the 32-bit xids, their epochs are the ones they have relative
+            // to the next xid of the checkpoint, as in Postgres.
+            let checkpoint_bytes = self
+                .timeline
+                .get_checkpoint(self.lsn, self.ctx)
+                .await
+                .context("failed to get checkpoint bytes")?;
+            let next_full_xid = CheckPoint::decode(&checkpoint_bytes)
+                .context("failed to decode checkpoint")?
+                .nextXid
+                .value;
+            for xid in xids {
+                self.add_twophase_file(xid, next_full_xid).await?;
+            }
         }
 
         fail_point!("basebackup-before-control-file", |_| {
             bail!("failpoint basebackup-before-control-file")
         });
 
+        if let Some(incremental) = &self.incremental {
+            let manifest = serde_json::to_vec(&incremental.manifest)?;
+            let header = new_tar_header(INCREMENTAL_MANIFEST_PATH, manifest.len() as u64)?;
+            self.append(&header, manifest.as_slice()).await?;
+        }
+
         // Generate pg_control and bootstrap WAL segment.
         self.add_pgcontrol_file().await?;
         self.ar.finish().await?;
         debug!("all tarred up!");
+        if self.stats.skipped_files > 0 {
+            warn!(
+                "basebackup is missing {} files that failed to be fetched",
+                self.stats.skipped_files
+            );
+        }
+        Ok(self.stats)
+    }
+
+    //
+    // Create the tablespace and database directories, with the relation files of the
+    // databases the filter includes.
+    //
+    async fn add_databases(&mut self) -> anyhow::Result<()> {
+        let mut dbdirs = self
+            .timeline
+            .list_dbdirs(self.lsn, self.ctx)
+            .await?
+            .into_iter()
+            .collect::<Vec<_>>();
+        dbdirs.sort_unstable();
+        for ((spcnode, dbnode), has_relmap_file) in dbdirs {
+            if !self.databases.includes(dbnode) {
+                info!("leaving database {dbnode} out of the basebackup");
+                self.stats.skipped_databases += 1;
+                continue;
+            }
+            self.add_dbdir(spcnode, dbnode, has_relmap_file).await?;
+            self.add_rels(spcnode, dbnode).await?;
+        }
+        Ok(())
+    }
+
+    //
+    // Include the relation files of a database.
+    //
+    // If full backup is requested, include all relation files. Otherwise only include init
+    // forks of unlogged relations. Either way, the main fork of an unlogged relation is a
+    // copy of its init fork, which is what Postgres would reset it to after a crash anyway.
+    //
+    async fn add_rels(&mut self, spcnode: u32, dbnode: u32) -> anyhow::Result<()> {
+        let rels = self
+            .timeline
+            .list_rels(spcnode, dbnode, Version::Lsn(self.lsn), self.ctx)
+            .await?;
+        let mut sorted_rels = rels.iter().copied().collect::<Vec<_>>();
+        sorted_rels.sort_unstable();
+        for rel in sorted_rels {
+            // Send init fork as main fork to provide well formed empty
+            // contents of UNLOGGED relations. Postgres copies it in
+            // `reinit.c` during recovery.
+            if rel.forknum == INIT_FORKNUM {
+                // I doubt we need _init fork itself, but having it at least
+                // serves as a marker relation is unlogged.
+                self.add_rel(rel, rel).await?;
+                self.add_rel(rel, rel.with_forknum(MAIN_FORKNUM)).await?;
+                continue;
+            }
+
+            if self.full_backup {
+                if rel.forknum == MAIN_FORKNUM && rels.contains(&rel.with_forknum(INIT_FORKNUM)) {
+                    // skip this, will include it when we reach the init fork
+                    continue;
+                }
+                self.add_rel(rel, rel).await?;
+            }
+        }
+        Ok(())
+    }
+
+    //
+    // Generate the files of all SLRU segments from repository.
+    //
+    // Every file is assembled from its own pages in add_slru_segment, so the order in which
+    // the pages were written doesn't matter, and the files are added in a fixed order.
+    //
+    async fn add_slru_segments(&mut self) -> anyhow::Result<()> {
+        for kind in [
+            SlruKind::Clog,
+            SlruKind::MultiXactOffsets,
+            SlruKind::MultiXactMembers,
+            SlruKind::Csn,
+        ] {
+            let mut segments = self
+                .timeline
+                .list_slru_segments(kind, Version::Lsn(self.lsn), self.ctx)
+                .await?
+                .into_iter()
+                .collect::<Vec<_>>();
+            segments.sort_unstable();
+            for segno in segments {
+                self.add_slru_segment(kind, segno).await?;
+            }
+        }
         Ok(())
     }
 
@@ -241,11 +854,15 @@ where
             .get_rel_size(src, Version::Lsn(self.lsn), false, self.ctx)
             .await?;
 
+        if self.incremental.is_some() {
+            return self.add_rel_changes(src, dst, nblocks).await;
+        }
+
         // If the relation is empty, create an empty file
         if nblocks == 0 {
             let file_name = dst.to_segfile_name(0);
             let header = new_tar_header(&file_name, 0)?;
-            self.ar.append(&header, &mut io::empty()).await?;
+            self.append(&header, &mut io::empty()).await?;
             return Ok(());
         }
 
@@ -255,18 +872,22 @@ where
         while startblk < nblocks {
             let endblk = std::cmp::min(startblk + RELSEG_SIZE, nblocks);
 
-            let mut segment_data: Vec<u8> = vec![];
-            for blknum in startblk..endblk {
-                let img = self
-                    .timeline
-                    .get_rel_page_at_lsn(src, blknum, Version::Lsn(self.lsn), false, self.ctx)
-                    .await?;
-                segment_data.extend_from_slice(&img[..]);
+            let (timeline, lsn, ctx) = (self.timeline, self.lsn, self.ctx);
+            let mut pages = stream::iter(startblk..endblk)
+                .map(|blknum| {
+                    timeline.get_rel_page_at_lsn(src, blknum, Version::Lsn(lsn), false, ctx)
+                })
+                .buffered(PAGE_FETCH_CONCURRENCY);
+            let mut segment_data: Vec<u8> =
+                Vec::with_capacity((endblk - startblk) as usize * BLCKSZ as usize);
+            while let Some(img) = pages.next().await {
+                self.check_cancelled()?;
+                segment_data.extend_from_slice(&img?[..]);
             }
 
             let file_name = dst.to_segfile_name(seg as u32);
             let header = new_tar_header(&file_name, segment_data.len() as u64)?;
-            self.ar.append(&header, segment_data.as_slice()).await?;
+            self.append(&header, segment_data.as_slice()).await?;
 
             seg += 1;
             startblk = endblk;
@@ -275,21 +896,101 @@ where
         Ok(())
     }
 
+    /// Add the changed blocks of relfilenode `src` of an incremental basebackup, naming
+    /// it as `dst`, and list its segments in the manifest.
+    async fn add_rel_changes(
+        &mut self,
+        src: RelTag,
+        dst: RelTag,
+        nblocks: BlockNumber,
+    ) -> anyhow::Result<()> {
+        let incremental = self.incremental.as_ref().expect("incremental basebackup");
+        let changes = incremental
+            .rel_changes
+            .get(&src)
+            .cloned()
+            .unwrap_or_default();
+        let min_nblocks = changes.min_nblocks.unwrap_or(nblocks).min(nblocks);
+        let changed = changes
+            .blocks
+            .range(..min_nblocks)
+            .copied()
+            .chain(min_nblocks..nblocks)
+            .collect::<Vec<_>>();
+
+        // An empty relation still has a file, like in a full basebackup.
+        let nsegments = std::cmp::max(nblocks.div_ceil(RELSEG_SIZE), 1);
+        let mut changed = changed.as_slice();
+        for seg in 0..nsegments {
+            let startblk = seg * RELSEG_SIZE;
+            let endblk = std::cmp::min(startblk + RELSEG_SIZE, nblocks);
+            let seg_len = changed.partition_point(|blknum| *blknum < endblk);
+            let (seg_changed, rest) = changed.split_at(seg_len);
+            changed = rest;
+
+            let path = dst.to_segfile_name(seg);
+            if !seg_changed.is_empty() {
+                let (timeline, lsn, ctx) = (self.timeline, self.lsn, self.ctx);
+                let mut pages = stream::iter(seg_changed.iter().copied())
+                    .map(|blknum| {
+                        timeline.get_rel_page_at_lsn(src, blknum, Version::Lsn(lsn), false, ctx)
+                    })
+                    .buffered(PAGE_FETCH_CONCURRENCY);
+                let mut delta_data: Vec<u8> =
+                    Vec::with_capacity(seg_changed.len() * BLCKSZ as usize);
+                while let Some(img) = pages.next().await {
+                    self.check_cancelled()?;
+                    delta_data.extend_from_slice(&img?[..]);
+                }
+                let header = new_tar_header(&format!("{path}.delta"), delta_data.len() as u64)?;
+                self.append(&header, delta_data.as_slice()).await?;
+            }
+
+            let incremental = self.incremental.as_mut().expect("incremental basebackup");
+            incremental.manifest.files.push(IncrementalFile {
+                path,
+                nblocks: endblk - startblk,
+                changed_blocks: seg_changed.iter().map(|blknum| blknum - startblk).collect(),
+            });
+        }
+        Ok(())
+    }
+
     //
     // Generate SLRU segment files from repository.
     //
     async fn add_slru_segment(&mut self, slru: SlruKind, segno: u32) -> anyhow::Result<()> {
+        let segname = format!("{}/{:>04X}", slru.to_str(), segno);
+        let fetched = self.fetch_slru_segment(slru, segno).await;
+        let Some(slru_buf) = self.fetched(&segname, fetched)? else {
+            return Ok(());
+        };
+
+        let header = new_tar_header(&segname, slru_buf.len() as u64)?;
+        self.append(&header, slru_buf.as_slice()).await?;
+        self.stats.slru_segments += 1;
+
+        trace!(
+            "Added to basebackup slru {} relsize {}",
+            segname,
+            slru_buf.len() / BLCKSZ as usize
+        );
+        Ok(())
+    }
+
+    async fn fetch_slru_segment(&self, slru: SlruKind, segno: u32) -> anyhow::Result<Vec<u8>> {
         let nblocks = self
             .timeline
             .get_slru_segment_size(slru, segno, Version::Lsn(self.lsn), self.ctx)
             .await?;
 
+        let (timeline, lsn, ctx) = (self.timeline, self.lsn, self.ctx);
+        let mut pages = stream::iter(0..nblocks)
+            .map(|blknum| timeline.get_slru_page_at_lsn(slru, segno, blknum, lsn, ctx))
+            .buffered(PAGE_FETCH_CONCURRENCY);
         let mut slru_buf: Vec<u8> = Vec::with_capacity(nblocks as usize * BLCKSZ as usize);
-        for blknum in 0..nblocks {
-            let img = self
-                .timeline
-                .get_slru_page_at_lsn(slru, segno, blknum, self.lsn, self.ctx)
-                .await?;
+        while let Some(img) = pages.next().await {
+            let img = img?;
 
             if slru == SlruKind::Clog {
                 ensure!(img.len() == BLCKSZ as usize || img.len() == BLCKSZ as usize + 8);
@@ -299,15 +1000,45 @@ where
 
             slru_buf.extend_from_slice(&img[..BLCKSZ as usize]);
         }
+        Ok(slru_buf)
+    }
 
-        let segname = format!("{}/{:>04X}", slru.to_str(), segno);
-        let header = new_tar_header(&segname, slru_buf.len() as u64)?;
-        self.ar.append(&header, slru_buf.as_slice()).await?;
+    /// Appends an entry to the tarball, counting it in the stats if it's a file.
+    async fn append<R>(&mut self, header: &Header, data: R) -> anyhow::Result<()>
+    where
+        R: AsyncRead + Unpin + Send,
+    {
+        self.check_cancelled()?;
+        self.ar.append(header, data).await?;
+        if header.entry_type().is_file() {
+            self.stats.files_written += 1;
+            self.stats.bytes_written += header.size()?;
+        }
+        Ok(())
+    }
 
-        trace!("Added to basebackup slru {} relsize {}", segname, nblocks);
+    fn check_cancelled(&self) -> anyhow::Result<()> {
+        if self.cancel.is_cancelled() {
+            return Err(BasebackupCancelled.into());
+        }
         Ok(())
     }
 
+    /// Returns the contents of a file that was fetched for the tarball. If fetching it failed
+    /// and the basebackup is best effort, returns `None` instead, and the file is left out.
+    ///
+    /// Nothing of the file must have been appended to the tarball yet.
+    fn fetched<T>(&mut self, path: &str, fetched: anyhow::Result<T>) -> anyhow::Result<Option<T>> {
+        match fetched {
+            Err(e) if self.best_effort => {
+                warn!("leaving {path} out of the basebackup, failed to fetch it: {e:#}");
+                self.stats.skipped_files += 1;
+                Ok(None)
+            }
+            fetched => fetched.map(Some),
+        }
+    }
+
     //
     // Include database/tablespace directories.
     //
@@ -321,12 +1052,21 @@ where
         has_relmap_file: bool,
     ) -> anyhow::Result<()> {
         let relmap_img = if has_relmap_file {
-            let img = self
-                .timeline
-                .get_relmap_file(spcnode, dbnode, Version::Lsn(self.lsn), self.ctx)
-                .await?;
-            ensure!(img.len() == 512);
-            Some(img)
+            let fetched = async {
+                let img = self
+                    .timeline
+                    .get_relmap_file(spcnode, dbnode, Version::Lsn(self.lsn), self.ctx)
+                    .await?;
+                ensure!(img.len() == 512);
+                anyhow::Ok(img)
+            }
+            .await;
+            let path = if spcnode == GLOBALTABLESPACE_OID {
+                "global/pg_filenode.map".to_string()
+            } else {
+                format!("base/{dbnode}/pg_filenode.map")
+            };
+            self.fetched(&path, fetched)?
         } else {
             None
         };
@@ -334,14 +1074,14 @@ where
         if spcnode == GLOBALTABLESPACE_OID {
             let pg_version_str = self.timeline.pg_version.to_string();
             let header = new_tar_header("PG_VERSION", pg_version_str.len() as u64)?;
-            self.ar.append(&header, pg_version_str.as_bytes()).await?;
+            self.append(&header, pg_version_str.as_bytes()).await?;
 
             info!("timeline.pg_version {}", self.timeline.pg_version);
 
             if let Some(img) = relmap_img {
                 // filenode map for global tablespace
                 let header = new_tar_header("global/pg_filenode.map", img.len() as u64)?;
-                self.ar.append(&header, &img[..]).await?;
+                self.append(&header, &img[..]).await?;
             } else {
                 warn!("global/pg_filenode.map is missing");
             }
@@ -365,24 +1105,31 @@ where
             {
                 return Ok(());
             }
-            // User defined tablespaces are not supported
-            ensure!(spcnode == DEFAULTTABLESPACE_OID);
+            // User defined tablespaces are not supported: their relation files would need
+            // a `pg_tblspc/<spcnode>` symlink and a directory for it to point to, which the
+            // compute does not have. Fail loudly rather than leave the database out.
+            ensure!(
+                spcnode == DEFAULTTABLESPACE_OID,
+                "database {dbnode} in user defined tablespace {spcnode} cannot be included in the basebackup"
+            );
 
             // Append dir path for each database
             let path = format!("base/{}", dbnode);
             let header = new_tar_header_dir(&path)?;
-            self.ar.append(&header, &mut io::empty()).await?;
+            self.append(&header, &mut io::empty()).await?;
 
-            if let Some(img) = relmap_img {
-                let dst_path = format!("base/{}/PG_VERSION", dbnode);
-
-                let pg_version_str = self.timeline.pg_version.to_string();
-                let header = new_tar_header(&dst_path, pg_version_str.len() as u64)?;
-                self.ar.append(&header, pg_version_str.as_bytes()).await?;
+            // Postgres refuses to connect to a database directory without PG_VERSION, so it's
+            // written even without the relmap file: a database being created with the WAL_LOG
+            // strategy has its relations logged before its relmap file is.
+            let dst_path = format!("base/{}/PG_VERSION", dbnode);
+            let pg_version_str = self.timeline.pg_version.to_string();
+            let header = new_tar_header(&dst_path, pg_version_str.len() as u64)?;
+            self.append(&header, pg_version_str.as_bytes()).await?;
 
+            if let Some(img) = relmap_img {
                 let relmap_path = format!("base/{}/pg_filenode.map", dbnode);
                 let header = new_tar_header(&relmap_path, img.len() as u64)?;
-                self.ar.append(&header, &img[..]).await?;
+                self.append(&header, &img[..]).await?;
             }
         };
         Ok(())
@@ -391,19 +1138,30 @@ where
     //
     // Extract twophase state files
     //
-    async fn add_twophase_file(&mut self, xid: TransactionId) -> anyhow::Result<()> {
-        let img = self
-            .timeline
-            .get_twophase_file(xid, self.lsn, self.ctx)
-            .await?;
+    async fn add_twophase_file(
+        &mut self,
+        xid: TransactionId,
+        next_full_xid: u64,
+    ) -> anyhow::Result<()> {
+        let path = format!(
+            "pg_twophase/{}",
+            twophase_file_name(self.timeline.pg_version, xid, next_full_xid)
+        );
+        let fetched = async {
+            let img = self
+                .timeline
+                .get_twophase_file(xid, self.lsn, self.ctx)
+                .await?;
+            twophase_file_contents(xid, &img)
+        }
+        .await;
+        let Some(buf) = self.fetched(&path, fetched)? else {
+            return Ok(());
+        };
 
-        let mut buf = BytesMut::new();
-        buf.extend_from_slice(&img[..]);
-        let crc = crc32c::crc32c(&img[..]);
-        buf.put_u32_le(crc);
-        let path = format!("pg_twophase/{:>08X}", xid);
         let header = new_tar_header(&path, buf.len() as u64)?;
-        self.ar.append(&header, &buf[..]).await?;
+        self.append(&header, &buf[..]).await?;
+        self.stats.twophase_files += 1;
 
         Ok(())
     }
@@ -424,12 +1182,11 @@ where
         } else {
             write!(zenith_signal, "PREV LSN: {}", self.prev_record_lsn)?;
         }
-        self.ar
-            .append(
-                &new_tar_header("zenith.signal", zenith_signal.len() as u64)?,
-                zenith_signal.as_bytes(),
-            )
-            .await?;
+        self.append(
+            &new_tar_header("zenith.signal", zenith_signal.len() as u64)?,
+            zenith_signal.as_bytes(),
+        )
+        .await?;
 
         let checkpoint_bytes = self
             .timeline
@@ -442,36 +1199,142 @@ where
             .await
             .context("failed get control bytes")?;
 
+        let checkpoint =
+            CheckPoint::decode(&checkpoint_bytes).context("failed to decode checkpoint")?;
+        // The cluster might have been initdb'ed with a non-default --wal-segsize, the compute
+        // expects its segments to be of that size.
+        let control_file =
+            postgres_ffi::decode_pg_control(&pg_control_bytes, self.timeline.pg_version)
+                .context("failed to decode control file")?;
+        check_control_file_lsns(self.lsn, Lsn(checkpoint.redo), Lsn(control_file.checkPoint))?;
+
+        // The compute continues on the PG timeline of the last checkpoint, e.g. the one
+        // switched to by a point in time recovery before the data directory was imported.
+        let pg_tli = checkpoint.ThisTimeLineID;
+        let pg_tli = if pg_tli == 0 { PG_TLI } else { pg_tli };
+
+        let wal_seg_size = postgres_ffi::wal_segment_size(control_file.xlog_seg_size)?;
+
         let (pg_control_bytes, system_identifier) = postgres_ffi::generate_pg_control(
             &pg_control_bytes,
             &checkpoint_bytes,
             self.lsn,
             self.timeline.pg_version,
+            pg_tli,
         )?;
 
         //send pg_control
         let header = new_tar_header("global/pg_control", pg_control_bytes.len() as u64)?;
-        self.ar.append(&header, &pg_control_bytes[..]).await?;
+        self.append(&header, &pg_control_bytes[..]).await?;
 
         //send wal segment
-        let segno = self.lsn.segment_number(WAL_SEGMENT_SIZE);
-        let wal_file_name = XLogFileName(PG_TLI, segno, WAL_SEGMENT_SIZE);
-        let wal_file_path = format!("pg_wal/{}", wal_file_name);
-        let header = new_tar_header(&wal_file_path, WAL_SEGMENT_SIZE as u64)?;
-
-        let wal_seg = postgres_ffi::generate_wal_segment(
-            segno,
+        let (wal_file_path, wal_seg) = bootstrap_wal_segment(
+            self.lsn,
+            pg_tli,
             system_identifier,
             self.timeline.pg_version,
-            self.lsn,
-        )
-        .map_err(|e| anyhow!(e).context("Failed generating wal segment"))?;
-        ensure!(wal_seg.len() == WAL_SEGMENT_SIZE);
-        self.ar.append(&header, &wal_seg[..]).await?;
+            wal_seg_size,
+        )?;
+        let header = new_tar_header(&wal_file_path, wal_seg_size as u64)?;
+        self.append(&header, wal_seg).await?;
         Ok(())
     }
 }
 
+/// Checks that the checkpoint and the control file stored in the repository are not ahead of
+/// the basebackup `lsn`. The control file generated from them would have the compute redo
+/// from `lsn`, behind the data they describe: that's a caller asking for an LSN the
+/// timeline can't serve, or a repository with a stale `pg_control`.
+///
+/// `snapshot_lsn` is the LSN of the checkpoint record the control file was taken at.
+fn check_control_file_lsns(
+    lsn: Lsn,
+    checkpoint_redo: Lsn,
+    snapshot_lsn: Lsn,
+) -> anyhow::Result<()> {
+    ensure!(
+        checkpoint_redo <= lsn,
+        "refusing to create a basebackup at {lsn}: the checkpoint of the repository redoes from {checkpoint_redo}, ahead of it"
+    );
+    ensure!(
+        snapshot_lsn <= lsn,
+        "refusing to create a basebackup at {lsn}: the control file of the repository was taken at {snapshot_lsn}, ahead of it"
+    );
+    Ok(())
+}
+
+/// Contents of the two-phase state file of `xid`: the state stored in the repository,
+/// followed by its CRC.
+///
+/// The state starts with a `TwoPhaseFileHeader`, whose `total_len` is the length of the
+/// whole file. An image that doesn't match it would only fail when Postgres starts, with
+/// no hint of the transaction, so it's checked here.
+fn twophase_file_contents(xid: TransactionId, img: &[u8]) -> anyhow::Result<BytesMut> {
+    ensure!(
+        img.len() >= TWOPHASE_FILE_HEADER_PREFIX_SIZE,
+        "two-phase state of transaction {xid} is too short to have a header: {} bytes",
+        img.len()
+    );
+    let magic = u32::from_le_bytes(img[0..4].try_into().unwrap());
+    ensure!(
+        magic == TWOPHASE_MAGIC,
+        "two-phase state of transaction {xid} has invalid magic {magic:#010X}"
+    );
+    let total_len = u32::from_le_bytes(img[4..8].try_into().unwrap()) as usize;
+    let file_len = img.len() + std::mem::size_of::<u32>();
+    ensure!(
+        total_len == file_len,
+        "two-phase state of transaction {xid} is {} bytes long, its header says {}",
+        img.len(),
+        total_len.saturating_sub(std::mem::size_of::<u32>())
+    );
+
+    let mut buf = BytesMut::with_capacity(file_len);
+    buf.extend_from_slice(img);
+    buf.put_u32_le(crc32c::crc32c(img));
+    Ok(buf)
+}
+
+/// Path and contents of the WAL segment the compute starts writing at `lsn`, on PG timeline
+/// `pg_tli`, in a cluster with `wal_seg_size` byte segments.
+///
+/// The segment is mostly zeroes, so only its page headers are kept in memory and the zeroes
+/// around them are produced while the segment is read, which keeps the memory used by
+/// concurrent basebackups low.
+fn bootstrap_wal_segment(
+    lsn: Lsn,
+    pg_tli: TimeLineID,
+    system_identifier: u64,
+    pg_version: u32,
+    wal_seg_size: usize,
+) -> anyhow::Result<(String, impl AsyncRead + Unpin + Send)> {
+    let segno = lsn.segment_number(wal_seg_size);
+    let wal_file_name = XLogFileName(pg_tli, segno, wal_seg_size);
+    let wal_file_path = format!("pg_wal/{}", wal_file_name);
+
+    let headers = postgres_ffi::generate_wal_segment_headers(
+        segno,
+        system_identifier,
+        pg_version,
+        pg_tli,
+        lsn,
+        wal_seg_size,
+    )
+    .map_err(|e| anyhow!(e).context("Failed generating wal segment"))?;
+
+    let mut wal_seg: Box<dyn AsyncRead + Unpin + Send> = Box::new(io::empty());
+    let mut written = 0;
+    for (offset, header) in headers {
+        ensure!(offset >= written, "overlapping wal segment headers");
+        let zeroes = io::repeat(0).take((offset - written) as u64);
+        written = offset + header.len();
+        wal_seg = Box::new(wal_seg.chain(zeroes).chain(std::io::Cursor::new(header)));
+    }
+    ensure!(written <= wal_seg_size);
+    let zeroes = io::repeat(0).take((wal_seg_size - written) as u64);
+    Ok((wal_file_path, wal_seg.chain(zeroes)))
+}
+
 //
 // Create new tarball entry header
 //
@@ -479,14 +1342,10 @@ fn new_tar_header(path: &str, size: u64) -> anyhow::Result<Header> {
     let mut header = Header::new_gnu();
     header.set_size(size);
     header.set_path(path)?;
-    header.set_mode(0b110000000); // -rw-------
-    header.set_mtime(
-        // use currenttime as last modified time
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-    );
+    header.set_mode(0o600); // -rw-------
+    header.set_uid(0);
+    header.set_gid(0);
+    header.set_mtime(BASEBACKUP_MTIME);
     header.set_cksum();
     Ok(header)
 }
@@ -495,15 +1354,786 @@ fn new_tar_header_dir(path: &str) -> anyhow::Result<Header> {
     let mut header = Header::new_gnu();
     header.set_size(0);
     header.set_path(path)?;
-    header.set_mode(0o755); // -rw-------
+    header.set_mode(0o700); // drwx------
+    header.set_uid(0);
+    header.set_gid(0);
     header.set_entry_type(EntryType::dir());
-    header.set_mtime(
-        // use currenttime as last modified time
-        SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)
-            .unwrap()
-            .as_secs(),
-    );
+    header.set_mtime(BASEBACKUP_MTIME);
     header.set_cksum();
     Ok(header)
 }
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+    use std::time::Duration;
+
+    use bytes::Bytes;
+    use futures::future::LocalBoxFuture;
+    use postgres_ffi::v15::bindings::{XLogLongPageHeaderData, XLogPageHeaderData};
+    use postgres_ffi::{WAL_SEGMENT_SIZE, XLOG_BLCKSZ};
+    use utils::id::RegionId;
+
+    use super::*;
+    use crate::tenant::harness::{TenantHarness, TIMELINE_ID};
+    use crate::DEFAULT_PG_VERSION;
+
+    #[tokio::test]
+    async fn bootstrap_wal_segment_on_later_timeline() -> anyhow::Result<()> {
+        let lsn = Lsn(0x0300_0128);
+        let (path, mut stream) = bootstrap_wal_segment(lsn, 2, 42, 15, WAL_SEGMENT_SIZE)?;
+        let mut segment = Vec::new();
+        stream.read_to_end(&mut segment).await?;
+
+        assert_eq!(path, "pg_wal/000000020000000000000003");
+        let header = XLogLongPageHeaderData::from_bytes(&mut &segment[..])?;
+        assert_eq!(header.std.xlp_tli, 2);
+        assert_eq!(header.xlp_sysid, 42);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn streamed_wal_segment_matches_generated() -> anyhow::Result<()> {
+        // Past the first page, so that the segment has a second page header.
+        let lsn = Lsn(0x0300_4128);
+        let (_, mut stream) = bootstrap_wal_segment(lsn, PG_TLI, 42, 15, WAL_SEGMENT_SIZE)?;
+        let mut streamed = Vec::new();
+        stream.read_to_end(&mut streamed).await?;
+
+        let generated = postgres_ffi::generate_wal_segment(
+            lsn.segment_number(WAL_SEGMENT_SIZE),
+            42,
+            15,
+            PG_TLI,
+            lsn,
+        )?;
+        assert_eq!(streamed.len(), WAL_SEGMENT_SIZE);
+        assert!(streamed == generated[..], "streamed segment differs");
+        Ok(())
+    }
+
+    #[test]
+    fn tar_headers_are_reproducible() -> anyhow::Result<()> {
+        let first = new_tar_header("base/1/1259", 8192)?;
+        let second = new_tar_header("base/1/1259", 8192)?;
+        assert_eq!(first.as_bytes(), second.as_bytes());
+        assert_eq!(first.mtime()?, BASEBACKUP_MTIME);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn extracted_permissions() -> anyhow::Result<()> {
+        use std::os::unix::fs::PermissionsExt;
+
+        let mut tarball = Vec::new();
+        let mut builder = Builder::new_non_terminated(&mut tarball);
+        builder
+            .append(&new_tar_header_dir("base")?, &mut io::empty())
+            .await?;
+        builder
+            .append(&new_tar_header("base/PG_VERSION", 2)?, &b"15"[..])
+            .await?;
+        builder.into_inner().await?;
+
+        let target = tempfile::tempdir()?;
+        tokio_tar::Archive::new(&tarball[..])
+            .unpack(target.path())
+            .await?;
+
+        let mode = |path: &str| -> anyhow::Result<u32> {
+            Ok(std::fs::metadata(target.path().join(path))?
+                .permissions()
+                .mode()
+                & 0o777)
+        };
+        assert_eq!(mode("base")?, 0o700);
+        assert_eq!(mode("base/PG_VERSION")?, 0o600);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn interleaved_slru_pages() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("interleaved_slru_pages")?
+            .load()
+            .await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+
+        let page = |segno: u32, blknum: u32| {
+            Bytes::from(vec![(segno * 2 + blknum + 1) as u8; BLCKSZ as usize])
+        };
+        let mut m = tline.begin_modification(Lsn(0x20));
+        for segno in [0, 1] {
+            m.put_slru_segment_creation(SlruKind::Clog, segno, 2, &ctx)
+                .await?;
+        }
+        // The pages of the two segments are written in turns.
+        for (segno, blknum) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+            m.put_slru_page_image(SlruKind::Clog, segno, blknum, page(segno, blknum))?;
+        }
+        m.commit().await?;
+
+        let (stats, files) =
+            run_test_basebackup(&tline, &ctx, TestBasebackup::default(), |basebackup| {
+                Box::pin(async move {
+                    basebackup.add_slru_segments().await?;
+                    Ok(basebackup.stats.clone())
+                })
+            })
+            .await?;
+        assert_eq!(stats.slru_segments, 2);
+        assert_eq!(stats.files_written, 2);
+        assert_eq!(stats.bytes_written, 4 * BLCKSZ as u64);
+        for segno in [0, 1] {
+            let expected = [page(segno, 0), page(segno, 1)].concat();
+            assert_eq!(files.get(&format!("pg_xact/{segno:04X}")), Some(&expected));
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn best_effort_skips_missing_files() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("best_effort_skips_missing_files")?
+            .load()
+            .await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+
+        // The pages of segment 1 are missing.
+        let page = Bytes::from(vec![1; BLCKSZ as usize]);
+        let mut m = tline.begin_modification(Lsn(0x20));
+        for segno in [0, 1] {
+            m.put_slru_segment_creation(SlruKind::Clog, segno, 1, &ctx)
+                .await?;
+        }
+        m.put_slru_page_image(SlruKind::Clog, 0, 0, page.clone())?;
+        m.commit().await?;
+
+        for best_effort in [false, true] {
+            let settings = TestBasebackup {
+                best_effort,
+                ..TestBasebackup::default()
+            };
+            let added = run_test_basebackup(&tline, &ctx, settings, |basebackup| {
+                Box::pin(async move {
+                    basebackup.add_slru_segments().await?;
+                    Ok(basebackup.stats.skipped_files)
+                })
+            })
+            .await;
+            if !best_effort {
+                assert!(added.is_err());
+                continue;
+            }
+            let (skipped_files, files) = added?;
+            assert_eq!(skipped_files, 1);
+            assert_eq!(files.get("pg_xact/0000"), Some(&page.to_vec()));
+            assert!(!files.contains_key("pg_xact/0001"));
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn twophase_file_checks() {
+        let mut img = Vec::new();
+        img.extend_from_slice(&TWOPHASE_MAGIC.to_le_bytes());
+        img.extend_from_slice(&20u32.to_le_bytes());
+        img.extend_from_slice(&[7; 8]);
+
+        let file = twophase_file_contents(100, &img).unwrap();
+        assert_eq!(file.len(), 20);
+        assert_eq!(file[16..], crc32c::crc32c(&img).to_le_bytes());
+
+        let err = twophase_file_contents(100, &img[..12]).unwrap_err();
+        assert!(err.to_string().contains("transaction 100"), "{err}");
+
+        img[0] = 0;
+        assert!(twophase_file_contents(100, &img).is_err());
+    }
+
+    #[test]
+    fn control_file_ahead_of_lsn() {
+        check_control_file_lsns(Lsn(0x200), Lsn(0x100), Lsn(0x200)).unwrap();
+
+        let err = check_control_file_lsns(Lsn(0x200), Lsn(0x300), Lsn(0x100)).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("0/200") && msg.contains("0/300"), "{msg}");
+
+        let err = check_control_file_lsns(Lsn(0x200), Lsn(0x100), Lsn(0x280)).unwrap_err();
+        let msg = err.to_string();
+        assert!(msg.contains("0/200") && msg.contains("0/280"), "{msg}");
+    }
+
+    #[tokio::test]
+    async fn unlogged_relations() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("unlogged_relations")?.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+
+        let unlogged = RelTag {
+            spcnode: DEFAULTTABLESPACE_OID,
+            dbnode: 111,
+            relnode: 1000,
+            forknum: MAIN_FORKNUM,
+        };
+        let logged = RelTag {
+            relnode: 1001,
+            ..unlogged
+        };
+        let page = |byte: u8| Bytes::from(vec![byte; BLCKSZ as usize]);
+        let mut m = tline.begin_modification(Lsn(0x20));
+        for (rel, byte) in [
+            (unlogged, 1),
+            (unlogged.with_forknum(INIT_FORKNUM), 2),
+            (logged, 3),
+        ] {
+            m.put_rel_creation(rel, 1, &ctx).await?;
+            m.put_rel_page_image(rel, 0, page(byte))?;
+        }
+        m.commit().await?;
+
+        for full_backup in [false, true] {
+            let settings = TestBasebackup {
+                full_backup,
+                ..TestBasebackup::default()
+            };
+            let ((), files) = run_test_basebackup(&tline, &ctx, settings, |basebackup| {
+                Box::pin(basebackup.add_rels(DEFAULTTABLESPACE_OID, 111))
+            })
+            .await?;
+
+            // The main fork is reset to the init fork, whatever was written to it.
+            assert_eq!(files.get("base/111/1000_init"), Some(&page(2).to_vec()));
+            assert_eq!(files.get("base/111/1000"), Some(&page(2).to_vec()));
+            let expected_logged = full_backup.then(|| page(3).to_vec());
+            assert_eq!(files.get("base/111/1001"), expected_logged.as_ref());
+            assert_eq!(files.len(), if full_backup { 3 } else { 2 });
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn new_database_without_relmap_file() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("new_database_without_relmap_file")?
+            .load()
+            .await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+
+        // A database just created with the WAL_LOG strategy: its relations are logged, its
+        // relmap file not yet.
+        let rel = RelTag {
+            spcnode: DEFAULTTABLESPACE_OID,
+            dbnode: 111,
+            relnode: 1000,
+            forknum: MAIN_FORKNUM,
+        };
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(rel, 1, &ctx).await?;
+        m.put_rel_page_image(rel, 0, Bytes::from(vec![1; BLCKSZ as usize]))?;
+        m.commit().await?;
+
+        let ((), files) =
+            run_test_basebackup(&tline, &ctx, TestBasebackup::default(), |basebackup| {
+                Box::pin(basebackup.add_dbdir(DEFAULTTABLESPACE_OID, 111, false))
+            })
+            .await?;
+        assert_eq!(
+            files.get("base/111/PG_VERSION"),
+            Some(&DEFAULT_PG_VERSION.to_string().into_bytes())
+        );
+        assert!(!files.contains_key("base/111/pg_filenode.map"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn database_filter() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("database_filter")?.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+
+        // A system database and two user ones.
+        let mut m = tline.begin_modification(Lsn(0x20));
+        for dbnode in [5, 16384, 16385] {
+            let rel = RelTag {
+                spcnode: DEFAULTTABLESPACE_OID,
+                dbnode,
+                relnode: 1000,
+                forknum: MAIN_FORKNUM,
+            };
+            m.put_rel_creation(rel, 1, &ctx).await?;
+            m.put_rel_page_image(rel, 0, Bytes::from(vec![1; BLCKSZ as usize]))?;
+        }
+        m.commit().await?;
+
+        for (databases, expected) in [
+            (DatabaseFilter::All, vec![5, 16384, 16385]),
+            (
+                DatabaseFilter::Include(HashSet::from([16385])),
+                vec![5, 16385],
+            ),
+            (
+                DatabaseFilter::Exclude(HashSet::from([16385])),
+                vec![5, 16384],
+            ),
+            // The system databases can't be left out.
+            (
+                DatabaseFilter::Exclude(HashSet::from([5])),
+                vec![5, 16384, 16385],
+            ),
+        ] {
+            let settings = TestBasebackup {
+                full_backup: true,
+                databases: databases.clone(),
+                ..TestBasebackup::default()
+            };
+            let (skipped_databases, files) =
+                run_test_basebackup(&tline, &ctx, settings, |basebackup| {
+                    Box::pin(async move {
+                        basebackup.add_databases().await?;
+                        Ok(basebackup.stats.skipped_databases)
+                    })
+                })
+                .await?;
+            assert_eq!(skipped_databases, 3 - expected.len());
+            for dbnode in [5, 16384, 16385] {
+                assert_eq!(
+                    files.contains_key(&format!("base/{dbnode}/1000")),
+                    expected.contains(&dbnode),
+                    "{databases:?}"
+                );
+                assert_eq!(
+                    files.contains_key(&format!("base/{dbnode}/PG_VERSION")),
+                    expected.contains(&dbnode),
+                    "{databases:?}"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn cancelled_basebackup_stops() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("cancelled_basebackup_stops")?
+            .load()
+            .await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+
+        let rel = RelTag {
+            spcnode: DEFAULTTABLESPACE_OID,
+            dbnode: 111,
+            relnode: 1000,
+            forknum: MAIN_FORKNUM,
+        };
+        let mut m = tline.begin_modification(Lsn(0x20));
+        m.put_rel_creation(rel, 2, &ctx).await?;
+        for blknum in 0..2 {
+            m.put_rel_page_image(rel, blknum, Bytes::from(vec![1; BLCKSZ as usize]))?;
+        }
+        m.commit().await?;
+
+        let cancel = CancellationToken::new();
+        let settings = TestBasebackup {
+            full_backup: true,
+            cancel: cancel.clone(),
+            ..TestBasebackup::default()
+        };
+        run_test_basebackup(&tline, &ctx, settings, move |basebackup| {
+            Box::pin(async move {
+                basebackup.add_rels(DEFAULTTABLESPACE_OID, 111).await?;
+                assert_eq!(basebackup.stats.files_written, 1);
+
+                cancel.cancel();
+                let err = basebackup
+                    .add_rels(DEFAULTTABLESPACE_OID, 111)
+                    .await
+                    .unwrap_err();
+                assert!(err.is::<BasebackupCancelled>(), "{err:#}");
+                // Stopped before the relation was appended again.
+                assert_eq!(basebackup.stats.files_written, 1);
+                Ok(())
+            })
+        })
+        .await?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn basebackup_behind_gc_cutoff() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("basebackup_behind_gc_cutoff")?;
+        let (tenant, ctx) = harness.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+        tline
+            .latest_gc_cutoff_lsn
+            .lock_for_write()
+            .store_and_unlock(Lsn(0x30))
+            .wait();
+
+        let mut tarball = Vec::new();
+        let err = send_basebackup_tarball(
+            harness.conf,
+            &mut tarball,
+            &tline,
+            Some(Lsn(0x20)),
+            None,
+            false,
+            None,
+            BasebackupCompression::None,
+            false,
+            &DatabaseFilter::All,
+            &ctx,
+            &CancellationToken::new(),
+        )
+        .await
+        .unwrap_err();
+        assert!(format!("{err:#}").contains("0/20"), "{err:#}");
+        assert!(tarball.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn incremental_rel_changes() -> anyhow::Result<()> {
+        let (tenant, ctx) = TenantHarness::create("incremental_rel_changes")?
+            .load()
+            .await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+
+        let rel = |relnode| RelTag {
+            spcnode: DEFAULTTABLESPACE_OID,
+            dbnode: 111,
+            relnode,
+            forknum: MAIN_FORKNUM,
+        };
+        let page = |byte: u8| Bytes::from(vec![byte; BLCKSZ as usize]);
+        let mut m = tline.begin_modification(Lsn(0x20));
+        for relnode in [1000, 1001, 1002] {
+            m.put_rel_creation(rel(relnode), 3, &ctx).await?;
+            for blknum in 0..3 {
+                m.put_rel_page_image(rel(relnode), blknum, page(1))?;
+            }
+        }
+        m.commit().await?;
+
+        // 1000 is unchanged, block 1 of 1001 is written, and 1002 is truncated and extended
+        // again, so its block 1 is sent even though it isn't written.
+        let mut m = tline.begin_modification(Lsn(0x30));
+        m.put_rel_page_image(rel(1001), 1, page(2))?;
+        m.put_rel_truncation(rel(1002), 1, &ctx).await?;
+        m.commit().await?;
+        let mut m = tline.begin_modification(Lsn(0x40));
+        m.put_rel_extend(rel(1002), 2, &ctx).await?;
+        m.commit().await?;
+
+        let rel_changes = tline.get_rel_changes(Lsn(0x20), Lsn(0x40), &ctx).await?;
+        let settings = TestBasebackup {
+            lsn: Lsn(0x40),
+            full_backup: true,
+            incremental: Some(Incremental {
+                rel_changes,
+                manifest: IncrementalManifest {
+                    since_lsn: Lsn(0x20),
+                    lsn: Lsn(0x40),
+                    files: Vec::new(),
+                },
+            }),
+            ..TestBasebackup::default()
+        };
+        let (manifest, files) = run_test_basebackup(&tline, &ctx, settings, |basebackup| {
+            Box::pin(async move {
+                basebackup.add_rels(DEFAULTTABLESPACE_OID, 111).await?;
+                Ok(basebackup.incremental.take().unwrap().manifest)
+            })
+        })
+        .await?;
+
+        let file = |path: &str, nblocks, changed_blocks: Vec<u32>| IncrementalFile {
+            path: path.to_string(),
+            nblocks,
+            changed_blocks,
+        };
+        assert_eq!(
+            manifest.files,
+            vec![
+                file("base/111/1000", 3, vec![]),
+                file("base/111/1001", 3, vec![1]),
+                file("base/111/1002", 2, vec![1]),
+            ]
+        );
+        assert_eq!(files.len(), 2);
+        assert_eq!(files.get("base/111/1001.delta"), Some(&page(2).to_vec()));
+        assert_eq!(files["base/111/1002.delta"].len(), BLCKSZ as usize);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn pg_control_counters() -> anyhow::Result<()> {
+        use postgres_ffi::v15::bindings::{
+            CheckPoint, ControlFileData, FullTransactionId, CATALOG_VERSION_NO, PG_CONTROL_VERSION,
+        };
+
+        let (tenant, ctx) = TenantHarness::create("pg_control_counters")?.load().await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+
+        // As of the initdb: the control file is never updated afterwards.
+        let control_file = ControlFileData {
+            system_identifier: 42,
+            pg_control_version: PG_CONTROL_VERSION,
+            catalog_version_no: CATALOG_VERSION_NO,
+            xlog_seg_size: WAL_SEGMENT_SIZE as u32,
+            checkPointCopy: CheckPoint {
+                nextXid: FullTransactionId { value: 3 },
+                nextOid: 10000,
+                nextMulti: 1,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        // The counters the WAL ingestion keeps in the checkpoint, as of each LSN.
+        let counters = [
+            (Lsn(0x0100_0028), 1024, 20000, 5),
+            (Lsn(0x0100_0100), 2048, 30000, 7),
+        ];
+        for (lsn, next_xid, next_oid, next_multi) in counters {
+            let checkpoint = CheckPoint {
+                nextXid: FullTransactionId { value: next_xid },
+                nextOid: next_oid,
+                nextMulti: next_multi,
+                ..control_file.checkPointCopy
+            };
+            let mut m = tline.begin_modification(lsn);
+            m.put_control_file(control_file.encode())?;
+            m.put_checkpoint(checkpoint.encode()?)?;
+            m.commit().await?;
+        }
+
+        for (lsn, next_xid, next_oid, next_multi) in counters {
+            let settings = TestBasebackup {
+                lsn,
+                ..TestBasebackup::default()
+            };
+            let ((), files) = run_test_basebackup(&tline, &ctx, settings, |basebackup| {
+                Box::pin(basebackup.add_pgcontrol_file())
+            })
+            .await?;
+            let pg_control = ControlFileData::decode(&files["global/pg_control"])?;
+            assert_eq!(pg_control.checkPointCopy.nextXid.value, next_xid);
+            assert_eq!(pg_control.checkPointCopy.nextOid, next_oid);
+            assert_eq!(pg_control.checkPointCopy.nextMulti, next_multi);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn non_default_wal_segment_size() -> anyhow::Result<()> {
+        use postgres_ffi::v15::bindings::{
+            CheckPoint, ControlFileData, CATALOG_VERSION_NO, PG_CONTROL_VERSION,
+        };
+        const WAL_SEG_SIZE: usize = 64 * 1024 * 1024;
+
+        let (tenant, ctx) = TenantHarness::create("non_default_wal_segment_size")?
+            .load()
+            .await;
+        let tline = tenant
+            .create_test_timeline(TIMELINE_ID, Lsn(8), DEFAULT_PG_VERSION, RegionId(0), &ctx)
+            .await?;
+
+        // In the second 64MB segment, but in the fifth of the default size.
+        let lsn = Lsn(0x0500_0128);
+        let control_file = ControlFileData {
+            system_identifier: 42,
+            pg_control_version: PG_CONTROL_VERSION,
+            catalog_version_no: CATALOG_VERSION_NO,
+            xlog_seg_size: WAL_SEG_SIZE as u32,
+            ..Default::default()
+        };
+        let mut m = tline.begin_modification(lsn);
+        m.put_control_file(control_file.encode())?;
+        m.put_checkpoint(CheckPoint::default().encode()?)?;
+        m.commit().await?;
+
+        let settings = TestBasebackup {
+            lsn,
+            ..TestBasebackup::default()
+        };
+        let ((), files) = run_test_basebackup(&tline, &ctx, settings, |basebackup| {
+            Box::pin(basebackup.add_pgcontrol_file())
+        })
+        .await?;
+        let segment = &files["pg_wal/000000010000000000000001"];
+        assert_eq!(segment.len(), WAL_SEG_SIZE);
+        let header = XLogLongPageHeaderData::from_bytes(&mut &segment[..])?;
+        assert_eq!(header.xlp_seg_size, WAL_SEG_SIZE as u32);
+        assert_eq!(header.std.xlp_pageaddr, 0x0400_0000);
+        // The page the compute starts on is where it is in a 64MB segment.
+        let page = lsn.page_offset_in_segment(WAL_SEG_SIZE) as usize;
+        let header = XLogPageHeaderData::from_bytes(&mut &segment[page..])?;
+        assert_eq!(header.xlp_pageaddr, lsn.page_lsn().0);
+
+        // The redo point of the control file is in the same segment.
+        let pg_control = ControlFileData::decode(&files["global/pg_control"])?;
+        assert_eq!(
+            Lsn(pg_control.checkPointCopy.redo).segment_number(WAL_SEG_SIZE),
+            1
+        );
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn spill_buffer() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let contents: Vec<u8> = (0..10_000u32).map(|i| i as u8).collect();
+        let spill_files = || std::fs::read_dir(temp_dir.path()).unwrap().count();
+
+        for (max_in_memory, spills) in [(10_000, false), (4096, true), (1, true)] {
+            let mut spill = SpillBuffer::new(temp_dir.path(), max_in_memory);
+            // Nothing is created for the basebackups that fit in memory.
+            assert_eq!(spill_files(), 0);
+            for chunk in contents.chunks(1000) {
+                spill.write_all(chunk).await?;
+            }
+            assert_eq!(spill.spilled, spills, "max_in_memory {max_in_memory}");
+            assert_eq!(spill_files(), usize::from(spills));
+
+            let mut sent = Vec::new();
+            spill.send(&mut sent).await?;
+            assert_eq!(sent, contents, "max_in_memory {max_in_memory}");
+            assert_eq!(spill_files(), 0);
+        }
+
+        let wait_for_removal = || {
+            tokio::time::timeout(Duration::from_secs(10), async {
+                while spill_files() > 0 {
+                    tokio::time::sleep(Duration::from_millis(10)).await;
+                }
+            })
+        };
+
+        // A basebackup that fails before it's sent doesn't leave the file behind either,
+        // it's removed in the background.
+        let mut spill = SpillBuffer::new(temp_dir.path(), 1);
+        spill.write_all(&contents).await?;
+        assert_eq!(spill_files(), 1);
+        drop(spill);
+        wait_for_removal().await?;
+
+        // Neither does a send that is cancelled, e.g. with the connection that is never read.
+        let mut spill = SpillBuffer::new(temp_dir.path(), 1);
+        spill.write_all(&contents).await?;
+        let (mut connection, _peer) = tokio::io::duplex(1000);
+        let sent = tokio::time::timeout(Duration::from_millis(100), spill.send(&mut connection));
+        assert!(
+            sent.await.is_err(),
+            "the send should block on the full connection"
+        );
+        wait_for_removal().await?;
+
+        // Off the runtime, the file is removed right away.
+        let mut spill = SpillBuffer::new(temp_dir.path(), 1);
+        spill.write_all(&contents).await?;
+        assert_eq!(spill_files(), 1);
+        std::thread::spawn(move || drop(spill)).join().unwrap();
+        assert_eq!(spill_files(), 0);
+        Ok(())
+    }
+
+    /// The settings of a test basebackup, [`TestBasebackup::default`] for the rest.
+    struct TestBasebackup {
+        lsn: Lsn,
+        full_backup: bool,
+        incremental: Option<Incremental>,
+        best_effort: bool,
+        databases: DatabaseFilter,
+        cancel: CancellationToken,
+    }
+
+    impl Default for TestBasebackup {
+        fn default() -> Self {
+            TestBasebackup {
+                lsn: Lsn(0x20),
+                full_backup: false,
+                incremental: None,
+                best_effort: false,
+                databases: DatabaseFilter::All,
+                cancel: CancellationToken::new(),
+            }
+        }
+    }
+
+    /// Runs `add` on a basebackup of `tline` with the `settings`. Returns what `add` returned
+    /// and the files of the tarball.
+    async fn run_test_basebackup<R>(
+        tline: &Timeline,
+        ctx: &RequestContext,
+        settings: TestBasebackup,
+        add: impl for<'a, 'b> FnOnce(
+            &'b mut Basebackup<'a, Vec<u8>>,
+        ) -> LocalBoxFuture<'b, anyhow::Result<R>>,
+    ) -> anyhow::Result<(R, HashMap<String, Vec<u8>>)> {
+        let mut tarball = Vec::new();
+        let mut basebackup = Basebackup {
+            ar: Builder::new_non_terminated(&mut tarball),
+            timeline: tline,
+            lsn: settings.lsn,
+            prev_record_lsn: Lsn(0),
+            full_backup: settings.full_backup,
+            incremental: settings.incremental,
+            best_effort: settings.best_effort,
+            databases: &settings.databases,
+            stats: BasebackupStats::new(TIMELINE_ID, settings.lsn, Lsn(0)),
+            ctx,
+            cancel: &settings.cancel,
+        };
+        let added = add(&mut basebackup).await?;
+        basebackup.ar.finish().await?;
+        drop(basebackup);
+        Ok((added, tarball_files(&tarball).await?))
+    }
+
+    /// Paths and contents of the files in a tarball.
+    async fn tarball_files(tarball: &[u8]) -> anyhow::Result<HashMap<String, Vec<u8>>> {
+        let mut files = HashMap::new();
+        let mut archive = tokio_tar::Archive::new(tarball);
+        let mut entries = archive.entries()?;
+        while let Some(entry) = entries.next().await {
+            let mut entry = entry?;
+            let path = entry.path()?.to_string_lossy().into_owned();
+            let mut contents = Vec::new();
+            entry.read_to_end(&mut contents).await?;
+            files.insert(path, contents);
+        }
+        Ok(files)
+    }
+
+    #[tokio::test]
+    async fn bootstrap_wal_segment_at_segment_boundary() -> anyhow::Result<()> {
+        // In the last page of a segment: the compute continues into the next segment on its
+        // own, the bootstrap segment only needs the header of the page it starts on.
+        let lsn = Lsn(0x0300_0000 - 0x100);
+        let (path, mut stream) = bootstrap_wal_segment(lsn, PG_TLI, 42, 15, WAL_SEGMENT_SIZE)?;
+        let mut segment = Vec::new();
+        stream.read_to_end(&mut segment).await?;
+        assert_eq!(path, "pg_wal/000000010000000000000002");
+        let last_page = WAL_SEGMENT_SIZE - XLOG_BLCKSZ;
+        let header = XLogPageHeaderData::from_bytes(&mut &segment[last_page..])?;
+        assert_eq!(header.xlp_pageaddr, 0x0300_0000 - XLOG_BLCKSZ as u64);
+        assert_eq!(header.xlp_rem_len, XLOG_BLCKSZ as u32 - 0x100);
+
+        // Right at the boundary, the compute starts in the next segment.
+        let (path, _) = bootstrap_wal_segment(Lsn(0x0300_0000), PG_TLI, 42, 15, WAL_SEGMENT_SIZE)?;
+        assert_eq!(path, "pg_wal/000000010000000000000003");
+        Ok(())
+    }
+}
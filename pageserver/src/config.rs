@@ -31,6 +31,7 @@ use utils::{
 use crate::disk_usage_eviction_task::DiskUsageEvictionTaskConfig;
 use crate::tenant::config::TenantConf;
 use crate::tenant::config::TenantConfOpt;
+use crate::tenant::remote_timeline_client::ChecksumAlgorithm;
 use crate::tenant::{
     TENANT_ATTACHING_MARKER_FILENAME, TENANT_DELETED_MARKER_FILE_NAME, TIMELINES_SEGMENT_NAME,
 };
@@ -70,6 +71,33 @@ pub mod defaults {
 
     pub const DEFAULT_INGEST_BATCH_SIZE: u64 = 100;
 
+    pub const DEFAULT_REMOTE_CHECKSUM_ALGORITHM: &str = "crc32c";
+
+    pub const DEFAULT_REMOTE_GC_RETAINED_LSNS: usize = 0;
+
+    pub const DEFAULT_REMOTE_INDEX_GENERATIONS: usize = 0;
+    pub const DEFAULT_REMOTE_REPAIR_MISSING_INDEX_PART: bool = false;
+
+    pub const DEFAULT_REMOTE_LIST_REFRESH_INTERVAL: &str = "0s";
+
+    pub const DEFAULT_REMOTE_OWNER_LEASE_TTL: &str = "0s";
+
+    pub const DEFAULT_REMOTE_UPLOAD_DEDUP: bool = false;
+    pub const DEFAULT_REMOTE_COALESCE_INDEX_UPLOADS: bool = false;
+
+    pub const DEFAULT_STRICT_METADATA_MERGE: bool = false;
+
+    pub const DEFAULT_REMOTE_DOWNLOAD_CHUNKS: usize = 1;
+    pub const DEFAULT_REMOTE_DOWNLOAD_CHUNK_MIN_SIZE: u64 = 256 * 1024 * 1024;
+    pub const DEFAULT_REMOTE_CHUNK_CHECKSUM_SIZE: u64 = 0;
+    pub const DEFAULT_REMOTE_LAYER_ARCHIVE_THRESHOLD: u64 = 0;
+    pub const DEFAULT_REMOTE_ZSTD_DICTIONARY_MAX_FILE_SIZE: u64 = 0;
+    pub const DEFAULT_FSYNC_DOWNLOADS: bool = true;
+
+    pub const DEFAULT_BASEBACKUP_SPILL_THRESHOLD: u64 = 0;
+
+    pub const DEFAULT_STARTUP_TENANT_COLLECTION_CONCURRENCY: usize = 16;
+
     ///
     /// Default built-in configuration file.
     ///
@@ -104,6 +132,26 @@ pub mod defaults {
 
 #ingest_batch_size = {DEFAULT_INGEST_BATCH_SIZE}
 
+#remote_checksum_algorithm = '{DEFAULT_REMOTE_CHECKSUM_ALGORITHM}'
+#remote_gc_retained_lsns = {DEFAULT_REMOTE_GC_RETAINED_LSNS}
+#remote_index_generations = {DEFAULT_REMOTE_INDEX_GENERATIONS}
+#remote_repair_missing_index_part = {DEFAULT_REMOTE_REPAIR_MISSING_INDEX_PART}
+#remote_list_refresh_interval = '{DEFAULT_REMOTE_LIST_REFRESH_INTERVAL}'
+#remote_upload_dedup = {DEFAULT_REMOTE_UPLOAD_DEDUP}
+#remote_coalesce_index_uploads = {DEFAULT_REMOTE_COALESCE_INDEX_UPLOADS}
+#strict_metadata_merge = {DEFAULT_STRICT_METADATA_MERGE}
+#remote_download_chunks = {DEFAULT_REMOTE_DOWNLOAD_CHUNKS}
+#remote_download_chunk_min_size = {DEFAULT_REMOTE_DOWNLOAD_CHUNK_MIN_SIZE}
+#remote_chunk_checksum_size = {DEFAULT_REMOTE_CHUNK_CHECKSUM_SIZE}
+#remote_layer_archive_threshold = {DEFAULT_REMOTE_LAYER_ARCHIVE_THRESHOLD}
+#remote_zstd_dictionary_max_file_size = {DEFAULT_REMOTE_ZSTD_DICTIONARY_MAX_FILE_SIZE}
+#sync_temp_dir = '/path/to/a/larger/volume'
+#fsync_downloads = {DEFAULT_FSYNC_DOWNLOADS}
+#basebackup_spill_threshold = {DEFAULT_BASEBACKUP_SPILL_THRESHOLD}
+#tenant_filter = ['0123*', 'abcd*']
+#remote_owner_lease_ttl = '{DEFAULT_REMOTE_OWNER_LEASE_TTL}'
+#startup_tenant_collection_concurrency = {DEFAULT_STARTUP_TENANT_COLLECTION_CONCURRENCY}
+
 [tenant_config]
 #checkpoint_distance = {DEFAULT_CHECKPOINT_DISTANCE} # in bytes
 #checkpoint_timeout = {DEFAULT_CHECKPOINT_TIMEOUT}
@@ -170,6 +218,9 @@ pub struct PageServerConf {
     pub auth_validation_public_key_path: Option<PathBuf>,
 
     pub remote_storage_config: Option<RemoteStorageConfig>,
+    /// The storages after the first one of a `remote_storage` list, that get a copy of what
+    /// is uploaded to the first one, see the `replica` module of the remote timeline client.
+    pub remote_storage_replicas: Vec<RemoteStorageConfig>,
 
     pub default_tenant_conf: TenantConf,
 
@@ -212,6 +263,83 @@ pub struct PageServerConf {
 
     /// Maximum number of WAL records to be ingested and committed at the same time
     pub ingest_batch_size: u64,
+
+    /// Checksum to store along with every file uploaded to the remote storage,
+    /// the downloads are verified against it.
+    pub remote_checksum_algorithm: ChecksumAlgorithm,
+
+    /// How many of the most recent `disk_consistent_lsn`s uploaded to the remote storage
+    /// should stay restorable: the layers removed from the timeline are deleted remotely
+    /// only once they are not needed for any of those. The index parts of those LSNs are kept
+    /// as with `remote_index_generations`, to restore them from. 0 deletes them right away.
+    pub remote_gc_retained_lsns: usize,
+
+    /// How many of the most recent index parts to keep in the remote storage as files of their
+    /// own, named after their `disk_consistent_lsn`, next to the latest `index_part.json`.
+    /// Their layers are retained as with `remote_gc_retained_lsns`. 0 keeps only the latest.
+    pub remote_index_generations: usize,
+
+    /// Rebuild the index part of a timeline whose layers are in the remote storage without
+    /// one, from the newest of its index generations and the layers uploaded after it.
+    pub remote_repair_missing_index_part: bool,
+
+    /// How often to list the tenant's timelines in the remote storage, to pick up the ones
+    /// that appeared there after the tenant was attached. Zero lists them only on attach.
+    pub remote_list_refresh_interval: Duration,
+
+    /// Copy the layer files already uploaded with the same contents within the remote storage,
+    /// instead of uploading them again.
+    pub remote_upload_dedup: bool,
+
+    /// Drop a queued index upload that hasn't started yet when a newer one is scheduled, as
+    /// long as only layer uploads were queued in between. The newer one lists all the same.
+    pub remote_coalesce_index_uploads: bool,
+
+    /// Fail to load a timeline whose local and remote metadata disagree, instead of
+    /// loading it from the one with the highest `disk_consistent_lsn`.
+    pub strict_metadata_merge: bool,
+
+    /// How many byte ranges of a large layer file to download at once. 1 downloads every
+    /// layer in a single request.
+    pub remote_download_chunks: usize,
+    /// Layer files smaller than this, in bytes, are always downloaded in a single request.
+    pub remote_download_chunk_min_size: u64,
+    /// Layer files larger than this, in bytes, are uploaded along with the checksums of their
+    /// chunks of this size, to verify the byte ranges of a download one by one. 0 uploads none.
+    pub remote_chunk_checksum_size: u64,
+
+    /// Layer files smaller than this, in bytes, are uploaded together in a single archive object
+    /// with the other small layers of the same index upload. 0 uploads every layer on its own.
+    pub remote_layer_archive_threshold: u64,
+
+    /// Layer files of at most this many bytes are compressed with a zstd dictionary trained on
+    /// the tenant's small layers, if the remote storage compresses with zstd. 0 trains none.
+    pub remote_zstd_dictionary_max_file_size: u64,
+
+    /// Directory to download the layer files into before they're moved into the timeline
+    /// directory, possibly on another filesystem. The timeline directory itself if not set.
+    pub sync_temp_dir: Option<PathBuf>,
+
+    /// Whether a downloaded layer file, and the directory it's moved into, are fsynced before
+    /// the layer is used. Off only for tests and ephemeral setups, where a crash loses the
+    /// local files anyway.
+    pub fsync_downloads: bool,
+
+    /// Basebackups are produced in full before they're sent, in memory up to this many bytes
+    /// and in a temporary file beyond that. 0 streams them to the client as they're produced.
+    pub basebackup_spill_threshold: u64,
+
+    /// The tenants to restore from the remote storage, all of them if not set. The others are
+    /// only loaded if they have local files, without their remote-only timelines.
+    pub tenant_filter: Option<TenantFilter>,
+
+    /// How long the tenant's owner lease in the remote storage stays valid without a heartbeat.
+    /// Uploads wait while another pageserver holds a valid one. Zero disables the leases.
+    pub remote_owner_lease_ttl: Duration,
+
+    /// How many local tenant directories are checked and have their config read at once at
+    /// startup, each on a blocking thread, before their load tasks are spawned.
+    pub startup_tenant_collection_concurrency: NonZeroUsize,
 }
 
 /// We do not want to store this in a PageServerConf because the latter may be logged
@@ -264,6 +392,7 @@ struct PageServerConfigBuilder {
     //
     auth_validation_public_key_path: BuilderValue<Option<PathBuf>>,
     remote_storage_config: BuilderValue<Option<RemoteStorageConfig>>,
+    remote_storage_replicas: BuilderValue<Vec<RemoteStorageConfig>>,
 
     id: BuilderValue<NodeId>,
 
@@ -288,6 +417,38 @@ struct PageServerConfigBuilder {
     background_task_maximum_delay: BuilderValue<Duration>,
 
     ingest_batch_size: BuilderValue<u64>,
+
+    remote_checksum_algorithm: BuilderValue<ChecksumAlgorithm>,
+
+    remote_gc_retained_lsns: BuilderValue<usize>,
+
+    remote_index_generations: BuilderValue<usize>,
+    remote_repair_missing_index_part: BuilderValue<bool>,
+
+    remote_list_refresh_interval: BuilderValue<Duration>,
+
+    remote_upload_dedup: BuilderValue<bool>,
+    remote_coalesce_index_uploads: BuilderValue<bool>,
+
+    strict_metadata_merge: BuilderValue<bool>,
+
+    remote_download_chunks: BuilderValue<usize>,
+    remote_download_chunk_min_size: BuilderValue<u64>,
+    remote_chunk_checksum_size: BuilderValue<u64>,
+
+    remote_layer_archive_threshold: BuilderValue<u64>,
+    remote_zstd_dictionary_max_file_size: BuilderValue<u64>,
+
+    sync_temp_dir: BuilderValue<Option<PathBuf>>,
+    fsync_downloads: BuilderValue<bool>,
+
+    basebackup_spill_threshold: BuilderValue<u64>,
+
+    tenant_filter: BuilderValue<Option<TenantFilter>>,
+
+    remote_owner_lease_ttl: BuilderValue<Duration>,
+
+    startup_tenant_collection_concurrency: BuilderValue<NonZeroUsize>,
 }
 
 impl Default for PageServerConfigBuilder {
@@ -313,6 +474,7 @@ impl Default for PageServerConfigBuilder {
             pg_auth_type: Set(AuthType::Trust),
             auth_validation_public_key_path: Set(None),
             remote_storage_config: Set(None),
+            remote_storage_replicas: Set(Vec::new()),
             id: NotSet,
             broker_endpoint: Set(storage_broker::DEFAULT_ENDPOINT
                 .parse()
@@ -352,6 +514,48 @@ impl Default for PageServerConfigBuilder {
             .unwrap()),
 
             ingest_batch_size: Set(DEFAULT_INGEST_BATCH_SIZE),
+
+            remote_checksum_algorithm: Set(ChecksumAlgorithm::from_str(
+                DEFAULT_REMOTE_CHECKSUM_ALGORITHM,
+            )
+            .expect("cannot parse default remote checksum algorithm")),
+
+            remote_gc_retained_lsns: Set(DEFAULT_REMOTE_GC_RETAINED_LSNS),
+
+            remote_index_generations: Set(DEFAULT_REMOTE_INDEX_GENERATIONS),
+            remote_repair_missing_index_part: Set(DEFAULT_REMOTE_REPAIR_MISSING_INDEX_PART),
+
+            remote_list_refresh_interval: Set(humantime::parse_duration(
+                DEFAULT_REMOTE_LIST_REFRESH_INTERVAL,
+            )
+            .expect("cannot parse default remote list refresh interval")),
+
+            remote_upload_dedup: Set(DEFAULT_REMOTE_UPLOAD_DEDUP),
+            remote_coalesce_index_uploads: Set(DEFAULT_REMOTE_COALESCE_INDEX_UPLOADS),
+
+            strict_metadata_merge: Set(DEFAULT_STRICT_METADATA_MERGE),
+
+            remote_download_chunks: Set(DEFAULT_REMOTE_DOWNLOAD_CHUNKS),
+            remote_download_chunk_min_size: Set(DEFAULT_REMOTE_DOWNLOAD_CHUNK_MIN_SIZE),
+            remote_chunk_checksum_size: Set(DEFAULT_REMOTE_CHUNK_CHECKSUM_SIZE),
+
+            remote_layer_archive_threshold: Set(DEFAULT_REMOTE_LAYER_ARCHIVE_THRESHOLD),
+            remote_zstd_dictionary_max_file_size: Set(DEFAULT_REMOTE_ZSTD_DICTIONARY_MAX_FILE_SIZE),
+
+            sync_temp_dir: Set(None),
+            fsync_downloads: Set(DEFAULT_FSYNC_DOWNLOADS),
+
+            basebackup_spill_threshold: Set(DEFAULT_BASEBACKUP_SPILL_THRESHOLD),
+
+            tenant_filter: Set(None),
+
+            remote_owner_lease_ttl: Set(humantime::parse_duration(DEFAULT_REMOTE_OWNER_LEASE_TTL)
+                .expect("cannot parse default remote owner lease ttl")),
+
+            startup_tenant_collection_concurrency: Set(NonZeroUsize::new(
+                DEFAULT_STARTUP_TENANT_COLLECTION_CONCURRENCY,
+            )
+            .expect("default startup tenant collection concurrency is positive")),
         }
     }
 }
@@ -416,6 +620,10 @@ impl PageServerConfigBuilder {
         self.remote_storage_config = BuilderValue::Set(remote_storage_config)
     }
 
+    pub fn remote_storage_replicas(&mut self, remote_storage_replicas: Vec<RemoteStorageConfig>) {
+        self.remote_storage_replicas = BuilderValue::Set(remote_storage_replicas)
+    }
+
     pub fn broker_endpoint(&mut self, broker_endpoint: Uri) {
         self.broker_endpoint = BuilderValue::Set(broker_endpoint)
     }
@@ -484,6 +692,86 @@ impl PageServerConfigBuilder {
         self.ingest_batch_size = BuilderValue::Set(ingest_batch_size)
     }
 
+    pub fn remote_checksum_algorithm(&mut self, remote_checksum_algorithm: ChecksumAlgorithm) {
+        self.remote_checksum_algorithm = BuilderValue::Set(remote_checksum_algorithm)
+    }
+
+    pub fn remote_gc_retained_lsns(&mut self, remote_gc_retained_lsns: usize) {
+        self.remote_gc_retained_lsns = BuilderValue::Set(remote_gc_retained_lsns)
+    }
+
+    pub fn remote_index_generations(&mut self, remote_index_generations: usize) {
+        self.remote_index_generations = BuilderValue::Set(remote_index_generations)
+    }
+
+    pub fn remote_repair_missing_index_part(&mut self, remote_repair_missing_index_part: bool) {
+        self.remote_repair_missing_index_part = BuilderValue::Set(remote_repair_missing_index_part)
+    }
+
+    pub fn remote_list_refresh_interval(&mut self, remote_list_refresh_interval: Duration) {
+        self.remote_list_refresh_interval = BuilderValue::Set(remote_list_refresh_interval)
+    }
+
+    pub fn remote_upload_dedup(&mut self, remote_upload_dedup: bool) {
+        self.remote_upload_dedup = BuilderValue::Set(remote_upload_dedup)
+    }
+
+    pub fn remote_coalesce_index_uploads(&mut self, remote_coalesce_index_uploads: bool) {
+        self.remote_coalesce_index_uploads = BuilderValue::Set(remote_coalesce_index_uploads)
+    }
+
+    pub fn strict_metadata_merge(&mut self, strict_metadata_merge: bool) {
+        self.strict_metadata_merge = BuilderValue::Set(strict_metadata_merge)
+    }
+
+    pub fn remote_download_chunks(&mut self, remote_download_chunks: usize) {
+        self.remote_download_chunks = BuilderValue::Set(remote_download_chunks)
+    }
+
+    pub fn remote_download_chunk_min_size(&mut self, remote_download_chunk_min_size: u64) {
+        self.remote_download_chunk_min_size = BuilderValue::Set(remote_download_chunk_min_size)
+    }
+
+    pub fn remote_chunk_checksum_size(&mut self, remote_chunk_checksum_size: u64) {
+        self.remote_chunk_checksum_size = BuilderValue::Set(remote_chunk_checksum_size)
+    }
+
+    pub fn remote_layer_archive_threshold(&mut self, remote_layer_archive_threshold: u64) {
+        self.remote_layer_archive_threshold = BuilderValue::Set(remote_layer_archive_threshold)
+    }
+
+    pub fn remote_zstd_dictionary_max_file_size(&mut self, max_file_size: u64) {
+        self.remote_zstd_dictionary_max_file_size = BuilderValue::Set(max_file_size)
+    }
+
+    pub fn sync_temp_dir(&mut self, sync_temp_dir: Option<PathBuf>) {
+        self.sync_temp_dir = BuilderValue::Set(sync_temp_dir)
+    }
+
+    pub fn fsync_downloads(&mut self, fsync_downloads: bool) {
+        self.fsync_downloads = BuilderValue::Set(fsync_downloads)
+    }
+
+    pub fn basebackup_spill_threshold(&mut self, basebackup_spill_threshold: u64) {
+        self.basebackup_spill_threshold = BuilderValue::Set(basebackup_spill_threshold)
+    }
+
+    pub fn tenant_filter(&mut self, tenant_filter: Option<TenantFilter>) {
+        self.tenant_filter = BuilderValue::Set(tenant_filter)
+    }
+
+    pub fn remote_owner_lease_ttl(&mut self, remote_owner_lease_ttl: Duration) {
+        self.remote_owner_lease_ttl = BuilderValue::Set(remote_owner_lease_ttl)
+    }
+
+    pub fn startup_tenant_collection_concurrency(
+        &mut self,
+        startup_tenant_collection_concurrency: NonZeroUsize,
+    ) {
+        self.startup_tenant_collection_concurrency =
+            BuilderValue::Set(startup_tenant_collection_concurrency)
+    }
+
     pub fn build(self) -> anyhow::Result<PageServerConf> {
         let concurrent_tenant_size_logical_size_queries = self
             .concurrent_tenant_size_logical_size_queries
@@ -527,6 +815,9 @@ impl PageServerConfigBuilder {
             remote_storage_config: self
                 .remote_storage_config
                 .ok_or(anyhow!("missing remote_storage_config"))?,
+            remote_storage_replicas: self
+                .remote_storage_replicas
+                .ok_or(anyhow!("missing remote_storage_replicas"))?,
             id: self.id.ok_or(anyhow!("missing id"))?,
             // TenantConf is handled separately
             default_tenant_conf: TenantConf::default(),
@@ -572,6 +863,59 @@ impl PageServerConfigBuilder {
             ingest_batch_size: self
                 .ingest_batch_size
                 .ok_or(anyhow!("missing ingest_batch_size"))?,
+            remote_checksum_algorithm: self
+                .remote_checksum_algorithm
+                .ok_or(anyhow!("missing remote_checksum_algorithm"))?,
+            remote_gc_retained_lsns: self
+                .remote_gc_retained_lsns
+                .ok_or(anyhow!("missing remote_gc_retained_lsns"))?,
+            remote_index_generations: self
+                .remote_index_generations
+                .ok_or(anyhow!("missing remote_index_generations"))?,
+            remote_repair_missing_index_part: self
+                .remote_repair_missing_index_part
+                .ok_or(anyhow!("missing remote_repair_missing_index_part"))?,
+            remote_list_refresh_interval: self
+                .remote_list_refresh_interval
+                .ok_or(anyhow!("missing remote_list_refresh_interval"))?,
+            remote_upload_dedup: self
+                .remote_upload_dedup
+                .ok_or(anyhow!("missing remote_upload_dedup"))?,
+            remote_coalesce_index_uploads: self
+                .remote_coalesce_index_uploads
+                .ok_or(anyhow!("missing remote_coalesce_index_uploads"))?,
+            strict_metadata_merge: self
+                .strict_metadata_merge
+                .ok_or(anyhow!("missing strict_metadata_merge"))?,
+            remote_download_chunks: self
+                .remote_download_chunks
+                .ok_or(anyhow!("missing remote_download_chunks"))?,
+            remote_download_chunk_min_size: self
+                .remote_download_chunk_min_size
+                .ok_or(anyhow!("missing remote_download_chunk_min_size"))?,
+            remote_chunk_checksum_size: self
+                .remote_chunk_checksum_size
+                .ok_or(anyhow!("missing remote_chunk_checksum_size"))?,
+            remote_layer_archive_threshold: self
+                .remote_layer_archive_threshold
+                .ok_or(anyhow!("missing remote_layer_archive_threshold"))?,
+            remote_zstd_dictionary_max_file_size: self
+                .remote_zstd_dictionary_max_file_size
+                .ok_or(anyhow!("missing remote_zstd_dictionary_max_file_size"))?,
+            sync_temp_dir: self.sync_temp_dir.ok_or(anyhow!("missing sync_temp_dir"))?,
+            fsync_downloads: self
+                .fsync_downloads
+                .ok_or(anyhow!("missing fsync_downloads"))?,
+            basebackup_spill_threshold: self
+                .basebackup_spill_threshold
+                .ok_or(anyhow!("missing basebackup_spill_threshold"))?,
+            tenant_filter: self.tenant_filter.ok_or(anyhow!("missing tenant_filter"))?,
+            remote_owner_lease_ttl: self
+                .remote_owner_lease_ttl
+                .ok_or(anyhow!("missing remote_owner_lease_ttl"))?,
+            startup_tenant_collection_concurrency: self
+                .startup_tenant_collection_concurrency
+                .ok_or(anyhow!("missing startup_tenant_collection_concurrency"))?,
         })
     }
 }
@@ -743,7 +1087,9 @@ impl PageServerConf {
                 "http_auth_type" => builder.http_auth_type(parse_toml_from_str(key, item)?),
                 "pg_auth_type" => builder.pg_auth_type(parse_toml_from_str(key, item)?),
                 "remote_storage" => {
-                    builder.remote_storage_config(RemoteStorageConfig::from_toml(item)?)
+                    let mut configs = RemoteStorageConfig::list_from_toml(item)?.into_iter();
+                    builder.remote_storage_config(configs.next());
+                    builder.remote_storage_replicas(configs.collect());
                 }
                 "tenant_config" => {
                     t_conf = Self::parse_toml_tenant_conf(item)?;
@@ -778,6 +1124,28 @@ impl PageServerConf {
                 "ondemand_download_behavior_treat_error_as_warn" => builder.ondemand_download_behavior_treat_error_as_warn(parse_toml_bool(key, item)?),
                 "background_task_maximum_delay" => builder.background_task_maximum_delay(parse_toml_duration(key, item)?),
                 "ingest_batch_size" => builder.ingest_batch_size(parse_toml_u64(key, item)?),
+                "remote_checksum_algorithm" => builder.remote_checksum_algorithm(parse_toml_from_str(key, item)?),
+                "remote_gc_retained_lsns" => builder.remote_gc_retained_lsns(parse_toml_u64(key, item)? as usize),
+                "remote_index_generations" => builder.remote_index_generations(parse_toml_u64(key, item)? as usize),
+                "remote_repair_missing_index_part" => builder.remote_repair_missing_index_part(parse_toml_bool(key, item)?),
+                "remote_list_refresh_interval" => builder.remote_list_refresh_interval(parse_toml_duration(key, item)?),
+                "remote_upload_dedup" => builder.remote_upload_dedup(parse_toml_bool(key, item)?),
+                "remote_coalesce_index_uploads" => builder.remote_coalesce_index_uploads(parse_toml_bool(key, item)?),
+                "strict_metadata_merge" => builder.strict_metadata_merge(parse_toml_bool(key, item)?),
+                "remote_download_chunks" => builder.remote_download_chunks(parse_toml_u64(key, item)? as usize),
+                "remote_download_chunk_min_size" => builder.remote_download_chunk_min_size(parse_toml_u64(key, item)?),
+                "remote_chunk_checksum_size" => builder.remote_chunk_checksum_size(parse_toml_u64(key, item)?),
+                "remote_layer_archive_threshold" => builder.remote_layer_archive_threshold(parse_toml_u64(key, item)?),
+                "remote_zstd_dictionary_max_file_size" => builder.remote_zstd_dictionary_max_file_size(parse_toml_u64(key, item)?),
+                "sync_temp_dir" => builder.sync_temp_dir(Some(PathBuf::from(parse_toml_string(key, item)?))),
+                "fsync_downloads" => builder.fsync_downloads(parse_toml_bool(key, item)?),
+                "basebackup_spill_threshold" => builder.basebackup_spill_threshold(parse_toml_u64(key, item)?),
+                "tenant_filter" => builder.tenant_filter(Some(TenantFilter::from_toml(key, item)?)),
+                "remote_owner_lease_ttl" => builder.remote_owner_lease_ttl(parse_toml_duration(key, item)?),
+                "startup_tenant_collection_concurrency" => builder.startup_tenant_collection_concurrency(
+                    NonZeroUsize::new(parse_toml_u64(key, item)? as usize)
+                        .context("startup_tenant_collection_concurrency should be positive")?
+                ),
                 _ => bail!("unrecognized pageserver option '{key}'"),
             }
         }
@@ -931,6 +1299,7 @@ impl PageServerConf {
             pg_auth_type: AuthType::Trust,
             auth_validation_public_key_path: None,
             remote_storage_config: None,
+            remote_storage_replicas: Vec::new(),
             default_tenant_conf: TenantConf::default(),
             broker_endpoint: storage_broker::DEFAULT_ENDPOINT.parse().unwrap(),
             broker_keepalive_interval: Duration::from_secs(5000),
@@ -947,8 +1316,38 @@ impl PageServerConf {
             ondemand_download_behavior_treat_error_as_warn: false,
             background_task_maximum_delay: Duration::ZERO,
             ingest_batch_size: defaults::DEFAULT_INGEST_BATCH_SIZE,
+            remote_checksum_algorithm: ChecksumAlgorithm::Crc32c,
+            remote_gc_retained_lsns: defaults::DEFAULT_REMOTE_GC_RETAINED_LSNS,
+            remote_index_generations: defaults::DEFAULT_REMOTE_INDEX_GENERATIONS,
+            remote_repair_missing_index_part: false,
+            remote_list_refresh_interval: Duration::ZERO,
+            remote_upload_dedup: false,
+            remote_coalesce_index_uploads: false,
+            strict_metadata_merge: false,
+            remote_download_chunks: defaults::DEFAULT_REMOTE_DOWNLOAD_CHUNKS,
+            remote_download_chunk_min_size: defaults::DEFAULT_REMOTE_DOWNLOAD_CHUNK_MIN_SIZE,
+            remote_chunk_checksum_size: defaults::DEFAULT_REMOTE_CHUNK_CHECKSUM_SIZE,
+            remote_layer_archive_threshold: defaults::DEFAULT_REMOTE_LAYER_ARCHIVE_THRESHOLD,
+            remote_zstd_dictionary_max_file_size:
+                defaults::DEFAULT_REMOTE_ZSTD_DICTIONARY_MAX_FILE_SIZE,
+            sync_temp_dir: None,
+            fsync_downloads: defaults::DEFAULT_FSYNC_DOWNLOADS,
+            basebackup_spill_threshold: defaults::DEFAULT_BASEBACKUP_SPILL_THRESHOLD,
+            tenant_filter: None,
+            remote_owner_lease_ttl: Duration::ZERO,
+            startup_tenant_collection_concurrency: NonZeroUsize::new(
+                defaults::DEFAULT_STARTUP_TENANT_COLLECTION_CONCURRENCY,
+            )
+            .expect("default startup tenant collection concurrency is positive"),
         }
     }
+
+    /// Whether the tenant's data is restored from the remote storage, see [`TenantFilter`].
+    pub fn restores_tenant(&self, tenant_id: &TenantId) -> bool {
+        self.tenant_filter
+            .as_ref()
+            .map_or(true, |filter| filter.matches(tenant_id))
+    }
 }
 
 // Helper functions to parse a toml Item
@@ -1072,14 +1471,86 @@ impl ConfigurableSemaphore {
     }
 }
 
+/// Glob patterns of the ids of the tenants to restore from the remote storage, e.g. to restore
+/// a part of a large bucket only. `*` matches any number of characters, `?` a single one.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TenantFilter {
+    patterns: Vec<String>,
+}
+
+impl TenantFilter {
+    /// A single pattern, or a list of them.
+    fn from_toml(name: &str, item: &Item) -> anyhow::Result<Self> {
+        let patterns = match item.as_array() {
+            Some(array) => array
+                .iter()
+                .map(|value| {
+                    value
+                        .as_str()
+                        .map(str::to_ascii_lowercase)
+                        .with_context(|| format!("configure option {name} is not a string list"))
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?,
+            None => vec![parse_toml_string(name, item)?.to_ascii_lowercase()],
+        };
+        for pattern in &patterns {
+            ensure!(
+                !pattern.is_empty()
+                    && pattern
+                        .chars()
+                        .all(|c| c.is_ascii_hexdigit() || c == '*' || c == '?'),
+                "configure option {name} has an invalid tenant id pattern '{pattern}'"
+            );
+        }
+        Ok(TenantFilter { patterns })
+    }
+
+    pub fn matches(&self, tenant_id: &TenantId) -> bool {
+        let tenant_id = tenant_id.to_string();
+        self.patterns
+            .iter()
+            .any(|pattern| glob_matches(pattern, &tenant_id))
+    }
+}
+
+fn glob_matches(pattern: &str, s: &str) -> bool {
+    let (pattern, s) = (pattern.as_bytes(), s.as_bytes());
+    let (mut p, mut i) = (0, 0);
+    // Where the last `*` is, and the position in `s` it matches up to so far.
+    let mut backtrack = None;
+    while i < s.len() {
+        match pattern.get(p) {
+            Some(b'*') => {
+                backtrack = Some((p, i));
+                p += 1;
+            }
+            Some(&c) if c == b'?' || c == s[i] => {
+                p += 1;
+                i += 1;
+            }
+            _ => match backtrack {
+                Some((star, matched)) => {
+                    backtrack = Some((star, matched + 1));
+                    p = star + 1;
+                    i = matched + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == b'*')
+}
+
 #[cfg(test)]
 mod tests {
     use std::{
         fs,
-        num::{NonZeroU32, NonZeroUsize},
+        num::{NonZeroU32, NonZeroU64, NonZeroUsize},
     };
 
-    use remote_storage::{RemoteStorageKind, S3Config};
+    use remote_storage::{
+        AzureConfig, GcsConfig, HttpConfig, RemoteStorageKind, S3Config, SftpConfig,
+    };
     use tempfile::{tempdir, TempDir};
     use utils::serde_percent::Percent;
 
@@ -1109,6 +1580,25 @@ synthetic_size_calculation_interval = '333 s'
 
 log_format = 'json'
 background_task_maximum_delay = '334 s'
+remote_checksum_algorithm = 'sha256'
+remote_gc_retained_lsns = 5
+remote_index_generations = 3
+remote_repair_missing_index_part = true
+remote_list_refresh_interval = '5 min'
+remote_upload_dedup = true
+remote_coalesce_index_uploads = true
+strict_metadata_merge = true
+remote_download_chunks = 8
+remote_download_chunk_min_size = 1048576
+remote_chunk_checksum_size = 8388608
+remote_layer_archive_threshold = 65536
+remote_zstd_dictionary_max_file_size = 131072
+sync_temp_dir = '/mnt/large/pageserver_downloads'
+fsync_downloads = false
+basebackup_spill_threshold = 16777216
+tenant_filter = ['0123*', 'abcd*']
+remote_owner_lease_ttl = '30 s'
+startup_tenant_collection_concurrency = 64
 
 "#;
 
@@ -1145,6 +1635,7 @@ background_task_maximum_delay = '334 s'
                 pg_auth_type: AuthType::Trust,
                 auth_validation_public_key_path: None,
                 remote_storage_config: None,
+                remote_storage_replicas: Vec::new(),
                 default_tenant_conf: TenantConf::default(),
                 broker_endpoint: storage_broker::DEFAULT_ENDPOINT.parse().unwrap(),
                 broker_keepalive_interval: humantime::parse_duration(
@@ -1171,6 +1662,36 @@ background_task_maximum_delay = '334 s'
                     defaults::DEFAULT_BACKGROUND_TASK_MAXIMUM_DELAY
                 )?,
                 ingest_batch_size: defaults::DEFAULT_INGEST_BATCH_SIZE,
+                remote_checksum_algorithm: ChecksumAlgorithm::from_str(
+                    defaults::DEFAULT_REMOTE_CHECKSUM_ALGORITHM
+                )?,
+                remote_gc_retained_lsns: defaults::DEFAULT_REMOTE_GC_RETAINED_LSNS,
+                remote_index_generations: defaults::DEFAULT_REMOTE_INDEX_GENERATIONS,
+                remote_repair_missing_index_part:
+                    defaults::DEFAULT_REMOTE_REPAIR_MISSING_INDEX_PART,
+                remote_list_refresh_interval: humantime::parse_duration(
+                    defaults::DEFAULT_REMOTE_LIST_REFRESH_INTERVAL
+                )?,
+                remote_upload_dedup: defaults::DEFAULT_REMOTE_UPLOAD_DEDUP,
+                remote_coalesce_index_uploads: defaults::DEFAULT_REMOTE_COALESCE_INDEX_UPLOADS,
+                strict_metadata_merge: defaults::DEFAULT_STRICT_METADATA_MERGE,
+                remote_download_chunks: defaults::DEFAULT_REMOTE_DOWNLOAD_CHUNKS,
+                remote_download_chunk_min_size: defaults::DEFAULT_REMOTE_DOWNLOAD_CHUNK_MIN_SIZE,
+                remote_chunk_checksum_size: defaults::DEFAULT_REMOTE_CHUNK_CHECKSUM_SIZE,
+                remote_layer_archive_threshold: defaults::DEFAULT_REMOTE_LAYER_ARCHIVE_THRESHOLD,
+                remote_zstd_dictionary_max_file_size:
+                    defaults::DEFAULT_REMOTE_ZSTD_DICTIONARY_MAX_FILE_SIZE,
+                sync_temp_dir: None,
+                fsync_downloads: defaults::DEFAULT_FSYNC_DOWNLOADS,
+                basebackup_spill_threshold: defaults::DEFAULT_BASEBACKUP_SPILL_THRESHOLD,
+                tenant_filter: None,
+                remote_owner_lease_ttl: humantime::parse_duration(
+                    defaults::DEFAULT_REMOTE_OWNER_LEASE_TTL
+                )?,
+                startup_tenant_collection_concurrency: NonZeroUsize::new(
+                    defaults::DEFAULT_STARTUP_TENANT_COLLECTION_CONCURRENCY
+                )
+                .unwrap(),
             },
             "Correct defaults should be used when no config values are provided"
         );
@@ -1211,6 +1732,7 @@ background_task_maximum_delay = '334 s'
                 pg_auth_type: AuthType::Trust,
                 auth_validation_public_key_path: None,
                 remote_storage_config: None,
+                remote_storage_replicas: Vec::new(),
                 default_tenant_conf: TenantConf::default(),
                 broker_endpoint: storage_broker::DEFAULT_ENDPOINT.parse().unwrap(),
                 broker_keepalive_interval: Duration::from_secs(5),
@@ -1227,6 +1749,27 @@ background_task_maximum_delay = '334 s'
                 ondemand_download_behavior_treat_error_as_warn: false,
                 background_task_maximum_delay: Duration::from_secs(334),
                 ingest_batch_size: 100,
+                remote_checksum_algorithm: ChecksumAlgorithm::Sha256,
+                remote_gc_retained_lsns: 5,
+                remote_index_generations: 3,
+                remote_repair_missing_index_part: true,
+                remote_list_refresh_interval: Duration::from_secs(300),
+                remote_upload_dedup: true,
+                remote_coalesce_index_uploads: true,
+                strict_metadata_merge: true,
+                remote_download_chunks: 8,
+                remote_download_chunk_min_size: 1048576,
+                remote_chunk_checksum_size: 8388608,
+                remote_layer_archive_threshold: 65536,
+                remote_zstd_dictionary_max_file_size: 131072,
+                sync_temp_dir: Some(PathBuf::from("/mnt/large/pageserver_downloads")),
+                fsync_downloads: false,
+                basebackup_spill_threshold: 16777216,
+                tenant_filter: Some(TenantFilter {
+                    patterns: vec!["0123*".to_owned(), "abcd*".to_owned()],
+                }),
+                remote_owner_lease_ttl: Duration::from_secs(30),
+                startup_tenant_collection_concurrency: NonZeroUsize::new(64).unwrap(),
             },
             "Should be able to parse all basic config values correctly"
         );
@@ -1280,9 +1823,26 @@ broker_endpoint = '{broker_endpoint}'
                         remote_storage::DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_SYNCS
                     )
                         .unwrap(),
+                    max_concurrent_sync_per_tenant: None,
+                    max_concurrent_sync_startup: None,
                     max_sync_errors: NonZeroU32::new(remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS)
                         .unwrap(),
-                    storage: RemoteStorageKind::LocalFs(local_storage_path.clone()),
+                    max_retries: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_RETRIES,
+                    base_backoff_ms: remote_storage::DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS,
+                    compression: remote_storage::Compression::None,
+                    max_bytes_per_sec: None,
+                    dry_run: false,
+                    encryption_key_file: None,
+                    operation_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+                    list_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+                    upload_start_jitter: remote_storage::DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+                    prefix: None,
+                    storage: RemoteStorageKind::LocalFs(remote_storage::LocalFsConfig {
+                        local_path: local_storage_path.clone(),
+                        read_only: false,
+                        fsync_batch_window: None,
+                        direct_io_min_size: None,
+                    }),
                 },
                 "Remote storage config should correctly parse the local FS config and fill other storage defaults"
             );
@@ -1300,24 +1860,54 @@ broker_endpoint = '{broker_endpoint}'
         let prefix_in_bucket = "test_prefix".to_string();
         let endpoint = "http://localhost:5000".to_string();
         let max_concurrent_syncs = NonZeroUsize::new(111).unwrap();
+        let max_concurrent_sync_per_tenant = NonZeroUsize::new(11).unwrap();
+        let max_concurrent_sync_startup = NonZeroUsize::new(444).unwrap();
         let max_sync_errors = NonZeroU32::new(222).unwrap();
+        let max_retries = 5;
+        let base_backoff_ms = 250;
+        let compression = remote_storage::Compression::Zstd;
+        let max_bytes_per_sec = NonZeroU64::new(10 * 1024 * 1024).unwrap();
         let s3_concurrency_limit = NonZeroUsize::new(333).unwrap();
+        let multipart_part_size = NonZeroUsize::new(16 * 1024 * 1024).unwrap();
+        let multipart_upload_concurrency = NonZeroUsize::new(8).unwrap();
+        let storage_class = "STANDARD_IA".to_string();
+        let content_type = "application/x-neon".to_string();
+        let cache_control = "no-cache".to_string();
         let broker_endpoint = "http://127.0.0.1:7777";
 
         let identical_toml_declarations = &[
             format!(
                 r#"[remote_storage]
 max_concurrent_syncs = {max_concurrent_syncs}
+max_concurrent_sync_per_tenant = {max_concurrent_sync_per_tenant}
+max_concurrent_sync_startup = {max_concurrent_sync_startup}
 max_sync_errors = {max_sync_errors}
+max_retries = {max_retries}
+base_backoff_ms = {base_backoff_ms}
+compression = '{compression}'
+max_bytes_per_sec = {max_bytes_per_sec}
+dry_run = true
+operation_timeout = '5 min'
+list_timeout = '1 hour'
+upload_start_jitter = '30 s'
 bucket_name = '{bucket_name}'
 bucket_region = '{bucket_region}'
 prefix_in_bucket = '{prefix_in_bucket}'
 endpoint = '{endpoint}'
-concurrency_limit = {s3_concurrency_limit}"#
+force_path_style = true
+concurrency_limit = {s3_concurrency_limit}
+multipart_part_size = {multipart_part_size}
+multipart_upload_concurrency = {multipart_upload_concurrency}
+multipart_resume_dir = 'multipart_uploads'
+multipart_upload_max_age = '7 days'
+storage_class = '{storage_class}'
+content_type = '{content_type}'
+cache_control = '{cache_control}'"#
             ),
             format!(
-                "remote_storage={{max_concurrent_syncs={max_concurrent_syncs}, max_sync_errors={max_sync_errors}, bucket_name='{bucket_name}',\
-                bucket_region='{bucket_region}', prefix_in_bucket='{prefix_in_bucket}', endpoint='{endpoint}', concurrency_limit={s3_concurrency_limit}}}",
+                "remote_storage={{max_concurrent_syncs={max_concurrent_syncs}, max_concurrent_sync_per_tenant={max_concurrent_sync_per_tenant}, max_concurrent_sync_startup={max_concurrent_sync_startup}, max_sync_errors={max_sync_errors}, max_retries={max_retries}, base_backoff_ms={base_backoff_ms}, compression='{compression}', max_bytes_per_sec={max_bytes_per_sec}, dry_run=true, operation_timeout='5 min', list_timeout='1 hour', upload_start_jitter='30 s', bucket_name='{bucket_name}',\
+                bucket_region='{bucket_region}', prefix_in_bucket='{prefix_in_bucket}', endpoint='{endpoint}', force_path_style=true, concurrency_limit={s3_concurrency_limit},\
+                multipart_part_size={multipart_part_size}, multipart_upload_concurrency={multipart_upload_concurrency}, multipart_resume_dir='multipart_uploads', multipart_upload_max_age='7 days', storage_class='{storage_class}', content_type='{content_type}', cache_control='{cache_control}'}}",
             ),
         ];
 
@@ -1344,14 +1934,35 @@ broker_endpoint = '{broker_endpoint}'
                 parsed_remote_storage_config,
                 RemoteStorageConfig {
                     max_concurrent_syncs,
+                    max_concurrent_sync_per_tenant: Some(max_concurrent_sync_per_tenant),
+                    max_concurrent_sync_startup: Some(max_concurrent_sync_startup),
                     max_sync_errors,
+                    max_retries,
+                    base_backoff_ms,
+                    compression,
+                    max_bytes_per_sec: Some(max_bytes_per_sec),
+                    dry_run: true,
+                    encryption_key_file: None,
+                    operation_timeout: Duration::from_secs(5 * 60),
+                    list_timeout: Duration::from_secs(60 * 60),
+                    upload_start_jitter: Duration::from_secs(30),
+                    prefix: None,
                     storage: RemoteStorageKind::AwsS3(S3Config {
                         bucket_name: bucket_name.clone(),
                         bucket_region: bucket_region.clone(),
                         prefix_in_bucket: Some(prefix_in_bucket.clone()),
                         endpoint: Some(endpoint.clone()),
+                        force_path_style: Some(true),
                         concurrency_limit: s3_concurrency_limit,
                         max_keys_per_list_response: None,
+                        multipart_part_size,
+                        multipart_upload_concurrency,
+                        multipart_resume_dir: Some(PathBuf::from("multipart_uploads")),
+                        multipart_upload_max_age: Some(Duration::from_secs(7 * 24 * 60 * 60)),
+                        storage_class: Some(storage_class.clone()),
+                        content_type: Some(content_type.clone()),
+                        cache_control: Some(cache_control.clone()),
+                        max_object_size: None,
                     }),
                 },
                 "Remote storage config should correctly parse the S3 config"
@@ -1360,6 +1971,251 @@ broker_endpoint = '{broker_endpoint}'
         Ok(())
     }
 
+    #[test]
+    fn parse_remote_azure_storage_config() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+
+        let container_name = "some-sample-container".to_string();
+        let container_region = "westeurope".to_string();
+        let storage_account = "someaccount".to_string();
+        let prefix_in_container = "test_prefix".to_string();
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoint = '{broker_endpoint}'
+
+[remote_storage]
+container_name = '{container_name}'
+container_region = '{container_region}'
+storage_account = '{storage_account}'
+prefix_in_container = '{prefix_in_container}'"#,
+            pg_distrib_dir.display(),
+        );
+
+        let toml = config_string.parse()?;
+
+        let parsed_remote_storage_config = PageServerConf::parse_and_validate(&toml, &workdir)
+            .unwrap_or_else(|e| panic!("Failed to parse config '{config_string}', reason: {e:?}"))
+            .remote_storage_config
+            .expect("Should have remote storage config for Azure");
+
+        assert_eq!(
+            parsed_remote_storage_config.storage,
+            RemoteStorageKind::AzureBlob(AzureConfig {
+                container_name,
+                container_region,
+                storage_account: Some(storage_account),
+                prefix_in_container: Some(prefix_in_container),
+                concurrency_limit: NonZeroUsize::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_AZURE_CONCURRENCY_LIMIT
+                )
+                .unwrap(),
+                max_keys_per_list_response: None,
+            }),
+            "Remote storage config should correctly parse the Azure config"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_remote_gcs_storage_config() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+
+        let gcs_bucket = "some-sample-bucket".to_string();
+        let prefix_in_bucket = "test_prefix".to_string();
+        let service_account_key_path = tempdir.path().join("key.json");
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoint = '{broker_endpoint}'
+
+[remote_storage]
+gcs_bucket = '{gcs_bucket}'
+prefix_in_bucket = '{prefix_in_bucket}'
+service_account_key_path = '{}'"#,
+            pg_distrib_dir.display(),
+            service_account_key_path.display(),
+        );
+
+        let toml = config_string.parse()?;
+
+        let parsed_remote_storage_config = PageServerConf::parse_and_validate(&toml, &workdir)
+            .unwrap_or_else(|e| panic!("Failed to parse config '{config_string}', reason: {e:?}"))
+            .remote_storage_config
+            .expect("Should have remote storage config for GCS");
+
+        assert_eq!(
+            parsed_remote_storage_config.storage,
+            RemoteStorageKind::Gcs(GcsConfig {
+                bucket_name: gcs_bucket,
+                prefix_in_bucket: Some(prefix_in_bucket),
+                service_account_key_path: Some(service_account_key_path),
+                endpoint: None,
+                concurrency_limit: NonZeroUsize::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_GCS_CONCURRENCY_LIMIT
+                )
+                .unwrap(),
+                max_keys_per_list_response: None,
+            }),
+            "Remote storage config should correctly parse the GCS config"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_remote_storage_replicas() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoint = '{broker_endpoint}'
+
+[[remote_storage]]
+local_path = '/mnt/nas'
+
+[[remote_storage]]
+bucket_name = 'bucket'
+bucket_region = 'region'"#,
+            pg_distrib_dir.display(),
+        );
+        let toml = config_string.parse()?;
+        let conf = PageServerConf::parse_and_validate(&toml, &workdir)
+            .unwrap_or_else(|e| panic!("Failed to parse config '{config_string}', reason: {e:?}"));
+
+        let primary = conf
+            .remote_storage_config
+            .expect("Should have the first remote storage as the primary one");
+        assert!(matches!(primary.storage, RemoteStorageKind::LocalFs(_)));
+        assert_eq!(conf.remote_storage_replicas.len(), 1);
+        assert!(matches!(
+            conf.remote_storage_replicas[0].storage,
+            RemoteStorageKind::AwsS3(_)
+        ));
+        Ok(())
+    }
+
+    #[test]
+    fn tenant_filter_matches_globs() -> anyhow::Result<()> {
+        let parse = |toml: &str| -> anyhow::Result<TenantFilter> {
+            let document = format!("tenant_filter = {toml}").parse::<Document>()?;
+            TenantFilter::from_toml("tenant_filter", &document["tenant_filter"])
+        };
+        let tenant_id = TenantId::from_str("0123456789abcdef0123456789abcdef")?;
+
+        assert!(parse("'0123*'")?.matches(&tenant_id));
+        assert!(parse("'*CDEF'")?.matches(&tenant_id));
+        assert!(parse("'0?23*89ab*ef'")?.matches(&tenant_id));
+        assert!(parse("['ffff*', '*4567*']")?.matches(&tenant_id));
+        assert!(!parse("'1234*'")?.matches(&tenant_id));
+        assert!(!parse("['0123', '*0123']")?.matches(&tenant_id));
+
+        assert!(parse("'tenant-*'").is_err());
+        assert!(parse("['']").is_err());
+        assert!(parse("[1]").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn parse_remote_sftp_storage_config() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+
+        let sftp_host = "sftp.example.com".to_string();
+        let sftp_username = "pageserver".to_string();
+        let sftp_key_path = tempdir.path().join("id_ed25519");
+        let sftp_root_path = PathBuf::from("/data/pageserver");
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoint = '{broker_endpoint}'
+
+[remote_storage]
+sftp_host = '{sftp_host}'
+sftp_username = '{sftp_username}'
+sftp_key_path = '{}'
+sftp_root_path = '{}'"#,
+            pg_distrib_dir.display(),
+            sftp_key_path.display(),
+            sftp_root_path.display(),
+        );
+
+        let toml = config_string.parse()?;
+
+        let parsed_remote_storage_config = PageServerConf::parse_and_validate(&toml, &workdir)
+            .unwrap_or_else(|e| panic!("Failed to parse config '{config_string}', reason: {e:?}"))
+            .remote_storage_config
+            .expect("Should have remote storage config for SFTP");
+
+        assert_eq!(
+            parsed_remote_storage_config.storage,
+            RemoteStorageKind::Sftp(SftpConfig {
+                host: sftp_host,
+                port: remote_storage::DEFAULT_SFTP_PORT,
+                username: sftp_username,
+                key_path: sftp_key_path,
+                known_hosts_path: None,
+                root_path: sftp_root_path,
+                concurrency_limit: NonZeroUsize::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_SFTP_CONCURRENCY_LIMIT
+                )
+                .unwrap(),
+            }),
+            "Remote storage config should correctly parse the SFTP config"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn parse_remote_http_read_only_storage_config() -> anyhow::Result<()> {
+        let tempdir = tempdir()?;
+        let (workdir, pg_distrib_dir) = prepare_fs(&tempdir)?;
+
+        let http_base_url = "https://cdn.example.com/nightly/".to_string();
+        let broker_endpoint = "http://127.0.0.1:7777";
+
+        let config_string = format!(
+            r#"{ALL_BASE_VALUES_TOML}
+pg_distrib_dir='{}'
+broker_endpoint = '{broker_endpoint}'
+
+[remote_storage]
+http_base_url = '{http_base_url}'"#,
+            pg_distrib_dir.display(),
+        );
+
+        let toml = config_string.parse()?;
+
+        let parsed_remote_storage_config = PageServerConf::parse_and_validate(&toml, &workdir)
+            .unwrap_or_else(|e| panic!("Failed to parse config '{config_string}', reason: {e:?}"))
+            .remote_storage_config
+            .expect("Should have remote storage config for HTTP");
+
+        assert_eq!(
+            parsed_remote_storage_config.storage,
+            RemoteStorageKind::HttpReadOnly(HttpConfig {
+                base_url: http_base_url,
+                index_url: None,
+                concurrency_limit: NonZeroUsize::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_HTTP_CONCURRENCY_LIMIT
+                )
+                .unwrap(),
+            }),
+            "Remote storage config should correctly parse the HTTP config"
+        );
+        Ok(())
+    }
+
     #[test]
     fn parse_tenant_config() -> anyhow::Result<()> {
         let tempdir = tempdir()?;
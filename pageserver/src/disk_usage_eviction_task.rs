@@ -89,6 +89,10 @@ pub fn launch_disk_usage_global_eviction_task(
         info!("disk usage based eviction task not configured");
         return Ok(());
     };
+    if storage.is_dry_run() {
+        info!("remote storage dry run: no layers are uploaded, not launching the disk usage based eviction task");
+        return Ok(());
+    }
 
     info!("launching disk usage based eviction task");
 
@@ -3,6 +3,7 @@
 //!
 use std::collections::HashMap;
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::{anyhow, Context, Result};
 use hyper::StatusCode;
@@ -19,8 +20,9 @@ use utils::http::json::json_request_or_empty_body;
 use utils::http::request::{get_request_param, must_get_query_param, parse_query_param};
 
 use super::models::{
-    StatusResponse, TenantConfigRequest, TenantCreateRequest, TenantCreateResponse, TenantInfo,
-    TimelineCreateRequest, TimelineGcRequest, TimelineInfo,
+    RemoteStorageHealth, RemoteTimelineInfo, StatusResponse, TenantConfigRequest,
+    TenantCreateRequest, TenantCreateResponse, TenantInfo, TimelineCreateRequest,
+    TimelineGcRequest, TimelineInfo,
 };
 use crate::context::{DownloadBehavior, RequestContext};
 use crate::metrics::{StorageTimeOperation, STORAGE_TIME_GLOBAL};
@@ -51,6 +53,9 @@ use utils::{
 // Imports only used for testing APIs
 use super::models::ConfigureFailpointsRequest;
 
+/// How long each step of the remote storage health check may take.
+const REMOTE_STORAGE_HEALTH_TIMEOUT: Duration = Duration::from_secs(30);
+
 struct State {
     conf: &'static PageServerConf,
     auth: Option<Arc<JwtAuth>>,
@@ -303,6 +308,15 @@ async fn build_timeline_info_common(
         pg_version: timeline.pg_version,
 
         state,
+        remote_sync_status: timeline
+            .remote_client
+            .as_ref()
+            .map(|remote_client| remote_client.sync_status()),
+        remote_replica_sync_status: timeline
+            .remote_client
+            .as_ref()
+            .map(|remote_client| remote_client.replica_sync_status())
+            .unwrap_or_default(),
     };
     Ok(info)
 }
@@ -530,14 +544,91 @@ async fn tenant_detach_handler(
     let tenant_id: TenantId = parse_request_param(&request, "tenant_id")?;
     check_permission(&request, Some(tenant_id))?;
     let detach_ignored: Option<bool> = parse_query_param(&request, "detach_ignored")?;
+    let flush: Option<bool> = parse_query_param(&request, "flush")?;
 
     let state = get_state(&request);
+    // The dry run storage has none of the tenant's files, they'd be gone with the local ones.
+    if state
+        .remote_storage
+        .as_ref()
+        .is_some_and(|storage| storage.is_dry_run())
+    {
+        return Err(ApiError::PreconditionFailed(
+            "remote storage dry run: the tenant files are not uploaded, cannot detach".into(),
+        ));
+    }
     let conf = state.conf;
-    mgr::detach_tenant(conf, tenant_id, detach_ignored.unwrap_or(false))
-        .instrument(info_span!("tenant_detach", %tenant_id))
-        .await?;
+    let summary = mgr::detach_tenant(
+        conf,
+        tenant_id,
+        detach_ignored.unwrap_or(false),
+        flush.unwrap_or(false),
+    )
+    .instrument(info_span!("tenant_detach", %tenant_id))
+    .await?;
 
-    json_response(StatusCode::OK, ())
+    json_response(StatusCode::OK, summary)
+}
+
+async fn tenant_remote_timelines_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: TenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let state = get_state(&request);
+    let Some(storage) = &state.remote_storage else {
+        return Err(ApiError::PreconditionFailed(
+            "remote storage is not configured".into(),
+        ));
+    };
+    let timelines = tenant::remote_timeline_client::list_remote_timelines_with_metadata(
+        storage, state.conf, tenant_id,
+    )
+    .instrument(info_span!("list_remote_timelines", %tenant_id))
+    .await
+    .map_err(ApiError::InternalServerError)?;
+
+    let response = timelines
+        .into_iter()
+        .map(|(timeline_id, metadata, generations)| RemoteTimelineInfo {
+            timeline_id,
+            ancestor_timeline_id: metadata.ancestor_timeline(),
+            ancestor_lsn: metadata
+                .ancestor_timeline()
+                .map(|_| metadata.ancestor_lsn()),
+            disk_consistent_lsn: metadata.disk_consistent_lsn(),
+            latest_gc_cutoff_lsn: metadata.latest_gc_cutoff_lsn(),
+            pg_version: metadata.pg_version(),
+            index_generations: generations,
+        })
+        .collect::<Vec<_>>();
+    json_response(StatusCode::OK, response)
+}
+
+async fn tenant_remote_timelines_failed_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: TenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let tenant = mgr::get_tenant(tenant_id, true).await?;
+    json_response(StatusCode::OK, tenant.failed_remote_loads())
+}
+
+async fn tenant_remote_timelines_retry_failed_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: TenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+
+    let tenant = mgr::get_tenant(tenant_id, true).await?;
+    let retried = tenant.retry_failed_remote_loads();
+    info!(%tenant_id, "retrying {retried} failed remote timeline loads");
+    json_response(StatusCode::OK, retried)
 }
 
 async fn tenant_load_handler(
@@ -1103,6 +1194,78 @@ async fn timeline_download_remote_layers_handler_get(
     json_response(StatusCode::OK, info)
 }
 
+async fn timeline_remote_sync_failed_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: TenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+    let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
+
+    let timeline = active_timeline_of_active_tenant(tenant_id, timeline_id).await?;
+    let remote_client = remote_client_of_timeline(&timeline)?;
+    json_response(StatusCode::OK, remote_client.failed_tasks())
+}
+
+async fn timeline_remote_sync_retry_failed_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: TenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+    let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
+
+    let timeline = active_timeline_of_active_tenant(tenant_id, timeline_id).await?;
+    let remote_client = remote_client_of_timeline(&timeline)?;
+    let retried = remote_client.retry_failed();
+    info!(%tenant_id, %timeline_id, "retrying {retried} failed remote tasks");
+    json_response(StatusCode::OK, retried)
+}
+
+async fn timeline_remote_sync_verify_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: TenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+    let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
+
+    let timeline = active_timeline_of_active_tenant(tenant_id, timeline_id).await?;
+    let remote_client = remote_client_of_timeline(&timeline)?;
+    let report = remote_client
+        .verify_remote_consistency()
+        .await
+        .map_err(ApiError::InternalServerError)?;
+    json_response(StatusCode::OK, report)
+}
+
+async fn timeline_remote_sync_resync_handler(
+    request: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    let tenant_id: TenantId = parse_request_param(&request, "tenant_id")?;
+    check_permission(&request, Some(tenant_id))?;
+    let timeline_id: TimelineId = parse_request_param(&request, "timeline_id")?;
+
+    let timeline = active_timeline_of_active_tenant(tenant_id, timeline_id).await?;
+    remote_client_of_timeline(&timeline)?;
+    let report = timeline
+        .force_remote_resync()
+        .await
+        .map_err(ApiError::InternalServerError)?;
+    info!(%tenant_id, %timeline_id, "forced a remote resync: {report:?}");
+    json_response(StatusCode::OK, report)
+}
+
+fn remote_client_of_timeline(
+    timeline: &Timeline,
+) -> Result<&Arc<tenant::remote_timeline_client::RemoteTimelineClient>, ApiError> {
+    timeline
+        .remote_client
+        .as_ref()
+        .ok_or_else(|| ApiError::PreconditionFailed("remote storage is not configured".into()))
+}
+
 async fn active_timeline_of_active_tenant(
     tenant_id: TenantId,
     timeline_id: TimelineId,
@@ -1127,6 +1290,56 @@ async fn always_panic_handler(
     json_response(StatusCode::NO_CONTENT, ())
 }
 
+async fn remote_uploads_pause_handler(
+    r: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    check_permission(&r, None)?;
+    tenant::remote_timeline_client::UploadPause::get(get_config(&r)).pause();
+    json_response(StatusCode::OK, ())
+}
+
+async fn remote_uploads_resume_handler(
+    r: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    check_permission(&r, None)?;
+    tenant::remote_timeline_client::UploadPause::get(get_config(&r)).resume();
+    json_response(StatusCode::OK, ())
+}
+
+async fn remote_storage_health_handler(
+    r: Request<Body>,
+    _cancel: CancellationToken,
+) -> Result<Response<Body>, ApiError> {
+    check_permission(&r, None)?;
+    let state = get_state(&r);
+    let Some(storage) = &state.remote_storage else {
+        return Err(ApiError::PreconditionFailed(
+            "remote storage is not configured".into(),
+        ));
+    };
+
+    let health = remote_storage::check_storage_health(storage, REMOTE_STORAGE_HEALTH_TIMEOUT).await;
+    let micros = |latency: Option<Duration>| latency.map(|latency| latency.as_micros() as u64);
+    let response = RemoteStorageHealth {
+        healthy: health.is_healthy(),
+        upload_micros: micros(health.upload_latency),
+        download_micros: micros(health.download_latency),
+        delete_micros: micros(health.delete_latency),
+        error: health.error.map(|e| e.to_string()),
+    };
+    if !response.healthy {
+        warn!("remote storage health check failed: {response:?}");
+    }
+    let status = if response.healthy {
+        StatusCode::OK
+    } else {
+        StatusCode::SERVICE_UNAVAILABLE
+    };
+    json_response(status, response)
+}
+
 async fn disk_usage_eviction_run(
     mut r: Request<Body>,
     _cancel: CancellationToken,
@@ -1398,6 +1611,15 @@ pub fn make_router(
         .post("/v1/tenant/:tenant_id/attach", |r| {
             api_handler(r, tenant_attach_handler)
         })
+        .get("/v1/tenant/:tenant_id/remote_timelines", |r| {
+            api_handler(r, tenant_remote_timelines_handler)
+        })
+        .get("/v1/tenant/:tenant_id/remote_timelines/failed", |r| {
+            api_handler(r, tenant_remote_timelines_failed_handler)
+        })
+        .put("/v1/tenant/:tenant_id/remote_timelines/retry_failed", |r| {
+            api_handler(r, tenant_remote_timelines_retry_failed_handler)
+        })
         .post("/v1/tenant/:tenant_id/detach", |r| {
             api_handler(r, tenant_detach_handler)
         })
@@ -1432,6 +1654,22 @@ pub fn make_router(
             "/v1/tenant/:tenant_id/timeline/:timeline_id/download_remote_layers",
             |r| api_handler(r, timeline_download_remote_layers_handler_get),
         )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/remote_sync/failed",
+            |r| api_handler(r, timeline_remote_sync_failed_handler),
+        )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/remote_sync/retry_failed",
+            |r| api_handler(r, timeline_remote_sync_retry_failed_handler),
+        )
+        .get(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/remote_sync/verify",
+            |r| api_handler(r, timeline_remote_sync_verify_handler),
+        )
+        .put(
+            "/v1/tenant/:tenant_id/timeline/:timeline_id/remote_sync/resync",
+            |r| api_handler(r, timeline_remote_sync_resync_handler),
+        )
         .delete("/v1/tenant/:tenant_id/timeline/:timeline_id", |r| {
             api_handler(r, timeline_delete_handler)
         })
@@ -1449,6 +1687,15 @@ pub fn make_router(
         .put("/v1/disk_usage_eviction/run", |r| {
             api_handler(r, disk_usage_eviction_run)
         })
+        .put("/v1/remote_storage/uploads/pause", |r| {
+            api_handler(r, remote_uploads_pause_handler)
+        })
+        .put("/v1/remote_storage/uploads/resume", |r| {
+            api_handler(r, remote_uploads_resume_handler)
+        })
+        .get("/v1/remote_storage/health", |r| {
+            api_handler(r, remote_storage_health_handler)
+        })
         .put("/v1/tenant/:tenant_id/break", |r| {
             testing_api_handler("set tenant state to broken", r, handle_tenant_break)
         })
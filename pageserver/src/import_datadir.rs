@@ -25,14 +25,15 @@ use postgres_ffi::ControlFileData;
 use postgres_ffi::DBState_DB_SHUTDOWNED;
 use postgres_ffi::Oid;
 use postgres_ffi::XLogFileName;
-use postgres_ffi::{BLCKSZ, WAL_SEGMENT_SIZE};
+use postgres_ffi::{BLCKSZ, RELSEG_SIZE, WAL_SEGMENT_SIZE};
 use utils::lsn::{Lsn, RecordLsn};
 
 // Returns checkpoint LSN from controlfile
-pub fn get_lsn_from_controlfile(path: &Path) -> Result<Lsn> {
+pub fn get_lsn_from_controlfile(path: &Path, pg_version: u32) -> Result<Lsn> {
     // Read control file to extract the LSN
     let controlfile_path = path.join("global").join("pg_control");
-    let controlfile = ControlFileData::decode(&std::fs::read(controlfile_path)?)?;
+    let controlfile =
+        postgres_ffi::decode_pg_control(&std::fs::read(controlfile_path)?, pg_version)?;
     let lsn = controlfile.checkPoint;
 
     Ok(Lsn(lsn))
@@ -130,6 +131,19 @@ async fn import_rel(
         e
     })?;
 
+    // A corrupt data directory shouldn't turn into a relation with blocks past the end
+    // of what PostgreSQL can address.
+    ensure!(
+        segno <= MAX_SEGNO,
+        "relation file {} has segment number {segno}, the maximum is {MAX_SEGNO}",
+        path.display()
+    );
+    ensure!(
+        len <= RELSEG_SIZE as usize * BLCKSZ as usize,
+        "relation file {} of {len} bytes is larger than a segment of {RELSEG_SIZE} blocks",
+        path.display()
+    );
+
     let mut buf: [u8; 8192] = [0u8; 8192];
 
     ensure!(len % BLCKSZ as usize == 0);
@@ -142,7 +156,7 @@ async fn import_rel(
         forknum,
     };
 
-    let mut blknum: u32 = segno * (1024 * 1024 * 1024 / BLCKSZ as u32);
+    let mut blknum: u32 = segno * RELSEG_SIZE;
 
     // Call put_rel_creation for every segment of the relation,
     // because there is no guarantee about the order in which we are processing segments.
@@ -501,7 +515,8 @@ async fn import_file(
                 let bytes = read_all_bytes(reader).await?;
 
                 // Extract the checkpoint record and import it separately.
-                let pg_control = ControlFileData::decode(&bytes[..])?;
+                let pg_control =
+                    postgres_ffi::decode_pg_control(&bytes[..], modification.tline.pg_version)?;
                 let checkpoint_bytes = pg_control.checkPointCopy.encode()?;
                 modification.put_checkpoint(checkpoint_bytes)?;
                 debug!("imported control file");
@@ -571,11 +586,22 @@ async fn import_file(
         import_slru(modification, slru, file_path, reader, len, ctx).await?;
         debug!("imported csn slru");
     } else if file_path.starts_with("pg_twophase") {
-        let xid = u32::from_str_radix(file_name.as_ref(), 16)?;
+        let xid = postgres_ffi::parse_twophase_file_name(file_name.as_ref())?;
 
         let bytes = read_all_bytes(reader).await?;
+        // The repository keeps the state as it's logged in the PREPARE record, without the
+        // CRC that ends the file. The basebackup adds it back.
+        ensure!(
+            bytes.len() >= 4,
+            "two-phase state file of transaction {xid} is too short"
+        );
+        let (state, crc) = bytes.split_at(bytes.len() - 4);
+        ensure!(
+            crc32c::crc32c(state).to_le_bytes() == crc,
+            "two-phase state file of transaction {xid} has invalid CRC"
+        );
         modification
-            .put_twophase_file(xid, Bytes::copy_from_slice(&bytes[..]), ctx)
+            .put_twophase_file(xid, Bytes::copy_from_slice(state), ctx)
             .await?;
         debug!("imported twophase file");
     } else if file_path.starts_with("pg_wal") {
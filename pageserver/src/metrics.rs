@@ -442,6 +442,14 @@ pub static STARTUP_IS_LOADING: Lazy<UIntGauge> = Lazy::new(|| {
     .expect("Failed to register pageserver_startup_is_loading")
 });
 
+pub(crate) static REMOTE_SYNC_STARTUP_PHASE: Lazy<UIntGauge> = Lazy::new(|| {
+    register_uint_gauge!(
+        "pageserver_remote_sync_startup_phase",
+        "1 while the remote sync tasks are limited by max_concurrent_sync_startup, 0 afterwards"
+    )
+    .expect("Failed to register pageserver_remote_sync_startup_phase")
+});
+
 /// How long did tenants take to go from construction to active state?
 pub(crate) static TENANT_ACTIVATION: Lazy<Histogram> = Lazy::new(|| {
     register_histogram!(
@@ -753,6 +761,112 @@ pub(crate) static REMOTE_OPERATION_TIME: Lazy<HistogramVec> = Lazy::new(|| {
     .expect("failed to define a metric")
 });
 
+pub(crate) static REMOTE_SYNC_TASKS_QUEUED: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pageserver_remote_sync_tasks_queued",
+        "Number of operations scheduled in the remote timeline clients' upload queues \
+         that have not started yet.",
+        &["file_kind", "op_kind"],
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_UPLOAD_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_remote_upload_bytes_total",
+        "Number of bytes successfully uploaded to the remote storage, after compression.",
+        &["file_kind"],
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_DOWNLOAD_BYTES: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_remote_download_bytes_total",
+        "Number of bytes downloaded from the remote storage, after decompression. \
+         Includes the bytes of the download attempts that failed afterwards.",
+        &["file_kind"],
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_SYNC_TASK_DURATION: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "pageserver_remote_sync_task_duration_seconds",
+        "Time it took to complete an upload, download or deletion, including all the retries. \
+         Unlike pageserver_remote_operation_seconds, measures the whole task rather than \
+         the individual remote storage requests.",
+        &["file_kind", "op_kind"],
+        vec![0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0],
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_SYNC_TASK_START_LAG: Lazy<HistogramVec> = Lazy::new(|| {
+    register_histogram_vec!(
+        "pageserver_remote_sync_task_start_lag_seconds",
+        "Time from scheduling an upload or deletion in the upload queue to its first attempt, \
+         spent waiting for the preceding operations, the upload_start_jitter and a sync permit.",
+        &["file_kind", "op_kind"],
+        vec![0.01, 0.05, 0.1, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0, 300.0, 600.0],
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_UPLOAD_DEDUP_COPIES: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pageserver_remote_upload_dedup_copies_total",
+        "Number of layer uploads replaced by a copy of a layer with the same contents \
+         within the remote storage, see `remote_upload_dedup`."
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_SYNC_ERRORS: Lazy<IntCounterVec> = Lazy::new(|| {
+    register_int_counter_vec!(
+        "pageserver_remote_sync_errors_total",
+        "Number of remote operations that failed after running out of retries, \
+         or with an error that is not worth retrying.",
+        &["file_kind", "op_kind"],
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_SYNC_FAILED_TASKS: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "pageserver_remote_sync_failed_tasks",
+        "Number of remote operations that have run out of their sync errors, \
+         and wait to be retried through the management API."
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_OWNER_LEASE_CONFLICTS: Lazy<IntCounter> = Lazy::new(|| {
+    register_int_counter!(
+        "pageserver_remote_owner_lease_conflicts_total",
+        "Number of owner lease checks that found another pageserver holding a tenant's lease"
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_UPLOADS_PAUSED: Lazy<IntGauge> = Lazy::new(|| {
+    register_int_gauge!(
+        "pageserver_remote_uploads_paused",
+        "1 while the remote uploads are paused, 0 at other times"
+    )
+    .expect("failed to define a metric")
+});
+
+pub(crate) static REMOTE_REPLICA_QUEUED_OPERATIONS: Lazy<IntGaugeVec> = Lazy::new(|| {
+    register_int_gauge_vec!(
+        "pageserver_remote_replica_queued_operations",
+        "Number of operations done in the primary remote storage that are yet to be done in \
+         a replica, by the position of the replica in the remote_storage list.",
+        &["replica"],
+    )
+    .expect("failed to define a metric")
+});
+
 pub(crate) static TENANT_TASK_EVENTS: Lazy<IntCounterVec> = Lazy::new(|| {
     register_int_counter_vec!(
         "pageserver_tenant_task_events",
@@ -1507,6 +1621,8 @@ pub fn preinitialize_metrics() {
 
     // gauges
     WALRECEIVER_ACTIVE_MANAGERS.get();
+    REMOTE_UPLOADS_PAUSED.get();
+    REMOTE_SYNC_FAILED_TASKS.get();
 
     // histograms
     [
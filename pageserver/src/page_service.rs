@@ -10,7 +10,6 @@
 //
 
 use anyhow::Context;
-use async_compression::tokio::write::GzipEncoder;
 use bytes::Buf;
 use bytes::Bytes;
 use futures::Stream;
@@ -26,7 +25,7 @@ use postgres_backend::{self, is_expected_io_error, AuthType, PostgresBackend, Qu
 use pq_proto::framed::ConnectionError;
 use pq_proto::FeStartupPacket;
 use pq_proto::{BeMessage, FeMessage, RowDescriptor};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
 use std::net::TcpListener;
 use std::pin::pin;
@@ -34,7 +33,6 @@ use std::str;
 use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
-use tokio::io::AsyncWriteExt;
 use tokio::io::{AsyncRead, AsyncWrite};
 use tokio_util::io::StreamReader;
 use tracing::field;
@@ -49,6 +47,7 @@ use utils::{
 
 use crate::auth::check_permission;
 use crate::basebackup;
+use crate::basebackup::{BasebackupCompression, DatabaseFilter};
 use crate::config::PageServerConf;
 use crate::context::{DownloadBehavior, RequestContext};
 use crate::import_datadir::import_wal_from_tar;
@@ -378,7 +377,7 @@ impl PageRequestMetrics {
 }
 
 struct PageServerHandler {
-    _conf: &'static PageServerConf,
+    conf: &'static PageServerConf,
     broker_client: storage_broker::BrokerClientChannel,
     auth: Option<Arc<JwtAuth>>,
     claims: Option<Claims>,
@@ -398,7 +397,7 @@ impl PageServerHandler {
         connection_ctx: RequestContext,
     ) -> Self {
         PageServerHandler {
-            _conf: conf,
+            conf,
             broker_client,
             auth,
             claims: None,
@@ -974,7 +973,10 @@ impl PageServerHandler {
     }
 
     #[allow(clippy::too_many_arguments)]
-    #[instrument(skip_all, fields(?lsn, ?prev_lsn, %full_backup))]
+    #[instrument(
+        skip_all,
+        fields(?lsn, ?prev_lsn, %full_backup, ?since_lsn, %best_effort, ?databases)
+    )]
     async fn handle_basebackup_request<IO>(
         &mut self,
         pgb: &mut PostgresBackend<IO>,
@@ -983,7 +985,10 @@ impl PageServerHandler {
         lsn: Option<Lsn>,
         prev_lsn: Option<Lsn>,
         full_backup: bool,
-        gzip: bool,
+        since_lsn: Option<Lsn>,
+        compression: BasebackupCompression,
+        best_effort: bool,
+        databases: DatabaseFilter,
         ctx: RequestContext,
     ) -> anyhow::Result<()>
     where
@@ -1011,54 +1016,29 @@ impl PageServerHandler {
         pgb.write_message_noflush(&BeMessage::CopyOutResponse)?;
         pgb.flush().await?;
 
-        // Send a tarball of the latest layer on the timeline. Compress if not
-        // fullbackup. TODO Compress in that case too (tests need to be updated)
-        if full_backup {
-            let mut writer = pgb.copyout_writer();
-            basebackup::send_basebackup_tarball(
-                &mut writer,
-                &timeline,
-                lsn,
-                prev_lsn,
-                full_backup,
-                &ctx,
-            )
-            .await?;
-        } else {
-            let mut writer = pgb.copyout_writer();
-            if gzip {
-                let mut encoder = GzipEncoder::with_quality(
-                    writer,
-                    // NOTE using fast compression because it's on the critical path
-                    //      for compute startup. For an empty database, we get
-                    //      <100KB with this method. The Level::Best compression method
-                    //      gives us <20KB, but maybe we should add basebackup caching
-                    //      on compute shutdown first.
-                    async_compression::Level::Fastest,
-                );
-                basebackup::send_basebackup_tarball(
-                    &mut encoder,
-                    &timeline,
-                    lsn,
-                    prev_lsn,
-                    full_backup,
-                    &ctx,
-                )
-                .await?;
-                // shutdown the encoder to ensure the gzip footer is written
-                encoder.shutdown().await?;
-            } else {
-                basebackup::send_basebackup_tarball(
-                    &mut writer,
-                    &timeline,
-                    lsn,
-                    prev_lsn,
-                    full_backup,
-                    &ctx,
-                )
-                .await?;
-            }
-        }
+        // Send a tarball of the latest layer on the timeline. Fullbackup is never
+        // compressed. TODO Compress in that case too (tests need to be updated)
+        //
+        // A client that went away fails the writes, which stops the basebackup, unless it's
+        // being spilled to a file: the shutdown of the connection task, e.g. by a tenant detach,
+        // stops it then.
+        let cancel = task_mgr::shutdown_token();
+        let mut writer = pgb.copyout_writer();
+        let stats = basebackup::send_basebackup_tarball(
+            self.conf,
+            &mut writer,
+            &timeline,
+            lsn,
+            prev_lsn,
+            full_backup,
+            since_lsn,
+            compression,
+            best_effort,
+            &databases,
+            &ctx,
+            &cancel,
+        )
+        .await?;
 
         pgb.write_message_noflush(&BeMessage::CopyDone)?;
         pgb.flush().await?;
@@ -1071,6 +1051,14 @@ impl PageServerHandler {
         info!(
             lsn_await_millis = lsn_awaited_after.as_millis(),
             basebackup_millis = basebackup_after.as_millis(),
+            basebackup_lsn = %stats.lsn,
+            prev_lsn = %stats.prev_lsn,
+            files = stats.files_written,
+            bytes = stats.bytes_written,
+            slru_segments = stats.slru_segments,
+            twophase_files = stats.twophase_files,
+            skipped_files = stats.skipped_files,
+            skipped_databases = stats.skipped_databases,
             "basebackup complete"
         );
 
@@ -1211,18 +1199,22 @@ where
                 None
             };
 
-            let gzip = if params.len() >= 4 {
-                if params[3] == "--gzip" {
-                    true
-                } else {
-                    return Err(QueryError::Other(anyhow::anyhow!(
-                        "Parameter in position 3 unknown {}",
-                        params[3],
-                    )));
+            let mut compression = BasebackupCompression::None;
+            let mut best_effort = false;
+            let mut databases = DatabaseFilter::All;
+            for (i, param) in params.iter().enumerate().skip(3) {
+                match *param {
+                    "--gzip" => compression = BasebackupCompression::Gzip,
+                    "--zstd" => compression = BasebackupCompression::Zstd,
+                    "--best-effort" => best_effort = true,
+                    _ if parse_database_filter_flag(param, &mut databases)? => {}
+                    _ => {
+                        return Err(QueryError::Other(anyhow::anyhow!(
+                            "Parameter in position {i} unknown {param}",
+                        )))
+                    }
                 }
-            } else {
-                false
-            };
+            }
 
             metrics::metric_vec_duration::observe_async_block_duration_by_result(
                 &*crate::metrics::BASEBACKUP_QUERY_TIME,
@@ -1234,7 +1226,10 @@ where
                         lsn,
                         None,
                         false,
-                        gzip,
+                        None,
+                        compression,
+                        best_effort,
+                        databases,
                         ctx,
                     )
                     .await?;
@@ -1316,6 +1311,14 @@ where
             } else {
                 None
             };
+            let mut databases = DatabaseFilter::All;
+            for (i, param) in params.iter().enumerate().skip(4) {
+                if !parse_database_filter_flag(param, &mut databases)? {
+                    return Err(QueryError::Other(anyhow::anyhow!(
+                        "Parameter in position {i} unknown {param}",
+                    )));
+                }
+            }
 
             self.check_permission(Some(tenant_id))?;
 
@@ -1327,7 +1330,59 @@ where
                 lsn,
                 prev_lsn,
                 true,
+                None,
+                BasebackupCompression::None,
                 false,
+                databases,
+                ctx,
+            )
+            .await?;
+            pgb.write_message_noflush(&BeMessage::CommandComplete(b"SELECT 1"))?;
+        }
+        // same as fullbackup, but only with the relation blocks changed since an older LSN
+        else if query_string.starts_with("incrementalbackup ") {
+            let (_, params_raw) = query_string.split_at("incrementalbackup ".len());
+            let params = params_raw.split_whitespace().collect::<Vec<_>>();
+
+            if params.len() < 3 {
+                return Err(QueryError::Other(anyhow::anyhow!(
+                    "invalid param number for incrementalbackup command"
+                )));
+            }
+
+            let tenant_id = TenantId::from_str(params[0])
+                .with_context(|| format!("Failed to parse tenant id from {}", params[0]))?;
+            let timeline_id = TimelineId::from_str(params[1])
+                .with_context(|| format!("Failed to parse timeline id from {}", params[1]))?;
+
+            tracing::Span::current()
+                .record("tenant_id", field::display(tenant_id))
+                .record("timeline_id", field::display(timeline_id));
+
+            let since_lsn = Lsn::from_str(params[2])
+                .with_context(|| format!("Failed to parse Lsn from {}", params[2]))?;
+            let lsn = if params.len() > 3 {
+                Some(
+                    Lsn::from_str(params[3])
+                        .with_context(|| format!("Failed to parse Lsn from {}", params[3]))?,
+                )
+            } else {
+                None
+            };
+
+            self.check_permission(Some(tenant_id))?;
+
+            self.handle_basebackup_request(
+                pgb,
+                tenant_id,
+                timeline_id,
+                lsn,
+                None,
+                true,
+                Some(since_lsn),
+                BasebackupCompression::None,
+                false,
+                DatabaseFilter::All,
                 ctx,
             )
             .await?;
@@ -1522,6 +1577,35 @@ impl From<GetActiveTenantError> for QueryError {
     }
 }
 
+/// Applies a `--include-databases=<oid>,...` or `--exclude-databases=<oid>,...` parameter of
+/// the basebackup commands to `databases`. Returns false if `param` is neither.
+fn parse_database_filter_flag(param: &str, databases: &mut DatabaseFilter) -> anyhow::Result<bool> {
+    let (include, oids) = if let Some(oids) = param.strip_prefix("--include-databases=") {
+        (true, oids)
+    } else if let Some(oids) = param.strip_prefix("--exclude-databases=") {
+        (false, oids)
+    } else {
+        return Ok(false);
+    };
+    anyhow::ensure!(
+        *databases == DatabaseFilter::All,
+        "only one of --include-databases and --exclude-databases can be given"
+    );
+    let dbnodes = oids
+        .split(',')
+        .map(|oid| {
+            oid.parse::<u32>()
+                .with_context(|| format!("Failed to parse database oid from {oid}"))
+        })
+        .collect::<anyhow::Result<HashSet<_>>>()?;
+    *databases = if include {
+        DatabaseFilter::Include(dbnodes)
+    } else {
+        DatabaseFilter::Exclude(dbnodes)
+    };
+    Ok(true)
+}
+
 /// Get active tenant.
 ///
 /// If the tenant is Loading, waits for it to become Active, for up to 30 s. That
@@ -18,7 +18,7 @@ use postgres_ffi::relfile_utils::{FSM_FORKNUM, VISIBILITYMAP_FORKNUM};
 use postgres_ffi::BLCKSZ;
 use postgres_ffi::{Oid, TimestampTz, TransactionId};
 use serde::{Deserialize, Serialize};
-use std::collections::{hash_map, HashMap, HashSet};
+use std::collections::{hash_map, BTreeSet, HashMap, HashSet};
 use std::ops::Range;
 use tokio_util::sync::CancellationToken;
 use tracing::{debug, trace, warn};
@@ -36,6 +36,16 @@ pub enum LsnForTimestamp {
     NoData(Lsn),
 }
 
+/// How a relation changed between two LSNs, see [`Timeline::get_rel_changes`].
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct RelChanges {
+    /// The blocks that were written.
+    pub blocks: BTreeSet<BlockNumber>,
+    /// The smallest size the relation had in between, if it was resized. Blocks past it that
+    /// weren't written again read as zeros, so they differ from the older version as well.
+    pub min_nblocks: Option<BlockNumber>,
+}
+
 #[derive(Debug, thiserror::Error)]
 pub enum CalculateLogicalSizeError {
     #[error("cancelled")]
@@ -258,6 +268,44 @@ impl Timeline {
         }
     }
 
+    /// Get the relations that changed after `since_lsn`, up to and including `lsn`.
+    ///
+    /// The relations that didn't change are not in the map. See
+    /// [`Timeline::get_changed_keys`] for the LSNs that `since_lsn` can be.
+    pub async fn get_rel_changes(
+        &self,
+        since_lsn: Lsn,
+        lsn: Lsn,
+        ctx: &RequestContext,
+    ) -> Result<HashMap<RelTag, RelChanges>, PageReconstructError> {
+        let changed_keys = self.get_changed_keys(since_lsn, lsn, ctx).await?;
+
+        let mut changes: HashMap<RelTag, RelChanges> = HashMap::new();
+        for (key, lsns) in changed_keys {
+            if !is_rel_block_key(key) {
+                continue;
+            }
+            let (rel, blknum) = key_to_rel_block(key)?;
+            let rel_changes = changes.entry(rel).or_default();
+            if key != rel_size_to_key(rel) {
+                rel_changes.blocks.insert(blknum);
+                continue;
+            }
+            // Every write of the size key is a resize; the size right after each of them is
+            // enough to know the smallest one.
+            for size_lsn in lsns {
+                let mut buf = self.get(key, size_lsn, ctx).await?;
+                let nblocks = buf.get_u32_le();
+                rel_changes.min_nblocks = Some(
+                    rel_changes
+                        .min_nblocks
+                        .map_or(nblocks, |min| min.min(nblocks)),
+                );
+            }
+        }
+        Ok(changes)
+    }
+
     /// Look up given SLRU page version.
     pub async fn get_slru_page_at_lsn(
         &self,
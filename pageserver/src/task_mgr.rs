@@ -42,14 +42,14 @@ use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
 
 use futures::FutureExt;
-use tokio::runtime::Runtime;
+use tokio::runtime::{Handle, Runtime};
 use tokio::task::JoinHandle;
 use tokio::task_local;
 use tokio_util::sync::CancellationToken;
 
 use tracing::{debug, error, info, warn};
 
-use once_cell::sync::Lazy;
+use once_cell::sync::{Lazy, OnceCell};
 
 use utils::id::{TenantId, TimelineId};
 
@@ -149,6 +149,27 @@ pub(crate) static BACKGROUND_RUNTIME_WORKER_THREADS: Lazy<usize> = Lazy::new(||
         .unwrap_or_else(|_e| usize::max(1, num_cpus::get()))
 });
 
+/// Runtime of the remote storage sync tasks, when the application that embeds the pageserver
+/// provides its own, see [`set_remote_sync_runtime`].
+static REMOTE_SYNC_RUNTIME: OnceCell<Handle> = OnceCell::new();
+
+/// Runs the remote storage uploads and deletions on `runtime`, e.g. one the embedding
+/// application already owns, instead of [`BACKGROUND_RUNTIME`]. Only the timelines loaded
+/// afterwards use it, so it has to be called before any tenant is loaded, and only once.
+pub fn set_remote_sync_runtime(runtime: Handle) -> anyhow::Result<()> {
+    REMOTE_SYNC_RUNTIME
+        .set(runtime)
+        .map_err(|_| anyhow::anyhow!("the remote sync runtime is already set"))
+}
+
+/// The runtime the remote storage sync tasks of a new timeline run on.
+pub(crate) fn remote_sync_runtime() -> Handle {
+    match REMOTE_SYNC_RUNTIME.get() {
+        Some(runtime) => runtime.clone(),
+        None => BACKGROUND_RUNTIME.handle().clone(),
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct PageserverTaskId(u64);
 
@@ -276,6 +297,15 @@ pub enum TaskKind {
     // task that handles attaching a tenant
     Attach,
 
+    // task that loads the timelines that appeared in the remote storage. One per tenant.
+    RemoteTimelineListRefresh,
+
+    // task that retries the index part download of a timeline loaded without it
+    RemoteReconciliation,
+
+    // heartbeat of the tenant's owner lease in the remote storage
+    RemoteOwnerLease,
+
     // Used mostly for background deletion from s3
     TimelineDeletionWorker,
 
@@ -13,9 +13,11 @@
 
 use anyhow::{bail, Context};
 use futures::FutureExt;
+use pageserver_api::models::RemoteSyncFailedTask;
+use pageserver_api::models::TenantDetachSummary;
 use pageserver_api::models::TimelineState;
-use remote_storage::DownloadError;
 use remote_storage::GenericRemoteStorage;
+use remote_storage::RemoteStorageError;
 use storage_broker::BrokerClientChannel;
 use tokio::sync::watch;
 use tokio::task::JoinSet;
@@ -51,6 +53,7 @@ use self::delete::DeleteTenantFlow;
 use self::metadata::LoadMetadataError;
 use self::metadata::TimelineMetadata;
 use self::mgr::TenantsMap;
+use self::remote_timeline_client::RemoteOpRetrySettings;
 use self::remote_timeline_client::RemoteTimelineClient;
 use self::timeline::uninit::TimelineUninitMark;
 use self::timeline::uninit::UninitializedTimeline;
@@ -118,7 +121,7 @@ mod span;
 
 pub mod metadata;
 mod par_fsync;
-mod remote_timeline_client;
+pub(crate) mod remote_timeline_client;
 pub mod storage_layer;
 
 pub mod config;
@@ -131,6 +134,7 @@ pub(crate) mod timeline;
 
 pub mod size;
 
+pub use remote_timeline_client::{subscribe_sync_events, SyncEvent};
 pub(crate) use timeline::span::debug_assert_current_span_has_tenant_and_timeline_id;
 pub use timeline::{
     LocalLayerInfoForDiskUsageEviction, LogicalSizeCalculationCause, PageReconstructError, Timeline,
@@ -192,6 +196,43 @@ pub struct Tenant {
     eviction_task_tenant_state: tokio::sync::Mutex<EvictionTaskTenantState>,
 
     pub(crate) delete_progress: Arc<tokio::sync::Mutex<DeleteTenantFlow>>,
+
+    /// Remote timelines that [`Tenant::refresh_remote_timelines`] failed to load, by id.
+    remote_load_failures: Mutex<HashMap<TimelineId, RemoteLoadFailure>>,
+}
+
+/// The failures in a row to load a remote timeline, and the last one.
+struct RemoteLoadFailure {
+    attempts: u32,
+    error: String,
+    /// The timeline is not tried again before, the wait doubles with every failure.
+    retry_at: Instant,
+}
+
+impl RemoteLoadFailure {
+    const MAX_BACKOFF: Duration = Duration::from_secs(10 * 60);
+
+    fn new(now: Instant) -> Self {
+        RemoteLoadFailure {
+            attempts: 0,
+            error: String::new(),
+            retry_at: now,
+        }
+    }
+
+    fn record(&mut self, error: &anyhow::Error, base_backoff: Duration, now: Instant) {
+        self.attempts += 1;
+        self.error = format!("{error:#}");
+        let backoff = base_backoff
+            .checked_mul(2u32.saturating_pow(self.attempts - 1))
+            .map_or(Self::MAX_BACKOFF, |backoff| backoff.min(Self::MAX_BACKOFF));
+        self.retry_at = now + backoff;
+    }
+
+    /// Whether the next refresh should try to load the timeline again.
+    fn is_due(&self, now: Instant, max_sync_errors: u32) -> bool {
+        self.attempts < max_sync_errors && self.retry_at <= now
+    }
 }
 
 // We should not blindly overwrite local metadata with remote one.
@@ -207,9 +248,15 @@ pub struct Tenant {
 //     wal for these layers needs to be reingested for example
 //
 // So the solution is to take remote metadata only when we're attaching.
+//
+// If the remote metadata still appears to be ahead, e.g. because an interrupted download
+// left a stale local metadata file behind, the one with the highest disk_consistent_lsn
+// (then latest_gc_cutoff_lsn) wins and the other one is logged. With `strict`, such a
+// conflict fails the timeline load instead.
 pub fn merge_local_remote_metadata<'a>(
     local: Option<&'a TimelineMetadata>,
     remote: Option<&'a TimelineMetadata>,
+    strict: bool,
 ) -> anyhow::Result<(&'a TimelineMetadata, bool)> {
     match (local, remote) {
         (None, None) => anyhow::bail!("we should have either local metadata or remote"),
@@ -238,22 +285,30 @@ pub fn merge_local_remote_metadata<'a>(
                 (Equal, Greater) => Ok((local, true)),
 
                 // We always update the local value first, so something else must have
-                // updated the remote value, probably a different pageserver.
-                // The control plane is supposed to prevent this from happening.
-                // Bail out.
+                // updated the remote value, probably a different pageserver, or the local
+                // file is a leftover. The control plane is supposed to prevent the former.
                 (Less, Less)
                 | (Less, Equal)
                 | (Equal, Less)
                 | (Less, Greater)
                 | (Greater, Less) => {
-                    anyhow::bail!(
-                        r#"remote metadata appears to be ahead of local metadata:
+                    if strict {
+                        anyhow::bail!(
+                            r#"remote metadata appears to be ahead of local metadata:
 local:
   {local:#?}
 remote:
   {remote:#?}
 "#
-                    );
+                        );
+                    }
+                    if consistent_lsn_cmp == Greater {
+                        warn!("remote metadata has a newer gc cutoff, discarding it in favor of the local one with the higher disk_consistent_lsn: {remote:?}");
+                        Ok((local, true))
+                    } else {
+                        warn!("remote metadata appears to be ahead of local metadata, discarding the local one: {local:?}");
+                        Ok((remote, false))
+                    }
                 }
             }
         }
@@ -384,6 +439,7 @@ impl Tenant {
     /// - Scans the local timeline directory for layer files and builds the layer map
     /// - Downloads remote index file and adds remote files to the layer map
     /// - Schedules remote upload tasks for any files that are present locally but missing from remote storage.
+    ///   If the index file failed to download, that is left to a background task which retries it.
     ///
     /// If the operation fails, the timeline is left in the tenant's hash map in Broken state. On success,
     /// it is marked as Active.
@@ -404,6 +460,7 @@ impl Tenant {
         let (up_to_date_metadata, picked_local) = merge_local_remote_metadata(
             local_metadata.as_ref(),
             remote_startup_data.as_ref().map(|r| &r.remote_metadata),
+            self.conf.strict_metadata_merge,
         )
         .context("merge_local_remote_metadata")?
         .to_owned();
@@ -446,7 +503,13 @@ impl Tenant {
             }
         };
 
-        if self.remote_storage.is_some() {
+        let reconciliation_pending = timeline
+            .remote_client
+            .as_ref()
+            .is_some_and(|remote_client| remote_client.is_reconciliation_pending());
+        if reconciliation_pending {
+            timeline.spawn_pending_reconciliation_task(up_to_date_metadata.disk_consistent_lsn());
+        } else if self.remote_storage.is_some() {
             // Reconcile local state with remote storage, downloading anything that's
             // missing locally, and scheduling uploads for anything that's missing
             // in remote storage.
@@ -736,7 +799,257 @@ impl Tenant {
             None,
             ctx,
         )
-        .await
+        .await?;
+
+        fail::fail_point!("remote-timeline-load-after-init", |_| {
+            anyhow::bail!("failpoint remote-timeline-load-after-init");
+        });
+        Ok(())
+    }
+
+    /// Loads the timelines that appeared in the remote storage since the tenant was attached or
+    /// loaded, e.g. the ones created by another pageserver, the same way [`Self::attach`] does:
+    /// their layers are downloaded on demand.
+    ///
+    /// Timelines that this pageserver has in memory or on disk are never touched, their remote
+    /// state is driven by their own upload queues. A timeline is claimed with an uninit mark
+    /// before loading, so a concurrent [`Self::create_timeline`] cannot race with the refresh.
+    /// Nothing is loaded for a tenant the `tenant_filter` excludes.
+    ///
+    /// A timeline that fails to load, from its index part on, is taken out of the tenant again
+    /// and its directory removed, so that a later refresh starts it over; the others are loaded
+    /// all the same. The refreshes skip it for a backoff that doubles with every failure in a row,
+    /// see [`Self::next_remote_load_retry`]. After `max_sync_errors` failures in a row, the
+    /// timeline is left alone until [`Self::retry_failed_remote_loads`], see
+    /// [`Self::failed_remote_loads`].
+    ///
+    /// Returns the number of loaded timelines.
+    pub(crate) async fn refresh_remote_timelines(
+        self: &Arc<Self>,
+        broker_client: &BrokerClientChannel,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<usize> {
+        span::debug_assert_current_span_has_tenant_id();
+
+        let Some(remote_storage) = self.remote_storage.as_ref() else {
+            return Ok(0);
+        };
+        if !self.conf.restores_tenant(&self.tenant_id) {
+            return Ok(0);
+        }
+        anyhow::ensure!(
+            self.is_active(),
+            "cannot refresh remote timelines of an inactive tenant"
+        );
+
+        let remote_timeline_ids = remote_timeline_client::list_remote_timelines(
+            remote_storage,
+            self.conf,
+            self.tenant_id,
+        )
+        .await?;
+
+        let retry_settings = RemoteOpRetrySettings::from_conf(self.conf);
+        let new_timeline_ids = {
+            let timelines = self.timelines.lock().unwrap();
+            let failures = self.remote_load_failures.lock().unwrap();
+            let now = Instant::now();
+            remote_timeline_ids
+                .into_iter()
+                .filter(|timeline_id| !timelines.contains_key(timeline_id))
+                .filter(|timeline_id| {
+                    failures.get(timeline_id).map_or(true, |failure| {
+                        failure.is_due(now, retry_settings.max_sync_errors)
+                    })
+                })
+                .collect::<Vec<_>>()
+        }
+        .into_iter()
+        .filter(|timeline_id| {
+            !self
+                .conf
+                .timeline_path(&self.tenant_id, timeline_id)
+                .exists()
+                && !self
+                    .conf
+                    .timeline_uninit_mark_file_path(self.tenant_id, *timeline_id)
+                    .exists()
+        })
+        .collect::<Vec<_>>();
+        if new_timeline_ids.is_empty() {
+            return Ok(0);
+        }
+        info!("found {} new remote timelines", new_timeline_ids.len());
+
+        let mut new_timelines = HashMap::new();
+        for timeline_id in new_timeline_ids {
+            let client = RemoteTimelineClient::new(
+                remote_storage.clone(),
+                self.conf,
+                self.tenant_id,
+                timeline_id,
+            );
+            let index_part = client
+                .download_index_file()
+                .instrument(info_span!("download_index_part", %timeline_id))
+                .await
+                .with_context(|| format!("download index part for timeline {timeline_id}"));
+            let index_part = match index_part {
+                Ok(MaybeDeletedIndexPart::IndexPart(index_part)) => index_part,
+                Ok(MaybeDeletedIndexPart::Deleted(_)) => {
+                    debug!("timeline {timeline_id} is deleted, skipping");
+                    continue;
+                }
+                Err(e) => {
+                    self.record_remote_load_failure(timeline_id, &e, &retry_settings);
+                    continue;
+                }
+            };
+            match index_part.parse_metadata().context("parse_metadata") {
+                Ok(remote_metadata) => {
+                    new_timelines.insert(timeline_id, (remote_metadata, index_part, client));
+                }
+                Err(e) => self.record_remote_load_failure(timeline_id, &e, &retry_settings),
+            }
+        }
+
+        // Ancestors that are not new are loaded already, only order the new timelines.
+        let new_timeline_ids = new_timelines.keys().copied().collect::<BTreeSet<_>>();
+        let sorted_timelines = tree_sort_timelines(new_timelines, |(remote_metadata, ..)| {
+            remote_metadata
+                .ancestor_timeline()
+                .filter(|ancestor_id| new_timeline_ids.contains(ancestor_id))
+        })?;
+
+        let mut loaded = 0;
+        for (timeline_id, (remote_metadata, index_part, remote_client)) in sorted_timelines {
+            let uninit_mark = {
+                let timelines = self.timelines.lock().unwrap();
+                match self.create_timeline_uninit_mark(timeline_id, &timelines) {
+                    Ok(uninit_mark) => uninit_mark,
+                    Err(e) => {
+                        // The timeline is being created locally, leave it to its creator.
+                        debug!("skipping remote timeline {timeline_id}: {e:#}");
+                        continue;
+                    }
+                }
+            };
+
+            if let Err(e) = self
+                .load_remote_timeline(timeline_id, index_part, remote_metadata, remote_client, ctx)
+                .await
+            {
+                self.unload_failed_remote_timeline(timeline_id, uninit_mark)
+                    .await;
+                self.record_remote_load_failure(timeline_id, &e, &retry_settings);
+                continue;
+            }
+            self.remote_load_failures
+                .lock()
+                .unwrap()
+                .remove(&timeline_id);
+            uninit_mark.remove_uninit_mark().with_context(|| {
+                format!("failed to remove uninit mark of remote timeline {timeline_id}")
+            })?;
+            loaded += 1;
+
+            // If the tenant is shutting down, the timeline is stopped along with the others.
+            if self.is_active() {
+                self.get_timeline(timeline_id, false)?
+                    .activate(broker_client.clone(), None, ctx);
+            }
+        }
+
+        Ok(loaded)
+    }
+
+    /// Undoes a failed [`Self::load_remote_timeline`]: the timeline is taken out of the tenant
+    /// if it got that far, and its directory removed along with the uninit mark.
+    async fn unload_failed_remote_timeline(
+        &self,
+        timeline_id: TimelineId,
+        uninit_mark: TimelineUninitMark,
+    ) {
+        let timeline = self.timelines.lock().unwrap().remove(&timeline_id);
+        if let Some(timeline) = timeline {
+            timeline.set_state(TimelineState::Stopping);
+            if let Some(remote_client) = &timeline.remote_client {
+                if let Err(e) = remote_client.cancel_sync().await {
+                    debug!("remote client of timeline {timeline_id} is not running: {e}");
+                }
+            }
+            task_mgr::shutdown_tasks(None, Some(self.tenant_id), Some(timeline_id)).await;
+        }
+        cleanup_timeline_directory(uninit_mark);
+    }
+
+    /// Counts a failure to load a remote timeline, and backs off its next attempt. After
+    /// `max_sync_errors` failures in a row, the timeline is not retried anymore.
+    fn record_remote_load_failure(
+        &self,
+        timeline_id: TimelineId,
+        error: &anyhow::Error,
+        retry_settings: &RemoteOpRetrySettings,
+    ) {
+        let mut failures = self.remote_load_failures.lock().unwrap();
+        let now = Instant::now();
+        let failure = failures
+            .entry(timeline_id)
+            .or_insert_with(|| RemoteLoadFailure::new(now));
+        failure.record(error, retry_settings.base_backoff, now);
+        if failure.attempts < retry_settings.max_sync_errors {
+            warn!(
+                "failed to load remote timeline {timeline_id} (attempt {}), retrying in {:?}: {error:#}",
+                failure.attempts,
+                failure.retry_at - now,
+            );
+        } else {
+            error!(
+                "failed to load remote timeline {timeline_id} {} times in a row, not retrying it anymore: {error:#}",
+                failure.attempts
+            );
+        }
+    }
+
+    /// When the earliest of the remote timelines that failed to load is due to be retried,
+    /// if there are any to retry.
+    pub(crate) fn next_remote_load_retry(&self) -> Option<Instant> {
+        let max_sync_errors = RemoteOpRetrySettings::from_conf(self.conf).max_sync_errors;
+        self.remote_load_failures
+            .lock()
+            .unwrap()
+            .values()
+            .filter(|failure| failure.attempts < max_sync_errors)
+            .map(|failure| failure.retry_at)
+            .min()
+    }
+
+    /// The remote timelines that have failed to load `max_sync_errors` times in a row and
+    /// wait for [`Self::retry_failed_remote_loads`], sorted by timeline id.
+    pub fn failed_remote_loads(&self) -> Vec<RemoteSyncFailedTask> {
+        let max_sync_errors = RemoteOpRetrySettings::from_conf(self.conf).max_sync_errors;
+        let failures = self.remote_load_failures.lock().unwrap();
+        let mut failed = failures
+            .iter()
+            .filter(|(_, failure)| failure.attempts >= max_sync_errors)
+            .collect::<Vec<_>>();
+        failed.sort_unstable_by_key(|(timeline_id, _)| **timeline_id);
+        failed
+            .into_iter()
+            .map(|(timeline_id, failure)| RemoteSyncFailedTask {
+                operation: format!("load remote timeline {timeline_id}"),
+                error: failure.error.clone(),
+            })
+            .collect()
+    }
+
+    /// Makes the next refresh try the failed remote timelines again. Returns their number.
+    pub fn retry_failed_remote_loads(&self) -> usize {
+        let max_sync_errors = RemoteOpRetrySettings::from_conf(self.conf).max_sync_errors;
+        let mut failures = self.remote_load_failures.lock().unwrap();
+        let before = failures.len();
+        failures.retain(|_, failure| failure.attempts < max_sync_errors);
+        before - failures.len()
     }
 
     /// Create a placeholder Tenant object for a broken tenant
@@ -911,7 +1224,17 @@ impl Tenant {
         for entry in
             std::fs::read_dir(&timelines_dir).context("list timelines directory for tenant")?
         {
-            let entry = entry.context("read timeline dir entry")?;
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    // Same as for the tenants dir: one bad entry should not keep the
+                    // other timelines of the tenant from loading.
+                    error!(
+                        "Failed to read timelines dir entry in {timelines_dir:?}, reason: {e:?}"
+                    );
+                    continue;
+                }
+            };
             let timeline_dir = entry.path();
 
             if crate::is_temporary(&timeline_dir) {
@@ -940,13 +1263,14 @@ impl Tenant {
                     "Found an uninit mark file {}, removing the timeline and its uninit mark",
                     timeline_uninit_mark_file.display()
                 );
-                let timeline_id = TimelineId::try_from(timeline_uninit_mark_file.file_stem())
-                    .with_context(|| {
-                        format!(
-                            "Could not parse timeline id out of the timeline uninit mark name {}",
-                            timeline_uninit_mark_file.display()
-                        )
-                    })?;
+                let Ok(timeline_id) = TimelineId::try_from(timeline_uninit_mark_file.file_stem())
+                else {
+                    warn!(
+                        "Could not parse timeline id out of the timeline uninit mark name {}, skipping it",
+                        timeline_uninit_mark_file.display()
+                    );
+                    continue;
+                };
                 let timeline_dir = self.conf.timeline_path(&self.tenant_id, &timeline_id);
                 if let Err(e) =
                     remove_timeline_and_uninit_mark(&timeline_dir, timeline_uninit_mark_file)
@@ -955,13 +1279,13 @@ impl Tenant {
                 }
             } else if crate::is_delete_mark(&timeline_dir) {
                 // If metadata exists, load as usual, continue deletion
-                let timeline_id =
-                    TimelineId::try_from(timeline_dir.file_stem()).with_context(|| {
-                        format!(
-                            "Could not parse timeline id out of the timeline uninit mark name {}",
-                            timeline_dir.display()
-                        )
-                    })?;
+                let Ok(timeline_id) = TimelineId::try_from(timeline_dir.file_stem()) else {
+                    warn!(
+                        "Could not parse timeline id out of the timeline delete mark name {}, skipping it",
+                        timeline_dir.display()
+                    );
+                    continue;
+                };
 
                 info!("Found deletion mark for timeline {}", timeline_id);
 
@@ -1004,13 +1328,14 @@ impl Tenant {
                     );
                     continue;
                 }
-                let timeline_id =
-                    TimelineId::try_from(timeline_dir.file_name()).with_context(|| {
-                        format!(
-                            "Could not parse timeline id out of the timeline dir name {}",
-                            timeline_dir.display()
-                        )
-                    })?;
+                let Ok(timeline_id) = TimelineId::try_from(timeline_dir.file_name()) else {
+                    // A file or directory that doesn't look like a timeline ID
+                    warn!(
+                        "unexpected file or directory in timelines directory: {}",
+                        timeline_dir.display()
+                    );
+                    continue;
+                };
                 let timeline_uninit_mark_file = self
                     .conf
                     .timeline_uninit_mark_file_path(self.tenant_id, timeline_id);
@@ -1035,20 +1360,19 @@ impl Tenant {
                     continue;
                 }
 
-                let file_name = entry.file_name();
-                if let Ok(timeline_id) =
-                    file_name.to_str().unwrap_or_default().parse::<TimelineId>()
-                {
-                    let metadata = load_metadata(self.conf, &self.tenant_id, &timeline_id)
-                        .context("failed to load metadata")?;
-                    timelines_to_load.insert(timeline_id, metadata);
-                } else {
-                    // A file or directory that doesn't look like a timeline ID
+                if !timeline_dir.is_dir() {
                     warn!(
-                        "unexpected file or directory in timelines directory: {}",
-                        file_name.to_string_lossy()
+                        "unexpected file in timelines directory: {}",
+                        timeline_dir.display()
                     );
+                    continue;
                 }
+
+                // A corrupt metadata file of a timeline that is there is still an error:
+                // loading the tenant without the timeline would lose its data silently.
+                let metadata = load_metadata(self.conf, &self.tenant_id, &timeline_id)
+                    .context("failed to load metadata")?;
+                timelines_to_load.insert(timeline_id, metadata);
             }
         }
 
@@ -1230,7 +1554,7 @@ impl Tenant {
                         Some(remote_client),
                     )
                 }
-                Err(DownloadError::NotFound) => {
+                Err(RemoteStorageError::NotFound) => {
                     info!("no index file was found on the remote, found_delete_mark: {found_delete_mark}");
 
                     if found_delete_mark {
@@ -1248,6 +1572,16 @@ impl Tenant {
                     // We're loading fresh timeline that didnt yet make it into remote.
                     (None, Some(remote_client))
                 }
+                // The local files are complete, so the timeline can be served from them, and
+                // reconciled with the remote storage once the index part downloads.
+                Err(e) if !e.is_permanent() && !found_delete_mark => {
+                    warn!("failed to download the index part, loading the timeline from the local data, remote reconciliation is pending: {e:#}");
+                    remote_client
+                        .init_upload_queue_pending_reconciliation(&local_metadata)
+                        .context("init queue pending reconciliation")
+                        .map_err(LoadLocalTimelineError::Load)?;
+                    (None, Some(remote_client))
+                }
                 Err(e) => return Err(LoadLocalTimelineError::Load(anyhow::Error::new(e))),
             },
             None => {
@@ -1713,7 +2047,7 @@ impl Tenant {
 
             // Spawn gc and compaction loops. The loops will shut themselves
             // down when they notice that the tenant is inactive.
-            tasks::start_background_loops(self, background_jobs_can_start);
+            tasks::start_background_loops(self, broker_client.clone(), background_jobs_can_start);
 
             let mut activated_timelines = 0;
 
@@ -1783,17 +2117,7 @@ impl Tenant {
         // But the tenant background loops are joined-on in our caller.
         // It's mesed up.
         // we just ignore the failure to stop
-
-        match self.set_stopping(shutdown_progress, false).await {
-            Ok(()) => {}
-            Err(SetStoppingError::Broken) => {
-                // assume that this is acceptable
-            }
-            Err(SetStoppingError::AlreadyStopping(other)) => {
-                // give caller the option to wait for this this shutdown
-                return Err(other);
-            }
-        };
+        self.start_shutdown(shutdown_progress).await?;
 
         if freeze_and_flush {
             // walreceiver has already began to shutdown with TenantState::Stopping, but we need to
@@ -1819,6 +2143,119 @@ impl Tenant {
         Ok(())
     }
 
+    /// Shutdown the tenant for its removal from the pageserver, see [`Tenant::shutdown`].
+    ///
+    /// Unlike the pageserver shutdown, the in-memory data is not flushed. With `flush_timeout`,
+    /// the uploads and deletions already scheduled for the timelines get that long to complete,
+    /// so that the remote storage is left consistent. The rest of the remote operations,
+    /// downloads included, are cancelled before the tenant tasks are shut down.
+    pub(crate) async fn shutdown_for_removal(
+        &self,
+        shutdown_progress: completion::Barrier,
+        flush_timeout: Option<Duration>,
+    ) -> Result<TenantDetachSummary, completion::Barrier> {
+        span::debug_assert_current_span_has_tenant_id();
+        self.start_shutdown(shutdown_progress).await?;
+
+        if flush_timeout.is_some() {
+            // No new layers from the WAL while the uploads are flushed.
+            task_mgr::shutdown_tasks(
+                Some(TaskKind::WalReceiverManager),
+                Some(self.tenant_id),
+                None,
+            )
+            .await;
+        }
+        let summary = self.stop_remote_sync(flush_timeout).await;
+
+        task_mgr::shutdown_tasks(None, Some(self.tenant_id), None).await;
+
+        Ok(summary)
+    }
+
+    /// Transitions the tenant into Stopping, or returns the `Barrier` of the shutdown already
+    /// in progress.
+    async fn start_shutdown(
+        &self,
+        shutdown_progress: completion::Barrier,
+    ) -> Result<(), completion::Barrier> {
+        match self.set_stopping(shutdown_progress, false).await {
+            Ok(()) => {}
+            Err(SetStoppingError::Broken) => {
+                // assume that this is acceptable
+            }
+            Err(SetStoppingError::AlreadyStopping(other)) => {
+                // give caller the option to wait for this this shutdown
+                return Err(other);
+            }
+        };
+        Ok(())
+    }
+
+    /// Waits up to `flush_timeout` for the scheduled uploads and deletions of every timeline,
+    /// then stops their upload queues and cancels whatever is still in flight.
+    async fn stop_remote_sync(&self, flush_timeout: Option<Duration>) -> TenantDetachSummary {
+        let per_timeline = |timeline_id: TimelineId, client: Arc<RemoteTimelineClient>| {
+            async move {
+                let pending = client.pending_operations();
+                let flushed = match flush_timeout {
+                    Some(timeout) => {
+                        match tokio::time::timeout(timeout, client.wait_completion()).await {
+                            Ok(Ok(())) => true,
+                            Ok(Err(e)) => {
+                                warn!("failed to flush the remote operations: {e:#}");
+                                false
+                            }
+                            Err(_) => {
+                                warn!("remote operations not flushed in {timeout:?}");
+                                false
+                            }
+                        }
+                    }
+                    None => false,
+                };
+                let cancelled = client.pending_operations();
+                if let Err(e) = client.cancel_sync().await {
+                    debug!("no remote operations to cancel: {e}");
+                }
+                (
+                    timeline_id,
+                    flushed,
+                    pending.saturating_sub(cancelled),
+                    cancelled,
+                )
+            }
+            .instrument(info_span!("stop_remote_sync", %timeline_id))
+        };
+
+        let mut js = JoinSet::new();
+        {
+            let timelines = self.timelines.lock().unwrap();
+            for (timeline_id, timeline) in timelines.iter() {
+                if let Some(client) = timeline.remote_client.as_ref() {
+                    js.spawn(per_timeline(*timeline_id, Arc::clone(client)));
+                }
+            }
+        }
+
+        let mut summary = TenantDetachSummary::default();
+        while let Some(res) = js.join_next().await {
+            match res {
+                Ok((timeline_id, flushed, flushed_operations, cancelled_operations)) => {
+                    summary.flushed_operations += flushed_operations;
+                    summary.cancelled_operations += cancelled_operations;
+                    if flush_timeout.is_some() && !flushed {
+                        summary.unflushed_timelines.push(timeline_id);
+                    }
+                }
+                Err(je) if je.is_cancelled() => unreachable!("no cancelling used"),
+                Err(je) if je.is_panic() => { /* logged already */ }
+                Err(je) => warn!("unexpected JoinError: {je:?}"),
+            }
+        }
+        summary
+    }
+
     /// Change tenant status to Stopping, to mark that it is being shut down.
     ///
     /// This function waits for the tenant to become active if it isn't already, before transitioning it into Stopping state.
@@ -2273,6 +2710,7 @@ impl Tenant {
             cached_synthetic_tenant_size: Arc::new(AtomicU64::new(0)),
             eviction_task_tenant_state: tokio::sync::Mutex::new(EvictionTaskTenantState::default()),
             delete_progress: Arc::new(tokio::sync::Mutex::new(DeleteTenantFlow::default())),
+            remote_load_failures: Mutex::new(HashMap::new()),
         }
     }
 
@@ -2768,7 +3206,7 @@ impl Tenant {
             }
         }
         let pgdata_path = &initdb_path;
-        let pgdata_lsn = import_datadir::get_lsn_from_controlfile(pgdata_path)?.align();
+        let pgdata_lsn = import_datadir::get_lsn_from_controlfile(pgdata_path, pg_version)?.align();
 
         // Import the contents of the data directory at the initial checkpoint
         // LSN, and any WAL after that.
@@ -4098,6 +4536,75 @@ mod tests {
         Ok(())
     }
 
+    #[test]
+    fn merge_conflicting_metadata() -> anyhow::Result<()> {
+        let metadata = |disk_consistent_lsn, latest_gc_cutoff_lsn| {
+            TimelineMetadata::new(
+                Lsn(disk_consistent_lsn),
+                None,
+                None,
+                Lsn(0),
+                Lsn(latest_gc_cutoff_lsn),
+                Lsn(0),
+                DEFAULT_PG_VERSION,
+                RegionId(0),
+            )
+        };
+
+        let stale_local = metadata(0x20, 0x10);
+        let remote = metadata(0x30, 0x10);
+        let (picked, picked_local) =
+            merge_local_remote_metadata(Some(&stale_local), Some(&remote), false)?;
+        assert_eq!(picked, &remote);
+        assert!(!picked_local);
+
+        // The highest disk_consistent_lsn wins, even with an older gc cutoff.
+        let local = metadata(0x40, 0x10);
+        let remote = metadata(0x30, 0x20);
+        let (picked, picked_local) =
+            merge_local_remote_metadata(Some(&local), Some(&remote), false)?;
+        assert_eq!(picked, &local);
+        assert!(picked_local);
+
+        merge_local_remote_metadata(Some(&stale_local), Some(&remote), true)
+            .expect_err("strict merge should fail on conflicting metadata");
+        merge_local_remote_metadata(Some(&local), Some(&remote), true)
+            .expect_err("strict merge should fail on conflicting metadata");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn junk_in_timelines_dir_is_skipped() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("junk_in_timelines_dir_is_skipped")?;
+        {
+            let (tenant, ctx) = harness.load().await;
+            tenant
+                .create_test_timeline(
+                    TIMELINE_ID,
+                    Lsn(0x10),
+                    DEFAULT_PG_VERSION,
+                    RegionId(0),
+                    &ctx,
+                )
+                .await?;
+        }
+
+        let timelines_dir = harness.conf.timelines_path(&harness.tenant_id);
+        std::fs::create_dir(timelines_dir.join("lost+found"))?;
+        std::fs::write(timelines_dir.join(".DS_Store"), b"")?;
+        std::fs::write(timelines_dir.join("junk.___uninit"), b"")?;
+        std::fs::write(timelines_dir.join(NEW_TIMELINE_ID.to_string()), b"")?;
+
+        let (tenant, _ctx) = harness.load().await;
+        tenant
+            .get_timeline(TIMELINE_ID, true)
+            .expect("the timeline should be loaded despite the junk around it");
+        assert!(tenant.get_timeline(NEW_TIMELINE_ID, false).is_err());
+
+        Ok(())
+    }
+
     #[tokio::test]
     async fn test_images() -> anyhow::Result<()> {
         let (tenant, ctx) = TenantHarness::create("test_images")?.load().await;
@@ -4683,4 +5190,165 @@ mod tests {
 
         Ok(())
     }
+
+    #[cfg(feature = "testing")]
+    #[tokio::test]
+    async fn failed_remote_timeline_load_is_retried() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("failed_remote_timeline_load_is_retried")?;
+        let remote_storage = {
+            use remote_storage::{RemoteStorageConfig, RemoteStorageKind};
+            let path = harness.conf.workdir.join("localfs");
+            std::fs::create_dir_all(&path)?;
+            let config = RemoteStorageConfig {
+                max_concurrent_syncs: std::num::NonZeroUsize::new(100).unwrap(),
+                max_concurrent_sync_per_tenant: None,
+                max_concurrent_sync_startup: None,
+                max_sync_errors: std::num::NonZeroU32::new(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS,
+                )
+                .unwrap(),
+                max_retries: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_RETRIES,
+                base_backoff_ms: remote_storage::DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS,
+                compression: remote_storage::Compression::None,
+                max_bytes_per_sec: None,
+                dry_run: false,
+                encryption_key_file: None,
+                operation_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+                list_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+                upload_start_jitter: remote_storage::DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+                prefix: None,
+                storage: RemoteStorageKind::LocalFs(remote_storage::LocalFsConfig {
+                    local_path: path,
+                    read_only: false,
+                    fsync_batch_window: None,
+                    direct_io_min_size: None,
+                }),
+            };
+            GenericRemoteStorage::from_config(&config)?
+        };
+        let ctx = RequestContext::new(TaskKind::UnitTest, DownloadBehavior::Error);
+
+        {
+            let tenant = harness.try_load(&ctx, Some(remote_storage.clone())).await?;
+            let tline = tenant
+                .create_test_timeline(
+                    TIMELINE_ID,
+                    Lsn(0x10),
+                    DEFAULT_PG_VERSION,
+                    RegionId(0),
+                    &ctx,
+                )
+                .await?;
+            tline
+                .remote_client
+                .clone()
+                .unwrap()
+                .wait_completion()
+                .await?;
+            task_mgr::shutdown_tasks(None, Some(tenant.tenant_id), None).await;
+        }
+        // The timeline is only in the remote storage now, as if another pageserver created it.
+        std::fs::remove_dir_all(harness.timeline_path(&TIMELINE_ID))?;
+        let tenant = harness.try_load(&ctx, Some(remote_storage)).await?;
+        assert!(tenant.get_timeline(TIMELINE_ID, false).is_err());
+        let _e = info_span!("remote_list_refresh", tenant_id = %tenant.tenant_id).entered();
+
+        // Never connected to: the timeline is loaded into an active tenant, but not activated
+        // through the broker in this test.
+        let broker_client = storage_broker::connect("http://127.0.0.1:1", Duration::from_secs(5))?;
+
+        // The harness configures no `[remote_storage]`, the default `max_sync_errors` applies.
+        fail::cfg("remote-timeline-load-after-init", "return").unwrap();
+        let attempts = || tenant.remote_load_failures.lock().unwrap()[&TIMELINE_ID].attempts;
+        for attempt in 1..=remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS {
+            assert_eq!(
+                tenant
+                    .refresh_remote_timelines(&broker_client, &ctx)
+                    .await?,
+                0
+            );
+            assert_eq!(attempts(), attempt);
+            // Nothing of the failed load is left behind for the next refresh.
+            assert!(tenant.get_timeline(TIMELINE_ID, false).is_err());
+            assert!(!harness.timeline_path(&TIMELINE_ID).exists());
+            assert!(!harness
+                .conf
+                .timeline_uninit_mark_file_path(tenant.tenant_id, TIMELINE_ID)
+                .exists());
+
+            if attempt < remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS {
+                // The refreshes leave the timeline alone until its backoff is over.
+                assert!(tenant.next_remote_load_retry().unwrap() > Instant::now());
+                tenant
+                    .refresh_remote_timelines(&broker_client, &ctx)
+                    .await?;
+                assert_eq!(attempts(), attempt, "attempt {attempt} is backed off");
+                tenant
+                    .remote_load_failures
+                    .lock()
+                    .unwrap()
+                    .get_mut(&TIMELINE_ID)
+                    .unwrap()
+                    .retry_at = Instant::now();
+            } else {
+                assert_eq!(
+                    tenant.next_remote_load_retry(),
+                    None,
+                    "the last attempt gives up"
+                );
+            }
+        }
+        fail::remove("remote-timeline-load-after-init");
+
+        let failed = tenant.failed_remote_loads();
+        assert_eq!(failed.len(), 1);
+        assert!(failed[0].error.contains("remote-timeline-load-after-init"));
+        assert_eq!(
+            tenant
+                .refresh_remote_timelines(&broker_client, &ctx)
+                .await?,
+            0
+        );
+
+        assert_eq!(tenant.retry_failed_remote_loads(), 1);
+        assert_eq!(
+            tenant
+                .refresh_remote_timelines(&broker_client, &ctx)
+                .await?,
+            1
+        );
+        assert!(tenant.get_timeline(TIMELINE_ID, false).is_ok());
+        assert!(tenant.failed_remote_loads().is_empty());
+
+        task_mgr::shutdown_tasks(None, Some(tenant.tenant_id), None).await;
+        Ok(())
+    }
+
+    #[test]
+    fn remote_load_failure_backoff_doubles() {
+        let now = Instant::now();
+        let error = anyhow::anyhow!("failed to load");
+        let mut failure = RemoteLoadFailure::new(now);
+        assert!(failure.is_due(now, 3));
+
+        let backoffs = (0..12)
+            .map(|_| {
+                failure.record(&error, Duration::from_secs(1), now);
+                failure.retry_at - now
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(
+            backoffs[..4],
+            [1, 2, 4, 8].map(Duration::from_secs),
+            "the backoff doubles with every failure in a row"
+        );
+        assert_eq!(backoffs[11], RemoteLoadFailure::MAX_BACKOFF);
+
+        assert!(!failure.is_due(now, 20));
+        assert!(failure.is_due(now + RemoteLoadFailure::MAX_BACKOFF, 20));
+        assert!(
+            !failure.is_due(now + RemoteLoadFailure::MAX_BACKOFF, 12),
+            "out of attempts"
+        );
+    }
 }
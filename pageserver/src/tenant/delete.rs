@@ -5,7 +5,7 @@ use std::{
 
 use anyhow::Context;
 use pageserver_api::models::TenantState;
-use remote_storage::{DownloadError, GenericRemoteStorage, RemotePath};
+use remote_storage::{GenericRemoteStorage, RemotePath, RemoteStorageError};
 use tokio::sync::OwnedMutexGuard;
 use tracing::{error, info, instrument, warn, Instrument, Span};
 
@@ -23,7 +23,9 @@ use crate::{
 
 use super::{
     mgr::{GetTenantError, TenantsMap},
-    remote_timeline_client::{FAILED_REMOTE_OP_RETRIES, FAILED_UPLOAD_WARN_THRESHOLD},
+    remote_timeline_client::{
+        RemoteOpRetrySettings, UploadDedupIndex, FAILED_UPLOAD_WARN_THRESHOLD,
+    },
     span,
     timeline::delete::DeleteTimelineFlow,
     tree_sort_timelines, DeleteTimelineError, Tenant,
@@ -51,17 +53,19 @@ pub enum DeleteTenantError {
 
 type DeletionGuard = tokio::sync::OwnedMutexGuard<DeleteTenantFlow>;
 
+fn remote_tenant_path(conf: &PageServerConf, tenant_id: &TenantId) -> anyhow::Result<RemotePath> {
+    conf.tenant_path(tenant_id)
+        .strip_prefix(&conf.workdir)
+        .context("Failed to strip workdir prefix")
+        .and_then(RemotePath::new)
+        .context("tenant path")
+}
+
 fn remote_tenant_delete_mark_path(
     conf: &PageServerConf,
     tenant_id: &TenantId,
 ) -> anyhow::Result<RemotePath> {
-    let tenant_remote_path = conf
-        .tenant_path(tenant_id)
-        .strip_prefix(&conf.workdir)
-        .context("Failed to strip workdir prefix")
-        .and_then(RemotePath::new)
-        .context("tenant path")?;
-    Ok(tenant_remote_path.join(Path::new("deleted")))
+    Ok(remote_tenant_path(conf, tenant_id)?.join(Path::new("deleted")))
 }
 
 async fn create_remote_delete_mark(
@@ -72,19 +76,19 @@ async fn create_remote_delete_mark(
     let remote_mark_path = remote_tenant_delete_mark_path(conf, tenant_id)?;
 
     let data: &[u8] = &[];
-    backoff::retry(
-        || async {
-            remote_storage
-                .upload(data, 0, &remote_mark_path, None)
-                .await
-        },
-        |_e| false,
-        FAILED_UPLOAD_WARN_THRESHOLD,
-        FAILED_REMOTE_OP_RETRIES,
-        "mark_upload",
-    )
-    .await
-    .context("mark_upload")?;
+    RemoteOpRetrySettings::from_conf(conf)
+        .retry(
+            || async {
+                remote_storage
+                    .upload(data, 0, &remote_mark_path, None)
+                    .await
+            },
+            RemoteStorageError::is_permanent,
+            FAILED_UPLOAD_WARN_THRESHOLD,
+            "mark_upload",
+        )
+        .await
+        .context("mark_upload")?;
 
     Ok(())
 }
@@ -158,22 +162,33 @@ async fn ensure_timelines_dir_empty(timelines_path: &Path) -> Result<(), DeleteT
     Ok(())
 }
 
-async fn remove_tenant_remote_delete_mark(
+// Deletes everything left under the tenant in the remote storage, the remote delete mark and
+// e.g. the files of the timelines this pageserver never had, in batches instead of one by one.
+async fn delete_remaining_remote_objects(
     conf: &PageServerConf,
     remote_storage: Option<&GenericRemoteStorage>,
     tenant_id: &TenantId,
 ) -> Result<(), DeleteTenantError> {
     if let Some(remote_storage) = remote_storage {
-        let path = remote_tenant_delete_mark_path(conf, tenant_id)?;
-        backoff::retry(
-            || async { remote_storage.delete(&path).await },
-            |_e| false,
-            FAILED_UPLOAD_WARN_THRESHOLD,
-            FAILED_REMOTE_OP_RETRIES,
-            "remove_tenant_remote_delete_mark",
-        )
-        .await
-        .context("remove_tenant_remote_delete_mark")?;
+        let path = remote_tenant_path(conf, tenant_id)?;
+        let deleted = RemoteOpRetrySettings::from_conf(conf)
+            .retry(
+                || async { remote_storage.delete_prefix(&path).await },
+                RemoteStorageError::is_permanent,
+                FAILED_UPLOAD_WARN_THRESHOLD,
+                "delete_remaining_remote_objects",
+            )
+            .await
+            .context("delete_remaining_remote_objects")?;
+        info!(
+            "deleted {deleted} remaining remote objects of the tenant, the delete mark among them"
+        );
+        if conf.remote_upload_dedup {
+            UploadDedupIndex::get(conf)
+                .await?
+                .forget_prefix(&path)
+                .await;
+        }
     }
     Ok(())
 }
@@ -233,7 +248,7 @@ async fn cleanup_remaining_fs_traces(
 /// 3. Shutdown tasks
 /// 4. Run ordered timeline deletions
 /// 5. Wait for timeline deletion operations that were scheduled before tenant deletion was requested
-/// 6. Remove remote mark
+/// 6. Delete the remaining remote objects of the tenant, the remote mark among them
 /// 7. Cleanup remaining fs traces, tenant dir, config, timelines dir, local delete mark
 /// It is resumable from any step in case a crash/restart occurs.
 /// There are three entrypoints to the process:
@@ -364,7 +379,7 @@ impl DeleteTenantFlow {
 
         let result = backoff::retry(
             || async { remote_storage.download(&remote_mark_path).await },
-            |e| matches!(e, DownloadError::NotFound),
+            |e| matches!(e, RemoteStorageError::NotFound),
             SHOULD_RESUME_DELETION_FETCH_MARK_ATTEMPTS,
             SHOULD_RESUME_DELETION_FETCH_MARK_ATTEMPTS,
             "fetch_tenant_deletion_mark",
@@ -373,7 +388,7 @@ impl DeleteTenantFlow {
 
         match result {
             Ok(_) => Ok(acquire(tenant)),
-            Err(DownloadError::NotFound) => Ok(None),
+            Err(RemoteStorageError::NotFound) => Ok(None),
             Err(e) => Err(anyhow::anyhow!(e)).context("should_resume_deletion")?,
         }
     }
@@ -535,7 +550,7 @@ impl DeleteTenantFlow {
                 .context("timelines dir not empty")?;
         }
 
-        remove_tenant_remote_delete_mark(conf, remote_storage.as_ref(), &tenant.tenant_id).await?;
+        delete_remaining_remote_objects(conf, remote_storage.as_ref(), &tenant.tenant_id).await?;
 
         fail::fail_point!("tenant-delete-before-cleanup-remaining-fs-traces", |_| {
             Err(anyhow::anyhow!(
@@ -3,16 +3,19 @@
 
 use std::collections::{hash_map, HashMap};
 use std::ffi::OsStr;
-use std::path::Path;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 use tokio::fs;
 
 use anyhow::Context;
+use futures::stream::{self, StreamExt};
 use once_cell::sync::Lazy;
 use tokio::sync::RwLock;
 use tokio::task::JoinSet;
 use tracing::*;
 
+use pageserver_api::models::TenantDetachSummary;
 use remote_storage::GenericRemoteStorage;
 use utils::crashsafe;
 
@@ -21,6 +24,7 @@ use crate::context::{DownloadBehavior, RequestContext};
 use crate::task_mgr::{self, TaskKind};
 use crate::tenant::config::TenantConfOpt;
 use crate::tenant::delete::DeleteTenantFlow;
+use crate::tenant::remote_timeline_client::{end_sync_startup_phase, init_remote_storage_replicas};
 use crate::tenant::{create_tenant_files, CreateTenantFilesMode, Tenant, TenantState};
 use crate::{InitializationOrder, IGNORED_TENANT_FILE_NAME};
 
@@ -70,76 +74,114 @@ pub async fn init_tenant_mgr(
     remote_storage: Option<GenericRemoteStorage>,
     init_order: InitializationOrder,
 ) -> anyhow::Result<()> {
+    if remote_storage.is_some() {
+        init_remote_storage_replicas(conf)?;
+
+        // The downloads of the initial load run with their own limit, until all the tenants
+        // are loaded.
+        let initial_load_done = init_order.initial_logical_size_can_start.clone();
+        task_mgr::BACKGROUND_RUNTIME.spawn(async move {
+            initial_load_done.wait().await;
+            end_sync_startup_phase(conf).await;
+            info!("Initial load done, remote sync tasks switched to max_concurrent_syncs");
+        });
+    }
+
     // Scan local filesystem for attached tenants
     let tenants_dir = conf.tenants_path();
 
+    let tenant_dirs = local_tenant_dirs(&tenants_dir).await?;
+
+    let ctx = RequestContext::todo_child(TaskKind::Startup, DownloadBehavior::Warn);
+
+    let tenants = collect_local_tenants(
+        conf,
+        tenant_dirs,
+        broker_client,
+        remote_storage,
+        Some(init_order),
+        &TENANTS,
+        &ctx,
+    )
+    .await?;
+
+    info!("Processed {} local tenants at startup", tenants.len());
+
+    let mut tenants_map = TENANTS.write().await;
+    assert!(matches!(&*tenants_map, &TenantsMap::Initializing));
+    *tenants_map = TenantsMap::Open(tenants);
+    Ok(())
+}
+
+/// Starts loading the tenants from their directories, `startup_tenant_collection_concurrency`
+/// of them at once. A tenant that fails to be collected is logged and skipped.
+async fn collect_local_tenants(
+    conf: &'static PageServerConf,
+    tenant_dirs: Vec<PathBuf>,
+    broker_client: storage_broker::BrokerClientChannel,
+    remote_storage: Option<GenericRemoteStorage>,
+    init_order: Option<InitializationOrder>,
+    tenants_map: &'static RwLock<TenantsMap>,
+    ctx: &RequestContext,
+) -> anyhow::Result<HashMap<TenantId, Arc<Tenant>>> {
+    // Checking the marker files and reading the config of a tenant is synchronous IO, which
+    // adds up to seconds with thousands of tenants, so several tenants are collected at once.
+    let mut collected_tenants = stream::iter(tenant_dirs)
+        .map(|tenant_dir_path| {
+            let broker_client = broker_client.clone();
+            let remote_storage = remote_storage.clone();
+            let init_order = init_order.clone();
+            let ctx = ctx.clone();
+            let span = Span::current();
+            tokio::task::spawn_blocking(move || {
+                let _entered = span.entered();
+                let collected = schedule_local_tenant_processing(
+                    conf,
+                    &tenant_dir_path,
+                    broker_client,
+                    remote_storage,
+                    init_order,
+                    tenants_map,
+                    &ctx,
+                );
+                (tenant_dir_path, collected)
+            })
+        })
+        .buffer_unordered(conf.startup_tenant_collection_concurrency.get());
+
     let mut tenants = HashMap::new();
+    while let Some(joined) = collected_tenants.next().await {
+        let (tenant_dir_path, collected) = joined.context("collect tenant files")?;
+        match collected {
+            Ok(tenant) => {
+                tenants.insert(tenant.tenant_id(), tenant);
+            }
+            Err(e) => {
+                error!(
+                    "Failed to collect tenant files from dir {tenant_dir_path:?}, reason: {e:#}"
+                );
+            }
+        }
+    }
+    Ok(tenants)
+}
 
-    let mut dir_entries = fs::read_dir(&tenants_dir)
+/// Lists the directories of the tenants to load from the tenants dir, cleaning up the leftovers
+/// of interrupted operations on the way.
+///
+/// Only failing to list the tenants dir itself is an error: an entry that cannot be read, or
+/// that does not look like a tenant directory (e.g. `lost+found` or `.DS_Store`), is logged and
+/// skipped, so that it doesn't keep the other tenants from loading.
+async fn local_tenant_dirs(tenants_dir: &Path) -> anyhow::Result<Vec<PathBuf>> {
+    let mut dir_entries = fs::read_dir(tenants_dir)
         .await
         .with_context(|| format!("Failed to list tenants dir {tenants_dir:?}"))?;
 
-    let ctx = RequestContext::todo_child(TaskKind::Startup, DownloadBehavior::Warn);
-
+    let mut tenant_dirs = Vec::new();
     loop {
-        match dir_entries.next_entry().await {
+        let tenant_dir_path = match dir_entries.next_entry().await {
             Ok(None) => break,
-            Ok(Some(dir_entry)) => {
-                let tenant_dir_path = dir_entry.path();
-                if crate::is_temporary(&tenant_dir_path) {
-                    info!(
-                        "Found temporary tenant directory, removing: {}",
-                        tenant_dir_path.display()
-                    );
-                    if let Err(e) = fs::remove_dir_all(&tenant_dir_path).await {
-                        error!(
-                            "Failed to remove temporary directory '{}': {:?}",
-                            tenant_dir_path.display(),
-                            e
-                        );
-                    }
-                } else {
-                    // This case happens if we:
-                    // * crash during attach before creating the attach marker file
-                    // * crash during tenant delete before removing tenant directory
-                    let is_empty = tenant_dir_path.is_empty_dir().with_context(|| {
-                        format!("Failed to check whether {tenant_dir_path:?} is an empty dir")
-                    })?;
-                    if is_empty {
-                        info!("removing empty tenant directory {tenant_dir_path:?}");
-                        if let Err(e) = fs::remove_dir(&tenant_dir_path).await {
-                            error!(
-                                "Failed to remove empty tenant directory '{}': {e:#}",
-                                tenant_dir_path.display()
-                            )
-                        }
-                        continue;
-                    }
-
-                    let tenant_ignore_mark_file = tenant_dir_path.join(IGNORED_TENANT_FILE_NAME);
-                    if tenant_ignore_mark_file.exists() {
-                        info!("Found an ignore mark file {tenant_ignore_mark_file:?}, skipping the tenant");
-                        continue;
-                    }
-
-                    match schedule_local_tenant_processing(
-                        conf,
-                        &tenant_dir_path,
-                        broker_client.clone(),
-                        remote_storage.clone(),
-                        Some(init_order.clone()),
-                        &TENANTS,
-                        &ctx,
-                    ) {
-                        Ok(tenant) => {
-                            tenants.insert(tenant.tenant_id(), tenant);
-                        }
-                        Err(e) => {
-                            error!("Failed to collect tenant files from dir {tenants_dir:?} for entry {dir_entry:?}, reason: {e:#}");
-                        }
-                    }
-                }
-            }
+            Ok(Some(dir_entry)) => dir_entry.path(),
             Err(e) => {
                 // On error, print it, but continue with the other tenants. If we error out
                 // here, the pageserver startup fails altogether, causing outage for *all*
@@ -147,16 +189,68 @@ pub async fn init_tenant_mgr(
                 error!(
                     "Failed to list tenants dir entry in directory {tenants_dir:?}, reason: {e:?}"
                 );
+                continue;
+            }
+        };
+
+        if crate::is_temporary(&tenant_dir_path) {
+            info!(
+                "Found temporary tenant directory, removing: {}",
+                tenant_dir_path.display()
+            );
+            if let Err(e) = fs::remove_dir_all(&tenant_dir_path).await {
+                error!(
+                    "Failed to remove temporary directory '{}': {:?}",
+                    tenant_dir_path.display(),
+                    e
+                );
             }
+            continue;
         }
-    }
 
-    info!("Processed {} local tenants at startup", tenants.len());
+        let is_tenant_id = tenant_dir_path
+            .file_name()
+            .and_then(OsStr::to_str)
+            .is_some_and(|name| name.parse::<TenantId>().is_ok());
+        if !is_tenant_id || !tenant_dir_path.is_dir() {
+            warn!(
+                "unexpected file or directory in tenants directory: {}",
+                tenant_dir_path.display()
+            );
+            continue;
+        }
 
-    let mut tenants_map = TENANTS.write().await;
-    assert!(matches!(&*tenants_map, &TenantsMap::Initializing));
-    *tenants_map = TenantsMap::Open(tenants);
-    Ok(())
+        // This case happens if we:
+        // * crash during attach before creating the attach marker file
+        // * crash during tenant delete before removing tenant directory
+        let is_empty = match tenant_dir_path.is_empty_dir() {
+            Ok(is_empty) => is_empty,
+            Err(e) => {
+                warn!("Failed to check whether {tenant_dir_path:?} is an empty dir, skipping it: {e:#}");
+                continue;
+            }
+        };
+        if is_empty {
+            info!("removing empty tenant directory {tenant_dir_path:?}");
+            if let Err(e) = fs::remove_dir(&tenant_dir_path).await {
+                error!(
+                    "Failed to remove empty tenant directory '{}': {e:#}",
+                    tenant_dir_path.display()
+                )
+            }
+            continue;
+        }
+
+        let tenant_ignore_mark_file = tenant_dir_path.join(IGNORED_TENANT_FILE_NAME);
+        if tenant_ignore_mark_file.exists() {
+            info!("Found an ignore mark file {tenant_ignore_mark_file:?}, skipping the tenant");
+            continue;
+        }
+
+        tenant_dirs.push(tenant_dir_path);
+    }
+
+    Ok(tenant_dirs)
 }
 
 pub(crate) fn schedule_local_tenant_processing(
@@ -200,7 +294,15 @@ pub(crate) fn schedule_local_tenant_processing(
 
     let tenant = if conf.tenant_attaching_mark_file_path(&tenant_id).exists() {
         info!("tenant {tenant_id} has attaching mark file, resuming its attach operation");
-        if let Some(remote_storage) = remote_storage {
+        if !conf.restores_tenant(&tenant_id) {
+            // The mark file is kept, the attach resumes once the filter lets the tenant through.
+            warn!("tenant {tenant_id} is excluded by the tenant_filter, not attaching it");
+            Tenant::create_broken_tenant(
+                conf,
+                tenant_id,
+                "attach excluded by the tenant_filter config".to_string(),
+            )
+        } else if let Some(remote_storage) = remote_storage {
             match Tenant::spawn_attach(conf, tenant_id, broker_client, remote_storage, ctx) {
                 Ok(tenant) => tenant,
                 Err(e) => {
@@ -463,12 +565,20 @@ pub enum TenantStateError {
     Other(#[from] anyhow::Error),
 }
 
+/// How long a detach with `flush` waits for the scheduled uploads of the tenant to complete.
+const DETACH_FLUSH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Detaches the tenant, removing its local files. With `flush`, the uploads and deletions
+/// already scheduled for its timelines get [`DETACH_FLUSH_TIMEOUT`] to complete before the
+/// rest of its remote operations are cancelled, otherwise they are cancelled right away.
 pub async fn detach_tenant(
     conf: &'static PageServerConf,
     tenant_id: TenantId,
     detach_ignored: bool,
-) -> Result<(), TenantStateError> {
-    detach_tenant0(conf, &TENANTS, tenant_id, detach_ignored).await
+    flush: bool,
+) -> Result<TenantDetachSummary, TenantStateError> {
+    let flush_timeout = flush.then_some(DETACH_FLUSH_TIMEOUT);
+    detach_tenant0(conf, &TENANTS, tenant_id, detach_ignored, flush_timeout).await
 }
 
 async fn detach_tenant0(
@@ -476,7 +586,8 @@ async fn detach_tenant0(
     tenants: &tokio::sync::RwLock<TenantsMap>,
     tenant_id: TenantId,
     detach_ignored: bool,
-) -> Result<(), TenantStateError> {
+    flush_timeout: Option<Duration>,
+) -> Result<TenantDetachSummary, TenantStateError> {
     let local_files_cleanup_operation = |tenant_id_to_clean| async move {
         let local_tenant_directory = conf.tenant_path(&tenant_id_to_clean);
         fs::remove_dir_all(&local_tenant_directory)
@@ -487,9 +598,14 @@ async fn detach_tenant0(
         Ok(())
     };
 
-    let removal_result =
-        remove_tenant_from_memory(tenants, tenant_id, local_files_cleanup_operation(tenant_id))
-            .await;
+    let removal_result = remove_tenant_from_memory(
+        tenants,
+        tenant_id,
+        flush_timeout,
+        local_files_cleanup_operation(tenant_id),
+    )
+    .await
+    .map(|((), summary)| summary);
 
     // Ignored tenants are not present in memory and will bail the removal from memory operation.
     // Before returning the error, check for ignored tenant removal case — we only need to clean its local files then.
@@ -500,7 +616,8 @@ async fn detach_tenant0(
             local_files_cleanup_operation(tenant_id)
                 .await
                 .with_context(|| format!("Ignored tenant {tenant_id} local files cleanup"))?;
-            return Ok(());
+            // An ignored tenant has no timelines to sync.
+            return Ok(TenantDetachSummary::default());
         }
     }
 
@@ -544,7 +661,7 @@ async fn ignore_tenant0(
     tenants: &tokio::sync::RwLock<TenantsMap>,
     tenant_id: TenantId,
 ) -> Result<(), TenantStateError> {
-    remove_tenant_from_memory(tenants, tenant_id, async {
+    remove_tenant_from_memory(tenants, tenant_id, None, async {
         let ignore_mark_file = conf.tenant_ignore_mark_file_path(&tenant_id);
         fs::File::create(&ignore_mark_file)
             .await
@@ -557,6 +674,7 @@ async fn ignore_tenant0(
         Ok(())
     })
     .await
+    .map(|((), _)| ())
 }
 
 #[derive(Debug, thiserror::Error)]
@@ -668,11 +786,14 @@ where
 /// Allows to remove other tenant resources manually, via `tenant_cleanup`.
 /// If the cleanup fails, tenant will stay in memory in [`TenantState::Broken`] state, and another removal
 /// operation would be needed to remove it.
+/// The remote operations of the tenant are cancelled, after `flush_timeout` for the scheduled uploads
+/// to complete, if given.
 async fn remove_tenant_from_memory<V, F>(
     tenants: &tokio::sync::RwLock<TenantsMap>,
     tenant_id: TenantId,
+    flush_timeout: Option<Duration>,
     tenant_cleanup: F,
-) -> Result<V, TenantStateError>
+) -> Result<(V, TenantDetachSummary), TenantStateError>
 where
     F: std::future::Future<Output = anyhow::Result<V>>,
 {
@@ -694,19 +815,19 @@ where
     // allow pageserver shutdown to await for our completion
     let (_guard, progress) = completion::channel();
 
-    // whenever we remove a tenant from memory, we don't want to flush and wait for upload
-    let freeze_and_flush = false;
-
+    // whenever we remove a tenant from memory, we don't want to flush the in-memory data, only
+    // the uploads scheduled already, if asked to.
+    //
     // shutdown is sure to transition tenant to stopping, and wait for all tasks to complete, so
     // that we can continue safely to cleanup.
-    match tenant.shutdown(progress, freeze_and_flush).await {
-        Ok(()) => {}
+    let summary = match tenant.shutdown_for_removal(progress, flush_timeout).await {
+        Ok(summary) => summary,
         Err(_other) => {
             // if pageserver shutdown or other detach/ignore is already ongoing, we don't want to
             // wait for it but return an error right away because these are distinct requests.
             return Err(TenantStateError::IsStopping(tenant_id));
         }
-    }
+    };
 
     match tenant_cleanup
         .await
@@ -717,7 +838,7 @@ where
             if tenants_accessor.remove(&tenant_id).is_none() {
                 warn!("Tenant {tenant_id} got removed from memory before operation finished");
             }
-            Ok(hook_value)
+            Ok((hook_value, summary))
         }
         Err(e) => {
             let tenants_accessor = tenants.read().await;
@@ -792,10 +913,17 @@ pub async fn immediate_gc(
 #[cfg(test)]
 mod tests {
     use std::collections::HashMap;
+    use std::num::NonZeroUsize;
     use std::sync::Arc;
-    use tracing::{info_span, Instrument};
+    use std::time::{Duration, Instant};
+    use tokio::sync::RwLock;
+    use tracing::{info, info_span, Instrument};
+    use utils::id::TenantId;
 
     use super::{super::harness::TenantHarness, TenantsMap};
+    use crate::config::PageServerConf;
+    use crate::context::{DownloadBehavior, RequestContext};
+    use crate::task_mgr::{self, TaskKind};
 
     #[tokio::test(start_paused = true)]
     async fn shutdown_joins_remove_tenant_from_memory() {
@@ -832,7 +960,7 @@ mod tests {
                         can_complete_cleanup.wait().await;
                         anyhow::Ok(())
                     };
-                    super::remove_tenant_from_memory(&tenants, id, cleanup).await
+                    super::remove_tenant_from_memory(&tenants, id, None, cleanup).await
                 }
                 .instrument(info_span!("foobar", tenant_id = %id))
             });
@@ -893,4 +1021,98 @@ mod tests {
         .await
         .expect("the stopping progress must still be complete");
     }
+
+    #[tokio::test]
+    async fn junk_in_tenants_dir_is_skipped() -> anyhow::Result<()> {
+        let tenants_dir = tempfile::tempdir()?;
+        let tenants_dir = tenants_dir.path();
+
+        let mut expected = Vec::new();
+        for _ in 0..3 {
+            let tenant_dir = tenants_dir.join(TenantId::generate().to_string());
+            std::fs::create_dir(&tenant_dir)?;
+            std::fs::write(tenant_dir.join("config"), b"")?;
+            expected.push(tenant_dir);
+        }
+        std::fs::create_dir(tenants_dir.join("lost+found"))?;
+        std::fs::write(tenants_dir.join("lost+found").join("#1234"), b"")?;
+        std::fs::write(tenants_dir.join(".DS_Store"), b"")?;
+        // A file named like a tenant is not a tenant either.
+        std::fs::write(tenants_dir.join(TenantId::generate().to_string()), b"")?;
+        let empty_tenant_dir = tenants_dir.join(TenantId::generate().to_string());
+        std::fs::create_dir(&empty_tenant_dir)?;
+
+        let mut found = super::local_tenant_dirs(tenants_dir).await?;
+        found.sort();
+        expected.sort();
+        assert_eq!(found, expected);
+        assert!(
+            tenants_dir.join("lost+found").exists(),
+            "junk should be left alone"
+        );
+        assert!(
+            !empty_tenant_dir.exists(),
+            "empty tenant dirs should be removed"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn collect_many_local_tenants() -> anyhow::Result<()> {
+        const TENANT_COUNT: usize = 256;
+
+        let harness = TenantHarness::create("collect_many_local_tenants")?;
+        let mut tenant_ids = vec![harness.tenant_id];
+        tenant_ids.extend((1..TENANT_COUNT).map(|_| TenantId::generate()));
+        for tenant_id in &tenant_ids {
+            std::fs::create_dir_all(harness.conf.timelines_path(tenant_id))?;
+            std::fs::write(
+                harness.conf.tenant_config_path(tenant_id),
+                "[tenant_config]\ngc_period = '0s'\ncompaction_period = '0s'\n",
+            )?;
+        }
+
+        // Never connected to: the tenants have no timelines to get through the broker.
+        let broker_client = storage_broker::connect("http://127.0.0.1:1", Duration::from_secs(5))?;
+        let ctx = RequestContext::new(TaskKind::UnitTest, DownloadBehavior::Error);
+        // tenant harness configures the logging and we cannot escape it
+        let _e = info_span!("testing", tenant_id = %harness.tenant_id).entered();
+
+        for concurrency in [1, 16, 64] {
+            let conf: &'static PageServerConf = Box::leak(Box::new(PageServerConf {
+                startup_tenant_collection_concurrency: NonZeroUsize::new(concurrency).unwrap(),
+                ..harness.conf.clone()
+            }));
+            let tenants_map: &'static RwLock<TenantsMap> =
+                Box::leak(Box::new(RwLock::new(TenantsMap::Initializing)));
+
+            let tenant_dirs = super::local_tenant_dirs(&conf.tenants_path()).await?;
+            let started_at = Instant::now();
+            let tenants = super::collect_local_tenants(
+                conf,
+                tenant_dirs,
+                broker_client.clone(),
+                None,
+                None,
+                tenants_map,
+                &ctx,
+            )
+            .await?;
+            info!(
+                "collected {TENANT_COUNT} tenants, {concurrency} at a time, in {:?}",
+                started_at.elapsed()
+            );
+
+            let mut collected = tenants.keys().copied().collect::<Vec<_>>();
+            collected.sort();
+            tenant_ids.sort();
+            assert_eq!(collected, tenant_ids, "concurrency {concurrency}");
+            for (tenant_id, tenant) in tenants {
+                tenant.wait_to_become_active().await?;
+                task_mgr::shutdown_tasks(None, Some(tenant_id), None).await;
+            }
+        }
+        Ok(())
+    }
 }
@@ -7,6 +7,7 @@
 //!   when it's safe to do so.
 //!
 //! * Stand-alone function, [`list_remote_timelines`], to get list of timelines of a tenant.
+//!   [`list_remote_timelines_with_metadata`] adds the metadata of their index parts.
 //!
 //! These functions use the low-level remote storage client, [`remote_storage::RemoteStorage`].
 //!
@@ -35,6 +36,7 @@
 //! - [`RemoteTimelineClient::schedule_index_upload_for_metadata_update`] when we've updated the timeline metadata file.
 //! - [`RemoteTimelineClient::schedule_index_upload_for_file_changes`] to upload an updated index file, after we've scheduled file uploads
 //! - [`RemoteTimelineClient::schedule_layer_file_deletion`] when we've deleted one or more layer files.
+//! - [`RemoteTimelineClient::schedule_compacted_layer_deletion`] when compaction has replaced layer files with new ones.
 //!
 //! Internally, these functions create [`UploadOp`]s and put them in a queue.
 //!
@@ -99,16 +101,62 @@
 //! when scheduling an operation while other operations that also affect the
 //! remote [`IndexPart`] are in flight.
 //!
+//! # Remote GC Retention
+//!
+//! By default, the layer files deleted locally are deleted remotely right after the
+//! index file stops referencing them. With `remote_gc_retained_lsns` configured,
+//! they are kept as "superseded" layers instead, listed in the [`IndexPart`] along
+//! with the last `disk_consistent_lsn` they were used at, so that the timeline can
+//! still be restored at any of the most recent uploaded `disk_consistent_lsn`s, from the
+//! index generations of these LSNs, which are kept for the same window.
+//! Every index upload moves that window forward, and the superseded layers left
+//! behind it are deleted after that upload, in the same way as without the retention.
+//!
+//! With `remote_index_generations` configured, every index part is also uploaded as
+//! `index_part_<disk_consistent_lsn>.json`, before `index_part.json`, which stays the
+//! latest one. The last N of these generations are kept and listed in the latest
+//! [`IndexPart`], the older ones are deleted after the index upload that drops them.
+//! The retention window and the generations kept are the same, the longer of the two
+//! options, so that every retained LSN has its index part, and every layer a kept
+//! generation lists is still in the remote storage.
+//!
+//! Every index upload also writes a `backup_manifest.json` for the tools outside of the
+//! pageserver, see the [`manifest`] module. The checksums of the layers uploaded before the
+//! index part are recorded in it, and in the index part too. When a timeline is loaded from
+//! the remote storage, its index part has to match the manifest uploaded along with it.
+//!
 //! # Retries & Error Handling
 //!
-//! The client retries operations indefinitely, using exponential back-off.
+//! The client retries operations indefinitely, using exponential back-off with
+//! random jitter, so that a burst of throttled uploads does not retry in lockstep.
+//! The backoff starts over after every `max_retries` failed attempts (see
+//! `RemoteOpRetrySettings`); each such exhausted series, or an error that retries
+//! cannot fix (e.g. a missing object or an auth failure), counts as a sync error.
+//! Once a task reaches `max_sync_errors` of those, its failures are logged as errors.
 //! There is no way to force a retry, i.e., interrupt the back-off.
 //! This could be built easily.
 //!
+//! The queue only knows the operations it has performed. When the remote storage loses an
+//! object the queue assumes uploaded, or one is repaired by hand,
+//! [`Timeline::force_remote_resync`](crate::tenant::Timeline::force_remote_resync) re-lists the
+//! remote copy of the timeline and uploads whatever it lacks of the local one again.
+//!
+//! With `remote_owner_lease_ttl` set, the uploads of a tenant wait while another pageserver
+//! holds its owner lease, e.g. after a failover that left the old node running. See the
+//! [`owner_lease`] module.
+//!
+//! A timeline whose layers are in the remote storage without an index part is reported, or
+//! with `remote_repair_missing_index_part` set, repaired, see the [`repair`] module.
+//!
+//! With `remote_zstd_dictionary_max_file_size` set, the small layers are compressed with a zstd
+//! dictionary trained on the tenant's own, see the [`dictionary`] module.
+//!
 //! # Cancellation
 //!
 //! The operations execute as plain [`task_mgr`] tasks, scoped to
-//! the client's tenant and timeline.
+//! the client's tenant and timeline. They run on the background runtime, unless
+//! the application embedding the pageserver sets its own runtime for them with
+//! [`task_mgr::set_remote_sync_runtime`].
 //! Dropping the client will drop queued operations but not executing operations.
 //! These will complete unless the `task_mgr` tasks are cancelled using `task_mgr`
 //! APIs, e.g., during pageserver shutdown, timeline delete, or tenant detach.
@@ -123,6 +171,9 @@
 //! for all pending operations to complete. It does not prevent more
 //! operations from getting scheduled.
 //!
+//! Finished index uploads and layer downloads of all timelines are also announced
+//! as [`SyncEvent`]s to the receivers of [`subscribe_sync_events`].
+//!
 //! # Crash Consistency
 //!
 //! We do not persist the upload queue state.
@@ -157,6 +208,10 @@
 //! downloading files from the remote storage. Downloads are performed immediately
 //! against the `RemoteStorage`, independently of the upload queue.
 //!
+//! All the remote operations share the sync permits, see the `sync_limit` module. The layer
+//! downloads a page request is waiting for ask for [`SyncPriority::High`], and get the next
+//! free permits before the background uploads and downloads.
+//!
 //! When we attach a tenant, we perform the following steps:
 //! - create `Tenant` object in `TenantState::Attaching` state
 //! - List timelines that are present in remote storage, and for each:
@@ -201,28 +256,51 @@
 //! [`Tenant::timeline_init_and_sync`]: super::Tenant::timeline_init_and_sync
 //! [`Timeline::reconcile_with_remote`]: super::Timeline::reconcile_with_remote
 
+mod checksum;
+mod dedup;
 mod delete;
+mod dictionary;
 mod download;
+mod events;
 pub mod index;
+mod manifest;
+mod owner_lease;
+pub(crate) mod parts;
+mod pause;
+mod repair;
+mod replica;
+mod sync_limit;
 mod upload;
+mod verify;
 
 use anyhow::Context;
 use chrono::{NaiveDateTime, Utc};
 // re-export these
-pub use download::{is_temp_download_file, list_remote_timelines};
-use scopeguard::ScopeGuard;
-use utils::backoff::{
-    self, exponential_backoff, DEFAULT_BASE_BACKOFF_SECONDS, DEFAULT_MAX_BACKOFF_SECONDS,
+pub use checksum::ChecksumAlgorithm;
+pub(crate) use dedup::UploadDedupIndex;
+pub use download::{
+    is_temp_download_file, list_remote_timelines, list_remote_timelines_with_metadata,
 };
+pub use events::{subscribe_sync_events, SyncEvent};
+pub(crate) use pause::UploadPause;
+use rand::Rng;
+use scopeguard::ScopeGuard;
+pub use sync_limit::SyncPriority;
+use utils::backoff;
 
-use std::collections::{HashMap, VecDeque};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::path::Path;
-use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
 use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
-use remote_storage::{DownloadError, GenericRemoteStorage, RemotePath};
+use pageserver_api::models::{
+    RemoteConsistencyReport, RemoteReplicaSyncStatus, RemoteSyncFailedTask, RemoteSyncStatus,
+};
+use remote_storage::{GenericRemoteStorage, RemotePath, RemoteStorageError};
 use std::ops::DerefMut;
-use tokio::runtime::Runtime;
+use tokio::runtime::Handle;
+use tokio_util::sync::CancellationToken;
 use tracing::{debug, error, info, instrument, warn};
 use tracing::{info_span, Instrument};
 use utils::lsn::Lsn;
@@ -230,25 +308,31 @@ use utils::lsn::Lsn;
 use crate::metrics::{
     MeasureRemoteOp, RemoteOpFileKind, RemoteOpKind, RemoteTimelineClientMetrics,
     RemoteTimelineClientMetricsCallTrackSize, REMOTE_ONDEMAND_DOWNLOADED_BYTES,
-    REMOTE_ONDEMAND_DOWNLOADED_LAYERS,
+    REMOTE_ONDEMAND_DOWNLOADED_LAYERS, REMOTE_SYNC_ERRORS, REMOTE_SYNC_FAILED_TASKS,
+    REMOTE_SYNC_TASKS_QUEUED, REMOTE_SYNC_TASK_DURATION, REMOTE_SYNC_TASK_START_LAG,
 };
 use crate::tenant::debug_assert_current_span_has_tenant_and_timeline_id;
 use crate::tenant::remote_timeline_client::index::LayerFileMetadata;
-use crate::tenant::upload_queue::Delete;
+use crate::tenant::upload_queue::{Delete, DeletedLayerParts};
 use crate::{
     config::PageServerConf,
     task_mgr,
     task_mgr::TaskKind,
-    task_mgr::BACKGROUND_RUNTIME,
     tenant::metadata::TimelineMetadata,
     tenant::upload_queue::{
         UploadOp, UploadQueue, UploadQueueInitialized, UploadQueueStopped, UploadTask,
     },
 };
+use checksum::Checksum;
+use dictionary::TenantDictionaries;
+use owner_lease::TenantOwnerLease;
+use replica::{ReplicaOp, ReplicaTarget, SyncTargets, TimelineReplica};
+use sync_limit::{SyncLimits, TenantSyncLimit};
 
 use utils::id::{TenantId, TimelineId};
 
 use self::index::IndexPart;
+use self::parts::LayerParts;
 
 use super::storage_layer::LayerFileName;
 use super::upload_queue::SetDeletedFlagProgress;
@@ -257,14 +341,130 @@ use super::upload_queue::SetDeletedFlagProgress;
 // that's expected. If a download fails, we log it at info-level, and retry.
 // But after FAILED_DOWNLOAD_WARN_THRESHOLD retries, we start to log it at WARN
 // level instead, as repeated failures can mean a more serious problem. If it
-// fails more than FAILED_DOWNLOAD_RETRIES times, we give up
+// fails more than `max_retries` times (see RemoteOpRetrySettings), we give up
 pub(crate) const FAILED_DOWNLOAD_WARN_THRESHOLD: u32 = 3;
-pub(crate) const FAILED_REMOTE_OP_RETRIES: u32 = 10;
 
 // Similarly log failed uploads and deletions at WARN level, after this many
 // retries. Uploads and deletions are retried forever, though.
 pub(crate) const FAILED_UPLOAD_WARN_THRESHOLD: u32 = 3;
 
+// Upper bound for the jittered backoff between retries of a single remote operation.
+const MAX_REMOTE_OP_BACKOFF: Duration = Duration::from_secs(10);
+
+/// Retry settings of the remote operations, taken from the `[remote_storage]` config.
+///
+/// Every remote storage request is retried up to `max_retries` times, with jittered
+/// exponential backoff starting at `base_backoff`. Only an operation that has run out of
+/// retries (or failed permanently) counts as a sync error, and `max_sync_errors` of
+/// those in a row are reported as an error.
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct RemoteOpRetrySettings {
+    pub(crate) max_retries: u32,
+    pub(crate) base_backoff: Duration,
+    pub(crate) max_sync_errors: u32,
+    pub(crate) operation_timeout: Duration,
+    pub(crate) list_timeout: Duration,
+    pub(crate) upload_start_jitter: Duration,
+}
+
+impl RemoteOpRetrySettings {
+    pub(crate) fn from_conf(conf: &PageServerConf) -> Self {
+        match &conf.remote_storage_config {
+            Some(config) => Self {
+                max_retries: config.max_retries,
+                base_backoff: Duration::from_millis(config.base_backoff_ms),
+                max_sync_errors: config.max_sync_errors.get(),
+                operation_timeout: config.operation_timeout,
+                list_timeout: config.list_timeout,
+                upload_start_jitter: config.upload_start_jitter,
+            },
+            None => Self {
+                max_retries: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_RETRIES,
+                base_backoff: Duration::from_millis(
+                    remote_storage::DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS,
+                ),
+                max_sync_errors: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS,
+                operation_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+                list_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+                upload_start_jitter: remote_storage::DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+            },
+        }
+    }
+
+    /// Retries `op` with jittered exponential backoff, giving up early on the errors
+    /// that `is_permanent` considers not worth retrying.
+    pub(crate) async fn retry<T, O, F, E>(
+        &self,
+        op: O,
+        is_permanent: impl Fn(&E) -> bool,
+        warn_threshold: u32,
+        description: &str,
+    ) -> Result<T, E>
+    where
+        E: std::fmt::Display + std::fmt::Debug,
+        O: FnMut() -> F,
+        F: std::future::Future<Output = Result<T, E>>,
+    {
+        backoff::retry_with_jitter(
+            op,
+            is_permanent,
+            warn_threshold,
+            self.max_retries,
+            self.base_backoff,
+            MAX_REMOTE_OP_BACKOFF,
+            description,
+        )
+        .await
+    }
+
+    /// Backoff to wait after the `attempt`-th consecutive failure of an operation.
+    fn backoff(&self, attempt: u32) -> Duration {
+        backoff::jittered_backoff_duration(attempt, self.base_backoff, MAX_REMOTE_OP_BACKOFF)
+    }
+
+    /// Backoff to wait after the `attempt`-th consecutive failure of an operation that is
+    /// retried until it succeeds. A permanent error won't go away on the next attempt any more
+    /// than on the last of the retries, so it waits the longest backoff right away.
+    fn backoff_after_error(&self, attempt: u32, permanent: bool) -> Duration {
+        if permanent {
+            MAX_REMOTE_OP_BACKOFF
+        } else {
+            self.backoff(attempt)
+        }
+    }
+}
+
+/// Fails a single attempt of a remote operation once `timeout` passes without its uploads or
+/// downloads moving a byte, or, with no data to transfer, if it takes longer than `timeout`. The
+/// attempt is dropped then, which cancels its requests: it can be retried as any other failed one.
+pub(crate) async fn with_timeout<T, E>(
+    timeout: Duration,
+    op: impl std::future::Future<Output = Result<T, E>>,
+    timed_out: impl FnOnce(anyhow::Error) -> E,
+) -> Result<T, E> {
+    match remote_storage::timeout_without_progress(timeout, op).await {
+        Ok(result) => result,
+        Err(e) => Err(timed_out(e)),
+    }
+}
+
+/// Records a finished download, retries included, in the remote sync metrics. The upload queue
+/// tasks do the same in [`RemoteTimelineClient::perform_upload_task`].
+fn sync_task_finished(
+    file_kind: RemoteOpFileKind,
+    op_kind: RemoteOpKind,
+    started_at: Instant,
+    failed: bool,
+) {
+    let labels = [file_kind.as_str(), op_kind.as_str()];
+    REMOTE_SYNC_TASK_DURATION
+        .with_label_values(&labels)
+        .observe(started_at.elapsed().as_secs_f64());
+    if failed {
+        REMOTE_SYNC_ERRORS.with_label_values(&labels).inc();
+    }
+}
+
 pub enum MaybeDeletedIndexPart {
     IndexPart(IndexPart),
     Deleted(IndexPart),
@@ -311,7 +511,7 @@ pub enum PersistIndexPartWithDeletedFlagError {
 pub struct RemoteTimelineClient {
     conf: &'static PageServerConf,
 
-    runtime: &'static Runtime,
+    runtime: Handle,
 
     tenant_id: TenantId,
     timeline_id: TimelineId,
@@ -320,7 +520,56 @@ pub struct RemoteTimelineClient {
 
     metrics: Arc<RemoteTimelineClientMetrics>,
 
+    /// Every upload, download or deletion holds a permit from here while it runs.
+    sync_limit: TenantSyncLimit,
+
+    /// The uploads wait until the node holds the tenant's owner lease, see [`owner_lease`].
+    owner_lease: Arc<TenantOwnerLease>,
+
+    /// The zstd dictionaries of the tenant's small layers, see [`dictionary`].
+    dictionaries: Arc<TenantDictionaries>,
+
+    /// The layer downloads in flight, for [`Self::sync_status`].
+    downloads_in_progress: Mutex<Vec<Arc<LayerDownloadProgress>>>,
+
+    /// Error of the last remote operation of each kind that failed for good, until one of the
+    /// same kind succeeds: a successful upload doesn't mean the downloads work again.
+    last_sync_errors: Mutex<HashMap<RemoteOpKind, String>>,
+
+    /// Upload tasks that have failed `max_sync_errors` times in a row, by task id. They wait
+    /// for [`Self::retry_failed`] to bump the counter in `retry_failed_tx`.
+    failed_tasks: Mutex<HashMap<u64, RemoteSyncFailedTask>>,
+    retry_failed_tx: tokio::sync::watch::Sender<u64>,
+
+    /// Cancelled by [`Self::cancel_sync`], aborts the uploads and downloads in flight.
+    sync_cancel: CancellationToken,
+
+    /// See [`UploadTask::start_delay`].
+    upload_start_jitter: Duration,
+
     storage_impl: GenericRemoteStorage,
+
+    /// The copies of the timeline in the other storages of the `remote_storage` list, see the
+    /// [`replica`] module.
+    replicas: Vec<Arc<TimelineReplica>>,
+}
+
+/// Creates the clients of the remote storage replicas. Called at the pageserver startup, so
+/// that an unusable replica config fails it rather than the first timeline load.
+pub(crate) fn init_remote_storage_replicas(conf: &'static PageServerConf) -> anyhow::Result<()> {
+    SyncTargets::init(conf).map(|_| ())
+}
+
+/// Switches the remote sync tasks from the `max_concurrent_sync_startup` limit to the
+/// `max_concurrent_syncs` one, see [`sync_limit`].
+pub(crate) async fn end_sync_startup_phase(conf: &'static PageServerConf) {
+    SyncLimits::get(conf).end_startup_phase().await
+}
+
+/// How far a layer download in flight has come.
+struct LayerDownloadProgress {
+    bytes_done: AtomicU64,
+    bytes_total: u64,
 }
 
 impl RemoteTimelineClient {
@@ -330,20 +579,83 @@ impl RemoteTimelineClient {
     /// Note: the caller must initialize the upload queue before any uploads can be scheduled,
     /// by calling init_upload_queue.
     ///
+    /// The uploads and deletions run on [`task_mgr::remote_sync_runtime`].
     pub fn new(
         remote_storage: GenericRemoteStorage,
         conf: &'static PageServerConf,
         tenant_id: TenantId,
         timeline_id: TimelineId,
     ) -> RemoteTimelineClient {
+        Self::with_runtime(
+            remote_storage,
+            conf,
+            tenant_id,
+            timeline_id,
+            task_mgr::remote_sync_runtime(),
+        )
+    }
+
+    /// Like [`RemoteTimelineClient::new`], with the uploads and deletions running on `runtime`,
+    /// and the operations of the remote storage replicas too.
+    pub fn with_runtime(
+        remote_storage: GenericRemoteStorage,
+        conf: &'static PageServerConf,
+        tenant_id: TenantId,
+        timeline_id: TimelineId,
+        runtime: Handle,
+    ) -> RemoteTimelineClient {
+        Self::with_replica_targets(
+            remote_storage,
+            conf,
+            tenant_id,
+            timeline_id,
+            runtime,
+            &SyncTargets::get(conf).replicas,
+        )
+    }
+
+    fn with_replica_targets(
+        remote_storage: GenericRemoteStorage,
+        conf: &'static PageServerConf,
+        tenant_id: TenantId,
+        timeline_id: TimelineId,
+        runtime: Handle,
+        replica_targets: &'static [ReplicaTarget],
+    ) -> RemoteTimelineClient {
+        let dictionaries = TenantDictionaries::for_tenant(conf, &remote_storage, tenant_id);
+        let replicas = replica_targets
+            .iter()
+            .map(|target| {
+                Arc::new(TimelineReplica::new(
+                    conf,
+                    runtime.clone(),
+                    tenant_id,
+                    timeline_id,
+                    target,
+                    remote_storage.clone(),
+                    Arc::clone(&dictionaries),
+                ))
+            })
+            .collect();
+        let owner_lease = TenantOwnerLease::for_tenant(conf, &remote_storage, tenant_id, &runtime);
         RemoteTimelineClient {
             conf,
-            runtime: &BACKGROUND_RUNTIME,
+            runtime,
             tenant_id,
             timeline_id,
             storage_impl: remote_storage,
             upload_queue: Mutex::new(UploadQueue::Uninitialized),
             metrics: Arc::new(RemoteTimelineClientMetrics::new(&tenant_id, &timeline_id)),
+            sync_limit: SyncLimits::get(conf).for_tenant(tenant_id),
+            owner_lease,
+            dictionaries,
+            downloads_in_progress: Mutex::new(Vec::new()),
+            last_sync_errors: Mutex::new(HashMap::new()),
+            failed_tasks: Mutex::new(HashMap::new()),
+            retry_failed_tx: tokio::sync::watch::channel(0).0,
+            sync_cancel: CancellationToken::new(),
+            upload_start_jitter: RemoteOpRetrySettings::from_conf(conf).upload_start_jitter,
+            replicas,
         }
     }
 
@@ -354,6 +666,7 @@ impl RemoteTimelineClient {
         let mut upload_queue = self.upload_queue.lock().unwrap();
         upload_queue.initialize_with_current_remote_index_part(index_part)?;
         self.update_remote_physical_size_gauge(Some(index_part));
+        self.replicate(ReplicaOp::CatchUp(index_part.clone()));
         Ok(())
     }
 
@@ -369,6 +682,94 @@ impl RemoteTimelineClient {
         Ok(())
     }
 
+    /// Initialize the upload queue for a timeline loaded from its local files only, because
+    /// its index part failed to download. The operations scheduled until
+    /// [`Self::finish_pending_reconciliation`] are queued, but never launched: they are
+    /// relative to the remote state, which is unknown yet.
+    pub fn init_upload_queue_pending_reconciliation(
+        &self,
+        local_metadata: &TimelineMetadata,
+    ) -> anyhow::Result<()> {
+        let mut upload_queue = self.upload_queue.lock().unwrap();
+        upload_queue
+            .initialize_empty_remote(local_metadata)?
+            .pending_reconciliation = Some(HashSet::new());
+        Ok(())
+    }
+
+    pub fn is_reconciliation_pending(&self) -> bool {
+        match &*self.upload_queue.lock().unwrap() {
+            UploadQueue::Initialized(upload_queue) => upload_queue.pending_reconciliation.is_some(),
+            UploadQueue::Uninitialized | UploadQueue::Stopped(_) => false,
+        }
+    }
+
+    /// The layers deleted locally while the reconciliation is pending.
+    pub fn layers_deleted_pending_reconciliation(&self) -> anyhow::Result<HashSet<LayerFileName>> {
+        let mut guard = self.upload_queue.lock().unwrap();
+        guard
+            .initialized_mut()?
+            .pending_reconciliation
+            .clone()
+            .context("no reconciliation is pending")
+    }
+
+    /// Ends the pending reconciliation: re-initializes the queue from the remote `index_part`,
+    /// or as empty if the remote has none, dropping the operations queued meanwhile, and
+    /// schedules the uploads of the `local_only_layers`, the deletions of the `deleted_layers`
+    /// and the index upload with the local `metadata`. All under one lock, so that the index
+    /// uploads scheduled concurrently, e.g. by a checkpoint, come after them.
+    pub fn finish_pending_reconciliation(
+        self: &Arc<Self>,
+        index_part: Option<&IndexPart>,
+        local_only_layers: &[(LayerFileName, LayerFileMetadata)],
+        deleted_layers: &[LayerFileName],
+        metadata: &TimelineMetadata,
+    ) -> anyhow::Result<()> {
+        let mut reconciled = UploadQueue::Uninitialized;
+        let remote_disk_consistent_lsn = match index_part {
+            Some(index_part) => {
+                reconciled.initialize_with_current_remote_index_part(index_part)?;
+                Some(index_part.parse_metadata()?.disk_consistent_lsn())
+            }
+            None => {
+                reconciled.initialize_empty_remote(metadata)?;
+                None
+            }
+        };
+        let metadata_bytes = metadata.to_bytes()?;
+
+        let mut guard = self.upload_queue.lock().unwrap();
+        let pending = guard.initialized_mut()?;
+        anyhow::ensure!(
+            pending.pending_reconciliation.is_some(),
+            "no reconciliation is pending"
+        );
+        for (op, ..) in pending.queued_operations.drain(..) {
+            self.tasks_queued_metric_dec(&op);
+            self.calls_unfinished_metric_end(&op);
+        }
+        *guard = reconciled;
+        self.update_remote_physical_size_gauge(index_part);
+        if let Some(index_part) = index_part {
+            self.replicate(ReplicaOp::CatchUp(index_part.clone()));
+        }
+
+        let upload_queue = guard.initialized_mut().expect("initialized above");
+        for (layer_file_name, layer_metadata) in local_only_layers {
+            self.schedule_layer_upload_op(upload_queue, layer_file_name, layer_metadata);
+        }
+        upload_queue.latest_metadata = metadata.clone();
+        self.schedule_layer_deletions(upload_queue, deleted_layers, None, metadata_bytes.clone());
+        if upload_queue.latest_files_changes_since_metadata_upload_scheduled > 0
+            || remote_disk_consistent_lsn != Some(metadata.disk_consistent_lsn())
+        {
+            self.schedule_index_upload(upload_queue, metadata_bytes);
+        }
+        self.launch_queued_tasks(upload_queue);
+        Ok(())
+    }
+
     /// Initialize the queue in stopped state. Used in startup path
     /// to continue deletion operation interrupted by pageserver crash or restart.
     pub fn init_upload_queue_stopped_to_continue_deletion(
@@ -398,6 +799,12 @@ impl RemoteTimelineClient {
         Ok(())
     }
 
+    /// Whether the remote storage only logs the uploads: the layers it reports as uploaded are
+    /// only on the local disk, and must not be evicted.
+    pub fn is_dry_run(&self) -> bool {
+        self.storage_impl.is_dry_run()
+    }
+
     pub fn last_uploaded_consistent_lsn(&self) -> Option<Lsn> {
         match &*self.upload_queue.lock().unwrap() {
             UploadQueue::Uninitialized => None,
@@ -408,6 +815,156 @@ impl RemoteTimelineClient {
         }
     }
 
+    /// Where the remote operations of the timeline are at. The layer downloads in flight come
+    /// first, then a failure, then the state of the upload queue.
+    pub fn sync_status(&self) -> RemoteSyncStatus {
+        {
+            let downloads = self.downloads_in_progress.lock().unwrap();
+            if !downloads.is_empty() {
+                return RemoteSyncStatus::Downloading {
+                    bytes_done: downloads
+                        .iter()
+                        .map(|download| download.bytes_done.load(Ordering::Relaxed))
+                        .sum(),
+                    bytes_total: downloads.iter().map(|download| download.bytes_total).sum(),
+                };
+            }
+        }
+        {
+            let errors = self.last_sync_errors.lock().unwrap();
+            let kinds = [
+                RemoteOpKind::Upload,
+                RemoteOpKind::Delete,
+                RemoteOpKind::Download,
+            ];
+            if let Some(error) = kinds.iter().find_map(|kind| errors.get(kind)) {
+                return RemoteSyncStatus::Failed {
+                    error: error.clone(),
+                };
+            }
+        }
+        let upload_queue = self.upload_queue.lock().unwrap();
+        let upload_queue = match &*upload_queue {
+            UploadQueue::Uninitialized => return RemoteSyncStatus::Uninitialized,
+            UploadQueue::Initialized(q) => q,
+            UploadQueue::Stopped(q) => &q.upload_queue_for_deletion,
+        };
+        if !upload_queue.inprogress_tasks.is_empty() {
+            RemoteSyncStatus::Uploading
+        } else if !upload_queue.queued_operations.is_empty() {
+            RemoteSyncStatus::Queued
+        } else {
+            RemoteSyncStatus::Ready
+        }
+    }
+
+    /// Remembers the error of a remote operation of `op_kind` that failed for good, or forgets
+    /// the last one once an operation of that kind succeeds, for [`Self::sync_status`].
+    fn record_sync_result(&self, op_kind: RemoteOpKind, error: Option<String>) {
+        let mut errors = self.last_sync_errors.lock().unwrap();
+        match error {
+            Some(error) => errors.insert(op_kind, error),
+            None => errors.remove(&op_kind),
+        };
+    }
+
+    /// How far behind the primary storage each remote storage replica is.
+    pub fn replica_sync_status(&self) -> Vec<RemoteReplicaSyncStatus> {
+        self.replicas
+            .iter()
+            .map(|replica| replica.sync_status())
+            .collect()
+    }
+
+    /// The upload tasks that have failed `max_sync_errors` times in a row and wait for
+    /// [`Self::retry_failed`].
+    pub fn failed_tasks(&self) -> Vec<RemoteSyncFailedTask> {
+        let failed_tasks = self.failed_tasks.lock().unwrap();
+        let mut task_ids: Vec<_> = failed_tasks.keys().copied().collect();
+        task_ids.sort_unstable();
+        task_ids
+            .into_iter()
+            .map(|task_id| failed_tasks[&task_id].clone())
+            .collect()
+    }
+
+    /// Compares the local files of the timeline with its remote copy, without changing either.
+    pub async fn verify_remote_consistency(&self) -> anyhow::Result<RemoteConsistencyReport> {
+        let _permit = self.sync_limit.acquire(SyncPriority::Low).await;
+        verify::verify_remote_consistency(
+            self.conf,
+            &self.storage_impl,
+            self.tenant_id,
+            self.timeline_id,
+        )
+        .await
+    }
+
+    /// Schedules the uploads of the `layers` and of the index part, whether or not the queue
+    /// assumes they're uploaded already, once [`Self::verify_remote_consistency`] has found
+    /// the remote storage lacking them. The layers with the same contents as a remote object
+    /// of the timeline are uploaded rather than copied, that object may be the corrupt one.
+    pub async fn schedule_forced_resync(
+        self: &Arc<Self>,
+        layers: &[(LayerFileName, LayerFileMetadata)],
+    ) -> anyhow::Result<()> {
+        if self.conf.remote_upload_dedup {
+            let timeline_path = self.conf.timeline_path(&self.tenant_id, &self.timeline_id);
+            UploadDedupIndex::get(self.conf)
+                .await?
+                .forget_prefix(&self.conf.remote_path(&timeline_path)?)
+                .await;
+        }
+        let mut guard = self.upload_queue.lock().unwrap();
+        let upload_queue = guard.initialized_mut()?;
+
+        for (layer_file_name, layer_metadata) in layers {
+            self.schedule_layer_upload_op(upload_queue, layer_file_name, layer_metadata);
+        }
+        // Also replaces an index part that was repaired by hand.
+        let metadata_bytes = upload_queue.latest_metadata.to_bytes()?;
+        self.schedule_index_upload(upload_queue, metadata_bytes);
+
+        self.launch_queued_tasks(upload_queue);
+        Ok(())
+    }
+
+    /// Makes the failed tasks start over, e.g. after the operator has fixed the permissions
+    /// of the bucket. Returns the number of tasks retried.
+    pub fn retry_failed(&self) -> usize {
+        let retried = self.failed_tasks.lock().unwrap().len();
+        self.retry_failed_tx
+            .send_modify(|generation| *generation += 1);
+        retried
+    }
+
+    /// Parks a task that has failed `max_sync_errors` times in a row until [`Self::retry_failed`],
+    /// or a shutdown.
+    async fn wait_for_retry_failed(&self, task: &UploadTask, error: &anyhow::Error) {
+        // Subscribed before the task shows up as failed, not to miss a retry right after.
+        let mut retry_failed_rx = self.retry_failed_tx.subscribe();
+        self.failed_tasks.lock().unwrap().insert(
+            task.task_id,
+            RemoteSyncFailedTask {
+                operation: task.op.to_string(),
+                error: format!("{error:#}"),
+            },
+        );
+        REMOTE_SYNC_FAILED_TASKS.inc();
+        let _failed_guard = scopeguard::guard((), |()| {
+            self.failed_tasks.lock().unwrap().remove(&task.task_id);
+            REMOTE_SYNC_FAILED_TASKS.dec();
+        });
+
+        tokio::select! {
+            _ = retry_failed_rx.changed() => {
+                info!("retrying failed remote task {}", task.op);
+            }
+            _ = task_mgr::shutdown_watcher() => {}
+            _ = self.sync_cancel.cancelled() => {}
+        }
+    }
+
     fn update_remote_physical_size_gauge(&self, current_remote_index_part: Option<&IndexPart>) {
         let size: u64 = if let Some(current_remote_index_part) = current_remote_index_part {
             current_remote_index_part
@@ -434,7 +991,7 @@ impl RemoteTimelineClient {
     //
 
     /// Download index file
-    pub async fn download_index_file(&self) -> Result<MaybeDeletedIndexPart, DownloadError> {
+    pub async fn download_index_file(&self) -> Result<MaybeDeletedIndexPart, RemoteStorageError> {
         let _unfinished_gauge_guard = self.metrics.call_begin(
             &RemoteOpFileKind::Index,
             &RemoteOpKind::Download,
@@ -443,6 +1000,8 @@ impl RemoteTimelineClient {
             },
         );
 
+        let started_at = Instant::now();
+        let _permit = self.sync_limit.acquire(SyncPriority::Low).await;
         let index_part = download::download_index_part(
             self.conf,
             &self.storage_impl,
@@ -456,24 +1015,81 @@ impl RemoteTimelineClient {
             RemoteOpKind::Download,
             Arc::clone(&self.metrics),
         )
-        .await?;
+        .await;
+        sync_task_finished(
+            RemoteOpFileKind::Index,
+            RemoteOpKind::Download,
+            started_at,
+            // A missing index part is an answer, not a failure.
+            matches!(&index_part, Err(e) if !matches!(e, RemoteStorageError::NotFound)),
+        );
+        let index_part = match index_part {
+            // Unless the layers of the timeline are there without it.
+            Err(RemoteStorageError::NotFound) => match repair::repair_missing_index_part(
+                self.conf,
+                &self.storage_impl,
+                self.tenant_id,
+                self.timeline_id,
+                &self.dictionaries,
+            )
+            .await
+            {
+                Ok(Some(index_part)) => index_part,
+                Ok(None) => return Err(RemoteStorageError::NotFound),
+                Err(e) => {
+                    error!("failed to repair the missing index part: {e:#}");
+                    return Err(RemoteStorageError::NotFound);
+                }
+            },
+            index_part => index_part?,
+        };
 
         if index_part.deleted_at.is_some() {
-            Ok(MaybeDeletedIndexPart::Deleted(index_part))
-        } else {
-            Ok(MaybeDeletedIndexPart::IndexPart(index_part))
+            return Ok(MaybeDeletedIndexPart::Deleted(index_part));
+        }
+
+        // The timeline is registered from the index part only if it's the one the manifest
+        // describes. The manifest goes first, so an upload interrupted before the index part
+        // leaves a newer one, and the index parts uploaded by the older versions have none.
+        match download::download_manifest(
+            self.conf,
+            &self.storage_impl,
+            &self.tenant_id,
+            &self.timeline_id,
+        )
+        .await
+        {
+            Ok(manifest) if manifest.disk_consistent_lsn() != index_part.disk_consistent_lsn => {
+                warn!(
+                    "backup manifest is of the upload at {}, not of the index part at {}, not validating the index part",
+                    manifest.disk_consistent_lsn(),
+                    index_part.disk_consistent_lsn
+                );
+            }
+            Ok(manifest) => manifest
+                .validate(&index_part)
+                .context("index part does not match the backup manifest")
+                .map_err(RemoteStorageError::Permanent)?,
+            Err(RemoteStorageError::NotFound) => {
+                info!("no backup manifest uploaded along with the index part, not validating it");
+            }
+            Err(e) => return Err(e),
         }
+
+        Ok(MaybeDeletedIndexPart::IndexPart(index_part))
     }
 
     /// Download a (layer) file from `path`, into local filesystem.
     ///
     /// 'layer_metadata' is the metadata from the remote index file.
+    /// `priority` decides how soon the download gets a sync permit, while others are waiting.
     ///
     /// On success, returns the size of the downloaded file.
     pub async fn download_layer_file(
         &self,
         layer_file_name: &LayerFileName,
         layer_metadata: &LayerFileMetadata,
+        priority: SyncPriority,
     ) -> anyhow::Result<u64> {
         let downloaded_size = {
             let _unfinished_gauge_guard = self.metrics.call_begin(
@@ -483,30 +1099,134 @@ impl RemoteTimelineClient {
                     reason: "no need for a downloads gauge",
                 },
             );
-            download::download_layer_file(
-                self.conf,
-                &self.storage_impl,
-                self.tenant_id,
-                self.timeline_id,
-                layer_file_name,
-                layer_metadata,
-            )
+            let progress = Arc::new(LayerDownloadProgress {
+                bytes_done: AtomicU64::new(0),
+                bytes_total: layer_metadata.file_size(),
+            });
+            self.downloads_in_progress
+                .lock()
+                .unwrap()
+                .push(Arc::clone(&progress));
+            let _progress_guard = scopeguard::guard((), |()| {
+                self.downloads_in_progress
+                    .lock()
+                    .unwrap()
+                    .retain(|download| !Arc::ptr_eq(download, &progress));
+            });
+
+            let started_at = Instant::now();
+            let download = async {
+                let _permit = self.sync_limit.acquire(priority).await;
+                self.download_layer_file_from_fastest(
+                    layer_file_name,
+                    layer_metadata,
+                    &progress.bytes_done,
+                )
+                .await
+            }
+            .instrument(info_span!(
+                "remote_download",
+                layer = %layer_file_name.file_name(),
+                bytes = layer_metadata.file_size()
+            ))
             .measure_remote_op(
                 self.tenant_id,
                 self.timeline_id,
                 RemoteOpFileKind::Layer,
                 RemoteOpKind::Download,
                 Arc::clone(&self.metrics),
-            )
-            .await?
+            );
+            // The temp file of a cancelled download is removed by `cancel_sync`.
+            let downloaded_size = tokio::select! {
+                downloaded_size = download => downloaded_size,
+                _ = self.sync_cancel.cancelled() => {
+                    anyhow::bail!("download of {} was cancelled", layer_file_name.file_name())
+                }
+            };
+            sync_task_finished(
+                RemoteOpFileKind::Layer,
+                RemoteOpKind::Download,
+                started_at,
+                downloaded_size.is_err(),
+            );
+            self.record_sync_result(
+                RemoteOpKind::Download,
+                downloaded_size.as_ref().err().map(|e| format!("{e:#}")),
+            );
+            downloaded_size?
         };
 
         REMOTE_ONDEMAND_DOWNLOADED_LAYERS.inc();
         REMOTE_ONDEMAND_DOWNLOADED_BYTES.inc_by(downloaded_size);
+        events::send_sync_event(SyncEvent::DownloadCompleted {
+            tenant_id: self.tenant_id,
+            timeline_id: self.timeline_id,
+            layer_file_name: layer_file_name.clone(),
+        });
 
         Ok(downloaded_size)
     }
 
+    /// Downloads the layer from the storage that [`replica::download_order`] puts first among
+    /// the primary one and the replicas that have the layer, from the next one if that fails,
+    /// and so on. Without replicas, from the primary storage alone.
+    async fn download_layer_file_from_fastest(
+        &self,
+        layer_file_name: &LayerFileName,
+        layer_metadata: &LayerFileMetadata,
+        bytes_done: &AtomicU64,
+    ) -> Result<u64, RemoteStorageError> {
+        let object_name = match layer_metadata.archive() {
+            Some(archive) => archive.name.clone(),
+            None => layer_file_name.file_name(),
+        };
+        let mut targets = vec![(0, &SyncTargets::get(self.conf).primary, &self.storage_impl)];
+        targets.extend(
+            self.replicas
+                .iter()
+                .filter(|replica| replica.has_object(&object_name))
+                .map(|replica| (replica.number(), replica.stats(), replica.storage())),
+        );
+        let order = replica::download_order(
+            &targets
+                .iter()
+                .map(|(_, stats, _)| *stats)
+                .collect::<Vec<_>>(),
+        );
+
+        let mut last_error = None;
+        for (i, target) in order.iter().enumerate() {
+            let (number, stats, storage) = targets[*target];
+            let started_at = Instant::now();
+            match download::download_layer_file(
+                self.conf,
+                storage,
+                self.tenant_id,
+                self.timeline_id,
+                layer_file_name,
+                layer_metadata,
+                bytes_done,
+                &self.dictionaries,
+            )
+            .await
+            {
+                Ok(downloaded_size) => {
+                    stats.record_download(downloaded_size, started_at.elapsed());
+                    return Ok(downloaded_size);
+                }
+                Err(e) => {
+                    stats.record_failure();
+                    if i + 1 < order.len() {
+                        warn!("failed to download layer from remote storage number {number}, trying the next one: {e:#}");
+                        bytes_done.store(0, Ordering::Relaxed);
+                    }
+                    last_error = Some(e);
+                }
+            }
+        }
+        Err(last_error.expect("the primary storage is always tried"))
+    }
+
     //
     // Upload operations.
     //
@@ -576,22 +1296,111 @@ impl RemoteTimelineClient {
             upload_queue.latest_files_changes_since_metadata_upload_scheduled,
         );
 
-        let disk_consistent_lsn = upload_queue.latest_metadata.disk_consistent_lsn();
+        // Checkpoints in quick succession schedule index uploads faster than they're performed,
+        // only the last of the ones still queued needs to be.
+        if self.conf.remote_coalesce_index_uploads {
+            if let Some(superseded) = upload_queue.take_superseded_index_upload() {
+                info!("dropping the superseded queued {superseded}");
+                // Its index generation is never uploaded, so it can't be kept either.
+                if let UploadOp::UploadMetadata(_, lsn) = &superseded {
+                    if upload_queue.index_generations.back() == Some(lsn) {
+                        upload_queue.index_generations.pop_back();
+                    }
+                }
+                self.tasks_queued_metric_dec(&superseded);
+                self.calls_unfinished_metric_end(&superseded);
+            }
+        }
 
-        let index_part = IndexPart::new(
-            upload_queue.latest_files.clone(),
-            disk_consistent_lsn,
-            metadata_bytes,
+        // The index part refers to the layers by their place in the archive, which has to be
+        // uploaded before it, same as the layers uploaded on their own.
+        if let Some(archive) = upload_queue.pack_unarchived_layers() {
+            info!(
+                "scheduled upload of {} small layer files in archive {}",
+                archive.layers.len(),
+                archive.name
+            );
+            let op = UploadOp::UploadArchive(archive);
+            self.calls_unfinished_metric_begin(&op);
+            upload_queue.push_op(op);
+        }
+
+        let disk_consistent_lsn = upload_queue.latest_metadata.disk_consistent_lsn();
+        let expired_layers =
+            upload_queue.advance_retention_window(disk_consistent_lsn, self.retention_window());
+        let expired_generations =
+            upload_queue.advance_index_generations(disk_consistent_lsn, self.retention_window());
+        let expired_layers: Vec<_> = expired_layers
+            .into_iter()
+            .filter(|name| !upload_queue.remove_from_archive(name))
+            .collect();
+        let empty_archives = upload_queue.take_empty_archives();
+
+        let mut index_part = IndexPart::new(
+            upload_queue.latest_files.clone(),
+            disk_consistent_lsn,
+            metadata_bytes,
         );
+        index_part.superseded_layers = upload_queue.superseded_layers.clone();
+        index_part.retained_lsns = upload_queue.retained_lsns.iter().copied().collect();
+        index_part.index_generations = upload_queue.index_generations.iter().copied().collect();
+        index_part.layer_archives = upload_queue.layer_archives.clone();
         let op = UploadOp::UploadMetadata(index_part, disk_consistent_lsn);
         self.calls_unfinished_metric_begin(&op);
-        upload_queue.queued_operations.push_back(op);
+        upload_queue.push_op(op);
         upload_queue.latest_files_changes_since_metadata_upload_scheduled = 0;
 
+        // The layers that fell out of the retention window are deleted after the index part
+        // that forgets them is uploaded, same as the layers deleted without the retention.
+        // The archived ones go with the last layer of their archive.
+        for name in expired_layers {
+            info!("superseded layer {name} is out of the remote GC retention window");
+            self.schedule_layer_deletion_op(
+                upload_queue,
+                name.file_name(),
+                DeletedLayerParts::Unknown,
+            );
+        }
+        for archive in empty_archives {
+            info!("layer archive {archive} has no layers left");
+            self.schedule_layer_deletion_op(upload_queue, archive, DeletedLayerParts::None);
+        }
+        for lsn in expired_generations {
+            let file_name = IndexPart::generation_file_name(lsn);
+            info!("scheduled deletion of the dropped index generation {file_name}");
+            let op = UploadOp::Delete(Delete {
+                file_kind: RemoteOpFileKind::Index,
+                file_name,
+                parts: DeletedLayerParts::None,
+                scheduled_from_timeline_delete: false,
+            });
+            self.calls_unfinished_metric_begin(&op);
+            upload_queue.push_op(op);
+        }
+
         // Launch the task immediately, if possible
         self.launch_queued_tasks(upload_queue);
     }
 
+    /// Queue the remote deletion of a layer, or a layer archive, that's no longer referenced by
+    /// the index part.
+    fn schedule_layer_deletion_op(
+        self: &Arc<Self>,
+        upload_queue: &mut UploadQueueInitialized,
+        file_name: String,
+        parts: DeletedLayerParts,
+    ) {
+        info!("scheduled layer file deletion {file_name}");
+        let op = UploadOp::Delete(Delete {
+            file_kind: RemoteOpFileKind::Layer,
+            file_name,
+            parts,
+            scheduled_from_timeline_delete: false,
+        });
+        self.calls_unfinished_metric_begin(&op);
+        upload_queue.push_op(op);
+    }
+
     ///
     /// Launch an upload operation in the background.
     ///
@@ -603,20 +1412,53 @@ impl RemoteTimelineClient {
         let mut guard = self.upload_queue.lock().unwrap();
         let upload_queue = guard.initialized_mut()?;
 
+        self.schedule_layer_upload_op(upload_queue, layer_file_name, layer_metadata);
+
+        // Launch the task immediately, if possible
+        self.launch_queued_tasks(upload_queue);
+        Ok(())
+    }
+
+    /// How many of the most recent uploaded `disk_consistent_lsn`s the superseded layers and
+    /// the index generations are kept for: a retained LSN is restored from its generation.
+    fn retention_window(&self) -> usize {
+        self.conf
+            .remote_gc_retained_lsns
+            .max(self.conf.remote_index_generations)
+    }
+
+    /// Queue a layer file upload (internal function)
+    fn schedule_layer_upload_op(
+        self: &Arc<Self>,
+        upload_queue: &mut UploadQueueInitialized,
+        layer_file_name: &LayerFileName,
+        layer_metadata: &LayerFileMetadata,
+    ) {
+        // A layer larger than the remote storage takes is uploaded in parts.
+        let layer_metadata = layer_metadata.clone().with_parts(LayerParts::of_layer(
+            layer_metadata.file_size(),
+            self.storage_impl.max_object_size(),
+        ));
         upload_queue
             .latest_files
             .insert(layer_file_name.clone(), layer_metadata.clone());
+        upload_queue.superseded_layers.remove(layer_file_name);
         upload_queue.latest_files_changes_since_metadata_upload_scheduled += 1;
+        // A layer uploaded again replaces its archived copy, the archive is deleted with
+        // the next index upload if it was the last one in there.
+        upload_queue.remove_from_archive(layer_file_name);
+
+        if layer_metadata.file_size() < self.conf.remote_layer_archive_threshold {
+            info!("scheduled layer file upload {layer_file_name} in the next layer archive");
+            upload_queue.unarchived_layers.push(layer_file_name.clone());
+            return;
+        }
 
-        let op = UploadOp::UploadLayer(layer_file_name.clone(), layer_metadata.clone());
+        let op = UploadOp::UploadLayer(layer_file_name.clone(), layer_metadata);
         self.calls_unfinished_metric_begin(&op);
-        upload_queue.queued_operations.push_back(op);
+        upload_queue.push_op(op);
 
         info!("scheduled layer file upload {layer_file_name}");
-
-        // Launch the task immediately, if possible
-        self.launch_queued_tasks(upload_queue);
-        Ok(())
     }
 
     /// Launch a delete operation in the background.
@@ -628,69 +1470,171 @@ impl RemoteTimelineClient {
     /// deletion won't actually be performed, until any previously scheduled
     /// upload operations, and the index file upload, have completed
     /// successfully.
+    ///
+    /// With `remote_gc_retained_lsns` configured, the remote files are not deleted
+    /// right away, but kept until no retained `disk_consistent_lsn` uses them anymore,
+    /// see [`UploadQueueInitialized::advance_retention_window`].
     pub fn schedule_layer_file_deletion(
         self: &Arc<Self>,
         names: &[LayerFileName],
     ) -> anyhow::Result<()> {
         let mut guard = self.upload_queue.lock().unwrap();
         let upload_queue = guard.initialized_mut()?;
+        if let Some(deleted_layers) = upload_queue.pending_reconciliation.as_mut() {
+            deleted_layers.extend(names.iter().cloned());
+        }
 
         // Deleting layers doesn't affect the values stored in TimelineMetadata,
         // so we don't need update it. Just serialize it.
         let metadata_bytes = upload_queue.latest_metadata.to_bytes()?;
+        self.schedule_layer_deletions(upload_queue, names, None, metadata_bytes);
+
+        // Launch the tasks immediately, if possible
+        self.launch_queued_tasks(upload_queue);
+        Ok(())
+    }
+
+    /// Launch the deletion of the layer files compaction has replaced with `covered_by`, which
+    /// hold all of their data, see [`Self::schedule_layer_file_deletion`].
+    ///
+    /// During heavy compaction, the layers flushed a moment ago may be compacted before their
+    /// uploads start. Those uploads are dropped, if no index part still to be uploaded refers to
+    /// the layers, and the layers covering them are scheduled for upload.
+    pub fn schedule_compacted_layer_deletion(
+        self: &Arc<Self>,
+        names: &[LayerFileName],
+        covered_by: &[LayerFileName],
+    ) -> anyhow::Result<()> {
+        let mut guard = self.upload_queue.lock().unwrap();
+        let upload_queue = guard.initialized_mut()?;
+        if let Some(deleted_layers) = upload_queue.pending_reconciliation.as_mut() {
+            deleted_layers.extend(names.iter().cloned());
+        }
+
+        let metadata_bytes = upload_queue.latest_metadata.to_bytes()?;
+        self.schedule_layer_deletions(upload_queue, names, Some(covered_by), metadata_bytes);
+
+        self.launch_queued_tasks(upload_queue);
+        Ok(())
+    }
+
+    /// Queue the index upload that forgets the layers, and their deletions (internal function)
+    ///
+    /// With `covered_by` known, the queued uploads of the layers that the remote storage never
+    /// needs are dropped.
+    fn schedule_layer_deletions(
+        self: &Arc<Self>,
+        upload_queue: &mut UploadQueueInitialized,
+        names: &[LayerFileName],
+        covered_by: Option<&[LayerFileName]>,
+        metadata_bytes: Vec<u8>,
+    ) {
+        let retain_remotely = self.retention_window() > 0;
+        let last_used_at = upload_queue.latest_metadata.disk_consistent_lsn();
 
         // Update the remote index file, removing the to-be-deleted files from the index,
         // before deleting the actual files.
         //
         // Once we start removing files from upload_queue.latest_files, there's
         // no going back! Otherwise, some of the files would already be removed
-        // from latest_files, but not yet scheduled for deletion. Use a closure
-        // to syntactically forbid ? or bail! calls here.
-        let no_bail_here = || {
-            for name in names {
-                upload_queue.latest_files.remove(name);
-                upload_queue.latest_files_changes_since_metadata_upload_scheduled += 1;
+        // from latest_files, but not yet scheduled for deletion. This function
+        // returns nothing to syntactically forbid ? or bail! calls here.
+        let mut own_objects = Vec::new();
+        for name in names {
+            let metadata = upload_queue.latest_files.remove(name);
+            upload_queue.latest_files_changes_since_metadata_upload_scheduled += 1;
+            if retain_remotely {
+                upload_queue
+                    .superseded_layers
+                    .insert(name.clone(), last_used_at);
+            } else if !upload_queue.remove_from_archive(name) {
+                let parts = metadata.as_ref().and_then(LayerFileMetadata::parts);
+                own_objects.push((name.file_name(), DeletedLayerParts::from(parts)));
             }
+        }
 
-            if upload_queue.latest_files_changes_since_metadata_upload_scheduled > 0 {
-                self.schedule_index_upload(upload_queue, metadata_bytes);
-            }
+        // Also deletes the archives left without layers, after the index upload.
+        if upload_queue.latest_files_changes_since_metadata_upload_scheduled > 0 {
+            self.schedule_index_upload(upload_queue, metadata_bytes);
+        }
 
-            // schedule the actual deletions
+        // Only now, the index uploads superseded by the one above are dropped. A layer kept for
+        // the remote GC is listed in the index part, so it's uploaded anyway. The deletion is
+        // still scheduled, in case an earlier upload of the same layer has made it.
+        let covered = covered_by.is_some_and(|covered_by| {
+            covered_by
+                .iter()
+                .all(|name| upload_queue.latest_files.contains_key(name))
+        });
+        if covered && !retain_remotely {
             for name in names {
-                let op = UploadOp::Delete(Delete {
-                    file_kind: RemoteOpFileKind::Layer,
-                    layer_file_name: name.clone(),
-                    scheduled_from_timeline_delete: false,
-                });
-                self.calls_unfinished_metric_begin(&op);
-                upload_queue.queued_operations.push_back(op);
-                info!("scheduled layer file deletion {name}");
+                if let Some(skipped) = upload_queue.take_unreferenced_layer_upload(name) {
+                    info!("dropping the queued {skipped}, the layer is compacted already");
+                    self.tasks_queued_metric_dec(&skipped);
+                    self.calls_unfinished_metric_end(&skipped);
+                }
             }
+        }
 
-            // Launch the tasks immediately, if possible
-            self.launch_queued_tasks(upload_queue);
-        };
-        no_bail_here();
-        Ok(())
+        // schedule the actual deletions, unless the remote GC takes care of them
+        for (file_name, parts) in own_objects {
+            self.schedule_layer_deletion_op(upload_queue, file_name, parts);
+        }
     }
 
     ///
-    /// Wait for all previously scheduled uploads/deletions to complete
+    /// Wait for all previously scheduled uploads/deletions to complete. Fails if the remote
+    /// uploads are paused before they do.
     ///
     pub async fn wait_completion(self: &Arc<Self>) -> anyhow::Result<()> {
         let mut receiver = {
             let mut guard = self.upload_queue.lock().unwrap();
             let upload_queue = guard.initialized_mut()?;
+            // Nothing completes until then, don't keep the caller waiting for it.
+            anyhow::ensure!(
+                upload_queue.pending_reconciliation.is_none(),
+                "the remote reconciliation is pending"
+            );
+            // The small layers are only packed into an archive with the next index upload, the
+            // caller waits for them to be uploaded too, e.g. before evicting them.
+            if !upload_queue.unarchived_layers.is_empty() {
+                let metadata_bytes = upload_queue.latest_metadata.to_bytes()?;
+                self.schedule_index_upload(upload_queue, metadata_bytes);
+            }
             self.schedule_barrier(upload_queue)
         };
 
-        if receiver.changed().await.is_err() {
-            anyhow::bail!("wait_completion aborted because upload queue was stopped");
+        // The tasks queued before the barrier don't start while the uploads are paused, don't
+        // keep the caller waiting until they are resumed.
+        tokio::select! {
+            biased;
+            changed = receiver.changed() => {
+                if changed.is_err() {
+                    anyhow::bail!("wait_completion aborted because upload queue was stopped");
+                }
+            }
+            _ = UploadPause::get(self.conf).wait_until_paused() => {
+                anyhow::bail!("wait_completion aborted because the remote uploads are paused");
+            }
         }
         Ok(())
     }
 
+    /// The number of uploads and deletions queued or in flight. Zero once the queue is stopped.
+    pub(crate) fn pending_operations(&self) -> usize {
+        match &*self.upload_queue.lock().unwrap() {
+            UploadQueue::Initialized(upload_queue) => {
+                let queued = upload_queue
+                    .queued_operations
+                    .iter()
+                    .filter(|(op, ..)| !matches!(op, UploadOp::Barrier(_)))
+                    .count();
+                queued + upload_queue.inprogress_tasks.len()
+            }
+            UploadQueue::Uninitialized | UploadQueue::Stopped(_) => 0,
+        }
+    }
+
     fn schedule_barrier(
         self: &Arc<Self>,
         upload_queue: &mut UploadQueueInitialized,
@@ -698,9 +1642,15 @@ impl RemoteTimelineClient {
         let (sender, receiver) = tokio::sync::watch::channel(());
         let barrier_op = UploadOp::Barrier(sender);
 
-        upload_queue.queued_operations.push_back(barrier_op);
+        upload_queue.push_op(barrier_op);
         // Don't count this kind of operation!
 
+        // The caller waits for the tasks in progress too, the ones queued before the barrier
+        // start without a delay, see `launch_queued_tasks`.
+        for task in upload_queue.inprogress_tasks.values() {
+            task.start_now.cancel();
+        }
+
         // Launch the task immediately, if possible
         self.launch_queued_tasks(upload_queue);
 
@@ -762,10 +1712,11 @@ impl RemoteTimelineClient {
                     &self.tenant_id,
                     &self.timeline_id,
                     &index_part_with_deleted_at,
+                    self.dictionaries.names(),
                 )
                 .await
             },
-            |_e| false,
+            remote_storage::is_permanent_error,
             1,
             // have just a couple of attempts
             // when executed as part of timeline deletion this happens in context of api call
@@ -811,34 +1762,53 @@ impl RemoteTimelineClient {
 
             debug_assert!(stopped.upload_queue_for_deletion.no_pending_work());
 
-            stopped
-                .upload_queue_for_deletion
-                .queued_operations
-                .reserve(stopped.upload_queue_for_deletion.latest_files.len());
+            let upload_queue = &mut stopped.upload_queue_for_deletion;
+            upload_queue.queued_operations.reserve(
+                upload_queue.latest_files.len()
+                    + upload_queue.superseded_layers.len()
+                    + upload_queue.layer_archives.len(),
+            );
 
-            // schedule the actual deletions
-            for name in stopped.upload_queue_for_deletion.latest_files.keys() {
+            // schedule the actual deletions, including the layers kept for the remote GC retention,
+            // and the archives instead of the layers packed into them
+            let archived: HashSet<&LayerFileName> =
+                upload_queue.layer_archives.values().flatten().collect();
+            let latest_files = upload_queue
+                .latest_files
+                .iter()
+                .map(|(name, metadata)| (name, DeletedLayerParts::from(metadata.parts())));
+            let superseded_layers = upload_queue
+                .superseded_layers
+                .keys()
+                .map(|name| (name, DeletedLayerParts::Unknown));
+            let file_names: Vec<(String, DeletedLayerParts)> = latest_files
+                .chain(superseded_layers)
+                .filter(|(name, _)| !archived.contains(name))
+                .map(|(name, parts)| (name.file_name(), parts))
+                .chain(
+                    upload_queue
+                        .layer_archives
+                        .keys()
+                        .map(|archive| (archive.clone(), DeletedLayerParts::None)),
+                )
+                .collect();
+            for (file_name, parts) in file_names {
+                info!("scheduled layer file deletion {file_name}");
                 let op = UploadOp::Delete(Delete {
                     file_kind: RemoteOpFileKind::Layer,
-                    layer_file_name: name.clone(),
+                    file_name,
+                    parts,
                     scheduled_from_timeline_delete: true,
                 });
                 self.calls_unfinished_metric_begin(&op);
-                stopped
-                    .upload_queue_for_deletion
-                    .queued_operations
-                    .push_back(op);
+                upload_queue.push_op(op);
 
-                info!("scheduled layer file deletion {name}");
                 deletions_queued += 1;
             }
 
-            self.launch_queued_tasks(&mut stopped.upload_queue_for_deletion);
+            self.launch_queued_tasks(upload_queue);
 
-            (
-                self.schedule_barrier(&mut stopped.upload_queue_for_deletion),
-                deletions_queued,
-            )
+            (self.schedule_barrier(upload_queue), deletions_queued)
         };
 
         receiver.changed().await.context("upload queue shut down")?;
@@ -848,19 +1818,23 @@ impl RemoteTimelineClient {
         let timeline_path = self.conf.timeline_path(&self.tenant_id, &self.timeline_id);
         let timeline_storage_path = self.conf.remote_path(&timeline_path)?;
 
-        let remaining = backoff::retry(
-            || async {
-                self.storage_impl
-                    .list_prefixes(Some(&timeline_storage_path))
-                    .await
-            },
-            |_e| false,
-            FAILED_DOWNLOAD_WARN_THRESHOLD,
-            FAILED_REMOTE_OP_RETRIES,
-            "list_prefixes",
-        )
-        .await
-        .context("list prefixes")?;
+        let retry_settings = RemoteOpRetrySettings::from_conf(self.conf);
+        let remaining = retry_settings
+            .retry(
+                || {
+                    with_timeout(
+                        retry_settings.list_timeout,
+                        self.storage_impl
+                            .list_prefixes(Some(&timeline_storage_path)),
+                        |_| RemoteStorageError::Timeout,
+                    )
+                },
+                RemoteStorageError::is_permanent,
+                FAILED_DOWNLOAD_WARN_THRESHOLD,
+                "list_prefixes",
+            )
+            .await
+            .context("list prefixes")?;
 
         let remaining: Vec<RemotePath> = remaining
             .into_iter()
@@ -875,15 +1849,29 @@ impl RemoteTimelineClient {
             .collect();
 
         if !remaining.is_empty() {
-            backoff::retry(
-                || async { self.storage_impl.delete_objects(&remaining).await },
-                |_e| false,
-                FAILED_UPLOAD_WARN_THRESHOLD,
-                FAILED_REMOTE_OP_RETRIES,
-                "delete_objects",
-            )
-            .await
-            .context("delete_objects")?;
+            retry_settings
+                .retry(
+                    || {
+                        with_timeout(
+                            retry_settings.operation_timeout,
+                            self.storage_impl.delete_objects(&remaining),
+                            |_| RemoteStorageError::Timeout,
+                        )
+                    },
+                    RemoteStorageError::is_permanent,
+                    FAILED_UPLOAD_WARN_THRESHOLD,
+                    "delete_objects",
+                )
+                .await
+                .context("delete_objects")?;
+        }
+        if self.conf.remote_upload_dedup {
+            // Uploads of the same contents to other timelines must not be deduplicated into the
+            // objects that are gone now.
+            UploadDedupIndex::get(self.conf)
+                .await?
+                .forget_prefix(&timeline_storage_path)
+                .await;
         }
 
         fail::fail_point!("timeline-delete-before-index-delete", |_| {
@@ -892,19 +1880,34 @@ impl RemoteTimelineClient {
             ))?
         });
 
+        // The replicas go first: with the index part of the primary storage in place, a retry
+        // of the deletion gets to them again.
+        for replica in &self.replicas {
+            replica
+                .delete_timeline()
+                .await
+                .context("delete the timeline from a remote storage replica")?;
+        }
+
         let index_file_path = timeline_storage_path.join(Path::new(IndexPart::FILE_NAME));
 
         debug!("deleting index part");
 
-        backoff::retry(
-            || async { self.storage_impl.delete(&index_file_path).await },
-            |_e| false,
-            FAILED_UPLOAD_WARN_THRESHOLD,
-            FAILED_REMOTE_OP_RETRIES,
-            "delete_index",
-        )
-        .await
-        .context("delete_index")?;
+        retry_settings
+            .retry(
+                || {
+                    with_timeout(
+                        retry_settings.operation_timeout,
+                        self.storage_impl.delete(&index_file_path),
+                        |_| RemoteStorageError::Timeout,
+                    )
+                },
+                RemoteStorageError::is_permanent,
+                FAILED_UPLOAD_WARN_THRESHOLD,
+                "delete_index",
+            )
+            .await
+            .context("delete_index")?;
 
         fail::fail_point!("timeline-delete-after-index-delete", |_| {
             Err(anyhow::anyhow!(
@@ -923,10 +1926,14 @@ impl RemoteTimelineClient {
     ///
     /// The caller needs to already hold the `upload_queue` lock.
     fn launch_queued_tasks(self: &Arc<Self>, upload_queue: &mut UploadQueueInitialized) {
-        while let Some(next_op) = upload_queue.queued_operations.front() {
+        // Held until the reconciliation with the remote index part, which drops the queue.
+        if upload_queue.pending_reconciliation.is_some() {
+            return;
+        }
+        while let Some((next_op, ..)) = upload_queue.queued_operations.front() {
             // Can we run this task now?
             let can_run_now = match next_op {
-                UploadOp::UploadLayer(_, _) => {
+                UploadOp::UploadLayer(_, _) | UploadOp::UploadArchive(_) => {
                     // Can always be scheduled.
                     true
                 }
@@ -954,13 +1961,27 @@ impl RemoteTimelineClient {
             }
 
             // We can launch this task. Remove it from the queue first.
-            let next_op = upload_queue.queued_operations.pop_front().unwrap();
+            let (next_op, scheduled_from, scheduled_at) =
+                upload_queue.queued_operations.pop_front().unwrap();
+            self.tasks_queued_metric_dec(&next_op);
+
+            // Many timelines flush their layers at once, e.g. on a checkpoint timeout, so their
+            // uploads are spread over the `upload_start_jitter`. Unless someone is waiting.
+            let start_delay = match next_op {
+                UploadOp::UploadLayer(_, _) | UploadOp::UploadArchive(_)
+                    if !self.upload_start_jitter.is_zero()
+                        && !upload_queue.has_queued_barrier() =>
+                {
+                    rand::thread_rng().gen_range(Duration::ZERO..=self.upload_start_jitter)
+                }
+                _ => Duration::ZERO,
+            };
 
             debug!("starting op: {}", next_op);
 
             // Update the counters
             match next_op {
-                UploadOp::UploadLayer(_, _) => {
+                UploadOp::UploadLayer(_, _) | UploadOp::UploadArchive(_) => {
                     upload_queue.num_inprogress_layer_uploads += 1;
                 }
                 UploadOp::UploadMetadata(_, _) => {
@@ -984,17 +2005,23 @@ impl RemoteTimelineClient {
                 task_id: upload_task_id,
                 op: next_op,
                 retries: AtomicU32::new(0),
+                scheduled_at,
+                start_delay,
+                start_now: CancellationToken::new(),
             });
             upload_queue
                 .inprogress_tasks
                 .insert(task.task_id, Arc::clone(&task));
 
-            // Spawn task to perform the task
+            // Spawn task to perform the task. It's not a child of the scheduling span, which
+            // may end long before the upload does, but it's linked to it.
             let self_rc = Arc::clone(self);
             let tenant_id = self.tenant_id;
             let timeline_id = self.timeline_id;
+            let span = info_span!(parent: None, "remote_upload", %tenant_id, %timeline_id, %upload_task_id);
+            span.follows_from(&scheduled_from);
             task_mgr::spawn(
-                self.runtime.handle(),
+                &self.runtime,
                 TaskKind::RemoteUploadTask,
                 Some(self.tenant_id),
                 Some(self.timeline_id),
@@ -1004,13 +2031,44 @@ impl RemoteTimelineClient {
                     self_rc.perform_upload_task(task).await;
                     Ok(())
                 }
-                .instrument(info_span!(parent: None, "remote_upload", %tenant_id, %timeline_id, %upload_task_id)),
+                .instrument(span),
             );
 
             // Loop back to process next task
         }
     }
 
+    /// Whether the next index upload refers to the layer, which then has to be uploaded.
+    fn is_layer_referenced(&self, layer_file_name: &LayerFileName) -> bool {
+        match &*self.upload_queue.lock().unwrap() {
+            UploadQueue::Initialized(upload_queue) => {
+                upload_queue.latest_files.contains_key(layer_file_name)
+            }
+            UploadQueue::Uninitialized | UploadQueue::Stopped(_) => false,
+        }
+    }
+
+    /// The index part with the checksums of its layers uploaded since it was scheduled: an
+    /// index upload starts only after the uploads scheduled before it are done.
+    fn with_uploaded_checksums(&self, index_part: &IndexPart) -> IndexPart {
+        let mut index_part = index_part.clone();
+        if let Ok(upload_queue) = self.upload_queue.lock().unwrap().initialized_mut() {
+            for (layer, metadata) in &mut index_part.layer_metadata {
+                if metadata.checksum.is_some() {
+                    continue;
+                }
+                if let Some(latest) = upload_queue
+                    .latest_files
+                    .get(layer)
+                    .filter(|latest| latest.file_size() == metadata.file_size)
+                {
+                    metadata.checksum = latest.checksum().cloned();
+                }
+            }
+        }
+        index_part
+    }
+
     ///
     /// Perform an upload task.
     ///
@@ -1023,18 +2081,37 @@ impl RemoteTimelineClient {
     /// queue.
     ///
     async fn perform_upload_task(self: &Arc<Self>, task: Arc<UploadTask>) {
+        let retry_settings = RemoteOpRetrySettings::from_conf(self.conf);
+        // Consecutive failed attempts since the last sync error.
+        let mut attempt = 0;
+        // Number of times the task has run out of its retries.
+        let mut sync_errors = 0;
+        let started_at = Instant::now();
+        let mut first_attempt = true;
+
+        if !task.start_delay.is_zero() {
+            debug!(
+                "delaying the start of {} by {:?}",
+                task.op, task.start_delay
+            );
+            tokio::select! {
+                _ = tokio::time::sleep(task.start_delay) => {}
+                _ = task.start_now.cancelled() => {}
+                _ = task_mgr::shutdown_watcher() => {}
+                _ = self.sync_cancel.cancelled() => {}
+            }
+        }
+
         // Loop to retry until it completes.
-        loop {
+        let uploaded_checksums = loop {
             // If we're requested to shut down, close up shop and exit.
             //
             // Note: We only check for the shutdown requests between retries, so
             // if a shutdown request arrives while we're busy uploading, in the
             // upload::upload:*() call below, we will wait not exit until it has
-            // finished. We probably could cancel the upload by simply dropping
-            // the Future, but we're not 100% sure if the remote storage library
-            // is cancellation safe, so we don't dare to do that. Hopefully, the
-            // upload finishes or times out soon enough.
-            if task_mgr::is_shutdown_requested() {
+            // finished, or until it makes no progress for `operation_timeout`. A
+            // `cancel_sync` call doesn't wait, it drops the attempt in flight.
+            if task_mgr::is_shutdown_requested() || self.sync_cancel.is_cancelled() {
                 info!("upload task cancelled by shutdown request");
                 match self.stop() {
                     Ok(()) => {}
@@ -1045,54 +2122,118 @@ impl RemoteTimelineClient {
                 return;
             }
 
-            let upload_result: anyhow::Result<()> = match &task.op {
-                UploadOp::UploadLayer(ref layer_file_name, ref layer_metadata) => {
-                    let path = &self
-                        .conf
-                        .timeline_path(&self.tenant_id, &self.timeline_id)
-                        .join(layer_file_name.file_name());
-                    upload::upload_timeline_layer(
-                        self.conf,
-                        &self.storage_impl,
-                        path,
-                        layer_metadata,
-                    )
-                    .measure_remote_op(
-                        self.tenant_id,
-                        self.timeline_id,
-                        RemoteOpFileKind::Layer,
-                        RemoteOpKind::Upload,
-                        Arc::clone(&self.metrics),
-                    )
-                    .await
+            if let UploadOp::Barrier(_) = &task.op {
+                // unreachable. Barrier operations are handled synchronously in
+                // launch_queued_tasks
+                warn!("unexpected Barrier operation in perform_upload_task");
+                break Vec::new();
+            }
+
+            // Released before sleeping between the retries, the other tasks can use it meanwhile.
+            // While the uploads are paused, no new attempts start, see the `pause` module, and
+            // neither do they while another node holds the owner lease.
+            let permit = tokio::select! {
+                permit = async {
+                    UploadPause::get(self.conf).wait_until_resumed().await;
+                    self.owner_lease.wait_until_held().await;
+                    self.sync_limit.acquire(SyncPriority::Low).await
+                } => permit,
+                _ = task_mgr::shutdown_watcher() => continue,
+                _ = self.sync_cancel.cancelled() => continue,
+            };
+            if std::mem::take(&mut first_attempt) {
+                if let Some((file_kind, op_kind, _)) = self.calls_unfinished_metric_impl(&task.op) {
+                    REMOTE_SYNC_TASK_START_LAG
+                        .with_label_values(&[file_kind.as_str(), op_kind.as_str()])
+                        .observe(task.scheduled_at.elapsed().as_secs_f64());
                 }
-                UploadOp::UploadMetadata(ref index_part, _lsn) => {
-                    let res = upload::upload_index_part(
-                        self.conf,
-                        &self.storage_impl,
-                        &self.tenant_id,
-                        &self.timeline_id,
-                        index_part,
-                    )
-                    .measure_remote_op(
-                        self.tenant_id,
-                        self.timeline_id,
-                        RemoteOpFileKind::Index,
-                        RemoteOpKind::Upload,
-                        Arc::clone(&self.metrics),
-                    )
-                    .await;
-                    if res.is_ok() {
-                        self.update_remote_physical_size_gauge(Some(index_part));
+            }
+
+            // An attempt that makes no progress for `operation_timeout` is dropped, which cancels
+            // the requests it has in flight and aborts an unfinished multipart upload (or leaves
+            // it to resume, with a `multipart_resume_dir`), then retried as any other failed one.
+            let upload = async {
+                match &task.op {
+                    UploadOp::UploadLayer(ref layer_file_name, ref layer_metadata) => {
+                        let path = &self
+                            .conf
+                            .timeline_path(&self.tenant_id, &self.timeline_id)
+                            .join(layer_file_name.file_name());
+                        upload::upload_timeline_layer(
+                            self.conf,
+                            &self.storage_impl,
+                            path,
+                            layer_metadata,
+                            Some(&self.dictionaries),
+                            true,
+                        )
+                        .measure_remote_op(
+                            self.tenant_id,
+                            self.timeline_id,
+                            RemoteOpFileKind::Layer,
+                            RemoteOpKind::Upload,
+                            Arc::clone(&self.metrics),
+                        )
+                        .await
+                        .map(|checksum| {
+                            checksum
+                                .map(|checksum| (layer_file_name.clone(), checksum))
+                                .into_iter()
+                                .collect()
+                        })
                     }
-                    res
-                }
-                UploadOp::Delete(delete) => {
-                    let path = &self
-                        .conf
-                        .timeline_path(&self.tenant_id, &self.timeline_id)
-                        .join(delete.layer_file_name.file_name());
-                    delete::delete_layer(self.conf, &self.storage_impl, path)
+                    UploadOp::UploadMetadata(ref index_part, _lsn) => {
+                        let index_part = &self.with_uploaded_checksums(index_part);
+                        let res = upload::upload_index_part(
+                            self.conf,
+                            &self.storage_impl,
+                            &self.tenant_id,
+                            &self.timeline_id,
+                            index_part,
+                            self.dictionaries.names(),
+                        )
+                        .measure_remote_op(
+                            self.tenant_id,
+                            self.timeline_id,
+                            RemoteOpFileKind::Index,
+                            RemoteOpKind::Upload,
+                            Arc::clone(&self.metrics),
+                        )
+                        .await;
+                        if res.is_ok() {
+                            self.update_remote_physical_size_gauge(Some(index_part));
+                        }
+                        res.map(|()| Vec::new())
+                    }
+                    UploadOp::UploadArchive(archive) => {
+                        upload::upload_layer_archive(
+                            self.conf,
+                            &self.storage_impl,
+                            &self.conf.timeline_path(&self.tenant_id, &self.timeline_id),
+                            archive,
+                            |layer_file_name| self.is_layer_referenced(layer_file_name),
+                        )
+                        .measure_remote_op(
+                            self.tenant_id,
+                            self.timeline_id,
+                            RemoteOpFileKind::Layer,
+                            RemoteOpKind::Upload,
+                            Arc::clone(&self.metrics),
+                        )
+                        .await
+                    }
+                    UploadOp::Delete(delete) => {
+                        let path = &self
+                            .conf
+                            .timeline_path(&self.tenant_id, &self.timeline_id)
+                            .join(&delete.file_name);
+                        delete::delete_layer(
+                            self.conf,
+                            &self.storage_impl,
+                            path,
+                            delete.parts,
+                            true,
+                        )
                         .measure_remote_op(
                             self.tenant_id,
                             self.timeline_id,
@@ -1101,28 +2242,32 @@ impl RemoteTimelineClient {
                             Arc::clone(&self.metrics),
                         )
                         .await
-                }
-                UploadOp::Barrier(_) => {
-                    // unreachable. Barrier operations are handled synchronously in
-                    // launch_queued_tasks
-                    warn!("unexpected Barrier operation in perform_upload_task");
-                    break;
+                        .map(|()| Vec::new())
+                    }
+                    UploadOp::Barrier(_) => unreachable!("barriers are not run as upload tasks"),
                 }
             };
+            let upload_result: anyhow::Result<Vec<(LayerFileName, Checksum)>> = tokio::select! {
+                result = with_timeout(retry_settings.operation_timeout, upload, |e| e) => result,
+                _ = self.sync_cancel.cancelled() => continue,
+            };
+
+            drop(permit);
 
             match upload_result {
-                Ok(()) => {
-                    break;
+                Ok(checksums) => {
+                    break checksums;
                 }
                 Err(e) => {
                     let retries = task.retries.fetch_add(1, Ordering::SeqCst);
+                    let permanent = remote_storage::is_permanent_error(&e);
 
                     // Uploads can fail due to rate limits (IAM, S3), spurious network problems,
                     // or other external reasons. Such issues are relatively regular, so log them
                     // at info level at first, and only WARN if the operation fails repeatedly.
                     //
                     // (See similar logic for downloads in `download::download_retry`)
-                    if retries < FAILED_UPLOAD_WARN_THRESHOLD {
+                    if retries < FAILED_UPLOAD_WARN_THRESHOLD && !permanent {
                         info!(
                             "failed to perform remote task {}, will retry (attempt {}): {:#}",
                             task.op, retries, e
@@ -1134,17 +2279,57 @@ impl RemoteTimelineClient {
                         );
                     }
 
+                    // The queue cannot skip the operation, so it is retried forever. But once
+                    // all the retries of an attempt are used up, or the error is not going to
+                    // go away by itself, count that as a sync error and start over.
+                    attempt += 1;
+                    let backoff = retry_settings.backoff_after_error(attempt, permanent);
+                    if permanent || attempt > retry_settings.max_retries {
+                        attempt = 0;
+                        sync_errors += 1;
+                        if let Some((file_kind, op_kind, _)) =
+                            self.calls_unfinished_metric_impl(&task.op)
+                        {
+                            REMOTE_SYNC_ERRORS
+                                .with_label_values(&[file_kind.as_str(), op_kind.as_str()])
+                                .inc();
+                            self.record_sync_result(op_kind, Some(format!("{e:#}")));
+                        }
+                        if sync_errors >= retry_settings.max_sync_errors {
+                            error!(
+                                "remote task {} has failed {} times in a row, out of {} allowed, not attempting it until the failed tasks are retried",
+                                task.op, sync_errors, retry_settings.max_sync_errors
+                            );
+                            self.wait_for_retry_failed(&task, &e).await;
+                            sync_errors = 0;
+                            continue;
+                        }
+                    }
+
                     // sleep until it's time to retry, or we're cancelled
                     tokio::select! {
                         _ = task_mgr::shutdown_watcher() => { },
-                        _ = exponential_backoff(
-                            retries,
-                            DEFAULT_BASE_BACKOFF_SECONDS,
-                            DEFAULT_MAX_BACKOFF_SECONDS,
-                        ) => { },
+                        _ = self.sync_cancel.cancelled() => { },
+                        _ = tokio::time::sleep(backoff) => { },
                     };
                 }
             }
+        };
+
+        if let Some((file_kind, op_kind, _)) = self.calls_unfinished_metric_impl(&task.op) {
+            REMOTE_SYNC_TASK_DURATION
+                .with_label_values(&[file_kind.as_str(), op_kind.as_str()])
+                .observe(started_at.elapsed().as_secs_f64());
+        }
+
+        if let Some((_, op_kind, _)) = self.calls_unfinished_metric_impl(&task.op) {
+            self.record_sync_result(op_kind, None);
+        }
+
+        // The replicas get the operation only once the primary storage has it, in the order
+        // the upload queue completes them in.
+        if let Some(op) = ReplicaOp::of_upload_op(&task.op) {
+            self.replicate(op);
         }
 
         let retries = task.retries.load(Ordering::SeqCst);
@@ -1185,12 +2370,44 @@ impl RemoteTimelineClient {
             upload_queue.inprogress_tasks.remove(&task.task_id);
 
             match task.op {
-                UploadOp::UploadLayer(_, _) => {
+                UploadOp::UploadLayer(ref layer_file_name, ref layer_metadata) => {
+                    upload_queue.num_inprogress_layer_uploads -= 1;
+                    // For the next index upload, unless the layer has changed since.
+                    for (_, checksum) in uploaded_checksums {
+                        if let Some(latest) = upload_queue
+                            .latest_files
+                            .get_mut(layer_file_name)
+                            .filter(|latest| latest.file_size() == layer_metadata.file_size())
+                        {
+                            *latest = latest.clone().with_checksum(checksum);
+                        }
+                    }
+                }
+                UploadOp::UploadArchive(ref archive) => {
                     upload_queue.num_inprogress_layer_uploads -= 1;
+                    // Same, unless the layer has been uploaded on its own since.
+                    for (layer_file_name, checksum) in uploaded_checksums {
+                        if let Some(latest) = upload_queue
+                            .latest_files
+                            .get_mut(&layer_file_name)
+                            .filter(|latest| {
+                                latest
+                                    .archive()
+                                    .map_or(false, |location| location.name == archive.name)
+                            })
+                        {
+                            *latest = latest.clone().with_checksum(checksum);
+                        }
+                    }
                 }
                 UploadOp::UploadMetadata(_, lsn) => {
                     upload_queue.num_inprogress_metadata_uploads -= 1;
                     upload_queue.last_uploaded_consistent_lsn = lsn; // XXX monotonicity check?
+                    events::send_sync_event(SyncEvent::UploadCompleted {
+                        tenant_id: self.tenant_id,
+                        timeline_id: self.timeline_id,
+                        disk_consistent_lsn: lsn,
+                    });
                 }
                 UploadOp::Delete(_) => {
                     upload_queue.num_inprogress_deletions -= 1;
@@ -1204,6 +2421,13 @@ impl RemoteTimelineClient {
         self.calls_unfinished_metric_end(&task.op);
     }
 
+    /// Queues `op` for every remote storage replica.
+    fn replicate(&self, op: ReplicaOp) {
+        for replica in &self.replicas {
+            replica.schedule(op.clone());
+        }
+    }
+
     fn calls_unfinished_metric_impl(
         &self,
         op: &UploadOp,
@@ -1219,6 +2443,11 @@ impl RemoteTimelineClient {
                 RemoteOpKind::Upload,
                 RemoteTimelineClientMetricsCallTrackSize::Bytes(m.file_size()),
             ),
+            UploadOp::UploadArchive(archive) => (
+                RemoteOpFileKind::Layer,
+                RemoteOpKind::Upload,
+                RemoteTimelineClientMetricsCallTrackSize::Bytes(archive.size()),
+            ),
             UploadOp::UploadMetadata(_, _) => (
                 RemoteOpFileKind::Index,
                 RemoteOpKind::Upload,
@@ -1248,6 +2477,20 @@ impl RemoteTimelineClient {
         };
         let guard = self.metrics.call_begin(&file_kind, &op_kind, track_bytes);
         guard.will_decrement_manually(); // in unfinished_ops_metric_end()
+
+        // All the calls that go through here wait in the queue first.
+        REMOTE_SYNC_TASKS_QUEUED
+            .with_label_values(&[file_kind.as_str(), op_kind.as_str()])
+            .inc();
+    }
+
+    /// Called when a queued operation leaves the queue, either to start or to be dropped.
+    fn tasks_queued_metric_dec(&self, op: &UploadOp) {
+        if let Some((file_kind, op_kind, _)) = self.calls_unfinished_metric_impl(op) {
+            REMOTE_SYNC_TASKS_QUEUED
+                .with_label_values(&[file_kind.as_str(), op_kind.as_str()])
+                .dec();
+        }
     }
 
     fn calls_unfinished_metric_end(&self, op: &UploadOp) {
@@ -1276,6 +2519,9 @@ impl RemoteTimelineClient {
             }
             UploadQueue::Initialized(initialized) => {
                 info!("shutting down upload queue");
+                for replica in &self.replicas {
+                    replica.stop();
+                }
 
                 // Replace the queue with the Stopped state, taking ownership of the old
                 // Initialized queue. We will do some checks on it, and then drop it.
@@ -1289,12 +2535,18 @@ impl RemoteTimelineClient {
                         latest_files: initialized.latest_files.clone(),
                         latest_files_changes_since_metadata_upload_scheduled: 0,
                         latest_metadata: initialized.latest_metadata.clone(),
+                        superseded_layers: initialized.superseded_layers.clone(),
+                        retained_lsns: initialized.retained_lsns.clone(),
+                        index_generations: initialized.index_generations.clone(),
+                        layer_archives: initialized.layer_archives.clone(),
+                        unarchived_layers: Vec::new(),
                         last_uploaded_consistent_lsn: initialized.last_uploaded_consistent_lsn,
                         num_inprogress_layer_uploads: 0,
                         num_inprogress_metadata_uploads: 0,
                         num_inprogress_deletions: 0,
                         inprogress_tasks: HashMap::default(),
                         queued_operations: VecDeque::default(),
+                        pending_reconciliation: None,
                     };
 
                     let upload_queue = std::mem::replace(
@@ -1325,7 +2577,8 @@ impl RemoteTimelineClient {
                 drop(qi.inprogress_tasks);
 
                 // Tear down queued ops
-                for op in qi.queued_operations.into_iter() {
+                for (op, ..) in qi.queued_operations.into_iter() {
+                    self.tasks_queued_metric_dec(&op);
                     self.calls_unfinished_metric_end(&op);
                     // Dropping UploadOp::Barrier() here will make wait_completion() return with an Err()
                     // which is exactly what we want to happen.
@@ -1338,6 +2591,29 @@ impl RemoteTimelineClient {
             }
         }
     }
+
+    /// Stops the upload queue like [`Self::stop`], but doesn't let the operations in flight
+    /// finish: the uploads and downloads of the timeline are dropped at their next await point
+    /// and waited for, then the temp files of the unfinished downloads are removed.
+    ///
+    /// An upload leaves nothing behind in the remote storage when dropped, its object appears
+    /// at once when complete. The client can't sync the timeline afterwards, this is meant for
+    /// the timeline deletion.
+    pub async fn cancel_sync(&self) -> Result<(), StopError> {
+        self.stop()?;
+        info!("cancelling the remote operations in flight");
+        self.sync_cancel.cancel();
+        for kind in [TaskKind::RemoteUploadTask, TaskKind::RemoteDownloadTask] {
+            task_mgr::shutdown_tasks(Some(kind), Some(self.tenant_id), Some(self.timeline_id))
+                .await;
+        }
+        if let Err(e) =
+            download::remove_temp_downloads(self.conf, self.tenant_id, self.timeline_id).await
+        {
+            warn!("failed to remove the temp files of the cancelled downloads: {e:#}");
+        }
+        Ok(())
+    }
 }
 
 #[cfg(test)]
@@ -1399,6 +2675,10 @@ mod tests {
         for entry in std::fs::read_dir(remote_path).unwrap().flatten() {
             let entry_name = entry.file_name();
             let fname = entry_name.to_str().unwrap();
+            // The local storage keeps the checksums of the objects next to them.
+            if fname.ends_with(".metadata") {
+                continue;
+            }
             found.push(String::from(fname));
         }
         found.sort();
@@ -1418,6 +2698,14 @@ mod tests {
 
     impl TestSetup {
         fn new(test_name: &str) -> anyhow::Result<Self> {
+            Self::with_storage_wrapper(test_name, |storage| storage)
+        }
+
+        /// Lets the test put its own storage in front of the local one the client works with.
+        fn with_storage_wrapper(
+            test_name: &str,
+            wrap_storage: impl FnOnce(GenericRemoteStorage) -> GenericRemoteStorage,
+        ) -> anyhow::Result<Self> {
             // Use a current-thread runtime in the test
             let runtime = Box::leak(Box::new(
                 tokio::runtime::Builder::new_current_thread()
@@ -1447,18 +2735,43 @@ mod tests {
                     remote_storage::DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_SYNCS,
                 )
                 .unwrap(),
+                max_concurrent_sync_per_tenant: None,
+                max_concurrent_sync_startup: None,
                 max_sync_errors: std::num::NonZeroU32::new(
                     remote_storage::DEFAULT_REMOTE_STORAGE_MAX_SYNC_ERRORS,
                 )
                 .unwrap(),
-                storage: RemoteStorageKind::LocalFs(remote_fs_dir.clone()),
+                max_retries: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_RETRIES,
+                base_backoff_ms: remote_storage::DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS,
+                compression: remote_storage::Compression::None,
+                max_bytes_per_sec: None,
+                dry_run: false,
+                encryption_key_file: None,
+                operation_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+                list_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+                upload_start_jitter: remote_storage::DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+                prefix: None,
+                storage: RemoteStorageKind::LocalFs(remote_storage::LocalFsConfig {
+                    local_path: remote_fs_dir.clone(),
+                    read_only: false,
+                    fsync_batch_window: None,
+                    direct_io_min_size: None,
+                }),
             };
 
-            let storage = GenericRemoteStorage::from_config(&storage_config).unwrap();
+            let storage = wrap_storage(GenericRemoteStorage::from_config(&storage_config).unwrap());
+            let owner_lease = TenantOwnerLease::for_tenant(
+                harness.conf,
+                &storage,
+                harness.tenant_id,
+                runtime.handle(),
+            );
+            let dictionaries =
+                TenantDictionaries::for_tenant(harness.conf, &storage, harness.tenant_id);
 
             let client = Arc::new(RemoteTimelineClient {
                 conf: harness.conf,
-                runtime,
+                runtime: runtime.handle().clone(),
                 tenant_id: harness.tenant_id,
                 timeline_id: TIMELINE_ID,
                 storage_impl: storage,
@@ -1467,6 +2780,16 @@ mod tests {
                     &harness.tenant_id,
                     &TIMELINE_ID,
                 )),
+                sync_limit: SyncLimits::get(harness.conf).for_tenant(harness.tenant_id),
+                owner_lease,
+                dictionaries,
+                downloads_in_progress: Mutex::new(Vec::new()),
+                last_sync_errors: Mutex::new(HashMap::new()),
+                failed_tasks: Mutex::new(HashMap::new()),
+                retry_failed_tx: tokio::sync::watch::channel(0).0,
+                sync_cancel: CancellationToken::new(),
+                upload_start_jitter: Duration::ZERO,
+                replicas: Vec::new(),
             });
 
             Ok(Self {
@@ -1592,6 +2915,11 @@ mod tests {
         );
         let downloaded_metadata = index_part.parse_metadata()?;
         assert_eq!(downloaded_metadata, metadata);
+        // The layers were uploaded before the index part, so it has their checksums.
+        assert!(index_part
+            .layer_metadata
+            .values()
+            .all(|layer_metadata| layer_metadata.checksum.is_some()));
 
         // Schedule upload and then a deletion. Check that the deletion is queued
         let content_baz = dummy_contents("baz");
@@ -1617,6 +2945,7 @@ mod tests {
                 &layer_file_name_1.file_name(),
                 &layer_file_name_2.file_name(),
                 "index_part.json",
+                "backup_manifest.json",
             ],
             &remote_timeline_dir,
         );
@@ -1629,6 +2958,7 @@ mod tests {
                 &layer_file_name_2.file_name(),
                 &layer_file_name_3.file_name(),
                 "index_part.json",
+                "backup_manifest.json",
             ],
             &remote_timeline_dir,
         );
@@ -1637,32 +2967,980 @@ mod tests {
     }
 
     #[test]
-    fn bytes_unfinished_gauge_for_layer_file_uploads() -> anyhow::Result<()> {
-        // Setup
+    fn permanent_errors_back_off_the_longest() {
+        let retry_settings = RemoteOpRetrySettings {
+            max_retries: 3,
+            base_backoff: Duration::from_millis(100),
+            max_sync_errors: 10,
+            operation_timeout: Duration::from_secs(120),
+            list_timeout: Duration::from_secs(120),
+            upload_start_jitter: Duration::ZERO,
+        };
+        for attempt in 1..=retry_settings.max_retries + 1 {
+            assert_eq!(
+                retry_settings.backoff_after_error(attempt, true),
+                MAX_REMOTE_OP_BACKOFF
+            );
+            assert!(
+                retry_settings.backoff_after_error(attempt, false)
+                    <= retry_settings.base_backoff * 2u32.pow(attempt)
+            );
+        }
+    }
 
+    #[test]
+    fn forced_resync_uploads_lost_layers_again() -> anyhow::Result<()> {
         let TestSetup {
             runtime,
+            entered_runtime: _entered_runtime,
             harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir,
             client,
-            ..
-        } = TestSetup::new("metrics")?;
-
-        let metadata = dummy_metadata(Lsn(0x10));
-        client.init_upload_queue_for_empty_remote(&metadata)?;
+        } = TestSetup::new("forced_resync_uploads_lost_layers_again").unwrap();
 
         let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let remote_timeline_dir =
+            remote_fs_dir.join(timeline_path.strip_prefix(&harness.conf.workdir)?);
+        client.init_upload_queue_for_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+
+        let layer_file_name: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let content = dummy_contents("foo");
+        let layer_metadata = LayerFileMetadata::new(content.len() as u64);
+        std::fs::write(timeline_path.join(layer_file_name.file_name()), &content)?;
+        client.schedule_layer_file_upload(&layer_file_name, &layer_metadata)?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x20)))?;
+        runtime.block_on(client.wait_completion())?;
 
-        let layer_file_name_1: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
-        let content_1 = dummy_contents("foo");
-        std::fs::write(
-            timeline_path.join(layer_file_name_1.file_name()),
-            &content_1,
-        )?;
+        // The remote storage loses the layer and the index part behind the client's back.
+        std::fs::remove_file(remote_timeline_dir.join(layer_file_name.file_name()))?;
+        std::fs::remove_file(remote_timeline_dir.join(IndexPart::FILE_NAME))?;
 
-        #[derive(Debug, PartialEq)]
-        struct BytesStartedFinished {
-            started: Option<usize>,
-            finished: Option<usize>,
+        runtime.block_on(
+            client.schedule_forced_resync(&[(layer_file_name.clone(), layer_metadata)]),
+        )?;
+        runtime.block_on(client.wait_completion())?;
+        assert_eq!(
+            std::fs::read(remote_timeline_dir.join(layer_file_name.file_name()))?,
+            content
+        );
+        let index_part = match runtime.block_on(client.download_index_file())? {
+            MaybeDeletedIndexPart::IndexPart(index_part) => index_part,
+            MaybeDeletedIndexPart::Deleted(_) => panic!("unexpectedly got deleted index part"),
+        };
+        assert_eq!(index_part.disk_consistent_lsn, Lsn(0x20));
+        assert_file_list(&index_part.timeline_layers, &[&layer_file_name.file_name()]);
+        Ok(())
+    }
+
+    #[test]
+    fn verify_lists_the_remote_objects() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir,
+            client,
+        } = TestSetup::new("verify_lists_the_remote_objects").unwrap();
+
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let remote_timeline_dir =
+            remote_fs_dir.join(timeline_path.strip_prefix(&harness.conf.workdir)?);
+        client.init_upload_queue_for_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+
+        let layer_file_name: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let content = dummy_contents("foo");
+        std::fs::write(timeline_path.join(layer_file_name.file_name()), &content)?;
+        client.schedule_layer_file_upload(
+            &layer_file_name,
+            &LayerFileMetadata::new(content.len() as u64),
+        )?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x20)))?;
+        runtime.block_on(client.wait_completion())?;
+
+        // The initial layer of the test timeline is never uploaded.
+        let report = runtime.block_on(client.verify_remote_consistency())?;
+        assert!(
+            !report
+                .missing_uploads
+                .contains(&layer_file_name.file_name()),
+            "{report:?}"
+        );
+        assert!(report.missing_remote_objects.is_empty(), "{report:?}");
+        assert!(report.remote_only.is_empty(), "{report:?}");
+        assert!(report.checksum_mismatches.is_empty(), "{report:?}");
+
+        // Same size, other contents.
+        let mut changed = content.clone();
+        changed[0] ^= 0xff;
+        std::fs::write(timeline_path.join(layer_file_name.file_name()), &changed)?;
+        let report = runtime.block_on(client.verify_remote_consistency())?;
+        assert_eq!(
+            report.checksum_mismatches,
+            vec![layer_file_name.file_name()]
+        );
+
+        std::fs::write(remote_timeline_dir.join("leftover"), "leftover")?;
+        std::fs::remove_file(remote_timeline_dir.join(layer_file_name.file_name()))?;
+        std::fs::remove_file(
+            remote_timeline_dir.join(format!("{}.metadata", layer_file_name.file_name())),
+        )?;
+        let report = runtime.block_on(client.verify_remote_consistency())?;
+        assert!(
+            report
+                .missing_uploads
+                .contains(&layer_file_name.file_name()),
+            "{report:?}"
+        );
+        assert_eq!(report.remote_only, vec!["leftover".to_owned()]);
+        Ok(())
+    }
+
+    #[test]
+    fn paused_uploads_fail_wait_completion() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir: _remote_fs_dir,
+            client,
+        } = TestSetup::new("paused_uploads_fail_wait_completion").unwrap();
+        client.init_upload_queue_for_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+        let pause = UploadPause::get(harness.conf);
+
+        // Nothing to wait for.
+        pause.pause();
+        runtime.block_on(client.wait_completion())?;
+
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x20)))?;
+        assert!(runtime.block_on(client.wait_completion()).is_err());
+
+        pause.resume();
+        runtime.block_on(client.wait_completion())?;
+        assert_eq!(client.pending_operations(), 0);
+        Ok(())
+    }
+
+    #[test]
+    fn sync_status() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir: _remote_fs_dir,
+            client,
+        } = TestSetup::new("sync_status").unwrap();
+
+        assert_eq!(client.sync_status(), RemoteSyncStatus::Uninitialized);
+        client.init_upload_queue_for_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+        assert_eq!(client.sync_status(), RemoteSyncStatus::Ready);
+
+        let layer_file_name: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let content = dummy_contents("foo");
+        std::fs::write(
+            harness
+                .timeline_path(&TIMELINE_ID)
+                .join(layer_file_name.file_name()),
+            &content,
+        )?;
+        client.schedule_layer_file_upload(
+            &layer_file_name,
+            &LayerFileMetadata::new(content.len() as u64),
+        )?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x20)))?;
+        assert_eq!(client.sync_status(), RemoteSyncStatus::Uploading);
+
+        // Downloads take precedence, and add up.
+        for bytes_done in [100, 200] {
+            client
+                .downloads_in_progress
+                .lock()
+                .unwrap()
+                .push(Arc::new(LayerDownloadProgress {
+                    bytes_done: AtomicU64::new(bytes_done),
+                    bytes_total: 1000,
+                }));
+        }
+        assert_eq!(
+            client.sync_status(),
+            RemoteSyncStatus::Downloading {
+                bytes_done: 300,
+                bytes_total: 2000
+            }
+        );
+        client.downloads_in_progress.lock().unwrap().clear();
+
+        runtime.block_on(client.wait_completion())?;
+        assert_eq!(client.sync_status(), RemoteSyncStatus::Ready);
+
+        // A failed download is reported until the next download succeeds.
+        let missing_layer: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D9-00000000016B5A52".parse().unwrap();
+        let download = runtime.block_on(
+            client
+                .download_layer_file(&missing_layer, &LayerFileMetadata::new(10), SyncPriority::Low)
+                .instrument(info_span!("download_layer", tenant_id = %harness.tenant_id, timeline_id = %TIMELINE_ID)),
+        );
+        assert!(download.is_err());
+        assert!(matches!(
+            client.sync_status(),
+            RemoteSyncStatus::Failed { .. }
+        ));
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x30)))?;
+        runtime.block_on(client.wait_completion())?;
+        assert!(matches!(
+            client.sync_status(),
+            RemoteSyncStatus::Failed { .. }
+        ));
+        std::fs::remove_file(
+            harness
+                .timeline_path(&TIMELINE_ID)
+                .join(layer_file_name.file_name()),
+        )?;
+        runtime.block_on(
+            client
+                .download_layer_file(&layer_file_name, &LayerFileMetadata::new(content.len() as u64), SyncPriority::Low)
+                .instrument(info_span!("download_layer", tenant_id = %harness.tenant_id, timeline_id = %TIMELINE_ID)),
+        )?;
+        assert_eq!(client.sync_status(), RemoteSyncStatus::Ready);
+
+        Ok(())
+    }
+
+    #[test]
+    fn failed_tasks_wait_for_retry() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir: _remote_fs_dir,
+            client,
+        } = TestSetup::new("failed_tasks_wait_for_retry").unwrap();
+
+        let task = Arc::new(UploadTask {
+            task_id: 1,
+            retries: AtomicU32::new(0),
+            op: UploadOp::Barrier(tokio::sync::watch::channel(()).0),
+            scheduled_at: Instant::now(),
+            start_delay: Duration::ZERO,
+            start_now: CancellationToken::new(),
+        });
+        let (done_tx, mut done_rx) = tokio::sync::oneshot::channel();
+        // Spawned as a task of the task manager, for the shutdown watcher.
+        task_mgr::spawn(
+            runtime.handle(),
+            TaskKind::RemoteUploadTask,
+            Some(harness.tenant_id),
+            Some(TIMELINE_ID),
+            "failed remote task",
+            false,
+            {
+                let client = Arc::clone(&client);
+                let task = Arc::clone(&task);
+                async move {
+                    let error = anyhow::anyhow!("access denied");
+                    client.wait_for_retry_failed(&task, &error).await;
+                    let _ = done_tx.send(());
+                    Ok(())
+                }
+            },
+        );
+
+        runtime.block_on(async {
+            while client.failed_tasks().is_empty() {
+                tokio::task::yield_now().await;
+            }
+        });
+        assert_eq!(
+            client.failed_tasks(),
+            [RemoteSyncFailedTask {
+                operation: task.op.to_string(),
+                error: "access denied".to_string(),
+            }]
+        );
+        assert!(done_rx.try_recv().is_err());
+
+        assert_eq!(client.retry_failed(), 1);
+        runtime.block_on(done_rx)?;
+        assert!(client.failed_tasks().is_empty());
+
+        Ok(())
+    }
+
+    /// Passes the uploads on to the inner storage with a stream that stops right before its end,
+    /// so that they are stuck with a part of the object written.
+    struct StuckUploads(GenericRemoteStorage);
+
+    struct NeverEnds;
+
+    impl tokio::io::AsyncRead for NeverEnds {
+        fn poll_read(
+            self: std::pin::Pin<&mut Self>,
+            _cx: &mut std::task::Context<'_>,
+            _buf: &mut tokio::io::ReadBuf<'_>,
+        ) -> std::task::Poll<std::io::Result<()>> {
+            std::task::Poll::Pending
+        }
+    }
+
+    #[async_trait::async_trait]
+    impl remote_storage::RemoteStorage for StuckUploads {
+        async fn list_prefixes(
+            &self,
+            prefix: Option<&RemotePath>,
+        ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+            self.0.list_prefixes(prefix).await
+        }
+
+        async fn list_files(
+            &self,
+            folder: Option<&RemotePath>,
+        ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+            self.0.list_files(folder).await
+        }
+
+        async fn upload(
+            &self,
+            data: remote_storage::UploadStream,
+            data_size_bytes: usize,
+            to: &RemotePath,
+            metadata: Option<remote_storage::StorageMetadata>,
+        ) -> Result<(), RemoteStorageError> {
+            use tokio::io::AsyncReadExt;
+            self.0
+                .upload(data.chain(NeverEnds), data_size_bytes, to, metadata)
+                .await
+        }
+
+        async fn download(
+            &self,
+            from: &RemotePath,
+        ) -> Result<remote_storage::Download, RemoteStorageError> {
+            self.0.download(from).await
+        }
+
+        async fn download_byte_range(
+            &self,
+            from: &RemotePath,
+            start_inclusive: u64,
+            end_exclusive: Option<u64>,
+        ) -> Result<remote_storage::Download, RemoteStorageError> {
+            self.0
+                .download_byte_range(from, start_inclusive, end_exclusive)
+                .await
+        }
+
+        async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+            self.0.delete(path).await
+        }
+
+        async fn delete_objects<'a>(
+            &self,
+            paths: &'a [RemotePath],
+        ) -> Result<(), RemoteStorageError> {
+            self.0.delete_objects(paths).await
+        }
+    }
+
+    /// Records the threads the uploads run on, and passes everything on to the inner storage.
+    struct UploadThreads {
+        inner: GenericRemoteStorage,
+        threads: Arc<Mutex<Vec<Option<String>>>>,
+    }
+
+    #[async_trait::async_trait]
+    impl remote_storage::RemoteStorage for UploadThreads {
+        async fn list_prefixes(
+            &self,
+            prefix: Option<&RemotePath>,
+        ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+            self.inner.list_prefixes(prefix).await
+        }
+
+        async fn list_files(
+            &self,
+            folder: Option<&RemotePath>,
+        ) -> Result<Vec<RemotePath>, RemoteStorageError> {
+            self.inner.list_files(folder).await
+        }
+
+        async fn upload(
+            &self,
+            data: remote_storage::UploadStream,
+            data_size_bytes: usize,
+            to: &RemotePath,
+            metadata: Option<remote_storage::StorageMetadata>,
+        ) -> Result<(), RemoteStorageError> {
+            self.threads
+                .lock()
+                .unwrap()
+                .push(std::thread::current().name().map(str::to_owned));
+            self.inner.upload(data, data_size_bytes, to, metadata).await
+        }
+
+        async fn download(
+            &self,
+            from: &RemotePath,
+        ) -> Result<remote_storage::Download, RemoteStorageError> {
+            self.inner.download(from).await
+        }
+
+        async fn download_byte_range(
+            &self,
+            from: &RemotePath,
+            start_inclusive: u64,
+            end_exclusive: Option<u64>,
+        ) -> Result<remote_storage::Download, RemoteStorageError> {
+            self.inner
+                .download_byte_range(from, start_inclusive, end_exclusive)
+                .await
+        }
+
+        async fn delete(&self, path: &RemotePath) -> Result<(), RemoteStorageError> {
+            self.inner.delete(path).await
+        }
+
+        async fn delete_objects<'a>(
+            &self,
+            paths: &'a [RemotePath],
+        ) -> Result<(), RemoteStorageError> {
+            self.inner.delete_objects(paths).await
+        }
+    }
+
+    #[test]
+    fn sync_tasks_run_on_the_given_runtime() -> anyhow::Result<()> {
+        let primary_threads = Arc::new(Mutex::new(Vec::new()));
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir: _remote_fs_dir,
+            client,
+        } = TestSetup::with_storage_wrapper("sync_tasks_run_on_the_given_runtime", {
+            let threads = Arc::clone(&primary_threads);
+            move |storage| {
+                GenericRemoteStorage::Custom(Arc::new(UploadThreads {
+                    inner: storage,
+                    threads,
+                }))
+            }
+        })?;
+        // The runtime of the embedding application, next to the current-thread one of the test
+        // that schedules the operations.
+        let sync_runtime = Box::leak(Box::new(
+            tokio::runtime::Builder::new_multi_thread()
+                .worker_threads(1)
+                .thread_name("remote-sync")
+                .enable_all()
+                .build()?,
+        ));
+        let on_sync_runtime = |threads: &[Option<String>]| {
+            !threads.is_empty()
+                && threads
+                    .iter()
+                    .all(|thread| thread.as_deref() == Some("remote-sync"))
+        };
+
+        let client = Arc::new(RemoteTimelineClient::with_runtime(
+            client.storage_impl.clone(),
+            harness.conf,
+            harness.tenant_id,
+            TIMELINE_ID,
+            sync_runtime.handle().clone(),
+        ));
+        client.init_upload_queue_for_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let layer_file_name: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let content = dummy_contents("foo");
+        std::fs::write(timeline_path.join(layer_file_name.file_name()), &content)?;
+        client.schedule_layer_file_upload(
+            &layer_file_name,
+            &LayerFileMetadata::new(content.len() as u64),
+        )?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x20)))?;
+        runtime.block_on(client.wait_completion())?;
+        assert!(
+            on_sync_runtime(&primary_threads.lock().unwrap()),
+            "{primary_threads:?}"
+        );
+
+        // A replica that catches up with the primary storage, scheduled from the test runtime
+        // too.
+        let index_part = match runtime.block_on(client.download_index_file())? {
+            MaybeDeletedIndexPart::IndexPart(index_part) => index_part,
+            MaybeDeletedIndexPart::Deleted(_) => panic!("unexpectedly got deleted index part"),
+        };
+        let replica_dir = harness.conf.workdir.join("replica");
+        let replica_threads = Arc::new(Mutex::new(Vec::new()));
+        let replica_targets: &'static [ReplicaTarget] = Box::leak(Box::new([ReplicaTarget::new(
+            1,
+            GenericRemoteStorage::Custom(Arc::new(UploadThreads {
+                inner: GenericRemoteStorage::LocalFs(remote_storage::LocalFs::new(
+                    replica_dir.clone(),
+                )?),
+                threads: Arc::clone(&replica_threads),
+            })),
+        )]));
+        let replicated = RemoteTimelineClient::with_replica_targets(
+            client.storage_impl.clone(),
+            harness.conf,
+            harness.tenant_id,
+            TIMELINE_ID,
+            sync_runtime.handle().clone(),
+            replica_targets,
+        );
+        replicated.init_upload_queue(&index_part)?;
+
+        let replica_index = replica_dir
+            .join(timeline_path.strip_prefix(&harness.conf.workdir)?)
+            .join(IndexPart::FILE_NAME);
+        runtime.block_on(tokio::time::timeout(Duration::from_secs(10), async {
+            while !replica_index.exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        }))?;
+        assert!(
+            on_sync_runtime(&replica_threads.lock().unwrap()),
+            "{replica_threads:?}"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn superseded_index_upload_is_coalesced() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir: _remote_fs_dir,
+            client,
+        } = TestSetup::new("superseded_index_upload_is_coalesced")?;
+        let conf: &'static PageServerConf = Box::leak(Box::new(PageServerConf {
+            remote_coalesce_index_uploads: true,
+            ..harness.conf.clone()
+        }));
+        let client = Arc::new(RemoteTimelineClient {
+            conf,
+            ..Arc::into_inner(client).expect("the client is not shared yet")
+        });
+        let queued_ops = |client: &RemoteTimelineClient| -> Vec<String> {
+            let mut guard = client.upload_queue.lock().unwrap();
+            let upload_queue = guard.initialized_mut().unwrap();
+            upload_queue
+                .queued_operations
+                .iter()
+                .map(|(op, ..)| op.to_string())
+                .collect()
+        };
+
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        client.init_upload_queue_for_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+
+        // The layer uploads in flight keep the index uploads queued.
+        let layer_file_name_1: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let layer_file_name_2: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D9-00000000016B5A52".parse().unwrap();
+        let content = dummy_contents("foo");
+        for layer_file_name in [&layer_file_name_1, &layer_file_name_2] {
+            std::fs::write(timeline_path.join(layer_file_name.file_name()), &content)?;
+        }
+
+        client.schedule_layer_file_upload(
+            &layer_file_name_1,
+            &LayerFileMetadata::new(content.len() as u64),
+        )?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x20)))?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x30)))?;
+        assert_eq!(queued_ops(&client), vec!["UploadMetadata(lsn: 0/30)"]);
+
+        runtime.block_on(client.wait_completion())?;
+        let index_part = match runtime.block_on(client.download_index_file())? {
+            MaybeDeletedIndexPart::IndexPart(index_part) => index_part,
+            MaybeDeletedIndexPart::Deleted(_) => panic!("unexpectedly got deleted index part"),
+        };
+        assert_eq!(index_part.disk_consistent_lsn, Lsn(0x30));
+        assert_file_list(
+            &index_part.timeline_layers,
+            &[&layer_file_name_1.file_name()],
+        );
+
+        // An index upload that someone waits for is not dropped.
+        client.schedule_layer_file_upload(
+            &layer_file_name_2,
+            &LayerFileMetadata::new(content.len() as u64),
+        )?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x40)))?;
+        let barrier = {
+            let mut guard = client.upload_queue.lock().unwrap();
+            client.schedule_barrier(guard.initialized_mut()?)
+        };
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x50)))?;
+        assert_eq!(
+            queued_ops(&client),
+            vec![
+                "UploadMetadata(lsn: 0/40)",
+                "Barrier",
+                "UploadMetadata(lsn: 0/50)"
+            ]
+        );
+        drop(barrier);
+        runtime.block_on(client.wait_completion())?;
+        Ok(())
+    }
+
+    #[test]
+    fn compacted_layer_upload_is_skipped() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir,
+            client,
+        } = TestSetup::new("compacted_layer_upload_is_skipped")?;
+        let conf: &'static PageServerConf = Box::leak(Box::new(PageServerConf {
+            remote_coalesce_index_uploads: true,
+            ..harness.conf.clone()
+        }));
+        let client = Arc::new(RemoteTimelineClient {
+            conf,
+            ..Arc::into_inner(client).expect("the client is not shared yet")
+        });
+        let queued_ops = |client: &RemoteTimelineClient| -> Vec<String> {
+            let mut guard = client.upload_queue.lock().unwrap();
+            let upload_queue = guard.initialized_mut().unwrap();
+            upload_queue
+                .queued_operations
+                .iter()
+                .map(|(op, ..)| op.to_string())
+                .collect()
+        };
+
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let remote_timeline_dir =
+            remote_fs_dir.join(timeline_path.strip_prefix(&harness.conf.workdir)?);
+        let layer_names: Vec<LayerFileName> = [
+            "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51",
+            "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D9-00000000016B5A52",
+            "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59DA-00000000016B5A53",
+            "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59DB-00000000016B5A54",
+            "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59DC-00000000016B5A55",
+        ]
+        .iter()
+        .map(|name| name.parse().unwrap())
+        .collect();
+        let [in_flight, flushed, compacted, kept, compacted_again] = &layer_names[..] else {
+            unreachable!()
+        };
+        let content = dummy_contents("foo");
+        let layer_metadata = LayerFileMetadata::new(content.len() as u64);
+        for layer_file_name in &layer_names {
+            std::fs::write(timeline_path.join(layer_file_name.file_name()), &content)?;
+        }
+        client.init_upload_queue_for_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+
+        // The upload in flight and the barrier behind it keep the flushed layer queued, until
+        // compaction replaces it. The index upload listing it is superseded then.
+        client.schedule_layer_file_upload(in_flight, &layer_metadata)?;
+        let barrier = {
+            let mut guard = client.upload_queue.lock().unwrap();
+            client.schedule_barrier(guard.initialized_mut()?)
+        };
+        client.schedule_layer_file_upload(flushed, &layer_metadata)?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x20)))?;
+        client.schedule_layer_file_upload(compacted, &layer_metadata)?;
+        client.schedule_compacted_layer_deletion(&[flushed.clone()], &[compacted.clone()])?;
+        assert_eq!(
+            queued_ops(&client),
+            vec![
+                "Barrier".to_string(),
+                format!(
+                    "UploadLayer({}, size={})",
+                    compacted.file_name(),
+                    content.len()
+                ),
+                "UploadMetadata(lsn: 0/20)".to_string(),
+                format!(
+                    "Delete(path: {}, scheduled_from_timeline_delete: false)",
+                    flushed.file_name()
+                ),
+            ]
+        );
+        drop(barrier);
+        runtime.block_on(client.wait_completion())?;
+        assert!(!remote_timeline_dir.join(flushed.file_name()).exists());
+        let index_part = match runtime.block_on(client.download_index_file())? {
+            MaybeDeletedIndexPart::IndexPart(index_part) => index_part,
+            MaybeDeletedIndexPart::Deleted(_) => panic!("unexpectedly got deleted index part"),
+        };
+        assert_file_list(
+            &index_part.timeline_layers,
+            &[&in_flight.file_name(), &compacted.file_name()],
+        );
+
+        // An index upload that is still to be performed keeps the upload of the layers it lists.
+        client.schedule_layer_file_upload(in_flight, &layer_metadata)?;
+        let barrier = {
+            let mut guard = client.upload_queue.lock().unwrap();
+            client.schedule_barrier(guard.initialized_mut()?)
+        };
+        client.schedule_layer_file_upload(kept, &layer_metadata)?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x30)))?;
+        let second_barrier = {
+            let mut guard = client.upload_queue.lock().unwrap();
+            client.schedule_barrier(guard.initialized_mut()?)
+        };
+        client.schedule_layer_file_upload(compacted_again, &layer_metadata)?;
+        client.schedule_compacted_layer_deletion(&[kept.clone()], &[compacted_again.clone()])?;
+        assert!(queued_ops(&client).contains(&format!(
+            "UploadLayer({}, size={})",
+            kept.file_name(),
+            content.len()
+        )));
+        drop(barrier);
+        drop(second_barrier);
+        runtime.block_on(client.wait_completion())?;
+        Ok(())
+    }
+
+    #[test]
+    fn pending_reconciliation_holds_the_queue() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir,
+            client,
+        } = TestSetup::new("pending_reconciliation_holds_the_queue")?;
+        let queued_ops = |client: &RemoteTimelineClient| -> Vec<String> {
+            let mut guard = client.upload_queue.lock().unwrap();
+            let upload_queue = guard.initialized_mut().unwrap();
+            assert!(upload_queue.inprogress_tasks.is_empty());
+            upload_queue
+                .queued_operations
+                .iter()
+                .map(|(op, ..)| op.to_string())
+                .collect()
+        };
+
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let remote_timeline_dir =
+            remote_fs_dir.join(timeline_path.strip_prefix(&harness.conf.workdir)?);
+        let layer_file_name_1: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let layer_file_name_2: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D9-00000000016B5A52".parse().unwrap();
+        let content = dummy_contents("foo");
+        let layer_metadata = LayerFileMetadata::new(content.len() as u64);
+        for layer_file_name in [&layer_file_name_1, &layer_file_name_2] {
+            std::fs::write(timeline_path.join(layer_file_name.file_name()), &content)?;
+        }
+
+        // Nothing is launched while the remote state is unknown.
+        client.init_upload_queue_pending_reconciliation(&dummy_metadata(Lsn(0x10)))?;
+        assert!(client.is_reconciliation_pending());
+        client.schedule_layer_file_upload(&layer_file_name_1, &layer_metadata)?;
+        client.schedule_index_upload_for_metadata_update(&dummy_metadata(Lsn(0x20)))?;
+        client.schedule_layer_file_deletion(&[layer_file_name_2.clone()])?;
+        assert_eq!(queued_ops(&client).len(), 4);
+        assert!(runtime.block_on(client.wait_completion()).is_err());
+        assert_eq!(
+            client.layers_deleted_pending_reconciliation()?,
+            HashSet::from([layer_file_name_2.clone()])
+        );
+
+        // No index part on the remote: the local layers are uploaded.
+        client.finish_pending_reconciliation(
+            None,
+            &[
+                (layer_file_name_1.clone(), layer_metadata.clone()),
+                (layer_file_name_2.clone(), layer_metadata.clone()),
+            ],
+            &[],
+            &dummy_metadata(Lsn(0x20)),
+        )?;
+        assert!(!client.is_reconciliation_pending());
+        assert!(client.layers_deleted_pending_reconciliation().is_err());
+        runtime.block_on(client.wait_completion())?;
+        let index_part = match runtime.block_on(client.download_index_file())? {
+            MaybeDeletedIndexPart::IndexPart(index_part) => index_part,
+            MaybeDeletedIndexPart::Deleted(_) => panic!("unexpectedly got deleted index part"),
+        };
+        assert_eq!(index_part.disk_consistent_lsn, Lsn(0x20));
+        assert_file_list(
+            &index_part.timeline_layers,
+            &[
+                &layer_file_name_1.file_name(),
+                &layer_file_name_2.file_name(),
+            ],
+        );
+
+        // The layers deleted while pending are deleted from the remote index part.
+        *client.upload_queue.lock().unwrap() = UploadQueue::Uninitialized;
+        client.init_upload_queue_pending_reconciliation(&dummy_metadata(Lsn(0x20)))?;
+        client.schedule_layer_file_deletion(&[layer_file_name_2.clone()])?;
+        client.finish_pending_reconciliation(
+            Some(&index_part),
+            &[],
+            &[layer_file_name_2.clone()],
+            &dummy_metadata(Lsn(0x20)),
+        )?;
+        runtime.block_on(client.wait_completion())?;
+        let index_part = match runtime.block_on(client.download_index_file())? {
+            MaybeDeletedIndexPart::IndexPart(index_part) => index_part,
+            MaybeDeletedIndexPart::Deleted(_) => panic!("unexpectedly got deleted index part"),
+        };
+        assert_file_list(
+            &index_part.timeline_layers,
+            &[&layer_file_name_1.file_name()],
+        );
+        assert!(!remote_timeline_dir
+            .join(layer_file_name_2.file_name())
+            .exists());
+        Ok(())
+    }
+
+    #[test]
+    fn upload_start_jitter() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir,
+            client,
+        } = TestSetup::new("upload_start_jitter")?;
+        // Way longer than the test runs, unless something cuts the delay short.
+        let client = Arc::new(RemoteTimelineClient {
+            upload_start_jitter: Duration::from_secs(24 * 60 * 60),
+            ..Arc::into_inner(client).expect("the client is not shared yet")
+        });
+
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let remote_timeline_dir =
+            remote_fs_dir.join(timeline_path.strip_prefix(&harness.conf.workdir)?);
+        client.init_upload_queue_for_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+
+        let layer_file_name: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let content = dummy_contents("foo");
+        std::fs::write(timeline_path.join(layer_file_name.file_name()), &content)?;
+        client.schedule_layer_file_upload(
+            &layer_file_name,
+            &LayerFileMetadata::new(content.len() as u64),
+        )?;
+
+        let remote_layer = remote_timeline_dir.join(layer_file_name.file_name());
+        runtime.block_on(tokio::time::sleep(Duration::from_millis(100)));
+        assert!(!remote_layer.exists(), "the upload started right away");
+        {
+            let mut guard = client.upload_queue.lock().unwrap();
+            let upload_queue = guard.initialized_mut()?;
+            assert_eq!(upload_queue.num_inprogress_layer_uploads, 1);
+        }
+
+        // Whoever waits for the upload doesn't wait for the jitter.
+        runtime.block_on(tokio::time::timeout(
+            Duration::from_secs(10),
+            client.wait_completion(),
+        ))??;
+        assert_eq!(std::fs::read(&remote_layer)?, content);
+        Ok(())
+    }
+
+    #[test]
+    fn cancelled_upload_leaves_no_remote_object() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir,
+            client,
+        } = TestSetup::with_storage_wrapper(
+            "cancelled_upload_leaves_no_remote_object",
+            |storage| GenericRemoteStorage::Custom(Arc::new(StuckUploads(storage))),
+        )?;
+
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let remote_timeline_dir =
+            remote_fs_dir.join(timeline_path.strip_prefix(&harness.conf.workdir)?);
+        client.init_upload_queue_for_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+
+        let layer_file_name: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let content = dummy_contents("foo");
+        std::fs::write(timeline_path.join(layer_file_name.file_name()), &content)?;
+        client.schedule_layer_file_upload(
+            &layer_file_name,
+            &LayerFileMetadata::new(content.len() as u64),
+        )?;
+        let metadata = dummy_metadata(Lsn(0x20));
+        client.schedule_index_upload_for_metadata_update(&metadata)?;
+
+        // Wait until the layer is partially written to the remote storage.
+        let partial_upload =
+            remote_timeline_dir.join(format!("{}.___temp", layer_file_name.file_name()));
+        runtime.block_on(async {
+            while !partial_upload.exists() {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        });
+
+        runtime.block_on(client.cancel_sync())?;
+
+        // Neither the layer nor the queued index upload made it to the remote storage.
+        assert_remote_files(&[], &remote_timeline_dir);
+        assert!(client
+            .schedule_layer_file_upload(
+                &layer_file_name,
+                &LayerFileMetadata::new(content.len() as u64),
+            )
+            .is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn bytes_unfinished_gauge_for_layer_file_uploads() -> anyhow::Result<()> {
+        // Setup
+
+        let TestSetup {
+            runtime,
+            harness,
+            client,
+            ..
+        } = TestSetup::new("metrics")?;
+
+        let metadata = dummy_metadata(Lsn(0x10));
+        client.init_upload_queue_for_empty_remote(&metadata)?;
+
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+
+        let layer_file_name_1: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let content_1 = dummy_contents("foo");
+        std::fs::write(
+            timeline_path.join(layer_file_name_1.file_name()),
+            &content_1,
+        )?;
+
+        #[derive(Debug, PartialEq)]
+        struct BytesStartedFinished {
+            started: Option<usize>,
+            finished: Option<usize>,
         }
         let get_bytes_started_stopped = || {
             let started = client
@@ -1721,4 +3999,249 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn resume_interrupted_layer_download() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir: _remote_fs_dir,
+            client,
+        } = TestSetup::new("resume_interrupted_layer_download").unwrap();
+
+        let layer_file_name: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let local_path = harness
+            .timeline_path(&TIMELINE_ID)
+            .join(layer_file_name.file_name());
+        let temp_path = utils::crashsafe::path_with_suffix_extension(
+            &local_path,
+            download::TEMP_DOWNLOAD_EXTENSION,
+        );
+        let remote_path = harness.conf.remote_path(&local_path)?;
+
+        let content: Vec<u8> = (0..10_000u32).flat_map(|i| i.to_le_bytes()).collect();
+        let layer_metadata = LayerFileMetadata::new(content.len() as u64);
+        let half = content.len() / 2;
+        let upload = |metadata| {
+            runtime.block_on(client.storage_impl.upload(
+                std::io::Cursor::new(content.clone()),
+                content.len(),
+                &remote_path,
+                metadata,
+            ))
+        };
+        let download = || {
+            runtime.block_on(
+                client
+                    .download_layer_file(&layer_file_name, &layer_metadata, SyncPriority::Low)
+                    .instrument(info_span!("download_layer", tenant_id = %harness.tenant_id, timeline_id = %TIMELINE_ID)),
+            )
+        };
+
+        // Without a checksum to verify, the part downloaded before is kept as is,
+        // which shows that only the rest of the layer was downloaded.
+        upload(None)?;
+        let marked_half = vec![0xFF; half];
+        std::fs::write(&temp_path, &marked_half)?;
+        assert_eq!(download()?, content.len() as u64);
+        let downloaded = std::fs::read(&local_path)?;
+        assert!(downloaded[..half] == marked_half[..]);
+        assert!(downloaded[half..] == content[half..]);
+        assert!(!temp_path.exists());
+
+        // A truncated download is completed into the correct layer
+        let checksum = checksum::checksum_bytes(ChecksumAlgorithm::Crc32c, &content).unwrap();
+        upload(Some(checksum.to_metadata()))?;
+        std::fs::remove_file(&local_path)?;
+        std::fs::write(&temp_path, &content[..half])?;
+        assert_eq!(download()?, content.len() as u64);
+        assert!(std::fs::read(&local_path)? == content);
+
+        // A corrupted part fails the checksum verification, and the layer is downloaded from scratch
+        std::fs::remove_file(&local_path)?;
+        std::fs::write(&temp_path, &marked_half)?;
+        assert_eq!(download()?, content.len() as u64);
+        assert!(std::fs::read(&local_path)? == content);
+
+        Ok(())
+    }
+
+    #[test]
+    fn small_layers_are_packed_into_an_archive() -> anyhow::Result<()> {
+        let TestSetup {
+            runtime,
+            entered_runtime: _entered_runtime,
+            harness,
+            tenant: _tenant,
+            tenant_ctx: _tenant_ctx,
+            remote_fs_dir,
+            client,
+        } = TestSetup::new("small_layers_are_packed_into_an_archive").unwrap();
+
+        let layer_1: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let layer_2: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D9-00000000016B5A52".parse().unwrap();
+        let timeline_path = harness.timeline_path(&TIMELINE_ID);
+        let content_1 = dummy_contents("foo");
+        let content_2 = dummy_contents("bar and some more");
+        std::fs::write(timeline_path.join(layer_1.file_name()), &content_1)?;
+        std::fs::write(timeline_path.join(layer_2.file_name()), &content_2)?;
+
+        let mut upload_queue = UploadQueue::Uninitialized;
+        let upload_queue = upload_queue.initialize_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+        for (name, content) in [(&layer_1, &content_1), (&layer_2, &content_2)] {
+            upload_queue
+                .latest_files
+                .insert(name.clone(), LayerFileMetadata::new(content.len() as u64));
+            upload_queue.unarchived_layers.push(name.clone());
+        }
+        let archive = upload_queue.pack_unarchived_layers().unwrap();
+        assert!(upload_queue.pack_unarchived_layers().is_none());
+        assert_eq!(archive.size(), (content_1.len() + content_2.len()) as u64);
+
+        // A layer the index part refers to is not zero-filled in the archive.
+        let layer_2_path = timeline_path.join(layer_2.file_name());
+        std::fs::rename(&layer_2_path, timeline_path.join("moved_away"))?;
+        assert!(runtime
+            .block_on(upload::upload_layer_archive(
+                harness.conf,
+                &client.storage_impl,
+                &timeline_path,
+                &archive,
+                |_| true,
+            ))
+            .is_err());
+        std::fs::rename(timeline_path.join("moved_away"), &layer_2_path)?;
+
+        let checksums = runtime.block_on(upload::upload_layer_archive(
+            harness.conf,
+            &client.storage_impl,
+            &timeline_path,
+            &archive,
+            |_| true,
+        ))?;
+        assert_eq!(checksums.len(), 2);
+        for (name, checksum) in checksums {
+            let metadata = upload_queue.latest_files.get_mut(&name).unwrap();
+            *metadata = metadata.clone().with_checksum(checksum);
+        }
+
+        let remote_timeline_dir =
+            remote_fs_dir.join(timeline_path.strip_prefix(&harness.conf.workdir)?);
+        assert_remote_files(&[&archive.name], &remote_timeline_dir);
+
+        // Each layer is downloaded back from its part of the archive.
+        for (name, content) in [(&layer_1, &content_1), (&layer_2, &content_2)] {
+            let local_path = timeline_path.join(name.file_name());
+            std::fs::remove_file(&local_path)?;
+            let metadata = upload_queue.latest_files[name].clone();
+            assert_eq!(metadata.archive().unwrap().name, archive.name);
+            let downloaded = runtime.block_on(
+                client
+                    .download_layer_file(name, &metadata, SyncPriority::Low)
+                    .instrument(info_span!("download_layer", tenant_id = %harness.tenant_id, timeline_id = %TIMELINE_ID)),
+            )?;
+            assert_eq!(downloaded, content.len() as u64);
+            assert!(std::fs::read(&local_path)? == *content);
+        }
+
+        // The archive goes away with the last of its layers.
+        assert!(upload_queue.remove_from_archive(&layer_1));
+        assert!(upload_queue.take_empty_archives().is_empty());
+        assert!(upload_queue.remove_from_archive(&layer_2));
+        assert!(!upload_queue.remove_from_archive(&layer_2));
+        assert_eq!(upload_queue.take_empty_archives(), vec![archive.name]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn superseded_layers_are_deleted_out_of_retention_window() -> anyhow::Result<()> {
+        let layer_1: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let layer_2: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D9-00000000016B5A52".parse().unwrap();
+        let layer_3: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59DA-00000000016B5A53".parse().unwrap();
+
+        let mut upload_queue = UploadQueue::Uninitialized;
+        let upload_queue = upload_queue.initialize_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+        upload_queue
+            .latest_files
+            .insert(layer_3.clone(), LayerFileMetadata::new(1));
+        upload_queue
+            .superseded_layers
+            .insert(layer_1.clone(), Lsn(0x10));
+        upload_queue
+            .superseded_layers
+            .insert(layer_2.clone(), Lsn(0x20));
+        // used by the timeline again, never deleted
+        upload_queue
+            .superseded_layers
+            .insert(layer_3.clone(), Lsn(0x10));
+
+        assert!(upload_queue
+            .advance_retention_window(Lsn(0x10), 2)
+            .is_empty());
+        assert!(upload_queue
+            .advance_retention_window(Lsn(0x20), 2)
+            .is_empty());
+        // an index upload without the LSN change doesn't move the window
+        assert!(upload_queue
+            .advance_retention_window(Lsn(0x20), 2)
+            .is_empty());
+        assert_eq!(upload_queue.superseded_layers.len(), 2);
+
+        assert_eq!(
+            upload_queue.advance_retention_window(Lsn(0x30), 2),
+            vec![layer_1]
+        );
+        assert_eq!(
+            upload_queue.retained_lsns,
+            VecDeque::from([Lsn(0x20), Lsn(0x30)])
+        );
+
+        // disabling the retention releases everything kept
+        assert_eq!(
+            upload_queue.advance_retention_window(Lsn(0x30), 0),
+            vec![layer_2]
+        );
+        assert!(upload_queue.superseded_layers.is_empty());
+        assert!(upload_queue.retained_lsns.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn index_generations_are_dropped_oldest_first() -> anyhow::Result<()> {
+        let mut upload_queue = UploadQueue::Uninitialized;
+        let upload_queue = upload_queue.initialize_empty_remote(&dummy_metadata(Lsn(0x10)))?;
+
+        assert!(upload_queue
+            .advance_index_generations(Lsn(0x10), 2)
+            .is_empty());
+        assert!(upload_queue
+            .advance_index_generations(Lsn(0x20), 2)
+            .is_empty());
+        // the same LSN uploaded again replaces its generation
+        assert!(upload_queue
+            .advance_index_generations(Lsn(0x20), 2)
+            .is_empty());
+        assert_eq!(
+            upload_queue.advance_index_generations(Lsn(0x30), 2),
+            vec![Lsn(0x10)]
+        );
+        assert_eq!(
+            upload_queue.index_generations,
+            VecDeque::from([Lsn(0x20), Lsn(0x30)])
+        );
+
+        // disabling the generations drops all of them
+        assert_eq!(
+            upload_queue.advance_index_generations(Lsn(0x40), 0),
+            vec![Lsn(0x20), Lsn(0x30)]
+        );
+        assert!(upload_queue.index_generations.is_empty());
+
+        Ok(())
+    }
 }
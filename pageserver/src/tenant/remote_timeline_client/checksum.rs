@@ -0,0 +1,445 @@
+//! Checksums of the files uploaded to the remote storage.
+//!
+//! The checksum is computed before the upload and stored in the object's [`StorageMetadata`],
+//! so that the downloads can detect truncated or otherwise corrupted objects before the
+//! data is used. The algorithm is chosen with the `remote_checksum_algorithm` pageserver
+//! config option, the downloads verify whichever checksum the object was uploaded with.
+//! Objects uploaded without a checksum are not verified.
+//!
+//! The large layers may also be uploaded with the checksums of their fixed size chunks, see
+//! [`ChunkChecksums`], so that a byte range of the layer can be verified on its own.
+
+use std::fmt;
+use std::str::FromStr;
+
+use remote_storage::{RemotePath, StorageMetadata};
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use sha2::Digest;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt};
+use utils::crashsafe::path_with_suffix_extension;
+
+/// Algorithm used to checksum the uploaded files.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum ChecksumAlgorithm {
+    /// Upload the files without checksums.
+    None,
+    Crc32c,
+    Sha256,
+}
+
+impl ChecksumAlgorithm {
+    /// Azure only accepts metadata names that are C# identifiers, hence the underscores.
+    fn metadata_key(&self) -> Option<&'static str> {
+        match self {
+            ChecksumAlgorithm::None => None,
+            ChecksumAlgorithm::Crc32c => Some("checksum_crc32c"),
+            ChecksumAlgorithm::Sha256 => Some("checksum_sha256"),
+        }
+    }
+
+    /// The key the objects uploaded by the older pageservers have their checksums under.
+    fn legacy_metadata_key(&self) -> Option<&'static str> {
+        match self {
+            ChecksumAlgorithm::None => None,
+            ChecksumAlgorithm::Crc32c => Some("checksum-crc32c"),
+            ChecksumAlgorithm::Sha256 => Some("checksum-sha256"),
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgorithm {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(ChecksumAlgorithm::None),
+            "crc32c" => Ok(ChecksumAlgorithm::Crc32c),
+            "sha256" => Ok(ChecksumAlgorithm::Sha256),
+            _ => anyhow::bail!(
+                "unknown checksum algorithm '{s}', expected one of: none, crc32c, sha256"
+            ),
+        }
+    }
+}
+
+impl fmt::Display for ChecksumAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ChecksumAlgorithm::None => "none",
+            ChecksumAlgorithm::Crc32c => "crc32c",
+            ChecksumAlgorithm::Sha256 => "sha256",
+        })
+    }
+}
+
+/// A hex-encoded checksum of a file, along with the algorithm it was computed with.
+///
+/// Written out as `<algorithm>:<value>`, e.g. in the index part and the backup manifest.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) struct Checksum {
+    algorithm: ChecksumAlgorithm,
+    value: String,
+}
+
+impl Checksum {
+    /// Finds the checksum the object was uploaded with, if any.
+    pub(super) fn from_metadata(metadata: Option<&StorageMetadata>) -> Option<Self> {
+        let metadata = metadata?;
+        [ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::Sha256]
+            .into_iter()
+            .find_map(|algorithm| {
+                let value = metadata
+                    .get(algorithm.metadata_key()?)
+                    .or_else(|| metadata.get(algorithm.legacy_metadata_key()?))?;
+                Some(Checksum {
+                    algorithm,
+                    value: value.to_owned(),
+                })
+            })
+    }
+
+    pub(super) fn to_metadata(&self) -> StorageMetadata {
+        let key = self
+            .algorithm
+            .metadata_key()
+            .expect("checksums are never computed with ChecksumAlgorithm::None");
+        StorageMetadata::from([(key, self.value.as_str())])
+    }
+
+    pub(super) fn algorithm(&self) -> ChecksumAlgorithm {
+        self.algorithm
+    }
+
+    pub(super) fn value(&self) -> &str {
+        &self.value
+    }
+
+    /// Checks that the downloaded data matches the checksum it was uploaded with.
+    pub(super) fn verify(&self, actual: &Checksum) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self == actual,
+            "{} checksum mismatch: expected {}, got {}",
+            self.algorithm,
+            self.value,
+            actual.value
+        );
+        Ok(())
+    }
+}
+
+impl fmt::Display for Checksum {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.algorithm, self.value)
+    }
+}
+
+impl FromStr for Checksum {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (algorithm, value) = s
+            .split_once(':')
+            .ok_or_else(|| anyhow::anyhow!("checksum '{s}' has no algorithm"))?;
+        let algorithm = algorithm.parse()?;
+        anyhow::ensure!(
+            algorithm != ChecksumAlgorithm::None,
+            "checksum '{s}' has no algorithm"
+        );
+        Ok(Checksum {
+            algorithm,
+            value: value.to_owned(),
+        })
+    }
+}
+
+pub(super) enum Hasher {
+    Crc32c(u32),
+    Sha256(sha2::Sha256),
+}
+
+impl Hasher {
+    pub(super) fn new(algorithm: ChecksumAlgorithm) -> Option<Self> {
+        match algorithm {
+            ChecksumAlgorithm::None => None,
+            ChecksumAlgorithm::Crc32c => Some(Hasher::Crc32c(0)),
+            ChecksumAlgorithm::Sha256 => Some(Hasher::Sha256(sha2::Sha256::new())),
+        }
+    }
+
+    pub(super) fn update(&mut self, data: &[u8]) {
+        match self {
+            Hasher::Crc32c(crc) => *crc = crc32c::crc32c_append(*crc, data),
+            Hasher::Sha256(hasher) => hasher.update(data),
+        }
+    }
+
+    pub(super) fn finish(self) -> Checksum {
+        match self {
+            Hasher::Crc32c(crc) => Checksum {
+                algorithm: ChecksumAlgorithm::Crc32c,
+                value: hex::encode(crc.to_be_bytes()),
+            },
+            Hasher::Sha256(hasher) => Checksum {
+                algorithm: ChecksumAlgorithm::Sha256,
+                value: hex::encode(hasher.finalize()),
+            },
+        }
+    }
+}
+
+pub(super) fn checksum_bytes(algorithm: ChecksumAlgorithm, data: &[u8]) -> Option<Checksum> {
+    let mut hasher = Hasher::new(algorithm)?;
+    hasher.update(data);
+    Some(hasher.finish())
+}
+
+/// Reads the whole file to compute its checksum, then rewinds it to the start for the upload.
+pub(super) async fn checksum_file(
+    algorithm: ChecksumAlgorithm,
+    file: &mut tokio::fs::File,
+) -> std::io::Result<Option<Checksum>> {
+    let Some(mut hasher) = Hasher::new(algorithm) else {
+        return Ok(None);
+    };
+    copy_with_hasher(file, &mut tokio::io::sink(), Some(&mut hasher)).await?;
+    file.rewind().await?;
+    Ok(Some(hasher.finish()))
+}
+
+/// Same as [`tokio::io::copy`], but also feeds the copied data into the `hasher`, if given.
+pub(super) async fn copy_with_hasher<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    mut hasher: Option<&mut Hasher>,
+) -> std::io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = reader.read(&mut buf).await?;
+        if n == 0 {
+            break;
+        }
+        if let Some(hasher) = hasher.as_deref_mut() {
+            hasher.update(&buf[..n]);
+        }
+        writer.write_all(&buf[..n]).await?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+/// The checksums of the chunks of a file, all of `chunk_size` bytes but the last one, stored
+/// as an object of its own next to the file's one. The metadata of an object can't hold that
+/// many values.
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct ChunkChecksums {
+    #[serde_as(as = "DisplayFromStr")]
+    algorithm: ChecksumAlgorithm,
+    chunk_size: u64,
+    file_size: u64,
+    /// Hex-encoded, same as [`Checksum::value`].
+    checksums: Vec<String>,
+}
+
+impl ChunkChecksums {
+    /// The object with the chunk checksums of the object at `path`.
+    pub(super) fn remote_path(path: &RemotePath) -> RemotePath {
+        RemotePath::new(&path_with_suffix_extension(path.get_path(), "chunks"))
+            .expect("a path with another extension is still relative")
+    }
+
+    /// Reads the whole file to checksum its chunks, then rewinds it to the start for the upload.
+    pub(super) async fn of_file(
+        algorithm: ChecksumAlgorithm,
+        chunk_size: u64,
+        file: &mut tokio::fs::File,
+    ) -> std::io::Result<Option<Self>> {
+        if Hasher::new(algorithm).is_none() {
+            return Ok(None);
+        }
+        let mut chunk_checksums = ChunkChecksums {
+            algorithm,
+            chunk_size,
+            file_size: 0,
+            checksums: Vec::new(),
+        };
+        loop {
+            let mut hasher = Hasher::new(algorithm).expect("checked above");
+            let read = copy_with_hasher(
+                &mut (&mut *file).take(chunk_size),
+                &mut tokio::io::sink(),
+                Some(&mut hasher),
+            )
+            .await?;
+            if read == 0 {
+                break;
+            }
+            chunk_checksums.file_size += read;
+            chunk_checksums.checksums.push(hasher.finish().value);
+        }
+        file.rewind().await?;
+        Ok(Some(chunk_checksums))
+    }
+
+    pub(super) fn algorithm(&self) -> ChecksumAlgorithm {
+        self.algorithm
+    }
+
+    pub(super) fn chunk_size(&self) -> u64 {
+        self.chunk_size
+    }
+
+    pub(super) fn file_size(&self) -> u64 {
+        self.file_size
+    }
+
+    /// The chunk that starts at `offset`, as its index and end offset.
+    pub(super) fn chunk_at(&self, offset: u64) -> Option<(usize, u64)> {
+        if offset % self.chunk_size != 0 || offset >= self.file_size {
+            return None;
+        }
+        let index = (offset / self.chunk_size) as usize;
+        Some((index, (offset + self.chunk_size).min(self.file_size)))
+    }
+
+    pub(super) fn hasher(&self) -> Hasher {
+        Hasher::new(self.algorithm)
+            .expect("chunk checksums are never computed without an algorithm")
+    }
+
+    /// Checks that the downloaded chunk matches the checksum it was uploaded with.
+    pub(super) fn verify(&self, index: usize, actual: &Checksum) -> anyhow::Result<()> {
+        let expected = Checksum {
+            algorithm: self.algorithm,
+            value: self.checksums.get(index).cloned().unwrap_or_default(),
+        };
+        expected
+            .verify(actual)
+            .map_err(|e| e.context(format!("chunk {index} of {}", self.checksums.len())))
+    }
+
+    /// Reads the first `size` bytes of the file, and returns the size of its longest prefix of
+    /// whole chunks that match their checksums.
+    pub(super) async fn verified_prefix(
+        &self,
+        file: &mut tokio::fs::File,
+        size: u64,
+    ) -> std::io::Result<u64> {
+        file.rewind().await?;
+        let mut verified = 0;
+        while let Some((index, end)) = self.chunk_at(verified).filter(|(_, end)| *end <= size) {
+            let mut hasher = self.hasher();
+            copy_with_hasher(
+                &mut (&mut *file).take(end - verified),
+                &mut tokio::io::sink(),
+                Some(&mut hasher),
+            )
+            .await?;
+            if self.verify(index, &hasher.finish()).is_err() {
+                break;
+            }
+            verified = end;
+        }
+        Ok(verified)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn checksum_roundtrips_through_metadata() {
+        for algorithm in [ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::Sha256] {
+            let checksum = checksum_bytes(algorithm, b"some layer data").unwrap();
+            let metadata = checksum.to_metadata();
+            assert_eq!(Checksum::from_metadata(Some(&metadata)), Some(checksum));
+        }
+        assert_eq!(checksum_bytes(ChecksumAlgorithm::None, b"data"), None);
+        assert_eq!(Checksum::from_metadata(None), None);
+    }
+
+    #[test]
+    fn checksum_from_legacy_metadata() {
+        for algorithm in [ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::Sha256] {
+            let checksum = checksum_bytes(algorithm, b"some layer data").unwrap();
+            let metadata = StorageMetadata::from([(
+                algorithm.legacy_metadata_key().unwrap(),
+                checksum.value(),
+            )]);
+            assert_eq!(Checksum::from_metadata(Some(&metadata)), Some(checksum));
+        }
+    }
+
+    #[test]
+    fn checksum_roundtrips_through_string() {
+        for algorithm in [ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::Sha256] {
+            let checksum = checksum_bytes(algorithm, b"some layer data").unwrap();
+            assert_eq!(checksum.to_string().parse::<Checksum>().unwrap(), checksum);
+        }
+        assert!("0badc0de".parse::<Checksum>().is_err());
+        assert!("none:0badc0de".parse::<Checksum>().is_err());
+    }
+
+    #[test]
+    fn truncated_data_fails_verification() {
+        let data = b"some layer data";
+        for algorithm in [ChecksumAlgorithm::Crc32c, ChecksumAlgorithm::Sha256] {
+            let expected = checksum_bytes(algorithm, data).unwrap();
+            expected
+                .verify(&checksum_bytes(algorithm, data).unwrap())
+                .expect("same data should pass");
+            expected
+                .verify(&checksum_bytes(algorithm, &data[..data.len() - 1]).unwrap())
+                .expect_err("truncated data should fail");
+        }
+    }
+
+    #[tokio::test]
+    async fn chunk_checksums_verify_whole_chunks() -> anyhow::Result<()> {
+        let temp_dir = tempfile::tempdir()?;
+        let path = temp_dir.path().join("layer");
+        std::fs::write(&path, b"0123456789")?;
+        let mut file = tokio::fs::File::open(&path).await?;
+        let chunk_checksums = ChunkChecksums::of_file(ChecksumAlgorithm::Crc32c, 4, &mut file)
+            .await?
+            .unwrap();
+        assert_eq!(chunk_checksums.file_size(), 10);
+        assert_eq!(chunk_checksums.checksums.len(), 3);
+        assert_eq!(chunk_checksums.chunk_at(8), Some((2, 10)));
+        assert_eq!(chunk_checksums.chunk_at(5), None);
+        // Rewound for the upload.
+        let mut contents = Vec::new();
+        file.read_to_end(&mut contents).await?;
+        assert_eq!(contents, b"0123456789");
+
+        let serialized = serde_json::to_vec(&chunk_checksums)?;
+        assert_eq!(
+            serde_json::from_slice::<ChunkChecksums>(&serialized)?,
+            chunk_checksums
+        );
+
+        assert_eq!(chunk_checksums.verified_prefix(&mut file, 10).await?, 10);
+        // The last chunk is not whole.
+        assert_eq!(chunk_checksums.verified_prefix(&mut file, 9).await?, 8);
+        std::fs::write(&path, b"0123X56789")?;
+        let mut file = tokio::fs::File::open(&path).await?;
+        assert_eq!(chunk_checksums.verified_prefix(&mut file, 10).await?, 4);
+
+        assert_eq!(
+            ChunkChecksums::of_file(ChecksumAlgorithm::None, 4, &mut file).await?,
+            None
+        );
+        assert_eq!(
+            ChunkChecksums::remote_path(&RemotePath::from_string("tenants/t/timelines/t/layer")?),
+            RemotePath::from_string("tenants/t/timelines/t/layer.chunks")?
+        );
+        Ok(())
+    }
+}
@@ -0,0 +1,253 @@
+//! Deduplication of the layer uploads by their contents, enabled with the `remote_upload_dedup`
+//! pageserver config option.
+//!
+//! Every uploaded layer is recorded in an index from the SHA-256 of its contents to its remote
+//! path. A layer with the same contents as one uploaded before, e.g. regenerated verbatim or
+//! uploaded by another branch, is copied from that path within the remote storage instead of
+//! being uploaded again.
+//!
+//! The index is a log in the workdir, so it survives restarts, compacted when it's loaded.
+//! It is only a hint: a copy from a path that was deleted in the meantime fails, and the layer
+//! is uploaded as usual. Nothing is fsynced either, a lost entry just costs an upload.
+//! The paths are forgotten as their objects are deleted: the layers one by one, the timelines
+//! and the tenants by their prefix.
+//! The paths are the ones of the primary remote storage: the uploads to and deletions from the
+//! replicas of a `remote_storage` list leave the index alone.
+
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context;
+use once_cell::sync::Lazy;
+use remote_storage::RemotePath;
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncWriteExt;
+use tokio::sync::Mutex;
+use tracing::warn;
+use utils::crashsafe::path_with_suffix_extension;
+
+use crate::config::PageServerConf;
+
+const INDEX_FILE_NAME: &str = "upload_dedup_index.jsonl";
+
+/// The indexes by workdir. There's one per pageserver, except in the tests.
+static INDEXES: Lazy<Mutex<HashMap<PathBuf, Arc<UploadDedupIndex>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// A line of the index file: a layer uploaded with the given contents hash, or, without the
+/// hash, a layer deleted from the remote storage.
+#[derive(Serialize, Deserialize)]
+struct IndexEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    hash: Option<String>,
+    path: String,
+}
+
+pub(crate) struct UploadDedupIndex {
+    file_path: PathBuf,
+    /// Remote path of an uploaded layer, by its contents hash. Held while the index file is
+    /// appended to, so that the file has the changes in the same order.
+    by_hash: Mutex<HashMap<String, String>>,
+}
+
+impl UploadDedupIndex {
+    pub(crate) async fn get(conf: &PageServerConf) -> anyhow::Result<Arc<Self>> {
+        let mut indexes = INDEXES.lock().await;
+        if let Some(index) = indexes.get(&conf.workdir) {
+            return Ok(Arc::clone(index));
+        }
+        let file_path = conf.workdir.join(INDEX_FILE_NAME);
+        let index = Arc::new(
+            tokio::task::spawn_blocking(move || Self::load(file_path))
+                .await
+                .context("load the upload dedup index")?,
+        );
+        indexes.insert(conf.workdir.clone(), Arc::clone(&index));
+        Ok(index)
+    }
+
+    fn load(file_path: PathBuf) -> Self {
+        let mut by_hash = HashMap::new();
+        match std::fs::read_to_string(&file_path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    match serde_json::from_str::<IndexEntry>(line) {
+                        Ok(IndexEntry {
+                            hash: Some(hash),
+                            path,
+                        }) => {
+                            by_hash.insert(hash, path);
+                        }
+                        Ok(IndexEntry { hash: None, path }) => {
+                            by_hash.retain(|_, uploaded| *uploaded != path);
+                        }
+                        // Likely the last line, torn by a crash.
+                        Err(e) => warn!("skipping a malformed line of {file_path:?}: {e}"),
+                    }
+                }
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            Err(e) => {
+                warn!("failed to read the upload dedup index {file_path:?}, starting over: {e}")
+            }
+        }
+
+        if let Err(e) = compact(&file_path, &by_hash) {
+            warn!("failed to compact the upload dedup index {file_path:?}: {e:#}");
+        }
+        Self {
+            file_path,
+            by_hash: Mutex::new(by_hash),
+        }
+    }
+
+    /// Remote path of a layer uploaded with the same contents, if any.
+    pub(super) async fn find(&self, hash: &str) -> Option<RemotePath> {
+        let by_hash = self.by_hash.lock().await;
+        let path = by_hash.get(hash)?;
+        RemotePath::from_string(path).ok()
+    }
+
+    pub(super) async fn record(&self, hash: &str, path: &RemotePath) {
+        let path = path.to_string();
+        let mut by_hash = self.by_hash.lock().await;
+        if by_hash.get(hash) == Some(&path) {
+            return;
+        }
+        by_hash.insert(hash.to_owned(), path.clone());
+        self.append(vec![IndexEntry {
+            hash: Some(hash.to_owned()),
+            path,
+        }])
+        .await;
+    }
+
+    /// Stops copying from a path, once the layer there is deleted.
+    pub(super) async fn forget(&self, path: &RemotePath) {
+        let path = path.to_string();
+        let mut by_hash = self.by_hash.lock().await;
+        let len_before = by_hash.len();
+        by_hash.retain(|_, uploaded| *uploaded != path);
+        if by_hash.len() != len_before {
+            self.append(vec![IndexEntry { hash: None, path }]).await;
+        }
+    }
+
+    /// Stops copying from every path under `prefix`, e.g. the objects of a deleted timeline or
+    /// tenant, or the ones of a timeline that were found missing or corrupt there.
+    pub(crate) async fn forget_prefix(&self, prefix: &RemotePath) {
+        let prefix = format!("{prefix}/");
+        let mut by_hash = self.by_hash.lock().await;
+        let mut forgotten = Vec::new();
+        by_hash.retain(|_, uploaded| {
+            let under_prefix = uploaded.starts_with(&prefix);
+            if under_prefix {
+                forgotten.push(IndexEntry {
+                    hash: None,
+                    path: uploaded.clone(),
+                });
+            }
+            !under_prefix
+        });
+        if !forgotten.is_empty() {
+            self.append(forgotten).await;
+        }
+    }
+
+    async fn append(&self, entries: Vec<IndexEntry>) {
+        let append = async {
+            let mut lines = String::new();
+            for entry in entries {
+                lines.push_str(&serde_json::to_string(&entry)?);
+                lines.push('\n');
+            }
+            let mut file = tokio::fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.file_path)
+                .await?;
+            file.write_all(lines.as_bytes()).await?;
+            // A tokio file finishes the write in the background unless flushed, the next
+            // append or a reload could miss it.
+            file.flush().await?;
+            anyhow::Ok(())
+        };
+        if let Err(e) = append.await {
+            warn!(
+                "failed to update the upload dedup index {:?}: {e:#}",
+                self.file_path
+            );
+        }
+    }
+}
+
+/// Rewrites the index file with just the current entries.
+fn compact(file_path: &Path, by_hash: &HashMap<String, String>) -> anyhow::Result<()> {
+    let mut contents = String::new();
+    for (hash, path) in by_hash {
+        contents.push_str(&serde_json::to_string(&IndexEntry {
+            hash: Some(hash.clone()),
+            path: path.clone(),
+        })?);
+        contents.push('\n');
+    }
+    let temp_path = path_with_suffix_extension(file_path, "temp");
+    std::fs::write(&temp_path, contents).context("write the compacted index")?;
+    std::fs::rename(&temp_path, file_path).context("replace the index")?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn index_survives_reload() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join(INDEX_FILE_NAME);
+        let layer_a = RemotePath::from_string("tenants/a/timelines/b/layer_a")?;
+        let layer_b = RemotePath::from_string("tenants/a/timelines/c/layer_b")?;
+
+        let index = UploadDedupIndex::load(file_path.clone());
+        index.record("hash_a", &layer_a).await;
+        index.record("hash_b", &layer_b).await;
+        index.forget(&layer_a).await;
+        // A torn write at the end of the log is skipped.
+        std::fs::OpenOptions::new()
+            .append(true)
+            .open(&file_path)?
+            .write_all(b"{\"hash\":\"hash_c\"")?;
+
+        let reloaded = UploadDedupIndex::load(file_path.clone());
+        assert_eq!(reloaded.find("hash_a").await, None);
+        assert_eq!(reloaded.find("hash_b").await, Some(layer_b));
+        assert_eq!(std::fs::read_to_string(&file_path)?.lines().count(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn prefix_is_forgotten() -> anyhow::Result<()> {
+        let dir = tempfile::tempdir()?;
+        let file_path = dir.path().join(INDEX_FILE_NAME);
+        let layer_a = RemotePath::from_string("tenants/a/timelines/b/layer_a")?;
+        let layer_b = RemotePath::from_string("tenants/a/timelines/bc/layer_b")?;
+
+        let index = UploadDedupIndex::load(file_path.clone());
+        index.record("hash_a", &layer_a).await;
+        index.record("hash_b", &layer_b).await;
+        index
+            .forget_prefix(&RemotePath::from_string("tenants/a/timelines/b")?)
+            .await;
+        assert_eq!(index.find("hash_a").await, None);
+        assert_eq!(index.find("hash_b").await, Some(layer_b.clone()));
+
+        let reloaded = UploadDedupIndex::load(file_path);
+        assert_eq!(reloaded.find("hash_a").await, None);
+        assert_eq!(reloaded.find("hash_b").await, Some(layer_b));
+        Ok(())
+    }
+}
@@ -1,16 +1,24 @@
 //! Helper functions to delete files from remote storage with a RemoteStorage
 use anyhow::Context;
 use std::path::Path;
-use tracing::debug;
+use tracing::{debug, instrument};
 
 use remote_storage::GenericRemoteStorage;
 
 use crate::config::PageServerConf;
+use crate::tenant::upload_queue::DeletedLayerParts;
 
+use super::checksum::ChunkChecksums;
+use super::dedup::UploadDedupIndex;
+use super::parts::LayerParts;
+
+#[instrument(skip_all, fields(layer = %local_layer_path.display()))]
 pub(super) async fn delete_layer<'a>(
     conf: &'static PageServerConf,
     storage: &'a GenericRemoteStorage,
     local_layer_path: &'a Path,
+    parts: DeletedLayerParts,
+    from_primary: bool,
 ) -> anyhow::Result<()> {
     fail::fail_point!("before-delete-layer", |_| {
         anyhow::bail!("failpoint before-delete-layer")
@@ -22,8 +30,39 @@ pub(super) async fn delete_layer<'a>(
     // We don't want to print an error if the delete failed if the file has
     // already been deleted. Thankfully, in this situation S3 already
     // does not yield an error. While OS-provided local file system APIs do yield
-    // errors, we avoid them in the `LocalFs` wrapper.
-    storage.delete(&path_to_delete).await.with_context(|| {
+    // errors, we avoid them in the `LocalFs` wrapper. The same goes for the chunk checksums,
+    // which only the large layers have.
+    let chunk_checksums_path = ChunkChecksums::remote_path(&path_to_delete);
+    let parts = match parts {
+        DeletedLayerParts::None => None,
+        DeletedLayerParts::Known(parts) => Some(parts),
+        // Nothing is split for the storages without a limit.
+        DeletedLayerParts::Unknown if storage.max_object_size().is_none() => None,
+        DeletedLayerParts::Unknown => LayerParts::download_manifest(storage, &path_to_delete)
+            .await
+            .with_context(|| format!("Failed to find the parts of layer {path_to_delete:?}"))?,
+    };
+    let mut paths = vec![path_to_delete.clone(), chunk_checksums_path];
+    if let Some(parts) = &parts {
+        paths.extend(parts.part_paths(&path_to_delete));
+    }
+    storage.delete_objects(&paths).await.with_context(|| {
         format!("Failed to delete remote layer from storage at {path_to_delete:?}")
-    })
+    })?;
+    // After the parts, so that a retry of a failed deletion still finds them.
+    if parts.is_some() {
+        let manifest_path = LayerParts::manifest_path(&path_to_delete);
+        storage
+            .delete(&manifest_path)
+            .await
+            .with_context(|| format!("Failed to delete the manifest {manifest_path:?}"))?;
+    }
+    // The index has the paths of the primary storage only.
+    if conf.remote_upload_dedup && from_primary {
+        UploadDedupIndex::get(conf)
+            .await?
+            .forget(&path_to_delete)
+            .await;
+    }
+    Ok(())
 }
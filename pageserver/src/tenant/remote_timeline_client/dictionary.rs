@@ -0,0 +1,286 @@
+//! Zstd dictionaries for the small layers of a tenant, which compress poorly on their own.
+//!
+//! With `remote_zstd_dictionary_max_file_size` set, and the remote storage compressing with
+//! zstd, the layers of at most that size are compressed with the tenant's [`ZstdDictionary`].
+//! Until the tenant has one, its small layers are compressed as usual and kept as samples.
+//! Once [`TRAINING_SAMPLES`] of them are there, a dictionary is trained on them and uploaded
+//! into the tenant's [`DICTIONARIES_DIR`], the uploads that follow use it.
+//!
+//! The dictionary objects are never overwritten: each is named by the time it was trained and
+//! the version of the dictionaries, `<unix millis>.v1.zdict`, and stays for the layers
+//! compressed with it. A pageserver that attaches the tenant carries on with the newest one.
+//! Each layer records the name of its dictionary in its metadata, the downloads load that
+//! dictionary from the storage they download the layer from: the replicas get a copy of it
+//! before the layers and index parts that need it. The dictionaries are cached for as long as
+//! the tenant is loaded, and listed in the backup manifests of its timelines.
+
+use std::collections::{BTreeSet, HashMap};
+use std::path::Path;
+use std::sync::{Arc, Mutex, Weak};
+
+use anyhow::Context;
+use chrono::Utc;
+use once_cell::sync::Lazy;
+use remote_storage::{
+    Compression, GenericRemoteStorage, RemotePath, RemoteStorageError, StorageMetadata,
+    ZstdDictionary,
+};
+use tokio::io::AsyncReadExt;
+use tracing::{info, warn};
+use utils::id::TenantId;
+
+use crate::config::PageServerConf;
+
+/// Directory of the dictionary objects, in the tenant's directory of the remote storage.
+pub(super) const DICTIONARIES_DIR: &str = "zstd_dictionaries";
+
+/// Version of the dictionaries in the object names, for a later change of the format.
+const DICTIONARY_VERSION: &str = "v1";
+
+/// Number of small layers to train a dictionary on.
+const TRAINING_SAMPLES: usize = 64;
+
+/// The samples are truncated to this size: the dictionary is built from the common parts of
+/// the files, which are all over them, and the training time grows with the samples.
+const MAX_SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Upper limit of the dictionary size, the size zstd's own CLI trains by default.
+const MAX_DICTIONARY_SIZE: usize = 112_640;
+
+static TENANT_DICTIONARIES: Lazy<Mutex<HashMap<TenantId, Weak<TenantDictionaries>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// The dictionaries shared by all the timelines of a tenant, see the module docs.
+pub(crate) struct TenantDictionaries {
+    conf: &'static PageServerConf,
+    tenant_id: TenantId,
+    /// The primary remote storage, where the dictionaries are uploaded to.
+    storage: GenericRemoteStorage,
+    /// The newest dictionary in the remote storage when the first upload looked.
+    newest: tokio::sync::OnceCell<Option<ZstdDictionary>>,
+    /// Only locked to look up and update, never across the remote operations or the training,
+    /// which would hold up all the uploads and downloads of the tenant.
+    state: Mutex<DictionariesState>,
+    /// Names of the tenant's dictionaries known so far, for the manifests.
+    names: Mutex<BTreeSet<String>>,
+}
+
+#[derive(Default)]
+struct DictionariesState {
+    /// The dictionary trained for the new uploads, if the remote storage had none.
+    trained: Option<ZstdDictionary>,
+    samples: Vec<Vec<u8>>,
+    /// The dictionaries loaded for the downloads, by name.
+    loaded: HashMap<String, ZstdDictionary>,
+}
+
+impl TenantDictionaries {
+    /// The tenant's dictionaries, as long as any of its timelines is alive.
+    pub(crate) fn for_tenant(
+        conf: &'static PageServerConf,
+        storage: &GenericRemoteStorage,
+        tenant_id: TenantId,
+    ) -> Arc<Self> {
+        let mut tenants = TENANT_DICTIONARIES.lock().unwrap();
+        if let Some(dictionaries) = tenants.get(&tenant_id).and_then(Weak::upgrade) {
+            return dictionaries;
+        }
+        tenants.retain(|_, dictionaries| dictionaries.strong_count() > 0);
+        let dictionaries = Arc::new(TenantDictionaries {
+            conf,
+            tenant_id,
+            storage: storage.clone(),
+            newest: tokio::sync::OnceCell::new(),
+            state: Mutex::new(DictionariesState::default()),
+            names: Mutex::new(BTreeSet::new()),
+        });
+        tenants.insert(tenant_id, Arc::downgrade(&dictionaries));
+        dictionaries
+    }
+
+    /// Whether a layer of `file_size` bytes, uploaded with `compression`, is compressed with a
+    /// dictionary.
+    pub(super) fn applies_to(
+        conf: &PageServerConf,
+        compression: Compression,
+        file_size: u64,
+    ) -> bool {
+        compression == Compression::Zstd
+            && conf.remote_zstd_dictionary_max_file_size > 0
+            && file_size <= conf.remote_zstd_dictionary_max_file_size
+    }
+
+    /// Whether the object at `path` is in the tenant's directory, so that the dictionary named
+    /// in its metadata, if any, is one of the tenant's.
+    pub(super) fn owns(&self, path: &RemotePath) -> bool {
+        self.conf
+            .remote_path(&self.conf.tenant_path(&self.tenant_id))
+            .map_or(false, |tenant_dir| {
+                path.get_path().starts_with(tenant_dir.get_path())
+            })
+    }
+
+    /// The dictionary to compress a small layer with, `None` while there's none yet: the
+    /// layer is kept as a sample then, and the dictionary is trained once there are enough.
+    pub(super) async fn for_upload(&self, layer: &[u8]) -> anyhow::Result<Option<ZstdDictionary>> {
+        let newest = self.newest.get_or_try_init(|| self.load_newest()).await?;
+        let samples = {
+            let mut state = self.state.lock().unwrap();
+            if let Some(current) = state.trained.as_ref().or(newest.as_ref()) {
+                return Ok(Some(current.clone()));
+            }
+            state
+                .samples
+                .push(layer[..layer.len().min(MAX_SAMPLE_SIZE)].to_vec());
+            if state.samples.len() < TRAINING_SAMPLES {
+                return Ok(None);
+            }
+            std::mem::take(&mut state.samples)
+        };
+
+        // The uploads meanwhile gather new samples, in case this one fails.
+        let name = format!(
+            "{}.{DICTIONARY_VERSION}.zdict",
+            Utc::now().timestamp_millis()
+        );
+        let trained = tokio::task::spawn_blocking(move || {
+            ZstdDictionary::train(name, &samples, MAX_DICTIONARY_SIZE)
+        })
+        .await
+        .context("zstd dictionary training panicked")?;
+        let dictionary = match trained {
+            Ok(dictionary) => dictionary,
+            Err(e) => {
+                warn!("failed to train a zstd dictionary, gathering new samples: {e}");
+                return Ok(None);
+            }
+        };
+
+        let path = self.dictionary_path(dictionary.name())?;
+        let data = dictionary.data().to_vec();
+        let size = data.len();
+        if let Err(e) = self
+            .storage
+            .upload(
+                std::io::Cursor::new(data),
+                size,
+                &path,
+                StorageMetadata::mark_immutable(None),
+            )
+            .await
+        {
+            warn!("failed to upload the zstd dictionary {path}, gathering new samples: {e}");
+            return Ok(None);
+        }
+        info!(
+            "trained the zstd dictionary {path} of {size} bytes on {TRAINING_SAMPLES} small layers"
+        );
+        self.names
+            .lock()
+            .unwrap()
+            .insert(dictionary.name().to_owned());
+        let mut state = self.state.lock().unwrap();
+        state
+            .loaded
+            .insert(dictionary.name().to_owned(), dictionary.clone());
+        state.samples.clear();
+        state.trained = Some(dictionary.clone());
+        Ok(Some(dictionary))
+    }
+
+    /// The dictionary a layer downloaded from `storage` is compressed with.
+    pub(super) async fn get(
+        &self,
+        name: &str,
+        storage: &GenericRemoteStorage,
+    ) -> Result<ZstdDictionary, RemoteStorageError> {
+        let loaded = self.state.lock().unwrap().loaded.get(name).cloned();
+        if let Some(dictionary) = loaded {
+            return Ok(dictionary);
+        }
+        // Concurrent downloads of the same layers may load the dictionary twice, to the same
+        // result.
+        let dictionary = self.download(storage, name).await?;
+        self.state
+            .lock()
+            .unwrap()
+            .loaded
+            .insert(name.to_owned(), dictionary.clone());
+        Ok(dictionary)
+    }
+
+    /// Names of the dictionaries uploaded or loaded so far.
+    pub(super) fn names(&self) -> Vec<String> {
+        self.names.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// Names of the tenant's dictionaries in `storage`.
+    pub(super) async fn list(&self, storage: &GenericRemoteStorage) -> anyhow::Result<Vec<String>> {
+        let dir = self.dictionaries_dir()?;
+        let paths = match storage.list_files(Some(&dir)).await {
+            Ok(paths) => paths,
+            Err(RemoteStorageError::NotFound) => Vec::new(),
+            Err(e) => return Err(e).context("Failed to list the zstd dictionaries"),
+        };
+        let suffix = format!(".{DICTIONARY_VERSION}.zdict");
+        Ok(paths
+            .iter()
+            .filter_map(|path| path.object_name())
+            .filter(|name| name.ends_with(&suffix))
+            .map(str::to_owned)
+            .collect())
+    }
+
+    /// Loads the newest of the dictionaries in the remote storage, if there are any.
+    async fn load_newest(&self) -> anyhow::Result<Option<ZstdDictionary>> {
+        let names = self.list(&self.storage).await?;
+        let newest = names
+            .iter()
+            .filter_map(|name| {
+                let trained_at = name.split('.').next()?.parse::<i64>().ok()?;
+                Some((trained_at, name))
+            })
+            .max();
+        let Some((_, name)) = newest else {
+            return Ok(None);
+        };
+        let dictionary = self.download(&self.storage, name).await?;
+        self.state
+            .lock()
+            .unwrap()
+            .loaded
+            .insert(name.to_owned(), dictionary.clone());
+        Ok(Some(dictionary))
+    }
+
+    async fn download(
+        &self,
+        storage: &GenericRemoteStorage,
+        name: &str,
+    ) -> Result<ZstdDictionary, RemoteStorageError> {
+        let path = self.dictionary_path(name)?;
+        let mut download = storage.download(&path).await?;
+        let mut data = Vec::new();
+        download
+            .download_stream
+            .read_to_end(&mut data)
+            .await
+            .with_context(|| format!("Failed to download the zstd dictionary {path}"))?;
+        self.names.lock().unwrap().insert(name.to_owned());
+        Ok(ZstdDictionary::new(name.to_owned(), data))
+    }
+
+    fn dictionaries_dir(&self) -> anyhow::Result<RemotePath> {
+        self.conf.remote_path(
+            &self
+                .conf
+                .tenant_path(&self.tenant_id)
+                .join(DICTIONARIES_DIR),
+        )
+    }
+
+    /// Path of the dictionary object named `name`, the same in every storage.
+    pub(super) fn dictionary_path(&self, name: &str) -> anyhow::Result<RemotePath> {
+        Ok(self.dictionaries_dir()?.join(Path::new(name)))
+    }
+}
@@ -1,39 +1,83 @@
 //! Helper functions to download files from remote storage with a RemoteStorage
 //!
 //! The functions in this module retry failed operations automatically, according
-//! to the FAILED_DOWNLOAD_RETRIES constant.
+//! to the `max_retries` and `base_backoff_ms` settings of the remote storage config.
+//!
+//! A layer file is durable once [`download_layer_file`] returns: the download is fsynced
+//! before it's renamed to the layer file name, and the file and the timeline directory
+//! after, so the layer map, which gets the layer only then, never refers to a file that a
+//! crash could lose or leave empty. With `fsync_downloads = false`, none of this is done and
+//! a crash may leave such files behind.
 
 use std::collections::HashSet;
 use std::future::Future;
-use std::path::Path;
-use std::time::Duration;
+use std::io::SeekFrom;
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 use anyhow::{anyhow, Context};
+use futures::stream::{self, StreamExt, TryStreamExt};
 use tokio::fs;
-use tokio::io::AsyncWriteExt;
-use utils::backoff;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt, AsyncWriteExt, BufWriter};
+use tokio_util::io::InspectReader;
+use tracing::{info, instrument, warn};
 
 use crate::config::PageServerConf;
+use crate::metrics::{RemoteOpFileKind, REMOTE_DOWNLOAD_BYTES};
+use crate::tenant::metadata::TimelineMetadata;
 use crate::tenant::storage_layer::LayerFileName;
 use crate::tenant::timeline::span::debug_assert_current_span_has_tenant_and_timeline_id;
-use remote_storage::{DownloadError, GenericRemoteStorage};
+use remote_storage::{
+    Compression, Download, GenericRemoteStorage, RemotePath, RemoteStorageError, ZstdDictionary,
+};
 use utils::crashsafe::path_with_suffix_extension;
 use utils::id::{TenantId, TimelineId};
+use utils::lsn::Lsn;
 
+use super::checksum::{self, copy_with_hasher, Checksum, ChunkChecksums, Hasher};
+use super::dictionary::{self, TenantDictionaries};
 use super::index::{IndexPart, LayerFileMetadata};
-use super::{FAILED_DOWNLOAD_WARN_THRESHOLD, FAILED_REMOTE_OP_RETRIES};
+use super::manifest::BackupManifest;
+use super::owner_lease::OwnerLease;
+use super::parts::LayerParts;
+use super::{with_timeout, RemoteOpRetrySettings, FAILED_DOWNLOAD_WARN_THRESHOLD};
 
 async fn fsync_path(path: impl AsRef<std::path::Path>) -> Result<(), std::io::Error> {
     fs::File::open(path).await?.sync_all().await
 }
 
-static MAX_DOWNLOAD_DURATION: Duration = Duration::from_secs(120);
+/// The download streams yield whatever chunks the network gives, often small ones, so the
+/// temp files are written through a buffer of this size. It bounds the memory a download
+/// holds besides the stream's own buffers.
+const DOWNLOAD_WRITE_BUFFER_SIZE: usize = 1024 * 1024;
+
+/// Copies `reader` into `file` from its current position, through a
+/// [`DOWNLOAD_WRITE_BUFFER_SIZE`] buffer, which is flushed before returning. Returns the number
+/// of bytes copied.
+async fn copy_buffered(
+    reader: &mut (impl AsyncRead + Unpin),
+    file: &mut fs::File,
+    hasher: Option<&mut Hasher>,
+) -> std::io::Result<u64> {
+    let mut writer = BufWriter::with_capacity(DOWNLOAD_WRITE_BUFFER_SIZE, file);
+    let copied = copy_with_hasher(reader, &mut writer, hasher).await?;
+    writer.flush().await?;
+    Ok(copied)
+}
 
 ///
 /// If 'metadata' is given, we will validate that the downloaded file's size matches that
 /// in the metadata. (In the future, we might do more cross-checks, like CRC validation)
 ///
+/// `bytes_done` follows the progress of the download, the part of a resumed download that
+/// was there already included.
+///
+/// A layer compressed with a zstd dictionary is decompressed with the one of the tenant's
+/// `dictionaries` named in its metadata.
+///
 /// Returns the size of the downloaded file.
+#[allow(clippy::too_many_arguments)]
 pub async fn download_layer_file<'a>(
     conf: &'static PageServerConf,
     storage: &'a GenericRemoteStorage,
@@ -41,16 +85,30 @@ pub async fn download_layer_file<'a>(
     timeline_id: TimelineId,
     layer_file_name: &'a LayerFileName,
     layer_metadata: &'a LayerFileMetadata,
-) -> Result<u64, DownloadError> {
+    bytes_done: &'a AtomicU64,
+    dictionaries: &'a TenantDictionaries,
+) -> Result<u64, RemoteStorageError> {
     debug_assert_current_span_has_tenant_and_timeline_id();
 
     let timeline_path = conf.timeline_path(&tenant_id, &timeline_id);
 
     let local_path = timeline_path.join(layer_file_name.file_name());
 
+    // A layer packed into an archive is the byte range of the archive object, stored as is.
+    let archived_range = layer_metadata.archive().map(|location| {
+        (
+            timeline_path.join(&location.name),
+            location.offset,
+            location.offset + layer_metadata.file_size(),
+        )
+    });
     let remote_path = conf
-        .remote_path(&local_path)
-        .map_err(DownloadError::Other)?;
+        .remote_path(
+            archived_range
+                .as_ref()
+                .map_or(&local_path, |(archive_path, _, _)| archive_path),
+        )
+        .map_err(RemoteStorageError::from)?;
 
     // Perform a rename inspired by durable_rename from file_utils.c.
     // The sequence:
@@ -61,35 +119,170 @@ pub async fn download_layer_file<'a>(
     //     fsync(parent)
     // For more context about durable_rename check this email from postgres mailing list:
     // https://www.postgresql.org/message-id/56583BDD.9060302@2ndquadrant.com
-    // If pageserver crashes the temp file will be deleted on startup and re-downloaded.
-    let temp_file_path = path_with_suffix_extension(&local_path, TEMP_DOWNLOAD_EXTENSION);
+    // If pageserver crashes, or the download fails midway, the next download of the layer
+    // continues from what's already in the temp file, see `start_layer_download`.
+    let temp_file_path = temp_download_path(conf, tenant_id, timeline_id, &local_path);
+    if let Some(temp_dir) = temp_file_path.parent() {
+        fs::create_dir_all(temp_dir)
+            .await
+            .with_context(|| format!("create the download directory {}", temp_dir.display()))
+            .map_err(RemoteStorageError::from)?;
+    }
+
+    let expected_size = layer_metadata.file_size();
+    let chunks = if conf.remote_download_chunks > 1
+        && archived_range.is_none()
+        && layer_metadata.parts().is_none()
+        && expected_size > 0
+        && expected_size >= conf.remote_download_chunk_min_size
+        && storage.efficient_byte_ranges()
+    {
+        conf.remote_download_chunks
+    } else {
+        1
+    };
 
     let (mut destination_file, bytes_amount) = download_retry(
+        conf,
         || async {
-            // TODO: this doesn't use the cached fd for some reason?
-            let mut destination_file = fs::File::create(&temp_file_path).await.with_context(|| {
-                format!(
-                    "create a destination file for layer '{}'",
-                    temp_file_path.display()
-                )
-            })
-            .map_err(DownloadError::Other)?;
-            let mut download = storage.download(&remote_path).await.with_context(|| {
-                format!(
-                    "open a download stream for layer with remote storage path '{remote_path:?}'"
+            if let Some((_, start, end)) = archived_range {
+                let download = storage.download_byte_range(&remote_path, start, Some(end)).await?;
+                let mut destination_file = fs::File::create(&temp_file_path)
+                    .await
+                    .with_context(|| format!("create a destination file for layer '{}'", temp_file_path.display()))
+                    .map_err(RemoteStorageError::from)?;
+                bytes_done.store(0, Ordering::Relaxed);
+                let mut download_stream = InspectReader::new(download.download_stream, |bytes: &[u8]| {
+                    bytes_done.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+                });
+                // The archive has no checksum of its own, the index part has the layer's.
+                let mut hasher = layer_metadata
+                    .checksum()
+                    .and_then(|checksum| Hasher::new(checksum.algorithm()));
+                let downloaded_bytes = copy_buffered(&mut download_stream, &mut destination_file, hasher.as_mut())
+                    .await
+                    .with_context(|| {
+                        format!("Failed to download bytes {start}..{end} of layer archive with remote storage path '{remote_path:?}' into file {temp_file_path:?}")
+                    })
+                    .map_err(RemoteStorageError::from)?;
+                if let (Some(expected), Some(hasher)) = (layer_metadata.checksum(), hasher) {
+                    if let Err(e) = expected.verify(&hasher.finish()) {
+                        return Err(RemoteStorageError::Transient(e.context(format!(
+                            "Downloaded bytes {start}..{end} of layer archive {remote_path:?} are corrupted"
+                        ))));
+                    }
+                }
+                REMOTE_DOWNLOAD_BYTES
+                    .with_label_values(&[RemoteOpFileKind::Layer.as_str()])
+                    .inc_by(downloaded_bytes);
+                return Ok((destination_file, downloaded_bytes));
+            }
+
+            if let Some(parts) = layer_metadata.parts().copied() {
+                bytes_done.store(0, Ordering::Relaxed);
+                let destination_file =
+                    download_layer_parts(storage, &remote_path, &temp_file_path, parts, bytes_done)
+                        .await?;
+                return Ok((destination_file, parts.file_size));
+            }
+
+            if chunks > 1 {
+                bytes_done.store(0, Ordering::Relaxed);
+                if let Some(destination_file) = download_layer_chunks(
+                    storage,
+                    &remote_path,
+                    &temp_file_path,
+                    expected_size,
+                    chunks,
+                    bytes_done,
                 )
-            })
-            .map_err(DownloadError::Other)?;
+                .await?
+                {
+                    return Ok((destination_file, expected_size));
+                }
+            }
 
-            let bytes_amount = tokio::time::timeout(MAX_DOWNLOAD_DURATION, tokio::io::copy(&mut download.download_stream, &mut destination_file))
+            let (mut destination_file, download, resumed_bytes) = start_layer_download(
+                storage,
+                &remote_path,
+                &temp_file_path,
+                layer_metadata.file_size(),
+            )
+            .await?;
+            bytes_done.store(resumed_bytes, Ordering::Relaxed);
+
+            let expected_checksum = Checksum::from_metadata(download.metadata.as_ref());
+            let mut hasher = expected_checksum
+                .as_ref()
+                .and_then(|checksum| Hasher::new(checksum.algorithm()));
+            if resumed_bytes > 0 {
+                match hasher.as_mut() {
+                    // The checksum covers the whole layer, including the part downloaded before.
+                    Some(hasher) => copy_with_hasher(
+                        &mut (&mut destination_file).take(resumed_bytes),
+                        &mut tokio::io::sink(),
+                        Some(hasher),
+                    )
+                    .await
+                    .map(|_| ()),
+                    None => destination_file
+                        .seek(SeekFrom::Start(resumed_bytes))
+                        .await
+                        .map(|_| ()),
+                }
+                .with_context(|| {
+                    format!(
+                        "read the partially downloaded layer '{}'",
+                        temp_file_path.display()
+                    )
+                })
+                .map_err(RemoteStorageError::from)?;
+            }
+            let download_stream: Pin<Box<dyn AsyncRead + Unpin + Send + Sync>> = match ZstdDictionary::name_from_metadata(download.metadata.as_ref()) {
+                // Small enough to be decompressed in memory, see `remote_zstd_dictionary_max_file_size`.
+                Some(name) => {
+                    let decompressed = dictionaries
+                        .get(name, storage)
+                        .await?
+                        .decompress(download.download_stream)
+                        .await
+                        .with_context(|| format!("decompress layer with remote storage path '{remote_path:?}'"))
+                        .map_err(RemoteStorageError::from)?;
+                    Box::pin(std::io::Cursor::new(decompressed))
+                }
+                None => Compression::from_metadata(download.metadata.as_ref())
+                    .map_err(RemoteStorageError::from)?
+                    .decompress(download.download_stream),
+            };
+            let mut download_stream = InspectReader::new(download_stream, |bytes: &[u8]| {
+                bytes_done.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+            });
+
+            let downloaded_bytes = copy_buffered(&mut download_stream, &mut destination_file, hasher.as_mut())
                 .await
-                .map_err(|e| DownloadError::Other(anyhow::anyhow!("Timed out  {:?}", e)))?
                 .with_context(|| {
                     format!("Failed to download layer with remote storage path '{remote_path:?}' into file {temp_file_path:?}")
                 })
-                .map_err(DownloadError::Other)?;
+                .map_err(RemoteStorageError::from)?;
+            REMOTE_DOWNLOAD_BYTES
+                .with_label_values(&[RemoteOpFileKind::Layer.as_str()])
+                .inc_by(downloaded_bytes);
+
+            // A corrupted download is retried, same as a failed one, but from scratch: the corrupted
+            // part might be the one downloaded before, so the temp file is not resumed from.
+            if let (Some(expected), Some(hasher)) = (expected_checksum, hasher) {
+                if let Err(e) = expected.verify(&hasher.finish()) {
+                    drop(destination_file);
+                    if let Err(remove_error) = fs::remove_file(&temp_file_path).await {
+                        warn!("failed to remove the corrupted download {temp_file_path:?}: {remove_error}");
+                    }
+                    return Err(RemoteStorageError::Transient(
+                        e.context(format!("Downloaded layer {remote_path:?} is corrupted")),
+                    ));
+                }
+            }
 
-            Ok((destination_file, bytes_amount))
+            Ok((destination_file, resumed_bytes + downloaded_bytes))
 
         },
         &format!("download {remote_path:?}"),
@@ -112,35 +305,37 @@ pub async fn download_layer_file<'a>(
                 temp_file_path.display()
             )
         })
-        .map_err(DownloadError::Other)?;
+        .map_err(RemoteStorageError::from)?;
 
     let expected = layer_metadata.file_size();
     if expected != bytes_amount {
-        return Err(DownloadError::Other(anyhow!(
+        return Err(RemoteStorageError::Transient(anyhow!(
             "According to layer file metadata should have downloaded {expected} bytes but downloaded {bytes_amount} bytes into file {temp_file_path:?}",
         )));
     }
 
-    // not using sync_data because it can lose file size update
-    destination_file
-        .sync_all()
-        .await
-        .with_context(|| {
-            format!(
-                "failed to fsync source file at {}",
-                temp_file_path.display()
-            )
-        })
-        .map_err(DownloadError::Other)?;
+    if conf.fsync_downloads {
+        // not using sync_data because it can lose file size update
+        destination_file
+            .sync_all()
+            .await
+            .with_context(|| {
+                format!(
+                    "failed to fsync source file at {}",
+                    temp_file_path.display()
+                )
+            })
+            .map_err(RemoteStorageError::from)?;
+    }
     drop(destination_file);
 
     fail::fail_point!("remote-storage-download-pre-rename", |_| {
-        Err(DownloadError::Other(anyhow!(
+        Err(RemoteStorageError::Transient(anyhow!(
             "remote-storage-download-pre-rename failpoint triggered"
         )))
     });
 
-    fs::rename(&temp_file_path, &local_path)
+    move_into_place(&temp_file_path, &local_path, conf.fsync_downloads)
         .await
         .with_context(|| {
             format!(
@@ -148,19 +343,621 @@ pub async fn download_layer_file<'a>(
                 local_path.display(),
             )
         })
-        .map_err(DownloadError::Other)?;
+        .map_err(RemoteStorageError::from)?;
 
-    fsync_path(&local_path)
-        .await
-        .with_context(|| format!("Could not fsync layer file {}", local_path.display(),))
-        .map_err(DownloadError::Other)?;
+    if conf.fsync_downloads {
+        fsync_path(&local_path)
+            .await
+            .with_context(|| format!("Could not fsync layer file {}", local_path.display(),))
+            .map_err(RemoteStorageError::from)?;
+        fsync_path(&timeline_path)
+            .await
+            .with_context(|| {
+                format!(
+                    "Could not fsync timeline directory {}",
+                    timeline_path.display()
+                )
+            })
+            .map_err(RemoteStorageError::from)?;
+    }
 
     tracing::debug!("download complete: {}", local_path.display());
 
     Ok(bytes_amount)
 }
 
-const TEMP_DOWNLOAD_EXTENSION: &str = "temp_download";
+/// Downloads the parts of a layer too large for a single object one after another into the
+/// temp file, each verified against its own checksum. The temp file is always written from
+/// scratch, a part that fails to download fails the whole attempt.
+async fn download_layer_parts(
+    storage: &GenericRemoteStorage,
+    remote_path: &RemotePath,
+    temp_file_path: &Path,
+    parts: LayerParts,
+    bytes_done: &AtomicU64,
+) -> Result<fs::File, RemoteStorageError> {
+    let mut destination_file = fs::File::create(temp_file_path)
+        .await
+        .with_context(|| {
+            format!(
+                "create a destination file for layer '{}'",
+                temp_file_path.display()
+            )
+        })
+        .map_err(RemoteStorageError::from)?;
+
+    for part in 0..parts.count() {
+        let (start, end) = parts.range(part);
+        let part_path = LayerParts::part_path(remote_path, part);
+        let download = storage.download(&part_path).await?;
+        let expected_checksum = Checksum::from_metadata(download.metadata.as_ref());
+        let mut hasher = expected_checksum
+            .as_ref()
+            .and_then(|checksum| Hasher::new(checksum.algorithm()));
+        let mut download_stream = InspectReader::new(download.download_stream, |bytes: &[u8]| {
+            bytes_done.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+        });
+        let copied = copy_buffered(&mut download_stream, &mut destination_file, hasher.as_mut())
+            .await
+            .with_context(|| {
+                format!("Failed to download part {part_path:?} of layer with remote storage path '{remote_path:?}' into file {temp_file_path:?}")
+            })
+            .map_err(RemoteStorageError::from)?;
+        REMOTE_DOWNLOAD_BYTES
+            .with_label_values(&[RemoteOpFileKind::Layer.as_str()])
+            .inc_by(copied);
+
+        let verified = match (expected_checksum, hasher) {
+            (Some(expected), Some(hasher)) => expected.verify(&hasher.finish()),
+            _ => Ok(()),
+        };
+        let corrupted = if copied != end - start {
+            Some(anyhow!(
+                "Downloaded {copied} bytes instead of {} from part {part_path:?}",
+                end - start
+            ))
+        } else {
+            verified
+                .err()
+                .map(|e| e.context(format!("Downloaded part {part_path:?} is corrupted")))
+        };
+        if let Some(e) = corrupted {
+            drop(destination_file);
+            if let Err(remove_error) = fs::remove_file(temp_file_path).await {
+                warn!("failed to remove the corrupted download {temp_file_path:?}: {remove_error}");
+            }
+            return Err(RemoteStorageError::Transient(e.context(format!(
+                "Failed to download layer {remote_path:?} split into parts"
+            ))));
+        }
+    }
+
+    Ok(destination_file)
+}
+
+/// Downloads the layer in `chunks` byte ranges at once, each into its own part of the temp
+/// file, which is created with the full layer size upfront. The temp file left by a failed
+/// chunked download is never resumed from: it has the full size already.
+///
+/// A layer uploaded with chunk checksums is downloaded in ranges of whole chunks, each chunk
+/// verified as it arrives. A corrupted chunk is downloaded again on its own, after the ranges.
+///
+/// Returns `None` without creating the temp file if the remote object can't be assembled from
+/// byte ranges, i.e. it's compressed. Otherwise returns the complete, verified temp file.
+async fn download_layer_chunks(
+    storage: &GenericRemoteStorage,
+    remote_path: &RemotePath,
+    temp_file_path: &Path,
+    expected_size: u64,
+    chunks: usize,
+    bytes_done: &AtomicU64,
+) -> Result<Option<fs::File>, RemoteStorageError> {
+    let chunk_checksums = download_chunk_checksums(storage, remote_path, expected_size).await?;
+    let mut chunk_size = expected_size.div_ceil(chunks as u64).max(1);
+    if let Some(chunk_checksums) = &chunk_checksums {
+        let checksummed_size = chunk_checksums.chunk_size();
+        chunk_size = chunk_size.div_ceil(checksummed_size) * checksummed_size;
+    }
+    let ranges = (0..expected_size)
+        .step_by(chunk_size as usize)
+        .map(|start| (start, (start + chunk_size).min(expected_size)))
+        .collect::<Vec<_>>();
+
+    // The first range tells what the object is: its offsets need to be the layer's ones.
+    let (first_start, first_end) = ranges[0];
+    let first = storage
+        .download_byte_range(remote_path, first_start, Some(first_end))
+        .await
+        .with_context(|| {
+            format!("open a download stream for the first chunk of layer with remote storage path '{remote_path:?}'")
+        })
+        .map_err(RemoteStorageError::from)?;
+    if Compression::from_metadata(first.metadata.as_ref()).map_err(RemoteStorageError::from)?
+        != Compression::None
+    {
+        return Ok(None);
+    }
+    let expected_checksum = Checksum::from_metadata(first.metadata.as_ref());
+
+    let destination_file = create_sized_file(temp_file_path, expected_size)
+        .await
+        .with_context(|| {
+            format!(
+                "create a destination file for layer '{}'",
+                temp_file_path.display()
+            )
+        })
+        .map_err(RemoteStorageError::from)?;
+
+    let mut first_stream = Some(first.download_stream);
+    let chunk_checksums = chunk_checksums.as_ref();
+    let chunk_downloads = ranges.into_iter().map(|(start, end)| {
+        let stream = if start == first_start {
+            first_stream.take()
+        } else {
+            None
+        };
+        async move {
+            let stream = match stream {
+                Some(stream) => stream,
+                None => {
+                    storage
+                        .download_byte_range(remote_path, start, Some(end))
+                        .await
+                        .with_context(|| {
+                            format!("open a download stream for bytes {start}..{end} of layer with remote storage path '{remote_path:?}'")
+                        })
+                        .map_err(RemoteStorageError::from)?
+                        .download_stream
+                }
+            };
+            let mut stream = InspectReader::new(stream, |bytes: &[u8]| {
+                bytes_done.fetch_add(bytes.len() as u64, Ordering::Relaxed);
+            })
+            .take(end - start);
+
+            let (copied, corrupted) =
+                write_chunk(&mut stream, temp_file_path, start, chunk_checksums)
+                    .await
+                    .with_context(|| {
+                        format!("Failed to download bytes {start}..{end} of layer with remote storage path '{remote_path:?}' into file {temp_file_path:?}")
+                    })
+                    .map_err(RemoteStorageError::from)?;
+            if copied != end - start {
+                return Err(RemoteStorageError::Transient(anyhow!(
+                    "Downloaded {copied} bytes instead of {} for bytes {start}..{end} of layer with remote storage path '{remote_path:?}'",
+                    end - start
+                )));
+            }
+            Ok(corrupted)
+        }
+    });
+    let corrupted = futures::future::try_join_all(chunk_downloads).await?;
+    REMOTE_DOWNLOAD_BYTES
+        .with_label_values(&[RemoteOpFileKind::Layer.as_str()])
+        .inc_by(expected_size);
+    if let Some(chunk_checksums) = chunk_checksums {
+        for start in corrupted.into_iter().flatten() {
+            refetch_corrupted_chunk(storage, remote_path, temp_file_path, chunk_checksums, start)
+                .await?;
+        }
+    }
+
+    let assembled_size = destination_file
+        .metadata()
+        .await
+        .with_context(|| format!("stat the downloaded layer '{}'", temp_file_path.display()))
+        .map_err(RemoteStorageError::from)?
+        .len();
+    if assembled_size != expected_size {
+        return Err(RemoteStorageError::Transient(anyhow!(
+            "Assembled {assembled_size} bytes instead of {expected_size} into file {temp_file_path:?}"
+        )));
+    }
+
+    // Same as for a download in one piece, a corrupted file is downloaded again from scratch.
+    let Some(expected) = expected_checksum else {
+        return Ok(Some(destination_file));
+    };
+    let Some(mut hasher) = Hasher::new(expected.algorithm()) else {
+        return Ok(Some(destination_file));
+    };
+    let mut assembled = fs::File::open(temp_file_path)
+        .await
+        .with_context(|| format!("open the downloaded layer '{}'", temp_file_path.display()))
+        .map_err(RemoteStorageError::from)?;
+    copy_with_hasher(&mut assembled, &mut tokio::io::sink(), Some(&mut hasher))
+        .await
+        .with_context(|| format!("read the downloaded layer '{}'", temp_file_path.display()))
+        .map_err(RemoteStorageError::from)?;
+    if let Err(e) = expected.verify(&hasher.finish()) {
+        drop(destination_file);
+        if let Err(remove_error) = fs::remove_file(temp_file_path).await {
+            warn!("failed to remove the corrupted download {temp_file_path:?}: {remove_error}");
+        }
+        return Err(RemoteStorageError::Transient(
+            e.context(format!("Downloaded layer {remote_path:?} is corrupted")),
+        ));
+    }
+
+    Ok(Some(destination_file))
+}
+
+async fn create_sized_file(path: &Path, size: u64) -> std::io::Result<fs::File> {
+    let file = fs::File::create(path).await?;
+    file.set_len(size).await?;
+    Ok(file)
+}
+
+/// Writes the `chunk` stream into the file at `offset`, returns the number of bytes written.
+///
+/// With the `chunk_checksums` of the layer, `offset` is the start of a checksummed chunk, and
+/// the stream is verified chunk by chunk as it's written. The start offsets of the chunks that
+/// don't match their checksums are returned too.
+async fn write_chunk(
+    chunk: &mut (impl tokio::io::AsyncRead + Unpin),
+    path: &Path,
+    offset: u64,
+    chunk_checksums: Option<&ChunkChecksums>,
+) -> std::io::Result<(u64, Vec<u64>)> {
+    let mut file = fs::OpenOptions::new().write(true).open(path).await?;
+    file.seek(SeekFrom::Start(offset)).await?;
+    let Some(chunk_checksums) = chunk_checksums else {
+        return Ok((copy_buffered(chunk, &mut file, None).await?, Vec::new()));
+    };
+
+    let mut copied = 0;
+    let mut corrupted = Vec::new();
+    while let Some((index, end)) = chunk_checksums.chunk_at(offset + copied) {
+        let start = offset + copied;
+        let mut hasher = chunk_checksums.hasher();
+        let chunk_copied = copy_buffered(
+            &mut (&mut *chunk).take(end - start),
+            &mut file,
+            Some(&mut hasher),
+        )
+        .await?;
+        copied += chunk_copied;
+        // The end of the stream, the caller checks that it's the expected one.
+        if chunk_copied < end - start {
+            break;
+        }
+        if let Err(e) = chunk_checksums.verify(index, &hasher.finish()) {
+            warn!("bytes {start}..{end} written into file {path:?} are corrupted: {e:#}");
+            corrupted.push(start);
+        }
+    }
+    Ok((copied, corrupted))
+}
+
+/// How many times a chunk that doesn't match its checksum is downloaded again, before the
+/// whole layer download is retried.
+const CORRUPTED_CHUNK_DOWNLOAD_ATTEMPTS: u32 = 3;
+
+/// Downloads the checksummed chunk that starts at `start` again, into its part of the file.
+async fn refetch_corrupted_chunk(
+    storage: &GenericRemoteStorage,
+    remote_path: &RemotePath,
+    temp_file_path: &Path,
+    chunk_checksums: &ChunkChecksums,
+    start: u64,
+) -> Result<(), RemoteStorageError> {
+    let (_, end) = chunk_checksums
+        .chunk_at(start)
+        .expect("corrupted chunks are reported by their start offsets");
+    for attempt in 1..=CORRUPTED_CHUNK_DOWNLOAD_ATTEMPTS {
+        info!("downloading the corrupted bytes {start}..{end} of layer with remote storage path '{remote_path:?}' again, attempt {attempt}");
+        let download = storage
+            .download_byte_range(remote_path, start, Some(end))
+            .await
+            .with_context(|| {
+                format!("open a download stream for bytes {start}..{end} of layer with remote storage path '{remote_path:?}'")
+            })
+            .map_err(RemoteStorageError::from)?;
+        let (copied, corrupted) = write_chunk(
+            &mut download.download_stream.take(end - start),
+            temp_file_path,
+            start,
+            Some(chunk_checksums),
+        )
+        .await
+        .with_context(|| {
+            format!("Failed to download bytes {start}..{end} of layer with remote storage path '{remote_path:?}' into file {temp_file_path:?}")
+        })
+        .map_err(RemoteStorageError::from)?;
+        REMOTE_DOWNLOAD_BYTES
+            .with_label_values(&[RemoteOpFileKind::Layer.as_str()])
+            .inc_by(copied);
+        if copied == end - start && corrupted.is_empty() {
+            return Ok(());
+        }
+    }
+    Err(RemoteStorageError::Transient(anyhow!(
+        "Bytes {start}..{end} of layer with remote storage path '{remote_path:?}' are corrupted after {CORRUPTED_CHUNK_DOWNLOAD_ATTEMPTS} downloads"
+    )))
+}
+
+/// Downloads the chunk checksums the layer at `remote_path` was uploaded with, if any.
+/// Unusable ones are ignored, the layer's own checksum still covers it.
+pub(super) async fn download_chunk_checksums(
+    storage: &GenericRemoteStorage,
+    remote_path: &RemotePath,
+    expected_size: u64,
+) -> Result<Option<ChunkChecksums>, RemoteStorageError> {
+    let path = ChunkChecksums::remote_path(remote_path);
+    let mut download = match storage.download(&path).await {
+        Ok(download) => download,
+        Err(RemoteStorageError::NotFound) => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    let mut bytes = Vec::new();
+    download
+        .download_stream
+        .read_to_end(&mut bytes)
+        .await
+        .with_context(|| format!("Failed to download the chunk checksums {path}"))
+        .map_err(RemoteStorageError::from)?;
+    match serde_json::from_slice::<ChunkChecksums>(&bytes) {
+        Ok(chunk_checksums) if chunk_checksums.file_size() == expected_size => {
+            Ok(Some(chunk_checksums))
+        }
+        Ok(chunk_checksums) => {
+            warn!(
+                "ignoring the chunk checksums {path} of {} bytes, the layer has {expected_size}",
+                chunk_checksums.file_size()
+            );
+            Ok(None)
+        }
+        Err(e) => {
+            warn!("ignoring the chunk checksums {path} that failed to parse: {e}");
+            Ok(None)
+        }
+    }
+}
+
+/// Cuts the partially downloaded layer down to its whole chunks that match the checksums the
+/// layer was uploaded with, so that a download is never resumed after corrupted data. Without
+/// the chunk checksums, the whole part is kept. Returns the size of the part kept.
+async fn verify_partial_download(
+    storage: &GenericRemoteStorage,
+    remote_path: &RemotePath,
+    temp_file_path: &Path,
+    partial_size: u64,
+    expected_size: u64,
+) -> Result<u64, RemoteStorageError> {
+    let Some(chunk_checksums) =
+        download_chunk_checksums(storage, remote_path, expected_size).await?
+    else {
+        return Ok(partial_size);
+    };
+    let verify = async {
+        let mut file = fs::OpenOptions::new()
+            .read(true)
+            .write(true)
+            .open(temp_file_path)
+            .await?;
+        let verified = chunk_checksums
+            .verified_prefix(&mut file, partial_size)
+            .await?;
+        if verified < partial_size {
+            file.set_len(verified).await?;
+        }
+        Ok::<_, std::io::Error>(verified)
+    };
+    let verified = verify
+        .await
+        .with_context(|| {
+            format!(
+                "verify the partially downloaded layer '{}'",
+                temp_file_path.display()
+            )
+        })
+        .map_err(RemoteStorageError::from)?;
+    if verified < partial_size {
+        info!(
+            "keeping {verified} of the {partial_size} bytes of layer '{}' downloaded before, the ones that match their chunk checksums",
+            temp_file_path.display()
+        );
+    }
+    Ok(verified)
+}
+
+/// Opens the temp file of the layer download, along with the remote stream to fill it with.
+///
+/// A temp file left by an interrupted download (a failed attempt or a pageserver crash) is
+/// resumed from its current length with a ranged download, as long as it's shorter than the
+/// layer size recorded in the index part. Otherwise the download starts over, and so it does
+/// for the compressed layers, whose offsets in the remote object don't match the file ones.
+/// With the chunk checksums of the layer, only the verified chunks of the temp file are kept.
+///
+/// Returns the temp file positioned at its start, the stream of the rest of the layer, and
+/// the size of the part that was downloaded before.
+async fn start_layer_download(
+    storage: &GenericRemoteStorage,
+    remote_path: &RemotePath,
+    temp_file_path: &Path,
+    expected_size: u64,
+) -> Result<(fs::File, Download, u64), RemoteStorageError> {
+    let partial_size = match fs::metadata(temp_file_path).await {
+        Ok(metadata) => metadata.len(),
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => 0,
+        Err(e) => {
+            return Err(RemoteStorageError::Transient(
+                anyhow::Error::new(e).context(format!(
+                    "stat the partially downloaded layer '{}'",
+                    temp_file_path.display()
+                )),
+            ))
+        }
+    };
+    let partial_size = if partial_size > 0 && partial_size < expected_size {
+        verify_partial_download(
+            storage,
+            remote_path,
+            temp_file_path,
+            partial_size,
+            expected_size,
+        )
+        .await?
+    } else {
+        partial_size
+    };
+
+    if partial_size > 0 && partial_size < expected_size {
+        let download = storage
+            .download_byte_range(remote_path, partial_size, None)
+            .await
+            .with_context(|| {
+                format!("open a download stream for the rest of layer with remote storage path '{remote_path:?}'")
+            })
+            .map_err(RemoteStorageError::from)?;
+
+        if Compression::from_metadata(download.metadata.as_ref())
+            .map_err(RemoteStorageError::from)?
+            == Compression::None
+        {
+            let destination_file = fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(temp_file_path)
+                .await
+                .with_context(|| {
+                    format!(
+                        "open the partially downloaded layer '{}'",
+                        temp_file_path.display()
+                    )
+                })
+                .map_err(RemoteStorageError::from)?;
+            info!(
+                "resuming the download of layer '{}' from byte {partial_size} of {expected_size}",
+                temp_file_path.display()
+            );
+            return Ok((destination_file, download, partial_size));
+        }
+    }
+
+    // TODO: this doesn't use the cached fd for some reason?
+    let destination_file = fs::File::create(temp_file_path)
+        .await
+        .with_context(|| {
+            format!(
+                "create a destination file for layer '{}'",
+                temp_file_path.display()
+            )
+        })
+        .map_err(RemoteStorageError::from)?;
+    let download = storage
+        .download(remote_path)
+        .await
+        .with_context(|| {
+            format!("open a download stream for layer with remote storage path '{remote_path:?}'")
+        })
+        .map_err(RemoteStorageError::from)?;
+
+    Ok((destination_file, download, 0))
+}
+
+pub(super) const TEMP_DOWNLOAD_EXTENSION: &str = "temp_download";
+
+/// Where the layer at `local_path` is downloaded to: next to it, or in the `sync_temp_dir`.
+fn temp_download_path(
+    conf: &PageServerConf,
+    tenant_id: TenantId,
+    timeline_id: TimelineId,
+    local_path: &Path,
+) -> PathBuf {
+    match &conf.sync_temp_dir {
+        Some(sync_temp_dir) => path_with_suffix_extension(
+            sync_temp_dir
+                .join(tenant_id.to_string())
+                .join(timeline_id.to_string())
+                .join(
+                    local_path
+                        .file_name()
+                        .expect("layer paths have a file name"),
+                ),
+            TEMP_DOWNLOAD_EXTENSION,
+        ),
+        None => path_with_suffix_extension(local_path, TEMP_DOWNLOAD_EXTENSION),
+    }
+}
+
+/// Renames the complete download to its final path. A download on another filesystem can't be
+/// renamed there: it's copied next to the final path and renamed from there instead, so the
+/// layer file appears at once all the same.
+async fn move_into_place(
+    temp_file_path: &Path,
+    local_path: &Path,
+    fsync: bool,
+) -> std::io::Result<()> {
+    match fs::rename(temp_file_path, local_path).await {
+        Err(e) if e.raw_os_error() == Some(nix::errno::Errno::EXDEV as i32) => {
+            copy_into_place(temp_file_path, local_path, fsync).await
+        }
+        result => result,
+    }
+}
+
+async fn copy_into_place(
+    temp_file_path: &Path,
+    local_path: &Path,
+    fsync: bool,
+) -> std::io::Result<()> {
+    let staging_path = path_with_suffix_extension(local_path, TEMP_DOWNLOAD_EXTENSION);
+    fs::copy(temp_file_path, &staging_path).await?;
+    if fsync {
+        fsync_path(&staging_path).await?;
+    }
+    fs::rename(&staging_path, local_path).await?;
+    if let Err(e) = fs::remove_file(temp_file_path).await {
+        warn!(
+            "failed to remove the download {} copied to {}: {e}",
+            temp_file_path.display(),
+            local_path.display()
+        );
+    }
+    Ok(())
+}
+
+/// Removes the temp files of the timeline's unfinished downloads, wherever they are downloaded.
+pub(super) async fn remove_temp_downloads(
+    conf: &PageServerConf,
+    tenant_id: TenantId,
+    timeline_id: TimelineId,
+) -> anyhow::Result<()> {
+    if let Some(sync_temp_dir) = &conf.sync_temp_dir {
+        let temp_dir = sync_temp_dir
+            .join(tenant_id.to_string())
+            .join(timeline_id.to_string());
+        match fs::remove_dir_all(&temp_dir).await {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                return Err(e).with_context(|| format!("remove {}", temp_dir.display()))
+            }
+            _ => {}
+        }
+    }
+
+    let timeline_path = conf.timeline_path(&tenant_id, &timeline_id);
+    let mut entries = match fs::read_dir(&timeline_path).await {
+        Ok(entries) => entries,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("list {}", timeline_path.display())),
+    };
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .with_context(|| format!("list {}", timeline_path.display()))?
+    {
+        let path = entry.path();
+        if is_temp_download_file(&path) {
+            info!("removing the unfinished download {}", path.display());
+            fs::remove_file(&path)
+                .await
+                .with_context(|| format!("remove {}", path.display()))?;
+        }
+    }
+    Ok(())
+}
 
 pub fn is_temp_download_file(path: &Path) -> bool {
     let extension = path.extension().map(|pname| {
@@ -188,11 +985,21 @@ pub async fn list_remote_timelines<'a>(
         anyhow::bail!("storage-sync-list-remote-timelines");
     });
 
-    let timelines = download_retry(
-        || storage.list_prefixes(Some(&tenant_storage_path)),
-        &format!("list prefixes for {tenant_path:?}"),
-    )
-    .await?;
+    let retry_settings = RemoteOpRetrySettings::from_conf(conf);
+    let timelines = retry_settings
+        .retry(
+            || {
+                with_timeout(
+                    retry_settings.list_timeout,
+                    storage.list_prefixes(Some(&tenant_storage_path)),
+                    |_| RemoteStorageError::Timeout,
+                )
+            },
+            RemoteStorageError::is_permanent,
+            FAILED_DOWNLOAD_WARN_THRESHOLD,
+            &format!("list prefixes for {tenant_path:?}"),
+        )
+        .await?;
 
     if timelines.is_empty() {
         anyhow::bail!("no timelines found on the remote storage")
@@ -204,6 +1011,11 @@ pub async fn list_remote_timelines<'a>(
         let object_name = timeline_remote_storage_key.object_name().ok_or_else(|| {
             anyhow::anyhow!("failed to get timeline id for remote tenant {tenant_id}")
         })?;
+        // The tenant's owner lease and zstd dictionaries are next to the timelines, and get
+        // listed with them.
+        if object_name == OwnerLease::FILE_NAME || object_name == dictionary::DICTIONARIES_DIR {
+            continue;
+        }
 
         let timeline_id: TimelineId = object_name.parse().with_context(|| {
             format!("failed to parse object name into timeline id '{object_name}'")
@@ -224,66 +1036,471 @@ pub async fn list_remote_timelines<'a>(
     Ok(timeline_ids)
 }
 
+/// How many index parts [`list_remote_timelines_with_metadata`] downloads at once.
+const LIST_INDEX_PART_CONCURRENCY: usize = 16;
+
+/// Lists the timelines of the tenant in the remote storage, like [`list_remote_timelines`],
+/// along with the metadata from their index parts, without the tenant being attached, e.g.
+/// to tell what an attach would bring. The timelines being deleted are left out.
+///
+/// Each timeline comes with the `disk_consistent_lsn`s of its index generations, the LSNs it
+/// can be restored at from [`IndexPart::generation_file_name`], oldest first.
+///
+/// Returns the timelines sorted by their ids.
+pub async fn list_remote_timelines_with_metadata(
+    storage: &GenericRemoteStorage,
+    conf: &'static PageServerConf,
+    tenant_id: TenantId,
+) -> anyhow::Result<Vec<(TimelineId, TimelineMetadata, Vec<Lsn>)>> {
+    let timeline_ids = list_remote_timelines(storage, conf, tenant_id).await?;
+
+    let timelines: Vec<Option<_>> = stream::iter(timeline_ids)
+        .map(|timeline_id| async move {
+            let index_part = download_index_part(conf, storage, &tenant_id, &timeline_id)
+                .await
+                .with_context(|| format!("download index part for timeline {timeline_id}"))?;
+            if index_part.deleted_at.is_some() {
+                return Ok(None);
+            }
+            let metadata = index_part
+                .parse_metadata()
+                .with_context(|| format!("parse the metadata of timeline {timeline_id}"))?;
+            anyhow::Ok(Some((timeline_id, metadata, index_part.index_generations)))
+        })
+        .buffer_unordered(LIST_INDEX_PART_CONCURRENCY)
+        .try_collect()
+        .await?;
+
+    let mut timelines: Vec<_> = timelines.into_iter().flatten().collect();
+    timelines.sort_by_key(|(timeline_id, ..)| *timeline_id);
+    Ok(timelines)
+}
+
 pub(super) async fn download_index_part(
     conf: &'static PageServerConf,
     storage: &GenericRemoteStorage,
     tenant_id: &TenantId,
     timeline_id: &TimelineId,
-) -> Result<IndexPart, DownloadError> {
+) -> Result<IndexPart, RemoteStorageError> {
+    download_index_part_file(conf, storage, tenant_id, timeline_id, IndexPart::FILE_NAME).await
+}
+
+/// Downloads the latest index part, or one of its generations, from `file_name`.
+#[instrument(skip_all, fields(bytes = tracing::field::Empty))]
+pub(super) async fn download_index_part_file(
+    conf: &'static PageServerConf,
+    storage: &GenericRemoteStorage,
+    tenant_id: &TenantId,
+    timeline_id: &TimelineId,
+    file_name: &str,
+) -> Result<IndexPart, RemoteStorageError> {
     let index_part_path = conf
         .metadata_path(tenant_id, timeline_id)
-        .with_file_name(IndexPart::FILE_NAME);
+        .with_file_name(file_name);
     let part_storage_path = conf
         .remote_path(&index_part_path)
-        .map_err(DownloadError::BadInput)?;
+        .map_err(RemoteStorageError::Permanent)?;
 
     let index_part_bytes = download_retry(
+        conf,
         || async {
-            let mut index_part_download = storage.download(&part_storage_path).await?;
+            let index_part_download = storage.download(&part_storage_path).await?;
+            let mut index_part_stream =
+                Compression::from_metadata(index_part_download.metadata.as_ref())
+                    .map_err(RemoteStorageError::from)?
+                    .decompress(index_part_download.download_stream);
 
             let mut index_part_bytes = Vec::new();
-            tokio::io::copy(
-                &mut index_part_download.download_stream,
-                &mut index_part_bytes,
-            )
-            .await
-            .with_context(|| {
-                format!("Failed to download an index part into file {index_part_path:?}")
-            })
-            .map_err(DownloadError::Other)?;
+            tokio::io::copy(&mut index_part_stream, &mut index_part_bytes)
+                .await
+                .with_context(|| {
+                    format!("Failed to download an index part into file {index_part_path:?}")
+                })
+                .map_err(RemoteStorageError::from)?;
+            REMOTE_DOWNLOAD_BYTES
+                .with_label_values(&[RemoteOpFileKind::Index.as_str()])
+                .inc_by(index_part_bytes.len() as u64);
+
+            if let Some(expected) = Checksum::from_metadata(index_part_download.metadata.as_ref()) {
+                let actual = checksum::checksum_bytes(expected.algorithm(), &index_part_bytes)
+                    .expect("checksums from the metadata always have an algorithm");
+                expected
+                    .verify(&actual)
+                    .with_context(|| {
+                        format!("Downloaded index part {part_storage_path:?} is corrupted")
+                    })
+                    .map_err(RemoteStorageError::from)?;
+            }
             Ok(index_part_bytes)
         },
         &format!("download {part_storage_path:?}"),
     )
     .await?;
 
+    tracing::Span::current().record("bytes", index_part_bytes.len());
     let index_part: IndexPart = serde_json::from_slice(&index_part_bytes)
         .with_context(|| {
             format!("Failed to deserialize index part file into file {index_part_path:?}")
         })
-        .map_err(DownloadError::Other)?;
+        .map_err(RemoteStorageError::from)?;
 
     Ok(index_part)
 }
 
+/// Downloads the manifest uploaded along with the latest index part.
+pub(super) async fn download_manifest(
+    conf: &'static PageServerConf,
+    storage: &GenericRemoteStorage,
+    tenant_id: &TenantId,
+    timeline_id: &TimelineId,
+) -> Result<BackupManifest, RemoteStorageError> {
+    let manifest_path = conf
+        .metadata_path(tenant_id, timeline_id)
+        .with_file_name(BackupManifest::FILE_NAME);
+    let manifest_storage_path = conf
+        .remote_path(&manifest_path)
+        .map_err(RemoteStorageError::Permanent)?;
+
+    let manifest_bytes = download_retry(
+        conf,
+        || async {
+            let download = storage.download(&manifest_storage_path).await?;
+            let mut manifest_stream = download.download_stream;
+            let mut manifest_bytes = Vec::new();
+            tokio::io::copy(&mut manifest_stream, &mut manifest_bytes)
+                .await
+                .context("Failed to download the backup manifest")
+                .map_err(RemoteStorageError::from)?;
+            REMOTE_DOWNLOAD_BYTES
+                .with_label_values(&[RemoteOpFileKind::Index.as_str()])
+                .inc_by(manifest_bytes.len() as u64);
+
+            if let Some(expected) = Checksum::from_metadata(download.metadata.as_ref()) {
+                let actual = checksum::checksum_bytes(expected.algorithm(), &manifest_bytes)
+                    .expect("checksums from the metadata always have an algorithm");
+                expected
+                    .verify(&actual)
+                    .with_context(|| {
+                        format!("Downloaded backup manifest {manifest_storage_path:?} is corrupted")
+                    })
+                    .map_err(RemoteStorageError::from)?;
+            }
+            Ok(manifest_bytes)
+        },
+        &format!("download {manifest_storage_path:?}"),
+    )
+    .await?;
+
+    serde_json::from_slice(&manifest_bytes)
+        .with_context(|| format!("Failed to deserialize backup manifest {manifest_path:?}"))
+        .map_err(RemoteStorageError::from)
+}
+
 /// Helper function to handle retries for a download operation.
 ///
 /// Remote operations can fail due to rate limits (IAM, S3), spurious network
-/// problems, or other external reasons. Retry FAILED_DOWNLOAD_RETRIES times,
-/// with backoff.
+/// problems, or other external reasons. Retry up to `max_retries` times, with
+/// jittered exponential backoff, unless the error is permanent. An attempt that downloads nothing
+/// for `operation_timeout` is cancelled and counts as a failed one.
 ///
 /// (See similar logic for uploads in `perform_upload_task`)
-async fn download_retry<T, O, F>(op: O, description: &str) -> Result<T, DownloadError>
+async fn download_retry<T, O, F>(
+    conf: &PageServerConf,
+    mut op: O,
+    description: &str,
+) -> Result<T, RemoteStorageError>
 where
     O: FnMut() -> F,
-    F: Future<Output = Result<T, DownloadError>>,
+    F: Future<Output = Result<T, RemoteStorageError>>,
 {
-    backoff::retry(
-        op,
-        |e| matches!(e, DownloadError::BadInput(_) | DownloadError::NotFound),
-        FAILED_DOWNLOAD_WARN_THRESHOLD,
-        FAILED_REMOTE_OP_RETRIES,
-        description,
-    )
-    .await
+    let retry_settings = RemoteOpRetrySettings::from_conf(conf);
+    retry_settings
+        .retry(
+            || {
+                with_timeout(retry_settings.operation_timeout, op(), |_| {
+                    RemoteStorageError::Timeout
+                })
+            },
+            RemoteStorageError::is_permanent,
+            FAILED_DOWNLOAD_WARN_THRESHOLD,
+            description,
+        )
+        .await
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tenant::harness::TenantHarness;
+    use crate::tenant::remote_timeline_client::checksum::{checksum_bytes, ChecksumAlgorithm};
+    use crate::tenant::remote_timeline_client::upload::upload_index_part;
+    use remote_storage::LocalFs;
+    use std::collections::HashMap;
+    use utils::lsn::Lsn;
+
+    #[tokio::test]
+    async fn chunked_download_assembles_the_layer() -> anyhow::Result<()> {
+        let storage_root = tempfile::tempdir()?;
+        let storage = GenericRemoteStorage::LocalFs(LocalFs::new(storage_root.path().to_owned())?);
+        let local_dir = tempfile::tempdir()?;
+
+        // Not a multiple of the chunk count, so the last chunk is shorter.
+        let layer: Vec<u8> = (0..1_000_003u32).map(|i| (i % 251) as u8).collect();
+        let remote_path = RemotePath::from_string("tenant/timeline/layer")?;
+        let checksum = checksum_bytes(ChecksumAlgorithm::Sha256, &layer).unwrap();
+        storage
+            .upload(
+                std::io::Cursor::new(layer.clone()),
+                layer.len(),
+                &remote_path,
+                Some(checksum.to_metadata()),
+            )
+            .await?;
+
+        let temp_file_path = local_dir.path().join("layer.temp_download");
+        let bytes_done = AtomicU64::new(0);
+        let file = download_layer_chunks(
+            &storage,
+            &remote_path,
+            &temp_file_path,
+            layer.len() as u64,
+            7,
+            &bytes_done,
+        )
+        .await?
+        .expect("uncompressed layer should be downloaded in chunks");
+        drop(file);
+
+        assert!(std::fs::read(&temp_file_path)? == layer);
+        assert_eq!(bytes_done.load(Ordering::Relaxed), layer.len() as u64);
+
+        // A compressed object is left to the download in one piece.
+        let compressed_path = RemotePath::from_string("tenant/timeline/compressed_layer")?;
+        let mut compressed = Vec::new();
+        Compression::Zstd
+            .compress(layer.as_slice(), &mut compressed)
+            .await?;
+        let compressed_size = compressed.len();
+        storage
+            .upload(
+                std::io::Cursor::new(compressed),
+                compressed_size,
+                &compressed_path,
+                Compression::Zstd.record_in_metadata(None),
+            )
+            .await?;
+        let compressed_temp_file_path = local_dir.path().join("compressed_layer.temp_download");
+        let file = download_layer_chunks(
+            &storage,
+            &compressed_path,
+            &compressed_temp_file_path,
+            layer.len() as u64,
+            7,
+            &AtomicU64::new(0),
+        )
+        .await?;
+        assert!(file.is_none());
+        assert!(!compressed_temp_file_path.exists());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn chunk_checksums_catch_corrupted_ranges() -> anyhow::Result<()> {
+        let storage_root = tempfile::tempdir()?;
+        let storage = GenericRemoteStorage::LocalFs(LocalFs::new(storage_root.path().to_owned())?);
+        let local_dir = tempfile::tempdir()?;
+
+        let layer: Vec<u8> = (0..100_003u32).map(|i| (i % 251) as u8).collect();
+        let layer_path = local_dir.path().join("layer");
+        std::fs::write(&layer_path, &layer)?;
+        let chunk_checksums = ChunkChecksums::of_file(
+            ChecksumAlgorithm::Crc32c,
+            4096,
+            &mut fs::File::open(&layer_path).await?,
+        )
+        .await?
+        .unwrap();
+        let remote_path = RemotePath::from_string("tenant/timeline/layer")?;
+        let chunk_checksums_bytes = serde_json::to_vec(&chunk_checksums)?;
+        let chunk_checksums_size = chunk_checksums_bytes.len();
+        storage
+            .upload(
+                std::io::Cursor::new(chunk_checksums_bytes),
+                chunk_checksums_size,
+                &ChunkChecksums::remote_path(&remote_path),
+                None,
+            )
+            .await?;
+
+        // The third chunk stays corrupted, however many times it's downloaded.
+        let mut corrupted = layer.clone();
+        corrupted[2 * 4096 + 10] ^= 0xff;
+        storage
+            .upload(
+                std::io::Cursor::new(corrupted.clone()),
+                corrupted.len(),
+                &remote_path,
+                None,
+            )
+            .await?;
+        let temp_file_path = local_dir.path().join("layer.temp_download");
+        let error = download_layer_chunks(
+            &storage,
+            &remote_path,
+            &temp_file_path,
+            layer.len() as u64,
+            3,
+            &AtomicU64::new(0),
+        )
+        .await
+        .expect_err("the corrupted chunk should fail the download");
+        assert!(error.to_string().contains("8192..12288"), "{error}");
+
+        storage
+            .upload(
+                std::io::Cursor::new(layer.clone()),
+                layer.len(),
+                &remote_path,
+                None,
+            )
+            .await?;
+        download_layer_chunks(
+            &storage,
+            &remote_path,
+            &temp_file_path,
+            layer.len() as u64,
+            3,
+            &AtomicU64::new(0),
+        )
+        .await?
+        .expect("uncompressed layer should be downloaded in chunks");
+        assert!(std::fs::read(&temp_file_path)? == layer);
+
+        // Only the whole chunks before the corrupted one are resumed from.
+        std::fs::write(&temp_file_path, &corrupted[..3 * 4096 + 100])?;
+        let partial_size = verify_partial_download(
+            &storage,
+            &remote_path,
+            &temp_file_path,
+            3 * 4096 + 100,
+            layer.len() as u64,
+        )
+        .await?;
+        assert_eq!(partial_size, 2 * 4096);
+        assert!(std::fs::read(&temp_file_path)? == layer[..2 * 4096]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn downloads_are_moved_across_filesystems() -> anyhow::Result<()> {
+        use std::os::unix::fs::MetadataExt;
+
+        let temp_dir = tempfile::tempdir()?;
+        let timeline_dir = tempfile::tempdir()?;
+        let local_path = timeline_dir.path().join("layer");
+
+        // The fallback for a rename across filesystems, forced.
+        let temp_file_path = temp_dir.path().join("layer.temp_download");
+        std::fs::write(&temp_file_path, b"layer contents")?;
+        copy_into_place(&temp_file_path, &local_path, true).await?;
+        assert_eq!(std::fs::read(&local_path)?, b"layer contents");
+        assert!(!temp_file_path.exists());
+        assert!(!path_with_suffix_extension(&local_path, TEMP_DOWNLOAD_EXTENSION).exists());
+
+        // A real cross-device rename, if the machine has a tmpfs on another device.
+        let shm = Path::new("/dev/shm");
+        if shm.is_dir() && shm.metadata()?.dev() != timeline_dir.path().metadata()?.dev() {
+            let shm_dir = tempfile::tempdir_in(shm)?;
+            let temp_file_path = shm_dir.path().join("layer.temp_download");
+            std::fs::write(&temp_file_path, b"new layer contents")?;
+            move_into_place(&temp_file_path, &local_path, true).await?;
+            assert_eq!(std::fs::read(&local_path)?, b"new layer contents");
+            assert!(!temp_file_path.exists());
+        }
+
+        // On the same filesystem, it's a plain rename.
+        let temp_file_path = timeline_dir.path().join("other_layer.temp_download");
+        std::fs::write(&temp_file_path, b"other layer contents")?;
+        move_into_place(
+            &temp_file_path,
+            &timeline_dir.path().join("other_layer"),
+            true,
+        )
+        .await?;
+        assert_eq!(
+            std::fs::read(timeline_dir.path().join("other_layer"))?,
+            b"other layer contents"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn list_remote_timelines_with_their_metadata() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("list_remote_timelines_with_their_metadata")?;
+        let storage_root = tempfile::tempdir()?;
+        let storage = GenericRemoteStorage::LocalFs(LocalFs::new(storage_root.path().to_owned())?);
+
+        let metadata = |ancestor: Option<(TimelineId, Lsn)>, disk_consistent_lsn| {
+            let metadata = TimelineMetadata::new(
+                disk_consistent_lsn,
+                None,
+                ancestor.map(|(timeline_id, _)| timeline_id),
+                ancestor.map_or(Lsn(0), |(_, lsn)| lsn),
+                Lsn(0x10),
+                Lsn(0x10),
+                crate::DEFAULT_PG_VERSION,
+                utils::id::RegionId(0),
+            );
+            // go through serialize + deserialize to fix the header, as the listing gets it
+            TimelineMetadata::from_bytes(&metadata.to_bytes().unwrap()).unwrap()
+        };
+        let upload = |timeline_id, metadata: TimelineMetadata, deleted: bool| {
+            let storage = &storage;
+            let tenant_id = harness.tenant_id;
+            let conf = harness.conf;
+            async move {
+                let mut index_part = IndexPart::new(
+                    HashMap::new(),
+                    metadata.disk_consistent_lsn(),
+                    metadata.to_bytes()?,
+                );
+                if deleted {
+                    index_part.deleted_at = Some(chrono::Utc::now().naive_utc());
+                }
+                index_part.index_generations = vec![Lsn(0x10), metadata.disk_consistent_lsn()];
+                upload_index_part(
+                    conf,
+                    storage,
+                    &tenant_id,
+                    &timeline_id,
+                    &index_part,
+                    Vec::new(),
+                )
+                .await
+            }
+        };
+
+        let main_id = TimelineId::from_array([1; 16]);
+        let deleted_id = TimelineId::from_array([2; 16]);
+        let branch_id = TimelineId::from_array([3; 16]);
+        let main = metadata(None, Lsn(0x40));
+        let branch = metadata(Some((main_id, Lsn(0x30))), Lsn(0x50));
+        upload(branch_id, branch.clone(), false).await?;
+        upload(main_id, main.clone(), false).await?;
+        upload(deleted_id, metadata(None, Lsn(0x20)), true).await?;
+
+        let timelines =
+            list_remote_timelines_with_metadata(&storage, harness.conf, harness.tenant_id).await?;
+        assert_eq!(
+            timelines,
+            vec![
+                (main_id, main, vec![Lsn(0x10), Lsn(0x40)]),
+                (branch_id, branch, vec![Lsn(0x10), Lsn(0x50)])
+            ]
+        );
+        Ok(())
+    }
 }
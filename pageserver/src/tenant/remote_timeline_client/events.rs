@@ -0,0 +1,70 @@
+//! Notifications about the finished remote operations of all timelines, for the code that
+//! wants to react to them, e.g. to mark a backup complete, instead of polling the sync status.
+//!
+//! The events are best-effort: nothing waits for the subscribers, an event sent while no one
+//! is subscribed is lost, and a subscriber that falls behind by more than
+//! [`SYNC_EVENTS_CAPACITY`] events misses the oldest ones.
+
+use once_cell::sync::Lazy;
+use tokio::sync::broadcast;
+use utils::id::{TenantId, TimelineId};
+use utils::lsn::Lsn;
+
+use crate::tenant::storage_layer::LayerFileName;
+
+pub const SYNC_EVENTS_CAPACITY: usize = 1024;
+
+static SYNC_EVENTS: Lazy<broadcast::Sender<SyncEvent>> =
+    Lazy::new(|| broadcast::channel(SYNC_EVENTS_CAPACITY).0);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SyncEvent {
+    /// The index part of the timeline is uploaded: everything up to `disk_consistent_lsn` is
+    /// in the remote storage.
+    UploadCompleted {
+        tenant_id: TenantId,
+        timeline_id: TimelineId,
+        disk_consistent_lsn: Lsn,
+    },
+    /// A layer of the timeline is downloaded.
+    DownloadCompleted {
+        tenant_id: TenantId,
+        timeline_id: TimelineId,
+        layer_file_name: LayerFileName,
+    },
+}
+
+/// Returns a receiver of the events sent from now on.
+pub fn subscribe_sync_events() -> broadcast::Receiver<SyncEvent> {
+    SYNC_EVENTS.subscribe()
+}
+
+pub(crate) fn send_sync_event(event: SyncEvent) {
+    // Fails only if there are no subscribers, then there is no one to tell.
+    let _ = SYNC_EVENTS.send(event);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_reach_the_subscribers() {
+        let tenant_id = TenantId::generate();
+        let timeline_id = TimelineId::generate();
+        let upload = SyncEvent::UploadCompleted {
+            tenant_id,
+            timeline_id,
+            disk_consistent_lsn: Lsn(0x10),
+        };
+
+        // no one to send it to, doesn't block or fail
+        send_sync_event(upload.clone());
+
+        let mut events = subscribe_sync_events();
+        send_sync_event(upload.clone());
+        // Other tests might send their events meanwhile.
+        let received = std::iter::from_fn(|| events.try_recv().ok()).find(|event| *event == upload);
+        assert_eq!(received, Some(upload));
+    }
+}
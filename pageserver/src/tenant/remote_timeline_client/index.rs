@@ -10,6 +10,8 @@ use serde_with::{serde_as, DisplayFromStr};
 use utils::bin_ser::SerializeError;
 
 use crate::tenant::metadata::TimelineMetadata;
+use crate::tenant::remote_timeline_client::checksum::Checksum;
+use crate::tenant::remote_timeline_client::parts::LayerParts;
 use crate::tenant::storage_layer::LayerFileName;
 use crate::tenant::upload_queue::UploadQueueInitialized;
 
@@ -23,24 +25,77 @@ use utils::lsn::Lsn;
 #[cfg_attr(test, derive(Default))]
 pub struct LayerFileMetadata {
     file_size: u64,
+    archive: Option<LayerArchiveLocation>,
+    parts: Option<LayerParts>,
+    checksum: Option<Checksum>,
 }
 
 impl From<&'_ IndexLayerMetadata> for LayerFileMetadata {
     fn from(other: &IndexLayerMetadata) -> Self {
         LayerFileMetadata {
             file_size: other.file_size,
+            archive: other.archive.clone(),
+            parts: other.parts,
+            checksum: other.checksum.clone(),
         }
     }
 }
 
 impl LayerFileMetadata {
     pub fn new(file_size: u64) -> Self {
-        LayerFileMetadata { file_size }
+        LayerFileMetadata {
+            file_size,
+            archive: None,
+            parts: None,
+            checksum: None,
+        }
     }
 
     pub fn file_size(&self) -> u64 {
         self.file_size
     }
+
+    /// Where the layer is in the remote storage, if it's packed into an archive object.
+    pub fn archive(&self) -> Option<&LayerArchiveLocation> {
+        self.archive.as_ref()
+    }
+
+    pub(crate) fn with_archive(self, archive: LayerArchiveLocation) -> Self {
+        LayerFileMetadata {
+            archive: Some(archive),
+            ..self
+        }
+    }
+
+    /// How the layer is split into several objects in the remote storage, if it is.
+    pub(super) fn parts(&self) -> Option<&LayerParts> {
+        self.parts.as_ref()
+    }
+
+    pub(super) fn with_parts(self, parts: Option<LayerParts>) -> Self {
+        LayerFileMetadata { parts, ..self }
+    }
+
+    /// Checksum of the layer's contents, known once the layer is uploaded as a single object.
+    pub(super) fn checksum(&self) -> Option<&Checksum> {
+        self.checksum.as_ref()
+    }
+
+    pub(super) fn with_checksum(self, checksum: Checksum) -> Self {
+        LayerFileMetadata {
+            checksum: Some(checksum),
+            ..self
+        }
+    }
+}
+
+/// The byte range of an archive object that holds a layer, see `remote_layer_archive_threshold`.
+/// The range is as long as the layer file.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LayerArchiveLocation {
+    /// Name of the archive object in the timeline's remote directory.
+    pub name: String,
+    pub offset: u64,
 }
 
 // TODO seems like another part of the remote storage file format
@@ -73,6 +128,34 @@ pub struct IndexPart {
     /// that latest version stores.
     pub layer_metadata: HashMap<LayerFileName, IndexLayerMetadata>,
 
+    /// Layers no longer used by the timeline, but kept in the remote storage for the restores
+    /// to any of the `retained_lsns`, along with the last `disk_consistent_lsn` they were used at.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    #[serde_as(as = "HashMap<_, DisplayFromStr>")]
+    pub superseded_layers: HashMap<LayerFileName, Lsn>,
+
+    /// The most recent `disk_consistent_lsn`s of the uploaded index parts, oldest first.
+    /// Empty, unless the remote GC retention is enabled with `remote_gc_retained_lsns`.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub retained_lsns: Vec<Lsn>,
+
+    /// The `disk_consistent_lsn`s of the index parts kept as files of their own, see
+    /// [`IndexPart::generation_file_name`], oldest first. The last one is this index part's,
+    /// unless it's empty: `remote_index_generations` is 0.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    #[serde_as(as = "Vec<DisplayFromStr>")]
+    pub index_generations: Vec<Lsn>,
+
+    /// The archive objects in the timeline's remote directory, with the layers packed into them
+    /// that are still in the remote storage. An archive is deleted along with its last layer.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "HashMap::is_empty")]
+    pub layer_archives: HashMap<String, HashSet<LayerFileName>>,
+
     // 'disk_consistent_lsn' is a copy of the 'disk_consistent_lsn' in the metadata.
     // It's duplicated here for convenience.
     #[serde_as(as = "DisplayFromStr")]
@@ -85,9 +168,29 @@ impl IndexPart {
     /// used to understand later versions.
     ///
     /// Version is currently informative only.
-    const LATEST_VERSION: usize = 2;
+    const LATEST_VERSION: usize = 7;
     pub const FILE_NAME: &'static str = "index_part.json";
 
+    /// Name of the copy of the index part uploaded at `disk_consistent_lsn`, next to the latest
+    /// one in [`IndexPart::FILE_NAME`].
+    pub fn generation_file_name(disk_consistent_lsn: Lsn) -> String {
+        format!("index_part_{:016X}.json", disk_consistent_lsn.0)
+    }
+
+    /// Whether the object is an index part, the latest one or one of its generations.
+    pub fn is_index_file_name(name: &str) -> bool {
+        name == Self::FILE_NAME
+            || name
+                .strip_prefix("index_part_")
+                .and_then(|name| name.strip_suffix(".json"))
+                .is_some_and(|lsn| lsn.len() == 16 && u64::from_str_radix(lsn, 16).is_ok())
+    }
+
+    /// Whether this index part is to be uploaded as a generation of its own as well.
+    pub(super) fn is_generation(&self) -> bool {
+        self.index_generations.last() == Some(&self.disk_consistent_lsn)
+    }
+
     pub fn new(
         layers_and_metadata: HashMap<LayerFileName, LayerFileMetadata>,
         disk_consistent_lsn: Lsn,
@@ -109,6 +212,10 @@ impl IndexPart {
             disk_consistent_lsn,
             metadata_bytes,
             deleted_at: None,
+            superseded_layers: HashMap::new(),
+            retained_lsns: Vec::new(),
+            index_generations: Vec::new(),
+            layer_archives: HashMap::new(),
         }
     }
 
@@ -124,24 +231,46 @@ impl TryFrom<&UploadQueueInitialized> for IndexPart {
         let disk_consistent_lsn = upload_queue.latest_metadata.disk_consistent_lsn();
         let metadata_bytes = upload_queue.latest_metadata.to_bytes()?;
 
-        Ok(Self::new(
+        let mut index_part = Self::new(
             upload_queue.latest_files.clone(),
             disk_consistent_lsn,
             metadata_bytes,
-        ))
+        );
+        index_part.superseded_layers = upload_queue.superseded_layers.clone();
+        index_part.retained_lsns = upload_queue.retained_lsns.iter().copied().collect();
+        index_part.index_generations = upload_queue.index_generations.iter().copied().collect();
+        index_part.layer_archives = upload_queue.layer_archives.clone();
+        Ok(index_part)
     }
 }
 
 /// Serialized form of [`LayerFileMetadata`].
+#[serde_as]
 #[derive(Debug, PartialEq, Eq, Clone, Serialize, Deserialize, Default)]
 pub struct IndexLayerMetadata {
     pub(super) file_size: u64,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) archive: Option<LayerArchiveLocation>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(super) parts: Option<LayerParts>,
+
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    pub(super) checksum: Option<Checksum>,
 }
 
 impl From<&'_ LayerFileMetadata> for IndexLayerMetadata {
     fn from(other: &'_ LayerFileMetadata) -> Self {
         IndexLayerMetadata {
             file_size: other.file_size,
+            archive: other.archive.clone(),
+            parts: other.parts,
+            checksum: other.checksum.clone(),
         }
     }
 }
@@ -170,16 +299,26 @@ mod tests {
             layer_metadata: HashMap::from([
                 ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap(), IndexLayerMetadata {
                     file_size: 25600000,
+                    archive: None,
+                    parts: None,
+                    checksum: None,
                 }),
                 ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap(), IndexLayerMetadata {
                     // serde_json should always parse this but this might be a double with jq for
                     // example.
                     file_size: 9007199254741001,
+                    archive: None,
+                    parts: None,
+                    checksum: None,
                 })
             ]),
             disk_consistent_lsn: "0/16960E8".parse::<Lsn>().unwrap(),
             metadata_bytes: [113,11,159,210,0,54,0,4,0,0,0,0,1,105,96,232,1,0,0,0,0,1,105,96,112,0,0,0,0,0,0,0,0,0,0,0,0,0,1,105,96,112,0,0,0,0,1,105,96,112,0,0,0,14,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0].to_vec(),
             deleted_at: None,
+            layer_archives: HashMap::new(),
+            superseded_layers: HashMap::new(),
+            retained_lsns: Vec::new(),
+            index_generations: Vec::new(),
         };
 
         let part = serde_json::from_str::<IndexPart>(example).unwrap();
@@ -207,16 +346,26 @@ mod tests {
             layer_metadata: HashMap::from([
                 ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap(), IndexLayerMetadata {
                     file_size: 25600000,
+                    archive: None,
+                    parts: None,
+                    checksum: None,
                 }),
                 ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap(), IndexLayerMetadata {
                     // serde_json should always parse this but this might be a double with jq for
                     // example.
                     file_size: 9007199254741001,
+                    archive: None,
+                    parts: None,
+                    checksum: None,
                 })
             ]),
             disk_consistent_lsn: "0/16960E8".parse::<Lsn>().unwrap(),
             metadata_bytes: [112,11,159,210,0,54,0,4,0,0,0,0,1,105,96,232,1,0,0,0,0,1,105,96,112,0,0,0,0,0,0,0,0,0,0,0,0,0,1,105,96,112,0,0,0,0,1,105,96,112,0,0,0,14,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0].to_vec(),
             deleted_at: None,
+            layer_archives: HashMap::new(),
+            superseded_layers: HashMap::new(),
+            retained_lsns: Vec::new(),
+            index_generations: Vec::new(),
         };
 
         let part = serde_json::from_str::<IndexPart>(example).unwrap();
@@ -245,23 +394,287 @@ mod tests {
             layer_metadata: HashMap::from([
                 ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap(), IndexLayerMetadata {
                     file_size: 25600000,
+                    archive: None,
+                    parts: None,
+                    checksum: None,
                 }),
                 ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap(), IndexLayerMetadata {
                     // serde_json should always parse this but this might be a double with jq for
                     // example.
                     file_size: 9007199254741001,
+                    archive: None,
+                    parts: None,
+                    checksum: None,
                 })
             ]),
             disk_consistent_lsn: "0/16960E8".parse::<Lsn>().unwrap(),
             metadata_bytes: [112,11,159,210,0,54,0,4,0,0,0,0,1,105,96,232,1,0,0,0,0,1,105,96,112,0,0,0,0,0,0,0,0,0,0,0,0,0,1,105,96,112,0,0,0,0,1,105,96,112,0,0,0,14,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0,0].to_vec(),
             deleted_at: Some(chrono::NaiveDateTime::parse_from_str(
-                "2023-07-31T09:00:00.123000000", "%Y-%m-%dT%H:%M:%S.%f").unwrap())
+                "2023-07-31T09:00:00.123000000", "%Y-%m-%dT%H:%M:%S.%f").unwrap()),
+            layer_archives: HashMap::new(),
+            superseded_layers: HashMap::new(),
+            retained_lsns: Vec::new(),
+            index_generations: Vec::new(),
         };
 
         let part = serde_json::from_str::<IndexPart>(example).unwrap();
         assert_eq!(part, expected);
     }
 
+    #[test]
+    fn v3_indexpart_is_parsed_with_superseded_layers() {
+        let example = r#"{
+            "version":3,
+            "timeline_layers":["000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9"],
+            "layer_metadata":{
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9": { "file_size": 25600000 }
+            },
+            "superseded_layers":{
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51": "0/16960E8"
+            },
+            "retained_lsns":["0/16960E8","0/16B5A52"],
+            "disk_consistent_lsn":"0/16B5A52",
+            "metadata_bytes":[]
+        }"#;
+
+        let expected = IndexPart {
+            version: 3,
+            timeline_layers: HashSet::from(["000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap()]),
+            layer_metadata: HashMap::from([
+                ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap(), IndexLayerMetadata {
+                    file_size: 25600000,
+                    archive: None,
+                    parts: None,
+                    checksum: None,
+                }),
+            ]),
+            disk_consistent_lsn: "0/16B5A52".parse::<Lsn>().unwrap(),
+            metadata_bytes: Vec::new(),
+            deleted_at: None,
+            layer_archives: HashMap::new(),
+            superseded_layers: HashMap::from([
+                ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap(), "0/16960E8".parse::<Lsn>().unwrap()),
+            ]),
+            retained_lsns: vec![
+                "0/16960E8".parse::<Lsn>().unwrap(),
+                "0/16B5A52".parse::<Lsn>().unwrap(),
+            ],
+            index_generations: Vec::new(),
+        };
+
+        let part = serde_json::from_str::<IndexPart>(example).unwrap();
+        assert_eq!(part, expected);
+
+        let roundtripped =
+            serde_json::from_slice::<IndexPart>(&serde_json::to_vec(&part).unwrap()).unwrap();
+        assert_eq!(roundtripped, expected);
+    }
+
+    #[test]
+    fn v4_indexpart_is_parsed_with_layer_archives() {
+        let example = r#"{
+            "version":4,
+            "timeline_layers":[
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9",
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51"
+            ],
+            "layer_metadata":{
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9": { "file_size": 25600000 },
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51": { "file_size": 8192, "archive": { "name": "layers-00000000016B5A52-1.archive", "offset": 4096 } }
+            },
+            "layer_archives":{
+                "layers-00000000016B5A52-1.archive": ["000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51"]
+            },
+            "disk_consistent_lsn":"0/16B5A52",
+            "metadata_bytes":[]
+        }"#;
+
+        let archived: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let expected = IndexPart {
+            version: 4,
+            timeline_layers: HashSet::from([
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap(),
+                archived.clone(),
+            ]),
+            layer_metadata: HashMap::from([
+                ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap(), IndexLayerMetadata {
+                    file_size: 25600000,
+                    archive: None,
+                    parts: None,
+                    checksum: None,
+                }),
+                (archived.clone(), IndexLayerMetadata {
+                    file_size: 8192,
+                    archive: Some(LayerArchiveLocation {
+                        name: "layers-00000000016B5A52-1.archive".to_string(),
+                        offset: 4096,
+                    }),
+                    parts: None,
+                    checksum: None,
+                }),
+            ]),
+            disk_consistent_lsn: "0/16B5A52".parse::<Lsn>().unwrap(),
+            metadata_bytes: Vec::new(),
+            deleted_at: None,
+            superseded_layers: HashMap::new(),
+            retained_lsns: Vec::new(),
+            index_generations: Vec::new(),
+            layer_archives: HashMap::from([(
+                "layers-00000000016B5A52-1.archive".to_string(),
+                HashSet::from([archived]),
+            )]),
+        };
+
+        let part = serde_json::from_str::<IndexPart>(example).unwrap();
+        assert_eq!(part, expected);
+
+        let roundtripped =
+            serde_json::from_slice::<IndexPart>(&serde_json::to_vec(&part).unwrap()).unwrap();
+        assert_eq!(roundtripped, expected);
+    }
+
+    #[test]
+    fn v5_indexpart_is_parsed_with_layer_parts() {
+        let example = r#"{
+            "version":5,
+            "timeline_layers":["000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9"],
+            "layer_metadata":{
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9": { "file_size": 25600000, "parts": { "file_size": 25600000, "part_size": 10485760 } }
+            },
+            "disk_consistent_lsn":"0/16960E8",
+            "metadata_bytes":[]
+        }"#;
+
+        let expected = IndexPart {
+            version: 5,
+            timeline_layers: HashSet::from(["000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap()]),
+            layer_metadata: HashMap::from([
+                ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap(), IndexLayerMetadata {
+                    file_size: 25600000,
+                    archive: None,
+                    parts: Some(LayerParts {
+                        file_size: 25600000,
+                        part_size: 10485760,
+                    }),
+                    checksum: None,
+                }),
+            ]),
+            disk_consistent_lsn: "0/16960E8".parse::<Lsn>().unwrap(),
+            metadata_bytes: Vec::new(),
+            deleted_at: None,
+            superseded_layers: HashMap::new(),
+            retained_lsns: Vec::new(),
+            index_generations: Vec::new(),
+            layer_archives: HashMap::new(),
+        };
+
+        let part = serde_json::from_str::<IndexPart>(example).unwrap();
+        assert_eq!(part, expected);
+
+        let roundtripped =
+            serde_json::from_slice::<IndexPart>(&serde_json::to_vec(&part).unwrap()).unwrap();
+        assert_eq!(roundtripped, expected);
+    }
+
+    #[test]
+    fn v6_indexpart_is_parsed_with_index_generations() {
+        let example = r#"{
+            "version":6,
+            "timeline_layers":["000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9"],
+            "layer_metadata":{
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9": { "file_size": 25600000 }
+            },
+            "retained_lsns":["0/16960E8","0/16B5A52"],
+            "index_generations":["0/16960E8","0/16B5A52"],
+            "disk_consistent_lsn":"0/16B5A52",
+            "metadata_bytes":[]
+        }"#;
+
+        let expected = IndexPart {
+            version: 6,
+            timeline_layers: HashSet::from(["000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap()]),
+            layer_metadata: HashMap::from([
+                ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap(), IndexLayerMetadata {
+                    file_size: 25600000,
+                    archive: None,
+                    parts: None,
+                    checksum: None,
+                }),
+            ]),
+            disk_consistent_lsn: "0/16B5A52".parse::<Lsn>().unwrap(),
+            metadata_bytes: Vec::new(),
+            deleted_at: None,
+            superseded_layers: HashMap::new(),
+            retained_lsns: vec![
+                "0/16960E8".parse::<Lsn>().unwrap(),
+                "0/16B5A52".parse::<Lsn>().unwrap(),
+            ],
+            index_generations: vec![
+                "0/16960E8".parse::<Lsn>().unwrap(),
+                "0/16B5A52".parse::<Lsn>().unwrap(),
+            ],
+            layer_archives: HashMap::new(),
+        };
+
+        let part = serde_json::from_str::<IndexPart>(example).unwrap();
+        assert_eq!(part, expected);
+        assert!(part.is_generation());
+
+        let roundtripped =
+            serde_json::from_slice::<IndexPart>(&serde_json::to_vec(&part).unwrap()).unwrap();
+        assert_eq!(roundtripped, expected);
+    }
+
+    #[test]
+    fn v7_indexpart_is_parsed_with_layer_checksums() {
+        let example = r#"{
+            "version":7,
+            "timeline_layers":["000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9"],
+            "layer_metadata":{
+                "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9": { "file_size": 25600000, "checksum": "crc32c:8a9136aa" }
+            },
+            "disk_consistent_lsn":"0/16960E8",
+            "metadata_bytes":[]
+        }"#;
+
+        let expected = IndexPart {
+            version: 7,
+            timeline_layers: HashSet::from(["000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap()]),
+            layer_metadata: HashMap::from([
+                ("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9".parse().unwrap(), IndexLayerMetadata {
+                    file_size: 25600000,
+                    archive: None,
+                    parts: None,
+                    checksum: Some("crc32c:8a9136aa".parse().unwrap()),
+                }),
+            ]),
+            disk_consistent_lsn: "0/16960E8".parse::<Lsn>().unwrap(),
+            metadata_bytes: Vec::new(),
+            deleted_at: None,
+            superseded_layers: HashMap::new(),
+            retained_lsns: Vec::new(),
+            index_generations: Vec::new(),
+            layer_archives: HashMap::new(),
+        };
+
+        let part = serde_json::from_str::<IndexPart>(example).unwrap();
+        assert_eq!(part, expected);
+
+        let roundtripped =
+            serde_json::from_slice::<IndexPart>(&serde_json::to_vec(&part).unwrap()).unwrap();
+        assert_eq!(roundtripped, expected);
+    }
+
+    #[test]
+    fn index_generation_file_names() {
+        let name = IndexPart::generation_file_name(Lsn(0x16B5A52));
+        assert_eq!(name, "index_part_00000000016B5A52.json");
+        assert!(IndexPart::is_index_file_name(&name));
+        assert!(IndexPart::is_index_file_name(IndexPart::FILE_NAME));
+        assert!(!IndexPart::is_index_file_name("index_part_16B5A52.json"));
+        assert!(!IndexPart::is_index_file_name("index_part.json.tmp"));
+    }
+
     #[test]
     fn empty_layers_are_parsed() {
         let empty_layers_json = r#"{
@@ -301,6 +714,10 @@ mod tests {
             ]
             .to_vec(),
             deleted_at: None,
+            layer_archives: HashMap::new(),
+            superseded_layers: HashMap::new(),
+            retained_lsns: Vec::new(),
+            index_generations: Vec::new(),
         };
 
         let empty_layers_parsed = serde_json::from_str::<IndexPart>(empty_layers_json).unwrap();
@@ -0,0 +1,231 @@
+//! Machine-readable description of a timeline image in the remote storage, for the tools
+//! outside of the pageserver, e.g. audits or copies to another region.
+//!
+//! Every index part upload writes [`BackupManifest::FILE_NAME`] next to the index part, before
+//! it: plain JSON, uncompressed, listing the index part and the layers it refers to, with their
+//! sizes and checksums, and the codec the objects are compressed with. The sizes and checksums
+//! are of the uncompressed contents, the same as the downloads get.
+//!
+//! The small layers may be compressed with the tenant's zstd dictionaries, the manifest lists
+//! the ones the pageserver knows of, in the tenant's `zstd_dictionaries` directory.
+//!
+//! A layer's checksum is known once the layer is uploaded as a single object, so it's missing
+//! for the layers split into parts (each part has its own checksum) and for the layers uploaded
+//! before the manifests were.
+//!
+//! Readers ignore the fields they don't know, the `version` tells which ones to expect.
+
+use std::collections::BTreeMap;
+
+use anyhow::{ensure, Context};
+use remote_storage::Compression;
+use serde::{Deserialize, Serialize};
+use serde_with::{serde_as, DisplayFromStr};
+use utils::id::{TenantId, TimelineId};
+use utils::lsn::Lsn;
+
+use super::checksum::Checksum;
+use super::index::IndexPart;
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct BackupManifest {
+    version: usize,
+    #[serde_as(as = "DisplayFromStr")]
+    tenant_id: TenantId,
+    #[serde_as(as = "DisplayFromStr")]
+    timeline_id: TimelineId,
+    #[serde_as(as = "DisplayFromStr")]
+    disk_consistent_lsn: Lsn,
+    /// Codec of the objects uploaded along with this manifest. Every object records its own
+    /// codec as well, the older ones may have another.
+    #[serde_as(as = "DisplayFromStr")]
+    compression: Compression,
+    index_part: ManifestFile,
+    /// The layers of the timeline image by file name.
+    layers: BTreeMap<String, ManifestFile>,
+    /// Names of the zstd dictionaries that the layers may be compressed with, each object
+    /// names its own in its metadata.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    zstd_dictionaries: Vec<String>,
+}
+
+#[serde_as]
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct ManifestFile {
+    size: u64,
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    #[serde_as(as = "Option<DisplayFromStr>")]
+    checksum: Option<Checksum>,
+    /// The archive object the layer is packed into, if it is.
+    #[serde(default)]
+    #[serde(skip_serializing_if = "Option::is_none")]
+    archive: Option<String>,
+}
+
+impl BackupManifest {
+    const LATEST_VERSION: usize = 1;
+    pub const FILE_NAME: &'static str = "backup_manifest.json";
+
+    /// The manifest of `index_part`, which serializes into `index_part_size` bytes with
+    /// `index_part_checksum`.
+    pub(super) fn new(
+        tenant_id: TenantId,
+        timeline_id: TimelineId,
+        index_part: &IndexPart,
+        index_part_size: u64,
+        index_part_checksum: Option<Checksum>,
+        compression: Compression,
+        zstd_dictionaries: Vec<String>,
+    ) -> Self {
+        let layers = index_part
+            .timeline_layers
+            .iter()
+            .filter_map(|layer| {
+                let metadata = index_part.layer_metadata.get(layer)?;
+                let file = ManifestFile {
+                    size: metadata.file_size,
+                    checksum: metadata.checksum.clone(),
+                    archive: metadata
+                        .archive
+                        .as_ref()
+                        .map(|archive| archive.name.clone()),
+                };
+                Some((layer.file_name(), file))
+            })
+            .collect();
+        BackupManifest {
+            version: Self::LATEST_VERSION,
+            tenant_id,
+            timeline_id,
+            disk_consistent_lsn: index_part.disk_consistent_lsn,
+            compression,
+            index_part: ManifestFile {
+                size: index_part_size,
+                checksum: index_part_checksum,
+                archive: None,
+            },
+            layers,
+            zstd_dictionaries,
+        }
+    }
+
+    pub(super) fn disk_consistent_lsn(&self) -> Lsn {
+        self.disk_consistent_lsn
+    }
+
+    /// Checks that the manifest describes every layer of the index part uploaded along with
+    /// it, with the same size and, if both know it, the same checksum.
+    pub(super) fn validate(&self, index_part: &IndexPart) -> anyhow::Result<()> {
+        ensure!(
+            self.disk_consistent_lsn == index_part.disk_consistent_lsn,
+            "manifest is of the upload at {}, not of the index part at {}",
+            self.disk_consistent_lsn,
+            index_part.disk_consistent_lsn
+        );
+        for layer in &index_part.timeline_layers {
+            let name = layer.file_name();
+            let file = self
+                .layers
+                .get(&name)
+                .with_context(|| format!("layer {name} is missing from the manifest"))?;
+            let Some(metadata) = index_part.layer_metadata.get(layer) else {
+                continue;
+            };
+            ensure!(
+                file.size == metadata.file_size,
+                "layer {name} has size {} in the manifest, {} in the index part",
+                file.size,
+                metadata.file_size
+            );
+            if let (Some(expected), Some(actual)) = (&file.checksum, &metadata.checksum) {
+                expected
+                    .verify(actual)
+                    .with_context(|| format!("layer {name} differs from the manifest"))?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::tenant::remote_timeline_client::checksum::{checksum_bytes, ChecksumAlgorithm};
+    use crate::tenant::remote_timeline_client::index::LayerFileMetadata;
+    use crate::tenant::storage_layer::LayerFileName;
+
+    fn layer(name: &str) -> LayerFileName {
+        name.parse().unwrap()
+    }
+
+    fn index_part(layers: &[(&str, u64, &str)]) -> IndexPart {
+        let layers = layers
+            .iter()
+            .map(|(name, size, contents)| {
+                let checksum =
+                    checksum_bytes(ChecksumAlgorithm::Crc32c, contents.as_bytes()).unwrap();
+                (
+                    layer(name),
+                    LayerFileMetadata::new(*size).with_checksum(checksum),
+                )
+            })
+            .collect::<HashMap<_, _>>();
+        IndexPart::new(layers, Lsn(0x16960E8), Vec::new())
+    }
+
+    const LAYER_A: &str = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__0000000001696070-00000000016960E9";
+    const LAYER_B: &str = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51";
+
+    fn manifest_of(index_part: &IndexPart) -> BackupManifest {
+        BackupManifest::new(
+            TenantId::generate(),
+            TimelineId::generate(),
+            index_part,
+            123,
+            checksum_bytes(ChecksumAlgorithm::Sha256, b"index part"),
+            Compression::Zstd,
+            vec!["1690000000000.v1.zdict".to_owned()],
+        )
+    }
+
+    #[test]
+    fn manifest_roundtrips() {
+        let manifest = manifest_of(&index_part(&[(LAYER_A, 8192, "a"), (LAYER_B, 4096, "b")]));
+        let json = serde_json::to_string(&manifest).unwrap();
+        assert_eq!(
+            serde_json::from_str::<BackupManifest>(&json).unwrap(),
+            manifest
+        );
+
+        // The fields added by the later versions are ignored.
+        let mut value = serde_json::to_value(&manifest).unwrap();
+        value["version"] = 2.into();
+        value["some_future_field"] = "some value".into();
+        let parsed = serde_json::from_value::<BackupManifest>(value).unwrap();
+        assert_eq!(parsed.layers, manifest.layers);
+    }
+
+    #[test]
+    fn manifest_validates_its_index_part() {
+        let uploaded = index_part(&[(LAYER_A, 8192, "a"), (LAYER_B, 4096, "b")]);
+        let manifest = manifest_of(&uploaded);
+        manifest.validate(&uploaded).unwrap();
+
+        // A manifest may have more layers than the index part, never fewer.
+        manifest
+            .validate(&index_part(&[(LAYER_A, 8192, "a")]))
+            .unwrap();
+        let partial = manifest_of(&index_part(&[(LAYER_A, 8192, "a")]));
+        assert!(partial.validate(&uploaded).is_err());
+
+        let resized = index_part(&[(LAYER_A, 8192, "a"), (LAYER_B, 8192, "b")]);
+        assert!(manifest.validate(&resized).is_err());
+        let changed = index_part(&[(LAYER_A, 8192, "a"), (LAYER_B, 4096, "c")]);
+        assert!(manifest.validate(&changed).is_err());
+    }
+}
@@ -0,0 +1,309 @@
+//! Detection of another pageserver uploading the same tenant, e.g. a node that was thought dead
+//! during a failover but came back.
+//!
+//! With `remote_owner_lease_ttl` set, every tenant with a remote timeline keeps an owner lease in
+//! the remote storage, [`OwnerLease::FILE_NAME`] in the tenant's directory: the id of the node
+//! that holds it and the time of its last heartbeat. The holder renews it every third of the
+//! TTL. A lease of another node whose heartbeat is younger than the TTL is live: the tenant's
+//! uploads (layers, index parts and deletions) wait until it expires, with an error in the log
+//! and [`REMOTE_OWNER_LEASE_CONFLICTS`] bumped. Reads and downloads go on as usual. The lease is
+//! deleted when the tenant shuts down, so a planned move doesn't wait for the TTL.
+//!
+//! This is a detection and not a lock: the remote storages have no conditional writes for the
+//! two nodes to take the lease atomically, and the clocks of the nodes can disagree. Two nodes
+//! that start at the same time can both see no lease and upload for a heartbeat, the next one
+//! tells the later one that it's not alone. Failures to read or renew the lease keep the state
+//! it had, a flaky storage doesn't stop the uploads by itself.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use anyhow::Context;
+use chrono::{NaiveDateTime, Utc};
+use once_cell::sync::Lazy;
+use remote_storage::{GenericRemoteStorage, RemotePath, RemoteStorageError};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use tokio::runtime::Handle;
+use tokio::sync::watch;
+use tracing::{error, info, info_span, warn, Instrument};
+use utils::id::{NodeId, TenantId};
+
+use crate::config::PageServerConf;
+use crate::metrics::REMOTE_OWNER_LEASE_CONFLICTS;
+use crate::task_mgr::{self, TaskKind};
+
+static OWNER_LEASES: Lazy<Mutex<HashMap<TenantId, Weak<TenantOwnerLease>>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+/// Contents of the lease object.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub(super) struct OwnerLease {
+    node_id: NodeId,
+    heartbeat: NaiveDateTime,
+}
+
+impl OwnerLease {
+    pub const FILE_NAME: &'static str = "owner_lease.json";
+}
+
+/// Who holds the lease, as seen by a node.
+#[derive(Debug, PartialEq, Eq)]
+enum LeaseHolder {
+    /// No lease, an expired one, or the node's own: the node (re)takes it.
+    Nobody,
+    /// Another node's live lease, with the age of its last heartbeat.
+    Other(NodeId, Duration),
+}
+
+impl LeaseHolder {
+    fn of(lease: Option<&OwnerLease>, node_id: NodeId, now: NaiveDateTime, ttl: Duration) -> Self {
+        let Some(lease) = lease.filter(|lease| lease.node_id != node_id) else {
+            return LeaseHolder::Nobody;
+        };
+        // A heartbeat from the future is a clock skew, the lease is live.
+        let age = (now - lease.heartbeat).to_std().unwrap_or(Duration::ZERO);
+        if age < ttl {
+            LeaseHolder::Other(lease.node_id, age)
+        } else {
+            LeaseHolder::Nobody
+        }
+    }
+}
+
+/// The lease state shared by all the timelines of a tenant, see the module docs.
+pub(crate) struct TenantOwnerLease {
+    held: watch::Sender<bool>,
+}
+
+impl TenantOwnerLease {
+    /// The tenant's lease, as long as any of its timelines is alive. The first call for the
+    /// tenant starts the heartbeat task on `runtime`, which stops once the last one is gone.
+    pub(crate) fn for_tenant(
+        conf: &'static PageServerConf,
+        storage: &GenericRemoteStorage,
+        tenant_id: TenantId,
+        runtime: &Handle,
+    ) -> Arc<Self> {
+        if conf.remote_owner_lease_ttl.is_zero() {
+            return Arc::new(TenantOwnerLease {
+                held: watch::channel(true).0,
+            });
+        }
+
+        let mut leases = OWNER_LEASES.lock().unwrap();
+        if let Some(lease) = leases.get(&tenant_id).and_then(Weak::upgrade) {
+            return lease;
+        }
+        leases.retain(|_, lease| lease.strong_count() > 0);
+        let lease = Arc::new(TenantOwnerLease {
+            held: watch::channel(false).0,
+        });
+        leases.insert(tenant_id, Arc::downgrade(&lease));
+
+        let heartbeat = Heartbeat {
+            conf,
+            storage: storage.clone(),
+            tenant_id,
+            lease: Arc::downgrade(&lease),
+        };
+        task_mgr::spawn(
+            runtime,
+            TaskKind::RemoteOwnerLease,
+            Some(tenant_id),
+            None,
+            "remote owner lease heartbeat",
+            false,
+            heartbeat
+                .run()
+                .instrument(info_span!("remote_owner_lease", tenant = %tenant_id)),
+        );
+        lease
+    }
+
+    /// Returns right away while the node holds the lease, or with the leases disabled. Waits
+    /// until it takes the lease otherwise.
+    pub(crate) async fn wait_until_held(&self) {
+        let mut held = self.held.subscribe();
+        // The sender lives as long as `self`.
+        let _ = held.wait_for(|held| *held).await;
+    }
+}
+
+struct Heartbeat {
+    conf: &'static PageServerConf,
+    storage: GenericRemoteStorage,
+    tenant_id: TenantId,
+    lease: Weak<TenantOwnerLease>,
+}
+
+impl Heartbeat {
+    async fn run(self) -> anyhow::Result<()> {
+        let lease_path = self.lease_path()?;
+        let period = self.conf.remote_owner_lease_ttl / 3;
+        let mut held = false;
+        loop {
+            let Some(lease) = self.lease.upgrade() else {
+                // The tenant's timelines may be back already, with a task of their own that
+                // holds the lease now.
+                let replaced = OWNER_LEASES
+                    .lock()
+                    .unwrap()
+                    .get(&self.tenant_id)
+                    .map_or(false, |lease| lease.strong_count() > 0);
+                held &= !replaced;
+                break;
+            };
+            held = self.beat(&lease_path, &lease, held).await;
+            drop(lease);
+
+            tokio::select! {
+                _ = tokio::time::sleep(period) => {}
+                _ = task_mgr::shutdown_watcher() => break,
+            }
+        }
+
+        if held {
+            match self.storage.delete(&lease_path).await {
+                Ok(()) | Err(RemoteStorageError::NotFound) => info!("released the owner lease"),
+                Err(e) => warn!("failed to release the owner lease: {e}"),
+            }
+        }
+        Ok(())
+    }
+
+    /// Checks the lease and renews it if no other node holds it. Returns whether this node
+    /// holds it now.
+    async fn beat(&self, lease_path: &RemotePath, lease: &TenantOwnerLease, held: bool) -> bool {
+        let remote = match self.download(lease_path).await {
+            Ok(remote) => remote,
+            Err(e) => {
+                warn!("failed to read the owner lease: {e:#}");
+                return held;
+            }
+        };
+        let now = Utc::now().naive_utc();
+        match LeaseHolder::of(
+            remote.as_ref(),
+            self.conf.id,
+            now,
+            self.conf.remote_owner_lease_ttl,
+        ) {
+            LeaseHolder::Other(node_id, age) => {
+                error!(
+                    "node {node_id} holds the owner lease, heartbeat {age:?} ago: another pageserver uploads this tenant, holding back the uploads until its lease expires"
+                );
+                REMOTE_OWNER_LEASE_CONFLICTS.inc();
+                lease.held.send_replace(false);
+                false
+            }
+            LeaseHolder::Nobody => {
+                let ours = OwnerLease {
+                    node_id: self.conf.id,
+                    heartbeat: now,
+                };
+                if let Err(e) = self.upload(lease_path, &ours).await {
+                    warn!("failed to renew the owner lease: {e:#}");
+                    return held;
+                }
+                if !held {
+                    info!("took the owner lease");
+                }
+                lease.held.send_replace(true);
+                true
+            }
+        }
+    }
+
+    fn lease_path(&self) -> anyhow::Result<RemotePath> {
+        self.conf.remote_path(
+            &self
+                .conf
+                .tenant_path(&self.tenant_id)
+                .join(OwnerLease::FILE_NAME),
+        )
+    }
+
+    async fn download(&self, lease_path: &RemotePath) -> anyhow::Result<Option<OwnerLease>> {
+        let mut download = match self.storage.download(lease_path).await {
+            Ok(download) => download,
+            Err(RemoteStorageError::NotFound) => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lease_bytes = Vec::new();
+        download
+            .download_stream
+            .read_to_end(&mut lease_bytes)
+            .await
+            .context("Failed to download the owner lease")?;
+        let lease =
+            serde_json::from_slice(&lease_bytes).context("Failed to parse the owner lease")?;
+        Ok(Some(lease))
+    }
+
+    async fn upload(&self, lease_path: &RemotePath, lease: &OwnerLease) -> anyhow::Result<()> {
+        let lease_bytes =
+            serde_json::to_vec(lease).context("Failed to serialize the owner lease")?;
+        let lease_size = lease_bytes.len();
+        self.storage
+            .upload(
+                tokio::io::BufReader::new(std::io::Cursor::new(lease_bytes)),
+                lease_size,
+                lease_path,
+                None,
+            )
+            .await
+            .context("Failed to upload the owner lease")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[test]
+    fn live_lease_of_another_node_is_respected() {
+        let ttl = Duration::from_secs(30);
+        let now = Utc::now().naive_utc();
+        let lease = |node_id, seconds_ago| OwnerLease {
+            node_id: NodeId(node_id),
+            heartbeat: now - chrono::Duration::seconds(seconds_ago),
+        };
+
+        assert_eq!(
+            LeaseHolder::of(None, NodeId(1), now, ttl),
+            LeaseHolder::Nobody
+        );
+        assert_eq!(
+            LeaseHolder::of(Some(&lease(1, 10)), NodeId(1), now, ttl),
+            LeaseHolder::Nobody
+        );
+        assert_eq!(
+            LeaseHolder::of(Some(&lease(2, 10)), NodeId(1), now, ttl),
+            LeaseHolder::Other(NodeId(2), Duration::from_secs(10))
+        );
+        assert_eq!(
+            LeaseHolder::of(Some(&lease(2, 30)), NodeId(1), now, ttl),
+            LeaseHolder::Nobody
+        );
+        // Another node's clock runs ahead.
+        assert_eq!(
+            LeaseHolder::of(Some(&lease(2, -60)), NodeId(1), now, ttl),
+            LeaseHolder::Other(NodeId(2), Duration::ZERO)
+        );
+    }
+
+    #[tokio::test]
+    async fn uploads_wait_for_the_lease() {
+        let lease = TenantOwnerLease {
+            held: watch::channel(false).0,
+        };
+        assert!(lease.wait_until_held().now_or_never().is_none());
+
+        lease.held.send_replace(true);
+        assert!(lease.wait_until_held().now_or_never().is_some());
+    }
+}
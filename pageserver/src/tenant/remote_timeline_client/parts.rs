@@ -0,0 +1,148 @@
+//! Layers larger than the largest object the remote storage takes, see
+//! [`GenericRemoteStorage::max_object_size`], are uploaded split into parts: each part is an
+//! object of its own next to where the layer would be, `<layer>.part-00000` and so on, and the
+//! `<layer>.parts` manifest object describes the split.
+//!
+//! The split is decided when the upload is scheduled and recorded in the layer metadata of the
+//! index part, so the downloads and the deletions know the parts without reading the manifest.
+//! The manifest is read only to delete the layers whose metadata is gone, i.e. the superseded
+//! ones, and describes the parts to anyone looking at the remote storage without the index part.
+//!
+//! The storages with multipart uploads, like S3, take objects much larger than a layer, so the
+//! layers are split only for the storages without them, or with a low `max_object_size`.
+
+use anyhow::Context;
+use remote_storage::{GenericRemoteStorage, RemotePath, RemoteStorageError};
+use serde::{Deserialize, Serialize};
+use tokio::io::AsyncReadExt;
+use utils::crashsafe::path_with_suffix_extension;
+
+/// A layer that needs more parts than that is not uploaded at all: that many requests for a
+/// single layer mean the `max_object_size` of the remote storage is set far too low.
+pub(super) const MAX_LAYER_PARTS: u64 = 10_000;
+
+/// How a layer is split into parts, all of `part_size` bytes except the last one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub struct LayerParts {
+    pub file_size: u64,
+    pub part_size: u64,
+}
+
+impl LayerParts {
+    /// The split of a layer of `file_size` bytes for a storage that takes the objects of at most
+    /// `max_object_size` bytes, `None` if the layer is uploaded as a single object.
+    pub(super) fn of_layer(file_size: u64, max_object_size: Option<u64>) -> Option<Self> {
+        let part_size = max_object_size.filter(|&max_object_size| file_size > max_object_size)?;
+        Some(LayerParts {
+            file_size,
+            // Not a single object of zero bytes is allowed, an empty part would not help anyway.
+            part_size: part_size.max(1),
+        })
+    }
+
+    pub(super) fn count(&self) -> u64 {
+        self.file_size.div_ceil(self.part_size)
+    }
+
+    /// The byte range of the layer that is stored in the part number `part`.
+    pub(super) fn range(&self, part: u64) -> (u64, u64) {
+        let start = part * self.part_size;
+        (start, (start + self.part_size).min(self.file_size))
+    }
+
+    /// Errors with the limit that makes the layer need too many parts to be uploaded.
+    pub(super) fn ensure_uploadable(&self, layer_path: &RemotePath) -> anyhow::Result<()> {
+        anyhow::ensure!(
+            self.count() <= MAX_LAYER_PARTS,
+            "Layer {layer_path} of {} bytes would be split into {} parts to fit the max object size of {} bytes of the remote storage, more than {MAX_LAYER_PARTS} allowed: raise the max object size of the remote storage, or lower the layer size with checkpoint_distance and compaction_target_size",
+            self.file_size,
+            self.count(),
+            self.part_size,
+        );
+        Ok(())
+    }
+
+    pub(super) fn part_path(layer_path: &RemotePath, part: u64) -> RemotePath {
+        RemotePath::new(&path_with_suffix_extension(
+            layer_path.get_path(),
+            &format!("part-{part:05}"),
+        ))
+        .expect("a path with another extension is still relative")
+    }
+
+    pub(super) fn manifest_path(layer_path: &RemotePath) -> RemotePath {
+        RemotePath::new(&path_with_suffix_extension(layer_path.get_path(), "parts"))
+            .expect("a path with another extension is still relative")
+    }
+
+    /// The objects with the parts of the split layer at `layer_path`, in order.
+    pub(super) fn part_paths(&self, layer_path: &RemotePath) -> Vec<RemotePath> {
+        (0..self.count())
+            .map(|part| Self::part_path(layer_path, part))
+            .collect()
+    }
+
+    /// Downloads the manifest of the layer at `layer_path`, `None` if the layer is not split.
+    pub(super) async fn download_manifest(
+        storage: &GenericRemoteStorage,
+        layer_path: &RemotePath,
+    ) -> Result<Option<Self>, RemoteStorageError> {
+        let path = Self::manifest_path(layer_path);
+        let mut download = match storage.download(&path).await {
+            Ok(download) => download,
+            Err(RemoteStorageError::NotFound) => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let mut bytes = Vec::new();
+        download
+            .download_stream
+            .read_to_end(&mut bytes)
+            .await
+            .with_context(|| format!("Failed to download the manifest {path}"))?;
+        let parts = serde_json::from_slice(&bytes)
+            .with_context(|| format!("Failed to parse the manifest {path}"))
+            .map_err(RemoteStorageError::Permanent)?;
+        Ok(Some(parts))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn layer_split() -> anyhow::Result<()> {
+        assert_eq!(LayerParts::of_layer(10, None), None);
+        assert_eq!(LayerParts::of_layer(10, Some(10)), None);
+
+        let parts = LayerParts::of_layer(10, Some(4)).unwrap();
+        assert_eq!(parts.count(), 3);
+        assert_eq!(
+            (0..3).map(|part| parts.range(part)).collect::<Vec<_>>(),
+            vec![(0, 4), (4, 8), (8, 10)]
+        );
+
+        let layer_path = RemotePath::from_string("tenant/timeline/layer")?;
+        assert_eq!(
+            parts.part_paths(&layer_path),
+            vec![
+                RemotePath::from_string("tenant/timeline/layer.part-00000")?,
+                RemotePath::from_string("tenant/timeline/layer.part-00001")?,
+                RemotePath::from_string("tenant/timeline/layer.part-00002")?,
+            ]
+        );
+        assert_eq!(
+            LayerParts::manifest_path(&layer_path),
+            RemotePath::from_string("tenant/timeline/layer.parts")?
+        );
+        parts.ensure_uploadable(&layer_path)?;
+
+        let too_many = LayerParts::of_layer(MAX_LAYER_PARTS + 1, Some(1)).unwrap();
+        let error = too_many.ensure_uploadable(&layer_path).unwrap_err();
+        assert!(
+            error.to_string().contains("max object size of 1 bytes"),
+            "{error}"
+        );
+        Ok(())
+    }
+}
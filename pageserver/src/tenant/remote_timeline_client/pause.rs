@@ -0,0 +1,102 @@
+//! Pausing of the remote uploads, e.g. for a maintenance window of the remote storage.
+//!
+//! While paused, the upload queues of all timelines of the pageserver don't start new attempts at
+//! their tasks (layer and index uploads, and layer deletions). The attempts in flight finish, the
+//! rest of the tasks wait in their queues until the uploads are resumed. Downloads are not
+//! paused, reads of evicted layers need them.
+//!
+//! The pause belongs to the config of the pageserver, see [`UploadPause::get`]: the tests run
+//! several pageservers in one process, each with its own config.
+
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use once_cell::sync::Lazy;
+use tokio::sync::watch;
+use tracing::info;
+
+use crate::config::PageServerConf;
+use crate::metrics::REMOTE_UPLOADS_PAUSED;
+
+/// The pauses by the working directory of their config.
+static UPLOAD_PAUSES: Lazy<Mutex<HashMap<PathBuf, &'static UploadPause>>> =
+    Lazy::new(|| Mutex::new(HashMap::new()));
+
+pub(crate) struct UploadPause {
+    paused: watch::Sender<bool>,
+}
+
+impl UploadPause {
+    fn new() -> Self {
+        Self {
+            paused: watch::channel(false).0,
+        }
+    }
+
+    /// The pause of the pageserver with the `conf` config, created on the first call.
+    pub(crate) fn get(conf: &PageServerConf) -> &'static Self {
+        UPLOAD_PAUSES
+            .lock()
+            .unwrap()
+            .entry(conf.workdir.clone())
+            .or_insert_with(|| Box::leak(Box::new(Self::new())))
+    }
+
+    /// Stops the upload tasks from starting new attempts, until [`Self::resume`].
+    pub(crate) fn pause(&self) {
+        if !self.paused.send_replace(true) {
+            info!("pausing remote uploads");
+        }
+        REMOTE_UPLOADS_PAUSED.set(1);
+    }
+
+    /// Lets the paused upload tasks carry on.
+    pub(crate) fn resume(&self) {
+        if self.paused.send_replace(false) {
+            info!("resuming remote uploads");
+        }
+        REMOTE_UPLOADS_PAUSED.set(0);
+    }
+
+    /// Returns right away unless the uploads are paused, then waits until they are resumed.
+    pub(crate) async fn wait_until_resumed(&self) {
+        self.wait_for(false).await
+    }
+
+    /// Waits until the uploads are paused, e.g. not to wait for an upload that won't start.
+    pub(crate) async fn wait_until_paused(&self) {
+        self.wait_for(true).await
+    }
+
+    async fn wait_for(&self, paused: bool) {
+        self.paused
+            .subscribe()
+            .wait_for(|&current| current == paused)
+            .await
+            .expect("the sender is in self, it is not dropped while we wait");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::FutureExt;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn paused_uploads_wait_for_resume() {
+        let pause = UploadPause::new();
+        assert!(pause.wait_until_resumed().now_or_never().is_some());
+        assert!(pause.wait_until_paused().now_or_never().is_none());
+
+        pause.pause();
+        assert!(pause.wait_until_paused().now_or_never().is_some());
+        let waiting = pause.wait_until_resumed();
+        tokio::pin!(waiting);
+        assert!((&mut waiting).now_or_never().is_none());
+
+        pause.resume();
+        waiting.await;
+    }
+}
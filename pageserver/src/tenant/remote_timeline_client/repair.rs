@@ -0,0 +1,380 @@
+//! Repair of a timeline whose layers are in the remote storage without an index part, e.g.
+//! after the upload of its first index part was interrupted, or the object was lost. Nothing
+//! else tells which layers the timeline has and what its metadata is, so it can't be attached.
+//!
+//! Such a timeline is reported with an error when its index part is not found. With
+//! `remote_repair_missing_index_part`, the index part is rebuilt from the newest of the index
+//! generations (see `remote_index_generations`), which has the metadata, and the objects in the
+//! timeline's remote directory:
+//!
+//! * The layers of the generation are kept if their objects are still there.
+//! * `disk_consistent_lsn` moves on over the level 0 delta layers uploaded after the generation,
+//!   as long as each starts where the previous one ends: they cover all the keys, so the layers
+//!   have everything up to the end of the last one. `prev_record_lsn` is not known past the
+//!   generation and is left out then.
+//! * The other layers up to the new `disk_consistent_lsn` are added, with the size of their
+//!   contents: the compressed ones are read through for it, with the tenant's zstd dictionary
+//!   if they are compressed with one. The newer layers are left out.
+//!
+//! The rebuilt index part is uploaded before it's used, so a repair is done once. Its backup
+//! manifest lists the tenant's dictionaries in the remote storage.
+
+use std::collections::{BTreeSet, HashMap, HashSet};
+
+use anyhow::Context;
+use remote_storage::{
+    Compression, GenericRemoteStorage, RemotePath, RemoteStorageError, ZstdDictionary,
+};
+use tracing::{error, warn};
+use utils::id::{TenantId, TimelineId};
+use utils::lsn::Lsn;
+
+use crate::config::PageServerConf;
+use crate::repository::Key;
+use crate::tenant::metadata::TimelineMetadata;
+use crate::tenant::storage_layer::LayerFileName;
+
+use super::checksum::Checksum;
+use super::dictionary::TenantDictionaries;
+use super::download::download_index_part_file;
+use super::index::{IndexLayerMetadata, IndexPart, LayerFileMetadata};
+use super::parts::LayerParts;
+use super::upload::upload_index_part;
+use super::{with_timeout, RemoteOpRetrySettings, FAILED_DOWNLOAD_WARN_THRESHOLD};
+
+/// Called when the index part of the timeline is not found. Returns the rebuilt index part, or
+/// `None` if the timeline has no layers in the remote storage, or it's not to be repaired.
+pub(super) async fn repair_missing_index_part(
+    conf: &'static PageServerConf,
+    storage: &GenericRemoteStorage,
+    tenant_id: TenantId,
+    timeline_id: TimelineId,
+    dictionaries: &TenantDictionaries,
+) -> anyhow::Result<Option<IndexPart>> {
+    let timeline_path = conf.timeline_path(&tenant_id, &timeline_id);
+    let timeline_storage_path = conf.remote_path(&timeline_path)?;
+    let retry_settings = RemoteOpRetrySettings::from_conf(conf);
+    let listing = retry_settings
+        .retry(
+            || {
+                with_timeout(
+                    retry_settings.list_timeout,
+                    storage.list_files(Some(&timeline_storage_path)),
+                    |_| RemoteStorageError::Timeout,
+                )
+            },
+            RemoteStorageError::is_permanent,
+            FAILED_DOWNLOAD_WARN_THRESHOLD,
+            "list_files",
+        )
+        .await;
+    let objects: BTreeSet<String> = match listing {
+        Ok(paths) => paths
+            .iter()
+            .filter_map(|path| path.object_name())
+            .map(str::to_owned)
+            .collect(),
+        Err(RemoteStorageError::NotFound) => return Ok(None),
+        Err(e) => return Err(e).context("Failed to list the remote timeline files"),
+    };
+
+    let layer_count = objects
+        .iter()
+        .filter(|object| object.parse::<LayerFileName>().is_ok())
+        .count();
+    if layer_count == 0 {
+        return Ok(None);
+    }
+    if !conf.remote_repair_missing_index_part {
+        error!(
+            "timeline has {layer_count} layers in the remote storage but no index part, it can't be attached from there until remote_repair_missing_index_part rebuilds it"
+        );
+        return Ok(None);
+    }
+    let Some(generation_lsn) = newest_generation(&objects) else {
+        error!(
+            "timeline has {layer_count} layers in the remote storage but no index part, and no index generation to rebuild it from"
+        );
+        return Ok(None);
+    };
+
+    let generation = download_index_part_file(
+        conf,
+        storage,
+        &tenant_id,
+        &timeline_id,
+        &IndexPart::generation_file_name(generation_lsn),
+    )
+    .await
+    .with_context(|| format!("Failed to download the index generation at {generation_lsn}"))?;
+    let plan = plan_repair(&generation, &objects);
+
+    let mut layers = plan.kept;
+    for layer in &plan.added {
+        let path = timeline_storage_path.join(std::path::Path::new(&layer.file_name()));
+        layers.insert(
+            layer.clone(),
+            layer_file_metadata(storage, dictionaries, &path).await?,
+        );
+    }
+
+    let metadata = generation.parse_metadata()?;
+    let metadata_bytes = if plan.disk_consistent_lsn == generation.disk_consistent_lsn {
+        metadata.to_bytes()?
+    } else {
+        TimelineMetadata::new(
+            plan.disk_consistent_lsn,
+            None,
+            metadata.ancestor_timeline(),
+            metadata.ancestor_lsn(),
+            metadata.latest_gc_cutoff_lsn(),
+            metadata.initdb_lsn(),
+            metadata.pg_version(),
+            metadata.region_id(),
+        )
+        .to_bytes()?
+    };
+    let mut index_part = IndexPart::new(layers, plan.disk_consistent_lsn, metadata_bytes);
+    index_part.superseded_layers = plan.superseded;
+    index_part.retained_lsns = generation.retained_lsns.clone();
+    index_part.index_generations = generation.index_generations.clone();
+    index_part.layer_archives = plan.archives;
+
+    upload_index_part(
+        conf,
+        storage,
+        &tenant_id,
+        &timeline_id,
+        &index_part,
+        dictionaries.list(storage).await?,
+    )
+    .await
+    .context("Failed to upload the rebuilt index part")?;
+    warn!(
+        "rebuilt the missing index part at {} from the index generation at {generation_lsn}, with {} layers uploaded after it",
+        plan.disk_consistent_lsn,
+        plan.added.len()
+    );
+    Ok(Some(index_part))
+}
+
+fn newest_generation(objects: &BTreeSet<String>) -> Option<Lsn> {
+    objects
+        .iter()
+        .filter(|object| IndexPart::is_index_file_name(object) && *object != IndexPart::FILE_NAME)
+        .filter_map(|object| {
+            let lsn = object.strip_prefix("index_part_")?.strip_suffix(".json")?;
+            u64::from_str_radix(lsn, 16).ok().map(Lsn)
+        })
+        .max()
+}
+
+/// What the rebuilt index part has, besides the sizes of the added layers.
+#[derive(Debug)]
+struct RepairPlan {
+    disk_consistent_lsn: Lsn,
+    /// The layers of the generation still in the remote storage.
+    kept: HashMap<LayerFileName, LayerFileMetadata>,
+    /// The layers in the remote storage the generation doesn't know, up to
+    /// `disk_consistent_lsn`. The superseded ones are kept as such.
+    added: Vec<LayerFileName>,
+    superseded: HashMap<LayerFileName, Lsn>,
+    archives: HashMap<String, HashSet<LayerFileName>>,
+}
+
+fn plan_repair(generation: &IndexPart, objects: &BTreeSet<String>) -> RepairPlan {
+    let is_present = |layer: &LayerFileName, metadata: &IndexLayerMetadata| {
+        let name = layer.file_name();
+        if let Some(archive) = &metadata.archive {
+            return objects.contains(&archive.name);
+        }
+        let Some(parts) = &metadata.parts else {
+            return objects.contains(&name);
+        };
+        let layer_path =
+            RemotePath::from_string(&name).expect("a layer file name is a relative path");
+        parts
+            .part_paths(&layer_path)
+            .into_iter()
+            .chain([LayerParts::manifest_path(&layer_path)])
+            .all(|path| {
+                path.object_name()
+                    .is_some_and(|object| objects.contains(object))
+            })
+    };
+    let kept: HashMap<_, _> = generation
+        .timeline_layers
+        .iter()
+        .filter_map(|layer| {
+            let metadata = generation.layer_metadata.get(layer)?;
+            is_present(layer, metadata).then(|| (layer.clone(), LayerFileMetadata::from(metadata)))
+        })
+        .collect();
+    let superseded: HashMap<_, _> = generation
+        .superseded_layers
+        .iter()
+        .filter(|(layer, _)| {
+            generation
+                .layer_metadata
+                .get(layer)
+                .map_or(objects.contains(&layer.file_name()), |metadata| {
+                    is_present(layer, metadata)
+                })
+        })
+        .map(|(layer, lsn)| (layer.clone(), *lsn))
+        .collect();
+    let archives = generation
+        .layer_archives
+        .iter()
+        .filter(|(archive, _)| objects.contains(*archive))
+        .map(|(archive, layers)| (archive.clone(), layers.clone()))
+        .collect();
+
+    let layers: Vec<LayerFileName> = objects
+        .iter()
+        .filter_map(|object| object.parse().ok())
+        .collect();
+    let mut disk_consistent_lsn = generation.disk_consistent_lsn;
+    loop {
+        let next = layers.iter().find_map(|layer| match layer {
+            LayerFileName::Delta(delta)
+                if delta.key_range == (Key::MIN..Key::MAX)
+                    && delta.lsn_range.start <= disk_consistent_lsn + 1
+                    && delta.lsn_range.end > disk_consistent_lsn + 1 =>
+            {
+                Some(Lsn(delta.lsn_range.end.0 - 1))
+            }
+            _ => None,
+        });
+        match next {
+            Some(lsn) => disk_consistent_lsn = lsn,
+            None => break,
+        }
+    }
+
+    let added = layers
+        .into_iter()
+        .filter(|layer| {
+            let last_lsn = match layer {
+                LayerFileName::Delta(delta) => Lsn(delta.lsn_range.end.0 - 1),
+                LayerFileName::Image(image) => image.lsn,
+            };
+            last_lsn <= disk_consistent_lsn
+                && !kept.contains_key(layer)
+                && !superseded.contains_key(layer)
+        })
+        .collect();
+
+    RepairPlan {
+        disk_consistent_lsn,
+        kept,
+        added,
+        superseded,
+        archives,
+    }
+}
+
+/// The size of the layer's contents, and its checksum if the object has one.
+async fn layer_file_metadata(
+    storage: &GenericRemoteStorage,
+    dictionaries: &TenantDictionaries,
+    path: &RemotePath,
+) -> anyhow::Result<LayerFileMetadata> {
+    let stat = storage.stat(path).await?;
+    let metadata = stat.metadata.as_ref();
+    let file_size = if let Some(name) = ZstdDictionary::name_from_metadata(metadata) {
+        let download = storage.download(path).await?;
+        let decompressed = dictionaries
+            .get(name, storage)
+            .await?
+            .decompress(download.download_stream)
+            .await
+            .with_context(|| format!("Failed to decompress layer {path} for its size"))?;
+        decompressed.len() as u64
+    } else if Compression::from_metadata(metadata)? == Compression::None {
+        stat.size
+    } else {
+        let mut contents = storage.open_read(path).await?;
+        tokio::io::copy(&mut contents, &mut tokio::io::sink())
+            .await
+            .with_context(|| format!("Failed to read layer {path} for its size"))?
+    };
+    let file_metadata = LayerFileMetadata::new(file_size);
+    Ok(match Checksum::from_metadata(metadata) {
+        Some(checksum) => file_metadata.with_checksum(checksum),
+        None => file_metadata,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn layer(lsns: &str) -> LayerFileName {
+        format!("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__{lsns}")
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn repair_follows_the_level_0_layers() {
+        let generation_layer = layer("0000000001696070-00000000016960E9");
+        let lost = layer("00000000016960E9-00000000016B59D8");
+        let generation = IndexPart::new(
+            HashMap::from([
+                (generation_layer.clone(), LayerFileMetadata::new(100)),
+                (lost.clone(), LayerFileMetadata::new(200)),
+            ]),
+            Lsn(0x16B59D7),
+            Vec::new(),
+        );
+
+        let next = layer("00000000016B59D8-00000000016B5A51");
+        let after_next = layer("00000000016B5A51-00000000016B5B00");
+        // Not adjacent to the others: the WAL in between is missing.
+        let detached = layer("00000000016B5C00-00000000016B5D00");
+        let image: LayerFileName =
+            "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B5A50"
+                .parse()
+                .unwrap();
+        let objects = [&generation_layer, &next, &after_next, &detached, &image]
+            .into_iter()
+            .map(LayerFileName::file_name)
+            .chain(["index_part_00000000016B59D7.json".to_owned()])
+            .collect::<BTreeSet<_>>();
+
+        assert_eq!(newest_generation(&objects), Some(Lsn(0x16B59D7)));
+        let plan = plan_repair(&generation, &objects);
+        assert_eq!(plan.disk_consistent_lsn, Lsn(0x16B5AFF));
+        assert_eq!(
+            plan.kept.keys().collect::<Vec<_>>(),
+            vec![&generation_layer]
+        );
+        let mut added = plan
+            .added
+            .iter()
+            .map(LayerFileName::file_name)
+            .collect::<Vec<_>>();
+        added.sort();
+        let mut expected = vec![next.file_name(), after_next.file_name(), image.file_name()];
+        expected.sort();
+        assert_eq!(added, expected);
+    }
+
+    #[test]
+    fn repair_without_newer_layers_keeps_the_generation() {
+        let generation_layer = layer("0000000001696070-00000000016960E9");
+        let generation = IndexPart::new(
+            HashMap::from([(generation_layer.clone(), LayerFileMetadata::new(100))]),
+            Lsn(0x16960E8),
+            Vec::new(),
+        );
+        let objects = BTreeSet::from([generation_layer.file_name()]);
+
+        let plan = plan_repair(&generation, &objects);
+        assert_eq!(plan.disk_consistent_lsn, Lsn(0x16960E8));
+        assert_eq!(plan.kept.len(), 1);
+        assert!(plan.added.is_empty());
+        assert_eq!(newest_generation(&objects), None);
+    }
+}
@@ -0,0 +1,1062 @@
+//! The timelines are copied to every storage of a `remote_storage` list, for a backup in another
+//! region or with another provider. The first storage of the list is the primary one: the upload
+//! queue works with it alone, as without the replicas, and the index part there is what the
+//! pageserver loads. Every operation the primary storage has completed is then replayed on each
+//! replica, in the same order, one at a time: a slow or unreachable replica falls behind on its
+//! own and holds up neither the upload queue nor the other replicas.
+//!
+//! The queued operations are kept in memory only. When a timeline is loaded, each replica catches
+//! up with the index part of the primary storage instead: it uploads the layers and archives its
+//! own index part doesn't have, the index part itself, and deletes what the primary doesn't have
+//! anymore. The layers are uploaded from the local files, or copied from the primary storage as
+//! they are there when the local ones are gone, e.g. evicted. A replica that falls too far
+//! behind, e.g. unreachable for long, drops its queue and catches up the same way with the next
+//! index part.
+//!
+//! The tenant's zstd dictionaries are copied to a replica before the first layer copied there
+//! that is compressed with one, and before the index parts whose backup manifests list them.
+//!
+//! The layer downloads go to the storage that has been the fastest so far among the primary one
+//! and the replicas known to have the layer, and to the next one if that fails.
+
+use std::collections::{BTreeMap, HashSet, VecDeque};
+use std::fmt;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use anyhow::Context;
+use metrics::IntGauge;
+use once_cell::sync::OnceCell;
+use pageserver_api::models::RemoteReplicaSyncStatus;
+use remote_storage::{
+    Download, GenericRemoteStorage, RemotePath, RemoteStorageError, ZstdDictionary,
+};
+use tokio::runtime::Handle;
+use tracing::{debug, info, info_span, warn, Instrument};
+use utils::id::{TenantId, TimelineId};
+
+use crate::config::PageServerConf;
+use crate::metrics::REMOTE_REPLICA_QUEUED_OPERATIONS;
+use crate::task_mgr::{self, TaskKind};
+use crate::tenant::storage_layer::LayerFileName;
+use crate::tenant::upload_queue::{DeletedLayerParts, LayerArchive, UploadOp};
+
+use super::checksum::ChunkChecksums;
+use super::dictionary::TenantDictionaries;
+use super::index::{IndexPart, LayerFileMetadata};
+use super::parts::LayerParts;
+use super::sync_limit::{SyncLimits, SyncPriority};
+use super::{
+    delete, download, upload, with_timeout, RemoteOpRetrySettings, UploadPause,
+    FAILED_DOWNLOAD_WARN_THRESHOLD, FAILED_UPLOAD_WARN_THRESHOLD,
+};
+
+static SYNC_TARGETS: OnceCell<SyncTargets> = OnceCell::new();
+
+/// Weight of the latest download in the moving average of the download speed of a storage.
+const DOWNLOAD_SPEED_WEIGHT: f64 = 0.3;
+
+/// How many operations a replica can fall behind by. Past that, e.g. when the replica has been
+/// unreachable for long, the queued ones are dropped and it catches up with the next index part
+/// instead, which doesn't take more memory the longer it is down.
+const MAX_QUEUED_OPERATIONS: usize = 1000;
+
+/// The storages of the `remote_storage` list, shared by all the timelines.
+pub(crate) struct SyncTargets {
+    pub(super) primary: TargetStats,
+    pub(super) replicas: Vec<ReplicaTarget>,
+}
+
+pub(crate) struct ReplicaTarget {
+    /// Position in the `remote_storage` list, the primary storage is number 0.
+    number: usize,
+    storage: GenericRemoteStorage,
+    stats: TargetStats,
+}
+
+/// What the operations so far tell about a storage, to pick the one to download from.
+#[derive(Default)]
+pub(super) struct TargetStats {
+    /// Moving average of the download speed, in bytes per second. `None` until a download
+    /// completes.
+    download_speed: Mutex<Option<f64>>,
+    /// Whether the last operation with the storage failed.
+    failing: AtomicBool,
+}
+
+impl ReplicaTarget {
+    #[cfg(test)]
+    pub(super) fn new(number: usize, storage: GenericRemoteStorage) -> Self {
+        Self {
+            number,
+            storage,
+            stats: TargetStats::default(),
+        }
+    }
+}
+
+impl SyncTargets {
+    /// Creates the clients of the replica storages of the config. Only the first call does, the
+    /// later ones return the same clients.
+    pub(crate) fn init(conf: &PageServerConf) -> anyhow::Result<&'static Self> {
+        SYNC_TARGETS.get_or_try_init(|| {
+            let replicas = conf
+                .remote_storage_replicas
+                .iter()
+                .zip(1..)
+                .map(|(config, number)| {
+                    let storage = GenericRemoteStorage::from_config(config).with_context(|| {
+                        format!("Failed to create the remote storage replica number {number}")
+                    })?;
+                    Ok(ReplicaTarget {
+                        number,
+                        storage,
+                        stats: TargetStats::default(),
+                    })
+                })
+                .collect::<anyhow::Result<_>>()?;
+            Ok(Self {
+                primary: TargetStats::default(),
+                replicas,
+            })
+        })
+    }
+
+    pub(crate) fn get(conf: &PageServerConf) -> &'static Self {
+        Self::init(conf).expect("the remote storage replicas are created at the pageserver startup")
+    }
+}
+
+impl TargetStats {
+    pub(super) fn record_download(&self, bytes: u64, elapsed: Duration) {
+        let speed = bytes as f64 / elapsed.as_secs_f64().max(0.001);
+        let mut download_speed = self.download_speed.lock().unwrap();
+        *download_speed = Some(match *download_speed {
+            Some(average) => average + DOWNLOAD_SPEED_WEIGHT * (speed - average),
+            None => speed,
+        });
+        self.failing.store(false, Ordering::Relaxed);
+    }
+
+    pub(super) fn record_failure(&self) {
+        self.failing.store(true, Ordering::Relaxed);
+    }
+}
+
+/// The order to try the `targets` in for a download, as their positions: the ones whose last
+/// operation succeeded first, the fastest first among them. A target without a completed
+/// download yet goes before the measured ones, so that it gets measured too.
+pub(super) fn download_order(targets: &[&TargetStats]) -> Vec<usize> {
+    let keys = targets
+        .iter()
+        .map(|stats| {
+            (
+                stats.failing.load(Ordering::Relaxed),
+                *stats.download_speed.lock().unwrap(),
+            )
+        })
+        .collect::<Vec<_>>();
+    let mut order = (0..targets.len()).collect::<Vec<_>>();
+    // A stable sort: the ties keep the order of the `remote_storage` list.
+    order.sort_by(|&a, &b| {
+        let ((a_failing, a_speed), (b_failing, b_speed)) = (keys[a], keys[b]);
+        a_failing
+            .cmp(&b_failing)
+            .then_with(|| match (a_speed, b_speed) {
+                (None, None) => std::cmp::Ordering::Equal,
+                (None, Some(_)) => std::cmp::Ordering::Less,
+                (Some(_), None) => std::cmp::Ordering::Greater,
+                (Some(a_speed), Some(b_speed)) => b_speed.total_cmp(&a_speed),
+            })
+    });
+    order
+}
+
+/// An operation to do in a replica.
+#[derive(Debug, Clone)]
+pub(super) enum ReplicaOp {
+    /// Catch up with this index part of the primary storage, see the module docs. Turns into
+    /// the operations that it takes.
+    CatchUp(IndexPart),
+    UploadLayer(LayerFileName, LayerFileMetadata),
+    UploadArchive(LayerArchive),
+    UploadIndex(IndexPart),
+    /// Delete a layer or an archive, by its name in the timeline's remote directory.
+    Delete(String, DeletedLayerParts),
+}
+
+impl ReplicaOp {
+    /// The operation that replays `op`, once the primary storage has completed it.
+    pub(super) fn of_upload_op(op: &UploadOp) -> Option<Self> {
+        match op {
+            UploadOp::UploadLayer(layer_file_name, layer_metadata) => Some(Self::UploadLayer(
+                layer_file_name.clone(),
+                layer_metadata.clone(),
+            )),
+            UploadOp::UploadArchive(archive) => Some(Self::UploadArchive(archive.clone())),
+            UploadOp::UploadMetadata(index_part, _) => Some(Self::UploadIndex(index_part.clone())),
+            // The timeline deletion deletes everything from the replicas at once, see
+            // `TimelineReplica::delete_timeline`.
+            UploadOp::Delete(delete) if delete.scheduled_from_timeline_delete => None,
+            UploadOp::Delete(delete) => Some(Self::Delete(delete.file_name.clone(), delete.parts)),
+            UploadOp::Barrier(_) => None,
+        }
+    }
+}
+
+impl fmt::Display for ReplicaOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ReplicaOp::CatchUp(index_part) => {
+                write!(f, "CatchUp(lsn: {})", index_part.disk_consistent_lsn)
+            }
+            ReplicaOp::UploadLayer(layer_file_name, _) => {
+                write!(f, "UploadLayer({})", layer_file_name.file_name())
+            }
+            ReplicaOp::UploadArchive(archive) => write!(f, "UploadArchive({})", archive.name),
+            ReplicaOp::UploadIndex(index_part) => {
+                write!(f, "UploadIndex(lsn: {})", index_part.disk_consistent_lsn)
+            }
+            ReplicaOp::Delete(file_name, _) => write!(f, "Delete({file_name})"),
+        }
+    }
+}
+
+/// What an operation did in the replica.
+enum Performed {
+    /// The objects of the operation are in the replica.
+    Done,
+    /// The primary storage doesn't have the objects to copy anymore, a later operation deletes
+    /// them anyway.
+    Skipped,
+    CatchUp(CatchUp),
+}
+
+/// What a catch up found in the replica, and the operations that bring it to the primary.
+struct CatchUp {
+    /// The layers and archives of the index part of the replica.
+    objects: HashSet<String>,
+    ops: Vec<ReplicaOp>,
+}
+
+/// A timeline in one of the replica storages, with the operations it is behind the primary
+/// storage by.
+pub(super) struct TimelineReplica {
+    conf: &'static PageServerConf,
+    runtime: Handle,
+    tenant_id: TenantId,
+    timeline_id: TimelineId,
+    target: &'static ReplicaTarget,
+    /// The primary storage, to copy the layers from that are not in the local files anymore.
+    primary: GenericRemoteStorage,
+    dictionaries: Arc<TenantDictionaries>,
+    queued_operations: IntGauge,
+    state: Mutex<ReplicaState>,
+}
+
+#[derive(Default)]
+struct ReplicaState {
+    queue: VecDeque<ReplicaOp>,
+    /// Whether a task is going through the queue.
+    running: bool,
+    /// Set when the upload queue of the timeline stops, no operations are queued after that.
+    stopped: bool,
+    /// Set when the queue has been dropped for being too long, until the next index part turns
+    /// into a catch up.
+    overflowed: bool,
+    /// The layers and archives the replica is known to have, for the downloads.
+    objects: HashSet<String>,
+    /// The zstd dictionaries of the tenant the replica is known to have.
+    dictionaries: HashSet<String>,
+    /// Error of the last attempt of the first queued operation, until it succeeds.
+    last_error: Option<String>,
+}
+
+impl TimelineReplica {
+    pub(super) fn new(
+        conf: &'static PageServerConf,
+        runtime: Handle,
+        tenant_id: TenantId,
+        timeline_id: TimelineId,
+        target: &'static ReplicaTarget,
+        primary: GenericRemoteStorage,
+        dictionaries: Arc<TenantDictionaries>,
+    ) -> Self {
+        Self {
+            conf,
+            runtime,
+            tenant_id,
+            timeline_id,
+            target,
+            primary,
+            dictionaries,
+            queued_operations: REMOTE_REPLICA_QUEUED_OPERATIONS
+                .with_label_values(&[&target.number.to_string()]),
+            state: Mutex::new(ReplicaState::default()),
+        }
+    }
+
+    pub(super) fn number(&self) -> usize {
+        self.target.number
+    }
+
+    pub(super) fn storage(&self) -> &GenericRemoteStorage {
+        &self.target.storage
+    }
+
+    pub(super) fn stats(&self) -> &TargetStats {
+        &self.target.stats
+    }
+
+    /// Whether the replica has the layer or archive object named `name`, as far as the
+    /// operations done with it so far tell.
+    pub(super) fn has_object(&self, name: &str) -> bool {
+        self.state.lock().unwrap().objects.contains(name)
+    }
+
+    pub(super) fn sync_status(&self) -> RemoteReplicaSyncStatus {
+        let state = self.state.lock().unwrap();
+        RemoteReplicaSyncStatus {
+            replica: self.target.number,
+            queued_operations: state.queue.len(),
+            error: state.last_error.clone(),
+        }
+    }
+
+    /// Queues `op` after the operations the replica is behind by already.
+    pub(super) fn schedule(self: &Arc<Self>, op: ReplicaOp) {
+        let mut state = self.state.lock().unwrap();
+        if state.stopped {
+            return;
+        }
+        debug!(
+            replica = self.target.number,
+            "scheduling replica operation {op}"
+        );
+        let queued = state.queue.len();
+        state.push(op);
+        self.queued_operations
+            .add(state.queue.len() as i64 - queued as i64);
+        if !state.queue.is_empty() && !std::mem::replace(&mut state.running, true) {
+            self.spawn_worker();
+        }
+    }
+
+    /// Drops the queued operations, the one in flight still completes. The replica catches up
+    /// with what it has missed the next time the timeline is loaded.
+    pub(super) fn stop(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.stopped = true;
+        self.queued_operations.sub(state.queue.len() as i64);
+        state.queue.clear();
+    }
+
+    fn spawn_worker(self: &Arc<Self>) {
+        let replica = Arc::clone(self);
+        task_mgr::spawn(
+            &self.runtime,
+            TaskKind::RemoteUploadTask,
+            Some(self.tenant_id),
+            Some(self.timeline_id),
+            "remote replica sync",
+            false,
+            async move {
+                replica.run().await;
+                Ok(())
+            }
+            .instrument(info_span!(
+                parent: None,
+                "remote_replica",
+                tenant_id = %self.tenant_id,
+                timeline_id = %self.timeline_id,
+                replica = self.target.number
+            )),
+        );
+    }
+
+    /// Does the queued operations in order, until the queue is empty.
+    async fn run(&self) {
+        loop {
+            let op = {
+                let mut state = self.state.lock().unwrap();
+                match state.queue.front() {
+                    Some(op) if !state.stopped => op.clone(),
+                    _ => {
+                        state.running = false;
+                        return;
+                    }
+                }
+            };
+
+            let Some(performed) = self.perform_with_retries(&op).await else {
+                // Shut down: the replica catches up at the next load.
+                self.state.lock().unwrap().running = false;
+                return;
+            };
+
+            let mut state = self.state.lock().unwrap();
+            if state.stopped {
+                state.running = false;
+                return;
+            }
+            state.queue.pop_front();
+            self.queued_operations.dec();
+            state.last_error = None;
+            match (op, performed) {
+                (_, Performed::CatchUp(catch_up)) => {
+                    state.objects = catch_up.objects;
+                    self.queued_operations.add(catch_up.ops.len() as i64);
+                    for op in catch_up.ops.into_iter().rev() {
+                        state.queue.push_front(op);
+                    }
+                }
+                (_, Performed::Skipped) => {}
+                (ReplicaOp::UploadLayer(layer_file_name, _), Performed::Done) => {
+                    state.objects.insert(layer_file_name.file_name());
+                }
+                (ReplicaOp::UploadArchive(archive), Performed::Done) => {
+                    state.objects.insert(archive.name);
+                }
+                (ReplicaOp::Delete(file_name, _), Performed::Done) => {
+                    state.objects.remove(&file_name);
+                }
+                (ReplicaOp::CatchUp(_) | ReplicaOp::UploadIndex(_), Performed::Done) => {}
+            }
+        }
+    }
+
+    /// Retries `op` until it succeeds, as the upload queue does, or until the pageserver shuts
+    /// down, `None` then.
+    async fn perform_with_retries(&self, op: &ReplicaOp) -> Option<Performed> {
+        let retry_settings = RemoteOpRetrySettings::from_conf(self.conf);
+        let sync_limit = SyncLimits::get(self.conf).for_tenant(self.tenant_id);
+        let mut attempt = 0;
+        loop {
+            let result = tokio::select! {
+                result = async {
+                    UploadPause::get(self.conf).wait_until_resumed().await;
+                    let _permit = sync_limit.acquire(SyncPriority::Low).await;
+                    with_timeout(retry_settings.operation_timeout, self.perform(op), |e| e).await
+                } => result,
+                _ = task_mgr::shutdown_watcher() => return None,
+            };
+            match result {
+                Ok(performed) => {
+                    self.target.stats.failing.store(false, Ordering::Relaxed);
+                    if attempt > 0 {
+                        info!(
+                            "replica operation {op} completed successfully after {attempt} retries"
+                        );
+                    }
+                    return Some(performed);
+                }
+                Err(e) => {
+                    self.target.stats.record_failure();
+                    if attempt < FAILED_UPLOAD_WARN_THRESHOLD {
+                        info!("failed to perform replica operation {op}, will retry (attempt {attempt}): {e:#}");
+                    } else {
+                        warn!("failed to perform replica operation {op}, will retry (attempt {attempt}): {e:?}");
+                    }
+                    self.state.lock().unwrap().last_error = Some(format!("{e:#}"));
+                    attempt += 1;
+                    tokio::select! {
+                        _ = tokio::time::sleep(retry_settings.backoff(attempt)) => {}
+                        _ = task_mgr::shutdown_watcher() => return None,
+                    }
+                }
+            }
+        }
+    }
+
+    async fn perform(&self, op: &ReplicaOp) -> anyhow::Result<Performed> {
+        let storage = &self.target.storage;
+        let timeline_path = self.conf.timeline_path(&self.tenant_id, &self.timeline_id);
+        match op {
+            ReplicaOp::CatchUp(index_part) => {
+                return self.catch_up(index_part).await.map(Performed::CatchUp)
+            }
+            ReplicaOp::UploadLayer(layer_file_name, layer_metadata) => {
+                let path = timeline_path.join(layer_file_name.file_name());
+                if local_file_exists(&path).await? {
+                    upload::upload_timeline_layer(
+                        self.conf,
+                        storage,
+                        &path,
+                        layer_metadata,
+                        None,
+                        false,
+                    )
+                    .await?;
+                } else if !self
+                    .copy_layer(&path, layer_metadata.parts().copied())
+                    .await?
+                {
+                    return Ok(Performed::Skipped);
+                }
+            }
+            ReplicaOp::UploadArchive(archive) => {
+                let mut all_local = true;
+                for (layer_file_name, _) in &archive.layers {
+                    all_local &=
+                        local_file_exists(&timeline_path.join(layer_file_name.file_name())).await?;
+                }
+                if all_local {
+                    // A layer deleted since fails the attempt, the next one copies the archive.
+                    upload::upload_layer_archive(
+                        self.conf,
+                        storage,
+                        &timeline_path,
+                        archive,
+                        |_| true,
+                    )
+                    .await?;
+                } else {
+                    let path = self.conf.remote_path(&timeline_path.join(&archive.name))?;
+                    if !self.copy_object(&path).await? {
+                        return Ok(Performed::Skipped);
+                    }
+                }
+            }
+            ReplicaOp::UploadIndex(index_part) => {
+                let dictionaries = self.dictionaries.names();
+                for name in &dictionaries {
+                    self.copy_dictionary(name).await?;
+                }
+                upload::upload_index_part(
+                    self.conf,
+                    storage,
+                    &self.tenant_id,
+                    &self.timeline_id,
+                    index_part,
+                    dictionaries,
+                )
+                .await?;
+            }
+            ReplicaOp::Delete(file_name, parts) => {
+                delete::delete_layer(
+                    self.conf,
+                    storage,
+                    &timeline_path.join(file_name),
+                    *parts,
+                    false,
+                )
+                .await?;
+            }
+        }
+        Ok(Performed::Done)
+    }
+
+    /// Compares the index part of the replica with the `target` one of the primary storage.
+    async fn catch_up(&self, target: &IndexPart) -> anyhow::Result<CatchUp> {
+        let replica_index_part = match download::download_index_part(
+            self.conf,
+            &self.target.storage,
+            &self.tenant_id,
+            &self.timeline_id,
+        )
+        .await
+        {
+            Ok(index_part) => Some(index_part),
+            Err(RemoteStorageError::NotFound) => None,
+            Err(e) => return Err(e).context("Failed to download the index part of the replica"),
+        };
+        let present = replica_index_part
+            .as_ref()
+            .map(index_objects)
+            .unwrap_or_default();
+        let wanted = index_objects(target);
+        // Also the ones the layers uploaded while the replica was behind are compressed with.
+        for name in self.dictionaries.list(&self.primary).await? {
+            self.copy_dictionary(&name).await?;
+        }
+
+        let mut ops = Vec::new();
+        let archives = target.layer_archives.iter().collect::<BTreeMap<_, _>>();
+        for (name, layers) in archives {
+            if present.contains_key(name) {
+                continue;
+            }
+            let mut layers = layers
+                .iter()
+                .filter_map(|layer| {
+                    let metadata = target.layer_metadata.get(layer)?;
+                    Some((layer.clone(), LayerFileMetadata::from(metadata)))
+                })
+                .collect::<Vec<_>>();
+            layers.sort_by_key(|(_, metadata)| metadata.archive().map(|archive| archive.offset));
+            ops.push(ReplicaOp::UploadArchive(LayerArchive {
+                name: name.clone(),
+                layers,
+            }));
+        }
+        let layers = target
+            .layer_metadata
+            .iter()
+            .map(|(layer, metadata)| (layer.file_name(), (layer, metadata)))
+            .collect::<BTreeMap<_, _>>();
+        for (layer, metadata) in layers.into_values() {
+            // The superseded layers are not copied, they are on their way out anyway.
+            if metadata.archive.is_none()
+                && target.timeline_layers.contains(layer)
+                && !present.contains_key(&layer.file_name())
+            {
+                ops.push(ReplicaOp::UploadLayer(layer.clone(), metadata.into()));
+            }
+        }
+        if replica_index_part.as_ref() != Some(target) {
+            ops.push(ReplicaOp::UploadIndex(target.clone()));
+        }
+        for (name, parts) in &present {
+            if !wanted.contains_key(name) {
+                ops.push(ReplicaOp::Delete(name.clone(), *parts));
+            }
+        }
+
+        if !ops.is_empty() {
+            info!(
+                replica = self.target.number,
+                "catching up with the primary storage in {} operations",
+                ops.len()
+            );
+        }
+        Ok(CatchUp {
+            objects: present.into_keys().collect(),
+            ops,
+        })
+    }
+
+    /// Copies the objects of the layer at `local_path` from the primary storage, as they are
+    /// there. Returns `false` if the primary storage doesn't have the layer, see
+    /// [`Self::copy_object`].
+    async fn copy_layer(
+        &self,
+        local_path: &Path,
+        parts: Option<LayerParts>,
+    ) -> anyhow::Result<bool> {
+        let path = self.conf.remote_path(local_path)?;
+        match parts {
+            Some(parts) => {
+                for part_path in parts.part_paths(&path) {
+                    if !self.copy_object(&part_path).await? {
+                        return Ok(false);
+                    }
+                }
+                self.copy_object(&LayerParts::manifest_path(&path)).await
+            }
+            None => {
+                if !self.copy_object(&path).await? {
+                    return Ok(false);
+                }
+                self.copy_object(&ChunkChecksums::remote_path(&path))
+                    .await?;
+                Ok(true)
+            }
+        }
+    }
+
+    /// Copies the object at `path` from the primary storage. Returns `false` if the primary
+    /// storage doesn't have it, i.e. a later operation deletes it anyway.
+    async fn copy_object(&self, path: &RemotePath) -> anyhow::Result<bool> {
+        let Some((download, size)) = self.download_from_primary(path).await? else {
+            info!("{path} is gone from the primary storage, not copying it");
+            return Ok(false);
+        };
+        // The layer can't be read from the replica without its dictionary.
+        if let Some(name) = ZstdDictionary::name_from_metadata(download.metadata.as_ref()) {
+            self.copy_dictionary(name).await?;
+        }
+        // The download from an encrypted primary storage is decrypted and not marked as
+        // encrypted anymore, the replica encrypts it with its own key, if it has one.
+        self.target
+            .storage
+            .upload(download.download_stream, size, path, download.metadata)
+            .await
+            .with_context(|| format!("Failed to copy {path} to the replica"))?;
+        Ok(true)
+    }
+
+    /// Copies the tenant's zstd dictionary named `name` from the primary storage, unless the
+    /// replica has it already. The dictionaries are never overwritten, see [`super::dictionary`].
+    async fn copy_dictionary(&self, name: &str) -> anyhow::Result<()> {
+        if self.state.lock().unwrap().dictionaries.contains(name) {
+            return Ok(());
+        }
+        let path = self.dictionaries.dictionary_path(name)?;
+        match self.target.storage.stat(&path).await {
+            Ok(_) => {}
+            Err(RemoteStorageError::NotFound) => {
+                let (download, size) =
+                    self.download_from_primary(&path).await?.with_context(|| {
+                        format!("zstd dictionary {path} is missing from the primary storage")
+                    })?;
+                self.target
+                    .storage
+                    .upload(download.download_stream, size, &path, download.metadata)
+                    .await
+                    .with_context(|| format!("Failed to copy {path} to the replica"))?;
+                info!("copied the zstd dictionary {path} to the replica");
+            }
+            Err(e) => {
+                return Err(e).with_context(|| format!("Failed to stat {path} in the replica"))
+            }
+        }
+        self.state
+            .lock()
+            .unwrap()
+            .dictionaries
+            .insert(name.to_owned());
+        Ok(())
+    }
+
+    /// The download of the object at `path` from the primary storage, and its size. `None` if
+    /// the primary storage doesn't have it.
+    async fn download_from_primary(
+        &self,
+        path: &RemotePath,
+    ) -> anyhow::Result<Option<(Download, usize)>> {
+        let size = match self.primary.stat(path).await {
+            Ok(object) => object.size,
+            Err(RemoteStorageError::NotFound) => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("Failed to stat {path}")),
+        };
+        let download = self
+            .primary
+            .download(path)
+            .await
+            .with_context(|| format!("Failed to download {path} from the primary storage"))?;
+        let size = usize::try_from(size).with_context(|| {
+            format!("Object {path} size {size} could not be converted to usize")
+        })?;
+        Ok(Some((download, size)))
+    }
+
+    /// Deletes all the objects of the timeline from the replica, the index part last. The
+    /// timeline deletion calls it, once the upload queue has stopped.
+    pub(super) async fn delete_timeline(&self) -> anyhow::Result<()> {
+        let retry_settings = RemoteOpRetrySettings::from_conf(self.conf);
+        let storage = &self.target.storage;
+        let timeline_storage_path = self
+            .conf
+            .remote_path(&self.conf.timeline_path(&self.tenant_id, &self.timeline_id))?;
+        let objects = retry_settings
+            .retry(
+                || {
+                    with_timeout(
+                        retry_settings.list_timeout,
+                        storage.list_files(Some(&timeline_storage_path)),
+                        |_| RemoteStorageError::Timeout,
+                    )
+                },
+                RemoteStorageError::is_permanent,
+                FAILED_DOWNLOAD_WARN_THRESHOLD,
+                "list the timeline in the replica",
+            )
+            .await
+            .context("Failed to list the timeline in the replica")?;
+        let index_part_path = timeline_storage_path.join(Path::new(IndexPart::FILE_NAME));
+        let objects = objects
+            .into_iter()
+            .filter(|path| *path != index_part_path)
+            .collect::<Vec<_>>();
+        if !objects.is_empty() {
+            retry_settings
+                .retry(
+                    || {
+                        with_timeout(
+                            retry_settings.operation_timeout,
+                            storage.delete_objects(&objects),
+                            |_| RemoteStorageError::Timeout,
+                        )
+                    },
+                    RemoteStorageError::is_permanent,
+                    FAILED_UPLOAD_WARN_THRESHOLD,
+                    "delete the timeline objects in the replica",
+                )
+                .await
+                .context("Failed to delete the timeline objects in the replica")?;
+        }
+        retry_settings
+            .retry(
+                || {
+                    with_timeout(
+                        retry_settings.operation_timeout,
+                        storage.delete(&index_part_path),
+                        |_| RemoteStorageError::Timeout,
+                    )
+                },
+                RemoteStorageError::is_permanent,
+                FAILED_UPLOAD_WARN_THRESHOLD,
+                "delete the index part in the replica",
+            )
+            .await
+            .context("Failed to delete the index part in the replica")?;
+        info!(
+            replica = self.target.number,
+            "deleted {} objects of the timeline from the replica",
+            objects.len() + 1
+        );
+        Ok(())
+    }
+}
+
+impl ReplicaState {
+    /// Queues `op`, or drops the queue if it gets too long, see [`MAX_QUEUED_OPERATIONS`].
+    fn push(&mut self, op: ReplicaOp) {
+        // The first operation is the one in flight, if a task is going through the queue.
+        let in_flight = usize::from(self.running).min(self.queue.len());
+        if !self.overflowed && self.queue.len() >= MAX_QUEUED_OPERATIONS {
+            warn!(
+                "replica is behind by {} operations, dropping them to catch up with the next index part instead",
+                self.queue.len()
+            );
+            self.queue.truncate(in_flight);
+            self.overflowed = true;
+        }
+        if !self.overflowed {
+            self.queue.push_back(op);
+            return;
+        }
+        match op {
+            ReplicaOp::CatchUp(index_part) | ReplicaOp::UploadIndex(index_part) => {
+                self.queue.push_back(ReplicaOp::CatchUp(index_part));
+                self.overflowed = false;
+            }
+            // The catch up with a later index part takes care of it.
+            _ => {}
+        }
+    }
+}
+
+impl Drop for TimelineReplica {
+    fn drop(&mut self) {
+        let queued = self.state.get_mut().unwrap().queue.len();
+        self.queued_operations.sub(queued as i64);
+    }
+}
+
+/// The layer, archive and index generation objects an index part refers to, with the parts of
+/// the layers to delete along with them.
+fn index_objects(index_part: &IndexPart) -> BTreeMap<String, DeletedLayerParts> {
+    let archived = index_part
+        .layer_archives
+        .values()
+        .flatten()
+        .collect::<HashSet<_>>();
+    let layers = index_part
+        .timeline_layers
+        .iter()
+        .filter(|layer| !archived.contains(layer))
+        .map(|layer| {
+            let metadata = index_part.layer_metadata.get(layer);
+            (
+                layer.file_name(),
+                DeletedLayerParts::from(metadata.and_then(|metadata| metadata.parts.as_ref())),
+            )
+        });
+    let superseded = index_part
+        .superseded_layers
+        .keys()
+        .filter(|layer| !archived.contains(layer))
+        .map(|layer| (layer.file_name(), DeletedLayerParts::Unknown));
+    let archives = index_part
+        .layer_archives
+        .keys()
+        .map(|name| (name.clone(), DeletedLayerParts::None));
+    let generations = index_part.index_generations.iter().map(|&lsn| {
+        (
+            IndexPart::generation_file_name(lsn),
+            DeletedLayerParts::None,
+        )
+    });
+    superseded
+        .chain(layers)
+        .chain(archives)
+        .chain(generations)
+        .collect()
+}
+
+async fn local_file_exists(path: &Path) -> anyhow::Result<bool> {
+    tokio::fs::try_exists(path)
+        .await
+        .with_context(|| format!("Failed to check if {} exists", path.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use remote_storage::{EncryptedWrapper, LocalFs, StorageMetadata};
+    use tokio::io::AsyncReadExt;
+    use utils::lsn::Lsn;
+
+    use super::super::checksum::{self, ChecksumAlgorithm};
+    use super::super::dedup::UploadDedupIndex;
+    use super::*;
+    use crate::tenant::harness::{TenantHarness, TIMELINE_ID};
+
+    #[test]
+    fn downloads_go_to_the_fastest_healthy_target() {
+        let primary = TargetStats::default();
+        let fast = TargetStats::default();
+        let slow = TargetStats::default();
+        let unmeasured = TargetStats::default();
+        // Unmeasured ones keep the list order, the primary storage goes first.
+        assert_eq!(download_order(&[&primary, &fast, &slow]), vec![0, 1, 2]);
+
+        primary.record_download(100 << 20, Duration::from_secs(2));
+        fast.record_download(100 << 20, Duration::from_secs(1));
+        slow.record_download(100 << 20, Duration::from_secs(10));
+        assert_eq!(
+            download_order(&[&primary, &fast, &slow, &unmeasured]),
+            vec![3, 1, 0, 2]
+        );
+
+        fast.record_failure();
+        assert_eq!(download_order(&[&primary, &fast, &slow]), vec![0, 2, 1]);
+        // A success clears the failure. A slow download lowers the average, a single one not
+        // below the primary storage yet.
+        fast.record_download(100 << 20, Duration::from_secs(100));
+        assert_eq!(download_order(&[&primary, &fast, &slow]), vec![1, 0, 2]);
+    }
+
+    #[test]
+    fn too_long_queue_turns_into_a_catch_up() {
+        let index_part = |lsn| IndexPart::new(HashMap::new(), Lsn(lsn), Vec::new());
+        let delete = || ReplicaOp::Delete("layer".to_owned(), DeletedLayerParts::None);
+        let mut state = ReplicaState {
+            running: true,
+            ..ReplicaState::default()
+        };
+        for _ in 0..MAX_QUEUED_OPERATIONS {
+            state.push(delete());
+        }
+        assert_eq!(state.queue.len(), MAX_QUEUED_OPERATIONS);
+
+        // Only the operation in flight is kept, the rest waits for the next index part.
+        state.push(delete());
+        assert_eq!(state.queue.len(), 1);
+        state.push(ReplicaOp::UploadIndex(index_part(0x10)));
+        state.push(delete());
+        let queue = state
+            .queue
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            queue,
+            vec!["Delete(layer)", "CatchUp(lsn: 0/10)", "Delete(layer)"]
+        );
+    }
+
+    #[tokio::test]
+    async fn replica_leaves_the_dedup_index_alone() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("replica_leaves_the_dedup_index_alone")?;
+        let conf: &'static PageServerConf = Box::leak(Box::new(PageServerConf {
+            remote_upload_dedup: true,
+            ..harness.conf.clone()
+        }));
+        let primary = GenericRemoteStorage::LocalFs(LocalFs::new(conf.workdir.join("primary"))?);
+        let target: &'static ReplicaTarget = Box::leak(Box::new(ReplicaTarget {
+            number: 1,
+            storage: GenericRemoteStorage::LocalFs(LocalFs::new(conf.workdir.join("replica"))?),
+            stats: TargetStats::default(),
+        }));
+        let dictionaries = TenantDictionaries::for_tenant(conf, &primary, harness.tenant_id);
+        let replica = TimelineReplica::new(
+            conf,
+            Handle::current(),
+            harness.tenant_id,
+            TIMELINE_ID,
+            target,
+            primary,
+            dictionaries,
+        );
+
+        let timeline_path = conf.timeline_path(&harness.tenant_id, &TIMELINE_ID);
+        std::fs::create_dir_all(&timeline_path)?;
+        let layer: LayerFileName = "000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__00000000016B59D8-00000000016B5A51".parse().unwrap();
+        let content = b"contents of a layer".to_vec();
+        std::fs::write(timeline_path.join(layer.file_name()), &content)?;
+        let layer_path = conf.remote_path(&timeline_path.join(layer.file_name()))?;
+
+        // A layer with the same contents is only in the primary storage, the replica can't copy
+        // from there and uploads its own.
+        let hash = checksum::checksum_bytes(ChecksumAlgorithm::Sha256, &content)
+            .expect("sha256 is a checksum algorithm")
+            .value()
+            .to_owned();
+        let primary_only_path = conf.remote_path(&timeline_path.join("primary_only_layer"))?;
+        let index = UploadDedupIndex::get(conf).await?;
+        index.record(&hash, &primary_only_path).await;
+
+        let upload =
+            ReplicaOp::UploadLayer(layer.clone(), LayerFileMetadata::new(content.len() as u64));
+        assert!(matches!(replica.perform(&upload).await?, Performed::Done));
+        let mut uploaded = Vec::new();
+        target
+            .storage
+            .download(&layer_path)
+            .await?
+            .download_stream
+            .read_to_end(&mut uploaded)
+            .await?;
+        assert_eq!(uploaded, content);
+        assert_eq!(index.find(&hash).await, Some(primary_only_path));
+
+        // Nor does the deletion from the replica forget the layer in the primary storage.
+        index.record(&hash, &layer_path).await;
+        let delete = ReplicaOp::Delete(layer.file_name(), DeletedLayerParts::None);
+        assert!(matches!(replica.perform(&delete).await?, Performed::Done));
+        assert_eq!(index.find(&hash).await, Some(layer_path));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn copies_from_an_encrypted_primary_are_readable() -> anyhow::Result<()> {
+        let harness = TenantHarness::create("copies_from_an_encrypted_primary_are_readable")?;
+        let conf = harness.conf;
+        let primary = GenericRemoteStorage::Encrypted(Arc::new(EncryptedWrapper::new(
+            GenericRemoteStorage::LocalFs(LocalFs::new(conf.workdir.join("primary"))?),
+            &[7; 32],
+        )?));
+        let target: &'static ReplicaTarget = Box::leak(Box::new(ReplicaTarget {
+            number: 1,
+            storage: GenericRemoteStorage::LocalFs(LocalFs::new(conf.workdir.join("replica"))?),
+            stats: TargetStats::default(),
+        }));
+        let dictionaries = TenantDictionaries::for_tenant(conf, &primary, harness.tenant_id);
+        let replica = TimelineReplica::new(
+            conf,
+            Handle::current(),
+            harness.tenant_id,
+            TIMELINE_ID,
+            target,
+            primary.clone(),
+            dictionaries,
+        );
+
+        let timeline_path = conf.timeline_path(&harness.tenant_id, &TIMELINE_ID);
+        let path = conf.remote_path(&timeline_path.join("layer"))?;
+        let content = b"contents of a layer".to_vec();
+        primary
+            .upload(
+                std::io::Cursor::new(content.clone()),
+                content.len(),
+                &path,
+                Some(StorageMetadata::from([("key", "value")])),
+            )
+            .await?;
+
+        assert!(replica.copy_object(&path).await?);
+        let download = target.storage.download(&path).await?;
+        let metadata = download.metadata.expect("the metadata is copied");
+        assert_eq!(metadata.get("key"), Some("value"));
+        assert_eq!(metadata.get("encryption"), None);
+        let mut copied = Vec::new();
+        download.download_stream.read_to_end(&mut copied).await?;
+        assert_eq!(copied, content);
+        Ok(())
+    }
+}
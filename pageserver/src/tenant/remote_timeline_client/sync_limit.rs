@@ -0,0 +1,336 @@
+//! Limits on the number of remote sync tasks (uploads, downloads and deletions) running at once.
+//!
+//! Every task takes a permit from the pageserver-wide semaphore, sized by `max_concurrent_syncs`
+//! in the remote storage config. With `max_concurrent_sync_per_tenant` set, it first takes
+//! a permit from its tenant's semaphore, so a tenant with a lot of work queued (e.g. a big
+//! backfill) cannot occupy all of the pageserver-wide permits: its extra tasks wait for the
+//! tenant's permits instead, while the other tenants' tasks queue up for the freed ones.
+//!
+//! Until the initial load of the tenants is done, the pageserver-wide semaphore is sized by
+//! `max_concurrent_sync_startup` instead, if that is set: the burst of downloads of a freshly
+//! started pageserver can use a different limit than the uploads of a pageserver serving the
+//! traffic. [`SyncLimits::end_startup_phase`] switches to `max_concurrent_syncs` then.
+//!
+//! The downloads a query is blocked on run with [`SyncPriority::High`] and go before the
+//! background tasks. The background tasks queue up for the pageserver-wide permits one at a
+//! time, and only while no high priority task is waiting: a high priority task has at most
+//! one background task ahead of it. The tasks that already run are not interrupted. The high
+//! priority tasks don't take their tenant's permits, the tenant's upload backlog would hold
+//! them up otherwise.
+
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::num::NonZeroUsize;
+use std::sync::{Arc, Mutex, Weak};
+
+use once_cell::sync::OnceCell;
+use tokio::sync::{watch, OwnedSemaphorePermit, Semaphore};
+use utils::id::TenantId;
+
+use crate::config::PageServerConf;
+use crate::metrics::REMOTE_SYNC_STARTUP_PHASE;
+
+static SYNC_LIMITS: OnceCell<SyncLimits> = OnceCell::new();
+
+/// Which tasks get the permits first, see the module docs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SyncPriority {
+    /// Downloads of the layers that a page request is waiting for.
+    High,
+    /// Uploads, deletions and the downloads nobody is waiting for right now.
+    Low,
+}
+
+/// The semaphores of all the tenants, and the pageserver-wide one.
+pub(crate) struct SyncLimits {
+    global: Arc<Semaphore>,
+    /// Size of the pageserver-wide semaphore after the startup phase.
+    steady_syncs: usize,
+    /// Size of the pageserver-wide semaphore during the startup phase, `None` after it.
+    startup_syncs: Mutex<Option<usize>>,
+    per_tenant: Option<NonZeroUsize>,
+    tenants: Mutex<HashMap<TenantId, Weak<Semaphore>>>,
+    /// Held by the low priority task that is waiting for a pageserver-wide permit.
+    low_priority_turn: Arc<tokio::sync::Mutex<()>>,
+    /// Number of high priority tasks waiting for a pageserver-wide permit.
+    high_priority_waiting: Arc<watch::Sender<usize>>,
+}
+
+impl SyncLimits {
+    pub(crate) fn new(
+        max_concurrent_syncs: NonZeroUsize,
+        max_concurrent_sync_per_tenant: Option<NonZeroUsize>,
+        max_concurrent_sync_startup: Option<NonZeroUsize>,
+    ) -> Self {
+        let startup_syncs = max_concurrent_sync_startup.unwrap_or(max_concurrent_syncs);
+        Self {
+            global: Arc::new(Semaphore::new(startup_syncs.get())),
+            steady_syncs: max_concurrent_syncs.get(),
+            startup_syncs: Mutex::new(Some(startup_syncs.get())),
+            per_tenant: max_concurrent_sync_per_tenant,
+            tenants: Mutex::new(HashMap::new()),
+            low_priority_turn: Arc::new(tokio::sync::Mutex::new(())),
+            high_priority_waiting: Arc::new(watch::channel(0).0),
+        }
+    }
+
+    /// The limits shared by the whole pageserver, created from the first config asked for.
+    pub(crate) fn get(conf: &PageServerConf) -> &'static Self {
+        SYNC_LIMITS.get_or_init(|| {
+            REMOTE_SYNC_STARTUP_PHASE.set(1);
+            match &conf.remote_storage_config {
+                Some(config) => Self::new(
+                    config.max_concurrent_syncs,
+                    config.max_concurrent_sync_per_tenant,
+                    config.max_concurrent_sync_startup,
+                ),
+                None => Self::new(
+                    NonZeroUsize::new(remote_storage::DEFAULT_REMOTE_STORAGE_MAX_CONCURRENT_SYNCS)
+                        .expect("default max_concurrent_syncs is not zero"),
+                    None,
+                    None,
+                ),
+            }
+        })
+    }
+
+    /// Resizes the pageserver-wide semaphore from `max_concurrent_sync_startup` to
+    /// `max_concurrent_syncs`, once the initial load of the tenants is done. Later calls do
+    /// nothing.
+    ///
+    /// When the steady-state limit is lower, the running tasks are not interrupted: this waits
+    /// until enough of them finish and keeps their permits, the tasks queued up meanwhile wait
+    /// as well.
+    pub(crate) async fn end_startup_phase(&self) {
+        let Some(startup_syncs) = self.startup_syncs.lock().unwrap().take() else {
+            return;
+        };
+        match self.steady_syncs.cmp(&startup_syncs) {
+            Ordering::Greater => self.global.add_permits(self.steady_syncs - startup_syncs),
+            Ordering::Less => {
+                let excess = u32::try_from(startup_syncs - self.steady_syncs)
+                    .expect("sync limits fit into the semaphore");
+                Arc::clone(&self.global)
+                    .acquire_many_owned(excess)
+                    .await
+                    .expect("sync limit semaphores are never closed")
+                    .forget();
+            }
+            Ordering::Equal => {}
+        }
+        REMOTE_SYNC_STARTUP_PHASE.set(0);
+    }
+
+    /// Limits for the tasks of the given tenant. All the timelines of a tenant share them,
+    /// as long as any of them is alive.
+    pub(crate) fn for_tenant(&self, tenant_id: TenantId) -> TenantSyncLimit {
+        let tenant = self.per_tenant.map(|per_tenant| {
+            let mut tenants = self.tenants.lock().unwrap();
+            if let Some(semaphore) = tenants.get(&tenant_id).and_then(Weak::upgrade) {
+                return semaphore;
+            }
+            // Forget the tenants that are gone, while we're at it.
+            tenants.retain(|_, semaphore| semaphore.strong_count() > 0);
+            let semaphore = Arc::new(Semaphore::new(per_tenant.get()));
+            tenants.insert(tenant_id, Arc::downgrade(&semaphore));
+            semaphore
+        });
+        TenantSyncLimit {
+            global: Arc::clone(&self.global),
+            tenant,
+            low_priority_turn: Arc::clone(&self.low_priority_turn),
+            high_priority_waiting: Arc::clone(&self.high_priority_waiting),
+        }
+    }
+}
+
+/// Limits of a single tenant's sync tasks, see the module docs.
+pub(crate) struct TenantSyncLimit {
+    global: Arc<Semaphore>,
+    tenant: Option<Arc<Semaphore>>,
+    low_priority_turn: Arc<tokio::sync::Mutex<()>>,
+    high_priority_waiting: Arc<watch::Sender<usize>>,
+}
+
+impl TenantSyncLimit {
+    /// Waits until the task is allowed to run, it stays allowed while the permit is held.
+    pub(crate) async fn acquire(&self, priority: SyncPriority) -> SyncPermit {
+        if priority == SyncPriority::High {
+            self.high_priority_waiting
+                .send_modify(|waiting| *waiting += 1);
+            let _waiting = scopeguard::guard((), |()| {
+                self.high_priority_waiting
+                    .send_modify(|waiting| *waiting -= 1);
+            });
+            return SyncPermit {
+                _global: self.acquire_global().await,
+                _tenant: None,
+            };
+        }
+
+        // The tenant's permit first: waiting for a pageserver-wide permit while holding all
+        // of the tenant's ones is fine, holding a pageserver-wide one while waiting is not.
+        let tenant = match &self.tenant {
+            Some(semaphore) => Some(
+                Arc::clone(semaphore)
+                    .acquire_owned()
+                    .await
+                    .expect("sync limit semaphores are never closed"),
+            ),
+            None => None,
+        };
+        let global = {
+            let _turn = self.low_priority_turn.lock().await;
+            self.high_priority_waiting
+                .subscribe()
+                .wait_for(|waiting| *waiting == 0)
+                .await
+                .expect("the sender is owned by the limits");
+            self.acquire_global().await
+        };
+        SyncPermit {
+            _global: global,
+            _tenant: tenant,
+        }
+    }
+
+    async fn acquire_global(&self) -> OwnedSemaphorePermit {
+        Arc::clone(&self.global)
+            .acquire_owned()
+            .await
+            .expect("sync limit semaphores are never closed")
+    }
+}
+
+/// Permit to run a sync task. The fields are dropped in order, so the pageserver-wide permit
+/// goes to the next task in line before the tenant's own tasks can queue up for it again.
+pub(crate) struct SyncPermit {
+    _global: OwnedSemaphorePermit,
+    _tenant: Option<OwnedSemaphorePermit>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn busy_tenants_take_turns() {
+        let limits = SyncLimits::new(NonZeroUsize::new(1).unwrap(), NonZeroUsize::new(1), None);
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        // The first tenant queues all of its tasks before the second one queues any.
+        let mut tasks = Vec::new();
+        for tenant in ["a", "b"] {
+            let limit = Arc::new(limits.for_tenant(TenantId::generate()));
+            for _ in 0..5 {
+                let limit = Arc::clone(&limit);
+                let order = Arc::clone(&order);
+                tasks.push(tokio::spawn(async move {
+                    let _permit = limit.acquire(SyncPriority::Low).await;
+                    order.lock().unwrap().push(tenant);
+                    tokio::task::yield_now().await;
+                }));
+            }
+        }
+        for task in tasks {
+            task.await.unwrap();
+        }
+
+        assert_eq!(
+            *order.lock().unwrap(),
+            ["a", "b", "a", "b", "a", "b", "a", "b", "a", "b"]
+        );
+    }
+
+    #[tokio::test]
+    async fn tenant_limit_leaves_room_for_others() {
+        let limits = SyncLimits::new(NonZeroUsize::new(3).unwrap(), NonZeroUsize::new(2), None);
+        let busy = limits.for_tenant(TenantId::generate());
+        let other = limits.for_tenant(TenantId::generate());
+
+        let _first = busy.acquire(SyncPriority::Low).await;
+        let _second = busy.acquire(SyncPriority::Low).await;
+        assert!(
+            futures::FutureExt::now_or_never(busy.acquire(SyncPriority::Low)).is_none(),
+            "a tenant should not take more than its own permits"
+        );
+        assert!(
+            futures::FutureExt::now_or_never(other.acquire(SyncPriority::Low)).is_some(),
+            "the other tenant should get the remaining permit"
+        );
+    }
+
+    #[tokio::test]
+    async fn high_priority_goes_before_the_backlog() {
+        let limits = SyncLimits::new(NonZeroUsize::new(1).unwrap(), None, None);
+        let tenant = Arc::new(limits.for_tenant(TenantId::generate()));
+        let order = Arc::new(Mutex::new(Vec::new()));
+
+        let running = tenant.acquire(SyncPriority::Low).await;
+        let mut tasks = Vec::new();
+        for (name, priority) in [
+            ("upload 1", SyncPriority::Low),
+            ("upload 2", SyncPriority::Low),
+            ("download", SyncPriority::High),
+        ] {
+            let tenant = Arc::clone(&tenant);
+            let order = Arc::clone(&order);
+            tasks.push(tokio::spawn(async move {
+                let _permit = tenant.acquire(priority).await;
+                order.lock().unwrap().push(name);
+                tokio::task::yield_now().await;
+            }));
+            tokio::task::yield_now().await;
+        }
+
+        // The upload that was already waiting for the permit goes first, the rest of the
+        // backlog waits for the download.
+        drop(running);
+        for task in tasks {
+            task.await.unwrap();
+        }
+        assert_eq!(*order.lock().unwrap(), ["upload 1", "download", "upload 2"]);
+    }
+
+    #[tokio::test]
+    async fn startup_limit_is_replaced_by_the_steady_one() {
+        let limits = SyncLimits::new(NonZeroUsize::new(1).unwrap(), None, NonZeroUsize::new(3));
+        let tenant = limits.for_tenant(TenantId::generate());
+
+        let first = tenant.acquire(SyncPriority::Low).await;
+        let second = tenant.acquire(SyncPriority::Low).await;
+        let third = tenant.acquire(SyncPriority::Low).await;
+        assert!(
+            futures::FutureExt::now_or_never(tenant.acquire(SyncPriority::Low)).is_none(),
+            "no more than the startup limit should run during the startup"
+        );
+
+        // The switch waits for the running tasks to get down to the steady-state limit.
+        let mut end_startup = std::pin::pin!(limits.end_startup_phase());
+        assert!(futures::FutureExt::now_or_never(end_startup.as_mut()).is_none());
+        drop(first);
+        drop(second);
+        end_startup.await;
+        assert!(
+            futures::FutureExt::now_or_never(tenant.acquire(SyncPriority::Low)).is_none(),
+            "no more than the steady-state limit should run after the startup"
+        );
+        drop(third);
+        let _fourth = tenant.acquire(SyncPriority::Low).await;
+
+        // Ending the startup phase again changes nothing.
+        limits.end_startup_phase().await;
+        assert!(futures::FutureExt::now_or_never(tenant.acquire(SyncPriority::Low)).is_none());
+    }
+
+    #[tokio::test]
+    async fn steady_limit_can_be_higher() {
+        let limits = SyncLimits::new(NonZeroUsize::new(2).unwrap(), None, NonZeroUsize::new(1));
+        let tenant = limits.for_tenant(TenantId::generate());
+
+        let _first = tenant.acquire(SyncPriority::Low).await;
+        assert!(futures::FutureExt::now_or_never(tenant.acquire(SyncPriority::Low)).is_none());
+        limits.end_startup_phase().await;
+        assert!(futures::FutureExt::now_or_never(tenant.acquire(SyncPriority::Low)).is_some());
+    }
+}
@@ -2,24 +2,51 @@
 
 use anyhow::{bail, Context};
 use fail::fail_point;
-use std::{io::ErrorKind, path::Path};
+use std::io::{ErrorKind, SeekFrom};
+use std::path::Path;
 use tokio::fs;
 
+use crate::metrics::{RemoteOpFileKind, REMOTE_UPLOAD_BYTES, REMOTE_UPLOAD_DEDUP_COPIES};
 use crate::{config::PageServerConf, tenant::remote_timeline_client::index::IndexPart};
-use remote_storage::GenericRemoteStorage;
+use remote_storage::{
+    Compression, GenericRemoteStorage, RemotePath, RemoteStorageError, StorageMetadata,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncSeekExt};
+use utils::crashsafe::path_with_suffix_extension;
 use utils::id::{TenantId, TimelineId};
 
+use super::checksum::{
+    self, copy_with_hasher, Checksum, ChecksumAlgorithm, ChunkChecksums, Hasher,
+};
+use super::dedup::UploadDedupIndex;
+use super::dictionary::TenantDictionaries;
 use super::index::LayerFileMetadata;
+use super::manifest::BackupManifest;
+use super::parts::LayerParts;
+use crate::tenant::storage_layer::LayerFileName;
+use crate::tenant::upload_queue::LayerArchive;
 
-use tracing::info;
+use tracing::{info, instrument};
+
+/// Extension of the compressed copy of a layer being uploaded. It ends with
+/// [`crate::TEMP_FILE_SUFFIX`], so that a copy left by a crash is removed on startup.
+const TEMP_COMPRESSED_EXTENSION: &str = "compressed___temp";
 
 /// Serializes and uploads the given index part data to the remote storage.
+///
+/// The [`BackupManifest`] of the index part goes first, so that the index part is never
+/// uploaded with the manifest of an older one. An index part that is one of the
+/// `index_generations` is uploaded into its generation file next, so that the latest index part
+/// never lists a generation that is not there. The manifest also lists the tenant's
+/// `zstd_dictionaries`.
+#[instrument(skip_all, fields(bytes = tracing::field::Empty))]
 pub(super) async fn upload_index_part<'a>(
     conf: &'static PageServerConf,
     storage: &'a GenericRemoteStorage,
     tenant_id: &TenantId,
     timeline_id: &TimelineId,
     index_part: &'a IndexPart,
+    zstd_dictionaries: Vec<String>,
 ) -> anyhow::Result<()> {
     tracing::trace!("uploading new index part");
 
@@ -29,37 +56,124 @@ pub(super) async fn upload_index_part<'a>(
 
     let index_part_bytes = serde_json::to_vec(&index_part)
         .context("Failed to serialize index part file into bytes")?;
+    let checksum = checksum::checksum_bytes(conf.remote_checksum_algorithm, &index_part_bytes);
+    let compression = remote_compression(conf);
+    let manifest = BackupManifest::new(
+        *tenant_id,
+        *timeline_id,
+        index_part,
+        index_part_bytes.len() as u64,
+        checksum.clone(),
+        compression,
+        zstd_dictionaries,
+    );
+    let mut compressed = Vec::new();
+    compression
+        .compress(index_part_bytes.as_slice(), &mut compressed)
+        .await
+        .context("Failed to compress index part")?;
+    let index_part_bytes = compressed;
     let index_part_size = index_part_bytes.len();
-    let index_part_bytes = tokio::io::BufReader::new(std::io::Cursor::new(index_part_bytes));
+    tracing::Span::current().record("bytes", index_part_size);
+
+    upload_manifest(conf, storage, tenant_id, timeline_id, &manifest).await?;
+
+    let mut file_names = Vec::with_capacity(2);
+    if index_part.is_generation() {
+        file_names.push(IndexPart::generation_file_name(
+            index_part.disk_consistent_lsn,
+        ));
+    }
+    file_names.push(IndexPart::FILE_NAME.to_owned());
+
+    for file_name in file_names {
+        let index_part_path = conf
+            .metadata_path(tenant_id, timeline_id)
+            .with_file_name(&file_name);
+        let storage_path = conf.remote_path(&index_part_path)?;
 
-    let index_part_path = conf
+        storage
+            .upload(
+                tokio::io::BufReader::new(std::io::Cursor::new(index_part_bytes.clone())),
+                index_part_size,
+                &storage_path,
+                compression.record_in_metadata(checksum.as_ref().map(|c| c.to_metadata())),
+            )
+            .await
+            .with_context(|| {
+                format!("Failed to upload index part {file_name} for '{tenant_id} / {timeline_id}'")
+            })?;
+
+        REMOTE_UPLOAD_BYTES
+            .with_label_values(&[RemoteOpFileKind::Index.as_str()])
+            .inc_by(index_part_size as u64);
+    }
+    Ok(())
+}
+
+/// Uploads the manifest as is, for the tools that don't know the codecs of the pageserver.
+async fn upload_manifest(
+    conf: &'static PageServerConf,
+    storage: &GenericRemoteStorage,
+    tenant_id: &TenantId,
+    timeline_id: &TimelineId,
+    manifest: &BackupManifest,
+) -> anyhow::Result<()> {
+    let manifest_bytes =
+        serde_json::to_vec(manifest).context("Failed to serialize the backup manifest")?;
+    let checksum = checksum::checksum_bytes(conf.remote_checksum_algorithm, &manifest_bytes);
+    let manifest_size = manifest_bytes.len();
+    let manifest_path = conf
         .metadata_path(tenant_id, timeline_id)
-        .with_file_name(IndexPart::FILE_NAME);
-    let storage_path = conf.remote_path(&index_part_path)?;
+        .with_file_name(BackupManifest::FILE_NAME);
+    let storage_path = conf.remote_path(&manifest_path)?;
 
     storage
-        .upload_storage_object(Box::new(index_part_bytes), index_part_size, &storage_path)
+        .upload(
+            tokio::io::BufReader::new(std::io::Cursor::new(manifest_bytes)),
+            manifest_size,
+            &storage_path,
+            checksum.map(|checksum| checksum.to_metadata()),
+        )
         .await
-        .with_context(|| format!("Failed to upload index part for '{tenant_id} / {timeline_id}'"))
+        .with_context(|| {
+            format!("Failed to upload the backup manifest for '{tenant_id} / {timeline_id}'")
+        })?;
+
+    REMOTE_UPLOAD_BYTES
+        .with_label_values(&[RemoteOpFileKind::Index.as_str()])
+        .inc_by(manifest_size as u64);
+    Ok(())
 }
 
 /// Attempts to upload given layer files.
 /// No extra checks for overlapping files is made and any files that are already present remotely will be overwritten, if submitted during the upload.
 ///
 /// On an error, bumps the retries count and reschedules the entire task.
+///
+/// A layer small enough for the `dictionaries` of its tenant is compressed with their current
+/// dictionary, see [`super::dictionary`].
+///
+/// Only the uploads to the primary storage (`to_primary`) go through the upload dedup index: its
+/// paths are in that storage, a replica can't copy from them, see [`super::dedup`].
+///
+/// Returns the checksum of the layer, if it's uploaded as a single object with one.
+#[instrument(skip_all, fields(layer = %source_path.display(), bytes = known_metadata.file_size()))]
 pub(super) async fn upload_timeline_layer<'a>(
     conf: &'static PageServerConf,
     storage: &'a GenericRemoteStorage,
     source_path: &'a Path,
     known_metadata: &'a LayerFileMetadata,
-) -> anyhow::Result<()> {
+    dictionaries: Option<&TenantDictionaries>,
+    to_primary: bool,
+) -> anyhow::Result<Option<Checksum>> {
     fail_point!("before-upload-layer", |_| {
         bail!("failpoint before-upload-layer")
     });
     let storage_path = conf.remote_path(source_path)?;
 
     let source_file_res = fs::File::open(&source_path).await;
-    let source_file = match source_file_res {
+    let mut source_file = match source_file_res {
         Ok(source_file) => source_file,
         Err(e) if e.kind() == ErrorKind::NotFound => {
             // If we encounter this arm, it wasn't intended, but it's also not
@@ -68,7 +182,7 @@ pub(super) async fn upload_timeline_layer<'a>(
             // something worse, like when a file is scheduled for upload before
             // it has been written to disk yet.
             info!(path = %source_path.display(), "File to upload doesn't exist. Likely the file has been deleted and an upload is not required any more.");
-            return Ok(());
+            return Ok(None);
         }
         Err(e) => Err(e)
             .with_context(|| format!("Failed to open a source file for layer {source_path:?}"))?,
@@ -91,8 +205,137 @@ pub(super) async fn upload_timeline_layer<'a>(
         format!("File {source_path:?} size {fs_size} could not be converted to usize")
     })?;
 
+    if let Some(parts) = known_metadata.parts() {
+        return upload_layer_parts(
+            conf,
+            storage,
+            source_file,
+            source_path,
+            &storage_path,
+            parts,
+        )
+        .await
+        .map(|()| None);
+    }
+
+    let checksum = checksum::checksum_file(conf.remote_checksum_algorithm, &mut source_file)
+        .await
+        .with_context(|| format!("Failed to compute the checksum of layer {source_path:?}"))?;
+
+    // The byte ranges of a compressed object don't map to the ones of the layer.
+    let compression = remote_compression(conf);
+    let chunk_size = conf.remote_chunk_checksum_size;
+    let chunk_checksums =
+        if chunk_size > 0 && fs_size as u64 > chunk_size && compression == Compression::None {
+            ChunkChecksums::of_file(conf.remote_checksum_algorithm, chunk_size, &mut source_file)
+                .await
+                .with_context(|| {
+                    format!("Failed to compute the chunk checksums of layer {source_path:?}")
+                })?
+        } else {
+            None
+        };
+    let dictionaries =
+        dictionaries.filter(|_| TenantDictionaries::applies_to(conf, compression, fs_size as u64));
+
+    let dedup = if conf.remote_upload_dedup && to_primary {
+        let hash = match &checksum {
+            Some(checksum) if checksum.algorithm() == ChecksumAlgorithm::Sha256 => {
+                checksum.value().to_owned()
+            }
+            _ => checksum::checksum_file(ChecksumAlgorithm::Sha256, &mut source_file)
+                .await
+                .with_context(|| format!("Failed to hash layer {source_path:?}"))?
+                .expect("sha256 is a checksum algorithm")
+                .value()
+                .to_owned(),
+        };
+        let index = UploadDedupIndex::get(conf).await?;
+        // The copy of another tenant's layer may name a dictionary this tenant doesn't have.
+        if let Some(uploaded) = index.find(&hash).await.filter(|uploaded| {
+            *uploaded != storage_path
+                && dictionaries.map_or(true, |dictionaries| dictionaries.owns(uploaded))
+        }) {
+            match storage.copy(&uploaded, &storage_path).await {
+                Ok(()) => {
+                    info!("uploaded layer {source_path:?} as a copy of {uploaded}, which has the same contents");
+                    REMOTE_UPLOAD_DEDUP_COPIES.inc();
+                    index.record(&hash, &storage_path).await;
+                    if let Some(chunk_checksums) = &chunk_checksums {
+                        upload_chunk_checksums(storage, &storage_path, chunk_checksums).await?;
+                    }
+                    return Ok(checksum);
+                }
+                Err(e) => {
+                    info!("failed to copy {uploaded} with the same contents as layer {source_path:?}, uploading it instead: {e:#}");
+                    index.forget(&uploaded).await;
+                }
+            }
+        }
+        Some((index, hash))
+    } else {
+        None
+    };
+
+    // Not immutable: a corrupted or lost layer is uploaded again under the same name, see
+    // `Timeline::force_remote_resync`.
+    let metadata = checksum.as_ref().map(Checksum::to_metadata);
+    let (body, body_size, metadata): (Box<dyn AsyncRead + Unpin + Send + Sync>, usize, _) =
+        match (compression, dictionaries) {
+            (Compression::None, _) => (
+                Box::new(source_file),
+                fs_size,
+                compression.record_in_metadata(metadata),
+            ),
+            (_, Some(dictionaries)) => {
+                let mut layer = Vec::with_capacity(fs_size);
+                source_file
+                    .read_to_end(&mut layer)
+                    .await
+                    .with_context(|| format!("Failed to read layer {source_path:?}"))?;
+                let dictionary = dictionaries.for_upload(&layer).await?;
+                let (compressed, metadata) = match dictionary {
+                    Some(dictionary) => (
+                        dictionary.compress(layer).await,
+                        dictionary.record_in_metadata(metadata),
+                    ),
+                    None => {
+                        let mut compressed = Vec::new();
+                        (
+                            compression
+                                .compress(layer.as_slice(), &mut compressed)
+                                .await
+                                .map(|_| compressed),
+                            compression.record_in_metadata(metadata),
+                        )
+                    }
+                };
+                let compressed = compressed
+                    .with_context(|| format!("Failed to compress layer {source_path:?}"))?;
+                let compressed_size = compressed.len();
+                (
+                    Box::new(std::io::Cursor::new(compressed)),
+                    compressed_size,
+                    metadata,
+                )
+            }
+            _ => {
+                let (compressed_file, compressed_size) =
+                    compress_layer(compression, source_file, source_path)
+                        .await
+                        .with_context(|| format!("Failed to compress layer {source_path:?}"))?;
+                let compressed_size = usize::try_from(compressed_size)
+                    .context("Compressed layer size could not be converted to usize")?;
+                (
+                    Box::new(compressed_file),
+                    compressed_size,
+                    compression.record_in_metadata(metadata),
+                )
+            }
+        };
+
     storage
-        .upload(source_file, fs_size, &storage_path, None)
+        .upload(body, body_size, &storage_path, metadata)
         .await
         .with_context(|| {
             format!(
@@ -100,6 +343,221 @@ pub(super) async fn upload_timeline_layer<'a>(
                 source_path.display()
             )
         })?;
+    if let Some((index, hash)) = dedup {
+        index.record(&hash, &storage_path).await;
+    }
+    // After the layer, so that the checksums never describe an older upload of it.
+    if let Some(chunk_checksums) = &chunk_checksums {
+        upload_chunk_checksums(storage, &storage_path, chunk_checksums).await?;
+    }
 
+    REMOTE_UPLOAD_BYTES
+        .with_label_values(&[RemoteOpFileKind::Layer.as_str()])
+        .inc_by(body_size as u64);
+    Ok(checksum)
+}
+
+/// Compresses the layer into a temporary file next to it, to upload from: the storages need to
+/// know the size of an upload before it starts, and a layer is too large to compress in memory.
+/// The file is unlinked right after it's created, it goes away with the returned handle.
+async fn compress_layer(
+    compression: Compression,
+    source_file: fs::File,
+    source_path: &Path,
+) -> std::io::Result<(fs::File, u64)> {
+    let temp_path = path_with_suffix_extension(source_path, TEMP_COMPRESSED_EXTENSION);
+    let mut compressed_file = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .open(&temp_path)
+        .await?;
+    fs::remove_file(&temp_path).await?;
+    let compressed_size = compression
+        .compress(source_file, &mut compressed_file)
+        .await?;
+    compressed_file.seek(SeekFrom::Start(0)).await?;
+    Ok((compressed_file, compressed_size))
+}
+
+/// Uploads the layer too large for a single object as the objects of its `parts`, then their
+/// manifest. The parts are byte ranges of the layer, so they are not compressed, and each has
+/// the checksum of its own contents.
+async fn upload_layer_parts(
+    conf: &'static PageServerConf,
+    storage: &GenericRemoteStorage,
+    mut source_file: fs::File,
+    source_path: &Path,
+    storage_path: &RemotePath,
+    parts: &LayerParts,
+) -> anyhow::Result<()> {
+    // A retry would not make the layer any smaller.
+    parts
+        .ensure_uploadable(storage_path)
+        .map_err(RemoteStorageError::Permanent)?;
+
+    for part in 0..parts.count() {
+        let (start, end) = parts.range(part);
+        source_file
+            .seek(SeekFrom::Start(start))
+            .await
+            .with_context(|| format!("Failed to seek to part {part} of layer {source_path:?}"))?;
+        let checksum = match Hasher::new(conf.remote_checksum_algorithm) {
+            Some(mut hasher) => {
+                let mut part_contents = (&mut source_file).take(end - start);
+                copy_with_hasher(
+                    &mut part_contents,
+                    &mut tokio::io::sink(),
+                    Some(&mut hasher),
+                )
+                .await
+                .with_context(|| {
+                    format!(
+                        "Failed to compute the checksum of part {part} of layer {source_path:?}"
+                    )
+                })?;
+                source_file
+                    .seek(SeekFrom::Start(start))
+                    .await
+                    .with_context(|| {
+                        format!("Failed to seek to part {part} of layer {source_path:?}")
+                    })?;
+                Some(hasher.finish())
+            }
+            None => None,
+        };
+        // Shares the position with `source_file`.
+        let part_file = source_file
+            .try_clone()
+            .await
+            .with_context(|| format!("Failed to reopen layer {source_path:?}"))?;
+        storage
+            .upload(
+                part_file.take(end - start),
+                (end - start) as usize,
+                &LayerParts::part_path(storage_path, part),
+                checksum.map(|checksum| checksum.to_metadata()),
+            )
+            .await
+            .with_context(|| format!("Failed to upload part {part} of layer {source_path:?}"))?;
+    }
+
+    let manifest_path = LayerParts::manifest_path(storage_path);
+    let manifest =
+        serde_json::to_vec(parts).context("Failed to serialize the layer parts into bytes")?;
+    let manifest_size = manifest.len();
+    storage
+        .upload(
+            std::io::Cursor::new(manifest),
+            manifest_size,
+            &manifest_path,
+            None,
+        )
+        .await
+        .with_context(|| format!("Failed to upload the manifest {manifest_path}"))?;
+
+    REMOTE_UPLOAD_BYTES
+        .with_label_values(&[RemoteOpFileKind::Layer.as_str()])
+        .inc_by(parts.file_size);
     Ok(())
 }
+
+/// Uploads the chunk checksums of the layer at `layer_path` next to it.
+async fn upload_chunk_checksums(
+    storage: &GenericRemoteStorage,
+    layer_path: &RemotePath,
+    chunk_checksums: &ChunkChecksums,
+) -> anyhow::Result<()> {
+    let path = ChunkChecksums::remote_path(layer_path);
+    let bytes = serde_json::to_vec(chunk_checksums)
+        .context("Failed to serialize the chunk checksums into bytes")?;
+    let size = bytes.len();
+    storage
+        .upload(std::io::Cursor::new(bytes), size, &path, None)
+        .await
+        .with_context(|| format!("Failed to upload the chunk checksums of layer {layer_path}"))
+}
+
+/// Uploads the small layer files of the timeline at `timeline_path` as one archive object,
+/// each layer at the offset recorded in its metadata.
+///
+/// The archive is stored uncompressed and without a checksum of its own, so that a single layer
+/// can be downloaded with a byte range request. A layer deleted locally before the upload is
+/// zero-filled, unless `is_referenced` tells that an index part still refers to it.
+///
+/// Returns the checksums of the archived layers, checked when they are downloaded.
+#[instrument(skip_all, fields(archive = %archive.name, bytes = archive.size()))]
+pub(super) async fn upload_layer_archive<'a>(
+    conf: &'static PageServerConf,
+    storage: &'a GenericRemoteStorage,
+    timeline_path: &'a Path,
+    archive: &'a LayerArchive,
+    is_referenced: impl Fn(&LayerFileName) -> bool,
+) -> anyhow::Result<Vec<(LayerFileName, Checksum)>> {
+    fail_point!("before-upload-layer", |_| {
+        bail!("failpoint before-upload-layer")
+    });
+    let storage_path = conf.remote_path(&timeline_path.join(&archive.name))?;
+
+    let archive_size = usize::try_from(archive.size()).with_context(|| {
+        format!(
+            "Archive {} size {} could not be converted to usize",
+            archive.name,
+            archive.size()
+        )
+    })?;
+    let mut contents = vec![0; archive_size];
+    let mut checksums = Vec::new();
+    for (name, metadata) in &archive.layers {
+        let source_path = timeline_path.join(name.file_name());
+        let location = metadata
+            .archive()
+            .with_context(|| format!("Layer {name} has no place in archive {}", archive.name))?;
+        let start = location.offset as usize;
+        let end = start + metadata.file_size() as usize;
+        match fs::read(&source_path).await {
+            Ok(bytes) if bytes.len() == end - start => {
+                contents[start..end].copy_from_slice(&bytes);
+                if let Some(checksum) =
+                    checksum::checksum_bytes(conf.remote_checksum_algorithm, &bytes)
+                {
+                    checksums.push((name.clone(), checksum));
+                }
+            }
+            Ok(bytes) => bail!(
+                "File {source_path:?} has its current FS size {} diferent from initially determined {}",
+                bytes.len(),
+                metadata.file_size()
+            ),
+            Err(e) if e.kind() == ErrorKind::NotFound && !is_referenced(name) => {
+                info!(path = %source_path.display(), "File to archive doesn't exist. Likely the file has been deleted and an upload is not required any more.");
+            }
+            Err(e) => Err(e).with_context(|| {
+                format!("Failed to read the source file for layer {source_path:?}")
+            })?,
+        }
+    }
+
+    storage
+        .upload(
+            std::io::Cursor::new(contents),
+            archive_size,
+            &storage_path,
+            StorageMetadata::mark_immutable(None),
+        )
+        .await
+        .with_context(|| format!("Failed to upload layer archive {}", archive.name))?;
+
+    REMOTE_UPLOAD_BYTES
+        .with_label_values(&[RemoteOpFileKind::Layer.as_str()])
+        .inc_by(archive_size as u64);
+    Ok(checksums)
+}
+
+/// Codec for the new uploads, the downloads use the one recorded in the object metadata.
+fn remote_compression(conf: &PageServerConf) -> Compression {
+    conf.remote_storage_config
+        .as_ref()
+        .map_or(Compression::None, |config| config.compression)
+}
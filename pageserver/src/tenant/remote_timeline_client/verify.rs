@@ -0,0 +1,487 @@
+//! One-shot comparison of the local files of a timeline with its copy in the remote storage,
+//! e.g. to make sure a backup is complete before the node is decommissioned.
+//!
+//! Only reads both sides: the drift is reported, not fixed. The local layers of the same size as
+//! their remote copy are read whole, to compare them with the checksums the remote storage has
+//! for them.
+
+use std::collections::{BTreeMap, BTreeSet};
+use std::path::Path;
+
+use anyhow::Context;
+use pageserver_api::models::{LayerSizeMismatch, RemoteConsistencyReport};
+use remote_storage::{GenericRemoteStorage, RemotePath, RemoteStorageError};
+use tokio::fs;
+use utils::id::{TenantId, TimelineId};
+
+use crate::config::PageServerConf;
+use crate::tenant::metadata::load_metadata;
+use crate::tenant::storage_layer::LayerFileName;
+
+use super::checksum::{self, ChunkChecksums};
+use super::download::{download_chunk_checksums, download_index_part};
+use super::index::IndexPart;
+use super::manifest::BackupManifest;
+use super::parts::LayerParts;
+use super::{with_timeout, RemoteOpRetrySettings, FAILED_DOWNLOAD_WARN_THRESHOLD};
+
+const LOCAL_FS_METADATA_SUFFIX: &str = ".metadata";
+
+pub(super) async fn verify_remote_consistency(
+    conf: &'static PageServerConf,
+    storage: &GenericRemoteStorage,
+    tenant_id: TenantId,
+    timeline_id: TimelineId,
+) -> anyhow::Result<RemoteConsistencyReport> {
+    let timeline_path = conf.timeline_path(&tenant_id, &timeline_id);
+    let local_metadata = load_metadata(conf, &tenant_id, &timeline_id)
+        .context("Failed to load the local timeline metadata")?;
+
+    // Layer file name -> size. Anything that is not a layer, like the metadata file or the
+    // temporary downloads, is not uploaded as is.
+    let mut local_layers = BTreeMap::new();
+    let mut entries = fs::read_dir(&timeline_path)
+        .await
+        .with_context(|| format!("Failed to list the timeline directory {timeline_path:?}"))?;
+    while let Some(entry) = entries
+        .next_entry()
+        .await
+        .with_context(|| format!("Failed to list the timeline directory {timeline_path:?}"))?
+    {
+        let Some(layer) = entry
+            .file_name()
+            .to_str()
+            .and_then(|name| name.parse::<LayerFileName>().ok())
+        else {
+            continue;
+        };
+        let metadata = entry
+            .metadata()
+            .await
+            .with_context(|| format!("Failed to get the metadata of {:?}", entry.path()))?;
+        if metadata.is_file() {
+            local_layers.insert(layer.file_name(), metadata.len());
+        }
+    }
+
+    let index_part = match download_index_part(conf, storage, &tenant_id, &timeline_id).await {
+        Ok(index_part) => Some(index_part),
+        Err(RemoteStorageError::NotFound) => None,
+        Err(e) => return Err(e).context("Failed to download the remote index part"),
+    };
+
+    let retry_settings = RemoteOpRetrySettings::from_conf(conf);
+    let timeline_storage_path = conf.remote_path(&timeline_path)?;
+    let listing = retry_settings
+        .retry(
+            || {
+                with_timeout(
+                    retry_settings.list_timeout,
+                    storage.list_files(Some(&timeline_storage_path)),
+                    |_| RemoteStorageError::Timeout,
+                )
+            },
+            RemoteStorageError::is_permanent,
+            FAILED_DOWNLOAD_WARN_THRESHOLD,
+            "list_files",
+        )
+        .await;
+    let listed_objects: BTreeSet<String> = match listing {
+        Ok(paths) => paths
+            .iter()
+            .filter_map(|path| path.object_name())
+            .map(str::to_owned)
+            .collect(),
+        Err(RemoteStorageError::NotFound) => BTreeSet::new(),
+        Err(e) => return Err(e).context("Failed to list the remote timeline files"),
+    };
+    // The local storage keeps the metadata of an object in a file next to it, that is not an
+    // object of its own.
+    let remote_objects = listed_objects
+        .iter()
+        .filter(|name| {
+            !name
+                .strip_suffix(LOCAL_FS_METADATA_SUFFIX)
+                .map_or(false, |object| listed_objects.contains(object))
+        })
+        .filter(|name| !IndexPart::is_index_file_name(name) && *name != BackupManifest::FILE_NAME)
+        .cloned()
+        .collect::<BTreeSet<_>>();
+
+    let mut report = compare(
+        local_metadata.disk_consistent_lsn(),
+        &local_layers,
+        index_part.as_ref(),
+        &remote_objects,
+    );
+    if let Some(index_part) = &index_part {
+        for (layer, &local_size) in &local_layers {
+            if report.missing_uploads.contains(layer) {
+                continue;
+            }
+            let remote_path = timeline_storage_path.join(Path::new(layer));
+            let matches = layer_checksums_match(
+                &retry_settings,
+                storage,
+                &timeline_path.join(layer),
+                &remote_path,
+                local_size,
+                index_part,
+                &remote_objects,
+            )
+            .await?;
+            if matches == Some(false) {
+                report.checksum_mismatches.push(layer.clone());
+            }
+        }
+    }
+    Ok(report)
+}
+
+/// Compares the local layer at `local_path` with its checksum in the remote index part, or with
+/// the checksums of the chunks of its remote object. `None` if the remote storage has neither,
+/// or the layer is of another size there.
+async fn layer_checksums_match(
+    retry_settings: &RemoteOpRetrySettings,
+    storage: &GenericRemoteStorage,
+    local_path: &Path,
+    remote_path: &RemotePath,
+    local_size: u64,
+    index_part: &IndexPart,
+    remote_objects: &BTreeSet<String>,
+) -> anyhow::Result<Option<bool>> {
+    let Some(metadata) = local_path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| name.parse::<LayerFileName>().ok())
+        .and_then(|layer| index_part.layer_metadata.get(&layer))
+    else {
+        return Ok(None);
+    };
+    if metadata.file_size != local_size {
+        return Ok(None);
+    }
+    let mut file = match fs::File::open(local_path).await {
+        Ok(file) => file,
+        // Evicted since the timeline directory was listed.
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to open the local layer {local_path:?}"))
+        }
+    };
+
+    if let Some(remote_checksum) = &metadata.checksum {
+        let local_checksum = checksum::checksum_file(remote_checksum.algorithm(), &mut file)
+            .await
+            .with_context(|| format!("Failed to compute the checksum of {local_path:?}"))?;
+        return Ok(Some(local_checksum.as_ref() == Some(remote_checksum)));
+    }
+
+    let chunks_path = ChunkChecksums::remote_path(remote_path);
+    if !chunks_path
+        .object_name()
+        .map_or(false, |name| remote_objects.contains(name))
+    {
+        return Ok(None);
+    }
+    let remote_chunk_checksums = retry_settings
+        .retry(
+            || {
+                with_timeout(
+                    retry_settings.operation_timeout,
+                    download_chunk_checksums(storage, remote_path, local_size),
+                    |_| RemoteStorageError::Timeout,
+                )
+            },
+            RemoteStorageError::is_permanent,
+            FAILED_DOWNLOAD_WARN_THRESHOLD,
+            "download the chunk checksums",
+        )
+        .await
+        .with_context(|| format!("Failed to download the chunk checksums {chunks_path}"))?;
+    let Some(remote_chunk_checksums) = remote_chunk_checksums else {
+        return Ok(None);
+    };
+    let local_chunk_checksums = ChunkChecksums::of_file(
+        remote_chunk_checksums.algorithm(),
+        remote_chunk_checksums.chunk_size(),
+        &mut file,
+    )
+    .await
+    .with_context(|| format!("Failed to compute the chunk checksums of {local_path:?}"))?;
+    Ok(Some(
+        local_chunk_checksums.as_ref() == Some(&remote_chunk_checksums),
+    ))
+}
+
+fn compare(
+    local_disk_consistent_lsn: utils::lsn::Lsn,
+    local_layers: &BTreeMap<String, u64>,
+    index_part: Option<&IndexPart>,
+    remote_objects: &BTreeSet<String>,
+) -> RemoteConsistencyReport {
+    // Layer file name -> size, if known. The superseded layers are kept for the restores
+    // to older LSNs, they are expected to be in the storage but not locally.
+    let mut remote_layers = BTreeMap::new();
+    let mut superseded_layers = BTreeSet::new();
+    // The layers packed into an archive are in the storage if their archive is, with the size
+    // recorded in the index part: the archive stores them without checksums.
+    let mut archives = BTreeSet::new();
+    let mut present_objects = remote_objects.clone();
+    // Same for the layers split into parts, which are in the storage if all their parts and
+    // their manifest are.
+    let mut layer_part_objects = BTreeSet::new();
+    // The chunk checksums stored next to the layers.
+    let mut chunk_checksum_objects = BTreeSet::new();
+    if let Some(index_part) = index_part {
+        for (archive, layers) in &index_part.layer_archives {
+            archives.insert(archive.clone());
+            if remote_objects.contains(archive) {
+                present_objects.extend(layers.iter().map(LayerFileName::file_name));
+            }
+        }
+        for (layer, metadata) in &index_part.layer_metadata {
+            let Some(parts) = metadata.parts else {
+                continue;
+            };
+            let layer_path = RemotePath::from_string(&layer.file_name())
+                .expect("a layer file name is a relative path");
+            let objects = parts
+                .part_paths(&layer_path)
+                .into_iter()
+                .chain([LayerParts::manifest_path(&layer_path)])
+                .filter_map(|path| path.object_name().map(str::to_owned))
+                .collect::<Vec<_>>();
+            if objects.iter().all(|object| remote_objects.contains(object)) {
+                present_objects.insert(layer.file_name());
+            }
+            layer_part_objects.extend(objects);
+        }
+        for layer in &index_part.timeline_layers {
+            let size = index_part
+                .layer_metadata
+                .get(layer)
+                .map(|metadata| metadata.file_size);
+            remote_layers.insert(layer.file_name(), size);
+        }
+        for layer in index_part.superseded_layers.keys() {
+            superseded_layers.insert(layer.file_name());
+        }
+        for layer in index_part.layer_metadata.keys() {
+            let layer_path = RemotePath::from_string(&layer.file_name())
+                .expect("a layer file name is a relative path");
+            if let Some(name) = ChunkChecksums::remote_path(&layer_path).object_name() {
+                chunk_checksum_objects.insert(name.to_owned());
+            }
+        }
+    }
+
+    let mut report = RemoteConsistencyReport {
+        missing_uploads: Vec::new(),
+        missing_remote_objects: Vec::new(),
+        remote_only: Vec::new(),
+        size_mismatches: Vec::new(),
+        checksum_mismatches: Vec::new(),
+        local_disk_consistent_lsn,
+        remote_disk_consistent_lsn: index_part.map(|index_part| index_part.disk_consistent_lsn),
+        missing_index_part: index_part.is_none()
+            && remote_objects
+                .iter()
+                .any(|object| object.parse::<LayerFileName>().is_ok()),
+    };
+
+    for (layer, &local_size) in local_layers {
+        match remote_layers.get(layer) {
+            Some(_) if !present_objects.contains(layer) => {
+                report.missing_uploads.push(layer.clone())
+            }
+            Some(Some(remote_size)) if *remote_size != local_size => {
+                report.size_mismatches.push(LayerSizeMismatch {
+                    layer: layer.clone(),
+                    local_size,
+                    remote_size: *remote_size,
+                })
+            }
+            Some(_) => {}
+            None => report.missing_uploads.push(layer.clone()),
+        }
+    }
+
+    for layer in remote_layers.keys().chain(&superseded_layers) {
+        if !present_objects.contains(layer) && !report.missing_uploads.contains(layer) {
+            report.missing_remote_objects.push(layer.clone());
+        }
+    }
+    report.missing_remote_objects.sort();
+
+    for object in remote_objects {
+        if !local_layers.contains_key(object)
+            && !remote_layers.contains_key(object)
+            && !superseded_layers.contains(object)
+            && !archives.contains(object)
+            && !layer_part_objects.contains(object)
+            && !chunk_checksum_objects.contains(object)
+        {
+            report.remote_only.push(object.clone());
+        }
+    }
+
+    report
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+
+    use utils::lsn::Lsn;
+
+    use super::*;
+    use crate::tenant::remote_timeline_client::index::LayerFileMetadata;
+
+    fn layer(lsns: &str) -> String {
+        format!("000000000000000000000000000000000000-FFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFFF__{lsns}")
+    }
+
+    #[test]
+    fn reports_the_drift() {
+        let uploaded = layer("0000000001696070-00000000016960E9");
+        let resized = layer("00000000016960E9-00000000016B59D8");
+        let not_uploaded = layer("00000000016B59D8-00000000016B5A51");
+        let lost = layer("00000000016B5A51-00000000016B5B00");
+        let evicted = layer("00000000016B5B00-00000000016B5C00");
+
+        let index_part = IndexPart::new(
+            HashMap::from([
+                (uploaded.parse().unwrap(), LayerFileMetadata::new(100)),
+                (resized.parse().unwrap(), LayerFileMetadata::new(200)),
+                (lost.parse().unwrap(), LayerFileMetadata::new(300)),
+                (evicted.parse().unwrap(), LayerFileMetadata::new(400)),
+            ]),
+            Lsn(0x16B5A51),
+            Vec::new(),
+        );
+        let local_layers = BTreeMap::from([
+            (uploaded.clone(), 100),
+            (resized.clone(), 201),
+            (not_uploaded.clone(), 500),
+        ]);
+        let remote_objects = BTreeSet::from([
+            uploaded.clone(),
+            resized.clone(),
+            evicted.clone(),
+            "leftover".to_owned(),
+        ]);
+
+        let report = compare(
+            Lsn(0x16B5C00),
+            &local_layers,
+            Some(&index_part),
+            &remote_objects,
+        );
+        assert_eq!(report.missing_uploads, vec![not_uploaded]);
+        assert_eq!(report.missing_remote_objects, vec![lost]);
+        assert_eq!(report.remote_only, vec!["leftover".to_owned()]);
+        assert_eq!(
+            report.size_mismatches,
+            vec![LayerSizeMismatch {
+                layer: resized,
+                local_size: 201,
+                remote_size: 200,
+            }]
+        );
+        assert_eq!(report.remote_disk_consistent_lsn, Some(Lsn(0x16B5A51)));
+        assert!(!report.is_consistent());
+
+        let in_sync = compare(
+            Lsn(0x16B5A51),
+            &BTreeMap::from([(uploaded.clone(), 100)]),
+            Some(&IndexPart::new(
+                HashMap::from([(uploaded.parse().unwrap(), LayerFileMetadata::new(100))]),
+                Lsn(0x16B5A51),
+                Vec::new(),
+            )),
+            &BTreeSet::from([uploaded.clone()]),
+        );
+        assert!(in_sync.is_consistent());
+
+        let never_uploaded = compare(Lsn(0x16B5A51), &local_layers, None, &BTreeSet::new());
+        assert_eq!(never_uploaded.missing_uploads.len(), 3);
+        assert_eq!(never_uploaded.remote_disk_consistent_lsn, None);
+        assert!(!never_uploaded.missing_index_part);
+
+        // An upload interrupted before the first index part.
+        let no_index_part = compare(
+            Lsn(0x16B5A51),
+            &local_layers,
+            None,
+            &BTreeSet::from([uploaded.clone(), "leftover".to_owned()]),
+        );
+        assert!(no_index_part.missing_index_part);
+        assert!(!no_index_part.is_consistent());
+    }
+
+    #[test]
+    fn archived_layers_are_in_their_archive() {
+        let archived = layer("0000000001696070-00000000016960E9");
+        let mut index_part = IndexPart::new(
+            HashMap::from([(archived.parse().unwrap(), LayerFileMetadata::new(100))]),
+            Lsn(0x16960E9),
+            Vec::new(),
+        );
+        index_part.layer_archives = HashMap::from([(
+            "layers.archive".to_owned(),
+            HashSet::from([archived.parse().unwrap()]),
+        )]);
+        let local_layers = BTreeMap::from([(archived.clone(), 100)]);
+
+        let report = compare(
+            Lsn(0x16960E9),
+            &local_layers,
+            Some(&index_part),
+            &BTreeSet::from(["layers.archive".to_owned()]),
+        );
+        assert!(report.is_consistent(), "{report:?}");
+
+        let lost_archive = compare(
+            Lsn(0x16960E9),
+            &local_layers,
+            Some(&index_part),
+            &BTreeSet::new(),
+        );
+        assert_eq!(lost_archive.missing_uploads, vec![archived]);
+    }
+
+    #[test]
+    fn split_layers_are_in_their_parts() {
+        let split = layer("0000000001696070-00000000016960E9");
+        let parts = LayerParts::of_layer(100, Some(60));
+        let index_part = IndexPart::new(
+            HashMap::from([(
+                split.parse().unwrap(),
+                LayerFileMetadata::new(100).with_parts(parts),
+            )]),
+            Lsn(0x16960E9),
+            Vec::new(),
+        );
+        let local_layers = BTreeMap::from([(split.clone(), 100)]);
+        let part_objects = BTreeSet::from([
+            format!("{split}.part-00000"),
+            format!("{split}.part-00001"),
+            format!("{split}.parts"),
+        ]);
+
+        let report = compare(
+            Lsn(0x16960E9),
+            &local_layers,
+            Some(&index_part),
+            &part_objects,
+        );
+        assert!(report.is_consistent(), "{report:?}");
+
+        let mut lost_part = part_objects.clone();
+        lost_part.remove(&format!("{split}.part-00001"));
+        let report = compare(Lsn(0x16960E9), &local_layers, Some(&index_part), &lost_part);
+        assert_eq!(report.missing_uploads, vec![split]);
+        assert!(report.remote_only.is_empty(), "{report:?}");
+    }
+}
@@ -229,6 +229,18 @@ impl std::fmt::Display for InMemoryLayer {
 }
 
 impl InMemoryLayer {
+    /// Keys with a value written in `lsn_range`, and the LSNs of the writes.
+    pub async fn load_keys(&self, lsn_range: Range<Lsn>) -> Vec<(Key, Lsn)> {
+        let inner = self.inner.read().await;
+        let mut keys = Vec::new();
+        for (key, versions) in inner.index.iter() {
+            for (lsn, _pos) in versions.slice_range(lsn_range.clone()) {
+                keys.push((*key, *lsn));
+            }
+        }
+        keys
+    }
+
     ///
     /// Get layer size on the disk
     ///
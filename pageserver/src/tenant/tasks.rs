@@ -1,5 +1,5 @@
 //! This module contains functions to serve per-tenant background processes,
-//! such as compaction and GC
+//! such as compaction, GC and the remote timeline list refresh
 
 use std::ops::ControlFlow;
 use std::sync::Arc;
@@ -10,13 +10,16 @@ use crate::metrics::TENANT_TASK_EVENTS;
 use crate::task_mgr;
 use crate::task_mgr::{TaskKind, BACKGROUND_RUNTIME};
 use crate::tenant::{Tenant, TenantState};
+use storage_broker::BrokerClientChannel;
 use tokio_util::sync::CancellationToken;
 use tracing::*;
 use utils::completion;
 
-/// Start per tenant background loops: compaction and gc.
+/// Start per tenant background loops: compaction, gc and, with remote storage configured,
+/// the remote timeline list refresh.
 pub fn start_background_loops(
     tenant: &Arc<Tenant>,
+    broker_client: BrokerClientChannel,
     background_jobs_can_start: Option<&completion::Barrier>,
 ) {
     let tenant_id = tenant.tenant_id;
@@ -66,6 +69,31 @@ pub fn start_background_loops(
             }
         },
     );
+    if tenant.remote_storage.is_some() && !tenant.conf.remote_list_refresh_interval.is_zero() {
+        task_mgr::spawn(
+            BACKGROUND_RUNTIME.handle(),
+            TaskKind::RemoteTimelineListRefresh,
+            Some(tenant_id),
+            None,
+            &format!("remote timeline list refresh for tenant {tenant_id}"),
+            false,
+            {
+                let tenant = Arc::clone(tenant);
+                let background_jobs_can_start = background_jobs_can_start.cloned();
+                async move {
+                    let cancel = task_mgr::shutdown_token();
+                    tokio::select! {
+                        _ = cancel.cancelled() => { return Ok(()) },
+                        _ = completion::Barrier::maybe_wait(background_jobs_can_start) => {}
+                    };
+                    remote_list_refresh_loop(tenant, broker_client, cancel)
+                        .instrument(info_span!("remote_list_refresh_loop", tenant_id = %tenant_id))
+                        .await;
+                    Ok(())
+                }
+            },
+        );
+    }
 }
 
 ///
@@ -197,6 +225,76 @@ async fn gc_loop(tenant: Arc<Tenant>, cancel: CancellationToken) {
     TENANT_TASK_EVENTS.with_label_values(&["stop"]).inc();
 }
 
+///
+/// Remote timeline list refresh task's main loop
+///
+async fn remote_list_refresh_loop(
+    tenant: Arc<Tenant>,
+    broker_client: BrokerClientChannel,
+    cancel: CancellationToken,
+) {
+    let wait_duration = Duration::from_secs(2);
+    TENANT_TASK_EVENTS.with_label_values(&["start"]).inc();
+    async {
+        let ctx = RequestContext::todo_child(
+            TaskKind::RemoteTimelineListRefresh,
+            DownloadBehavior::Download,
+        );
+        // The timelines were just listed when the tenant was attached, no need to hurry.
+        let period = tenant.conf.remote_list_refresh_interval;
+        if random_init_delay(period, &cancel).await.is_err() {
+            return;
+        }
+        loop {
+            tokio::select! {
+                _ = cancel.cancelled() => {
+                    return;
+                },
+                tenant_wait_result = wait_for_active_tenant(&tenant) => match tenant_wait_result {
+                    ControlFlow::Break(()) => return,
+                    ControlFlow::Continue(()) => (),
+                },
+            }
+
+            let started_at = Instant::now();
+
+            let sleep_duration = match tenant.refresh_remote_timelines(&broker_client, &ctx).await {
+                Ok(loaded) => {
+                    if loaded > 0 {
+                        info!("loaded {loaded} timelines found in the remote storage");
+                    }
+                    // The timelines that failed to load are retried on their own backoff.
+                    match tenant.next_remote_load_retry() {
+                        Some(retry_at) => retry_at
+                            .saturating_duration_since(Instant::now())
+                            .min(period),
+                        None => period,
+                    }
+                }
+                Err(e) => {
+                    error!(
+                        "Remote timeline list refresh failed, retrying in {:?}: {e:?}",
+                        wait_duration
+                    );
+                    wait_duration
+                }
+            };
+
+            warn_when_period_overrun(started_at.elapsed(), period, "remote_list_refresh");
+
+            // Sleep
+            if tokio::time::timeout(sleep_duration, cancel.cancelled())
+                .await
+                .is_ok()
+            {
+                break;
+            }
+        }
+    }
+    .await;
+    TENANT_TASK_EVENTS.with_label_values(&["stop"]).inc();
+}
+
 async fn wait_for_active_tenant(tenant: &Arc<Tenant>) -> ControlFlow<()> {
     // if the tenant has a proper status already, no need to wait for anything
     if tenant.current_state() == TenantState::Active {
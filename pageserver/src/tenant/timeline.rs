@@ -14,9 +14,9 @@ use itertools::Itertools;
 use pageserver_api::models::{
     DownloadRemoteLayersTaskInfo, DownloadRemoteLayersTaskSpawnRequest,
     DownloadRemoteLayersTaskState, LayerMapInfo, LayerResidenceEventReason, LayerResidenceStatus,
-    TimelineState,
+    RemoteConsistencyReport, TimelineState,
 };
-use remote_storage::GenericRemoteStorage;
+use remote_storage::{GenericRemoteStorage, RemoteStorageError};
 use serde_with::serde_as;
 use storage_broker::BrokerClientChannel;
 use tokio::runtime::Handle;
@@ -47,7 +47,7 @@ use crate::tenant::timeline::logical_size::CurrentLogicalSize;
 use crate::tenant::{
     ephemeral_file::is_ephemeral_file,
     layer_map::{LayerMap, SearchResult},
-    metadata::{save_metadata, TimelineMetadata},
+    metadata::{load_metadata, save_metadata, TimelineMetadata},
     par_fsync,
     storage_layer::{PersistentLayer, ValueReconstructResult, ValueReconstructState},
 };
@@ -67,7 +67,7 @@ use pageserver_api::reltag::RelTag;
 use postgres_connection::PgConnectionConfig;
 use postgres_ffi::to_pg_timestamp;
 use utils::{
-    completion,
+    backoff, completion,
     id::{RegionId, TenantId, TimelineId},
     lsn::{AtomicLsn, Lsn, RecordLsn},
     seqwait::SeqWait,
@@ -92,11 +92,14 @@ use self::walreceiver::{WalReceiver, WalReceiverConf};
 
 use super::config::TenantConf;
 use super::remote_timeline_client::index::IndexPart;
-use super::remote_timeline_client::RemoteTimelineClient;
+use super::remote_timeline_client::{MaybeDeletedIndexPart, RemoteTimelineClient, SyncPriority};
 use super::storage_layer::{
     AsLayerDesc, DeltaLayer, ImageLayer, Layer, LayerAccessStatsReset, PersistentLayerDesc,
 };
 
+/// Upper bound for the backoff between the index part downloads of a pending reconciliation.
+const PENDING_RECONCILIATION_MAX_BACKOFF_SECONDS: f64 = 60.0;
+
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 pub(super) enum FlushLoopState {
     NotStarted,
@@ -430,6 +433,75 @@ impl Timeline {
         self.latest_gc_cutoff_lsn.read()
     }
 
+    /// Get the keys written to this timeline after `since_lsn`, up to and including `lsn`,
+    /// with the LSNs of the writes.
+    ///
+    /// The keys come from the delta and in-memory layers of this timeline only, so the
+    /// writes before the branch point or the initial import, and the ones that GC may
+    /// have already removed, are not there. The caller must check that `since_lsn` is
+    /// past all of them. Remote delta layers are downloaded.
+    pub async fn get_changed_keys(
+        &self,
+        since_lsn: Lsn,
+        lsn: Lsn,
+        ctx: &RequestContext,
+    ) -> anyhow::Result<HashMap<Key, Vec<Lsn>>> {
+        let lsn_range = Lsn(since_lsn.0 + 1)..Lsn(lsn.0 + 1);
+        let overlaps =
+            |range: &Range<Lsn>| range.start < lsn_range.end && lsn_range.start < range.end;
+        loop {
+            let mut delta_layers = Vec::new();
+            let mut remote_layers = Vec::new();
+            let in_memory_layers = {
+                let guard = self.layers.read().await;
+                let layer_map = guard.layer_map();
+                for desc in layer_map.iter_historic_layers() {
+                    if !desc.is_delta() || !overlaps(&desc.lsn_range) {
+                        continue;
+                    }
+                    let layer = guard.get_from_desc(&desc);
+                    if let Some(remote_layer) = layer.clone().downcast_remote_layer() {
+                        remote_layers.push(remote_layer);
+                    } else if let Some(delta_layer) = layer.downcast_delta_layer() {
+                        delta_layers.push(delta_layer);
+                    }
+                }
+                layer_map
+                    .open_layer
+                    .iter()
+                    .chain(layer_map.frozen_layers.iter())
+                    .filter(|layer| overlaps(&layer.get_lsn_range()))
+                    .cloned()
+                    .collect::<Vec<_>>()
+            };
+
+            // The layers might be evicted again before we get to them, then we go around once
+            // more.
+            if !remote_layers.is_empty() {
+                for remote_layer in remote_layers {
+                    self.download_remote_layer(remote_layer, SyncPriority::High)
+                        .await?;
+                }
+                continue;
+            }
+
+            let mut changed_keys: HashMap<Key, Vec<Lsn>> = HashMap::new();
+            for delta_layer in delta_layers {
+                for (key, key_lsn, _) in delta_layer.load_keys(ctx).await? {
+                    if lsn_range.contains(&key_lsn) {
+                        changed_keys.entry(key).or_default().push(key_lsn);
+                    }
+                }
+            }
+            for in_memory_layer in in_memory_layers {
+                for (key, key_lsn) in in_memory_layer.load_keys(lsn_range.clone()).await {
+                    changed_keys.entry(key).or_default().push(key_lsn);
+                }
+            }
+            return Ok(changed_keys);
+        }
+    }
+
     /// Look up given page version.
     ///
     /// If a remote layer file is needed, it is downloaded as part of this
@@ -723,7 +795,7 @@ impl Timeline {
 
             let mut downloads = rls
                 .into_iter()
-                .map(|rl| self.download_remote_layer(rl))
+                .map(|rl| self.download_remote_layer(rl, SyncPriority::Low))
                 .collect::<futures::stream::FuturesUnordered<_>>();
 
             let mut failed = 0;
@@ -1052,7 +1124,8 @@ impl Timeline {
             return Ok(Some(false));
         }
 
-        self.download_remote_layer(remote_layer).await?;
+        self.download_remote_layer(remote_layer, SyncPriority::Low)
+            .await?;
         Ok(Some(true))
     }
 
@@ -1126,6 +1199,11 @@ impl Timeline {
         layers_to_evict: &[Arc<dyn PersistentLayer>],
         cancel: CancellationToken,
     ) -> anyhow::Result<Vec<Option<Result<(), EvictionError>>>> {
+        anyhow::ensure!(
+            !remote_client.is_dry_run(),
+            "remote storage dry run: the layers are not uploaded, cannot evict"
+        );
+
         // ensure that the layers have finished uploading
         // (don't hold the layer_removal_cs while we do it, we're not removing anything yet)
         remote_client
@@ -1736,8 +1814,8 @@ impl Timeline {
         // Create RemoteLayer instances for them.
         let mut local_only_layers = local_layers;
 
-        // We're holding a layer map lock for a while but this
-        // method is only called during init so it's fine.
+        // We're holding a layer map lock for a while but this method is only called
+        // during init, or once by the pending reconciliation, so it's fine.
         let mut guard = self.layers.write().await;
 
         let mut corrupted_local_layers = Vec::new();
@@ -1955,6 +2033,206 @@ impl Timeline {
         Ok(())
     }
 
+    /// Spawns the task that finishes the reconciliation with the remote storage of a timeline
+    /// that was loaded from its local files, because its index part failed to download.
+    /// See [`RemoteTimelineClient::init_upload_queue_pending_reconciliation`].
+    pub(super) fn spawn_pending_reconciliation_task(
+        self: &Arc<Self>,
+        loaded_disk_consistent_lsn: Lsn,
+    ) {
+        let self_clone = Arc::clone(self);
+        task_mgr::spawn(
+            task_mgr::BACKGROUND_RUNTIME.handle(),
+            TaskKind::RemoteReconciliation,
+            Some(self.tenant_id),
+            Some(self.timeline_id),
+            "remote reconciliation",
+            false,
+            async move {
+                self_clone
+                    .pending_reconciliation_loop(loaded_disk_consistent_lsn)
+                    .await;
+                Ok(())
+            }
+            .instrument(info_span!(parent: None, "remote_reconciliation", tenant_id = %self.tenant_id, timeline_id = %self.timeline_id)),
+        );
+    }
+
+    async fn pending_reconciliation_loop(&self, loaded_disk_consistent_lsn: Lsn) {
+        let Some(remote_client) = self.remote_client.as_ref() else {
+            return;
+        };
+        let cancel = task_mgr::shutdown_token();
+        let mut attempt = 0;
+        let index_part = loop {
+            tokio::select! {
+                _ = cancel.cancelled() => return,
+                _ = backoff::exponential_backoff(
+                    attempt,
+                    backoff::DEFAULT_BASE_BACKOFF_SECONDS,
+                    PENDING_RECONCILIATION_MAX_BACKOFF_SECONDS,
+                ) => {}
+            }
+            attempt += 1;
+
+            match remote_client.download_index_file().await {
+                Ok(MaybeDeletedIndexPart::IndexPart(index_part)) => break Some(index_part),
+                Ok(MaybeDeletedIndexPart::Deleted(_)) => {
+                    error!(
+                        "the remote timeline is deleted, leaving the remote reconciliation pending"
+                    );
+                    return;
+                }
+                Err(RemoteStorageError::NotFound) => break None,
+                Err(e) => {
+                    warn!("failed to download the index part, remote reconciliation is still pending (attempt {attempt}): {e:#}");
+                }
+            }
+        };
+
+        match self
+            .finish_pending_reconciliation(index_part.as_ref(), loaded_disk_consistent_lsn)
+            .await
+        {
+            Ok(()) => info!("remote reconciliation is done"),
+            Err(e) => error!("remote reconciliation failed, it stays pending until restart: {e:#}"),
+        }
+    }
+
+    /// Reconciles with the remote `index_part` like [`Self::reconcile_with_remote`], taking
+    /// the changes made since the timeline was loaded into account: the layers deleted
+    /// meanwhile are deleted remotely too.
+    ///
+    /// The remote layers above `loaded_disk_consistent_lsn` would overlap the ones created
+    /// since, so such a timeline is only reconciled by a restart, which picks the remote
+    /// metadata.
+    async fn finish_pending_reconciliation(
+        &self,
+        index_part: Option<&IndexPart>,
+        loaded_disk_consistent_lsn: Lsn,
+    ) -> anyhow::Result<()> {
+        let remote_client = self
+            .remote_client
+            .as_ref()
+            .ok_or_else(|| anyhow!("cannot reconcile without remote storage"))?;
+        if let Some(index_part) = index_part {
+            let remote_disk_consistent_lsn = index_part.parse_metadata()?.disk_consistent_lsn();
+            ensure!(
+                remote_disk_consistent_lsn <= loaded_disk_consistent_lsn,
+                "remote disk_consistent_lsn {remote_disk_consistent_lsn} is ahead of {loaded_disk_consistent_lsn} the timeline was loaded at, restart to reconcile"
+            );
+        }
+
+        // Compaction and GC hold it too, so no layers are deleted meanwhile.
+        let _layer_removal_guard = self.layer_removal_cs.lock().await;
+        let deleted_layers = remote_client.layers_deleted_pending_reconciliation()?;
+        let mut deleted_remote_layers = Vec::new();
+        if let Some(index_part) = index_part {
+            let mut remaining = index_part.clone();
+            remaining.timeline_layers.retain(|name| {
+                let deleted = deleted_layers.contains(name);
+                if deleted {
+                    deleted_remote_layers.push(name.clone());
+                }
+                !deleted
+            });
+            let local_layers = {
+                let guard = self.layers.read().await;
+                guard
+                    .layer_map()
+                    .iter_historic_layers()
+                    .map(|l| (l.filename(), guard.get_from_desc(&l)))
+                    .filter(|(_, layer)| !layer.is_remote_layer())
+                    .collect::<HashMap<_, _>>()
+            };
+            self.create_remote_layers(&remaining, local_layers, self.get_disk_consistent_lsn())
+                .await?;
+        }
+        let metadata = load_metadata(self.conf, &self.tenant_id, &self.timeline_id)
+            .context("load the local metadata")?;
+
+        // Under the read lock, the layers flushed meanwhile are either uploaded here, or schedule
+        // their own upload after the queue is reconciled.
+        let guard = self.layers.read().await;
+        let mut local_only_layers = Vec::new();
+        for desc in guard.layer_map().iter_historic_layers() {
+            let name = desc.filename();
+            if guard.get_from_desc(&desc).is_remote_layer()
+                || index_part.is_some_and(|index_part| index_part.timeline_layers.contains(&name))
+            {
+                continue;
+            }
+            info!("scheduling {name} for upload");
+            local_only_layers.push((name, LayerFileMetadata::new(desc.file_size())));
+        }
+        remote_client.finish_pending_reconciliation(
+            index_part,
+            &local_only_layers,
+            &deleted_remote_layers,
+            &metadata,
+        )
+    }
+
+    /// Re-lists the remote copy of the timeline, compares it with the local layers and uploads
+    /// the ones it lacks or has a different size or checksums of, along with the index part,
+    /// whatever the upload queue assumes is uploaded. The recovery after a remote object was
+    /// lost, or repaired by hand. Returns the differences found.
+    ///
+    /// The uploads go on meanwhile. The layers the remote storage has no copy of, not even
+    /// a local one, can't be recovered, they are only reported.
+    pub async fn force_remote_resync(&self) -> anyhow::Result<RemoteConsistencyReport> {
+        let remote_client = self
+            .remote_client
+            .as_ref()
+            .ok_or_else(|| anyhow!("cannot resync without remote storage"))?;
+
+        // Compaction and GC hold it too, so the layers to upload aren't deleted meanwhile.
+        let _layer_removal_guard = self.layer_removal_cs.lock().await;
+        // The queued uploads would show up as missing, and the failed ones never finish.
+        remote_client.retry_failed();
+        remote_client
+            .wait_completion()
+            .await
+            .context("wait for the queued remote operations")?;
+        let report = remote_client.verify_remote_consistency().await?;
+
+        let local_layers = {
+            let guard = self.layers.read().await;
+            guard
+                .layer_map()
+                .iter_historic_layers()
+                .filter(|desc| !guard.get_from_desc(desc).is_remote_layer())
+                .map(|desc| (desc.filename().file_name(), desc))
+                .collect::<HashMap<_, _>>()
+        };
+        let mismatched_layers = report
+            .size_mismatches
+            .iter()
+            .map(|mismatch| &mismatch.layer);
+        let mut layers_to_upload = Vec::new();
+        for name in report
+            .missing_uploads
+            .iter()
+            .chain(mismatched_layers)
+            .chain(&report.checksum_mismatches)
+        {
+            let Some(desc) = local_layers.get(name) else {
+                continue;
+            };
+            info!("scheduling {name} for upload again");
+            layers_to_upload.push((desc.filename(), LayerFileMetadata::new(desc.file_size())));
+        }
+        for name in &report.missing_remote_objects {
+            if !local_layers.contains_key(name) {
+                error!("layer {name} is lost: it's missing from the remote storage, and not local");
+            }
+        }
+        remote_client
+            .schedule_forced_resync(&layers_to_upload)
+            .await?;
+        Ok(report)
+    }
+
     fn try_spawn_size_init_task(self: &Arc<Self>, lsn: Lsn, ctx: &RequestContext) {
         let state = self.current_state();
         if matches!(
@@ -2603,7 +2881,9 @@ impl Timeline {
                             "on-demand downloading remote layer {id} for task kind {:?}",
                             ctx.task_kind()
                         );
-                        timeline.download_remote_layer(remote_layer).await?;
+                        timeline
+                            .download_remote_layer(remote_layer, SyncPriority::High)
+                            .await?;
                         continue 'layer_map_search;
                     }
                     (DownloadBehavior::Warn, _) | (DownloadBehavior::Error, true) => {
@@ -2613,7 +2893,9 @@ impl Timeline {
                             ctx.task_kind()
                         );
                         UNEXPECTED_ONDEMAND_DOWNLOADS.inc();
-                        timeline.download_remote_layer(remote_layer).await?;
+                        timeline
+                            .download_remote_layer(remote_layer, SyncPriority::High)
+                            .await?;
                         continue 'layer_map_search;
                     }
                     (DownloadBehavior::Error, false) => {
@@ -3909,6 +4191,7 @@ impl Timeline {
 
         let mut insert_layers = Vec::new();
         let mut remove_layers = Vec::new();
+        let mut new_layer_names = Vec::with_capacity(new_layers.len());
 
         for l in new_layers {
             let new_delta_path = l.path();
@@ -3926,6 +4209,7 @@ impl Timeline {
                     &LayerFileMetadata::new(metadata.len()),
                 )?;
             }
+            new_layer_names.push(l.filename());
 
             // update the timeline's physical size
             self.metrics
@@ -3971,9 +4255,11 @@ impl Timeline {
 
         drop_wlock(guard);
 
-        // Also schedule the deletions in remote storage
+        // Also schedule the deletions in remote storage. The new layers hold all the data of
+        // the compacted ones, which needn't be uploaded if they aren't yet.
         if let Some(remote_client) = &self.remote_client {
-            remote_client.schedule_layer_file_deletion(&layer_names_to_delete)?;
+            remote_client
+                .schedule_compacted_layer_deletion(&layer_names_to_delete, &new_layer_names)?;
         }
 
         Ok(())
@@ -4413,6 +4699,10 @@ impl Timeline {
     ///     - If it succeeded, we return `Ok(...)`.
     ///     - If it failed, we or another concurrent caller will initiate a new download attempt.
     ///
+    /// The page request path asks for [`SyncPriority::High`], so that the query doesn't wait for
+    /// the background uploads and downloads queued before it. If the layer is being downloaded
+    /// already, the ongoing download keeps its priority.
+    ///
     /// Download errors are classified and retried if appropriate by the underlying RemoteTimelineClient function.
     /// It has an internal limit for the maximum number of retries and prints appropriate log messages.
     /// If we exceed the limit, it returns an error, and this function passes it through.
@@ -4428,6 +4718,7 @@ impl Timeline {
     pub async fn download_remote_layer(
         &self,
         remote_layer: Arc<RemoteLayer>,
+        priority: SyncPriority,
     ) -> anyhow::Result<()> {
         span::debug_assert_current_span_has_tenant_and_timeline_id();
 
@@ -4470,7 +4761,11 @@ impl Timeline {
                 // Does retries + exponential back-off internally.
                 // When this fails, don't layer further retry attempts here.
                 let result = remote_client
-                    .download_layer_file(&remote_layer.filename(), &remote_layer.layer_metadata)
+                    .download_layer_file(
+                        &remote_layer.filename(),
+                        &remote_layer.layer_metadata,
+                        priority,
+                    )
                     .await;
 
                 if let Ok(size) = &result {
@@ -4620,7 +4915,7 @@ impl Timeline {
                 .iter_historic_layers()
                 .map(|l| guard.get_from_desc(&l))
                 .filter_map(|l| l.downcast_remote_layer())
-                .map(|l| self.download_remote_layer(l))
+                .map(|l| self.download_remote_layer(l, SyncPriority::Low))
                 .for_each(|dl| downloads.push(dl))
         }
         let total_layer_count = downloads.len();
@@ -4906,7 +5201,10 @@ mod tests {
         lsn::Lsn,
     };
 
-    use crate::tenant::{harness::TenantHarness, storage_layer::PersistentLayer};
+    use crate::tenant::{
+        harness::TenantHarness, remote_timeline_client::SyncPriority,
+        storage_layer::PersistentLayer,
+    };
 
     use super::{EvictionError, Timeline};
 
@@ -4923,8 +5221,25 @@ mod tests {
             std::fs::create_dir_all(&path).unwrap();
             let config = RemoteStorageConfig {
                 max_concurrent_syncs: std::num::NonZeroUsize::new(2_000_000).unwrap(),
+                max_concurrent_sync_per_tenant: None,
+                max_concurrent_sync_startup: None,
                 max_sync_errors: std::num::NonZeroU32::new(3_000_000).unwrap(),
-                storage: RemoteStorageKind::LocalFs(path),
+                max_retries: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_RETRIES,
+                base_backoff_ms: remote_storage::DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS,
+                compression: remote_storage::Compression::None,
+                max_bytes_per_sec: None,
+                dry_run: false,
+                encryption_key_file: None,
+                operation_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+                list_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+                upload_start_jitter: remote_storage::DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+                prefix: None,
+                storage: RemoteStorageKind::LocalFs(remote_storage::LocalFsConfig {
+                    local_path: path,
+                    read_only: false,
+                    fsync_batch_window: None,
+                    direct_io_min_size: None,
+                }),
             };
             GenericRemoteStorage::from_config(&config).unwrap()
         };
@@ -4988,8 +5303,25 @@ mod tests {
             std::fs::create_dir_all(&path).unwrap();
             let config = RemoteStorageConfig {
                 max_concurrent_syncs: std::num::NonZeroUsize::new(2_000_000).unwrap(),
+                max_concurrent_sync_per_tenant: None,
+                max_concurrent_sync_startup: None,
                 max_sync_errors: std::num::NonZeroU32::new(3_000_000).unwrap(),
-                storage: RemoteStorageKind::LocalFs(path),
+                max_retries: remote_storage::DEFAULT_REMOTE_STORAGE_MAX_RETRIES,
+                base_backoff_ms: remote_storage::DEFAULT_REMOTE_STORAGE_BASE_BACKOFF_MS,
+                compression: remote_storage::Compression::None,
+                max_bytes_per_sec: None,
+                dry_run: false,
+                encryption_key_file: None,
+                operation_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_OPERATION_TIMEOUT,
+                list_timeout: remote_storage::DEFAULT_REMOTE_STORAGE_LIST_TIMEOUT,
+                upload_start_jitter: remote_storage::DEFAULT_REMOTE_STORAGE_UPLOAD_START_JITTER,
+                prefix: None,
+                storage: RemoteStorageKind::LocalFs(remote_storage::LocalFsConfig {
+                    local_path: path,
+                    read_only: false,
+                    fsync_batch_window: None,
+                    direct_io_min_size: None,
+                }),
             };
             GenericRemoteStorage::from_config(&config).unwrap()
         };
@@ -5037,7 +5369,10 @@ mod tests {
 
         let layer = find_some_layer(&timeline).await;
         let layer = layer.downcast_remote_layer().unwrap();
-        timeline.download_remote_layer(layer).await.unwrap();
+        timeline
+            .download_remote_layer(layer, SyncPriority::Low)
+            .await
+            .unwrap();
 
         let res = only_one(second.await);
 
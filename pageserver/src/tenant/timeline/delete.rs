@@ -37,9 +37,9 @@ async fn stop_tasks(timeline: &Timeline) -> Result<(), DeleteTimelineError> {
     }
     debug!("wal receiver shutdown confirmed");
 
-    // Prevent new uploads from starting.
+    // Prevent new uploads from starting, and abort the ones in flight.
     if let Some(remote_client) = timeline.remote_client.as_ref() {
-        let res = remote_client.stop();
+        let res = remote_client.cancel_sync().await;
         match res {
             Ok(()) => {}
             Err(e) => match e {
@@ -54,6 +54,16 @@ impl Timeline {
         self: &Arc<Self>,
         background_tasks_can_start: Option<&completion::Barrier>,
     ) {
+        if self
+            .remote_client
+            .as_ref()
+            .map_or(false, |remote_client| remote_client.is_dry_run())
+        {
+            info!(
+                "remote storage dry run: no layers are uploaded, not launching the eviction task"
+            );
+            return;
+        }
         let self_clone = Arc::clone(self);
         let background_tasks_can_start = background_tasks_can_start.cloned();
         task_mgr::spawn(
@@ -168,7 +168,7 @@ impl TimelineUninitMark {
         }
     }
 
-    fn remove_uninit_mark(mut self) -> anyhow::Result<()> {
+    pub(crate) fn remove_uninit_mark(mut self) -> anyhow::Result<()> {
         if !self.uninit_mark_deleted {
             self.delete_mark_file_if_present()?;
         }
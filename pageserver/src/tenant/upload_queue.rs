@@ -3,8 +3,9 @@ use crate::metrics::RemoteOpFileKind;
 use super::storage_layer::LayerFileName;
 use crate::tenant::metadata::TimelineMetadata;
 use crate::tenant::remote_timeline_client::index::IndexPart;
-use crate::tenant::remote_timeline_client::index::LayerFileMetadata;
-use std::collections::{HashMap, VecDeque};
+use crate::tenant::remote_timeline_client::index::{LayerArchiveLocation, LayerFileMetadata};
+use crate::tenant::remote_timeline_client::parts::LayerParts;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt::Debug;
 
 use chrono::NaiveDateTime;
@@ -12,6 +13,8 @@ use std::sync::Arc;
 use tracing::info;
 
 use std::sync::atomic::AtomicU32;
+use std::time::{Duration, Instant};
+use tokio_util::sync::CancellationToken;
 use utils::lsn::Lsn;
 
 // clippy warns that Uninitialized is much smaller than Initialized, which wastes
@@ -53,6 +56,28 @@ pub(crate) struct UploadQueueInitialized {
     /// DANGER: do not return to outside world, e.g., safekeepers.
     pub(crate) latest_metadata: TimelineMetadata,
 
+    /// Layers removed from `latest_files` that are still kept in the remote storage for the
+    /// remote GC retention window, with the last `disk_consistent_lsn` they were part of.
+    /// Like `latest_files`, this takes into account the queued operations.
+    pub(crate) superseded_layers: HashMap<LayerFileName, Lsn>,
+
+    /// The remote GC retention window: `disk_consistent_lsn`s of the most recent index uploads,
+    /// oldest first. Superseded layers used only by the LSNs before the window get deleted.
+    pub(crate) retained_lsns: VecDeque<Lsn>,
+
+    /// `disk_consistent_lsn`s of the index parts kept in the remote storage as files of their
+    /// own, oldest first, see `remote_index_generations`. Takes into account the queued
+    /// operations.
+    pub(crate) index_generations: VecDeque<Lsn>,
+
+    /// The archive objects with the layers packed into them that are still in the remote
+    /// storage, see `remote_layer_archive_threshold`. Takes into account the queued operations.
+    pub(crate) layer_archives: HashMap<String, HashSet<LayerFileName>>,
+
+    /// Small layers from `latest_files` that are waiting to be packed into the next archive,
+    /// before the next index upload.
+    pub(crate) unarchived_layers: Vec<LayerFileName>,
+
     /// `disk_consistent_lsn` from the last metadata file that was successfully
     /// uploaded. `Lsn(0)` if nothing was uploaded yet.
     /// Unlike `latest_files` or `latest_metadata`, this value is never ahead.
@@ -73,13 +98,185 @@ pub(crate) struct UploadQueueInitialized {
     /// Queued operations that have not been launched yet. They might depend on previous
     /// tasks to finish. For example, metadata upload cannot be performed before all
     /// preceding layer file uploads have completed.
-    pub(crate) queued_operations: VecDeque<UploadOp>,
+    ///
+    /// Each operation is kept with the span of the caller that scheduled it and the time it
+    /// was scheduled at, see `push_op`.
+    pub(crate) queued_operations: VecDeque<(UploadOp, tracing::Span, Instant)>,
+
+    /// Set while the remote index part is unknown, because it failed to download at startup.
+    /// Nothing is launched meanwhile, the queued operations are dropped once the index part is
+    /// downloaded. The layers deleted meanwhile are collected here, to delete them remotely then.
+    pub(crate) pending_reconciliation: Option<HashSet<LayerFileName>>,
 }
 
 impl UploadQueueInitialized {
     pub(super) fn no_pending_work(&self) -> bool {
         self.inprogress_tasks.is_empty() && self.queued_operations.is_empty()
     }
+
+    /// Queues the operation along with the current span. The span of the task that performs
+    /// the operation follows from it, to tie e.g. a checkpoint to the uploads it caused.
+    pub(crate) fn push_op(&mut self, op: UploadOp) {
+        self.queued_operations
+            .push_back((op, tracing::Span::current(), Instant::now()));
+    }
+
+    /// Whether someone is waiting for the queued operations to complete, see
+    /// [`UploadTask::start_delay`].
+    pub(crate) fn has_queued_barrier(&self) -> bool {
+        self.queued_operations
+            .iter()
+            .any(|(op, ..)| matches!(op, UploadOp::Barrier(_)))
+    }
+
+    /// Takes the last queued index upload out of the queue, if only layer uploads were queued
+    /// after it. An index upload scheduled next lists everything it does, so it's superseded:
+    /// nothing that waits for it, a barrier or a deletion, was queued in between.
+    pub(crate) fn take_superseded_index_upload(&mut self) -> Option<UploadOp> {
+        let last = self.queued_operations.iter().rposition(|(op, ..)| {
+            !matches!(op, UploadOp::UploadLayer(..) | UploadOp::UploadArchive(_))
+        })?;
+        match self.queued_operations[last].0 {
+            UploadOp::UploadMetadata(..) => self.queued_operations.remove(last).map(|(op, ..)| op),
+            _ => None,
+        }
+    }
+
+    /// Takes the queued upload of a deleted layer out of the queue, if none of the index parts
+    /// still to be uploaded refers to the layer: then the remote storage never needs it.
+    pub(crate) fn take_unreferenced_layer_upload(
+        &mut self,
+        layer_name: &LayerFileName,
+    ) -> Option<UploadOp> {
+        let position = self.queued_operations.iter().position(
+            |(op, ..)| matches!(op, UploadOp::UploadLayer(name, _) if name == layer_name),
+        )?;
+        let refers_to_layer = |op: &UploadOp| match op {
+            UploadOp::UploadMetadata(index_part, _) => {
+                index_part.timeline_layers.contains(layer_name)
+                    || index_part.superseded_layers.contains_key(layer_name)
+            }
+            _ => false,
+        };
+        let in_progress = self.inprogress_tasks.values().map(|task| &task.op);
+        let queued = self.queued_operations.iter().map(|(op, ..)| op);
+        if in_progress.chain(queued).any(refers_to_layer) {
+            return None;
+        }
+        self.queued_operations.remove(position).map(|(op, ..)| op)
+    }
+
+    /// Moves the remote GC retention window to include `disk_consistent_lsn`, keeping at most
+    /// `window_size` LSNs in it, and returns the superseded layers that are no longer needed
+    /// for any of them. The returned layers are forgotten, the caller has to delete them.
+    pub(crate) fn advance_retention_window(
+        &mut self,
+        disk_consistent_lsn: Lsn,
+        window_size: usize,
+    ) -> Vec<LayerFileName> {
+        if self.retained_lsns.back() != Some(&disk_consistent_lsn) {
+            self.retained_lsns.push_back(disk_consistent_lsn);
+        }
+        while self.retained_lsns.len() > window_size {
+            self.retained_lsns.pop_front();
+        }
+
+        // An empty window (the retention got disabled) releases all the layers kept before.
+        let oldest_retained = self.retained_lsns.front().copied().unwrap_or(Lsn::MAX);
+        let latest_files = &self.latest_files;
+        let mut expired = Vec::new();
+        self.superseded_layers.retain(|name, last_used_at| {
+            // Never delete a layer the timeline uses again.
+            if latest_files.contains_key(name) {
+                return false;
+            }
+            if *last_used_at < oldest_retained {
+                expired.push(name.clone());
+                return false;
+            }
+            true
+        });
+        expired
+    }
+
+    /// Adds the index part of `disk_consistent_lsn` to the generations kept remotely, keeping at
+    /// most `generations` of them, and returns the `disk_consistent_lsn`s of the generations
+    /// dropped, whose files the caller has to delete.
+    pub(crate) fn advance_index_generations(
+        &mut self,
+        disk_consistent_lsn: Lsn,
+        generations: usize,
+    ) -> Vec<Lsn> {
+        if generations > 0 && self.index_generations.back() != Some(&disk_consistent_lsn) {
+            self.index_generations.push_back(disk_consistent_lsn);
+        }
+        let expired = self.index_generations.len().saturating_sub(generations);
+        self.index_generations.drain(..expired).collect()
+    }
+
+    /// Packs the small layers scheduled for upload since the last index upload into an archive,
+    /// recording where each of them is in `latest_files`. Returns the archive to upload, if any.
+    pub(crate) fn pack_unarchived_layers(&mut self) -> Option<LayerArchive> {
+        if self.unarchived_layers.is_empty() {
+            return None;
+        }
+        let name = format!(
+            "layers-{:016X}-{:08x}.archive",
+            self.latest_metadata.disk_consistent_lsn().0,
+            rand::random::<u32>()
+        );
+        let mut layers = Vec::new();
+        let mut offset = 0;
+        for layer_name in std::mem::take(&mut self.unarchived_layers) {
+            // Deleted since, or uploaded on its own after all.
+            let Some(metadata) = self.latest_files.get_mut(&layer_name) else {
+                continue;
+            };
+            if metadata.archive().is_some() {
+                continue;
+            }
+            let location = LayerArchiveLocation {
+                name: name.clone(),
+                offset,
+            };
+            offset += metadata.file_size();
+            *metadata = metadata.clone().with_archive(location);
+            layers.push((layer_name, metadata.clone()));
+        }
+        if layers.is_empty() {
+            return None;
+        }
+        self.layer_archives.insert(
+            name.clone(),
+            layers
+                .iter()
+                .map(|(layer_name, _)| layer_name.clone())
+                .collect(),
+        );
+        Some(LayerArchive { name, layers })
+    }
+
+    /// Forgets that the layer is in the remote storage as a part of an archive. Returns whether
+    /// it was: an archived layer has no object of its own to delete.
+    pub(crate) fn remove_from_archive(&mut self, layer_name: &LayerFileName) -> bool {
+        self.layer_archives
+            .values_mut()
+            .any(|layers| layers.remove(layer_name))
+    }
+
+    /// Forgets the archives none of whose layers is in the remote storage anymore, and returns
+    /// their names for the caller to delete.
+    pub(crate) fn take_empty_archives(&mut self) -> Vec<String> {
+        let mut empty = Vec::new();
+        self.layer_archives.retain(|name, layers| {
+            if layers.is_empty() {
+                empty.push(name.clone());
+                return false;
+            }
+            true
+        });
+        empty
+    }
 }
 
 #[derive(Clone, Copy)]
@@ -113,6 +310,11 @@ impl UploadQueue {
             latest_files: HashMap::new(),
             latest_files_changes_since_metadata_upload_scheduled: 0,
             latest_metadata: metadata.clone(),
+            superseded_layers: HashMap::new(),
+            retained_lsns: VecDeque::new(),
+            index_generations: VecDeque::new(),
+            layer_archives: HashMap::new(),
+            unarchived_layers: Vec::new(),
             // We haven't uploaded anything yet, so, `last_uploaded_consistent_lsn` must be 0 to prevent
             // safekeepers from garbage-collecting anything.
             last_uploaded_consistent_lsn: Lsn(0),
@@ -123,6 +325,7 @@ impl UploadQueue {
             num_inprogress_deletions: 0,
             inprogress_tasks: HashMap::new(),
             queued_operations: VecDeque::new(),
+            pending_reconciliation: None,
         };
 
         *self = UploadQueue::Initialized(state);
@@ -169,6 +372,11 @@ impl UploadQueue {
             latest_files: files,
             latest_files_changes_since_metadata_upload_scheduled: 0,
             latest_metadata: index_part_metadata.clone(),
+            superseded_layers: index_part.superseded_layers.clone(),
+            retained_lsns: index_part.retained_lsns.iter().copied().collect(),
+            index_generations: index_part.index_generations.iter().copied().collect(),
+            layer_archives: index_part.layer_archives.clone(),
+            unarchived_layers: Vec::new(),
             last_uploaded_consistent_lsn: index_part_metadata.disk_consistent_lsn(),
             // what follows are boring default initializations
             task_counter: 0,
@@ -177,6 +385,7 @@ impl UploadQueue {
             num_inprogress_deletions: 0,
             inprogress_tasks: HashMap::new(),
             queued_operations: VecDeque::new(),
+            pending_reconciliation: None,
         };
 
         *self = UploadQueue::Initialized(state);
@@ -210,20 +419,66 @@ pub(crate) struct UploadTask {
     pub(crate) retries: AtomicU32,
 
     pub(crate) op: UploadOp,
+
+    /// When the operation was queued.
+    pub(crate) scheduled_at: Instant,
+    /// Random delay before the first attempt, up to the `upload_start_jitter`, to spread the
+    /// uploads scheduled at once. Cancelling `start_now` cuts it short, once someone waits
+    /// for the task to complete.
+    pub(crate) start_delay: Duration,
+    pub(crate) start_now: CancellationToken,
 }
 
 #[derive(Debug)]
 pub(crate) struct Delete {
     pub(crate) file_kind: RemoteOpFileKind,
-    pub(crate) layer_file_name: LayerFileName,
+    /// Name of the object in the timeline's remote directory: a layer file, or a layer archive.
+    pub(crate) file_name: String,
+    pub(crate) parts: DeletedLayerParts,
     pub(crate) scheduled_from_timeline_delete: bool,
 }
 
+/// The parts of a split layer, deleted along with it, see [`LayerParts`].
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum DeletedLayerParts {
+    /// Not a split layer, or not a layer at all.
+    None,
+    Known(LayerParts),
+    /// The layer metadata is gone, as of the superseded layers: the parts are listed by the
+    /// manifest in the remote storage, if the layer is split.
+    Unknown,
+}
+
+impl From<Option<&LayerParts>> for DeletedLayerParts {
+    fn from(parts: Option<&LayerParts>) -> Self {
+        parts.map_or(Self::None, |parts| Self::Known(*parts))
+    }
+}
+
+/// Small layer files uploaded together as a single object, at the offsets in their metadata.
+#[derive(Debug, Clone)]
+pub(crate) struct LayerArchive {
+    pub(crate) name: String,
+    pub(crate) layers: Vec<(LayerFileName, LayerFileMetadata)>,
+}
+
+impl LayerArchive {
+    pub(crate) fn size(&self) -> u64 {
+        self.layers
+            .iter()
+            .map(|(_, metadata)| metadata.file_size())
+            .sum()
+    }
+}
+
 #[derive(Debug)]
 pub(crate) enum UploadOp {
     /// Upload a layer file
     UploadLayer(LayerFileName, LayerFileMetadata),
 
+    /// Upload an archive of small layer files
+    UploadArchive(LayerArchive),
+
     /// Upload the metadata file
     UploadMetadata(IndexPart, Lsn),
 
@@ -245,12 +500,18 @@ impl std::fmt::Display for UploadOp {
                     metadata.file_size()
                 )
             }
+            UploadOp::UploadArchive(archive) => write!(
+                f,
+                "UploadArchive({}, layers={}, size={})",
+                archive.name,
+                archive.layers.len(),
+                archive.size()
+            ),
             UploadOp::UploadMetadata(_, lsn) => write!(f, "UploadMetadata(lsn: {})", lsn),
             UploadOp::Delete(delete) => write!(
                 f,
                 "Delete(path: {}, scheduled_from_timeline_delete: {})",
-                delete.layer_file_name.file_name(),
-                delete.scheduled_from_timeline_delete
+                delete.file_name, delete.scheduled_from_timeline_delete
             ),
             UploadOp::Barrier(_) => write!(f, "Barrier"),
         }
@@ -302,6 +302,20 @@ impl WalIngest {
                     self.checkpoint.oldestXid = xlog_checkpoint.oldestXid;
                     self.checkpoint_modified = true;
                 }
+                // Postgres continues from the counters of the checkpoint record, even if the
+                // other records never got that far, e.g. after a pg_resetwal.
+                if self.checkpoint.advance_counters(
+                    &xlog_checkpoint,
+                    info == pg_constants::XLOG_CHECKPOINT_SHUTDOWN,
+                ) {
+                    self.checkpoint_modified = true;
+                }
+                // Track the PG timeline, the basebackup starts the compute on it.
+                if self.checkpoint.ThisTimeLineID != xlog_checkpoint.ThisTimeLineID {
+                    self.checkpoint.ThisTimeLineID = xlog_checkpoint.ThisTimeLineID;
+                    self.checkpoint.PrevTimeLineID = xlog_checkpoint.PrevTimeLineID;
+                    self.checkpoint_modified = true;
+                }
             }
         } else if decoded.xl_rmid == pg_constants::RM_LOGICALMSG_ID {
             let info = decoded.xl_info & pg_constants::XLR_RMGR_INFO_MASK;
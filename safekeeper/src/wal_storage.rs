@@ -584,6 +584,7 @@ impl WalReader {
                     self.timeline_start_lsn.segment_number(self.wal_seg_size),
                     self.system_id,
                     self.pg_version,
+                    PG_TLI,
                     self.timeline_start_lsn,
                 )?;
                 self.timeline_start_segment = Some(it);
@@ -202,13 +202,20 @@ class PageserverHttpClient(requests.Session):
         )
         self.verbose_error(res)
 
-    def tenant_detach(self, tenant_id: TenantId, detach_ignored=False):
+    def tenant_detach(
+        self, tenant_id: TenantId, detach_ignored=False, flush=False
+    ) -> Dict[str, Any]:
         params = {}
         if detach_ignored:
             params["detach_ignored"] = "true"
+        if flush:
+            params["flush"] = "true"
 
         res = self.post(f"http://localhost:{self.port}/v1/tenant/{tenant_id}/detach", params=params)
         self.verbose_error(res)
+        res_json = res.json()
+        assert isinstance(res_json, dict)
+        return res_json
 
     def tenant_delete(self, tenant_id: TenantId):
         res = self.delete(f"http://localhost:{self.port}/v1/tenant/{tenant_id}")
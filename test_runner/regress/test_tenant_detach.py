@@ -446,6 +446,77 @@ def test_tenant_detach_regular_tenant(neon_simple_env: NeonEnv):
         should not be present in pageserver's memory"
 
 
+# Detaches a tenant whose layer uploads are stuck, which cancels them, then reattaches it and
+# detaches it again with flush, once nothing holds the uploads back.
+@pytest.mark.parametrize("remote_storage_kind", [RemoteStorageKind.LOCAL_FS])
+def test_tenant_detach_flush(
+    neon_env_builder: NeonEnvBuilder,
+    remote_storage_kind: RemoteStorageKind,
+):
+    neon_env_builder.enable_remote_storage(
+        remote_storage_kind=remote_storage_kind,
+        test_name="test_tenant_detach_flush",
+    )
+    env = neon_env_builder.init_start()
+    client = env.pageserver.http_client()
+
+    # small checkpoint distance to flush layers, and schedule their uploads, while inserting
+    tenant_id, timeline_id = env.neon_cli.create_tenant(conf={"checkpoint_distance": "1048576"})
+
+    env.pageserver.allowed_errors.append(f".*Tenant {tenant_id} not found.*")
+    env.pageserver.allowed_errors.append(
+        f".*Tenant {tenant_id} will not become active\\. Current state: Stopping.*"
+    )
+
+    client.configure_failpoints(("before-upload-layer", "return"))
+
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as endpoint:
+        with endpoint.cursor() as cur:
+            cur.execute("CREATE TABLE t(key int primary key, value text)")
+            cur.execute("INSERT INTO t SELECT generate_series(1,100000), 'payload'")
+            current_lsn = Lsn(query_scalar(cur, "SELECT pg_current_wal_flush_lsn()"))
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+
+    def layer_uploads_queued():
+        queued = client.get_remote_timeline_client_metric(
+            "pageserver_remote_timeline_client_calls_unfinished",
+            tenant_id,
+            timeline_id,
+            "layer",
+            "upload",
+        )
+        assert queued is not None and queued > 0
+
+    wait_until(20, 0.5, layer_uploads_queued)
+
+    summary = client.tenant_detach(tenant_id)
+    log.info(f"detach summary: {summary}")
+    assert summary["flushed_operations"] == 0
+    assert summary["cancelled_operations"] > 0
+    assert summary["unflushed_timelines"] == []
+
+    client.configure_failpoints(("before-upload-layer", "off"))
+    client.tenant_attach(tenant_id)
+    wait_until_tenant_state(client, tenant_id, "Active", 10)
+
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as endpoint:
+        with endpoint.cursor() as cur:
+            cur.execute("INSERT INTO t SELECT generate_series(100001,200000), 'payload'")
+            current_lsn = Lsn(query_scalar(cur, "SELECT pg_current_wal_flush_lsn()"))
+    wait_for_last_record_lsn(client, tenant_id, timeline_id, current_lsn)
+
+    summary = client.tenant_detach(tenant_id, flush=True)
+    log.info(f"detach summary: {summary}")
+    assert summary["cancelled_operations"] == 0
+    assert summary["unflushed_timelines"] == []
+
+    client.tenant_attach(tenant_id)
+    wait_until_tenant_state(client, tenant_id, "Active", 10)
+    with env.endpoints.create_start("main", tenant_id=tenant_id) as endpoint:
+        with endpoint.cursor() as cur:
+            assert query_scalar(cur, "SELECT count(*) FROM t") == 200000
+
+
 @pytest.mark.parametrize("remote_storage_kind", available_remote_storages())
 def test_detach_while_attaching(
     neon_env_builder: NeonEnvBuilder,